@@ -2,6 +2,7 @@ use graph::bytes::Bytes;
 use graph::components::arweave::ArweaveAdapter as ArweaveAdapterTrait;
 use graph::prelude::*;
 use graph::url::Url;
+use graph::util::http::read_body;
 use reqwest::header;
 
 pub struct ArweaveAdapter {
@@ -26,7 +27,7 @@ impl ArweaveAdapter {
 
 #[async_trait]
 impl ArweaveAdapterTrait for ArweaveAdapter {
-    async fn tx_data(&self, tx_id: &str) -> Result<Bytes, Error> {
+    async fn tx_data(&self, tx_id: &str, max_bytes: Option<u64>) -> Result<Bytes, Error> {
         // Check that the user input is encoded in base64url, and is therefore safe to interpolate.
         if !tx_id
             .chars()
@@ -35,8 +36,10 @@ impl ArweaveAdapterTrait for ArweaveAdapter {
             return Err(format_err!("Invalid Arweave transaction id: `{}`", tx_id));
         }
 
-        self.http_client
-            .get(self.endpoint.join(&format!("tx/{}/data.", tx_id)).unwrap())
+        let url = self.endpoint.join(&format!("tx/{}/data.", tx_id)).unwrap();
+        let res = self
+            .http_client
+            .get(url.clone())
             .header(
                 header::CONTENT_TYPE,
                 header::HeaderValue::from_static("application/octet-stream"),
@@ -44,8 +47,7 @@ impl ArweaveAdapterTrait for ArweaveAdapter {
             .timeout(Duration::from_secs(60))
             .send()
             .and_then(|res| async { res.error_for_status() })
-            .and_then(|res| res.bytes())
-            .err_into()
-            .await
+            .await?;
+        read_body(url.as_str(), res, max_bytes).await
     }
 }
@@ -3,7 +3,10 @@ mod metrics;
 mod subgraph;
 pub mod three_box;
 
-pub use crate::link_resolver::LinkResolver;
+pub use crate::link_resolver::{
+    ArweaveLinkResolver, DiskCache, FileLinkResolver, HttpLinkResolver, LinkResolver,
+    SchemeLinkResolver,
+};
 pub use crate::metrics::MetricsRegistry;
 pub use crate::subgraph::{
     DataSourceLoader, SubgraphAssignmentProvider, SubgraphInstanceManager, SubgraphRegistrar,
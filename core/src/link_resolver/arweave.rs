@@ -0,0 +1,53 @@
+use async_trait::async_trait;
+
+use graph::components::arweave::ArweaveAdapter;
+use graph::prelude::{LinkResolver as LinkResolverTrait, *};
+
+use super::max_file_bytes;
+use super::stream::json_value_stream;
+
+/// A `LinkResolver` for links of the form `ar://<transaction id>` that reads
+/// the data of an Arweave transaction through an `ArweaveAdapter`.
+#[derive(Clone)]
+pub struct ArweaveLinkResolver {
+    adapter: Arc<dyn ArweaveAdapter>,
+}
+
+impl ArweaveLinkResolver {
+    pub fn new(adapter: Arc<dyn ArweaveAdapter>) -> Self {
+        Self { adapter }
+    }
+}
+
+#[async_trait]
+impl LinkResolverTrait for ArweaveLinkResolver {
+    fn with_timeout(self, _timeout: Duration) -> Self {
+        self
+    }
+
+    fn with_retries(self) -> Self {
+        self
+    }
+
+    async fn cat(&self, _logger: &Logger, link: &Link) -> Result<Vec<u8>, Error> {
+        let tx_id = link.link.trim_start_matches("ar://");
+        Ok(self
+            .adapter
+            .tx_data(tx_id, max_file_bytes())
+            .await?
+            .to_vec())
+    }
+
+    async fn json_stream(
+        &self,
+        logger: &Logger,
+        link: &Link,
+        format: StreamFormat,
+    ) -> Result<JsonValueStream, Error> {
+        let data = self.cat(logger, link).await?;
+        Ok(json_value_stream(
+            futures03::stream::iter(vec![Ok(data)]),
+            format,
+        ))
+    }
+}
@@ -0,0 +1,313 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use graph::prelude::{format_err, hex, Error, IpfsCache};
+
+use super::{MAX_IPFS_DISK_CACHE_FILE_SIZE, MAX_IPFS_DISK_CACHE_SIZE};
+
+/// The extension of files that are still being written.
+const TMP_EXTENSION: &str = "tmp";
+
+/// The size of the blocks that `ipfs add` splits files into by default.
+const MAX_BLOCK_SIZE: usize = 256 * 1024;
+
+/// A cache of IPFS files on disk that survives restarts and can be shared
+/// by all subgraphs on a node. Since IPFS files are immutable, entries are
+/// never invalidated; files are stored under the SHA-256 hash of their IPFS
+/// path. When the cache grows beyond `max_size` bytes, the oldest files are
+/// removed.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_size: u64,
+    max_file_size: u64,
+    /// The total size of the files in the cache. Holding the lock also
+    /// serializes changes to the cache directory
+    size: Mutex<u64>,
+    /// Used to generate unique names for temporary files
+    tmp_counter: AtomicUsize,
+}
+
+impl DiskCache {
+    /// Use `dir` for the cache, creating it if needed. Files that are
+    /// larger than `max_file_size` are never cached.
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64, max_file_size: u64) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .map_err(|e| format_err!("failed to create IPFS cache dir {}: {}", dir.display(), e))?;
+
+        let mut size = 0;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().map_or(false, |ext| ext == TMP_EXTENSION) {
+                // Left over from an interrupted write
+                fs::remove_file(&path)?;
+            } else {
+                size += entry.metadata()?.len();
+            }
+        }
+
+        Ok(DiskCache {
+            dir,
+            max_size,
+            max_file_size,
+            size: Mutex::new(size),
+            tmp_counter: AtomicUsize::new(0),
+        })
+    }
+
+    /// Use `dir` for the cache, with the size limits from the environment.
+    pub fn from_env(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        Self::new(
+            dir,
+            *MAX_IPFS_DISK_CACHE_SIZE,
+            *MAX_IPFS_DISK_CACHE_FILE_SIZE,
+        )
+    }
+
+    fn file_path(&self, path: &str) -> PathBuf {
+        let path = path.trim_start_matches("/ipfs/").trim_matches('/');
+        self.dir.join(hex::encode(Sha256::digest(path.as_bytes())))
+    }
+
+    /// Return the contents of the IPFS file `path` if it is in the cache.
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        fs::read(self.file_path(path)).ok()
+    }
+
+    /// Add the contents of the IPFS file `path` to the cache unless they are
+    /// too large.
+    pub fn insert(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let len = data.len() as u64;
+        if len > self.max_file_size || len > self.max_size {
+            return Ok(());
+        }
+
+        let mut size = self.size.lock().unwrap();
+        let file_path = self.file_path(path);
+        if file_path.exists() {
+            return Ok(());
+        }
+
+        // Write to a temporary file first so that readers never see a
+        // partially written file
+        let tmp_path = file_path.with_extension(format!(
+            "{}.{}",
+            self.tmp_counter.fetch_add(1, Ordering::SeqCst),
+            TMP_EXTENSION
+        ));
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, &file_path)?;
+        *size += len;
+
+        if *size > self.max_size {
+            self.evict(&mut *size, &file_path)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the oldest files, except for `keep`, until the cache is no
+    /// larger than `max_size`.
+    fn evict(&self, size: &mut u64, keep: &Path) -> Result<(), Error> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path == keep || path.extension().map_or(false, |ext| ext == TMP_EXTENSION) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((modified, path, metadata.len()));
+        }
+        files.sort();
+
+        for (_, path, len) in files {
+            if *size <= self.max_size {
+                break;
+            }
+            fs::remove_file(&path)?;
+            *size = size.saturating_sub(len);
+        }
+        Ok(())
+    }
+
+    /// Add the files in `dir` to the cache. Each file must be named after
+    /// its IPFS hash, i.e., `dir` must be laid out like the output of
+    /// `ipfs get <hash>` for each file. The contents of each file are checked
+    /// against its hash before it is added; since we can only compute the
+    /// hash of files that `ipfs add` stores in a single block, larger files
+    /// and directories are rejected. Returns the number of files that were
+    /// added.
+    pub fn seed_from_dir(&self, dir: &Path) -> Result<usize, Error> {
+        let mut count = 0;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| format_err!("invalid file name {}", path.display()))?;
+            if path.is_dir() {
+                return Err(format_err!(
+                    "can not seed the IPFS cache with directory {}",
+                    path.display()
+                ));
+            }
+
+            let data = fs::read(&path)?;
+            match ipfs_hash(&data) {
+                Some(hash) if hash == name => self.insert(name, &data)?,
+                Some(hash) => {
+                    return Err(format_err!(
+                        "the contents of {} do not match its IPFS hash: they hash to {}",
+                        path.display(),
+                        hash
+                    ))
+                }
+                None => {
+                    return Err(format_err!(
+                        "can not check the IPFS hash of {}: only files of at most {} bytes \
+                         can be checked",
+                        path.display(),
+                        MAX_BLOCK_SIZE
+                    ))
+                }
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl IpfsCache for DiskCache {
+    fn seed_from_dir(&self, dir: &Path) -> Result<usize, Error> {
+        DiskCache::seed_from_dir(self, dir)
+    }
+}
+
+/// The IPFS hash (CIDv0) that `ipfs add` with its default settings gives a
+/// file with contents `data`, or `None` if the file does not fit into a
+/// single block. A single-block file is a DAG-PB node without links whose
+/// data is the UnixFS message for the file, and its hash is the SHA-256
+/// multihash of that node.
+fn ipfs_hash(data: &[u8]) -> Option<String> {
+    fn varint(mut n: usize, out: &mut Vec<u8>) {
+        while n >= 0x80 {
+            out.push((n as u8 & 0x7f) | 0x80);
+            n >>= 7;
+        }
+        out.push(n as u8);
+    }
+
+    if data.len() > MAX_BLOCK_SIZE {
+        return None;
+    }
+
+    // UnixFS `Data { Type: File, Data: data, filesize: len }`
+    let mut unixfs = vec![0x08, 0x02];
+    if !data.is_empty() {
+        unixfs.push(0x12);
+        varint(data.len(), &mut unixfs);
+        unixfs.extend_from_slice(data);
+    }
+    unixfs.push(0x18);
+    varint(data.len(), &mut unixfs);
+
+    // DAG-PB `PBNode { Data: unixfs }`
+    let mut node = vec![0x0a];
+    varint(unixfs.len(), &mut node);
+    node.extend_from_slice(&unixfs);
+
+    // The multihash prefix for SHA-256 digests
+    let mut multihash = vec![0x12, 0x20];
+    multihash.extend_from_slice(&Sha256::digest(&node));
+    Some(bs58::encode(multihash).into_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn insert_get_and_evict() {
+        let dir = temp_dir("ipfs-disk-cache");
+        let cache = DiskCache::new(&dir, 10, 5).unwrap();
+
+        cache.insert("/ipfs/Qm1", b"1234").unwrap();
+        assert_eq!(Some(b"1234".to_vec()), cache.get("Qm1"));
+
+        // Too large to be cached
+        cache.insert("Qm2", b"123456").unwrap();
+        assert_eq!(None, cache.get("Qm2"));
+
+        // Going over the total size evicts older files
+        cache.insert("Qm3", b"12345").unwrap();
+        cache.insert("Qm4", b"12345").unwrap();
+        assert_eq!(Some(b"12345".to_vec()), cache.get("Qm4"));
+        assert!(*cache.size.lock().unwrap() <= 10);
+
+        // The cache survives a restart
+        let cache = DiskCache::new(&dir, 10, 5).unwrap();
+        assert_eq!(Some(b"12345".to_vec()), cache.get("Qm4"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ipfs_hash() {
+        // The hashes that `ipfs add` reports for these files
+        assert_eq!(
+            Some("QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o"),
+            super::ipfs_hash(b"hello world\n").as_deref()
+        );
+        assert_eq!(
+            Some("QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH"),
+            super::ipfs_hash(b"").as_deref()
+        );
+        assert_eq!(None, super::ipfs_hash(&vec![0; MAX_BLOCK_SIZE + 1]));
+    }
+
+    #[test]
+    fn seed_from_dir() {
+        let dir = temp_dir("ipfs-disk-cache-seed");
+        let seed = temp_dir("ipfs-disk-cache-seed-src");
+        fs::create_dir_all(&seed).unwrap();
+        let hash = "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o";
+        fs::write(seed.join(hash), "hello world\n").unwrap();
+
+        let cache = DiskCache::new(&dir, 1000, 1000).unwrap();
+        assert_eq!(1, cache.seed_from_dir(&seed).unwrap());
+        assert_eq!(
+            Some(b"hello world\n".to_vec()),
+            cache.get(&format!("/ipfs/{}", hash))
+        );
+
+        // Files whose contents do not match their name are rejected
+        fs::write(seed.join(hash), "tampered\n").unwrap();
+        let bad = temp_dir("ipfs-disk-cache-seed-bad");
+        let cache = DiskCache::new(&bad, 1000, 1000).unwrap();
+        let err = cache.seed_from_dir(&seed).unwrap_err();
+        assert!(err.to_string().contains("do not match its IPFS hash"));
+        assert_eq!(None, cache.get(hash));
+
+        // We can not check the hash of directories
+        fs::remove_file(seed.join(hash)).unwrap();
+        fs::create_dir_all(seed.join("QmDir")).unwrap();
+        assert!(cache.seed_from_dir(&seed).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&seed).unwrap();
+        fs::remove_dir_all(&bad).unwrap();
+    }
+}
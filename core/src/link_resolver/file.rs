@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use async_trait::async_trait;
+
+use graph::prelude::{LinkResolver as LinkResolverTrait, *};
+
+use super::stream::json_value_stream;
+
+/// The size of the chunks in which `json_stream` reads files.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A `LinkResolver` that reads files from a local directory. Links are
+/// interpreted as paths relative to that directory; a leading `file://` or
+/// `/` is ignored, so that files outside the directory can not be read.
+/// This is useful for tests and for working on subgraphs without access to
+/// an IPFS node.
+#[derive(Clone, Debug)]
+pub struct FileLinkResolver {
+    base_dir: PathBuf,
+}
+
+impl FileLinkResolver {
+    pub fn new(base_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            base_dir: base_dir.into(),
+        }
+    }
+
+    fn path(&self, link: &Link) -> Result<PathBuf, Error> {
+        let path = Path::new(
+            link.link
+                .trim_start_matches("file://")
+                .trim_start_matches('/'),
+        );
+        if path.components().any(|c| c == Component::ParentDir) {
+            return Err(format_err!(
+                "file links must not contain `..`: {}",
+                link.link
+            ));
+        }
+        Ok(self.base_dir.join(path))
+    }
+
+    fn open(&self, link: &Link) -> Result<(PathBuf, File), Error> {
+        let path = self.path(link)?;
+        let file = File::open(&path)
+            .map_err(|e| format_err!("failed to open file {}: {}", path.display(), e))?;
+        Ok((path, file))
+    }
+}
+
+/// Reads a file in chunks of `CHUNK_SIZE` bytes.
+struct Chunks(Option<File>);
+
+impl Iterator for Chunks {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let file = self.0.as_mut()?;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        match file.read(&mut chunk) {
+            Ok(0) => {
+                self.0 = None;
+                None
+            }
+            Ok(n) => {
+                chunk.truncate(n);
+                Some(Ok(chunk))
+            }
+            Err(e) => {
+                self.0 = None;
+                Some(Err(e.into()))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LinkResolverTrait for FileLinkResolver {
+    fn with_timeout(self, _timeout: Duration) -> Self {
+        self
+    }
+
+    fn with_retries(self) -> Self {
+        self
+    }
+
+    async fn cat(&self, _logger: &Logger, link: &Link) -> Result<Vec<u8>, Error> {
+        let (path, mut file) = self.open(link)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(|e| format_err!("failed to read file {}: {}", path.display(), e))?;
+        Ok(data)
+    }
+
+    async fn json_stream(
+        &self,
+        _logger: &Logger,
+        link: &Link,
+        format: StreamFormat,
+    ) -> Result<JsonValueStream, Error> {
+        let (_, file) = self.open(link)?;
+        Ok(json_value_stream(
+            futures03::stream::iter(Chunks(Some(file))),
+            format,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::fs;
+
+    async fn read_values(text: &str, format: StreamFormat) -> Vec<Result<Value, String>> {
+        let dir = std::env::temp_dir().join(format!("file-link-resolver-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = format!("{:?}", format);
+        fs::write(dir.join(&name), text).unwrap();
+
+        let resolver = FileLinkResolver::new(&dir);
+        let logger = Logger::root(slog::Discard, o!());
+        let link = Link::from(format!("/{}", name));
+
+        let stream = LinkResolverTrait::json_stream(&resolver, &logger, &link, format)
+            .await
+            .unwrap();
+        let values = stream
+            .map(|sv| sv.map(|sv| sv.value).map_err(|e| e.to_string()))
+            .collect()
+            .await;
+        fs::remove_file(dir.join(&name)).unwrap();
+        values
+    }
+
+    #[tokio::test]
+    async fn cat() {
+        let dir = std::env::temp_dir();
+        let name = format!("file-link-resolver-cat-{}", std::process::id());
+        fs::write(dir.join(&name), "42").unwrap();
+
+        let resolver = FileLinkResolver::new(&dir);
+        let logger = Logger::root(slog::Discard, o!());
+        let data = LinkResolverTrait::cat(&resolver, &logger, &Link::from(name.clone()))
+            .await
+            .unwrap();
+        fs::remove_file(dir.join(&name)).unwrap();
+        assert_eq!(b"42".to_vec(), data);
+
+        let err = LinkResolverTrait::cat(&resolver, &logger, &Link::from(name))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("failed to open file"));
+
+        let err = LinkResolverTrait::cat(&resolver, &logger, &Link::from("../etc".to_owned()))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("must not contain `..`"));
+    }
+
+    #[tokio::test]
+    async fn json_stream_formats() {
+        let values = read_values("{\"a\": 1}\nnope\n[2]\n", StreamFormat::JsonLines).await;
+        assert_eq!(Ok(json!({"a": 1})), values[0]);
+        assert!(values[1].is_err());
+        assert_eq!(Ok(json!([2])), values[2]);
+
+        let values = read_values("[{\"a\": 1},\n[2]]", StreamFormat::JsonArray).await;
+        assert_eq!(vec![Ok(json!({"a": 1})), Ok(json!([2]))], values);
+
+        let values = read_values("a,b\n1,2\n3,4\n", StreamFormat::Csv).await;
+        assert_eq!(
+            vec![
+                Ok(json!({"a": "1", "b": "2"})),
+                Ok(json!({"a": "3", "b": "4"}))
+            ],
+            values
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use async_trait::async_trait;
+
+use graph::prelude::{LinkResolver as LinkResolverTrait, *};
+use graph::util::http::read_body;
+
+use super::stream::json_value_stream;
+use super::{max_file_bytes, IPFS_TIMEOUT};
+
+/// A `LinkResolver` for `http://` and `https://` links. Since the contents
+/// behind such links can change, `SchemeLinkResolver` only hands links to
+/// this resolver that pin the hash of the expected contents.
+#[derive(Clone)]
+pub struct HttpLinkResolver {
+    client: reqwest::Client,
+    timeout: Duration,
+}
+
+impl Default for HttpLinkResolver {
+    fn default() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            timeout: *IPFS_TIMEOUT,
+        }
+    }
+}
+
+#[async_trait]
+impl LinkResolverTrait for HttpLinkResolver {
+    fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn with_retries(self) -> Self {
+        self
+    }
+
+    async fn cat(&self, _logger: &Logger, link: &Link) -> Result<Vec<u8>, Error> {
+        let res = self
+            .client
+            .get(&link.link)
+            .timeout(self.timeout)
+            .send()
+            .and_then(|res| async { res.error_for_status() })
+            .await
+            .map_err(|e| format_err!("failed to fetch {}: {}", link.link, e))?;
+        let data = read_body(&link.link, res, max_file_bytes()).await?;
+        Ok(data.to_vec())
+    }
+
+    async fn json_stream(
+        &self,
+        logger: &Logger,
+        link: &Link,
+        format: StreamFormat,
+    ) -> Result<JsonValueStream, Error> {
+        let data = self.cat(logger, link).await?;
+        Ok(json_value_stream(
+            futures03::stream::iter(vec![Ok(data)]),
+            format,
+        ))
+    }
+}
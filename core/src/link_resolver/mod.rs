@@ -0,0 +1,520 @@
+use std::env;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use futures03::stream::FuturesUnordered;
+use ipfs_api::{response::ObjectStatResponse, IpfsClient};
+use lazy_static::lazy_static;
+use lru_time_cache::LruCache;
+
+use graph::prelude::{LinkResolver as LinkResolverTrait, *};
+
+mod arweave;
+mod disk_cache;
+mod file;
+mod http;
+mod scheme;
+mod stream;
+
+pub use self::arweave::ArweaveLinkResolver;
+pub use self::disk_cache::DiskCache;
+pub use self::file::FileLinkResolver;
+pub use self::http::HttpLinkResolver;
+pub use self::scheme::SchemeLinkResolver;
+use self::stream::json_value_stream;
+
+/// Environment variable for limiting the `ipfs.map` file size limit.
+const MAX_IPFS_MAP_FILE_SIZE_VAR: &'static str = "GRAPH_MAX_IPFS_MAP_FILE_SIZE";
+
+/// The default file size limit for `ipfs.map` is 256MiB.
+const DEFAULT_MAX_IPFS_MAP_FILE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Environment variable for limiting the `ipfs.cat` file size limit.
+const MAX_IPFS_FILE_SIZE_VAR: &'static str = "GRAPH_MAX_IPFS_FILE_BYTES";
+
+lazy_static! {
+    /// The default file size limit for the IPFS cache is 1MiB.
+    static ref MAX_IPFS_CACHE_FILE_SIZE: u64 = read_u64_from_env("GRAPH_MAX_IPFS_CACHE_FILE_SIZE")
+        .unwrap_or(1024 * 1024);
+
+    /// The default size limit for the IPFS cache is 50 items.
+    static ref MAX_IPFS_CACHE_SIZE: u64 = read_u64_from_env("GRAPH_MAX_IPFS_CACHE_SIZE")
+        .unwrap_or(50);
+
+    /// The default size limit for the IPFS disk cache is 1GiB.
+    static ref MAX_IPFS_DISK_CACHE_SIZE: u64 =
+        read_u64_from_env("GRAPH_MAX_IPFS_DISK_CACHE_SIZE").unwrap_or(1024 * 1024 * 1024);
+
+    /// The default file size limit for the IPFS disk cache is 64MiB.
+    static ref MAX_IPFS_DISK_CACHE_FILE_SIZE: u64 =
+        read_u64_from_env("GRAPH_MAX_IPFS_DISK_CACHE_FILE_SIZE").unwrap_or(64 * 1024 * 1024);
+
+    /// The timeout for IPFS requests in seconds
+    static ref IPFS_TIMEOUT: Duration = Duration::from_secs(
+        read_u64_from_env("GRAPH_IPFS_TIMEOUT").unwrap_or(60)
+    );
+}
+
+fn read_u64_from_env(name: &str) -> Option<u64> {
+    env::var(name).ok().map(|s| {
+        u64::from_str(&s).unwrap_or_else(|_| {
+            panic!(
+                "expected env var {} to contain a number (unsigned 64-bit integer), but got '{}'",
+                name, s
+            )
+        })
+    })
+}
+
+/// The IPFS APIs don't have a quick "do you have the file" function. Instead, we
+/// just rely on whether an API times out. That makes sense for IPFS, but not for
+/// our application. We want to be able to quickly select from a potential list
+/// of clients where hopefully one already has the file, and just get the file
+/// from that.
+///
+/// The strategy here then is to use the object_stat API as a proxy for "do you
+/// have the file". Whichever client has or gets the file first wins. This API is
+/// a good choice, because it doesn't involve us actually starting to download
+/// the file from each client, which would be wasteful of bandwidth and memory in
+/// the case multiple clients respond in a timely manner. In addition, we may
+/// make good use of the stat returned.
+async fn select_fastest_client_with_stat<'a>(
+    clients: &'a [IpfsClient],
+    logger: &'a Logger,
+    path: &'_ str,
+    timeout: Duration,
+    do_retry: bool,
+) -> Result<(ObjectStatResponse, &'a IpfsClient), failure::Error> {
+    let mut err: Option<failure::Error> = None;
+
+    let mut stats: FuturesUnordered<_> = clients
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let retry_fut = if do_retry {
+                retry("object.stat", logger).no_limit()
+            } else {
+                retry("object.stat", logger).limit(1)
+            }
+            .timeout(timeout);
+
+            retry_fut
+                .run(move || c.object_stat(path).map_ok(move |s| (s, i)).boxed().compat())
+                .compat()
+        })
+        .collect();
+
+    while let Some(result) = stats.next().await {
+        match result {
+            Ok((stat, index)) => {
+                return Ok((stat, &clients[index]));
+            }
+            Err(e) => err = Some(e.into()),
+        }
+    }
+
+    Err(err.unwrap_or_else(|| {
+        format_err!(
+            "No IPFS clients were supplied to handle the call to object.stat. File: {}",
+            path
+        )
+    }))
+}
+
+/// The limit on the size of files that `cat` returns, set with
+/// `GRAPH_MAX_IPFS_FILE_BYTES`. It applies to links of all schemes.
+fn max_file_bytes() -> Option<u64> {
+    read_u64_from_env(MAX_IPFS_FILE_SIZE_VAR)
+}
+
+// Returns an error if the stat is bigger than `max_file_bytes`
+fn restrict_file_size(
+    path: &str,
+    stat: &ObjectStatResponse,
+    max_file_bytes: &Option<u64>,
+) -> Result<(), failure::Error> {
+    if let Some(max_file_bytes) = max_file_bytes {
+        if stat.cumulative_size > *max_file_bytes {
+            return Err(format_err!(
+                "IPFS file {} is too large. It can be at most {} bytes but is {} bytes",
+                path,
+                max_file_bytes,
+                stat.cumulative_size
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Like `restrict_file_size`, for a file that we found in a cache. The
+// cumulative size that IPFS reports includes the overhead of the DAG the file
+// is stored in, and caches only hold the contents of the file; we compare
+// against the size of the contents since that is all we know.
+fn restrict_cached_file_size(
+    path: &str,
+    data: &[u8],
+    max_file_bytes: &Option<u64>,
+) -> Result<(), failure::Error> {
+    if let Some(max_file_bytes) = max_file_bytes {
+        if data.len() as u64 > *max_file_bytes {
+            return Err(format_err!(
+                "IPFS file {} is too large. It can be at most {} bytes but is {} bytes",
+                path,
+                max_file_bytes,
+                data.len()
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct LinkResolver {
+    clients: Arc<Vec<IpfsClient>>,
+    cache: Arc<Mutex<LruCache<String, Vec<u8>>>>,
+    disk_cache: Option<Arc<DiskCache>>,
+    /// Counts cache lookups by cache (`memory` or `disk`) and result (`hit`
+    /// or `miss`)
+    cache_lookups: Option<Box<CounterVec>>,
+    timeout: Duration,
+    retry: bool,
+}
+
+impl LinkResolver {
+    /// Keep IPFS files in `disk_cache` in addition to the in-memory cache.
+    pub fn with_disk_cache(mut self, disk_cache: Arc<DiskCache>) -> Self {
+        self.disk_cache = Some(disk_cache);
+        self
+    }
+
+    /// Report cache hits and misses to `registry`.
+    pub fn with_metrics(mut self, registry: Arc<dyn MetricsRegistry>) -> Self {
+        self.cache_lookups = registry
+            .new_counter_vec(
+                "ipfs_cache_lookups",
+                "Counts lookups in the IPFS caches by cache and result",
+                vec![String::from("cache"), String::from("result")],
+            )
+            .ok();
+        self
+    }
+
+    fn record_lookup(&self, cache: &str, hit: bool) {
+        if let Some(counters) = &self.cache_lookups {
+            counters
+                .with_label_values(&[cache, if hit { "hit" } else { "miss" }])
+                .inc();
+        }
+    }
+
+    fn cache_in_memory(&self, path: &str, data: &[u8]) {
+        // Only cache files if they are not too large
+        if data.len() <= *MAX_IPFS_CACHE_FILE_SIZE as usize {
+            let mut cache = self.cache.lock().unwrap();
+            if !cache.contains_key(path) {
+                cache.insert(path.to_owned(), data.to_vec());
+            }
+        }
+    }
+}
+
+impl From<IpfsClient> for LinkResolver {
+    fn from(client: IpfsClient) -> Self {
+        vec![client].into()
+    }
+}
+
+impl From<Vec<IpfsClient>> for LinkResolver {
+    fn from(clients: Vec<IpfsClient>) -> Self {
+        Self {
+            clients: Arc::new(clients),
+            cache: Arc::new(Mutex::new(LruCache::with_capacity(
+                *MAX_IPFS_CACHE_SIZE as usize,
+            ))),
+            disk_cache: None,
+            cache_lookups: None,
+            timeout: *IPFS_TIMEOUT,
+            retry: false,
+        }
+    }
+}
+
+#[async_trait]
+impl LinkResolverTrait for LinkResolver {
+    fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn with_retries(mut self) -> Self {
+        self.retry = true;
+        self
+    }
+
+    /// Supports links of the form `/ipfs/ipfs_hash` or just `ipfs_hash`.
+    async fn cat(&self, logger: &Logger, link: &Link) -> Result<Vec<u8>, Error> {
+        // Discard the `/ipfs/` prefix (if present) to get the hash.
+        let path = link.link.trim_start_matches("/ipfs/").to_owned();
+
+        // FIXME: Having an env variable here is a problem for consensus.
+        // Index Nodes should not disagree on whether the file should be read.
+        let max_file_size = max_file_bytes();
+
+        let hit = self.cache.lock().unwrap().get(&path).cloned();
+        if let Some(data) = hit {
+            trace!(logger, "IPFS cache hit"; "hash" => &path);
+            self.record_lookup("memory", true);
+            restrict_cached_file_size(&path, &data, &max_file_size)?;
+            return Ok(data);
+        }
+        trace!(logger, "IPFS cache miss"; "hash" => &path);
+        self.record_lookup("memory", false);
+
+        if let Some(disk_cache) = &self.disk_cache {
+            let hit = disk_cache.get(&path);
+            self.record_lookup("disk", hit.is_some());
+            if let Some(data) = hit {
+                trace!(logger, "IPFS disk cache hit"; "hash" => &path);
+                restrict_cached_file_size(&path, &data, &max_file_size)?;
+                self.cache_in_memory(&path, &data);
+                return Ok(data);
+            }
+        }
+
+        let (stat, client) =
+            select_fastest_client_with_stat(&self.clients, logger, &path, self.timeout, self.retry)
+                .await?;
+
+        restrict_file_size(&path, &stat, &max_file_size)?;
+
+        let path = path.clone();
+        let retry_fut = if self.retry {
+            retry("ipfs.cat", &logger).no_limit()
+        } else {
+            retry("ipfs.cat", &logger).limit(1)
+        }
+        .timeout(self.timeout);
+
+        let data = retry_fut
+            .run(move || {
+                let path = path.clone();
+                async move {
+                    let data = client
+                        .cat(&path)
+                        .map_ok(|b| BytesMut::from_iter(b.into_iter()))
+                        .try_concat()
+                        .await?
+                        .to_vec();
+
+                    self.cache_in_memory(&path, &data);
+                    Result::<Vec<u8>, Error>::Ok(data)
+                }
+                .boxed()
+                .compat()
+            })
+            .compat()
+            .await?;
+
+        if let Some(disk_cache) = &self.disk_cache {
+            // Not being able to cache the file is not a reason to fail
+            if let Err(e) = disk_cache.insert(link.link.trim_start_matches("/ipfs/"), &data) {
+                warn!(logger, "Failed to write IPFS file to disk cache";
+                      "hash" => link.link.trim_start_matches("/ipfs/"),
+                      "error" => e.to_string());
+            }
+        }
+
+        Ok(data)
+    }
+
+    async fn json_stream(
+        &self,
+        logger: &Logger,
+        link: &Link,
+        format: StreamFormat,
+    ) -> Result<JsonValueStream, Error> {
+        // Discard the `/ipfs/` prefix (if present) to get the hash.
+        let path = link.link.trim_start_matches("/ipfs/");
+
+        let (stat, client) =
+            select_fastest_client_with_stat(&self.clients, logger, path, self.timeout, self.retry)
+                .await?;
+
+        let max_file_size =
+            read_u64_from_env(MAX_IPFS_MAP_FILE_SIZE_VAR).or(Some(DEFAULT_MAX_IPFS_MAP_FILE_SIZE));
+        restrict_file_size(path, &stat, &max_file_size)?;
+
+        let chunks = client.cat(&path).map_err(Error::from);
+        Ok(json_value_stream(chunks, format))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use ipfs_api::IpfsClient;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+
+    /// Serve `files` through the parts of the IPFS HTTP API that the
+    /// resolver uses, and return a client for it. The reported sizes are
+    /// the lengths of the files themselves.
+    fn ipfs_fixture(files: Vec<(&'static str, &'static [u8])>) -> IpfsClient {
+        let files: Arc<HashMap<_, _>> = Arc::new(files.into_iter().collect());
+        let make_service = make_service_fn(move |_| {
+            let files = files.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let files = files.clone();
+                    async move { Ok::<_, Infallible>(serve_ipfs(&files, req)) }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let uri = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        IpfsClient::new_from_uri(&uri).unwrap()
+    }
+
+    fn serve_ipfs(files: &HashMap<&str, &[u8]>, req: Request<Body>) -> Response<Body> {
+        let hash = req
+            .uri()
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .find(|param| param.starts_with("arg="))
+            .map(|param| param.trim_start_matches("arg="))
+            .unwrap_or("");
+        let data = match files.get(hash) {
+            Some(data) => data,
+            None => {
+                let err = json!({ "Message": "file not found", "Code": 0, "Type": "error" });
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from(err.to_string()))
+                    .unwrap();
+            }
+        };
+        match req.uri().path() {
+            "/api/v0/object/stat" => {
+                let stat = json!({
+                    "Hash": hash,
+                    "NumLinks": 0,
+                    "BlockSize": data.len(),
+                    "LinksSize": 0,
+                    "DataSize": data.len(),
+                    "CumulativeSize": data.len(),
+                });
+                Response::new(Body::from(stat.to_string()))
+            }
+            "/api/v0/cat" => Response::new(Body::from(data.to_vec())),
+            _ => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn max_file_size() {
+        env::set_var(MAX_IPFS_FILE_SIZE_VAR, "200");
+        let client = ipfs_fixture(vec![("QmLarge", &[0u8; 201])]);
+        let resolver = super::LinkResolver::from(client);
+
+        let logger = Logger::root(slog::Discard, o!());
+
+        let link = Link::from("QmLarge".to_owned());
+        let err = LinkResolver::cat(&resolver, &logger, &link)
+            .await
+            .unwrap_err();
+        env::remove_var(MAX_IPFS_FILE_SIZE_VAR);
+        assert_eq!(
+            err.to_string(),
+            "IPFS file QmLarge is too large. It can be at most 200 bytes but is 201 bytes"
+        );
+    }
+    #[tokio::test]
+    async fn max_file_size_for_cached_files() {
+        let dir = std::env::temp_dir().join(format!("ipfs-cache-max-size-{}", std::process::id()));
+        let disk_cache = Arc::new(DiskCache::new(&dir, 1000, 1000).unwrap());
+        disk_cache.insert("QmLarge", &[0u8; 201]).unwrap();
+        let resolver = super::LinkResolver::from(Vec::new()).with_disk_cache(disk_cache);
+        let logger = Logger::root(slog::Discard, o!());
+        let link = Link::from("/ipfs/QmLarge".to_owned());
+
+        env::set_var(MAX_IPFS_FILE_SIZE_VAR, "200");
+        let err = LinkResolver::cat(&resolver, &logger, &link)
+            .await
+            .unwrap_err();
+        env::remove_var(MAX_IPFS_FILE_SIZE_VAR);
+        assert_eq!(
+            err.to_string(),
+            "IPFS file QmLarge is too large. It can be at most 200 bytes but is 201 bytes"
+        );
+
+        // Once the file is cached in memory, the limit still applies
+        LinkResolver::cat(&resolver, &logger, &link).await.unwrap();
+        env::set_var(MAX_IPFS_FILE_SIZE_VAR, "200");
+        let err = LinkResolver::cat(&resolver, &logger, &link)
+            .await
+            .unwrap_err();
+        env::remove_var(MAX_IPFS_FILE_SIZE_VAR);
+        assert!(err.to_string().contains("is too large"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    async fn json_round_trip(text: &'static str) -> Result<Vec<Value>, failure::Error> {
+        let client = ipfs_fixture(vec![("QmJson", text.as_bytes())]);
+        let resolver = super::LinkResolver::from(client);
+
+        let logger = Logger::root(slog::Discard, o!());
+        let link = "QmJson".to_owned();
+
+        let stream =
+            LinkResolver::json_stream(&resolver, &logger, &Link { link }, StreamFormat::JsonLines)
+                .await?;
+        stream.map_ok(|sv| sv.value).try_collect().await
+    }
+
+    #[tokio::test]
+    async fn read_json_stream() {
+        let values = json_round_trip("\"with newline\"\n").await;
+        assert_eq!(vec![json!("with newline")], values.unwrap());
+
+        let values = json_round_trip("\"without newline\"").await;
+        assert_eq!(vec![json!("without newline")], values.unwrap());
+
+        let values = json_round_trip("\"two\" \n \"things\"").await;
+        assert_eq!(vec![json!("two"), json!("things")], values.unwrap());
+
+        let values = json_round_trip("\"one\"\n  \"two\" \n [\"bad\" \n \"split\"]").await;
+        assert_eq!(
+            "EOF while parsing a list at line 4 column 0: ' [\"bad\" \n'",
+            values.unwrap_err().to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn ipfs_map_file_size() {
+        let file = "\"small test string that trips the size restriction\"";
+        env::set_var(MAX_IPFS_MAP_FILE_SIZE_VAR, (file.len() - 1).to_string());
+
+        let err = json_round_trip(file).await.unwrap_err();
+        env::remove_var(MAX_IPFS_MAP_FILE_SIZE_VAR);
+
+        assert!(err.to_string().contains(" is too large"));
+
+        let values = json_round_trip(file).await;
+        assert_eq!(
+            vec!["small test string that trips the size restriction"],
+            values.unwrap()
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+
+use graph::prelude::{LinkResolver as LinkResolverTrait, *};
+
+use super::stream::json_value_stream;
+use super::LinkResolver as IpfsLinkResolver;
+
+/// The fragment with which a link pins the SHA-256 hash of its contents,
+/// e.g. `https://example.com/schema.graphql#sha256=<hex digest>`
+const SHA256_FRAGMENT: &str = "sha256=";
+
+#[derive(Clone)]
+struct SchemeResolver {
+    resolver: Arc<dyn LinkResolverTrait>,
+    require_hash: bool,
+}
+
+/// A link broken up into its parts
+#[derive(Debug, PartialEq)]
+struct ParsedLink<'a> {
+    /// The URI scheme, if the link has one
+    scheme: Option<String>,
+    /// The link without the fragment that pins its hash
+    link: &'a str,
+    /// The expected SHA-256 hash of the contents of the link
+    sha256: Option<Vec<u8>>,
+}
+
+impl<'a> ParsedLink<'a> {
+    fn parse(link: &'a str) -> Result<Self, Error> {
+        let (link, sha256) = match link.rfind('#') {
+            Some(pos) => {
+                let fragment = &link[pos + 1..];
+                if !fragment.starts_with(SHA256_FRAGMENT) {
+                    return Err(format_err!(
+                        "the fragment of link `{}` must be of the form `#{}<hex digest>`",
+                        link,
+                        SHA256_FRAGMENT
+                    ));
+                }
+                let hash = hex::decode(&fragment[SHA256_FRAGMENT.len()..])
+                    .map_err(|e| format_err!("invalid SHA-256 hash in link `{}`: {}", link, e))?;
+                (&link[..pos], Some(hash))
+            }
+            None => (link, None),
+        };
+
+        let scheme = link.find("://").and_then(|pos| {
+            let scheme = &link[..pos];
+            if !scheme.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+            {
+                Some(scheme.to_ascii_lowercase())
+            } else {
+                None
+            }
+        });
+
+        Ok(ParsedLink {
+            scheme,
+            link,
+            sha256,
+        })
+    }
+}
+
+/// A `LinkResolver` that hands links to other resolvers depending on the
+/// URI scheme of the link. Links with the `ipfs://` scheme, and links
+/// without a scheme like `/ipfs/<hash>` or just `<hash>`, are resolved
+/// through IPFS; resolvers for other schemes are added with `with_scheme`.
+///
+/// Any link can pin the hash of its contents by ending in
+/// `#sha256=<hex digest>`; the contents are then checked against that hash.
+/// The settings from `with_timeout` and `with_retries` only apply to IPFS.
+#[derive(Clone)]
+pub struct SchemeLinkResolver {
+    ipfs: IpfsLinkResolver,
+    schemes: Arc<HashMap<String, SchemeResolver>>,
+}
+
+impl SchemeLinkResolver {
+    pub fn new(ipfs: IpfsLinkResolver) -> Self {
+        Self {
+            ipfs,
+            schemes: Arc::new(HashMap::new()),
+        }
+    }
+
+    /// Resolve links of the form `<scheme>://...` with `resolver`. The link
+    /// is passed to `resolver` without the fragment that pins its hash. If
+    /// `require_hash` is `true`, links for this scheme must pin the hash of
+    /// their contents.
+    pub fn with_scheme(
+        mut self,
+        scheme: &str,
+        resolver: impl LinkResolverTrait,
+        require_hash: bool,
+    ) -> Self {
+        Arc::make_mut(&mut self.schemes).insert(
+            scheme.to_ascii_lowercase(),
+            SchemeResolver {
+                resolver: Arc::new(resolver),
+                require_hash,
+            },
+        );
+        self
+    }
+
+    /// Find the resolver for `link`, and the link that should be passed to
+    /// it.
+    fn resolver(&self, link: &ParsedLink) -> Result<(&dyn LinkResolverTrait, Link), Error> {
+        match link.scheme.as_deref() {
+            None => Ok((&self.ipfs, Link::from(link.link.to_owned()))),
+            Some("ipfs") => Ok((
+                &self.ipfs,
+                Link::from(format!("/ipfs/{}", link.link.trim_start_matches("ipfs://"))),
+            )),
+            Some(scheme) => {
+                let entry = self.schemes.get(scheme).ok_or_else(|| {
+                    format_err!(
+                        "links with scheme `{}` are not supported: {}",
+                        scheme,
+                        link.link
+                    )
+                })?;
+                if entry.require_hash && link.sha256.is_none() {
+                    return Err(format_err!(
+                        "links with scheme `{}` must pin the hash of their contents \
+                         with `#{}<hex digest>`: {}",
+                        scheme,
+                        SHA256_FRAGMENT,
+                        link.link
+                    ));
+                }
+                Ok((entry.resolver.as_ref(), Link::from(link.link.to_owned())))
+            }
+        }
+    }
+}
+
+fn verify_hash(link: &ParsedLink, data: &[u8]) -> Result<(), Error> {
+    if let Some(expected) = &link.sha256 {
+        let actual = Sha256::digest(data);
+        if actual.as_slice() != expected.as_slice() {
+            return Err(format_err!(
+                "the contents of `{}` do not match the pinned hash: expected sha256 {}, got {}",
+                link.link,
+                hex::encode(expected),
+                hex::encode(actual)
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl LinkResolverTrait for SchemeLinkResolver {
+    fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.ipfs = self.ipfs.with_timeout(timeout);
+        self
+    }
+
+    fn with_retries(mut self) -> Self {
+        self.ipfs = self.ipfs.with_retries();
+        self
+    }
+
+    async fn cat(&self, logger: &Logger, link: &Link) -> Result<Vec<u8>, Error> {
+        let parsed = ParsedLink::parse(&link.link)?;
+        let (resolver, link) = self.resolver(&parsed)?;
+        let data = resolver.cat(logger, &link).await?;
+        verify_hash(&parsed, &data)?;
+        Ok(data)
+    }
+
+    async fn json_stream(
+        &self,
+        logger: &Logger,
+        link: &Link,
+        format: StreamFormat,
+    ) -> Result<JsonValueStream, Error> {
+        let parsed = ParsedLink::parse(&link.link)?;
+        let (resolver, link) = self.resolver(&parsed)?;
+        if parsed.sha256.is_none() {
+            return resolver.json_stream(logger, &link, format).await;
+        }
+
+        // We can only check the hash once we have seen all the data
+        let data = resolver.cat(logger, &link).await?;
+        verify_hash(&parsed, &data)?;
+        Ok(json_value_stream(
+            futures03::stream::iter(vec![Ok(data)]),
+            format,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileLinkResolver;
+    use std::fs;
+
+    #[test]
+    fn parse_link() {
+        let link = ParsedLink::parse("/ipfs/QmHash").unwrap();
+        assert_eq!(None, link.scheme);
+        assert_eq!("/ipfs/QmHash", link.link);
+
+        let link = ParsedLink::parse("AR://tx#sha256=00ff").unwrap();
+        assert_eq!(Some("ar".to_owned()), link.scheme);
+        assert_eq!("AR://tx", link.link);
+        assert_eq!(Some(vec![0, 255]), link.sha256);
+
+        assert!(ParsedLink::parse("https://host/file#other").is_err());
+        assert!(ParsedLink::parse("https://host/file#sha256=xyz").is_err());
+    }
+
+    #[tokio::test]
+    async fn resolve_by_scheme() {
+        let dir = std::env::temp_dir().join(format!("scheme-link-resolver-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("schema.graphql"), "type Thing").unwrap();
+
+        async fn cat(resolver: &SchemeLinkResolver, link: &str) -> Result<Vec<u8>, Error> {
+            let logger = Logger::root(slog::Discard, o!());
+            resolver.cat(&logger, &Link::from(link.to_owned())).await
+        }
+
+        let resolver = SchemeLinkResolver::new(IpfsLinkResolver::from(Vec::new())).with_scheme(
+            "file",
+            FileLinkResolver::new(&dir),
+            false,
+        );
+        let pinned = SchemeLinkResolver::new(IpfsLinkResolver::from(Vec::new())).with_scheme(
+            "file",
+            FileLinkResolver::new(&dir),
+            true,
+        );
+        let hash = hex::encode(Sha256::digest(b"type Thing"));
+
+        assert_eq!(
+            b"type Thing".to_vec(),
+            cat(&resolver, "file:///schema.graphql").await.unwrap()
+        );
+        assert_eq!(
+            b"type Thing".to_vec(),
+            cat(&pinned, &format!("file://schema.graphql#sha256={}", hash))
+                .await
+                .unwrap()
+        );
+
+        let err = cat(&pinned, "file://schema.graphql").await.unwrap_err();
+        assert!(err.to_string().contains("must pin the hash"));
+
+        let bad_hash = "00".repeat(32);
+        let err = cat(
+            &resolver,
+            &format!("file://schema.graphql#sha256={}", bad_hash),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("do not match the pinned hash"));
+
+        let err = cat(&resolver, "ar://tx").await.unwrap_err();
+        assert!(err.to_string().contains("are not supported"));
+
+        fs::remove_file(dir.join("schema.graphql")).unwrap();
+    }
+}
@@ -0,0 +1,570 @@
+use bytes::BytesMut;
+use serde_json::Value;
+
+use graph::prelude::futures03::stream::{self, Stream, StreamExt};
+use graph::prelude::{
+    format_err, Error, JsonStreamValue, JsonValueStream, MalformedRecord, StreamFormat,
+};
+
+/// Turn a stream of chunks of bytes into a stream of JSON values by splitting
+/// the bytes according to `format`. Malformed records produce a
+/// `MalformedRecord` error, after which the stream can still be polled for
+/// more values. Errors from `chunks` end the stream.
+pub(crate) fn json_value_stream<S, B>(chunks: S, format: StreamFormat) -> JsonValueStream
+where
+    S: Stream<Item = Result<B, Error>> + Send + Unpin + 'static,
+    B: AsRef<[u8]> + Send,
+{
+    let splitter = RecordSplitter::new(format);
+
+    Box::pin(stream::unfold(
+        (chunks, splitter),
+        |(mut chunks, mut splitter)| async move {
+            loop {
+                if let Some(value) = splitter.next_value() {
+                    return Some((value, (chunks, splitter)));
+                }
+                if splitter.is_done() {
+                    return None;
+                }
+                match chunks.next().await {
+                    Some(Ok(bytes)) => splitter.push(bytes.as_ref()),
+                    Some(Err(e)) => {
+                        splitter.abort();
+                        return Some((Err(e), (chunks, splitter)));
+                    }
+                    None => splitter.finish(),
+                }
+            }
+        },
+    ))
+}
+
+/// Incrementally splits bytes into records and deserializes each record
+/// into a JSON value. Bytes are added with `push`, and the end of the input
+/// is signalled with `finish`.
+pub(crate) struct RecordSplitter {
+    format: StreamFormat,
+    buf: BytesMut,
+    /// How far into `buf` we have already looked for the end of the current
+    /// record
+    scanned: usize,
+    /// The number of lines we have completely consumed. We need that to
+    /// report line numbers in error messages that refer to the overall input
+    /// rather than the snippet we are deserializing
+    line: usize,
+    /// The input has been exhausted
+    eof: bool,
+    /// No more values can be produced
+    done: bool,
+    /// Scanner state: inside a quoted CSV field or a JSON string
+    in_quotes: bool,
+    /// Scanner state: the last character inside a JSON string was a `\`
+    escaped: bool,
+    /// Scanner state: nesting depth inside a JSON array element
+    depth: usize,
+    /// We have seen the opening `[` of a JSON array
+    array_opened: bool,
+    /// The column names from the first line of a CSV file
+    header: Option<Vec<String>>,
+}
+
+impl RecordSplitter {
+    pub fn new(format: StreamFormat) -> Self {
+        RecordSplitter {
+            format,
+            buf: BytesMut::with_capacity(1024),
+            scanned: 0,
+            line: 0,
+            eof: false,
+            done: false,
+            in_quotes: false,
+            escaped: false,
+            depth: 0,
+            array_opened: false,
+            header: None,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Signal that there is no more input. Whatever is left in the buffer
+    /// is treated as the last record.
+    pub fn finish(&mut self) {
+        self.eof = true;
+    }
+
+    /// Stop producing values, e.g., because reading the input failed.
+    pub fn abort(&mut self) {
+        self.done = true;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Return the next value if the buffer contains a complete record. A
+    /// return value of `None` means that more input is needed, or, if
+    /// `is_done` is `true`, that there are no more values.
+    pub fn next_value(&mut self) -> Option<Result<JsonStreamValue, Error>> {
+        if self.done {
+            return None;
+        }
+        match self.format {
+            StreamFormat::JsonLines => self.next_json_line(),
+            StreamFormat::JsonArray => self.next_array_element(),
+            StreamFormat::Csv => self.next_csv_record(),
+        }
+    }
+
+    /// Take the next record off the front of `buf`, using `find_end` to find
+    /// where the record ends. At the end of the input, the rest of the buffer
+    /// is the last record.
+    fn take_record(&mut self, find_end: impl Fn(&mut Self) -> Option<usize>) -> Option<BytesMut> {
+        let end = match find_end(self) {
+            Some(end) => end,
+            None if self.eof && !self.buf.is_empty() => self.buf.len(),
+            None => {
+                if self.eof {
+                    self.done = true;
+                }
+                return None;
+            }
+        };
+        self.scanned = 0;
+        Some(self.buf.split_to(end))
+    }
+
+    fn next_json_line(&mut self) -> Option<Result<JsonStreamValue, Error>> {
+        loop {
+            let line_bytes = self.take_record(|splitter| {
+                match splitter.buf[splitter.scanned..]
+                    .iter()
+                    .position(|b| *b == b'\n')
+                {
+                    Some(offset) => Some(splitter.scanned + offset + 1),
+                    None => {
+                        splitter.scanned = splitter.buf.len();
+                        None
+                    }
+                }
+            })?;
+            self.line += 1;
+
+            if line_bytes.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            let line = match std::str::from_utf8(&line_bytes) {
+                Ok(line) => line,
+                Err(e) => return Some(Err(self.malformed(self.line, e.to_string()))),
+            };
+            return Some(match serde_json::from_str::<Value>(line) {
+                Ok(value) => Ok(JsonStreamValue {
+                    value,
+                    line: self.line,
+                }),
+                Err(e) => {
+                    // Adjust the line number in the serde error. This
+                    // is fun because we can only get at the full error
+                    // message, and not the error message without line number
+                    let msg = e.to_string();
+                    let msg = msg.split(" at line ").next().unwrap();
+                    Err(self.malformed(
+                        self.line,
+                        format!(
+                            "{} at line {} column {}: '{}'",
+                            msg,
+                            e.line() + self.line - 1,
+                            e.column(),
+                            line
+                        ),
+                    ))
+                }
+            });
+        }
+    }
+
+    fn next_csv_record(&mut self) -> Option<Result<JsonStreamValue, Error>> {
+        loop {
+            let record_bytes = self.take_record(|splitter| {
+                for i in splitter.scanned..splitter.buf.len() {
+                    match splitter.buf[i] {
+                        b'"' => splitter.in_quotes = !splitter.in_quotes,
+                        b'\n' if !splitter.in_quotes => return Some(i + 1),
+                        _ => {}
+                    }
+                }
+                splitter.scanned = splitter.buf.len();
+                None
+            })?;
+            self.in_quotes = false;
+            let start_line = self.line + 1;
+            self.line += record_bytes.iter().filter(|b| **b == b'\n').count();
+
+            let record = match std::str::from_utf8(&record_bytes) {
+                Ok(record) => record.trim_end_matches(|c| c == '\n' || c == '\r'),
+                Err(e) => return Some(Err(self.malformed(start_line, e.to_string()))),
+            };
+            if record.trim().is_empty() {
+                continue;
+            }
+
+            let fields = match parse_csv_record(record) {
+                Ok(fields) => fields,
+                Err(e) => {
+                    let msg = format!("{} in CSV record at line {}: '{}'", e, start_line, record);
+                    if self.header.is_none() {
+                        // Without a header, none of the following records
+                        // can be turned into objects
+                        self.done = true;
+                        return Some(Err(format_err!("{}", msg)));
+                    }
+                    return Some(Err(self.malformed(start_line, msg)));
+                }
+            };
+
+            let header = match &self.header {
+                Some(header) => header,
+                None => {
+                    self.header = Some(fields);
+                    continue;
+                }
+            };
+
+            if fields.len() != header.len() {
+                let msg = format!(
+                    "CSV record at line {} has {} fields but the header has {}: '{}'",
+                    start_line,
+                    fields.len(),
+                    header.len(),
+                    record
+                );
+                return Some(Err(self.malformed(start_line, msg)));
+            }
+
+            let value = header
+                .iter()
+                .cloned()
+                .zip(fields.into_iter().map(Value::String))
+                .collect::<serde_json::Map<_, _>>();
+            return Some(Ok(JsonStreamValue {
+                value: Value::Object(value),
+                line: start_line,
+            }));
+        }
+    }
+
+    fn next_array_element(&mut self) -> Option<Result<JsonStreamValue, Error>> {
+        self.skip_whitespace();
+
+        if self.buf.is_empty() {
+            if self.eof {
+                self.done = true;
+                if self.array_opened {
+                    return Some(Err(format_err!(
+                        "unexpected end of input in JSON array at line {}",
+                        self.line + 1
+                    )));
+                }
+            }
+            return None;
+        }
+
+        if !self.array_opened {
+            if self.buf[0] != b'[' {
+                self.done = true;
+                return Some(Err(format_err!(
+                    "expected a JSON array at line {}",
+                    self.line + 1
+                )));
+            }
+            self.array_opened = true;
+            let _ = self.buf.split_to(1);
+            return self.next_array_element();
+        }
+
+        if self.buf[0] == b']' {
+            self.done = true;
+            return None;
+        }
+
+        let mut end = None;
+        for i in self.scanned..self.buf.len() {
+            let b = self.buf[i];
+            if self.in_quotes {
+                if self.escaped {
+                    self.escaped = false;
+                } else if b == b'\\' {
+                    self.escaped = true;
+                } else if b == b'"' {
+                    self.in_quotes = false;
+                }
+                continue;
+            }
+            match b {
+                b'"' => self.in_quotes = true,
+                b'[' | b'{' => self.depth += 1,
+                b']' | b'}' if self.depth > 0 => self.depth -= 1,
+                b',' | b']' => {
+                    end = Some(i);
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        let end = match end {
+            Some(end) => end,
+            None => {
+                self.scanned = self.buf.len();
+                if self.eof {
+                    self.done = true;
+                    return Some(Err(format_err!(
+                        "unexpected end of input in JSON array at line {}",
+                        self.line + 1
+                    )));
+                }
+                return None;
+            }
+        };
+
+        let element = self.buf.split_to(end);
+        let delimiter = self.buf.split_to(1);
+        self.scanned = 0;
+        self.in_quotes = false;
+        self.escaped = false;
+        self.depth = 0;
+        if delimiter[0] == b']' {
+            self.done = true;
+        }
+
+        let start_line = self.line + 1;
+        self.line += element.iter().filter(|b| **b == b'\n').count();
+
+        let element = match std::str::from_utf8(&element) {
+            Ok(element) => element,
+            Err(e) => return Some(Err(self.malformed(start_line, e.to_string()))),
+        };
+        Some(match serde_json::from_str::<Value>(element) {
+            Ok(value) => Ok(JsonStreamValue {
+                value,
+                line: start_line,
+            }),
+            Err(e) => {
+                let msg = e.to_string();
+                let msg = msg.split(" at line ").next().unwrap();
+                Err(self.malformed(
+                    start_line,
+                    format!(
+                        "{} at line {} column {}: '{}'",
+                        msg,
+                        e.line() + start_line - 1,
+                        e.column(),
+                        element.trim()
+                    ),
+                ))
+            }
+        })
+    }
+
+    /// Remove leading whitespace from `buf`, keeping track of the lines we
+    /// skip over
+    fn skip_whitespace(&mut self) {
+        let n = self
+            .buf
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(self.buf.len());
+        if n > 0 {
+            let ws = self.buf.split_to(n);
+            self.line += ws.iter().filter(|b| **b == b'\n').count();
+            self.scanned = self.scanned.saturating_sub(n);
+        }
+    }
+
+    fn malformed(&self, line: usize, message: String) -> Error {
+        MalformedRecord { line, message }.into()
+    }
+}
+
+/// Split one CSV record into its fields. Fields can be quoted with `"`, and
+/// quoted fields may contain commas, newlines and `""` for a literal quote.
+fn parse_csv_record(record: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = record.chars().peekable();
+    let mut quoted = false;
+    let mut at_start = true;
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                    match chars.peek() {
+                        None | Some(',') => {}
+                        Some(c) => {
+                            return Err(format!("unexpected character `{}` after closing quote", c))
+                        }
+                    }
+                }
+            } else {
+                field.push(c);
+            }
+        } else {
+            match c {
+                ',' => {
+                    fields.push(std::mem::take(&mut field));
+                    at_start = true;
+                    continue;
+                }
+                '"' if at_start => quoted = true,
+                '"' => return Err("unexpected quote in unquoted field".to_owned()),
+                c => field.push(c),
+            }
+        }
+        at_start = false;
+    }
+
+    if quoted {
+        return Err("unterminated quoted field".to_owned());
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Feed `text` to a splitter in chunks of `chunk_size` bytes and collect
+    /// the values, or the error messages for malformed records
+    fn split(text: &str, format: StreamFormat, chunk_size: usize) -> Vec<Result<Value, String>> {
+        let mut splitter = RecordSplitter::new(format);
+        let mut values = Vec::new();
+        let mut chunks = text.as_bytes().chunks(chunk_size);
+        loop {
+            while let Some(value) = splitter.next_value() {
+                values.push(value.map(|sv| sv.value).map_err(|e| e.to_string()));
+            }
+            if splitter.is_done() {
+                return values;
+            }
+            match chunks.next() {
+                Some(chunk) => splitter.push(chunk),
+                None => splitter.finish(),
+            }
+        }
+    }
+
+    fn split_all_chunk_sizes(text: &str, format: StreamFormat) -> Vec<Result<Value, String>> {
+        let expected = split(text, format, text.len().max(1));
+        for chunk_size in 1..text.len() {
+            assert_eq!(
+                expected,
+                split(text, format, chunk_size),
+                "chunk size {}",
+                chunk_size
+            );
+        }
+        expected
+    }
+
+    #[test]
+    fn json_lines() {
+        let values = split_all_chunk_sizes("1\n\n{\"a\": [1, 2]}\n\"x\"", StreamFormat::JsonLines);
+        assert_eq!(
+            vec![Ok(json!(1)), Ok(json!({"a": [1, 2]})), Ok(json!("x"))],
+            values
+        );
+
+        let values = split_all_chunk_sizes("1\n[\n2\n", StreamFormat::JsonLines);
+        assert_eq!(
+            vec![
+                Ok(json!(1)),
+                Err("EOF while parsing a list at line 3 column 0: '[\n'".to_owned()),
+                Ok(json!(2))
+            ],
+            values
+        );
+    }
+
+    #[test]
+    fn json_array() {
+        let values = split_all_chunk_sizes(
+            " [ {\"a\": \"b,]\\\"\"},\n [1, [2]], \"x\" ]  ",
+            StreamFormat::JsonArray,
+        );
+        assert_eq!(
+            vec![
+                Ok(json!({"a": "b,]\""})),
+                Ok(json!([1, [2]])),
+                Ok(json!("x"))
+            ],
+            values
+        );
+
+        assert!(split_all_chunk_sizes("[]", StreamFormat::JsonArray).is_empty());
+        assert!(split_all_chunk_sizes("", StreamFormat::JsonArray).is_empty());
+
+        let values = split_all_chunk_sizes("[1, nope, 3]", StreamFormat::JsonArray);
+        assert_eq!(Ok(json!(1)), values[0]);
+        assert!(values[1].as_ref().unwrap_err().contains("'nope'"));
+        assert_eq!(Ok(json!(3)), values[2]);
+
+        let values = split_all_chunk_sizes("{\"a\": 1}", StreamFormat::JsonArray);
+        assert_eq!(
+            vec![Err("expected a JSON array at line 1".to_owned())],
+            values
+        );
+
+        let values = split_all_chunk_sizes("[1,\n 2", StreamFormat::JsonArray);
+        assert_eq!(
+            vec![
+                Ok(json!(1)),
+                Err("unexpected end of input in JSON array at line 2".to_owned())
+            ],
+            values
+        );
+    }
+
+    #[test]
+    fn csv() {
+        let values = split_all_chunk_sizes(
+            "id,name\r\n1,\"Doe, \"\"J\"\"\nsecond line\"\n\n2,\n",
+            StreamFormat::Csv,
+        );
+        assert_eq!(
+            vec![
+                Ok(json!({"id": "1", "name": "Doe, \"J\"\nsecond line"})),
+                Ok(json!({"id": "2", "name": ""}))
+            ],
+            values
+        );
+
+        let values = split_all_chunk_sizes("id,name\n1\n2,b", StreamFormat::Csv);
+        assert_eq!(
+            vec![
+                Err("CSV record at line 2 has 1 fields but the header has 2: '1'".to_owned()),
+                Ok(json!({"id": "2", "name": "b"}))
+            ],
+            values
+        );
+
+        let values = split_all_chunk_sizes("id\n\"1\"x", StreamFormat::Csv);
+        assert_eq!(
+            vec![Err(
+                "unexpected character `x` after closing quote in CSV record at line 2: '\"1\"x'"
+                    .to_owned()
+            )],
+            values
+        );
+    }
+}
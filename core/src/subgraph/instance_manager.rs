@@ -6,7 +6,7 @@ use std::sync::{Arc, RwLock};
 use std::time::Instant;
 
 use graph::components::ethereum::{triggers_in_block, EthereumNetworks};
-use graph::components::store::ModificationsAndCache;
+use graph::components::store::{EntityTypeStats, ModificationsAndCache};
 use graph::components::subgraph::{MappingError, ProofOfIndexing, SharedProofOfIndexing};
 use graph::data::store::scalar::Bytes;
 use graph::data::subgraph::schema::{
@@ -26,6 +26,21 @@ lazy_static! {
             .unwrap_or("10000".into())
             .parse::<usize>()
             .expect("invalid GRAPH_ENTITY_CACHE_SIZE");
+
+    /// How often to update the entity count and table size metrics of a
+    /// subgraph, in seconds. Collecting them scans all of the subgraph's
+    /// tables. Setting this to 0 turns the metrics off.
+    static ref ENTITY_STATS_INTERVAL: Option<Duration> = {
+        let secs = std::env::var("GRAPH_ENTITY_STATS_INTERVAL")
+            .unwrap_or("3600".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_ENTITY_STATS_INTERVAL");
+        if secs == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(secs))
+        }
+    };
 }
 
 type SharedInstanceKeepAliveMap = Arc<RwLock<HashMap<SubgraphDeploymentId, CancelGuard>>>;
@@ -114,6 +129,12 @@ struct SubgraphInstanceMetrics {
     pub block_ops_transaction_duration: Box<Histogram>,
 
     trigger_processing_duration: Box<HistogramVec>,
+
+    entity_count: Box<GaugeVec>,
+    entity_versions: Box<GaugeVec>,
+    entity_history_ratio: Box<GaugeVec>,
+    table_size: Box<GaugeVec>,
+    index_size: Box<GaugeVec>,
 }
 
 impl SubgraphInstanceMetrics {
@@ -151,12 +172,47 @@ impl SubgraphInstanceMetrics {
                 vec![0.01, 0.05, 0.1, 0.3, 0.7, 2.0],
             )
             .expect("failed to create `deployment_transact_block_operations_duration_{}");
+        let entity_gauge = |name: &str, help: &str| {
+            registry
+                .new_deployment_gauge_vec(
+                    name,
+                    help,
+                    subgraph_hash,
+                    vec![String::from("entity_type")],
+                )
+                .expect(&format!("failed to create `{}` gauge", name))
+        };
+        let entity_count = entity_gauge(
+            "deployment_entity_count",
+            "Counts the entities of each type in a subgraph deployment",
+        );
+        let entity_versions = entity_gauge(
+            "deployment_entity_versions",
+            "Counts the entity versions of each type in a subgraph deployment, including historical ones",
+        );
+        let entity_history_ratio = entity_gauge(
+            "deployment_entity_history_ratio",
+            "The number of historical versions per entity of each type in a subgraph deployment",
+        );
+        let table_size = entity_gauge(
+            "deployment_table_size_bytes",
+            "The size of the table for each entity type in a subgraph deployment",
+        );
+        let index_size = entity_gauge(
+            "deployment_index_size_bytes",
+            "The size of the indexes for each entity type in a subgraph deployment",
+        );
 
         Self {
             block_trigger_count,
             block_processing_duration,
             trigger_processing_duration,
             block_ops_transaction_duration,
+            entity_count,
+            entity_versions,
+            entity_history_ratio,
+            table_size,
+            index_size,
         }
     }
 
@@ -166,11 +222,37 @@ impl SubgraphInstanceMetrics {
             .observe(duration);
     }
 
+    pub fn observe_entity_stats(&self, stats: &[EntityTypeStats]) {
+        for stats in stats {
+            let labels = vec![stats.entity_type.as_str()];
+            self.entity_count
+                .with_label_values(labels.as_slice())
+                .set(stats.entities as f64);
+            self.entity_versions
+                .with_label_values(labels.as_slice())
+                .set(stats.versions as f64);
+            self.entity_history_ratio
+                .with_label_values(labels.as_slice())
+                .set(stats.history_ratio().unwrap_or(0.0));
+            self.table_size
+                .with_label_values(labels.as_slice())
+                .set(stats.table_size as f64);
+            self.index_size
+                .with_label_values(labels.as_slice())
+                .set(stats.index_size as f64);
+        }
+    }
+
     pub fn unregister<M: MetricsRegistry>(&self, registry: Arc<M>) {
         registry.unregister(self.block_processing_duration.clone());
         registry.unregister(self.block_trigger_count.clone());
         registry.unregister(self.trigger_processing_duration.clone());
         registry.unregister(self.block_ops_transaction_duration.clone());
+        registry.unregister(self.entity_count.clone());
+        registry.unregister(self.entity_versions.clone());
+        registry.unregister(self.entity_history_ratio.clone());
+        registry.unregister(self.table_size.clone());
+        registry.unregister(self.index_size.clone());
     }
 }
 
@@ -184,6 +266,7 @@ impl SubgraphInstanceManager {
         block_stream_builder: B,
         metrics_registry: Arc<M>,
         graphql_runner: Arc<impl GraphQlRunner>,
+        host_metrics: SharedHostMetrics,
     ) -> Self
     where
         S: Store + ChainStore + SubgraphDeploymentStore + EthereumCallCache,
@@ -206,6 +289,7 @@ impl SubgraphInstanceManager {
             block_stream_builder,
             metrics_registry.clone(),
             graphql_runner,
+            host_metrics,
         );
 
         SubgraphInstanceManager {
@@ -224,6 +308,7 @@ impl SubgraphInstanceManager {
         block_stream_builder: B,
         metrics_registry: Arc<M>,
         graphql_runner: Arc<impl GraphQlRunner>,
+        host_metrics: SharedHostMetrics,
     ) where
         S: Store + ChainStore + SubgraphDeploymentStore + EthereumCallCache,
         B: BlockStreamBuilder,
@@ -279,6 +364,7 @@ impl SubgraphInstanceManager {
                             manifest,
                             metrics_registry_for_subgraph.clone(),
                             graphql_runner.clone(),
+                            host_metrics.cheap_clone(),
                         )
                         .await
                         {
@@ -295,7 +381,7 @@ impl SubgraphInstanceManager {
                         let logger = logger_factory.subgraph_logger(&id);
                         info!(logger, "Stop subgraph");
 
-                        Self::stop_subgraph(instances.clone(), id);
+                        Self::stop_subgraph(instances.clone(), &host_metrics, id);
                         manager_metrics.subgraph_count.dec();
                     }
                 };
@@ -313,6 +399,7 @@ impl SubgraphInstanceManager {
         manifest: SubgraphManifest,
         registry: Arc<M>,
         graphql_runner: Arc<impl GraphQlRunner>,
+        shared_host_metrics: SharedHostMetrics,
     ) -> Result<(), Error>
     where
         B: BlockStreamBuilder,
@@ -338,7 +425,18 @@ impl SubgraphInstanceManager {
                 SubgraphHealth::Healthy | SubgraphHealth::Unhealthy => vec![],
             }
         };
-        store.start_subgraph_deployment(&logger, &manifest.id, status_ops)?;
+        // Starting a grafted subgraph copies the data of the graft base,
+        // which can take a very long time
+        {
+            let logger = logger.clone();
+            let store = store.cheap_clone();
+            let id = manifest.id.clone();
+            graph::spawn_blocking_allow_panic(move || {
+                store.start_subgraph_deployment(&logger, &id, status_ops)
+            })
+            .await
+            .map_err(|e| format_err!("failed to start subgraph {}: {}", manifest.id, e))??;
+        }
 
         let mut templates: Vec<DataSourceTemplate> = vec![];
         for data_source in manifest.data_sources.iter() {
@@ -378,6 +476,7 @@ impl SubgraphInstanceManager {
             deployment_id.as_str(),
             stopwatch_metrics.clone(),
         ));
+        shared_host_metrics.insert(deployment_id.clone(), host_metrics.cheap_clone());
         let ethrpc_metrics = Arc::new(SubgraphEthRpcMetrics::new(registry.clone(), &deployment_id));
         let block_stream_metrics = Arc::new(BlockStreamMetrics::new(
             registry.clone(),
@@ -422,16 +521,29 @@ impl SubgraphInstanceManager {
         // forward; this is easier than updating the existing block stream.
         //
         // This task has many calls to the store, so mark it as `blocking`.
+        let store = ctx.inputs.store.clone();
+        let stop_logger = ctx.state.logger.clone();
         graph::spawn_blocking(async move {
             let res = run_subgraph(ctx).await;
             subgraph_metrics_unregister.unregister(registry);
+            // Nothing gets written for the subgraph anymore; finish writing
+            // what is queued so the writer does not linger
+            if let Err(e) = store.stop_writes(&deployment_id) {
+                error!(stop_logger, "Failed to write queued blocks: {}", e);
+            }
             res
         });
 
         Ok(())
     }
 
-    fn stop_subgraph(instances: SharedInstanceKeepAliveMap, id: SubgraphDeploymentId) {
+    fn stop_subgraph(
+        instances: SharedInstanceKeepAliveMap,
+        host_metrics: &SharedHostMetrics,
+        id: SubgraphDeploymentId,
+    ) {
+        host_metrics.remove(&id);
+
         // Drop the cancel guard to shut down the subgraph now
         let mut instances = instances.write().unwrap();
         instances.remove(&id);
@@ -461,6 +573,9 @@ where
     let store_for_err = ctx.inputs.store.cheap_clone();
     let logger = ctx.state.logger.cheap_clone();
     let id_for_err = ctx.inputs.deployment_id.clone();
+    // Do not collect entity statistics right away; they are not interesting
+    // for deployments that fail or get restarted soon after they start
+    let mut entity_stats_updated = Instant::now();
 
     loop {
         debug!(logger, "Starting or restarting subgraph");
@@ -542,6 +657,19 @@ where
             match res {
                 Ok((c, needs_restart)) => {
                     ctx = c;
+
+                    if let Some(interval) = *ENTITY_STATS_INTERVAL {
+                        if entity_stats_updated.elapsed() >= interval {
+                            entity_stats_updated = Instant::now();
+                            update_entity_stats(
+                                &logger,
+                                ctx.inputs.store.cheap_clone(),
+                                ctx.inputs.deployment_id.clone(),
+                                subgraph_metrics.cheap_clone(),
+                            );
+                        }
+                    }
+
                     if needs_restart {
                         // Cancel the stream for real
                         ctx.state
@@ -571,10 +699,34 @@ where
                         "code" => LogCode::SubgraphSyncingFailure
                     );
 
+                    // Blocks are written in the background, and the error
+                    // might be about an earlier block than the current one
+                    let mut failed_block = match e.downcast_ref::<StoreError>() {
+                        Some(StoreError::WriteFailed(_, failed_block, _)) => *failed_block,
+                        _ => block_ptr,
+                    };
+                    let mut message = e.to_string();
+
+                    // Blocks before the failed one might still be waiting to
+                    // be written
+                    if let Err(e) = store_for_err.flush_writes(&id_for_err) {
+                        error!(
+                            &logger,
+                            "Failed to write blocks before the failed block: {}", e;
+                            "id" => id_for_err.to_string(),
+                        );
+                        if let StoreError::WriteFailed(_, earlier, _) = e {
+                            if earlier.number < failed_block.number {
+                                failed_block = earlier;
+                                message = e.to_string();
+                            }
+                        }
+                    }
+
                     let error = SubgraphError {
                         subgraph_id: id_for_err.clone(),
-                        message: e.to_string(),
-                        block_ptr: Some(block_ptr),
+                        message,
+                        block_ptr: Some(failed_block),
                         handler: None,
                     };
 
@@ -595,6 +747,21 @@ where
     }
 }
 
+/// Collect estimated entity statistics for the subgraph in the background
+/// and report them as metrics
+fn update_entity_stats<S: Store>(
+    logger: &Logger,
+    store: Arc<S>,
+    id: SubgraphDeploymentId,
+    metrics: Arc<SubgraphInstanceMetrics>,
+) {
+    let logger = logger.cheap_clone();
+    graph::spawn_blocking_allow_panic(move || match store.estimated_entity_stats(&id) {
+        Ok(stats) => metrics.observe_entity_stats(&stats),
+        Err(e) => warn!(logger, "Failed to collect entity statistics"; "error" => e.to_string()),
+    });
+}
+
 /// Processes a block and returns the updated context and a boolean flag indicating
 /// whether new dynamic data sources have been added to the subgraph.
 async fn process_block<B: BlockStreamBuilder, T: RuntimeHostBuilder, S>(
@@ -828,18 +995,14 @@ where
         .store
         .transact_block_operations(subgraph_id, block_ptr_after, mods, stopwatch)
     {
-        Ok(should_migrate) => {
+        Ok(()) => {
             let elapsed = start.elapsed().as_secs_f64();
             metrics.block_ops_transaction_duration.observe(elapsed);
-            if should_migrate {
-                ctx.inputs.store.migrate_subgraph_deployment(
-                    &logger,
-                    &ctx.inputs.deployment_id,
-                    &block_ptr_after,
-                );
-            }
             Ok((ctx, needs_restart))
         }
+        // Keep the pointer of the block that could not be written so
+        // that the subgraph is marked as failed at that block
+        Err(e @ StoreError::WriteFailed(..)) => Err(Error::from(e).into()),
         Err(e) => {
             Err(format_err!("Error while processing block stream for a subgraph: {}", e).into())
         }
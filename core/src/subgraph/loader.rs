@@ -184,6 +184,10 @@ where
     ) -> Result<Vec<DataSource>, Error> {
         let start_time = Instant::now();
 
+        // Dynamic data sources from blocks that are still queued are not
+        // visible to queries yet
+        self.store.flush_writes(&deployment_id)?;
+
         let mut data_sources = vec![];
 
         loop {
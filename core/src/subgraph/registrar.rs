@@ -5,11 +5,12 @@ use std::{env, iter};
 use async_trait::async_trait;
 use lazy_static::lazy_static;
 
-use graph::components::ethereum::EthereumNetworks;
+use graph::components::ethereum::{EthereumNetworks, NodeCapabilities};
 use graph::data::subgraph::schema::{
     generate_entity_id, SubgraphDeploymentAssignmentEntity, SubgraphDeploymentEntity,
     SubgraphEntity, SubgraphVersionEntity, TypedEntity,
 };
+use graph::data::subgraph::UnresolvedSchema;
 use graph::prelude::{
     CreateSubgraphResult, SubgraphAssignmentProvider as SubgraphAssignmentProviderTrait,
     SubgraphRegistrar as SubgraphRegistrarTrait, *,
@@ -82,6 +83,7 @@ where
         let logger_clone1 = self.logger.clone();
         let logger_clone2 = self.logger.clone();
         let provider = self.provider.clone();
+        let store = self.store.clone();
         let node_id = self.node_id.clone();
         let assignment_event_stream_cancel_handle =
             self.assignment_event_stream_cancel_guard.handle();
@@ -127,6 +129,7 @@ where
                         handle_assignment_event(
                             assignment_event,
                             provider.clone(),
+                            store.clone(),
                             logger_clone1.clone(),
                         )
                         .boxed()
@@ -194,6 +197,9 @@ where
                                         if let Some(entity) = entity_opt {
                                             if entity.get("nodeId")
                                                 == Some(&node_id.to_string().into())
+                                                && !SubgraphDeploymentAssignmentEntity::is_paused(
+                                                    &entity,
+                                                )
                                             {
                                                 // Start subgraph on this node
                                                 Box::new(stream::once(Ok(AssignmentEvent::Add {
@@ -201,7 +207,8 @@ where
                                                     node_id: node_id.clone(),
                                                 })))
                                             } else {
-                                                // Ensure it is removed from this node
+                                                // Ensure it is removed from this node, or
+                                                // stopped if it was paused
                                                 Box::new(stream::once(Ok(
                                                     AssignmentEvent::Remove {
                                                         subgraph_id: subgraph_hash,
@@ -245,6 +252,9 @@ where
             .and_then(move |assignment_entities| {
                 assignment_entities
                     .into_iter()
+                    .filter(|assignment_entity| {
+                        !SubgraphDeploymentAssignmentEntity::is_paused(assignment_entity)
+                    })
                     .map(|assignment_entity| {
                         // Parse as subgraph hash
                         assignment_entity.id().and_then(|id| {
@@ -368,11 +378,314 @@ where
     ) -> Result<(), SubgraphRegistrarError> {
         reassign_subgraph(self.store.clone(), hash, node_id)
     }
+
+    async fn subgraph_names(
+        &self,
+        hash: &SubgraphDeploymentId,
+    ) -> Result<Vec<String>, SubgraphRegistrarError> {
+        Ok(self.store.subgraph_names(hash)?)
+    }
+
+    async fn pause_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Result<(), SubgraphRegistrarError> {
+        set_paused(self.store.clone(), hash, true)
+    }
+
+    async fn resume_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Result<(), SubgraphRegistrarError> {
+        set_paused(self.store.clone(), hash, false)
+    }
+
+    async fn rewind_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<(), SubgraphRegistrarError> {
+        let logger = self.logger_factory.subgraph_logger(&hash);
+
+        // The deployment must not index while we rewind it. Deployments
+        // that are not assigned to any node do not index. A paused
+        // deployment might still be finishing a block; the store waits for
+        // a block that is being written and refuses the ones that the
+        // deployment processed before the rewind but writes afterwards
+        match current_assignment(self.store.as_ref(), &hash) {
+            Ok((_, true)) | Err(SubgraphRegistrarError::DeploymentNotFound(_)) => (),
+            Ok((_, false)) => {
+                return Err(SubgraphRegistrarError::DeploymentNotPaused(
+                    hash.to_string(),
+                ))
+            }
+            Err(e) => return Err(e),
+        }
+
+        let block_ptr = self.block_pointer(&logger, &hash, block).await?;
+        info!(logger, "Rewinding subgraph"; "block" => block_ptr.number);
+
+        let store = self.store.clone();
+        // Reverting many blocks can take a long time
+        graph::spawn_blocking_allow_panic(move || store.rewind(&hash, block_ptr))
+            .await
+            .map_err(|e| SubgraphRegistrarError::Unknown(format_err!("{}", e)))?
+            .map_err(SubgraphRegistrarError::from)
+    }
+
+    async fn reindex_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Result<(), SubgraphRegistrarError> {
+        reindex_subgraph(self.store.clone(), hash)
+    }
+
+    async fn set_history_blocks(
+        &self,
+        hash: SubgraphDeploymentId,
+        history_blocks: Option<BlockNumber>,
+    ) -> Result<(), SubgraphRegistrarError> {
+        Ok(self.store.set_history_blocks(&hash, history_blocks)?)
+    }
+
+    async fn create_index(
+        &self,
+        hash: SubgraphDeploymentId,
+        entity_type: String,
+        fields: Vec<String>,
+    ) -> Result<String, SubgraphRegistrarError> {
+        let store = self.store.clone();
+        // Building the index can take a long time
+        graph::spawn_blocking_allow_panic(move || store.create_index(&hash, &entity_type, fields))
+            .await
+            .map_err(|e| SubgraphRegistrarError::Unknown(format_err!("{}", e)))?
+            .map_err(SubgraphRegistrarError::from)
+    }
+
+    async fn drop_index(
+        &self,
+        hash: SubgraphDeploymentId,
+        index_name: String,
+    ) -> Result<(), SubgraphRegistrarError> {
+        let store = self.store.clone();
+        graph::spawn_blocking_allow_panic(move || store.drop_index(&hash, &index_name))
+            .await
+            .map_err(|e| SubgraphRegistrarError::Unknown(format_err!("{}", e)))?
+            .map_err(SubgraphRegistrarError::from)
+    }
+
+    async fn evolve_schema(
+        &self,
+        hash: SubgraphDeploymentId,
+        schema: String,
+    ) -> Result<(), SubgraphRegistrarError> {
+        let logger = self.logger_factory.subgraph_logger(&hash);
+
+        let schema = UnresolvedSchema {
+            file: Link {
+                link: format!("/ipfs/{}", schema),
+            },
+        }
+        .resolve(hash.clone(), self.resolver.as_ref(), &logger)
+        .await?;
+        let (schemas, _) = schema.resolve_schema_references(self.store.clone());
+        schema.validate(&schemas).map_err(|errors| {
+            SubgraphRegistrarError::ManifestValidationError(vec![
+                SubgraphManifestValidationError::SchemaValidationError(errors),
+            ])
+        })?;
+
+        // The deployment must not index while we change its tables. We can
+        // only stop it if it is running on this node
+        let node_id = self
+            .assigned_node(&hash)?
+            .ok_or_else(|| SubgraphRegistrarError::DeploymentNotFound(hash.to_string()))?;
+        if node_id != self.node_id.to_string() {
+            return Err(SubgraphRegistrarError::Unknown(format_err!(
+                "deployment {} is assigned to node {}; reassign it to node {} \
+                 to change its schema",
+                hash,
+                node_id,
+                self.node_id
+            )));
+        }
+        match self.provider.stop(hash.clone()).await {
+            Ok(()) | Err(SubgraphAssignmentProviderError::NotRunning(_)) => (),
+            Err(e) => return Err(SubgraphRegistrarError::Unknown(e.into())),
+        }
+
+        let store = self.store.clone();
+        let id = hash.clone();
+        // Changing the tables can take a long time
+        let result =
+            graph::spawn_blocking_allow_panic(move || store.evolve_deployment_schema(&id, &schema))
+                .await
+                .map_err(|e| SubgraphRegistrarError::Unknown(format_err!("{}", e)))?
+                .map_err(SubgraphRegistrarError::from);
+
+        // Resume indexing whether the change succeeded or not
+        start_subgraph(hash, self.provider.clone(), logger).await;
+        result
+    }
+
+    async fn move_deployment(
+        &self,
+        hash: SubgraphDeploymentId,
+        shard: String,
+    ) -> Result<(), SubgraphRegistrarError> {
+        let logger = self.logger_factory.subgraph_logger(&hash);
+
+        // Switching over to the new shard requires that the deployment is
+        // not indexing. We can only stop it if it is running on this node
+        let node_id = self.assigned_node(&hash)?;
+        if let Some(node_id) = &node_id {
+            if node_id != &self.node_id.to_string() {
+                return Err(SubgraphRegistrarError::Unknown(format_err!(
+                    "deployment {} is assigned to node {}; reassign it to node {} \
+                     to move it",
+                    hash,
+                    node_id,
+                    self.node_id
+                )));
+            }
+        }
+
+        let store = self.store.clone();
+        let id = hash.clone();
+        graph::spawn_blocking_allow_panic(move || store.start_deployment_move(&id, &shard))
+            .await
+            .map_err(|e| SubgraphRegistrarError::Unknown(format_err!("{}", e)))??;
+
+        // Copying the data can take a very long time, and we do that in the
+        // background
+        let store = self.store.clone();
+        let provider = self.provider.clone();
+        let running = node_id.is_some();
+        graph::spawn(async move {
+            if let Err(e) = move_deployment(&logger, store, provider, hash, running).await {
+                error!(logger, "Moving the deployment failed"; "error" => e.to_string());
+            }
+        });
+        Ok(())
+    }
+
+    async fn rollback_move(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Result<(), SubgraphRegistrarError> {
+        let logger = self.logger_factory.subgraph_logger(&hash);
+
+        // The deployment must not index while we switch it back. We can
+        // only stop it if it is running on this node
+        let node_id = self.assigned_node(&hash)?;
+        if let Some(node_id) = &node_id {
+            if node_id != &self.node_id.to_string() {
+                return Err(SubgraphRegistrarError::Unknown(format_err!(
+                    "deployment {} is assigned to node {}; reassign it to node {} \
+                     to roll back its move",
+                    hash,
+                    node_id,
+                    self.node_id
+                )));
+            }
+            match self.provider.stop(hash.clone()).await {
+                Ok(()) | Err(SubgraphAssignmentProviderError::NotRunning(_)) => (),
+                Err(e) => return Err(SubgraphRegistrarError::Unknown(e.into())),
+            }
+        }
+
+        let store = self.store.clone();
+        let id = hash.clone();
+        let rollback_logger = logger.clone();
+        let result = graph::spawn_blocking_allow_panic(move || {
+            store.rollback_deployment_move(&rollback_logger, &id)
+        })
+        .await
+        .map_err(|e| SubgraphRegistrarError::Unknown(format_err!("{}", e)))?
+        .map_err(SubgraphRegistrarError::from);
+
+        // Resume indexing whether the rollback succeeded or not
+        if node_id.is_some() {
+            start_subgraph(hash, self.provider.clone(), logger).await;
+        }
+        result
+    }
+
+    async fn finalize_move(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Result<(), SubgraphRegistrarError> {
+        let store = self.store.clone();
+        graph::spawn_blocking_allow_panic(move || store.drop_retired_deployment_data(&hash))
+            .await
+            .map_err(|e| SubgraphRegistrarError::Unknown(format_err!("{}", e)))?
+            .map_err(SubgraphRegistrarError::from)
+    }
+}
+
+impl<L, P, S, CS> SubgraphRegistrar<L, P, S, CS>
+where
+    L: LinkResolver,
+    P: SubgraphAssignmentProviderTrait,
+    S: Store + SubgraphDeploymentStore,
+    CS: ChainStore,
+{
+    /// The id of the node the deployment `hash` is assigned to, or `None`
+    /// if it is not assigned to any node
+    fn assigned_node(
+        &self,
+        hash: &SubgraphDeploymentId,
+    ) -> Result<Option<String>, SubgraphRegistrarError> {
+        Ok(self
+            .store
+            .find(
+                SubgraphDeploymentAssignmentEntity::query()
+                    .filter(EntityFilter::new_equal("id", hash.to_string())),
+            )?
+            .first()
+            .and_then(|assignment| assignment.get("nodeId"))
+            .map(|node_id| node_id.to_string()))
+    }
+
+    /// Look up the pointer for block number `block` on the network that the
+    /// deployment `hash` indexes
+    async fn block_pointer(
+        &self,
+        logger: &Logger,
+        hash: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<EthereumBlockPointer, SubgraphRegistrarError> {
+        let network_name = self
+            .store
+            .network_name(hash)?
+            .ok_or_else(|| format_err!("deployment {} does not index any network", hash))?;
+        let chain_store = self
+            .chain_stores
+            .get(&network_name)
+            .ok_or_else(|| SubgraphRegistrarError::NetworkNotSupported(network_name.clone()))?;
+        let ethereum_adapter = self.ethereum_networks.adapter_with_capabilities(
+            network_name,
+            &NodeCapabilities {
+                archive: false,
+                traces: false,
+            },
+        )?;
+        ethereum_adapter
+            .block_pointer_from_number(logger, chain_store.clone(), block as u64)
+            .compat()
+            .await
+            .map_err(|_| {
+                SubgraphRegistrarError::ManifestValidationError(vec![
+                    SubgraphManifestValidationError::BlockNotFound(block.to_string()),
+                ])
+            })
+    }
 }
 
 async fn handle_assignment_event(
     event: AssignmentEvent,
     provider: Arc<impl SubgraphAssignmentProviderTrait>,
+    store: Arc<impl Store>,
     logger: Logger,
 ) -> Result<(), CancelableError<SubgraphAssignmentProviderError>> {
     let logger = logger.to_owned();
@@ -383,7 +696,23 @@ async fn handle_assignment_event(
         AssignmentEvent::Add {
             subgraph_id,
             node_id: _,
-        } => Ok(start_subgraph(subgraph_id, provider.clone(), logger).await),
+        } => {
+            // A deployment that failed is still considered running; stop
+            // it so that starting it again retries it
+            let failed = has_failed(store.as_ref(), &subgraph_id).unwrap_or_else(|e| {
+                warn!(logger, "Failed to check whether the subgraph failed";
+                      "error" => e.to_string(),
+                      "subgraph_id" => subgraph_id.to_string());
+                false
+            });
+            if failed {
+                match provider.stop(subgraph_id.clone()).await {
+                    Ok(()) | Err(SubgraphAssignmentProviderError::NotRunning(_)) => (),
+                    Err(e) => return Err(CancelableError::Error(e)),
+                }
+            }
+            Ok(start_subgraph(subgraph_id, provider.clone(), logger).await)
+        }
         AssignmentEvent::Remove {
             subgraph_id,
             node_id: _,
@@ -395,6 +724,45 @@ async fn handle_assignment_event(
     }
 }
 
+/// Copy the data of a deployment to the shard it is being moved to, and
+/// switch the deployment over once that is done. If the deployment is
+/// `running` on this node, it is stopped while we switch over
+async fn move_deployment(
+    logger: &Logger,
+    store: Arc<impl Store>,
+    provider: Arc<impl SubgraphAssignmentProviderTrait>,
+    hash: SubgraphDeploymentId,
+    running: bool,
+) -> Result<(), Error> {
+    let copy_logger = logger.clone();
+    let copy_store = store.clone();
+    let id = hash.clone();
+    graph::spawn_blocking_allow_panic(move || copy_store.copy_moving_deployment(&copy_logger, &id))
+        .await
+        .map_err(|e| format_err!("{}", e))??;
+
+    if running {
+        match provider.stop(hash.clone()).await {
+            Ok(()) | Err(SubgraphAssignmentProviderError::NotRunning(_)) => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let finish_logger = logger.clone();
+    let id = hash.clone();
+    let result = graph::spawn_blocking_allow_panic(move || {
+        store.finish_deployment_move(&finish_logger, &id)
+    })
+    .await
+    .map_err(|e| format_err!("{}", e))
+    .and_then(|result| result.map_err(Error::from));
+
+    // Resume indexing whether the switch succeeded or not
+    if running {
+        start_subgraph(hash, provider, logger.clone()).await;
+    }
+    result
+}
+
 async fn start_subgraph(
     subgraph_id: SubgraphDeploymentId,
     provider: Arc<impl SubgraphAssignmentProviderTrait>,
@@ -1070,23 +1438,110 @@ fn reassign_subgraph(
     hash: SubgraphDeploymentId,
     node_id: NodeId,
 ) -> Result<(), SubgraphRegistrarError> {
-    let mut ops = vec![];
+    let (current_node_id, paused) = current_assignment(store.as_ref(), &hash)?;
+
+    if current_node_id == node_id.to_string() {
+        return Err(SubgraphRegistrarError::DeploymentAssignmentUnchanged(
+            hash.clone().to_string(),
+        ));
+    }
+
+    // A paused deployment stays paused on its new node
+    write_assignment(
+        store,
+        &hash,
+        &current_node_id,
+        SubgraphDeploymentAssignmentEntity::new(node_id).paused(paused),
+    )
+}
+
+/// Pause or resume a subgraph deployment. The node the deployment is
+/// assigned to stops or starts indexing it when it sees the change to the
+/// assignment
+fn set_paused(
+    store: Arc<impl Store>,
+    hash: SubgraphDeploymentId,
+    paused: bool,
+) -> Result<(), SubgraphRegistrarError> {
+    let (current_node_id, currently_paused) = current_assignment(store.as_ref(), &hash)?;
+
+    if currently_paused == paused {
+        return Err(SubgraphRegistrarError::DeploymentAssignmentUnchanged(
+            hash.to_string(),
+        ));
+    }
+
+    let node_id = NodeId::new(current_node_id.clone())
+        .map_err(|()| format_err!("invalid node id in assignment: {}", current_node_id))?;
+    write_assignment(
+        store,
+        &hash,
+        &current_node_id,
+        SubgraphDeploymentAssignmentEntity::new(node_id).paused(paused),
+    )
+}
+
+/// Make the node a failed subgraph deployment is assigned to start it
+/// again. Starting a deployment clears its failed state
+fn reindex_subgraph(
+    store: Arc<impl Store>,
+    hash: SubgraphDeploymentId,
+) -> Result<(), SubgraphRegistrarError> {
+    let (current_node_id, paused) = current_assignment(store.as_ref(), &hash)?;
+
+    if paused {
+        return Err(SubgraphRegistrarError::DeploymentPaused(hash.to_string()));
+    }
+    if !has_failed(store.as_ref(), &hash)? {
+        return Err(SubgraphRegistrarError::DeploymentNotFailed(
+            hash.to_string(),
+        ));
+    }
+
+    // Writing the assignment again sends an `Add` event to the node, which
+    // restarts the deployment since it has failed
+    let node_id = NodeId::new(current_node_id.clone())
+        .map_err(|()| format_err!("invalid node id in assignment: {}", current_node_id))?;
+    write_assignment(
+        store,
+        &hash,
+        &current_node_id,
+        SubgraphDeploymentAssignmentEntity::new(node_id),
+    )
+}
 
+/// The node the deployment `hash` is assigned to, and whether it is paused
+fn current_assignment(
+    store: &impl Store,
+    hash: &SubgraphDeploymentId,
+) -> Result<(String, bool), SubgraphRegistrarError> {
     let current_deployment = store.find(
         SubgraphDeploymentAssignmentEntity::query()
             .filter(EntityFilter::new_equal("id", hash.clone().to_string())),
     )?;
 
-    let current_node_id = current_deployment
+    let assignment = current_deployment
         .first()
-        .and_then(|d| d.get("nodeId"))
+        .ok_or_else(|| SubgraphRegistrarError::DeploymentNotFound(hash.clone().to_string()))?;
+    let node_id = assignment
+        .get("nodeId")
         .ok_or_else(|| SubgraphRegistrarError::DeploymentNotFound(hash.clone().to_string()))?;
 
-    if current_node_id.to_string() == node_id.to_string() {
-        return Err(SubgraphRegistrarError::DeploymentAssignmentUnchanged(
-            hash.clone().to_string(),
-        ));
-    }
+    Ok((
+        node_id.to_string(),
+        SubgraphDeploymentAssignmentEntity::is_paused(assignment),
+    ))
+}
+
+/// Replace the assignment of the deployment `hash`, unless it was changed
+/// to a node other than `current_node_id` in the meantime
+fn write_assignment(
+    store: Arc<impl Store>,
+    hash: &SubgraphDeploymentId,
+    current_node_id: &str,
+    assignment: SubgraphDeploymentAssignmentEntity,
+) -> Result<(), SubgraphRegistrarError> {
+    let mut ops = vec![];
 
     ops.push(MetadataOperation::AbortUnless {
         description: "Deployment assignment is unchanged".to_owned(),
@@ -1100,8 +1555,8 @@ fn reassign_subgraph(
     // Create the assignment update operations.
     // Note: This will also generate a remove operation for the existing subgraph assignment.
     ops.extend(
-        SubgraphDeploymentAssignmentEntity::new(node_id)
-            .write_operations(&hash.clone())
+        assignment
+            .write_operations(hash)
             .into_iter()
             .map(|op| op.into()),
     );
@@ -1110,3 +1565,201 @@ fn reassign_subgraph(
 
     Ok(())
 }
+
+/// Whether the deployment `hash` has failed
+fn has_failed(store: &impl Store, hash: &SubgraphDeploymentId) -> Result<bool, Error> {
+    Ok(store
+        .get(SubgraphDeploymentEntity::key(hash.clone()))?
+        .map(|deployment| deployment.get("failed") == Some(&Value::Bool(true)))
+        .unwrap_or(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LinkResolver as IpfsResolver;
+    use graph::data::subgraph::schema::SubgraphError;
+    use graph::prelude::tokio::time::timeout;
+    use graph_mock::MockStore;
+    use ipfs_api::IpfsClient;
+    use std::sync::Mutex;
+    use test_store::{create_test_subgraph, LOGGER, STORE};
+
+    const SCHEMA: &str = "type Thing @entity { id: ID! }";
+
+    /// Records the deployments it is asked to start and stop
+    #[derive(Default)]
+    struct TestProvider {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl TestProvider {
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl EventProducer<SubgraphAssignmentProviderEvent> for TestProvider {
+        fn take_event_stream(
+            &mut self,
+        ) -> Option<Box<dyn Stream<Item = SubgraphAssignmentProviderEvent, Error = ()> + Send>>
+        {
+            None
+        }
+    }
+
+    #[async_trait]
+    impl SubgraphAssignmentProviderTrait for TestProvider {
+        async fn start(
+            &self,
+            id: &SubgraphDeploymentId,
+        ) -> Result<(), SubgraphAssignmentProviderError> {
+            self.calls.lock().unwrap().push(format!("start {}", id));
+            Ok(())
+        }
+
+        async fn stop(
+            &self,
+            id: SubgraphDeploymentId,
+        ) -> Result<(), SubgraphAssignmentProviderError> {
+            self.calls.lock().unwrap().push(format!("stop {}", id));
+            Ok(())
+        }
+    }
+
+    fn node_id() -> NodeId {
+        NodeId::new("registrar_test").unwrap()
+    }
+
+    fn registrar(
+        provider: Arc<TestProvider>,
+    ) -> SubgraphRegistrar<
+        IpfsResolver,
+        TestProvider,
+        impl Store + SubgraphDeploymentStore,
+        MockStore,
+    > {
+        SubgraphRegistrar::new(
+            &LoggerFactory::new(LOGGER.clone(), None),
+            Arc::new(IpfsResolver::from(IpfsClient::default())),
+            provider,
+            STORE.clone(),
+            HashMap::new(),
+            EthereumNetworks::new(),
+            node_id(),
+            SubgraphVersionSwitchingMode::Instant,
+        )
+    }
+
+    /// Create the deployment `id` and assign it to our node
+    fn assign(id: &str) -> SubgraphDeploymentId {
+        create_test_subgraph(id, SCHEMA);
+        let id = SubgraphDeploymentId::new(id).unwrap();
+        STORE
+            .apply_metadata_operations(
+                SubgraphDeploymentAssignmentEntity::new(node_id()).write_operations(&id),
+            )
+            .unwrap();
+        id
+    }
+
+    fn fail(id: &SubgraphDeploymentId) {
+        let error = SubgraphError {
+            subgraph_id: id.clone(),
+            message: "failed on purpose".to_owned(),
+            block_ptr: None,
+            handler: None,
+        };
+        STORE
+            .apply_metadata_operations(SubgraphDeploymentEntity::fail_operations(id, error))
+            .unwrap();
+    }
+
+    fn add(id: &SubgraphDeploymentId) -> AssignmentEvent {
+        AssignmentEvent::Add {
+            subgraph_id: id.clone(),
+            node_id: node_id(),
+        }
+    }
+
+    fn remove(id: &SubgraphDeploymentId) -> AssignmentEvent {
+        AssignmentEvent::Remove {
+            subgraph_id: id.clone(),
+            node_id: node_id(),
+        }
+    }
+
+    #[tokio::test]
+    async fn pause_resume_and_reindex_send_assignment_events() {
+        let registrar = registrar(Arc::new(TestProvider::default()));
+        let events = registrar.assignment_events();
+
+        let id = assign("registrarPauseResumeReindex");
+        registrar.pause_subgraph(id.clone()).await.unwrap();
+        assert!(matches!(
+            registrar.pause_subgraph(id.clone()).await,
+            Err(SubgraphRegistrarError::DeploymentAssignmentUnchanged(_))
+        ));
+        assert!(matches!(
+            registrar.reindex_subgraph(id.clone()).await,
+            Err(SubgraphRegistrarError::DeploymentPaused(_))
+        ));
+        registrar.resume_subgraph(id.clone()).await.unwrap();
+        assert!(matches!(
+            registrar.reindex_subgraph(id.clone()).await,
+            Err(SubgraphRegistrarError::DeploymentNotFailed(_))
+        ));
+        fail(&id);
+        registrar.reindex_subgraph(id.clone()).await.unwrap();
+
+        let expected = vec![add(&id), remove(&id), add(&id), add(&id)];
+        let subgraph_id = id.clone();
+        let events = events
+            .filter(move |event| match event {
+                AssignmentEvent::Add {
+                    subgraph_id: id, ..
+                }
+                | AssignmentEvent::Remove {
+                    subgraph_id: id, ..
+                } => id == &subgraph_id,
+            })
+            .take(expected.len() as u64)
+            .collect()
+            .compat();
+        let events = timeout(Duration::from_secs(10), events)
+            .await
+            .expect("timed out waiting for assignment events")
+            .unwrap();
+        assert_eq!(expected, events);
+    }
+
+    #[tokio::test]
+    async fn failed_deployments_are_restarted() {
+        let provider = Arc::new(TestProvider::default());
+        let id = assign("registrarRestartFailed");
+        let logger = LOGGER.clone();
+
+        handle_assignment_event(add(&id), provider.clone(), STORE.clone(), logger.clone())
+            .await
+            .unwrap();
+        assert_eq!(vec![format!("start {}", id)], provider.calls());
+
+        fail(&id);
+        handle_assignment_event(add(&id), provider.clone(), STORE.clone(), logger.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![
+                format!("start {}", id),
+                format!("stop {}", id),
+                format!("start {}", id)
+            ],
+            provider.calls()
+        );
+
+        handle_assignment_event(remove(&id), provider.clone(), STORE.clone(), logger)
+            .await
+            .unwrap();
+        assert_eq!(Some(&format!("stop {}", id)), provider.calls().last());
+    }
+}
@@ -4,5 +4,7 @@ use failure::Error;
 
 #[async_trait]
 pub trait ArweaveAdapter: Send + Sync {
-    async fn tx_data(&self, tx_id: &str) -> Result<Bytes, Error>;
+    /// Fetch the data of transaction `tx_id`. Fails if the data is larger than
+    /// `max_bytes`.
+    async fn tx_data(&self, tx_id: &str, max_bytes: Option<u64>) -> Result<Bytes, Error>;
 }
@@ -1,8 +1,9 @@
+use std::path::Path;
 use std::pin::Pin;
 use std::time::Duration;
 
 use async_trait::async_trait;
-use failure::Error;
+use failure::{Error, Fail};
 use futures03::prelude::Stream;
 use serde_json::Value;
 use slog::Logger;
@@ -20,6 +21,30 @@ pub struct JsonStreamValue {
 pub type JsonValueStream =
     Pin<Box<dyn Stream<Item = Result<JsonStreamValue, Error>> + Send + 'static>>;
 
+/// The layout of a file that `json_stream` splits into individual values.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StreamFormat {
+    /// One JSON value per line
+    JsonLines,
+    /// A single top-level JSON array; each element is one value
+    JsonArray,
+    /// Comma-separated values with a header line. Each record is turned into
+    /// a JSON object that maps the column names from the header to the
+    /// (string) values of the record
+    Csv,
+}
+
+/// A single record in a `JsonValueStream` could not be turned into a JSON
+/// value. Streams return this error for malformed records, and it is possible
+/// to keep reading values from the stream after such an error. Any other
+/// error ends the stream.
+#[derive(Fail, Debug)]
+#[fail(display = "{}", message)]
+pub struct MalformedRecord {
+    pub line: usize,
+    pub message: String,
+}
+
 /// Resolves links to subgraph manifests and resources referenced by them.
 #[async_trait]
 pub trait LinkResolver: Send + Sync + 'static {
@@ -37,8 +62,22 @@ pub trait LinkResolver: Send + Sync + 'static {
     async fn cat(&self, logger: &Logger, link: &Link) -> Result<Vec<u8>, Error>;
 
     /// Read the contents of `link` and deserialize them into a stream of JSON
-    /// values. The values must each be on a single line; newlines are significant
-    /// as they are used to split the file contents and each line is deserialized
-    /// separately.
-    async fn json_stream(&self, logger: &Logger, link: &Link) -> Result<JsonValueStream, Error>;
+    /// values. How the contents are split into values is determined by
+    /// `format`; for `StreamFormat::JsonLines`, the values must each be on a
+    /// single line as newlines are used to split the file contents and each
+    /// line is deserialized separately.
+    async fn json_stream(
+        &self,
+        logger: &Logger,
+        link: &Link,
+        format: StreamFormat,
+    ) -> Result<JsonValueStream, Error>;
+}
+
+/// A cache of IPFS files that operators can fill ahead of time, e.g., with
+/// the files of subgraphs they are about to deploy.
+pub trait IpfsCache: Send + Sync + 'static {
+    /// Add the files in `dir` to the cache. Returns the number of files
+    /// that were added.
+    fn seed_from_dir(&self, dir: &Path) -> Result<usize, Error>;
 }
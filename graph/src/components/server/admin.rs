@@ -1,8 +1,61 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::io;
 use std::sync::Arc;
 
+use crate::components::link_resolver::IpfsCache;
+use crate::data::graphql::persisted::PersistedQueries;
 use crate::prelude::Logger;
-use crate::prelude::NodeId;
+use crate::prelude::{Error, NodeId};
+
+/// Someone who may use the admin server, as configured in the admin auth
+/// file. A principal authenticates either with a static `token` or by
+/// signing its requests with its `secret`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminPrincipal {
+    /// A token the principal sends as `Authorization: Bearer <token>`
+    #[serde(default)]
+    pub token: Option<String>,
+    /// The secret the principal signs requests with
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub permissions: Vec<AdminPermission>,
+}
+
+/// Permission to call some methods of the admin server
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminPermission {
+    /// The methods that may be called; `*` stands for all methods
+    pub methods: Vec<String>,
+    /// Prefixes of the subgraph names or deployment IDs the methods may be
+    /// called for. The methods may be called for anything if this is empty
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+}
+
+/// The principals of the admin server by their name. If there are none,
+/// the admin server does not check who sends requests
+pub type AdminPrincipals = BTreeMap<String, AdminPrincipal>;
+
+/// A request to the admin server as recorded in the audit log
+#[derive(Clone, Debug)]
+pub struct AdminAuditEntry {
+    /// The principal that sent the request, or `None` if the admin server
+    /// does not check who sends requests or we could not tell
+    pub principal: Option<String>,
+    pub method: String,
+    pub params: serde_json::Value,
+    /// Why the request failed or was refused, or `None` if it succeeded
+    pub error: Option<String>,
+}
+
+/// Where the admin server records who did what
+pub trait AdminAuditLog: Send + Sync + 'static {
+    fn record(&self, entry: AdminAuditEntry) -> Result<(), Error>;
+}
 
 /// Common trait for JSON-RPC admin server implementations.
 pub trait JsonRpcServer<P> {
@@ -13,6 +66,10 @@ pub trait JsonRpcServer<P> {
         http_port: u16,
         ws_port: u16,
         provider: Arc<P>,
+        persisted_queries: Arc<PersistedQueries>,
+        ipfs_cache: Option<Arc<dyn IpfsCache>>,
+        principals: AdminPrincipals,
+        audit_log: Arc<dyn AdminAuditLog>,
         node_id: NodeId,
         logger: Logger,
     ) -> Result<Self::Server, io::Error>;
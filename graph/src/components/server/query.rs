@@ -9,6 +9,10 @@ pub enum GraphQLServerError {
     ClientError(String),
     QueryError(QueryError),
     InternalError(String),
+    /// The request did not come with valid credentials
+    Unauthorized(String),
+    /// The client has exceeded one of its limits
+    TooManyRequests(String),
 }
 
 impl From<QueryError> for GraphQLServerError {
@@ -29,6 +33,12 @@ impl fmt::Display for GraphQLServerError {
             GraphQLServerError::InternalError(ref s) => {
                 write!(f, "GraphQL server error (internal error): {}", s)
             }
+            GraphQLServerError::Unauthorized(ref s) => {
+                write!(f, "GraphQL server error (unauthorized): {}", s)
+            }
+            GraphQLServerError::TooManyRequests(ref s) => {
+                write!(f, "GraphQL server error (too many requests): {}", s)
+            }
         }
     }
 }
@@ -43,6 +53,8 @@ impl Error for GraphQLServerError {
             GraphQLServerError::ClientError(_) => None,
             GraphQLServerError::QueryError(ref e) => Some(e),
             GraphQLServerError::InternalError(_) => None,
+            GraphQLServerError::Unauthorized(_) => None,
+            GraphQLServerError::TooManyRequests(_) => None,
         }
     }
 }
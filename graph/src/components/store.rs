@@ -6,7 +6,7 @@ use mockall::predicate::*;
 use mockall::*;
 use serde::{Deserialize, Serialize};
 use stable_hash::prelude::*;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fmt;
 use std::str::FromStr;
@@ -424,6 +424,10 @@ pub struct StoreEvent {
     // logs as they flow through the system
     pub tag: usize,
     pub changes: HashSet<EntityChange>,
+    /// The block number that processing or reverting a block moved the
+    /// subgraph to, if the changes come from doing that
+    #[serde(default)]
+    pub block: Option<BlockNumber>,
 }
 
 impl From<Vec<MetadataOperation>> for StoreEvent {
@@ -459,7 +463,17 @@ impl StoreEvent {
 
         let tag = NEXT_TAG.fetch_add(1, Ordering::Relaxed);
         let changes = changes.into_iter().collect();
-        StoreEvent { tag, changes }
+        StoreEvent {
+            tag,
+            changes,
+            block: None,
+        }
+    }
+
+    /// Mark the changes in this event as bringing the subgraph to `block`
+    pub fn at_block(mut self, block: BlockNumber) -> Self {
+        self.block = Some(block);
+        self
     }
 
     /// Extend `ev1` with `ev2`. If `ev1` is `None`, just set it to `ev2`
@@ -468,6 +482,7 @@ impl StoreEvent {
             trace!(logger, "Adding changes to event";
                            "from" => ev2.tag, "to" => e.tag);
             e.changes.extend(ev2.changes);
+            e.block = ev2.block.or(e.block);
         } else {
             *ev1 = Some(ev2);
         }
@@ -475,6 +490,7 @@ impl StoreEvent {
 
     pub fn extend(mut self, other: StoreEvent) -> Self {
         self.changes.extend(other.changes);
+        self.block = other.block.or(self.block);
         self
     }
 }
@@ -492,7 +508,7 @@ impl fmt::Display for StoreEvent {
 
 impl PartialEq for StoreEvent {
     fn eq(&self, other: &StoreEvent) -> bool {
-        // Ignore tag for equality
+        // Ignore tag and block for equality
         self.changes == other.changes
     }
 }
@@ -708,14 +724,69 @@ impl MetadataOperation {
     }
 }
 
+/// The progress of copying the data of a graft base into a new deployment
 #[derive(Clone, Debug)]
-pub struct AttributeIndexDefinition {
-    pub subgraph_id: SubgraphDeploymentId,
-    pub entity_number: usize,
-    pub attribute_number: usize,
-    pub field_value_type: ValueType,
-    pub attribute_name: String,
-    pub entity_name: String,
+pub struct CopyStatus {
+    /// The deployment whose data is being copied
+    pub src: SubgraphDeploymentId,
+    /// The deployment starts from the data as of this block
+    pub target_block: EthereumBlockPointer,
+    /// Whether all data has been copied and the deployment can start
+    /// indexing
+    pub finished: bool,
+    pub tables: Vec<CopyTableStatus>,
+}
+
+/// The progress of copying the entities of one type; see `CopyStatus`
+#[derive(Clone, Debug)]
+pub struct CopyTableStatus {
+    pub entity_type: String,
+    pub rows_copied: i64,
+    /// All rows with a `vid` less than this have been copied
+    pub next_vid: i64,
+    /// The largest `vid` that will be copied
+    pub target_vid: i64,
+    pub finished: bool,
+}
+
+/// Statistics about the entities of one type in a deployment and the
+/// table that stores them
+#[derive(Clone, Debug)]
+pub struct EntityTypeStats {
+    pub entity_type: String,
+    /// The number of entities as of the deployment's latest block
+    pub entities: i64,
+    /// The number of entity versions, including the current ones and all
+    /// versions that are only visible at earlier blocks
+    pub versions: i64,
+    /// The size of the table in bytes, not including its indexes
+    pub table_size: i64,
+    /// The combined size of all indexes on the table in bytes
+    pub index_size: i64,
+}
+
+impl EntityTypeStats {
+    /// The number of historical versions for each current entity; `None`
+    /// if there are no current entities
+    pub fn history_ratio(&self) -> Option<f64> {
+        if self.entities == 0 {
+            None
+        } else {
+            Some((self.versions - self.entities) as f64 / self.entities as f64)
+        }
+    }
+}
+
+/// The entities of one type that changed in a block
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityTypeChanges {
+    pub entity_type: String,
+    /// Entities that did not exist before the block
+    pub created: Vec<String>,
+    /// Entities that existed before the block and still exist after it
+    pub updated: Vec<String>,
+    /// Entities that existed before the block but not after it
+    pub removed: Vec<String>,
 }
 
 #[derive(Fail, Debug)]
@@ -746,6 +817,8 @@ pub enum StoreError {
         _0, _1
     )]
     DuplicateBlockProcessing(SubgraphDeploymentId, u64),
+    #[fail(display = "writing block {} for subgraph `{}` failed: {}", _1, _0, _2)]
+    WriteFailed(SubgraphDeploymentId, EthereumBlockPointer, String),
 }
 
 impl From<TransactionAbortError> for StoreError {
@@ -844,15 +917,27 @@ pub trait Store: Send + Sync + 'static {
     ///
     /// `block_ptr_to` must point to a child block of the current subgraph block pointer.
     ///
-    /// Return `true` if the subgraph mentioned in `history_event` should have
-    /// its schema migrated at `block_ptr_to`
+    /// The store may write the changes in the background after this method
+    /// returns; `get`, `get_many` and `block_ptr` already reflect them, but
+    /// other ways of reading the subgraph's data may not until
+    /// `flush_writes` has been called.
     fn transact_block_operations(
         &self,
         subgraph_id: SubgraphDeploymentId,
         block_ptr_to: EthereumBlockPointer,
         mods: Vec<EntityModification>,
         stopwatch: StopwatchMetrics,
-    ) -> Result<bool, StoreError>;
+    ) -> Result<(), StoreError>;
+
+    /// Wait until all changes passed to `transact_block_operations` for the
+    /// subgraph have been written to the database. Returns an error if
+    /// writing any of them failed.
+    fn flush_writes(&self, subgraph_id: &SubgraphDeploymentId) -> Result<(), StoreError>;
+
+    /// Write all changes passed to `transact_block_operations` for the
+    /// subgraph and stop writing them in the background. Called when the
+    /// subgraph stops running.
+    fn stop_writes(&self, subgraph_id: &SubgraphDeploymentId) -> Result<(), StoreError>;
 
     /// Apply the specified metadata operations.
     fn apply_metadata_operations(
@@ -860,13 +945,6 @@ pub trait Store: Send + Sync + 'static {
         operations: Vec<MetadataOperation>,
     ) -> Result<(), StoreError>;
 
-    /// Build indexes for a set of subgraph entity attributes
-    fn build_entity_attribute_indexes(
-        &self,
-        subgraph: &SubgraphDeploymentId,
-        indexes: Vec<AttributeIndexDefinition>,
-    ) -> Result<(), SubgraphAssignmentProviderError>;
-
     /// Revert the entity changes from a single block atomically in the store, and update the
     /// subgraph block pointer from `block_ptr_from` to `block_ptr_to`.
     ///
@@ -879,6 +957,145 @@ pub trait Store: Send + Sync + 'static {
         block_ptr_to: EthereumBlockPointer,
     ) -> Result<(), StoreError>;
 
+    /// The names of all subgraphs that have a version that uses the
+    /// deployment `subgraph_id`
+    fn subgraph_names(&self, subgraph_id: &SubgraphDeploymentId)
+        -> Result<Vec<String>, StoreError>;
+
+    /// Keep only the last `history_blocks` blocks of entity history for
+    /// `subgraph_id`, or all of it if `history_blocks` is `None`. Older
+    /// history is removed in the background, and queries for blocks whose
+    /// history was removed fail.
+    fn set_history_blocks(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        history_blocks: Option<BlockNumber>,
+    ) -> Result<(), StoreError>;
+
+    /// Create an index on the attributes `fields` of `entity_type` in a
+    /// deployment without blocking indexing of the deployment. Returns the
+    /// name of the index.
+    fn create_index(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        entity_type: &str,
+        fields: Vec<String>,
+    ) -> Result<String, StoreError>;
+
+    /// Drop the index `index_name` from a deployment without blocking
+    /// indexing of the deployment.
+    fn drop_index(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        index_name: &str,
+    ) -> Result<(), StoreError>;
+
+    /// The progress of copying data from the graft base into each of the
+    /// deployments, or into the shard the deployment is being moved to.
+    /// Deployments that are not grafted or being moved are not in the
+    /// result.
+    fn copy_statuses(
+        &self,
+        subgraph_ids: &[SubgraphDeploymentId],
+    ) -> Result<HashMap<SubgraphDeploymentId, CopyStatus>, StoreError>;
+
+    /// Count the entities and entity versions of each type in a deployment
+    /// and report how much space their tables and indexes take up. This
+    /// scans all the deployment's tables and can take a while for large
+    /// deployments.
+    fn entity_stats(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<EntityTypeStats>, StoreError>;
+
+    /// Like `entity_stats`, but estimate the number of entities and entity
+    /// versions from the statistics the database keeps instead of
+    /// counting them. This is cheap enough to be done regularly, but the
+    /// numbers can be quite a bit off, especially for small tables.
+    fn estimated_entity_stats(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<EntityTypeStats>, StoreError>;
+
+    /// The entities that were created, updated or removed in `block`,
+    /// grouped by entity type. Entity types without changes are left out.
+    /// This queries all the deployment's tables, but uses their block range
+    /// indexes to only read the parts that changed around `block`. Fails
+    /// for blocks whose history has been pruned.
+    fn entity_changes_in_block(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<Vec<EntityTypeChanges>, StoreError>;
+
+    /// Change the schema of a deployment in place to `schema`. Only
+    /// changes that do not affect data that is already stored are
+    /// possible, like adding entity types, nullable attributes or
+    /// fulltext fields. This only changes the deployment's tables and the
+    /// schema it is queried with; its manifest and mappings stay the same,
+    /// and new attributes are `null` for all existing entities.
+    fn evolve_deployment_schema(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        schema: &Schema,
+    ) -> Result<(), StoreError>;
+
+    /// Rewind a deployment to `block_ptr`, undoing all entity changes it
+    /// made after that block, like reverting each of these blocks with
+    /// `revert_block_operations` would. The deployment must not be
+    /// indexing while this runs.
+    fn rewind(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block_ptr: EthereumBlockPointer,
+    ) -> Result<(), StoreError>;
+
+    /// Start moving the data of a deployment into `shard`. This creates
+    /// the tables for the deployment in `shard`, but does not copy any
+    /// data yet. Starting the same move again has no effect.
+    fn start_deployment_move(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        shard: &str,
+    ) -> Result<(), StoreError>;
+
+    /// Copy the data of a deployment into the shard it is being moved to
+    /// while the deployment keeps indexing. This can take a very long time
+    /// and must be run on a blocking thread.
+    fn copy_moving_deployment(
+        &self,
+        logger: &Logger,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError>;
+
+    /// Copy the changes a deployment made while it was being copied and
+    /// switch the deployment over to the shard it was moved to. The data
+    /// in the shard the deployment was moved away from is kept until it is
+    /// dropped with `drop_retired_deployment_data` so that the move can be
+    /// rolled back. The deployment must not be indexing while this runs.
+    fn finish_deployment_move(
+        &self,
+        logger: &Logger,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError>;
+
+    /// Switch a deployment back to the data it had in the shard it was
+    /// last moved away from. The deployment is rewound to the block at
+    /// which it was moved, and the data in the shard it was moved to is
+    /// dropped. The deployment must not be indexing while this runs.
+    fn rollback_deployment_move(
+        &self,
+        logger: &Logger,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError>;
+
+    /// Drop the data a deployment left behind in the shard it was last
+    /// moved away from; after that, the move can not be rolled back
+    fn drop_retired_deployment_data(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError>;
+
     /// Subscribe to changes for specific subgraphs and entities.
     ///
     /// Returns a stream of store events that match the input arguments.
@@ -1182,20 +1399,6 @@ pub trait Store: Send + Sync + 'static {
         ops: Vec<MetadataOperation>,
     ) -> Result<(), StoreError>;
 
-    /// Try to perform a pending migration for a subgraph schema. Even if a
-    /// subgraph has a pending schema migration, this method might not actually
-    /// perform the migration because of limits on the total number of
-    /// migrations that can happen at the same time across the whole system.
-    ///
-    /// Any errors happening during the migration will be logged as warnings
-    /// on `logger`, but otherwise ignored
-    fn migrate_subgraph_deployment(
-        &self,
-        logger: &Logger,
-        subgraph_id: &SubgraphDeploymentId,
-        block_ptr: &EthereumBlockPointer,
-    );
-
     /// Return the number of the block with the given hash for the given
     /// subgraph
     fn block_number(
@@ -1280,10 +1483,18 @@ impl Store for MockStore {
         _block_ptr_to: EthereumBlockPointer,
         _mods: Vec<EntityModification>,
         _stopwatch: StopwatchMetrics,
-    ) -> Result<bool, StoreError> {
+    ) -> Result<(), StoreError> {
         unimplemented!()
     }
 
+    fn flush_writes(&self, _subgraph_id: &SubgraphDeploymentId) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn stop_writes(&self, _subgraph_id: &SubgraphDeploymentId) -> Result<(), StoreError> {
+        Ok(())
+    }
+
     fn apply_metadata_operations(
         &self,
         _operations: Vec<MetadataOperation>,
@@ -1291,14 +1502,6 @@ impl Store for MockStore {
         unimplemented!()
     }
 
-    fn build_entity_attribute_indexes(
-        &self,
-        _subgraph: &SubgraphDeploymentId,
-        _indexes: Vec<AttributeIndexDefinition>,
-    ) -> Result<(), SubgraphAssignmentProviderError> {
-        unimplemented!()
-    }
-
     fn revert_block_operations(
         &self,
         _subgraph_id: SubgraphDeploymentId,
@@ -1308,33 +1511,140 @@ impl Store for MockStore {
         unimplemented!()
     }
 
-    fn subscribe(&self, _entities: Vec<SubgraphEntityPair>) -> StoreEventStreamBox {
+    fn subgraph_names(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<String>, StoreError> {
         unimplemented!()
     }
 
-    fn create_subgraph_deployment(
+    fn set_history_blocks(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+        _history_blocks: Option<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn create_index(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+        _entity_type: &str,
+        _fields: Vec<String>,
+    ) -> Result<String, StoreError> {
+        unimplemented!()
+    }
+
+    fn drop_index(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+        _index_name: &str,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn copy_statuses(
+        &self,
+        _subgraph_ids: &[SubgraphDeploymentId],
+    ) -> Result<HashMap<SubgraphDeploymentId, CopyStatus>, StoreError> {
+        unimplemented!()
+    }
+
+    fn entity_stats(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<EntityTypeStats>, StoreError> {
+        unimplemented!()
+    }
+
+    fn estimated_entity_stats(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<EntityTypeStats>, StoreError> {
+        unimplemented!()
+    }
+
+    fn entity_changes_in_block(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+        _block: BlockNumber,
+    ) -> Result<Vec<EntityTypeChanges>, StoreError> {
+        unimplemented!()
+    }
+
+    fn evolve_deployment_schema(
         &self,
+        _subgraph_id: &SubgraphDeploymentId,
         _schema: &Schema,
-        _ops: Vec<MetadataOperation>,
     ) -> Result<(), StoreError> {
         unimplemented!()
     }
 
-    fn start_subgraph_deployment(
+    fn rewind(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+        _block_ptr: EthereumBlockPointer,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn start_deployment_move(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+        _shard: &str,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn copy_moving_deployment(
+        &self,
+        _logger: &Logger,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn finish_deployment_move(
+        &self,
+        _logger: &Logger,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn rollback_deployment_move(
         &self,
         _logger: &Logger,
         _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn drop_retired_deployment_data(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn subscribe(&self, _entities: Vec<SubgraphEntityPair>) -> StoreEventStreamBox {
+        unimplemented!()
+    }
+
+    fn create_subgraph_deployment(
+        &self,
+        _schema: &Schema,
         _ops: Vec<MetadataOperation>,
     ) -> Result<(), StoreError> {
         unimplemented!()
     }
 
-    fn migrate_subgraph_deployment(
+    fn start_subgraph_deployment(
         &self,
         _logger: &Logger,
         _subgraph_id: &SubgraphDeploymentId,
-        _block_ptr: &EthereumBlockPointer,
-    ) {
+        _ops: Vec<MetadataOperation>,
+    ) -> Result<(), StoreError> {
         unimplemented!()
     }
 
@@ -1360,15 +1670,13 @@ pub trait SubgraphDeploymentStore: Send + Sync + 'static {
     /// adding a root query type etc. to it
     fn api_schema(&self, subgraph_id: &SubgraphDeploymentId) -> Result<Arc<ApiSchema>, Error>;
 
-    /// Return true if the subgraph uses the relational storage scheme; if
-    /// it is false, the subgraph uses JSONB storage. This method exposes
-    /// store internals that should really be hidden and should be used
-    /// sparingly and only when absolutely needed
-    fn uses_relational_schema(&self, subgraph_id: &SubgraphDeploymentId) -> Result<bool, Error>;
-
     /// Return the name of the network that the subgraph is indexing from. The
     /// names returned are things like `mainnet` or `ropsten`
     fn network_name(&self, subgraph_id: &SubgraphDeploymentId) -> Result<Option<String>, Error>;
+
+    /// Return the earliest block at which the subgraph can be queried;
+    /// history before that block has been removed
+    fn earliest_block(&self, subgraph_id: &SubgraphDeploymentId) -> Result<BlockNumber, Error>;
 }
 
 /// Common trait for blockchain store implementations.
@@ -1591,6 +1899,11 @@ impl EntityCache {
         self.updates.insert(key, None);
     }
 
+    /// The number of entities that were changed through `set` or `remove`
+    pub fn update_count(&self) -> usize {
+        self.updates.len()
+    }
+
     pub fn set(&mut self, key: EntityKey, mut entity: Entity) -> Result<(), QueryExecutionError> {
         use std::collections::btree_map::Entry;
 
@@ -1,12 +1,13 @@
 use std::cmp::PartialEq;
+use std::collections::HashMap;
 use std::fmt;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use async_trait::async_trait;
 use failure::Error;
 use futures::sync::mpsc;
 
-use crate::components::metrics::HistogramVec;
+use crate::components::metrics::{Collector, HistogramVec};
 use crate::components::subgraph::SharedProofOfIndexing;
 use crate::prelude::*;
 use web3::types::{Log, Transaction};
@@ -143,6 +144,83 @@ impl HostMetrics {
             .with_label_values(vec![fn_name].as_slice())
             .observe(duration);
     }
+
+    /// How often each handler ran and how long that took, sorted by handler
+    pub fn handler_stats(&self) -> Vec<HandlerStats> {
+        let mut stats: Vec<_> = self
+            .handler_execution_time
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .filter_map(|metric| {
+                let handler = metric
+                    .get_label()
+                    .iter()
+                    .find(|label| label.get_name() == "handler")?
+                    .get_value()
+                    .to_owned();
+                let histogram = metric.get_histogram();
+                Some(HandlerStats {
+                    handler,
+                    count: histogram.get_sample_count(),
+                    total_time: histogram.get_sample_sum(),
+                    buckets: histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+                        .collect(),
+                })
+            })
+            .collect();
+        stats.sort_by(|a, b| a.handler.cmp(&b.handler));
+        stats
+    }
+}
+
+/// How often a handler of a deployment ran and how long that took
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandlerStats {
+    pub handler: String,
+    /// The number of times the handler ran
+    pub count: u64,
+    /// The combined time of all runs in seconds
+    pub total_time: f64,
+    /// Pairs of a time in seconds and the number of runs that took at most
+    /// that long, in increasing order of time
+    pub buckets: Vec<(f64, u64)>,
+}
+
+impl HandlerStats {
+    /// The average time of a run in seconds; `None` if the handler never ran
+    pub fn average_time(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.total_time / self.count as f64)
+        }
+    }
+}
+
+/// The `HostMetrics` of the deployments that this node is indexing, so that
+/// other components can report on them. The subgraph instance manager adds
+/// deployments when it starts them and removes them when it stops them
+#[derive(Clone, Debug, Default)]
+pub struct SharedHostMetrics(Arc<RwLock<HashMap<SubgraphDeploymentId, Arc<HostMetrics>>>>);
+
+impl CheapClone for SharedHostMetrics {}
+
+impl SharedHostMetrics {
+    pub fn insert(&self, id: SubgraphDeploymentId, metrics: Arc<HostMetrics>) {
+        self.0.write().unwrap().insert(id, metrics);
+    }
+
+    pub fn remove(&self, id: &SubgraphDeploymentId) {
+        self.0.write().unwrap().remove(id);
+    }
+
+    pub fn get(&self, id: &SubgraphDeploymentId) -> Option<Arc<HostMetrics>> {
+        self.0.read().unwrap().get(id).cloned()
+    }
 }
 
 pub trait RuntimeHostBuilder: Clone + Send + Sync + 'static {
@@ -40,4 +40,92 @@ pub trait SubgraphRegistrar: Send + Sync + 'static {
         hash: SubgraphDeploymentId,
         node_id: NodeId,
     ) -> Result<(), SubgraphRegistrarError>;
+
+    /// The names of all subgraphs that have a version that uses the
+    /// deployment
+    async fn subgraph_names(
+        &self,
+        hash: &SubgraphDeploymentId,
+    ) -> Result<Vec<String>, SubgraphRegistrarError>;
+
+    /// Stop indexing the deployment, but keep it assigned to its node
+    async fn pause_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Result<(), SubgraphRegistrarError>;
+
+    /// Start indexing a paused deployment again
+    async fn resume_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Result<(), SubgraphRegistrarError>;
+
+    /// Undo everything the deployment did after `block`. The deployment
+    /// must be paused, and continues indexing from `block` once it is
+    /// resumed
+    async fn rewind_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<(), SubgraphRegistrarError>;
+
+    /// Clear the failed state of the deployment and start indexing it
+    /// again on the node it is assigned to
+    async fn reindex_subgraph(
+        &self,
+        hash: SubgraphDeploymentId,
+    ) -> Result<(), SubgraphRegistrarError>;
+
+    /// Keep only the last `history_blocks` blocks of entity history for the
+    /// deployment, or all of it if `history_blocks` is `None`
+    async fn set_history_blocks(
+        &self,
+        hash: SubgraphDeploymentId,
+        history_blocks: Option<BlockNumber>,
+    ) -> Result<(), SubgraphRegistrarError>;
+
+    /// Create an index on `fields` of `entity_type` in the deployment and
+    /// return its name. Indexing continues while the index is being built
+    async fn create_index(
+        &self,
+        hash: SubgraphDeploymentId,
+        entity_type: String,
+        fields: Vec<String>,
+    ) -> Result<String, SubgraphRegistrarError>;
+
+    /// Drop the index `index_name` from the deployment
+    async fn drop_index(
+        &self,
+        hash: SubgraphDeploymentId,
+        index_name: String,
+    ) -> Result<(), SubgraphRegistrarError>;
+
+    /// Change the schema of the deployment in place to the schema in the
+    /// IPFS file `schema`. The deployment keeps indexing with the same
+    /// mappings
+    async fn evolve_schema(
+        &self,
+        hash: SubgraphDeploymentId,
+        schema: String,
+    ) -> Result<(), SubgraphRegistrarError>;
+
+    /// Move the data of the deployment to the database shard `shard`. The
+    /// data is copied in the background while the deployment keeps
+    /// indexing; the deployment is paused briefly at the end to switch it
+    /// over to the new shard
+    async fn move_deployment(
+        &self,
+        hash: SubgraphDeploymentId,
+        shard: String,
+    ) -> Result<(), SubgraphRegistrarError>;
+
+    /// Switch the deployment back to the shard it was last moved away
+    /// from, and rewind it to the block at which it was moved
+    async fn rollback_move(&self, hash: SubgraphDeploymentId)
+        -> Result<(), SubgraphRegistrarError>;
+
+    /// Drop the data the deployment left behind in the shard it was last
+    /// moved away from. After that, the move can not be rolled back
+    async fn finalize_move(&self, hash: SubgraphDeploymentId)
+        -> Result<(), SubgraphRegistrarError>;
 }
@@ -0,0 +1,381 @@
+//! API keys for the query servers. Operators of a node that is shared by
+//! several clients hand out keys to them, which lets them attribute usage
+//! to clients and put limits on how much of the node each key can use.
+//!
+//! Clients send their key either as a bearer token in the `Authorization`
+//! header or in the `X-Api-Key` header. Requests without a key are only
+//! accepted if `GRAPH_GRAPHQL_REQUIRE_API_KEY` is not set; they all count as
+//! coming from one anonymous client whose limits are those of the key `*`.
+//! Requests with a key that we do not know are always rejected.
+use http::header::{HeaderMap, AUTHORIZATION};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::components::metrics::{CounterVec, GaugeVec, MetricsRegistry};
+use crate::components::server::query::GraphQLServerError;
+use crate::data::graphql::effort::QueryEffort;
+use crate::prelude::{debug, info, o, Logger};
+
+lazy_static! {
+    /// Reject requests that do not come with an API key
+    static ref REQUIRE_API_KEY: bool = env::var("GRAPH_GRAPHQL_REQUIRE_API_KEY").is_ok();
+}
+
+/// The entry in the API key file that holds the limits for requests that
+/// do not come with a key
+const ANONYMOUS_KEY: &str = "*";
+
+/// The configuration of one API key as it appears in the API key file
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyConfig {
+    /// The client the key belongs to. Usage is reported per client
+    pub client: String,
+    /// How many requests the key may be used for per second
+    #[serde(default)]
+    pub max_requests_per_second: Option<f64>,
+    /// How many milliseconds the queries sent with the key may take in
+    /// total over the window set with `GRAPH_LOAD_WINDOW_SIZE`
+    #[serde(default)]
+    pub max_effort: Option<u64>,
+    /// How many subscriptions and live queries may be open with the key at
+    /// the same time
+    #[serde(default)]
+    pub max_subscriptions: Option<usize>,
+}
+
+/// The configuration of all API keys, keyed by the key
+pub type ApiKeyConfigs = BTreeMap<String, ApiKeyConfig>;
+
+struct ApiKeyMetrics {
+    /// Requests that we accepted, by client
+    requests: Box<CounterVec>,
+    /// Requests that we turned away, by client and the reason why
+    rejected: Box<CounterVec>,
+    /// The moving average of the time queries take, by client
+    effort: Box<GaugeVec>,
+    /// Open subscriptions and live queries, by client
+    subscriptions: Box<GaugeVec>,
+}
+
+/// The client that sent a request and the limits that apply to it
+pub struct ApiClient {
+    logger: Logger,
+    config: ApiKeyConfig,
+    /// The number of requests the client can still send right away, and
+    /// when we last computed that
+    allowance: Mutex<(f64, Instant)>,
+    effort: QueryEffort,
+    subscriptions: AtomicUsize,
+    metrics: Arc<ApiKeyMetrics>,
+}
+
+impl fmt::Debug for ApiClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ApiClient {{ client: {} }}", self.name())
+    }
+}
+
+impl ApiClient {
+    fn new(logger: &Logger, config: ApiKeyConfig, metrics: Arc<ApiKeyMetrics>) -> Self {
+        ApiClient {
+            logger: logger.clone(),
+            allowance: Mutex::new((
+                config.max_requests_per_second.unwrap_or(0.0).max(1.0),
+                Instant::now(),
+            )),
+            config,
+            effort: QueryEffort::default(),
+            subscriptions: AtomicUsize::new(0),
+            metrics,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.client
+    }
+
+    fn reject(&self, reason: &str, message: String) -> GraphQLServerError {
+        debug!(self.logger, "Rejecting request"; "client" => self.name(), "reason" => reason);
+        self.metrics
+            .rejected
+            .with_label_values(&[self.name(), reason])
+            .inc();
+        GraphQLServerError::TooManyRequests(message)
+    }
+
+    /// Check that the client may send another request right now. Requests
+    /// are metered with a token bucket that holds as many requests as the
+    /// client may send in one second
+    fn check_request(&self) -> Result<(), GraphQLServerError> {
+        self.check_requests(1)
+    }
+
+    /// Check that the client may send `count` more requests right now, and
+    /// count them against its limits. Either all of them are allowed or none
+    /// are. Each query in a batch counts as one request
+    pub fn check_requests(&self, count: usize) -> Result<(), GraphQLServerError> {
+        if count == 0 {
+            return Ok(());
+        }
+
+        if let Some(rate) = self.config.max_requests_per_second {
+            let mut allowance = self.allowance.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.saturating_duration_since(allowance.1).as_secs_f64();
+            allowance.0 = (allowance.0 + elapsed * rate).min(rate.max(1.0));
+            allowance.1 = now;
+            if allowance.0 < count as f64 {
+                return Err(self.reject(
+                    "rate",
+                    format!("Exceeded the limit of {} requests per second", rate),
+                ));
+            }
+            allowance.0 -= count as f64;
+        }
+
+        if let Some(max_effort) = self.config.max_effort {
+            let effort = self.effort.total_effort();
+            if effort > Duration::from_millis(max_effort) {
+                return Err(self.reject(
+                    "effort",
+                    format!(
+                        "Queries took {}ms recently, which exceeds the limit of {}ms",
+                        effort.as_millis(),
+                        max_effort
+                    ),
+                ));
+            }
+        }
+
+        self.metrics
+            .requests
+            .with_label_values(&[self.name()])
+            .inc_by(count as f64);
+        Ok(())
+    }
+
+    /// Record that running a query for the client took `duration`
+    pub fn add_effort(&self, duration: Duration) {
+        let gauge = self.metrics.effort.with_label_values(&[self.name()]);
+        self.effort.add(0, duration, &gauge);
+    }
+
+    /// Reserve one of the subscriptions the client may have open. The
+    /// subscription counts against the limit until the permit is dropped
+    pub fn subscribe(self: &Arc<Self>) -> Result<SubscriptionPermit, GraphQLServerError> {
+        let open = self.subscriptions.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Some(max_subscriptions) = self.config.max_subscriptions {
+            if open > max_subscriptions {
+                self.subscriptions.fetch_sub(1, Ordering::SeqCst);
+                return Err(self.reject(
+                    "subscriptions",
+                    format!(
+                        "Exceeded the limit of {} open subscriptions",
+                        max_subscriptions
+                    ),
+                ));
+            }
+        }
+        self.metrics
+            .subscriptions
+            .with_label_values(&[self.name()])
+            .inc();
+        Ok(SubscriptionPermit {
+            client: self.clone(),
+        })
+    }
+}
+
+/// An open subscription of a client
+pub struct SubscriptionPermit {
+    client: Arc<ApiClient>,
+}
+
+impl Drop for SubscriptionPermit {
+    fn drop(&mut self) {
+        self.client.subscriptions.fetch_sub(1, Ordering::SeqCst);
+        self.client
+            .metrics
+            .subscriptions
+            .with_label_values(&[self.client.name()])
+            .dec();
+    }
+}
+
+pub struct ApiKeys {
+    logger: Logger,
+    required: bool,
+    /// The clients by their key
+    clients: HashMap<String, Arc<ApiClient>>,
+    /// The client that requests without a key count against
+    anonymous: Arc<ApiClient>,
+    metrics: Arc<ApiKeyMetrics>,
+}
+
+impl fmt::Debug for ApiKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ApiKeys {{ }}")
+    }
+}
+
+impl ApiKeys {
+    pub fn new(logger: &Logger, registry: Arc<dyn MetricsRegistry>, keys: ApiKeyConfigs) -> Self {
+        Self::with_required(logger, registry, keys, *REQUIRE_API_KEY)
+    }
+
+    /// Create API keys that either require or do not require requests to
+    /// come with a key, regardless of `GRAPH_GRAPHQL_REQUIRE_API_KEY`
+    pub fn with_required(
+        logger: &Logger,
+        registry: Arc<dyn MetricsRegistry>,
+        keys: ApiKeyConfigs,
+        required: bool,
+    ) -> Self {
+        let logger = logger.new(o!("component" => "ApiKeys"));
+        let metrics = Arc::new(ApiKeyMetrics {
+            requests: registry
+                .new_counter_vec(
+                    "query_api_key_requests",
+                    "The number of requests that were accepted, by client",
+                    vec![String::from("client")],
+                )
+                .expect("failed to create `query_api_key_requests` counter"),
+            rejected: registry
+                .new_counter_vec(
+                    "query_api_key_rejected",
+                    "The number of requests that were rejected because the client \
+                     exceeded one of its limits",
+                    vec![String::from("client"), String::from("reason")],
+                )
+                .expect("failed to create `query_api_key_rejected` counter"),
+            effort: registry
+                .new_gauge_vec(
+                    "query_api_key_effort_ms",
+                    "Moving average of time spent running queries, by client",
+                    vec![String::from("client")],
+                )
+                .expect("failed to create `query_api_key_effort_ms` gauge"),
+            subscriptions: registry
+                .new_gauge_vec(
+                    "query_api_key_subscriptions",
+                    "The number of open subscriptions and live queries, by client",
+                    vec![String::from("client")],
+                )
+                .expect("failed to create `query_api_key_subscriptions` gauge"),
+        });
+
+        if !keys.is_empty() || required {
+            info!(logger, "Checking API keys";
+                  "keys" => keys.len(), "required" => required);
+        }
+        let mut keys = keys;
+        let anonymous = keys.remove(ANONYMOUS_KEY).unwrap_or_else(|| ApiKeyConfig {
+            client: String::from("anonymous"),
+            ..Default::default()
+        });
+        let anonymous = Arc::new(ApiClient::new(&logger, anonymous, metrics.clone()));
+        let clients = keys
+            .into_iter()
+            .map(|(key, config)| {
+                let client = ApiClient::new(&logger, config, metrics.clone());
+                (key, Arc::new(client))
+            })
+            .collect();
+
+        ApiKeys {
+            logger,
+            required,
+            clients,
+            anonymous,
+            metrics,
+        }
+    }
+
+    /// Find the client that sent a request with `headers` and check that
+    /// it may send another request
+    pub fn check(&self, headers: &HeaderMap) -> Result<Arc<ApiClient>, GraphQLServerError> {
+        self.check_key(api_key(headers))
+    }
+
+    /// Find the client with `key` and check that it may send another
+    /// request. Requests without a key belong to the anonymous client if
+    /// keys are not required
+    pub fn check_key(&self, key: Option<&str>) -> Result<Arc<ApiClient>, GraphQLServerError> {
+        let client = match key {
+            Some(key) => match self.clients.get(key) {
+                Some(client) => client,
+                None => {
+                    self.reject("unknown");
+                    return Err(GraphQLServerError::Unauthorized(String::from(
+                        "Unknown API key",
+                    )));
+                }
+            },
+            None if self.required => {
+                self.reject("missing");
+                return Err(GraphQLServerError::Unauthorized(String::from(
+                    "An API key is required",
+                )));
+            }
+            None => &self.anonymous,
+        };
+        client.check_request()?;
+        Ok(client.clone())
+    }
+
+    fn reject(&self, reason: &str) {
+        debug!(self.logger, "Rejecting request"; "reason" => reason);
+        self.metrics.rejected.with_label_values(&["", reason]).inc();
+    }
+}
+
+/// The API key in `headers`, if there is one
+pub fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            let mut parts = value.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                    Some(token.trim())
+                }
+                _ => None,
+            }
+        })
+        .or_else(|| {
+            headers
+                .get("X-Api-Key")
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn reads_keys_from_headers() {
+        assert_eq!(
+            Some("secret"),
+            api_key(&headers("authorization", "Bearer secret"))
+        );
+        assert_eq!(Some("secret"), api_key(&headers("x-api-key", "secret")));
+        assert_eq!(None, api_key(&headers("authorization", "Basic secret")));
+        assert_eq!(None, api_key(&HeaderMap::new()));
+    }
+}
@@ -66,7 +66,7 @@ lazy_static! {
     };
 }
 
-struct QueryEffort {
+pub(crate) struct QueryEffort {
     inner: Arc<RwLock<QueryEffortInner>>,
 }
 
@@ -94,7 +94,7 @@ impl QueryEffort {
         }
     }
 
-    pub fn add(&self, shape_hash: u64, duration: Duration, gauge: &Box<Gauge>) {
+    pub fn add(&self, shape_hash: u64, duration: Duration, gauge: &Gauge) {
         let mut inner = self.inner.write().unwrap();
         inner.add(shape_hash, duration);
         gauge.set(inner.total.average().unwrap_or(ZERO_DURATION).as_millis() as f64);
@@ -111,6 +111,15 @@ impl QueryEffort {
         let query_effort = inner.effort.get(&shape_hash).map(|stats| stats.duration());
         (query_effort, total_effort)
     }
+
+    /// The total effort over the current window. Unlike `current_effort`,
+    /// this does not count measurements that expired since the last call
+    /// to `add`
+    pub fn total_effort(&self) -> Duration {
+        let mut inner = self.inner.write().unwrap();
+        inner.total.expire_bins(Instant::now());
+        inner.total.duration()
+    }
 }
 
 impl QueryEffortInner {
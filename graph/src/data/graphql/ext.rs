@@ -10,18 +10,34 @@ use std::collections::{BTreeMap, HashMap};
 
 pub trait ObjectTypeExt {
     fn field(&self, name: &Name) -> Option<&Field>;
+
+    /// Return `true` if entities of this type can never be changed or
+    /// removed once they have been created, i.e., if the type is
+    /// annotated with `@entity(immutable: true)`
+    fn is_immutable(&self) -> bool;
 }
 
 impl ObjectTypeExt for ObjectType {
     fn field(&self, name: &Name) -> Option<&Field> {
         self.fields.iter().find(|field| &field.name == name)
     }
+
+    fn is_immutable(&self) -> bool {
+        self.find_directive("entity".to_string())
+            .and_then(|entity| entity.argument("immutable"))
+            .map(|immutable| immutable == &Value::Boolean(true))
+            .unwrap_or(false)
+    }
 }
 
 impl ObjectTypeExt for InterfaceType {
     fn field(&self, name: &Name) -> Option<&Field> {
         self.fields.iter().find(|field| &field.name == name)
     }
+
+    fn is_immutable(&self) -> bool {
+        false
+    }
 }
 
 pub trait DocumentExt {
@@ -161,6 +177,7 @@ impl DocumentExt for Document {
 
 pub trait TypeExt {
     fn get_base_type(&self) -> &Name;
+    fn is_list(&self) -> bool;
 }
 
 impl TypeExt for Type {
@@ -171,6 +188,14 @@ impl TypeExt for Type {
             Type::ListType(inner) => Self::get_base_type(&inner),
         }
     }
+
+    fn is_list(&self) -> bool {
+        match self {
+            Type::NamedType(_) => false,
+            Type::NonNullType(inner) => inner.is_list(),
+            Type::ListType(_) => true,
+        }
+    }
 }
 
 pub trait DirectiveExt {
@@ -25,5 +25,9 @@ pub mod shape_hash;
 
 pub mod effort;
 
+pub mod persisted;
+
+pub mod api_keys;
+
 pub mod object_or_interface;
 pub use object_or_interface::ObjectOrInterface;
@@ -0,0 +1,377 @@
+//! Persisted queries. Clients can send the SHA-256 hash of a query instead
+//! of its text, following the automatic persisted queries (APQ) protocol
+//! that Apollo clients use, and operators can restrict a deployment or
+//! subgraph name to a list of allowed queries.
+//!
+//! Allow-lists are keyed by a 'target', which is either a deployment ID or
+//! a subgraph name. A request for a subgraph name is subject to the
+//! allow-lists of both the name and the deployment the name currently
+//! points to.
+//!
+//! Allow-lists that operators change through the admin server are kept in
+//! an `AllowListStore`, which all query nodes share, and every node reloads
+//! them when any node changes them.
+use futures03::compat::Stream01CompatExt;
+use futures03::stream::StreamExt;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use crate::components::metrics::{CounterVec, MetricsRegistry};
+use crate::components::server::query::GraphQLServerError;
+use crate::data::query::QueryError;
+use crate::prelude::{error, format_err, info, o, Error, Logger, Stream};
+
+lazy_static! {
+    /// The number of queries that clients registered through the APQ
+    /// protocol that we remember
+    static ref CACHE_SIZE: usize = env::var("GRAPH_GRAPHQL_PERSISTED_QUERY_CACHE_SIZE")
+        .ok()
+        .map(|s| {
+            usize::from_str(&s).unwrap_or_else(|_| {
+                panic!(
+                    "GRAPH_GRAPHQL_PERSISTED_QUERY_CACHE_SIZE must be a number, but is `{}`",
+                    s
+                )
+            })
+        })
+        .unwrap_or(10_000);
+}
+
+/// The allow-list for one target as it appears in the allow-list file
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AllowList {
+    /// Reject all queries that are not on the allow-list
+    #[serde(default)]
+    pub allow_list_only: bool,
+    /// The text of the allowed queries
+    #[serde(default)]
+    pub queries: Vec<String>,
+}
+
+/// The allow-lists for all targets, keyed by deployment ID or subgraph name
+pub type AllowLists = BTreeMap<String, AllowList>;
+
+/// The SHA-256 hash of `query` in hex, which is how clients refer to
+/// persisted queries
+pub fn query_hash(query: &str) -> String {
+    hex::encode(Sha256::digest(query.as_bytes()))
+}
+
+/// Where allow-lists are kept so that all query nodes use the same lists
+pub trait AllowListStore: Send + Sync + 'static {
+    /// The allow-lists of all targets
+    fn allow_lists(&self) -> Result<AllowLists, Error>;
+
+    /// Add `query`, whose hash is `hash`, to the allow-list of `target`
+    fn allow(&self, target: &str, hash: &str, query: &str) -> Result<(), Error>;
+
+    /// Remove the query with `hash` from the allow-list of `target`.
+    /// Returns `false` if the query was not on the allow-list
+    fn disallow(&self, target: &str, hash: &str) -> Result<bool, Error>;
+
+    /// Turn the allow-list-only mode for `target` on or off
+    fn set_allow_list_only(&self, target: &str, enabled: bool) -> Result<(), Error>;
+
+    /// A stream with an item for every change that any node makes to the
+    /// allow-lists. Can only be called once
+    fn changes(&self) -> Box<dyn Stream<Item = (), Error = ()> + Send>;
+}
+
+#[derive(Default)]
+struct Target {
+    allow_list_only: bool,
+    /// The allowed queries by their hash
+    queries: HashMap<String, Arc<String>>,
+}
+
+pub struct PersistedQueries {
+    logger: Logger,
+    targets: RwLock<HashMap<String, Target>>,
+    /// Where the allow-lists are stored, if they are shared with other
+    /// nodes
+    store: Option<Arc<dyn AllowListStore>>,
+    /// Queries that clients registered through the APQ protocol, by hash
+    cache: RwLock<HashMap<String, Arc<String>>>,
+    /// Requests for allowed queries, by hash. We only track allowed queries
+    /// since operators control how many of them there are
+    requests: Box<CounterVec>,
+    /// Requests that we turned away, by the reason why
+    rejected: Box<CounterVec>,
+}
+
+impl fmt::Debug for PersistedQueries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PersistedQueries {{ }}")
+    }
+}
+
+impl PersistedQueries {
+    pub fn new(
+        logger: &Logger,
+        registry: Arc<dyn MetricsRegistry>,
+        allow_lists: AllowLists,
+    ) -> Result<Self, Error> {
+        let logger = logger.new(o!("component" => "PersistedQueries"));
+        let requests = registry
+            .new_counter_vec(
+                "query_persisted_requests",
+                "The number of requests for each allowed query",
+                vec![String::from("hash")],
+            )
+            .expect("failed to create `query_persisted_requests` counter");
+        let rejected = registry
+            .new_counter_vec(
+                "query_persisted_rejected",
+                "The number of requests that were rejected because their query is \
+                 unknown or not allowed",
+                vec![String::from("reason")],
+            )
+            .expect("failed to create `query_persisted_rejected` counter");
+
+        let persisted = PersistedQueries {
+            logger,
+            targets: RwLock::new(HashMap::new()),
+            store: None,
+            cache: RwLock::new(HashMap::new()),
+            requests,
+            rejected,
+        };
+        for (target, allow_list) in allow_lists {
+            for query in allow_list.queries {
+                persisted.allow(&target, query)?;
+            }
+            persisted.set_allow_list_only(&target, allow_list.allow_list_only)?;
+        }
+        Ok(persisted)
+    }
+
+    /// Keep the allow-lists in `store`, which other nodes share. The
+    /// allow-lists that this was created with are added to the ones that
+    /// are already in the store
+    pub fn with_store(mut self, store: Arc<dyn AllowListStore>) -> Result<Self, Error> {
+        for (target, allow_list) in self.targets.read().unwrap().iter() {
+            for (hash, query) in &allow_list.queries {
+                store.allow(target, hash, query)?;
+            }
+            if allow_list.allow_list_only {
+                store.set_allow_list_only(target, true)?;
+            }
+        }
+        self.store = Some(store);
+        self.reload()?;
+        Ok(self)
+    }
+
+    /// Reload the allow-lists whenever any node changes them in the store
+    pub fn listen(self: &Arc<Self>) {
+        let changes = match &self.store {
+            Some(store) => store.changes(),
+            None => return,
+        };
+        let persisted = self.clone();
+        crate::spawn(changes.compat().for_each(move |_| {
+            let persisted = persisted.clone();
+            async move {
+                let logger = persisted.logger.clone();
+                match crate::spawn_blocking_allow_panic(move || persisted.reload()).await {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => {
+                        error!(logger, "Failed to reload allow-lists"; "error" => e.to_string())
+                    }
+                    Err(e) => {
+                        error!(logger, "Reloading allow-lists panicked"; "error" => e.to_string())
+                    }
+                }
+            }
+        }));
+    }
+
+    /// Replace the allow-lists with the ones in the store
+    fn reload(&self) -> Result<(), Error> {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+        let targets = store
+            .allow_lists()?
+            .into_iter()
+            .map(|(target, allow_list)| {
+                let queries = allow_list
+                    .queries
+                    .into_iter()
+                    .map(|query| (query_hash(&query), Arc::new(query)))
+                    .collect();
+                let target_lists = Target {
+                    allow_list_only: allow_list.allow_list_only,
+                    queries,
+                };
+                (target, target_lists)
+            })
+            .collect();
+        *self.targets.write().unwrap() = targets;
+        Ok(())
+    }
+
+    /// Determine the text of the query a request for `targets` asks for.
+    /// The request can contain the text of the query, its hash, or both.
+    /// Queries that are not allowed are rejected here, before anybody
+    /// spends any effort on parsing them
+    pub fn resolve(
+        &self,
+        targets: &[&str],
+        query: Option<String>,
+        hash: Option<String>,
+    ) -> Result<String, GraphQLServerError> {
+        let all_targets = self.targets.read().unwrap();
+        let targets: Vec<_> = targets
+            .iter()
+            .filter_map(|target| all_targets.get(*target))
+            .collect();
+        let restricted = targets.iter().any(|target| target.allow_list_only);
+        let allowed = |hash: &str| {
+            targets
+                .iter()
+                .find_map(|target| target.queries.get(hash).cloned())
+        };
+
+        // Whether the client asks us to remember the query for later
+        // requests that only contain its hash
+        let register = query.is_some() && hash.is_some();
+        let (query, hash) = match (query, hash) {
+            (None, None) => {
+                return Err(GraphQLServerError::ClientError(String::from(
+                    "The \"query\" field is missing in request data",
+                )))
+            }
+            (Some(query), None) if targets.is_empty() => return Ok(query),
+            (Some(query), None) => {
+                let hash = query_hash(&query);
+                (Some(query), hash)
+            }
+            (Some(query), Some(hash)) => {
+                if query_hash(&query) != hash.to_lowercase() {
+                    self.reject("hash_mismatch");
+                    return Err(GraphQLServerError::ClientError(String::from(
+                        "The query does not match the hash of the persisted query",
+                    )));
+                }
+                (Some(query), hash.to_lowercase())
+            }
+            (None, Some(hash)) => (None, hash.to_lowercase()),
+        };
+
+        if let Some(allowed) = allowed(&hash) {
+            self.requests.with_label_values(&[&hash]).inc();
+            return Ok(query.unwrap_or_else(|| allowed.as_ref().clone()));
+        }
+        if restricted {
+            self.reject("not_allowed");
+            return Err(QueryError::QueryNotAllowed(hash).into());
+        }
+
+        match query {
+            Some(query) if !register => Ok(query),
+            Some(query) => {
+                let mut cache = self.cache.write().unwrap();
+                if *CACHE_SIZE > 0 && !cache.contains_key(&hash) {
+                    // Clients register their queries again when we forget
+                    // them, so it does not matter which query we evict
+                    if cache.len() >= *CACHE_SIZE {
+                        if let Some(evict) = cache.keys().next().cloned() {
+                            cache.remove(&evict);
+                        }
+                    }
+                    cache.insert(hash, Arc::new(query.clone()));
+                }
+                Ok(query)
+            }
+            None => match self.cache.read().unwrap().get(&hash) {
+                Some(query) => Ok(query.as_ref().clone()),
+                None => {
+                    self.reject("not_found");
+                    Err(QueryError::PersistedQueryNotFound.into())
+                }
+            },
+        }
+    }
+
+    fn reject(&self, reason: &str) {
+        self.rejected.with_label_values(&[reason]).inc();
+    }
+
+    /// Add `query` to the allow-list of `target` and return its hash
+    pub fn allow(&self, target: &str, query: String) -> Result<String, Error> {
+        graphql_parser::parse_query(&query)
+            .map_err(|e| format_err!("invalid GraphQL query: {}", e))?;
+        let hash = query_hash(&query);
+        info!(self.logger, "Allowing query"; "target" => target, "hash" => &hash);
+        if let Some(store) = &self.store {
+            store.allow(target, &hash, &query)?;
+        }
+        self.targets
+            .write()
+            .unwrap()
+            .entry(target.to_owned())
+            .or_default()
+            .queries
+            .insert(hash.clone(), Arc::new(query));
+        Ok(hash)
+    }
+
+    /// Remove the query with `hash` from the allow-list of `target`.
+    /// Returns `false` if the query was not on the allow-list
+    pub fn disallow(&self, target: &str, hash: &str) -> Result<bool, Error> {
+        info!(self.logger, "Disallowing query"; "target" => target, "hash" => hash);
+        let hash = hash.to_lowercase();
+        let stored = match &self.store {
+            Some(store) => store.disallow(target, &hash)?,
+            None => false,
+        };
+        let removed = self
+            .targets
+            .write()
+            .unwrap()
+            .get_mut(target)
+            .map_or(false, |target| target.queries.remove(&hash).is_some());
+        Ok(stored || removed)
+    }
+
+    /// Turn the allow-list-only mode for `target` on or off
+    pub fn set_allow_list_only(&self, target: &str, enabled: bool) -> Result<(), Error> {
+        info!(self.logger, "Setting allow-list-only mode";
+              "target" => target, "enabled" => enabled);
+        if let Some(store) = &self.store {
+            store.set_allow_list_only(target, enabled)?;
+        }
+        self.targets
+            .write()
+            .unwrap()
+            .entry(target.to_owned())
+            .or_default()
+            .allow_list_only = enabled;
+        Ok(())
+    }
+
+    /// Whether `target` only accepts allowed queries, and its allowed
+    /// queries by hash
+    pub fn allow_list(&self, target: &str) -> (bool, BTreeMap<String, String>) {
+        match self.targets.read().unwrap().get(target) {
+            Some(target) => (
+                target.allow_list_only,
+                target
+                    .queries
+                    .iter()
+                    .map(|(hash, query)| (hash.clone(), query.as_ref().clone()))
+                    .collect(),
+            ),
+            None => (false, BTreeMap::new()),
+        }
+    }
+}
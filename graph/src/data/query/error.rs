@@ -263,6 +263,12 @@ pub enum QueryError {
     EncodingError(FromUtf8Error),
     ParseError(Arc<anyhow::Error>),
     ExecutionError(QueryExecutionError),
+    /// The client sent only the hash of a query that we do not know; it
+    /// should send the query text together with the hash
+    PersistedQueryNotFound,
+    /// The query with this hash is not on the allow-list of a subgraph
+    /// that only accepts allowed queries
+    QueryNotAllowed(String),
 }
 
 impl From<FromUtf8Error> for QueryError {
@@ -297,6 +303,12 @@ impl fmt::Display for QueryError {
             QueryError::EncodingError(ref e) => write!(f, "{}", e),
             QueryError::ExecutionError(ref e) => write!(f, "{}", e),
             QueryError::ParseError(ref e) => write!(f, "{}", e),
+            // Clients that follow the persisted query protocol look for
+            // exactly this message
+            QueryError::PersistedQueryNotFound => write!(f, "PersistedQueryNotFound"),
+            QueryError::QueryNotAllowed(ref hash) => {
+                write!(f, "The query with hash {} is not allowed", hash)
+            }
         }
     }
 }
@@ -314,6 +326,8 @@ impl Serialize for QueryError {
             }) = self
             {
                 3
+            } else if let QueryError::PersistedQueryNotFound = self {
+                2
             } else {
                 1
             };
@@ -379,6 +393,12 @@ impl Serialize for QueryError {
                 map.serialize_entry("prefetch", &SerializableValue(&prefetch))?;
                 format!("{}", self)
             }
+            QueryError::PersistedQueryNotFound => {
+                let mut extensions = HashMap::new();
+                extensions.insert("code", "PERSISTED_QUERY_NOT_FOUND");
+                map.serialize_entry("extensions", &extensions)?;
+                format!("{}", self)
+            }
             _ => format!("{}", self),
         };
 
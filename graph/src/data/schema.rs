@@ -42,6 +42,10 @@ pub enum SchemaValidationError {
 
     #[fail(display = "@entity directive missing on the following types: `{}`", _0)]
     EntityDirectivesMissing(Strings),
+    #[fail(display = "Type `{}` has an invalid @entity directive: {}", _0, _1)]
+    EntityDirectiveInvalid(String, String), // (type, reason)
+    #[fail(display = "Type `{}` has an invalid index directive: {}", _0, _1)]
+    IndexDirectiveInvalid(String, String), // (type, reason)
 
     #[fail(
         display = "Entity type `{}` does not satisfy interface `{}` because it is missing \
@@ -660,6 +664,8 @@ impl Schema {
         let mut errors = vec![];
         self.validate_schema_types()
             .unwrap_or_else(|err| errors.push(err));
+        errors.append(&mut self.validate_entity_directives());
+        errors.append(&mut self.validate_index_directives());
         self.validate_derived_from()
             .unwrap_or_else(|err| errors.push(err));
         self.validate_schema_type_has_no_fields()
@@ -1101,6 +1107,117 @@ impl Schema {
         }
     }
 
+    /// Check that the arguments of `@entity` directives are valid. The
+    /// only argument we accept is `immutable`, which must be a boolean
+    fn validate_entity_directives(&self) -> Vec<SchemaValidationError> {
+        self.document
+            .get_object_type_definitions()
+            .into_iter()
+            .filter_map(|object_type| {
+                object_type
+                    .find_directive(String::from("entity"))
+                    .map(|entity| (object_type, entity))
+            })
+            .flat_map(|(object_type, entity)| {
+                entity.arguments.iter().filter_map(move |(name, value)| {
+                    match (name.as_str(), value) {
+                        ("immutable", Value::Boolean(_)) => None,
+                        ("immutable", _) => Some(SchemaValidationError::EntityDirectiveInvalid(
+                            object_type.name.clone(),
+                            "the `immutable` argument must be a boolean".to_owned(),
+                        )),
+                        (name, _) => Some(SchemaValidationError::EntityDirectiveInvalid(
+                            object_type.name.clone(),
+                            format!("unknown argument `{}`", name),
+                        )),
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Check `@index(fields: [..])` directives on entity types and
+    /// `@noIndex` directives on their fields. An `@index` must list at
+    /// least one field, and all fields must be stored, scalar attributes
+    /// of the type. The `id` of an entity must always be indexed
+    fn validate_index_directives(&self) -> Vec<SchemaValidationError> {
+        let mut errors = vec![];
+        for object_type in self.document.get_object_type_definitions() {
+            let invalid = |reason: String| {
+                SchemaValidationError::IndexDirectiveInvalid(object_type.name.clone(), reason)
+            };
+
+            for index in object_type
+                .directives
+                .iter()
+                .filter(|directive| directive.name == "index")
+            {
+                if index.arguments.iter().any(|(name, _)| name != "fields") {
+                    errors.push(invalid(
+                        "@index only accepts a `fields` argument".to_owned(),
+                    ));
+                    continue;
+                }
+                let fields = match index.argument("fields").and_then(|fields| fields.as_list()) {
+                    Some(fields) if !fields.is_empty() => fields,
+                    _ => {
+                        errors.push(invalid(
+                            "@index requires a non-empty list of field names in `fields`"
+                                .to_owned(),
+                        ));
+                        continue;
+                    }
+                };
+                for field in fields {
+                    let name = match field.as_string() {
+                        Some(name) => name,
+                        None => {
+                            errors.push(invalid(format!(
+                                "the entries in `fields` must be strings, but `{}` is not",
+                                field
+                            )));
+                            continue;
+                        }
+                    };
+                    match object_type.fields.iter().find(|field| &field.name == name) {
+                        None => errors.push(invalid(format!(
+                            "@index refers to the unknown field `{}`",
+                            name
+                        ))),
+                        Some(field)
+                            if field.find_directive(String::from("derivedFrom")).is_some() =>
+                        {
+                            errors.push(invalid(format!(
+                                "@index can not use the derived field `{}`",
+                                name
+                            )))
+                        }
+                        Some(field) if field.field_type.is_list() => errors.push(invalid(format!(
+                            "@index can not use the list field `{}`",
+                            name
+                        ))),
+                        Some(_) => (),
+                    }
+                }
+            }
+
+            for field in &object_type.fields {
+                if let Some(no_index) = field.find_directive(String::from("noIndex")) {
+                    if !no_index.arguments.is_empty() {
+                        errors.push(invalid(format!(
+                            "@noIndex on field `{}` does not accept arguments",
+                            field.name
+                        )));
+                    }
+                    if field.name == "id" {
+                        errors.push(invalid("the `id` field must be indexed".to_owned()));
+                    }
+                }
+            }
+        }
+        errors
+    }
+
     fn validate_derived_from(&self) -> Result<(), SchemaValidationError> {
         // Helper to construct a DerivedFromInvalid
         fn invalid(
@@ -1574,3 +1691,72 @@ type Gravatar @entity {
 
     assert_eq!(schema.validate_fulltext_directives(), vec![]);
 }
+
+#[test]
+fn test_entity_directive_validation() {
+    fn validate(directive: &str) -> Vec<SchemaValidationError> {
+        let raw = format!("type Transfer {} {{ id: ID! }}", directive);
+        let document = graphql_parser::parse_schema(&raw).expect("Failed to parse schema");
+        let schema = Schema::new(SubgraphDeploymentId::new("id1").unwrap(), document);
+        schema.validate_entity_directives()
+    }
+
+    assert_eq!(validate("@entity"), vec![]);
+    assert_eq!(validate("@entity(immutable: true)"), vec![]);
+    assert_eq!(validate("@entity(immutable: false)"), vec![]);
+    assert_eq!(
+        validate("@entity(immutable: \"yes\")"),
+        vec![SchemaValidationError::EntityDirectiveInvalid(
+            "Transfer".to_owned(),
+            "the `immutable` argument must be a boolean".to_owned()
+        )]
+    );
+    assert_eq!(
+        validate("@entity(frozen: true)"),
+        vec![SchemaValidationError::EntityDirectiveInvalid(
+            "Transfer".to_owned(),
+            "unknown argument `frozen`".to_owned()
+        )]
+    );
+}
+
+#[test]
+fn test_index_directive_validation() {
+    fn validate(raw: &str) -> Vec<SchemaValidationError> {
+        let document = graphql_parser::parse_schema(raw).expect("Failed to parse schema");
+        let schema = Schema::new(SubgraphDeploymentId::new("id1").unwrap(), document);
+        schema.validate_index_directives()
+    }
+
+    fn invalid(reason: &str) -> Vec<SchemaValidationError> {
+        vec![SchemaValidationError::IndexDirectiveInvalid(
+            "Swap".to_owned(),
+            reason.to_owned(),
+        )]
+    }
+
+    assert_eq!(
+        validate(
+            "type Swap @entity @index(fields: [\"pair\", \"timestamp\"]) {
+               id: ID!, pair: String!, timestamp: Int!, note: String @noIndex
+             }"
+        ),
+        vec![]
+    );
+    assert_eq!(
+        validate("type Swap @entity @index(fields: []) { id: ID! }"),
+        invalid("@index requires a non-empty list of field names in `fields`")
+    );
+    assert_eq!(
+        validate("type Swap @entity @index(fields: [\"pair\"]) { id: ID! }"),
+        invalid("@index refers to the unknown field `pair`")
+    );
+    assert_eq!(
+        validate("type Swap @entity @index(fields: [\"tags\"]) { id: ID!, tags: [String!]! }"),
+        invalid("@index can not use the list field `tags`")
+    );
+    assert_eq!(
+        validate("type Swap @entity { id: ID! @noIndex }"),
+        invalid("the `id` field must be indexed")
+    );
+}
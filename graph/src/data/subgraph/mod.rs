@@ -282,6 +282,12 @@ pub enum SubgraphRegistrarError {
     DeploymentNotFound(String),
     #[fail(display = "deployment assignment unchanged: {}", _0)]
     DeploymentAssignmentUnchanged(String),
+    #[fail(display = "deployment is paused: {}", _0)]
+    DeploymentPaused(String),
+    #[fail(display = "deployment must be paused first: {}", _0)]
+    DeploymentNotPaused(String),
+    #[fail(display = "deployment has not failed: {}", _0)]
+    DeploymentNotFailed(String),
     #[fail(display = "subgraph registrar internal query error: {}", _0)]
     QueryExecutionError(QueryExecutionError),
     #[fail(display = "subgraph registrar error with store: {}", _0)]
@@ -411,7 +417,9 @@ impl From<serde_yaml::Error> for SubgraphManifestResolveError {
 /// Data source contexts are conveniently represented as entities.
 pub type DataSourceContext = Entity;
 
-/// IPLD link.
+/// IPLD link. Besides IPFS paths like `/ipfs/<hash>`, the link can be a URI
+/// such as `ipfs://<hash>`, `file://<path>` or `ar://<transaction id>`; which
+/// of these are supported depends on the `LinkResolver` that is used.
 #[derive(Clone, Debug, Default, Hash, Eq, PartialEq, Deserialize)]
 pub struct Link {
     #[serde(rename = "/")]
@@ -15,7 +15,6 @@
 pub mod queries;
 
 use graphql_parser::query as q;
-use graphql_parser::schema::{Definition, Document, Field, Name, Type, TypeDefinition};
 use hex;
 use lazy_static::lazy_static;
 use rand::rngs::OsRng;
@@ -28,11 +27,11 @@ use web3::types::*;
 use super::SubgraphDeploymentId;
 use crate::components::ethereum::EthereumBlockPointer;
 use crate::components::store::{
-    AttributeIndexDefinition, EntityCollection, EntityFilter, EntityKey, EntityOperation,
-    EntityQuery, EntityRange, MetadataOperation,
+    EntityCollection, EntityFilter, EntityKey, EntityOperation, EntityQuery, EntityRange,
+    MetadataOperation,
 };
 use crate::data::graphql::{TryFromValue, ValueMap};
-use crate::data::store::{Entity, NodeId, SubgraphEntityPair, Value, ValueType};
+use crate::data::store::{Entity, NodeId, SubgraphEntityPair, Value};
 use crate::data::subgraph::{SubgraphManifest, SubgraphName};
 use crate::prelude::*;
 
@@ -480,6 +479,7 @@ impl SubgraphDeploymentEntity {
 pub struct SubgraphDeploymentAssignmentEntity {
     node_id: NodeId,
     cost: u64,
+    paused: bool,
 }
 
 impl TypedEntity for SubgraphDeploymentAssignmentEntity {
@@ -489,7 +489,22 @@ impl TypedEntity for SubgraphDeploymentAssignmentEntity {
 
 impl SubgraphDeploymentAssignmentEntity {
     pub fn new(node_id: NodeId) -> Self {
-        Self { node_id, cost: 1 }
+        Self {
+            node_id,
+            cost: 1,
+            paused: false,
+        }
+    }
+
+    /// Keep the deployment assigned to its node, but stop indexing it
+    pub fn paused(mut self, paused: bool) -> Self {
+        self.paused = paused;
+        self
+    }
+
+    /// Whether the assignment `entity` is paused
+    pub fn is_paused(entity: &Entity) -> bool {
+        entity.get("paused") == Some(&Value::Bool(true))
     }
 
     pub fn write_operations(self, id: &SubgraphDeploymentId) -> Vec<MetadataOperation> {
@@ -497,6 +512,7 @@ impl SubgraphDeploymentAssignmentEntity {
             id: id.to_string(),
             nodeId: self.node_id.to_string(),
             cost: self.cost,
+            paused: self.paused,
         };
         vec![set_metadata_operation(Self::TYPENAME, id.as_str(), entity)]
     }
@@ -1405,101 +1421,3 @@ pub fn generate_entity_id() -> String {
     // and without spending bits on a version identifier.
     hex::encode(id_bytes)
 }
-
-pub fn attribute_index_definitions(
-    subgraph_id: SubgraphDeploymentId,
-    document: Document,
-) -> Vec<AttributeIndexDefinition> {
-    let mut indexing_ops = vec![];
-    for (entity_number, schema_type) in document.definitions.clone().into_iter().enumerate() {
-        if let Definition::TypeDefinition(definition) = schema_type {
-            if let TypeDefinition::Object(schema_object) = definition {
-                for (attribute_number, entity_field) in schema_object
-                    .fields
-                    .into_iter()
-                    .filter(|f| f.name != "id")
-                    .enumerate()
-                {
-                    // Skip derived fields since they are not stored in objects
-                    // of this type. We can not put this check into the filter
-                    // above since that changes how indexes are numbered
-                    if is_derived_field(&entity_field) {
-                        continue;
-                    }
-                    indexing_ops.push(AttributeIndexDefinition {
-                        subgraph_id: subgraph_id.clone(),
-                        entity_number,
-                        attribute_number,
-                        field_value_type: match inner_type_name(
-                            &entity_field.field_type,
-                            &document.definitions,
-                        ) {
-                            Ok(value_type) => value_type,
-                            Err(_) => continue,
-                        },
-                        attribute_name: entity_field.name,
-                        entity_name: schema_object.name.clone(),
-                    });
-                }
-            }
-        }
-    }
-    indexing_ops
-}
-
-fn is_derived_field(field: &Field) -> bool {
-    field
-        .directives
-        .iter()
-        .any(|dir| dir.name == Name::from("derivedFrom"))
-}
-
-// This largely duplicates graphql::schema::ast::is_entity_type_definition
-// We do not use that function here to avoid this crate depending on
-// graph_graphql
-fn is_entity(type_name: &str, definitions: &[Definition]) -> bool {
-    use self::TypeDefinition::*;
-
-    definitions.iter().any(|defn| {
-        if let Definition::TypeDefinition(type_def) = defn {
-            match type_def {
-                // Entity types are obvious
-                Object(object_type) => {
-                    object_type.name == type_name
-                        && object_type
-                            .directives
-                            .iter()
-                            .any(|directive| directive.name == "entity")
-                }
-
-                // We assume that only entities can implement interfaces;
-                // thus, any interface type definition is automatically
-                // an entity type
-                Interface(interface_type) => interface_type.name == type_name,
-
-                // Everything else (unions, scalars, enums) are not
-                // considered entity types
-                _ => false,
-            }
-        } else {
-            false
-        }
-    })
-}
-
-/// Returns the value type for a GraphQL field type.
-fn inner_type_name(field_type: &Type, definitions: &[Definition]) -> Result<ValueType, Error> {
-    match field_type {
-        Type::NamedType(ref name) => ValueType::from_str(&name).or_else(|e| {
-            if is_entity(name, definitions) {
-                // The field is a reference to another type and therefore
-                // of type `String`
-                Ok(ValueType::String)
-            } else {
-                Err(e)
-            }
-        }),
-        Type::NonNullType(inner) => inner_type_name(&inner, definitions),
-        Type::ListType(inner) => inner_type_name(inner, definitions).and(Ok(ValueType::List)),
-    }
-}
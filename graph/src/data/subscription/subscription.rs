@@ -1,11 +1,22 @@
+use std::sync::Arc;
+
+use crate::data::graphql::api_keys::ApiClient;
 use crate::prelude::Query;
 
 /// A GraphQL subscription made by a client.
 ///
-/// At the moment, this only contains the GraphQL query submitted as the
-/// subscription payload.
+/// This contains the GraphQL query submitted as the subscription payload
+/// and how the client wants to receive results.
 #[derive(Clone, Debug)]
 pub struct Subscription {
     /// The GraphQL subscription query.
     pub query: Query,
+
+    /// Send only the entities that changed with each update instead of
+    /// the whole result.
+    pub diff: bool,
+
+    /// The client whose API key the subscription came with. The time spent
+    /// running the subscription counts against the client's effort.
+    pub client: Option<Arc<ApiClient>>,
 }
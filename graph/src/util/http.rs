@@ -0,0 +1,44 @@
+use bytes::{Bytes, BytesMut};
+use failure::{format_err, Error};
+
+/// Read the body of `res`, failing as soon as it turns out to be larger than
+/// `max_bytes`. The body is read chunk by chunk so that an oversized response
+/// is never held in memory in full.
+pub async fn read_body(
+    url: &str,
+    mut res: reqwest::Response,
+    max_bytes: Option<u64>,
+) -> Result<Bytes, Error> {
+    let max_bytes = match max_bytes {
+        Some(max_bytes) => max_bytes,
+        None => return Ok(res.bytes().await?),
+    };
+
+    if let Some(size) = res.content_length() {
+        if size > max_bytes {
+            return Err(format_err!(
+                "file {} is too large. It can be at most {} bytes but is {} bytes",
+                url,
+                max_bytes,
+                size
+            ));
+        }
+    }
+
+    // The `Content-Length` header can be missing or wrong, so we still need
+    // to count the bytes as they arrive
+    let mut body = BytesMut::new();
+    while let Some(chunk) = res.chunk().await? {
+        let size = (body.len() + chunk.len()) as u64;
+        if size > max_bytes {
+            return Err(format_err!(
+                "file {} is too large. It can be at most {} bytes but is at least {} bytes",
+                url,
+                max_bytes,
+                size
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
@@ -7,6 +7,9 @@ pub mod ethereum;
 /// Security utilities.
 pub mod security;
 
+/// Utilities for reading HTTP responses.
+pub mod http;
+
 pub mod lfu_cache;
 
 pub mod error;
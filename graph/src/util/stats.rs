@@ -163,7 +163,8 @@ impl MovingStats {
         self.total.add(duration);
     }
 
-    fn expire_bins(&mut self, now: Instant) {
+    /// Forget the measurements that are older than `window_size` at `now`
+    pub fn expire_bins(&mut self, now: Instant) {
         while self
             .bins
             .front()
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use graph::components::server::query::GraphQLServerError;
+use graph::data::graphql::api_keys::{ApiClient, ApiKeyConfig, ApiKeys};
+use graph::prelude::{o, slog, Logger};
+use graph_mock::MockMetricsRegistry;
+
+fn api_keys(keys: Vec<(&str, ApiKeyConfig)>, required: bool) -> ApiKeys {
+    let logger = Logger::root(slog::Discard, o!());
+    let registry = Arc::new(MockMetricsRegistry::new());
+    let keys = keys
+        .into_iter()
+        .map(|(key, config)| (key.to_owned(), config))
+        .collect();
+    ApiKeys::with_required(&logger, registry, keys, required)
+}
+
+fn too_many_requests(result: Result<Arc<ApiClient>, GraphQLServerError>) -> bool {
+    matches!(result, Err(GraphQLServerError::TooManyRequests(_)))
+}
+
+#[test]
+fn limits_effort() {
+    let keys = api_keys(
+        vec![(
+            "secret",
+            ApiKeyConfig {
+                client: String::from("team-a"),
+                max_effort: Some(100),
+                ..Default::default()
+            },
+        )],
+        false,
+    );
+
+    let client = keys.check_key(Some("secret")).unwrap();
+    client.add_effort(Duration::from_millis(60));
+    assert!(keys.check_key(Some("secret")).is_ok());
+    client.add_effort(Duration::from_millis(60));
+    assert!(too_many_requests(keys.check_key(Some("secret"))));
+}
+
+#[test]
+fn limits_subscriptions() {
+    let keys = api_keys(
+        vec![(
+            "secret",
+            ApiKeyConfig {
+                client: String::from("team-a"),
+                max_subscriptions: Some(2),
+                ..Default::default()
+            },
+        )],
+        false,
+    );
+
+    let client = keys.check_key(Some("secret")).unwrap();
+    let first = client.subscribe().unwrap();
+    let _second = client.subscribe().unwrap();
+    assert!(matches!(
+        client.subscribe(),
+        Err(GraphQLServerError::TooManyRequests(_))
+    ));
+    // Closing a subscription makes room for another one
+    drop(first);
+    assert!(client.subscribe().is_ok());
+}
+
+#[test]
+fn limits_requests_without_key() {
+    let anonymous = ApiKeyConfig {
+        client: String::from("everybody"),
+        max_subscriptions: Some(1),
+        ..Default::default()
+    };
+
+    let keys = api_keys(vec![("*", anonymous.clone())], false);
+    let client = keys.check_key(None).unwrap();
+    assert_eq!("everybody", client.name());
+    let _permit = client.subscribe().unwrap();
+    assert!(keys.check_key(None).unwrap().subscribe().is_err());
+
+    // Keyless requests without limits still count as the anonymous client
+    let keys = api_keys(vec![], false);
+    assert_eq!("anonymous", keys.check_key(None).unwrap().name());
+
+    let keys = api_keys(vec![("*", anonymous)], true);
+    assert!(matches!(
+        keys.check_key(None),
+        Err(GraphQLServerError::Unauthorized(_))
+    ));
+}
@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use graph::prelude::{
+    o, slog, HandlerStats, HostMetrics, Logger, StopwatchMetrics, SubgraphDeploymentId,
+};
+use graph_mock::MockMetricsRegistry;
+
+fn host_metrics() -> HostMetrics {
+    let registry = Arc::new(MockMetricsRegistry::new());
+    let stopwatch = StopwatchMetrics::new(
+        Logger::root(slog::Discard, o!()),
+        SubgraphDeploymentId::new("hostMetrics").unwrap(),
+        registry.clone(),
+    );
+    HostMetrics::new(registry, "hostMetrics", stopwatch)
+}
+
+#[test]
+fn reports_handler_stats() {
+    let metrics = host_metrics();
+    assert_eq!(Vec::<HandlerStats>::new(), metrics.handler_stats());
+
+    metrics.observe_handler_execution_time(0.25, "handleTransfer");
+    metrics.observe_handler_execution_time(2.0, "handleApproval");
+    metrics.observe_handler_execution_time(0.25, "handleApproval");
+    // Host functions are not handlers
+    metrics.observe_host_fn_execution_time(1.0, "ethereum.call");
+
+    let stats = metrics.handler_stats();
+    assert_eq!(
+        vec![
+            HandlerStats {
+                handler: String::from("handleApproval"),
+                count: 2,
+                total_time: 2.25,
+                buckets: vec![(0.1, 0), (0.5, 1), (1.0, 1), (10.0, 2), (100.0, 2)],
+            },
+            HandlerStats {
+                handler: String::from("handleTransfer"),
+                count: 1,
+                total_time: 0.25,
+                buckets: vec![(0.1, 0), (0.5, 1), (1.0, 1), (10.0, 1), (100.0, 1)],
+            },
+        ],
+        stats
+    );
+    assert_eq!(Some(1.125), stats[0].average_time());
+}
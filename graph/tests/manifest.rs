@@ -4,7 +4,9 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 
-use graph::components::link_resolver::{JsonValueStream, LinkResolver as LinkResolverTrait};
+use graph::components::link_resolver::{
+    JsonValueStream, LinkResolver as LinkResolverTrait, StreamFormat,
+};
 use graph::prelude::{
     Entity, Link, SubgraphDeploymentId, SubgraphManifest, SubgraphManifestValidationError,
     UnvalidatedSubgraphManifest,
@@ -44,6 +46,7 @@ impl LinkResolverTrait for TextResolver {
         &self,
         _logger: &Logger,
         _link: &Link,
+        _format: StreamFormat,
     ) -> Result<JsonValueStream, failure::Error> {
         unimplemented!()
     }
@@ -0,0 +1,133 @@
+use std::sync::Arc;
+
+use graph::components::server::query::GraphQLServerError;
+use graph::data::graphql::persisted::{query_hash, AllowList, AllowLists, PersistedQueries};
+use graph::prelude::{o, slog, Logger, QueryError};
+use graph_mock::MockMetricsRegistry;
+
+const QUERY: &str = "{ users { id } }";
+const OTHER_QUERY: &str = "{ users { name } }";
+
+fn persisted_queries(allow_lists: AllowLists) -> PersistedQueries {
+    let logger = Logger::root(slog::Discard, o!());
+    let registry = Arc::new(MockMetricsRegistry::new());
+    PersistedQueries::new(&logger, registry, allow_lists).unwrap()
+}
+
+fn resolve(
+    persisted: &PersistedQueries,
+    query: Option<&str>,
+    hash: Option<&str>,
+) -> Result<String, GraphQLServerError> {
+    persisted.resolve(
+        &["users", "QmUsers"],
+        query.map(str::to_owned),
+        hash.map(str::to_owned),
+    )
+}
+
+#[test]
+fn apq_hash_miss() {
+    let persisted = persisted_queries(AllowLists::new());
+    let hash = query_hash(QUERY);
+
+    // The hash alone is not enough until a client has sent the query
+    let res = resolve(&persisted, None, Some(&hash));
+    assert!(matches!(
+        res,
+        Err(GraphQLServerError::QueryError(
+            QueryError::PersistedQueryNotFound
+        ))
+    ));
+
+    assert_eq!(
+        QUERY,
+        resolve(&persisted, Some(QUERY), Some(&hash)).unwrap()
+    );
+    assert_eq!(QUERY, resolve(&persisted, None, Some(&hash)).unwrap());
+    // Hashes are not case sensitive
+    assert_eq!(
+        QUERY,
+        resolve(&persisted, None, Some(&hash.to_uppercase())).unwrap()
+    );
+
+    // Sending a query without its hash does not register it
+    let res = resolve(&persisted, Some(OTHER_QUERY), None);
+    assert_eq!(OTHER_QUERY, res.unwrap());
+    let res = resolve(&persisted, None, Some(&query_hash(OTHER_QUERY)));
+    assert!(matches!(
+        res,
+        Err(GraphQLServerError::QueryError(
+            QueryError::PersistedQueryNotFound
+        ))
+    ));
+
+    let res = resolve(&persisted, None, None);
+    assert!(matches!(res, Err(GraphQLServerError::ClientError(_))));
+}
+
+#[test]
+fn apq_hash_mismatch() {
+    let persisted = persisted_queries(AllowLists::new());
+    let hash = query_hash(QUERY);
+
+    let res = resolve(&persisted, Some(OTHER_QUERY), Some(&hash));
+    assert!(matches!(res, Err(GraphQLServerError::ClientError(_))));
+
+    // The mismatched query was not registered under the hash
+    let res = resolve(&persisted, None, Some(&hash));
+    assert!(matches!(
+        res,
+        Err(GraphQLServerError::QueryError(
+            QueryError::PersistedQueryNotFound
+        ))
+    ));
+}
+
+#[test]
+fn allow_list_only() {
+    let mut allow_lists = AllowLists::new();
+    allow_lists.insert(
+        "QmUsers".to_owned(),
+        AllowList {
+            allow_list_only: true,
+            queries: vec![QUERY.to_owned()],
+        },
+    );
+    let persisted = persisted_queries(allow_lists);
+    let hash = query_hash(QUERY);
+    let other_hash = query_hash(OTHER_QUERY);
+
+    // Allowed queries can be sent by text, by hash, or both
+    assert_eq!(QUERY, resolve(&persisted, Some(QUERY), None).unwrap());
+    assert_eq!(QUERY, resolve(&persisted, None, Some(&hash)).unwrap());
+    assert_eq!(
+        QUERY,
+        resolve(&persisted, Some(QUERY), Some(&hash)).unwrap()
+    );
+
+    // Other queries are rejected, even when a client tries to register
+    // them through APQ
+    for (query, hash) in &[
+        (Some(OTHER_QUERY), None),
+        (Some(OTHER_QUERY), Some(other_hash.as_str())),
+        (None, Some(other_hash.as_str())),
+    ] {
+        let res = resolve(&persisted, *query, *hash);
+        assert!(matches!(
+            res,
+            Err(GraphQLServerError::QueryError(QueryError::QueryNotAllowed(ref rejected)))
+                if rejected == &other_hash
+        ));
+    }
+
+    // The allow-list does not restrict other targets
+    let res = persisted.resolve(&["other"], Some(OTHER_QUERY.to_owned()), None);
+    assert_eq!(OTHER_QUERY, res.unwrap());
+
+    persisted.set_allow_list_only("QmUsers", false).unwrap();
+    assert_eq!(
+        OTHER_QUERY,
+        resolve(&persisted, Some(OTHER_QUERY), None).unwrap()
+    );
+}
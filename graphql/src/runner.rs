@@ -13,8 +13,8 @@ use crate::subscription::execute_prepared_subscription;
 use graph::data::graphql::effort::LoadManager;
 use graph::prelude::{
     async_trait, o, CheapClone, EthereumBlockPointer, GraphQlRunner as GraphQlRunnerTrait, Logger,
-    Query, QueryExecutionError, QueryResult, Store, StoreError, SubgraphDeploymentId,
-    SubgraphDeploymentStore, Subscription, SubscriptionError, SubscriptionResult,
+    Query, QueryExecutionError, QueryResult, Store, SubgraphDeploymentId, SubgraphDeploymentStore,
+    Subscription, SubscriptionError, SubscriptionResult,
 };
 
 use lazy_static::lazy_static;
@@ -82,24 +82,13 @@ where
             .map_err(|e| vec![QueryExecutionError::StoreError(e.into())])?
             .map(|s| format!("ethereum/{}", s))
             .unwrap_or("unknown".to_string());
-        let network_info = if self
-            .store
-            .uses_relational_schema(subgraph)
-            .map_err(|e| vec![StoreError::from(e).into()])?
-        {
-            // Relational storage: produce the full output
-            object_value(vec![(
-                network.as_str(),
-                object! {
-                        hash: block_ptr.hash_hex(),
-                        number: q::Number::from(block_ptr.number as i32)
-                },
-            )])
-        } else {
-            // JSONB storage: we can not reliably report anything about
-            // the block where the query happened
-            object_value(vec![(network.as_str(), object_value(vec![]))])
-        };
+        let network_info = object_value(vec![(
+            network.as_str(),
+            object! {
+                    hash: block_ptr.hash_hex(),
+                    number: q::Number::from(block_ptr.number as i32)
+            },
+        )]);
         let mut exts = BTreeMap::new();
         exts.insert(
             "subgraph".to_owned(),
@@ -228,6 +217,8 @@ where
 
         execute_prepared_subscription(
             query,
+            subscription.diff,
+            subscription.client,
             SubscriptionExecutionOptions {
                 logger: self.logger.clone(),
                 resolver: StoreResolver::for_subscription(&self.logger, self.store.clone()),
@@ -67,13 +67,24 @@ impl StoreResolver {
         bc: BlockConstraint,
         subgraph: SubgraphDeploymentId,
     ) -> Result<EthereumBlockPointer, QueryExecutionError> {
-        if store
-            .uses_relational_schema(&subgraph)
-            .map_err(StoreError::from)?
-            && !subgraph.is_meta()
-        {
-            // Relational storage (most subgraphs); block constraints fully
-            // supported
+        if !subgraph.is_meta() {
+            // Block constraints are fully supported, as long as the history
+            // for the block is still there
+            let check_history = |number: BlockNumber| -> Result<(), QueryExecutionError> {
+                let earliest = store.earliest_block(&subgraph).map_err(StoreError::from)?;
+                if number < earliest {
+                    Err(QueryExecutionError::ValueParseError(
+                        "block".to_owned(),
+                        format!(
+                            "subgraph {} only has history starting at block number {} \
+                             and data for block number {} is therefore no longer available",
+                            subgraph, earliest, number
+                        ),
+                    ))
+                } else {
+                    Ok(())
+                }
+            };
             match bc {
                 BlockConstraint::Number(number) => store
                     .block_ptr(subgraph.clone())
@@ -81,6 +92,7 @@ impl StoreResolver {
                     .and_then(|ptr| {
                         let ptr =
                             ptr.expect("we should have already checked that the subgraph exists");
+                        check_history(number)?;
                         if ptr.number < number as u64 {
                             Err(QueryExecutionError::ValueParseError(
                                 "block.number".to_owned(),
@@ -113,7 +125,10 @@ impl StoreResolver {
                                     "no block with that hash found".to_owned(),
                                 )
                             })
-                            .map(|number| EthereumBlockPointer::from((hash, number as u64)))
+                            .and_then(|number| {
+                                check_history(number)?;
+                                Ok(EthereumBlockPointer::from((hash, number as u64)))
+                            })
                     }),
                 BlockConstraint::Latest => store
                     .block_ptr(subgraph.clone())
@@ -125,7 +140,7 @@ impl StoreResolver {
                     }),
             }
         } else {
-            // JSONB storage or subgraph metadata; only allow BlockConstraint::Latest
+            // Subgraph metadata; only allow BlockConstraint::Latest
             if matches!(bc, BlockConstraint::Latest) {
                 Ok(EthereumBlockPointer::from((
                     web3::types::H256::zero(),
@@ -133,9 +148,8 @@ impl StoreResolver {
                 )))
             } else {
                 Err(QueryExecutionError::NotSupported(
-                    "This subgraph uses JSONB storage, which does not \
-            support querying at a specific block height. Redeploy \
-            a new version of this subgraph to enable this feature."
+                    "Subgraph metadata does not support querying at a \
+                     specific block height"
                         .to_owned(),
                 ))
             }
@@ -0,0 +1,342 @@
+use graphql_parser::{query as q, schema as s};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use graph::prelude::*;
+
+use super::SubscriptionExecutor;
+use crate::execution::Resolver;
+use crate::schema::ast as sast;
+use crate::store::parse_subgraph_id;
+use crate::values::object_value;
+
+/// Arguments that select a window of the matching entities. Diffs always
+/// cover all matching entities
+const WINDOW_ARGUMENTS: &[&str] = &["first", "skip", "orderBy", "orderDirection"];
+
+/// Turns the results of a subscription into diffs against the results that
+/// were already sent to the client.
+///
+/// A diff subscription must select a list of entities, and must select the
+/// `id` of these entities so that results can be matched up across updates.
+/// Since the client gets sent every entity that matches, the subscription
+/// can not use `first`, `skip`, or `orderBy`. The first message contains all
+/// matching entities as `added`; every later message only reruns the query
+/// for the entities that changed in a block and lists which entities were
+/// `added`, `updated` or `removed`, together with the `block` in which that
+/// happened. When entities of other types change, the whole query is rerun,
+/// since that can change which entities match.
+pub(crate) struct Diff {
+    /// The subscription field, which is rewritten to only query changed entities
+    field: q::Field,
+    /// The key under which the field appears in the response
+    response_key: String,
+    subgraph_id: SubgraphDeploymentId,
+    entity_type: String,
+    /// The entities the client has been sent so far, by id
+    entities: Mutex<BTreeMap<String, q::Value>>,
+}
+
+impl Diff {
+    pub fn new(
+        schema: &s::Document,
+        subscription_type: &s::ObjectType,
+        field: &q::Field,
+    ) -> Result<Self, QueryExecutionError> {
+        let not_supported = |reason: &str| {
+            QueryExecutionError::NotSupported(format!(
+                "subscription field `{}` can not be sent as a diff: {}",
+                field.name, reason
+            ))
+        };
+
+        let field_definition = sast::get_field(subscription_type, &field.name)
+            .ok_or_else(|| not_supported("the field is not defined"))?;
+        if !sast::is_list_or_non_null_list_field(field_definition) {
+            return Err(not_supported("the field is not a list"));
+        }
+        let object_type = match sast::get_type_definition_from_field(schema, field_definition) {
+            Some(s::TypeDefinition::Object(object_type))
+                if sast::get_object_type_directive(object_type, "entity".to_owned()).is_some() =>
+            {
+                object_type
+            }
+            _ => return Err(not_supported("the field is not a list of entities")),
+        };
+        let selects_id = field
+            .selection_set
+            .items
+            .iter()
+            .any(|selection| match selection {
+                q::Selection::Field(field) => field.name == "id" && field.alias.is_none(),
+                _ => false,
+            });
+        if !selects_id {
+            return Err(not_supported("the `id` of the entities must be selected"));
+        }
+        if let Some((name, _)) = field
+            .arguments
+            .iter()
+            .find(|(name, _)| WINDOW_ARGUMENTS.contains(&name.as_str()))
+        {
+            return Err(not_supported(&format!(
+                "the argument `{}` can not be used",
+                name
+            )));
+        }
+
+        Ok(Diff {
+            field: field.clone(),
+            response_key: field.alias.as_ref().unwrap_or(&field.name).clone(),
+            subgraph_id: parse_subgraph_id(object_type)?,
+            entity_type: object_type.name.clone(),
+            entities: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Compute the diff that `event` causes for the client
+    pub async fn execute<R>(
+        self: Arc<Self>,
+        executor: SubscriptionExecutor<R>,
+        event: Arc<StoreEvent>,
+    ) -> Arc<QueryResult>
+    where
+        R: Resolver + CheapClone + 'static,
+    {
+        // The event that starts the subscription does not have any changes;
+        // we send all entities that match the query
+        if event.changes.is_empty() {
+            return match self.all_entities(&executor).await {
+                Ok(found) => {
+                    let ids = found.keys().cloned().collect();
+                    self.update(ids, found, None)
+                }
+                Err(result) => result,
+            };
+        }
+
+        let mut ids = BTreeSet::new();
+        let mut other_changes = false;
+        for change in &event.changes {
+            if change.subgraph_id == self.subgraph_id && change.entity_type == self.entity_type {
+                ids.insert(change.entity_id.clone());
+            } else {
+                other_changes = true;
+            }
+        }
+
+        // A change to another type can change the value of any entity we
+        // sent, for example, through a derived field, and it can change
+        // which entities match a filter on a related entity. We have to
+        // look at all entities again
+        if other_changes {
+            let found = match self.all_entities(&executor).await {
+                Ok(found) => found,
+                Err(result) => return result,
+            };
+            ids.extend(self.entities.lock().unwrap().keys().cloned());
+            ids.extend(found.keys().cloned());
+            return self.update(ids.into_iter().collect(), found, event.block);
+        }
+
+        let ids: Vec<_> = ids.into_iter().collect();
+        let mut found = BTreeMap::new();
+        for chunk in ids.chunks((executor.max_first as usize).max(1)) {
+            let selection_set = Arc::new(self.restrict(chunk, &executor.query));
+            let result = executor.clone().execute(selection_set).await;
+            match self.entities_in(&result) {
+                Some(entities) => found.extend(entities),
+                None => return result,
+            }
+        }
+        self.update(ids, found, event.block)
+    }
+
+    /// All entities that match the subscription by id. Since the number of
+    /// entities a query can return is limited, they are read in pages that
+    /// are ordered by id. Returns the failed result if a query fails
+    async fn all_entities<R>(
+        &self,
+        executor: &SubscriptionExecutor<R>,
+    ) -> Result<BTreeMap<String, q::Value>, Arc<QueryResult>>
+    where
+        R: Resolver + CheapClone + 'static,
+    {
+        let page_size = executor.max_first.max(1);
+        let mut found = BTreeMap::new();
+        let mut after = None;
+        loop {
+            let selection_set = Arc::new(self.page(after, page_size, &executor.query));
+            let result = executor.clone().execute(selection_set).await;
+            let page = match self.entities_in(&result) {
+                Some(page) => page,
+                None => return Err(result),
+            };
+            let last_page = page.len() < page_size as usize;
+            // The page is ordered by the database, which might compare
+            // strings differently from Rust
+            after = page.last().map(|(id, _)| id.clone());
+            found.extend(page);
+            if last_page {
+                return Ok(found);
+            }
+        }
+    }
+
+    /// The entities in `result` and their ids in the order in which they
+    /// appear in `result`, or `None` if the result has errors
+    fn entities_in(&self, result: &QueryResult) -> Option<Vec<(String, q::Value)>> {
+        if result.has_errors() {
+            return None;
+        }
+        let entities = match &result.data {
+            Some(q::Value::Object(data)) => match data.get(&self.response_key) {
+                Some(q::Value::List(entities)) => entities,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        entities
+            .iter()
+            .map(|entity| match entity {
+                q::Value::Object(object) => match object.get("id") {
+                    Some(q::Value::String(id)) => Some((id.clone(), entity.clone())),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The filter of the subscription with variables replaced by their
+    /// values
+    fn filter(&self, query: &crate::execution::Query) -> BTreeMap<String, q::Value> {
+        match self
+            .field
+            .arguments
+            .iter()
+            .find(|(name, _)| name == "where")
+            .map(|(_, value)| resolve(query, value))
+        {
+            Some(q::Value::Object(filter)) => filter,
+            _ => BTreeMap::new(),
+        }
+    }
+
+    /// A selection set for the field of the subscription with `filter` and
+    /// `first` instead of the arguments the client passed
+    fn with_arguments(
+        &self,
+        query: &crate::execution::Query,
+        filter: BTreeMap<String, q::Value>,
+        first: usize,
+        order_by_id: bool,
+    ) -> q::SelectionSet {
+        let mut field = self.field.clone();
+        field.arguments.retain(|(name, _)| name != "where");
+        field
+            .arguments
+            .push(("where".to_owned(), q::Value::Object(filter)));
+        field.arguments.push((
+            "first".to_owned(),
+            q::Value::Int(q::Number::from(first as i32)),
+        ));
+        if order_by_id {
+            field
+                .arguments
+                .push(("orderBy".to_owned(), q::Value::Enum("id".to_owned())));
+            field.arguments.push((
+                "orderDirection".to_owned(),
+                q::Value::Enum("asc".to_owned()),
+            ));
+        }
+
+        q::SelectionSet {
+            span: query.selection_set.span,
+            items: vec![q::Selection::Field(field)],
+        }
+    }
+
+    /// A selection set that queries `size` of the entities the subscription
+    /// selects, starting after the entity with id `after`
+    fn page(
+        &self,
+        after: Option<String>,
+        size: u32,
+        query: &crate::execution::Query,
+    ) -> q::SelectionSet {
+        let mut filter = self.filter(query);
+        if let Some(after) = after {
+            filter.insert("id_gt".to_owned(), q::Value::String(after));
+        }
+        self.with_arguments(query, filter, size as usize, true)
+    }
+
+    /// A selection set that only queries the entities with the given `ids`
+    /// among the ones the subscription selects
+    fn restrict(&self, ids: &[String], query: &crate::execution::Query) -> q::SelectionSet {
+        let mut filter = self.filter(query);
+        let ids: Vec<_> = match filter.get("id_in").map(|value| resolve(query, value)) {
+            Some(q::Value::List(allowed)) => ids
+                .iter()
+                .map(|id| q::Value::String(id.clone()))
+                .filter(|id| allowed.contains(id))
+                .collect(),
+            _ => ids.iter().map(|id| q::Value::String(id.clone())).collect(),
+        };
+        let first = ids.len();
+        filter.insert("id_in".to_owned(), q::Value::List(ids));
+        self.with_arguments(query, filter, first, false)
+    }
+
+    /// Compare the entities we `found` for `ids` with what the client has
+    /// and remember what we are sending
+    fn update(
+        &self,
+        ids: Vec<String>,
+        mut found: BTreeMap<String, q::Value>,
+        block: Option<BlockNumber>,
+    ) -> Arc<QueryResult> {
+        let mut entities = self.entities.lock().unwrap();
+        let mut added = Vec::new();
+        let mut updated = Vec::new();
+        let mut removed = Vec::new();
+
+        for id in ids {
+            match found.remove(&id) {
+                Some(value) => match entities.insert(id, value.clone()) {
+                    None => added.push(value),
+                    Some(old) if old != value => updated.push(value),
+                    Some(_) => (),
+                },
+                None => {
+                    if entities.remove(&id).is_some() {
+                        removed.push(q::Value::String(id));
+                    }
+                }
+            }
+        }
+
+        let block = block
+            .map(|block| q::Value::Int(q::Number::from(block)))
+            .unwrap_or(q::Value::Null);
+        let diff = object_value(vec![
+            ("block", block),
+            ("added", q::Value::List(added)),
+            ("updated", q::Value::List(updated)),
+            ("removed", q::Value::List(removed)),
+        ]);
+        Arc::new(QueryResult::new(Some(object_value(vec![(
+            self.response_key.as_str(),
+            diff,
+        )]))))
+    }
+}
+
+/// `value` with variables replaced by their values
+fn resolve(query: &crate::execution::Query, value: &q::Value) -> q::Value {
+    match value {
+        q::Value::Variable(name) => query.variables.get(name).cloned().unwrap_or(q::Value::Null),
+        value => value.clone(),
+    }
+}
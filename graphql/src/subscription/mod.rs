@@ -4,10 +4,15 @@ use std::iter;
 use std::result::Result;
 use std::time::{Duration, Instant};
 
+use graph::data::graphql::api_keys::ApiClient;
 use graph::prelude::*;
 
 use crate::execution::*;
 
+mod diff;
+
+use self::diff::Diff;
+
 /// Options available for subscription execution.
 pub struct SubscriptionExecutionOptions<R>
 where
@@ -50,11 +55,13 @@ where
         options.max_complexity,
         options.max_depth,
     )?;
-    execute_prepared_subscription(query, options)
+    execute_prepared_subscription(query, subscription.diff, subscription.client, options)
 }
 
 pub(crate) fn execute_prepared_subscription<R>(
     query: Arc<crate::execution::Query>,
+    diff: bool,
+    client: Option<Arc<ApiClient>>,
     options: SubscriptionExecutionOptions<R>,
 ) -> Result<SubscriptionResult, SubscriptionError>
 where
@@ -85,13 +92,25 @@ where
     );
 
     let source_stream = create_source_event_stream(&ctx)?;
-    let response_stream = map_source_to_response_stream(&ctx, source_stream, options.timeout);
+    let diff = if diff {
+        let (subscription_type, field) = subscription_field(&ctx)?;
+        Some(Arc::new(Diff::new(
+            ctx.query.schema.document(),
+            subscription_type,
+            field,
+        )?))
+    } else {
+        None
+    };
+    let response_stream =
+        map_source_to_response_stream(&ctx, source_stream, diff, client, options.timeout);
     Ok(response_stream)
 }
 
-fn create_source_event_stream(
-    ctx: &ExecutionContext<impl Resolver>,
-) -> Result<StoreEventStreamBox, SubscriptionError> {
+/// The subscription type and the one top-level field of the subscription
+fn subscription_field<'a>(
+    ctx: &'a ExecutionContext<impl Resolver>,
+) -> Result<(&'a s::ObjectType, &'a q::Field), SubscriptionError> {
     let subscription_type = ctx
         .query
         .schema
@@ -114,7 +133,13 @@ fn create_source_event_stream(
     }
 
     let fields = grouped_field_set.get_index(0).unwrap();
-    let field = fields.1[0];
+    Ok((subscription_type.as_ref(), fields.1[0]))
+}
+
+fn create_source_event_stream(
+    ctx: &ExecutionContext<impl Resolver>,
+) -> Result<StoreEventStreamBox, SubscriptionError> {
+    let (subscription_type, field) = subscription_field(ctx)?;
     let argument_values = coerce_argument_values(&ctx, &subscription_type, field)?;
 
     resolve_field_stream(ctx, &subscription_type, field, argument_values)
@@ -131,17 +156,26 @@ fn resolve_field_stream(
         .map_err(SubscriptionError::from)
 }
 
-fn map_source_to_response_stream(
-    ctx: &ExecutionContext<impl Resolver + CheapClone + 'static>,
+fn map_source_to_response_stream<R>(
+    ctx: &ExecutionContext<R>,
     source_stream: StoreEventStreamBox,
+    diff: Option<Arc<Diff>>,
+    client: Option<Arc<ApiClient>>,
     timeout: Option<Duration>,
-) -> QueryResultStream {
-    let logger = ctx.logger.cheap_clone();
-    let resolver = ctx.resolver.cheap_clone();
-    let query = ctx.query.cheap_clone();
-    let max_first = ctx.max_first;
-    let max_skip = ctx.max_skip;
-    let load_manager = ctx.load_manager.cheap_clone();
+) -> QueryResultStream
+where
+    R: Resolver + CheapClone + 'static,
+{
+    let executor = SubscriptionExecutor {
+        logger: ctx.logger.cheap_clone(),
+        resolver: ctx.resolver.cheap_clone(),
+        query: ctx.query.cheap_clone(),
+        timeout,
+        max_first: ctx.max_first,
+        max_skip: ctx.max_skip,
+        load_manager: ctx.load_manager.cheap_clone(),
+        client,
+    };
 
     // Create a stream with a single empty event. By chaining this in front
     // of the real events, we trick the subscription into executing its query
@@ -151,6 +185,7 @@ fn map_source_to_response_stream(
     let trigger_stream = futures03::stream::iter(vec![Ok(Arc::new(StoreEvent {
         tag: 0,
         changes: Default::default(),
+        block: None,
     }))]);
 
     Box::new(
@@ -161,55 +196,65 @@ fn map_source_to_response_stream(
                     futures03::future::ready(Arc::new(QueryExecutionError::EventStreamError.into()))
                         .boxed()
                 }
-                Ok(event) => execute_subscription_event(
-                    logger.clone(),
-                    resolver.cheap_clone(),
-                    query.clone(),
-                    event,
-                    timeout,
-                    max_first,
-                    max_skip,
-                    load_manager.cheap_clone(),
-                )
-                .boxed(),
+                Ok(event) => {
+                    debug!(executor.logger, "Execute subscription event"; "event" => format!("{:?}", event));
+                    match &diff {
+                        Some(diff) => diff.cheap_clone().execute(executor.clone(), event).boxed(),
+                        None => {
+                            let selection_set = executor.query.selection_set.cheap_clone();
+                            executor.clone().execute(selection_set).boxed()
+                        }
+                    }
+                }
             }),
     )
 }
 
-async fn execute_subscription_event(
+/// Everything needed to run the subscription query in response to an event
+#[derive(Clone)]
+pub(crate) struct SubscriptionExecutor<R> {
     logger: Logger,
-    resolver: impl Resolver + 'static,
+    resolver: R,
     query: Arc<crate::execution::Query>,
-    event: Arc<StoreEvent>,
     timeout: Option<Duration>,
     max_first: u32,
     max_skip: u32,
     load_manager: Arc<dyn QueryLoadManager>,
-) -> Arc<QueryResult> {
-    debug!(logger, "Execute subscription event"; "event" => format!("{:?}", event));
-
-    // Create a fresh execution context with deadline.
-    let ctx = Arc::new(ExecutionContext {
-        logger,
-        resolver,
-        query,
-        deadline: timeout.map(|t| Instant::now() + t),
-        max_first,
-        max_skip,
-        cache_status: Default::default(),
-        load_manager,
-    });
+    /// The client whose effort running the subscription counts against
+    client: Option<Arc<ApiClient>>,
+}
 
-    let subscription_type = match ctx.query.schema.subscription_type.as_ref() {
-        Some(t) => t.cheap_clone(),
-        None => return Arc::new(QueryExecutionError::NoRootSubscriptionObjectType.into()),
-    };
+impl<R> SubscriptionExecutor<R>
+where
+    R: Resolver + CheapClone + 'static,
+{
+    /// Run `selection_set`, which is either the selection set of the
+    /// subscription query or derived from it
+    async fn execute(self, selection_set: Arc<q::SelectionSet>) -> Arc<QueryResult> {
+        let start = Instant::now();
+        let client = self.client;
 
-    execute_root_selection_set(
-        ctx.cheap_clone(),
-        ctx.query.selection_set.cheap_clone(),
-        subscription_type,
-        None,
-    )
-    .await
+        // Create a fresh execution context with deadline.
+        let ctx = Arc::new(ExecutionContext {
+            logger: self.logger,
+            resolver: self.resolver,
+            query: self.query,
+            deadline: self.timeout.map(|t| Instant::now() + t),
+            max_first: self.max_first,
+            max_skip: self.max_skip,
+            cache_status: Default::default(),
+            load_manager: self.load_manager,
+        });
+
+        let subscription_type = match ctx.query.schema.subscription_type.as_ref() {
+            Some(t) => t.cheap_clone(),
+            None => return Arc::new(QueryExecutionError::NoRootSubscriptionObjectType.into()),
+        };
+
+        let result = execute_root_selection_set(ctx, selection_set, subscription_type, None).await;
+        if let Some(client) = client {
+            client.add_effort(start.elapsed());
+        }
+        result
+    }
 }
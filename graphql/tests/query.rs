@@ -13,13 +13,13 @@ use graph::prelude::{
     async_trait, futures03::stream::StreamExt, futures03::FutureExt, futures03::TryFutureExt, o,
     slog, tokio, ApiSchema, Entity, EntityKey, EntityOperation, EthereumBlockPointer,
     FutureExtension, GraphQlRunner as _, Logger, Query, QueryError, QueryExecutionError,
-    QueryLoadManager, QueryResult, QueryVariables, Schema, Store, SubgraphDeploymentEntity,
-    SubgraphDeploymentId, SubgraphDeploymentStore, SubgraphManifest, Subscription,
-    SubscriptionError, Value,
+    QueryLoadManager, QueryResult, QueryResultStream, QueryVariables, Schema, Store,
+    SubgraphDeploymentEntity, SubgraphDeploymentId, SubgraphDeploymentStore, SubgraphManifest,
+    Subscription, SubscriptionError, Value,
 };
 use graph_graphql::prelude::*;
 use test_store::{
-    execute_subgraph_query_with_complexity, execute_subgraph_query_with_deadline,
+    block_store, execute_subgraph_query_with_complexity, execute_subgraph_query_with_deadline,
     transact_entity_operations, BLOCK_ONE, GENESIS_PTR, LOAD_MANAGER, LOGGER, STORE,
 };
 
@@ -76,9 +76,13 @@ fn test_schema(id: SubgraphDeploymentId) -> Schema {
 }
 
 fn api_test_schema() -> ApiSchema {
-    let mut schema = test_schema(TEST_SUBGRAPH_ID.clone());
+    api_schema_for(TEST_SUBGRAPH_ID.clone())
+}
+
+fn api_schema_for(id: SubgraphDeploymentId) -> ApiSchema {
+    let mut schema = test_schema(id.clone());
     schema.document = api_schema(&schema.document).expect("Failed to derive API schema");
-    schema.add_subgraph_id_directives(TEST_SUBGRAPH_ID.clone());
+    schema.add_subgraph_id_directives(id);
     ApiSchema::from_api_schema(schema).unwrap()
 }
 
@@ -835,7 +839,8 @@ async fn query_complexity_subscriptions() {
 
     // This query is exactly at the maximum complexity.
     // FIXME: Not collecting the stream because that will hang the test.
-    let _ignore_stream = execute_subscription(Subscription { query }, options).unwrap();
+    let _ignore_stream =
+        execute_subscription(Subscription { query, diff: false }, options).unwrap();
 
     let query = Query::new(
         Arc::new(api_test_schema()),
@@ -876,7 +881,7 @@ async fn query_complexity_subscriptions() {
     };
 
     // The extra introspection causes the complexity to go over.
-    let result = execute_subscription(Subscription { query }, options);
+    let result = execute_subscription(Subscription { query, diff: false }, options);
     match result {
         Err(SubscriptionError::GraphQLError(e)) => match e[0] {
             QueryExecutionError::TooComplex(1_010_200, _) => (), // Expected
@@ -1218,7 +1223,7 @@ async fn subscription_gets_result_even_without_events() {
 
     // Execute the subscription and expect at least one result to be
     // available in the result stream
-    let stream = execute_subscription(Subscription { query }, options).unwrap();
+    let stream = execute_subscription(Subscription { query, diff: false }, options).unwrap();
     let results: Vec<_> = stream
         .take(1)
         .collect()
@@ -1245,6 +1250,304 @@ async fn subscription_gets_result_even_without_events() {
     );
 }
 
+#[tokio::test]
+async fn diff_subscription_starts_with_all_entities() {
+    let logger = Logger::root(slog::Discard, o!());
+    let options = |logger: &Logger| SubscriptionExecutionOptions {
+        logger: logger.clone(),
+        resolver: StoreResolver::for_subscription(logger, STORE.clone()),
+        timeout: None,
+        max_complexity: None,
+        max_depth: 100,
+        max_first: std::u32::MAX,
+        max_skip: std::u32::MAX,
+        load_manager: mock_query_load_manager(),
+    };
+    let subscription = |query: &str| Subscription {
+        query: Query::new(
+            Arc::new(api_test_schema()),
+            graphql_parser::parse_query(query).unwrap(),
+            None,
+            None,
+        ),
+        diff: true,
+        client: None,
+    };
+
+    // Diffs need the `id` of the entities, and can not be restricted to
+    // a window of the entities
+    for query in &[
+        "subscription { musicians { name } }",
+        "subscription { musicians(first: 2) { id name } }",
+        "subscription { musicians(skip: 1) { id name } }",
+        "subscription { musicians(orderBy: name) { id name } }",
+    ] {
+        let result = execute_subscription(subscription(query), options(&logger));
+        match result {
+            Err(SubscriptionError::GraphQLError(e)) => match e[0] {
+                QueryExecutionError::NotSupported(_) => (),
+                ref e => panic!("expected NotSupported for {}, got {:?}", query, e),
+            },
+            _ => panic!("expected an error for the diff subscription {}", query),
+        }
+    }
+
+    // The first message contains everything the query matches
+    let stream = execute_subscription(
+        subscription("subscription { musicians(where: { id_in: [\"m1\", \"m2\"] }) { id name } }"),
+        options(&logger),
+    )
+    .unwrap();
+    let results: Vec<_> = stream
+        .take(1)
+        .collect()
+        .map(Result::<_, ()>::Ok)
+        .compat()
+        .timeout(Duration::from_secs(3))
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(results.len(), 1);
+    let musician = |id: &str, name: &str| {
+        object_value(vec![
+            ("id", q::Value::String(String::from(id))),
+            ("name", q::Value::String(String::from(name))),
+        ])
+    };
+    assert_eq!(
+        results[0].data,
+        Some(object_value(vec![(
+            "musicians",
+            object_value(vec![
+                ("block", q::Value::Null),
+                (
+                    "added",
+                    q::Value::List(vec![musician("m1", "John"), musician("m2", "Lisa")])
+                ),
+                ("updated", q::Value::List(vec![])),
+                ("removed", q::Value::List(vec![])),
+            ])
+        )])),
+    );
+}
+
+#[tokio::test]
+async fn diff_subscription_follows_store_changes() {
+    // Use a separate deployment since we write to it; put it back to the
+    // state after block one if an earlier run left later blocks behind
+    let id = SubgraphDeploymentId::new("graphqlTestsDiff").unwrap();
+    let block_ptrs: Vec<EthereumBlockPointer> = vec![
+        GENESIS_PTR.clone(),
+        BLOCK_ONE.clone(),
+        (block_store::BLOCK_TWO.block_hash(), 2u64).into(),
+        (block_store::BLOCK_THREE.block_hash(), 3u64).into(),
+    ];
+    if !STORE.is_deployed(&id).unwrap() {
+        insert_test_entities(&**STORE, id.clone());
+    }
+    while let Some(ptr) = STORE.block_ptr(id.clone()).unwrap() {
+        if ptr.number <= 1 {
+            break;
+        }
+        let parent = block_ptrs[ptr.number as usize - 1].clone();
+        STORE
+            .revert_block_operations(id.clone(), ptr, parent)
+            .unwrap();
+    }
+
+    let logger = Logger::root(slog::Discard, o!());
+    // Make the first message need several pages of musicians
+    let options = SubscriptionExecutionOptions {
+        logger: logger.clone(),
+        resolver: StoreResolver::for_subscription(&logger, STORE.clone()),
+        timeout: None,
+        max_complexity: None,
+        max_depth: 100,
+        max_first: 2,
+        max_skip: std::u32::MAX,
+        load_manager: mock_query_load_manager(),
+    };
+    let subscription = Subscription {
+        query: Query::new(
+            Arc::new(api_schema_for(id.clone())),
+            graphql_parser::parse_query("subscription { musicians { id name mainBand { name } } }")
+                .unwrap(),
+            None,
+            None,
+        ),
+        diff: true,
+        client: None,
+    };
+    let mut stream = execute_subscription(subscription, options).unwrap();
+
+    // Wait for the diff for `block`; events from resetting the deployment
+    // might still arrive for earlier blocks
+    async fn next_diff(stream: &mut QueryResultStream, block: Option<i32>) -> q::Value {
+        let block = block
+            .map(|block| q::Value::Int(q::Number::from(block)))
+            .unwrap_or(q::Value::Null);
+        loop {
+            let result = stream
+                .next()
+                .map(Result::<_, ()>::Ok)
+                .compat()
+                .timeout(Duration::from_secs(10))
+                .await
+                .unwrap()
+                .unwrap()
+                .expect("the subscription ended");
+            assert!(!result.has_errors(), "{:?}", result.errors);
+            let diff = match &result.data {
+                Some(q::Value::Object(data)) => data["musicians"].clone(),
+                data => panic!("unexpected data {:?}", data),
+            };
+            match &diff {
+                q::Value::Object(fields) if fields["block"] == block => return diff,
+                _ => (),
+            }
+        }
+    }
+    let musician = |id: &str, name: &str, band: Option<&str>| {
+        let band = band
+            .map(|band| object_value(vec![("name", q::Value::String(String::from(band)))]))
+            .unwrap_or(q::Value::Null);
+        object_value(vec![
+            ("id", q::Value::String(String::from(id))),
+            ("name", q::Value::String(String::from(name))),
+            ("mainBand", band),
+        ])
+    };
+    let diff = |block: i32, added, updated, removed: Vec<&str>| {
+        object_value(vec![
+            ("block", q::Value::Int(q::Number::from(block))),
+            ("added", q::Value::List(added)),
+            ("updated", q::Value::List(updated)),
+            (
+                "removed",
+                q::Value::List(
+                    removed
+                        .into_iter()
+                        .map(|id| q::Value::String(String::from(id)))
+                        .collect(),
+                ),
+            ),
+        ])
+    };
+    let set = |entity_type: &str, data: Vec<(&str, Value)>| {
+        let data = Entity::from(data);
+        EntityOperation::Set {
+            key: EntityKey {
+                subgraph_id: id.clone(),
+                entity_type: entity_type.to_owned(),
+                entity_id: data["id"].clone().as_string().unwrap(),
+            },
+            data,
+        }
+    };
+
+    // All musicians are added in the first message
+    assert_eq!(
+        next_diff(&mut stream, None).await,
+        object_value(vec![
+            ("block", q::Value::Null),
+            (
+                "added",
+                q::Value::List(vec![
+                    musician("m1", "John", Some("The Musicians")),
+                    musician("m2", "Lisa", Some("The Musicians")),
+                    musician("m3", "Tom", Some("The Amateurs")),
+                    musician("m4", "Valerie", None),
+                ])
+            ),
+            ("updated", q::Value::List(vec![])),
+            ("removed", q::Value::List(vec![])),
+        ])
+    );
+
+    // Changing musicians sends only those musicians
+    transact_entity_operations(
+        &STORE,
+        id.clone(),
+        block_ptrs[2].clone(),
+        vec![
+            set(
+                "Musician",
+                vec![
+                    ("id", Value::from("m1")),
+                    ("name", Value::from("Johnny")),
+                    ("mainBand", Value::from("b1")),
+                    (
+                        "bands",
+                        Value::List(vec![Value::from("b1"), Value::from("b2")]),
+                    ),
+                ],
+            ),
+            set(
+                "Musician",
+                vec![
+                    ("id", Value::from("m5")),
+                    ("name", Value::from("Mia")),
+                    ("mainBand", Value::from("b2")),
+                    ("bands", Value::List(vec![Value::from("b2")])),
+                ],
+            ),
+            EntityOperation::Remove {
+                key: EntityKey {
+                    subgraph_id: id.clone(),
+                    entity_type: "Musician".to_owned(),
+                    entity_id: "m2".to_owned(),
+                },
+            },
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        next_diff(&mut stream, Some(2)).await,
+        diff(
+            2,
+            vec![musician("m5", "Mia", Some("The Amateurs"))],
+            vec![musician("m1", "Johnny", Some("The Musicians"))],
+            vec!["m2"],
+        )
+    );
+
+    // Changing a band updates the musicians whose main band it is
+    transact_entity_operations(
+        &STORE,
+        id.clone(),
+        block_ptrs[3].clone(),
+        vec![set(
+            "Band",
+            vec![
+                ("id", Value::from("b2")),
+                ("name", Value::from("The Pros")),
+                (
+                    "originalSongs",
+                    Value::List(vec![
+                        Value::from("s1"),
+                        Value::from("s3"),
+                        Value::from("s4"),
+                    ]),
+                ),
+            ],
+        )],
+    )
+    .unwrap();
+    assert_eq!(
+        next_diff(&mut stream, Some(3)).await,
+        diff(
+            3,
+            vec![],
+            vec![
+                musician("m3", "Tom", Some("The Pros")),
+                musician("m5", "Mia", Some("The Pros")),
+            ],
+            vec![],
+        )
+    );
+}
+
 #[tokio::test]
 async fn can_use_nested_filter() {
     let result = execute_query_document(
@@ -1314,11 +1617,8 @@ async fn check_musicians_at(
 
     let result = execute_query_document_with_variables(query, vars).await;
 
-    match (
-        STORE.uses_relational_schema(&*TEST_SUBGRAPH_ID).unwrap(),
-        expected,
-    ) {
-        (true, Ok(ids)) => {
+    match expected {
+        Ok(ids) => {
             let ids: Vec<_> = ids
                 .into_iter()
                 .map(|id| object_value(vec![("id", q::Value::String(String::from(id)))]))
@@ -1332,7 +1632,7 @@ async fn check_musicians_at(
             );
             assert_eq!(result.data, expected, "failed query: ({})", qid);
         }
-        (true, Err(msg)) => {
+        Err(msg) => {
             assert!(
                 result.errors.is_some(),
                 "expected error `{}` but got successful result ({})",
@@ -1353,13 +1653,6 @@ async fn check_musicians_at(
                 qid
             );
         }
-        (false, _) => {
-            assert!(
-                result.errors.is_some(),
-                "JSONB does not support time travel: {}",
-                qid
-            );
-        }
     }
 }
 
@@ -1440,16 +1733,12 @@ async fn block_extension() {
 
     let result = execute_query_document(query).await;
 
-    if STORE.uses_relational_schema(&*TEST_SUBGRAPH_ID).unwrap() {
-        let ext = object! {
-        subgraph: object! {
-            blocks: object! {
-                unknown: object! {
-                    hash: "0000000000000000000000000000000000000000000000000000000000000000",
-                    number: 0}},
-            id: "graphqlTestsQuery" }};
-        assert_eq!(Some(ext), result.extensions);
-    } else {
-        assert_eq!(None, result.extensions);
-    }
+    let ext = object! {
+    subgraph: object! {
+        blocks: object! {
+            unknown: object! {
+                hash: "0000000000000000000000000000000000000000000000000000000000000000",
+                number: 0}},
+        id: "graphqlTestsQuery" }};
+    assert_eq!(Some(ext), result.extensions);
 }
@@ -1,6 +1,6 @@
 use mockall::predicate::*;
 use mockall::*;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 
 use graph::components::store::*;
 use graph::data::subgraph::schema::*;
@@ -11,6 +11,8 @@ use web3::types::{Address, H256};
 mock! {
     pub Store {
         fn get_mock(&self, key: EntityKey) -> Result<Option<Entity>, QueryExecutionError>;
+
+        fn block_ptr_mock(&self, subgraph_id: SubgraphDeploymentId) -> Result<Option<EthereumBlockPointer>, Error>;
     }
 
     trait SubgraphDeploymentStore: Send + Sync + 'static {
@@ -18,8 +20,6 @@ mock! {
 
         fn api_schema(&self, subgraph_id: &SubgraphDeploymentId) -> Result<Arc<ApiSchema>, Error>;
 
-        fn uses_relational_schema(&self, subgraph_id: &SubgraphDeploymentId) -> Result<bool, Error>;
-
         fn network_name(&self, subgraph_id: &SubgraphDeploymentId) -> Result<Option<String>, Error>;
     }
 
@@ -59,9 +59,9 @@ mock! {
 impl Store for MockStore {
     fn block_ptr(
         &self,
-        _subgraph_id: SubgraphDeploymentId,
+        subgraph_id: SubgraphDeploymentId,
     ) -> Result<Option<EthereumBlockPointer>, Error> {
-        unimplemented!()
+        self.block_ptr_mock(subgraph_id)
     }
 
     fn get(&self, key: EntityKey) -> Result<Option<Entity>, QueryExecutionError> {
@@ -110,10 +110,18 @@ impl Store for MockStore {
         _block_ptr_to: EthereumBlockPointer,
         _mods: Vec<EntityModification>,
         _stopwatch: StopwatchMetrics,
-    ) -> Result<bool, StoreError> {
+    ) -> Result<(), StoreError> {
         unimplemented!()
     }
 
+    fn flush_writes(&self, _subgraph_id: &SubgraphDeploymentId) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn stop_writes(&self, _subgraph_id: &SubgraphDeploymentId) -> Result<(), StoreError> {
+        Ok(())
+    }
+
     fn apply_metadata_operations(
         &self,
         _operations: Vec<MetadataOperation>,
@@ -121,14 +129,6 @@ impl Store for MockStore {
         unimplemented!()
     }
 
-    fn build_entity_attribute_indexes(
-        &self,
-        _subgraph: &SubgraphDeploymentId,
-        _indexes: Vec<AttributeIndexDefinition>,
-    ) -> Result<(), SubgraphAssignmentProviderError> {
-        unimplemented!()
-    }
-
     fn revert_block_operations(
         &self,
         _subgraph_id: SubgraphDeploymentId,
@@ -138,33 +138,140 @@ impl Store for MockStore {
         unimplemented!()
     }
 
-    fn subscribe(&self, _entities: Vec<SubgraphEntityPair>) -> StoreEventStreamBox {
+    fn subgraph_names(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<String>, StoreError> {
         unimplemented!()
     }
 
-    fn create_subgraph_deployment(
+    fn set_history_blocks(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+        _history_blocks: Option<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn create_index(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+        _entity_type: &str,
+        _fields: Vec<String>,
+    ) -> Result<String, StoreError> {
+        unimplemented!()
+    }
+
+    fn drop_index(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+        _index_name: &str,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn copy_statuses(
+        &self,
+        _subgraph_ids: &[SubgraphDeploymentId],
+    ) -> Result<HashMap<SubgraphDeploymentId, CopyStatus>, StoreError> {
+        unimplemented!()
+    }
+
+    fn entity_stats(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<EntityTypeStats>, StoreError> {
+        unimplemented!()
+    }
+
+    fn estimated_entity_stats(
         &self,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<EntityTypeStats>, StoreError> {
+        unimplemented!()
+    }
+
+    fn entity_changes_in_block(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+        _block: BlockNumber,
+    ) -> Result<Vec<EntityTypeChanges>, StoreError> {
+        unimplemented!()
+    }
+
+    fn evolve_deployment_schema(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
         _schema: &Schema,
-        _ops: Vec<MetadataOperation>,
     ) -> Result<(), StoreError> {
         unimplemented!()
     }
 
-    fn start_subgraph_deployment(
+    fn rewind(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+        _block_ptr: EthereumBlockPointer,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn start_deployment_move(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+        _shard: &str,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn copy_moving_deployment(
         &self,
         _logger: &Logger,
         _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn finish_deployment_move(
+        &self,
+        _logger: &Logger,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn rollback_deployment_move(
+        &self,
+        _logger: &Logger,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn drop_retired_deployment_data(
+        &self,
+        _subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError> {
+        unimplemented!()
+    }
+
+    fn subscribe(&self, _entities: Vec<SubgraphEntityPair>) -> StoreEventStreamBox {
+        unimplemented!()
+    }
+
+    fn create_subgraph_deployment(
+        &self,
+        _schema: &Schema,
         _ops: Vec<MetadataOperation>,
     ) -> Result<(), StoreError> {
         unimplemented!()
     }
 
-    fn migrate_subgraph_deployment(
+    fn start_subgraph_deployment(
         &self,
         _logger: &Logger,
         _subgraph_id: &SubgraphDeploymentId,
-        _block_ptr: &EthereumBlockPointer,
-    ) {
+        _ops: Vec<MetadataOperation>,
+    ) -> Result<(), StoreError> {
         unimplemented!()
     }
 
@@ -225,5 +332,13 @@ pub fn mock_store_with_users_subgraph() -> (Arc<MockStore>, SubgraphDeploymentId
 
     store.expect_network_name().returning(|_| Ok(None));
 
+    // Simulate a subgraph that has processed another block every time its
+    // block pointer is read
+    let mut block = 0u64;
+    store.expect_block_ptr_mock().returning(move |_| {
+        block += 1;
+        Ok(Some((H256::zero(), block).into()))
+    });
+
     (Arc::new(store), subgraph_id)
 }
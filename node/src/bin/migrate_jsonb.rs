@@ -0,0 +1,125 @@
+//! Convert deployments that still use JSONB storage to relational storage.
+//! This must be run while the deployments are not assigned to any node
+use clap::{App, Arg};
+use std::process::exit;
+
+use graph::log::logger;
+use graph::prelude::{error, info, SubgraphDeploymentId};
+use graph::util::security::SafeDisplay;
+use graph_store_postgres::jsonb_migration;
+
+fn main() {
+    let matches = App::new("graph-migrate-jsonb")
+        .about("Convert deployments that use JSONB storage to relational storage")
+        .arg(
+            Arg::with_name("postgres-url")
+                .takes_value(true)
+                .required(true)
+                .long("postgres-url")
+                .value_name("URL")
+                .help("Location of the Postgres database used for storing entities"),
+        )
+        .arg(
+            Arg::with_name("samples")
+                .takes_value(true)
+                .long("samples")
+                .value_name("N")
+                .default_value("1000")
+                .help("How many randomly chosen entities to compare with their originals"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Convert and check the deployments, but leave them unchanged"),
+        )
+        .arg(
+            Arg::with_name("all")
+                .long("all")
+                .conflicts_with("deployment")
+                .help("Convert all deployments that use JSONB storage"),
+        )
+        .arg(
+            Arg::with_name("deployment")
+                .multiple(true)
+                .required_unless_one(&["all", "list"])
+                .value_name("DEPLOYMENT")
+                .help("The IPFS hash of a deployment to convert"),
+        )
+        .arg(
+            Arg::with_name("list")
+                .long("list")
+                .help("List the deployments that use JSONB storage and exit"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .help("Enable debug logging"),
+        )
+        .get_matches();
+
+    let logger = logger(matches.is_present("debug"));
+    let postgres_url = matches.value_of("postgres-url").unwrap();
+    let samples = matches
+        .value_of("samples")
+        .unwrap()
+        .parse::<usize>()
+        .expect("--samples must be a number");
+    let dry_run = matches.is_present("dry-run");
+
+    info!(logger, "Connecting to Postgres"; "url" => SafeDisplay(postgres_url));
+    let conn = jsonb_migration::connect(postgres_url).unwrap_or_else(|e| {
+        error!(logger, "{}", e);
+        exit(1)
+    });
+
+    let jsonb_deployments = jsonb_migration::jsonb_deployments(&conn).unwrap_or_else(|e| {
+        error!(logger, "Failed to list JSONB deployments: {}", e);
+        exit(1)
+    });
+    if matches.is_present("list") {
+        for deployment in jsonb_deployments {
+            println!("{}", deployment);
+        }
+        return;
+    }
+
+    let deployments = if matches.is_present("all") {
+        jsonb_deployments
+    } else {
+        matches
+            .values_of("deployment")
+            .unwrap()
+            .map(|hash| {
+                SubgraphDeploymentId::new(hash).unwrap_or_else(|_| {
+                    error!(logger, "Invalid deployment `{}`", hash);
+                    exit(1)
+                })
+            })
+            .collect()
+    };
+
+    let mut failed = false;
+    for deployment in deployments {
+        match jsonb_migration::migrate(&logger, &conn, &deployment, samples, dry_run) {
+            Ok(report) => {
+                let entities: i64 = report.counts.values().sum();
+                info!(logger, "Converted deployment";
+                      "subgraph" => report.subgraph.as_str(),
+                      "from" => &report.old_namespace,
+                      "to" => &report.namespace,
+                      "entities" => entities,
+                      "sampled" => report.sampled,
+                      "dry_run" => report.dry_run);
+            }
+            Err(e) => {
+                error!(logger, "Failed to convert deployment";
+                       "subgraph" => deployment.as_str(),
+                       "error" => e.to_string());
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        exit(1);
+    }
+}
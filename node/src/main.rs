@@ -15,14 +15,17 @@ use url::Url;
 
 use graph::components::ethereum::{EthereumNetworks, NodeCapabilities};
 use graph::components::forward;
+use graph::data::graphql::api_keys::{ApiKeyConfigs, ApiKeys};
 use graph::data::graphql::effort::LoadManager;
+use graph::data::graphql::persisted::{AllowLists, PersistedQueries};
 use graph::log::logger;
 use graph::prelude::{IndexNodeServer as _, JsonRpcServer as _, *};
 use graph::util::security::SafeDisplay;
 use graph_chain_arweave::adapter::ArweaveAdapter;
 use graph_chain_ethereum::{network_indexer, BlockIngestor, BlockStreamBuilder, Transport};
 use graph_core::{
-    three_box::ThreeBoxAdapter, LinkResolver, MetricsRegistry,
+    three_box::ThreeBoxAdapter, ArweaveLinkResolver, DiskCache, FileLinkResolver, HttpLinkResolver,
+    LinkResolver, MetricsRegistry, SchemeLinkResolver,
     SubgraphAssignmentProvider as IpfsSubgraphAssignmentProvider, SubgraphInstanceManager,
     SubgraphRegistrar as IpfsSubgraphRegistrar,
 };
@@ -35,8 +38,8 @@ use graph_server_metrics::PrometheusMetricsServer;
 use graph_server_websocket::SubscriptionServer as GraphQLSubscriptionServer;
 use graph_store_postgres::connection_pool::create_connection_pool;
 use graph_store_postgres::{
-    ChainHeadUpdateListener as PostgresChainHeadUpdateListener, Store as DieselStore, StoreConfig,
-    SubscriptionManager,
+    AdminAuditLog, AllowListStore, ChainHeadUpdateListener as PostgresChainHeadUpdateListener,
+    PlacementRule, Shard, Store as DieselStore, StoreConfig, SubscriptionManager,
 };
 use graphql_parser::query as q;
 
@@ -54,6 +57,15 @@ lazy_static! {
         .map(|s| u64::from_str(&s)
              .unwrap_or_else(|_| panic!("failed to parse env var ETHEREUM_ANCESTOR_COUNT")))
         .unwrap_or(50);
+
+    // How often to remove entity history that deployments do not want to
+    // keep; defaults to every 10 minutes
+    static ref PRUNE_INTERVAL: Duration = Duration::from_secs(
+        env::var("GRAPH_PRUNE_INTERVAL")
+            .ok()
+            .map(|s| u64::from_str(&s)
+                .unwrap_or_else(|_| panic!("failed to parse env var GRAPH_PRUNE_INTERVAL")))
+            .unwrap_or(600));
 }
 
 git_testament!(TESTAMENT);
@@ -92,6 +104,57 @@ fn read_expensive_queries() -> Result<Vec<Arc<q::Document>>, std::io::Error> {
     Ok(queries)
 }
 
+fn read_allow_lists() -> Result<AllowLists, std::io::Error> {
+    // A JSON file that maps subgraph names and deployment IDs to the
+    // queries that may be run against them. The file is optional unless
+    // GRAPH_GRAPHQL_ALLOW_LISTS points to it explicitly
+    const ALLOW_LISTS: &str = "/etc/graph-node/allow-lists.json";
+    let path = match env::var("GRAPH_GRAPHQL_ALLOW_LISTS") {
+        Ok(path) => path,
+        Err(_) if Path::new(ALLOW_LISTS).exists() => ALLOW_LISTS.to_owned(),
+        Err(_) => return Ok(AllowLists::new()),
+    };
+    let file = std::fs::File::open(&path)?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+        let msg = format!("invalid allow-lists in {}: {}", path, e);
+        std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+    })
+}
+
+fn read_api_keys() -> Result<ApiKeyConfigs, std::io::Error> {
+    // A JSON file that maps API keys to the client they belong to and the
+    // limits for the key. The file is optional unless GRAPH_GRAPHQL_API_KEYS
+    // points to it explicitly
+    const API_KEYS: &str = "/etc/graph-node/api-keys.json";
+    let path = match env::var("GRAPH_GRAPHQL_API_KEYS") {
+        Ok(path) => path,
+        Err(_) if Path::new(API_KEYS).exists() => API_KEYS.to_owned(),
+        Err(_) => return Ok(ApiKeyConfigs::new()),
+    };
+    let file = std::fs::File::open(&path)?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+        let msg = format!("invalid API keys in {}: {}", path, e);
+        std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+    })
+}
+
+fn read_admin_auth() -> Result<AdminPrincipals, std::io::Error> {
+    // A JSON file that maps the names of the principals that may use the
+    // admin server to their credentials and permissions. The file is
+    // optional unless GRAPH_ADMIN_AUTH points to it explicitly
+    const ADMIN_AUTH: &str = "/etc/graph-node/admin-auth.json";
+    let path = match env::var("GRAPH_ADMIN_AUTH") {
+        Ok(path) => path,
+        Err(_) if Path::new(ADMIN_AUTH).exists() => ADMIN_AUTH.to_owned(),
+        Err(_) => return Ok(AdminPrincipals::new()),
+    };
+    let file = std::fs::File::open(&path)?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+        let msg = format!("invalid admin auth in {}: {}", path, e);
+        std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+    })
+}
+
 /// Replace the host portion of `url` and return a new URL with `host`
 /// as the host portion
 ///
@@ -164,6 +227,33 @@ async fn main() {
                 Defaults to weight 1 for each host",
                 ),
         )
+        .arg(
+            Arg::with_name("postgres-shard")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .long("postgres-shard")
+                .value_name("NAME=URL")
+                .help(
+                    "An additional Postgres database (shard) for storing the data of \
+                     deployments. All metadata is kept in the database given with \
+                     --postgres-url, which is the shard `primary`. The database must \
+                     allow prepared transactions with `max_prepared_transactions`",
+                ),
+        )
+        .arg(
+            Arg::with_name("postgres-shard-rule")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .long("postgres-shard-rule")
+                .value_name("SHARD=REGEX")
+                .help(
+                    "Store new deployments with a subgraph name that matches REGEX in SHARD. \
+                     Rules are checked in the order in which they are given; deployments \
+                     that match no rule are stored in the primary",
+                ),
+        )
         .arg(
             Arg::with_name("ethereum-rpc")
                 .takes_value(true)
@@ -305,6 +395,17 @@ async fn main() {
                 .default_value("false")
                 .help("Ensures that the block ingestor component does not execute"),
         )
+        .arg(
+            Arg::with_name("enable-history-pruner")
+                .long("enable-history-pruner")
+                .value_name("ENABLE_HISTORY_PRUNER")
+                .env("ENABLE_HISTORY_PRUNER")
+                .default_value("false")
+                .help(
+                    "Periodically remove the entity history that deployments do not want \
+                     to keep. Only one node should do this",
+                ),
+        )
         .arg(
             Arg::with_name("store-connection-pool-size")
                 .long("store-connection-pool-size")
@@ -332,6 +433,38 @@ async fn main() {
                 .value_name("URL")
                 .help("HTTP endpoint of an Arweave gateway"),
         )
+        .arg(
+            Arg::with_name("file-link-dir")
+                .long("file-link-dir")
+                .value_name("DIR")
+                .env("GRAPH_FILE_LINK_DIR")
+                .help(
+                    "Resolve `file://` links in subgraph manifests relative to this \
+                     directory. Meant for developing subgraphs; `file://` links are \
+                     rejected if this is not set",
+                ),
+        )
+        .arg(
+            Arg::with_name("ipfs-cache-dir")
+                .long("ipfs-cache-dir")
+                .value_name("DIR")
+                .env("GRAPH_IPFS_CACHE_DIR")
+                .help(
+                    "Keep files fetched from IPFS in this directory so that they \
+                     survive restarts and are shared by all subgraphs on this node",
+                ),
+        )
+        .arg(
+            Arg::with_name("ipfs-cache-seed")
+                .long("ipfs-cache-seed")
+                .value_name("DIR")
+                .requires("ipfs-cache-dir")
+                .help(
+                    "Add the files in this directory to the IPFS cache on startup. \
+                     Each file must be named after its IPFS hash; files whose contents \
+                     do not match their hash are rejected",
+                ),
+        )
         .arg(
             Arg::with_name("3box-api")
                 .default_value("https://ipfs.3box.io/")
@@ -413,6 +546,13 @@ async fn main() {
         .parse()
         .expect("invalid --disable-block-ingestor/DISABLE_BLOCK_INGESTOR value");
 
+    // Obtain ENABLE_HISTORY_PRUNER setting
+    let enable_history_pruner: bool = matches
+        .value_of("enable-history-pruner")
+        .unwrap()
+        .parse()
+        .expect("invalid --enable-history-pruner/ENABLE_HISTORY_PRUNER value");
+
     // Obtain STORE_CONNECTION_POOL_SIZE setting
     let store_conn_pool_size: u32 = matches
         .value_of("store-connection-pool-size")
@@ -445,6 +585,28 @@ async fn main() {
         .map(|s| s.parse::<usize>())
         .collect::<Result<_, _>>()
         .expect("--postgres-host-weights must be a comma-separated list of integers");
+    let pg_shards: Vec<(String, String)> = matches
+        .values_of("postgres-shard")
+        .into_iter()
+        .flatten()
+        .map(|shard| {
+            let mut parts = shard.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(url)) => (name.to_owned(), url.to_owned()),
+                _ => panic!(
+                    "--postgres-shard must have the form NAME=URL but is `{}`",
+                    SafeDisplay(shard)
+                ),
+            }
+        })
+        .collect();
+    let placement_rules: Vec<PlacementRule> = matches
+        .values_of("postgres-shard-rule")
+        .into_iter()
+        .flatten()
+        .map(|rule| rule.parse::<PlacementRule>())
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|e| panic!("invalid --postgres-shard-rule: {}", e));
 
     info!(logger, "Starting up");
 
@@ -528,9 +690,6 @@ async fn main() {
         })
         .collect();
 
-    // Convert the client into a link resolver
-    let link_resolver = Arc::new(LinkResolver::from(ipfs_clients));
-
     // Set up Prometheus registry
     let prometheus_registry = Arc::new(Registry::new());
     let metrics_registry = Arc::new(MetricsRegistry::new(
@@ -540,6 +699,40 @@ async fn main() {
     let mut metrics_server =
         PrometheusMetricsServer::new(&logger_factory, prometheus_registry.clone());
 
+    // Convert the clients into a link resolver, and add resolvers for the
+    // other supported link schemes
+    let mut ipfs_resolver = LinkResolver::from(ipfs_clients).with_metrics(metrics_registry.clone());
+    let mut ipfs_cache: Option<Arc<dyn IpfsCache>> = None;
+    if let Some(dir) = matches.value_of("ipfs-cache-dir") {
+        let disk_cache = Arc::new(DiskCache::from_env(dir).expect("failed to open IPFS cache dir"));
+        if let Some(seed) = matches.value_of("ipfs-cache-seed") {
+            let count = disk_cache
+                .seed_from_dir(Path::new(seed))
+                .expect("failed to seed IPFS cache");
+            info!(logger, "Seeded IPFS cache"; "dir" => seed, "files" => count);
+        }
+        info!(logger, "Caching IPFS files on disk"; "dir" => dir);
+        ipfs_cache = Some(disk_cache.clone());
+        ipfs_resolver = ipfs_resolver.with_disk_cache(disk_cache);
+    }
+    // Mappings can only read files from IPFS through `ipfs.cat` and
+    // `ipfs.map`; the contents of links with other schemes can change, which
+    // would make indexing non-deterministic
+    let runtime_link_resolver = Arc::new(ipfs_resolver.clone());
+    let mut link_resolver = SchemeLinkResolver::new(ipfs_resolver)
+        .with_scheme(
+            "ar",
+            ArweaveLinkResolver::new(arweave_adapter.clone()),
+            false,
+        )
+        .with_scheme("http", HttpLinkResolver::default(), true)
+        .with_scheme("https", HttpLinkResolver::default(), true);
+    if let Some(dir) = matches.value_of("file-link-dir") {
+        info!(logger, "Resolving `file://` links"; "dir" => dir);
+        link_resolver = link_resolver.with_scheme("file", FileLinkResolver::new(dir), false);
+    }
+    let link_resolver = Arc::new(link_resolver);
+
     // Ethereum clients
     let mut eth_networks = EthereumNetworks::new();
 
@@ -610,6 +803,26 @@ async fn main() {
         })
         .collect();
 
+    let shards: Vec<_> = pg_shards
+        .into_iter()
+        .map(|(name, url)| {
+            info!(
+                logger,
+                "Connecting to Postgres shard {}", name;
+                "url" => SafeDisplay(url.as_str())
+            );
+            let pool = create_connection_pool(
+                &format!("shard_{}", name),
+                url.clone(),
+                store_conn_pool_size,
+                &logger,
+                connection_pool_registry.cheap_clone(),
+                wait_stats.cheap_clone(),
+            );
+            Shard::new(name, url, pool).unwrap_or_else(|e| panic!("invalid shard: {}", e))
+        })
+        .collect();
+
     let chain_head_update_listener = Arc::new(PostgresChainHeadUpdateListener::new(
         &logger,
         stores_metrics_registry.clone(),
@@ -622,6 +835,28 @@ async fn main() {
     ));
 
     let expensive_queries = read_expensive_queries().unwrap();
+    let allow_list_store = Arc::new(AllowListStore::new(
+        &logger,
+        postgres_conn_pool.clone(),
+        postgres_url.clone(),
+    ));
+    let persisted_queries = Arc::new(
+        PersistedQueries::new(
+            &logger,
+            metrics_registry.clone(),
+            read_allow_lists().unwrap(),
+        )
+        .and_then(|persisted| persisted.with_store(allow_list_store))
+        .expect("failed to load persisted queries"),
+    );
+    persisted_queries.listen();
+    let api_keys = Arc::new(ApiKeys::new(
+        &logger,
+        metrics_registry.clone(),
+        read_api_keys().unwrap(),
+    ));
+    let admin_principals = read_admin_auth().unwrap();
+    let admin_audit_log = Arc::new(AdminAuditLog::new(postgres_conn_pool.clone()));
 
     graph::spawn(
         futures::stream::FuturesOrdered::from_iter(stores_eth_networks.flatten().into_iter().map(
@@ -656,6 +891,7 @@ async fn main() {
                     StoreConfig {
                         postgres_url: postgres_url.clone(),
                         network_name: network_name.to_string(),
+                        placement: placement_rules.clone(),
                     },
                     &stores_logger,
                     network_identifier,
@@ -664,6 +900,7 @@ async fn main() {
                     postgres_conn_pool.clone(),
                     read_only_conn_pools.clone(),
                     pg_host_weights.clone(),
+                    shards.clone(),
                     stores_metrics_registry.clone(),
                 )),
             )
@@ -690,18 +927,25 @@ async fn main() {
                 graphql_metrics_registry,
                 graphql_runner.clone(),
                 generic_store.clone(),
+                persisted_queries.clone(),
+                api_keys.clone(),
                 node_id.clone(),
             );
             let subscription_server = GraphQLSubscriptionServer::new(
                 &logger,
                 graphql_runner.clone(),
                 generic_store.clone(),
+                api_keys.clone(),
             );
 
+            // The index node server reports on the handlers of the
+            // subgraphs that the instance manager runs
+            let host_metrics = SharedHostMetrics::default();
             let mut index_node_server = IndexNodeServer::new(
                 &logger_factory,
                 graphql_runner.clone(),
                 generic_store.clone(),
+                host_metrics.cheap_clone(),
                 node_id.clone(),
             );
 
@@ -791,7 +1035,7 @@ async fn main() {
             );
             let runtime_host_builder = WASMRuntimeHostBuilder::new(
                 eth_networks.clone(),
-                link_resolver.clone(),
+                runtime_link_resolver,
                 stores.clone(),
                 arweave_adapter,
                 three_box_adapter,
@@ -805,6 +1049,7 @@ async fn main() {
                 block_stream_builder,
                 metrics_registry.clone(),
                 graphql_runner.cheap_clone(),
+                host_metrics,
             );
 
             // Create IPFS-based subgraph provider
@@ -854,6 +1099,10 @@ async fn main() {
                 http_port,
                 ws_port,
                 subgraph_registrar.clone(),
+                persisted_queries.clone(),
+                ipfs_cache,
+                admin_principals,
+                admin_audit_log,
                 node_id.clone(),
                 logger.clone(),
             )
@@ -913,6 +1162,22 @@ async fn main() {
                     .compat(),
             );
 
+            // Periodically remove the entity history that deployments do
+            // not want to keep
+            if enable_history_pruner {
+                let prune_store = generic_store.clone();
+                let prune_logger = logger.new(o!("component" => "HistoryPruner"));
+                std::thread::spawn(move || loop {
+                    std::thread::sleep(*PRUNE_INTERVAL);
+                    if let Err(e) = prune_store
+                        .prune_deployments(&prune_logger, *REORG_THRESHOLD as BlockNumber)
+                    {
+                        warn!(prune_logger, "Failed to prune entity history";
+                                            "error" => e.to_string());
+                    }
+                });
+            }
+
             future::ok(())
         })
         .compat(),
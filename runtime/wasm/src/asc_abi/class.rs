@@ -600,3 +600,19 @@ impl<V> Clone for AscWrapped<V> {
         Self { inner: self.inner }
     }
 }
+
+/// Result of a conversion to a value type such as `i64` or `f64`. Unlike
+/// `AscResult`, the value is stored inline in the `Wrapped<T>`, which is how
+/// AssemblyScript lays out a generic class instantiated with a value type.
+#[repr(C)]
+#[derive(AscType)]
+pub(crate) struct AscValueResult<V: AscValue, E> {
+    pub value: AscPtr<AscWrappedValue<V>>,
+    pub error: AscPtr<AscWrapped<E>>,
+}
+
+#[repr(C)]
+#[derive(AscType, Copy, Clone)]
+pub(crate) struct AscWrappedValue<V: AscValue> {
+    pub inner: V,
+}
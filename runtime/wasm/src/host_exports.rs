@@ -20,6 +20,29 @@ use graph_graphql::prelude::validate_entity;
 
 use crate::module::{WasmInstance, WasmInstanceContext};
 
+lazy_static! {
+    /// The number of `ipfs.map` callback results that are merged into the
+    /// changes of the call at once. This only saves us from holding the
+    /// results of all callbacks at the same time; the merged changes stay in
+    /// memory until the block is done.
+    static ref IPFS_MAP_BATCH_SIZE: usize = std::env::var("GRAPH_IPFS_MAP_BATCH_SIZE")
+        .ok()
+        .map(|s| usize::from_str(&s).unwrap_or_else(|_| {
+            panic!("GRAPH_IPFS_MAP_BATCH_SIZE must be a number, but is `{}`", s)
+        }))
+        .unwrap_or(100);
+
+    /// The largest number of entities that one call to `ipfs.map` may change.
+    /// Changes can only be written to the store once the entire block has
+    /// been processed, and this limit is what bounds the memory they take.
+    static ref IPFS_MAP_MAX_ENTITIES: usize = std::env::var("GRAPH_IPFS_MAP_MAX_ENTITIES")
+        .ok()
+        .map(|s| usize::from_str(&s).unwrap_or_else(|_| {
+            panic!("GRAPH_IPFS_MAP_MAX_ENTITIES must be a number, but is `{}`", s)
+        }))
+        .unwrap_or(1_000_000);
+}
+
 pub(crate) enum EthereumCallError {
     /// We might have detected a reorg.
     PossibleReorg(anyhow::Error),
@@ -178,12 +201,7 @@ impl HostExports {
 
         // Validate the changes against the subgraph schema.
         // If the set of fields we have is already valid, avoid hitting the DB.
-        if !is_valid
-            && self
-                .store
-                .uses_relational_schema(&self.subgraph_id)
-                .compat()?
-        {
+        if !is_valid {
             let entity = state
                 .entity_cache
                 .get(&key)
@@ -365,13 +383,17 @@ impl HostExports {
         Ok(block_on03(self.link_resolver.cat(logger, &Link { link })).compat()?)
     }
 
-    // Read the IPFS file `link`, split it into JSON objects, and invoke the
-    // exported function `callback` on each JSON object. The successful return
-    // value contains the block state produced by each callback invocation. Each
-    // invocation of `callback` happens in its own instance of a WASM module,
-    // which is identical to `module` when it was first started. The signature
-    // of the callback must be `callback(JSONValue, Value)`, and the `userData`
-    // parameter is passed to the callback without any changes
+    // Read the IPFS file `link`, split it into JSON values according to the
+    // format given in `flags`, and invoke the exported function `callback` on
+    // each value. The block states produced by the callbacks are merged in
+    // batches of `GRAPH_IPFS_MAP_BATCH_SIZE`, and the call fails if they
+    // change more than `GRAPH_IPFS_MAP_MAX_ENTITIES` entities. The changes
+    // only become part of the block state of `module` if the whole file was
+    // processed successfully. Each invocation of `callback` happens in its
+    // own instance of a WASM module, which is identical to `module` when it
+    // was first started. The signature of the callback must be
+    // `callback(JSONValue, Value)`, and the `userData` parameter is passed to
+    // the callback without any changes
     pub(crate) fn ipfs_map(
         link_resolver: &Arc<dyn LinkResolver>,
         module: &mut WasmInstanceContext,
@@ -379,14 +401,10 @@ impl HostExports {
         callback: &str,
         user_data: store::Value,
         flags: Vec<String>,
-    ) -> Result<Vec<BlockState>, anyhow::Error> {
+    ) -> Result<IpfsMapStats, anyhow::Error> {
         use graph::prelude::failure::ResultExt;
 
-        const JSON_FLAG: &str = "json";
-        anyhow::ensure!(
-            flags.contains(&JSON_FLAG.to_string()),
-            "Flags must contain 'json'"
-        );
+        let flags = IpfsMapFlags::parse(&flags)?;
 
         let host_metrics = module.host_metrics.clone();
         let valid_module = module.valid_module.clone();
@@ -402,19 +420,43 @@ impl HostExports {
         let mut last_log = start;
         let logger = ctx.logger.new(o!("ipfs_map" => link.clone()));
 
+        // Collect the changes separately so that a failure leaves the block
+        // state of `module` untouched
+        let mut state = BlockState::new(
+            module.ctx.state.entity_cache.store.clone(),
+            Default::default(),
+        );
         let result = {
             let mut stream: JsonValueStream =
-                block_on03(link_resolver.json_stream(&logger, &Link { link })).compat()?;
-            let mut v = Vec::new();
+                block_on03(link_resolver.json_stream(&logger, &Link { link }, flags.format))
+                    .compat()?;
+            let mut stats = IpfsMapStats::default();
+            let mut batch = Vec::with_capacity(*IPFS_MAP_BATCH_SIZE);
             while let Some(sv) = block_on03(stream.next()) {
-                let sv = sv.compat()?;
-                let module = WasmInstance::from_valid_module_with_ctx(
+                let sv = match sv {
+                    Ok(sv) => sv,
+                    Err(e) => match e.downcast_ref::<MalformedRecord>() {
+                        Some(record) if flags.skip_errors => {
+                            warn!(
+                                logger,
+                                "Skipping malformed record";
+                                "line" => record.line,
+                                "error" => &record.message
+                            );
+                            stats.skipped += 1;
+                            continue;
+                        }
+                        _ => return Err(e.compat().into()),
+                    },
+                };
+                let instance = WasmInstance::from_valid_module_with_ctx(
                     valid_module.clone(),
                     ctx.derive_with_empty_block_state(),
                     host_metrics.clone(),
                     module.timeout,
                 )?;
-                let result = module.handle_json_callback(&callback, &sv.value, &user_data)?;
+                let result = instance.handle_json_callback(&callback, &sv.value, &user_data)?;
+                stats.records += 1;
                 // Log progress every 15s
                 if last_log.elapsed() > Duration::from_secs(15) {
                     debug!(
@@ -425,11 +467,20 @@ impl HostExports {
                     );
                     last_log = Instant::now();
                 }
-                v.push(result)
+                batch.push(result);
+                if batch.len() >= *IPFS_MAP_BATCH_SIZE {
+                    merge_block_states(&mut state, &mut batch)?;
+                    check_ipfs_map_entities(&state)?;
+                }
             }
-            Ok(v)
+            merge_block_states(&mut state, &mut batch)?;
+            check_ipfs_map_entities(&state)?;
+            Ok(stats)
         };
-        result.map_err(move |e: Error| anyhow::anyhow!("{}: {}", errmsg, e.to_string()))
+        let stats =
+            result.map_err(move |e: Error| anyhow::anyhow!("{}: {}", errmsg, e.to_string()))?;
+        merge_block_states(&mut module.ctx.state, &mut vec![state])?;
+        Ok(stats)
     }
 
     /// Expects a decimal string.
@@ -607,7 +658,7 @@ impl HostExports {
     }
 
     pub(crate) fn arweave_transaction_data(&self, tx_id: &str) -> Option<Bytes> {
-        block_on03(self.arweave_adapter.tx_data(tx_id)).ok()
+        block_on03(self.arweave_adapter.tx_data(tx_id, None)).ok()
     }
 
     pub(crate) fn box_profile(
@@ -618,10 +669,201 @@ impl HostExports {
     }
 }
 
+/// Merge the block states produced by `ipfs.map` callbacks into `state`,
+/// leaving `batch` empty.
+fn merge_block_states(
+    state: &mut BlockState,
+    batch: &mut Vec<BlockState>,
+) -> Result<(), anyhow::Error> {
+    for output_state in batch.drain(..) {
+        state
+            .entity_cache
+            .extend(output_state.entity_cache)
+            .map_err(anyhow::Error::from)?;
+        state
+            .created_data_sources
+            .extend(output_state.created_data_sources);
+    }
+    Ok(())
+}
+
+/// Fail if the changes `ipfs.map` collected in `state` touch more entities
+/// than `GRAPH_IPFS_MAP_MAX_ENTITIES` allows.
+fn check_ipfs_map_entities(state: &BlockState) -> Result<(), anyhow::Error> {
+    let count = state.entity_cache.update_count();
+    anyhow::ensure!(
+        count <= *IPFS_MAP_MAX_ENTITIES,
+        "the callbacks changed {} entities, but at most {} are allowed; \
+         see GRAPH_IPFS_MAP_MAX_ENTITIES",
+        count,
+        *IPFS_MAP_MAX_ENTITIES
+    );
+    Ok(())
+}
+
+/// What happened during a call to `ipfs.map`.
+#[derive(Debug, Default)]
+pub(crate) struct IpfsMapStats {
+    /// The number of records for which the callback was invoked
+    pub records: usize,
+    /// The number of malformed records that were skipped
+    pub skipped: usize,
+}
+
+/// The options a mapping can pass to `ipfs.map`. Exactly one of `json`,
+/// `jsonArray` or `csv` must be given to determine the file format; the
+/// `skipErrors` flag makes `ipfs.map` log and skip malformed records
+/// instead of failing. Unknown flags are ignored.
+#[derive(Debug, PartialEq)]
+struct IpfsMapFlags {
+    format: StreamFormat,
+    skip_errors: bool,
+}
+
+impl IpfsMapFlags {
+    fn parse(flags: &[String]) -> Result<Self, anyhow::Error> {
+        let mut format = None;
+        let mut skip_errors = false;
+        for flag in flags {
+            let flag_format = match flag.as_str() {
+                "json" => StreamFormat::JsonLines,
+                "jsonArray" => StreamFormat::JsonArray,
+                "csv" => StreamFormat::Csv,
+                "skipErrors" => {
+                    skip_errors = true;
+                    continue;
+                }
+                _ => continue,
+            };
+            anyhow::ensure!(
+                format.is_none() || format == Some(flag_format),
+                "Flags must contain only one of 'json', 'jsonArray' or 'csv'"
+            );
+            format = Some(flag_format);
+        }
+        let format = format
+            .ok_or_else(|| anyhow::anyhow!("Flags must contain 'json', 'jsonArray' or 'csv'"))?;
+        Ok(IpfsMapFlags {
+            format,
+            skip_errors,
+        })
+    }
+}
+
 pub(crate) fn json_from_bytes(bytes: &Vec<u8>) -> Result<serde_json::Value, serde_json::Error> {
     serde_json::from_reader(bytes.as_slice())
 }
 
+/// A step in a path into a JSON value
+#[derive(Debug, PartialEq)]
+enum JsonPathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// Parse a path like `$.attributes[0].value` or `a["key with spaces"]`. The
+/// leading `$` and the `.` before a leading key are optional, and the empty
+/// path refers to the value itself.
+fn parse_json_path(path: &str) -> Result<Vec<JsonPathSegment>, anyhow::Error> {
+    let invalid = |reason: &str| anyhow::anyhow!("Invalid JSON path `{}`: {}", path, reason);
+
+    let normalized = match path.strip_prefix('$') {
+        Some(rest) => rest.to_owned(),
+        None if path.is_empty() || path.starts_with('.') || path.starts_with('[') => {
+            path.to_owned()
+        }
+        None => format!(".{}", path),
+    };
+    let chars: Vec<char> = normalized.chars().collect();
+
+    let mut segments = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '.' => {
+                i += 1;
+                let start = i;
+                while i < chars.len() && chars[i] != '.' && chars[i] != '[' {
+                    i += 1;
+                }
+                if start == i {
+                    return Err(invalid("empty key"));
+                }
+                segments.push(JsonPathSegment::Key(chars[start..i].iter().collect()));
+            }
+            '[' => {
+                i += 1;
+                match chars.get(i) {
+                    Some(&quote) if quote == '"' || quote == '\'' => {
+                        i += 1;
+                        let start = i;
+                        while i < chars.len() && chars[i] != quote {
+                            i += 1;
+                        }
+                        if i == chars.len() {
+                            return Err(invalid("unterminated key"));
+                        }
+                        segments.push(JsonPathSegment::Key(chars[start..i].iter().collect()));
+                        i += 1;
+                    }
+                    _ => {
+                        let start = i;
+                        while i < chars.len() && chars[i].is_ascii_digit() {
+                            i += 1;
+                        }
+                        let index = chars[start..i]
+                            .iter()
+                            .collect::<String>()
+                            .parse()
+                            .map_err(|_| invalid("expected an array index or a quoted key"))?;
+                        segments.push(JsonPathSegment::Index(index));
+                    }
+                }
+                if chars.get(i) != Some(&']') {
+                    return Err(invalid("expected `]`"));
+                }
+                i += 1;
+            }
+            _ => return Err(invalid("expected `.` or `[`")),
+        }
+    }
+    Ok(segments)
+}
+
+/// Look up the value at `path` in `value`. Returns `None` if there is no
+/// such value, and an error if `path` is not a valid path.
+pub(crate) fn json_get<'a>(
+    value: &'a serde_json::Value,
+    path: &str,
+) -> Result<Option<&'a serde_json::Value>, anyhow::Error> {
+    Ok(parse_json_path(path)?
+        .iter()
+        .try_fold(value, |value, segment| match segment {
+            JsonPathSegment::Key(key) => value.get(key),
+            JsonPathSegment::Index(index) => value.get(*index),
+        }))
+}
+
+/// Convert a store value to JSON. `BigInt` and `BigDecimal` values become
+/// strings so that they do not lose precision, and `Bytes` become hex
+/// strings.
+pub(crate) fn store_value_to_json(value: &store::Value) -> serde_json::Value {
+    use serde_json::Value as JsonValue;
+
+    match value {
+        store::Value::String(s) => JsonValue::String(s.clone()),
+        store::Value::Int(i) => JsonValue::from(*i),
+        store::Value::BigDecimal(d) => JsonValue::String(d.to_string()),
+        store::Value::Bool(b) => JsonValue::Bool(*b),
+        store::Value::List(values) => {
+            JsonValue::Array(values.iter().map(store_value_to_json).collect())
+        }
+        store::Value::Null => JsonValue::Null,
+        store::Value::Bytes(bytes) => JsonValue::String(bytes.to_string()),
+        store::Value::BigInt(n) => JsonValue::String(n.to_string()),
+    }
+}
+
 pub(crate) fn string_to_h160(string: &str) -> Result<H160, anyhow::Error> {
     // `H160::from_str` takes a hex string with no leading `0x`.
     let s = string.trim_start_matches("0x");
@@ -647,6 +889,100 @@ pub(crate) fn bytes_to_string(logger: &Logger, bytes: Vec<u8>) -> String {
     s.trim_end_matches('\u{0000}').to_string()
 }
 
+pub(crate) fn crypto_sha256(input: &[u8]) -> Vec<u8> {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(input).to_vec()
+}
+
+/// SHA3-256 as standardized in FIPS 202; this is not the same as Keccak-256.
+pub(crate) fn crypto_sha3(input: &[u8]) -> Vec<u8> {
+    use sha3::{Digest, Sha3_256};
+    Sha3_256::digest(input).to_vec()
+}
+
+/// Recover the address that signed the 32 byte `hash`. The `signature` must
+/// be 65 bytes long and consist of `r`, `s` and the recovery id `v`, which
+/// can be either `0`/`1` or `27`/`28`. Returns `None` if no address can be
+/// recovered from the signature.
+pub(crate) fn crypto_secp256k1_recover(
+    hash: &[u8],
+    signature: &[u8],
+) -> Result<Option<H160>, anyhow::Error> {
+    use secp256k1::recovery::{RecoverableSignature, RecoveryId};
+    use secp256k1::{Message, Secp256k1};
+
+    if hash.len() != 32 {
+        return Err(anyhow::anyhow!(
+            "secp256k1.recover: the hash must be 32 bytes long but is {} bytes",
+            hash.len()
+        ));
+    }
+    if signature.len() != 65 {
+        return Err(anyhow::anyhow!(
+            "secp256k1.recover: the signature must be 65 bytes long but is {} bytes",
+            signature.len()
+        ));
+    }
+
+    let v = match signature[64] {
+        v @ 0..=1 => v,
+        v @ 27..=28 => v - 27,
+        _ => return Ok(None),
+    };
+    let recovery_id = match RecoveryId::from_i32(v as i32) {
+        Ok(id) => id,
+        Err(_) => return Ok(None),
+    };
+    let signature = match RecoverableSignature::from_compact(&signature[..64], recovery_id) {
+        Ok(signature) => signature,
+        Err(_) => return Ok(None),
+    };
+    let message = Message::from_slice(hash)?;
+    let public_key = match Secp256k1::verification_only().recover(&message, &signature) {
+        Ok(public_key) => public_key,
+        Err(_) => return Ok(None),
+    };
+
+    // The address is the last 20 bytes of the hash of the public key,
+    // without the leading byte that marks it as uncompressed
+    let hash = tiny_keccak::keccak256(&public_key.serialize_uncompressed()[1..]);
+    Ok(Some(H160::from_slice(&hash[12..])))
+}
+
+pub(crate) fn ethereum_encode(token: Token) -> Vec<u8> {
+    ethabi::encode(&[token])
+}
+
+/// Decode `data` as a single value of type `types`, e.g. `(address,uint256)`.
+/// Returns `None` if the data does not match the type.
+pub(crate) fn ethereum_decode(types: &str, data: &[u8]) -> Result<Option<Token>, anyhow::Error> {
+    let param_type = ethabi::param_type::Reader::read(types)
+        .map_err(|e| anyhow::anyhow!("ethereum.decode: invalid type `{}`: {}", types, e))?;
+    Ok(ethabi::decode(&[param_type], data)
+        .ok()
+        .and_then(|mut tokens| tokens.pop()))
+}
+
+pub(crate) fn bytes_to_base64(bytes: &[u8]) -> String {
+    data_encoding::BASE64.encode(bytes)
+}
+
+pub(crate) fn base64_to_bytes(string: &str) -> Result<Vec<u8>, anyhow::Error> {
+    data_encoding::BASE64
+        .decode(string.as_bytes())
+        .with_context(|| format!("Failed to decode base64 string: '{}'", string))
+}
+
+pub(crate) fn bytes_to_base32(bytes: &[u8]) -> String {
+    data_encoding::BASE32.encode(bytes)
+}
+
+pub(crate) fn base32_to_bytes(string: &str) -> Result<Vec<u8>, anyhow::Error> {
+    data_encoding::BASE32
+        .decode(string.as_bytes())
+        .with_context(|| format!("Failed to decode base32 string: '{}'", string))
+}
+
 #[test]
 fn test_string_to_h160_with_0x() {
     assert_eq!(
@@ -663,6 +999,30 @@ fn block_on03<T>(future: impl futures03::Future<Output = T> + Send) -> T {
     graph::block_on(future)
 }
 
+#[test]
+fn ipfs_map_flags() {
+    let parse = |flags: &[&str]| {
+        IpfsMapFlags::parse(&flags.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    };
+
+    assert_eq!(
+        IpfsMapFlags {
+            format: StreamFormat::JsonLines,
+            skip_errors: false
+        },
+        parse(&["json"]).unwrap()
+    );
+    assert_eq!(
+        IpfsMapFlags {
+            format: StreamFormat::Csv,
+            skip_errors: true
+        },
+        parse(&["skipErrors", "csv", "other"]).unwrap()
+    );
+    assert!(parse(&["skipErrors"]).is_err());
+    assert!(parse(&["json", "jsonArray"]).is_err());
+}
+
 #[test]
 fn bytes_to_string_is_lossy() {
     assert_eq!(
@@ -684,3 +1044,113 @@ fn bytes_to_string_is_lossy() {
         )
     )
 }
+
+#[test]
+fn crypto_hashes() {
+    assert_eq!(
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        hex::encode(crypto_sha256(b""))
+    );
+    assert_eq!(
+        "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a",
+        hex::encode(crypto_sha3(b""))
+    );
+}
+
+#[test]
+fn secp256k1_recover() {
+    use secp256k1::{Message, Secp256k1, SecretKey};
+
+    let secp = Secp256k1::new();
+    let secret_key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+    let public_key = secp256k1::PublicKey::from_secret_key(&secp, &secret_key);
+    let address =
+        H160::from_slice(&tiny_keccak::keccak256(&public_key.serialize_uncompressed()[1..])[12..]);
+
+    let hash = tiny_keccak::keccak256(b"hello");
+    let (recovery_id, compact) = secp
+        .sign_recoverable(&Message::from_slice(&hash).unwrap(), &secret_key)
+        .serialize_compact();
+    let mut signature = compact.to_vec();
+    signature.push(recovery_id.to_i32() as u8 + 27);
+
+    assert_eq!(
+        Some(address),
+        crypto_secp256k1_recover(&hash, &signature).unwrap()
+    );
+
+    signature[64] = 5;
+    assert_eq!(None, crypto_secp256k1_recover(&hash, &signature).unwrap());
+    assert!(crypto_secp256k1_recover(&hash[1..], &signature).is_err());
+}
+
+#[test]
+fn ethereum_encode_decode() {
+    let token = Token::Tuple(vec![
+        Token::Address(Address::from_low_u64_be(1)),
+        Token::Uint(42u64.into()),
+    ]);
+    let data = ethereum_encode(token.clone());
+    assert_eq!(64, data.len());
+    assert_eq!(
+        Some(token),
+        ethereum_decode("(address,uint256)", &data).unwrap()
+    );
+    assert_eq!(
+        None,
+        ethereum_decode("(address,uint256)", &data[1..]).unwrap()
+    );
+    assert!(ethereum_decode("(address,nope)", &data).is_err());
+}
+
+#[test]
+fn base64_and_base32() {
+    assert_eq!("aGVsbG8=", bytes_to_base64(b"hello"));
+    assert_eq!(b"hello".to_vec(), base64_to_bytes("aGVsbG8=").unwrap());
+    assert!(base64_to_bytes("not base64!").is_err());
+
+    assert_eq!("NBSWY3DP", bytes_to_base32(b"hello"));
+    assert_eq!(b"hello".to_vec(), base32_to_bytes("NBSWY3DP").unwrap());
+    assert!(base32_to_bytes("not base32!").is_err());
+}
+
+#[test]
+fn json_get_by_path() {
+    use serde_json::json;
+
+    let value = json!({
+        "name": "Token",
+        "attributes": [{"trait_type": "color", "value": "red"}],
+        "key with.dots": 1
+    });
+    let get = |path| json_get(&value, path).unwrap().cloned();
+
+    assert_eq!(Some(value.clone()), get(""));
+    assert_eq!(Some(value.clone()), get("$"));
+    assert_eq!(Some(json!("Token")), get("name"));
+    assert_eq!(Some(json!("red")), get("$.attributes[0].value"));
+    assert_eq!(Some(json!("red")), get("attributes[0]['value']"));
+    assert_eq!(Some(json!(1)), get("[\"key with.dots\"]"));
+    assert_eq!(None, get("attributes[1]"));
+    assert_eq!(None, get("name.first"));
+
+    assert!(json_get(&value, "attributes[x]").is_err());
+    assert!(json_get(&value, "name..first").is_err());
+    assert!(json_get(&value, "['name'").is_err());
+}
+
+#[test]
+fn store_value_to_json_keeps_precision() {
+    use serde_json::json;
+
+    let value = store::Value::List(vec![
+        store::Value::Int(1),
+        store::Value::BigInt(BigInt::from(u64::MAX)),
+        store::Value::Bytes(store::scalar::Bytes::from(&[1u8, 2][..])),
+        store::Value::Null,
+    ]);
+    assert_eq!(
+        json!([1, "18446744073709551615", "0x0102", null]),
+        store_value_to_json(&value)
+    );
+}
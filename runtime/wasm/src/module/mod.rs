@@ -20,7 +20,7 @@ use web3::types::{Log, Transaction, U256};
 use crate::asc_abi::asc_ptr::*;
 use crate::asc_abi::class::*;
 use crate::asc_abi::*;
-use crate::host_exports::{EthereumCallError, HostExports};
+use crate::host_exports::{EthereumCallError, HostExports, IpfsMapStats};
 use crate::mapping::ValidModule;
 use crate::UnresolvedContractCall;
 
@@ -417,6 +417,16 @@ impl WasmInstance {
             flags
         );
 
+        link!(
+            "ipfs.mapWithSkipCount",
+            ipfs_map_with_skip_count,
+            "host_export_ipfs_map",
+            link_ptr,
+            callback,
+            user_data,
+            flags
+        );
+
         link!("store.remove", store_remove, entity_ptr, id_ptr);
 
         link!("typeConversion.bytesToString", bytes_to_string, ptr);
@@ -425,6 +435,10 @@ impl WasmInstance {
         link!("typeConversion.bigIntToHex", big_int_to_hex, ptr);
         link!("typeConversion.stringToH160", string_to_h160, ptr);
         link!("typeConversion.bytesToBase58", bytes_to_base58, ptr);
+        link!("typeConversion.bytesToBase64", bytes_to_base64, ptr);
+        link!("typeConversion.base64ToBytes", base64_to_bytes, ptr);
+        link!("typeConversion.bytesToBase32", bytes_to_base32, ptr);
+        link!("typeConversion.base32ToBytes", base32_to_bytes, ptr);
 
         link!("json.fromBytes", json_from_bytes, ptr);
         link!("json.try_fromBytes", json_try_from_bytes, ptr);
@@ -432,8 +446,26 @@ impl WasmInstance {
         link!("json.toU64", json_to_u64, ptr);
         link!("json.toF64", json_to_f64, ptr);
         link!("json.toBigInt", json_to_big_int, ptr);
+        link!("json.try_toI64", json_try_to_i64, ptr);
+        link!("json.try_toU64", json_try_to_u64, ptr);
+        link!("json.try_toF64", json_try_to_f64, ptr);
+        link!("json.try_toBigInt", json_try_to_big_int, ptr);
+        link!("json.get", json_get, value_ptr, path_ptr);
+        link!("json.toBytes", json_to_bytes, ptr);
+        link!("json.fromTypedMap", json_from_typed_map, ptr);
 
         link!("crypto.keccak256", crypto_keccak_256, ptr);
+        link!("crypto.sha256", crypto_sha256, ptr);
+        link!("crypto.sha3", crypto_sha3, ptr);
+        link!(
+            "crypto.secp256k1.recover",
+            crypto_secp256k1_recover,
+            hash_ptr,
+            signature_ptr
+        );
+
+        link!("ethereum.encode", ethereum_encode, token_ptr);
+        link!("ethereum.decode", ethereum_decode, types_ptr, data_ptr);
 
         link!("bigInt.plus", big_int_plus, x_ptr, y_ptr);
         link!("bigInt.minus", big_int_minus, x_ptr, y_ptr);
@@ -823,7 +855,7 @@ impl WasmInstanceContext {
         }
     }
 
-    /// function ipfs.map(link: String, callback: String, flags: String[]): void
+    /// function ipfs.map(link: String, callback: String, userData: Value, flags: String[]): void
     fn ipfs_map(
         &mut self,
         link_ptr: AscPtr<AscString>,
@@ -831,6 +863,32 @@ impl WasmInstanceContext {
         user_data: AscPtr<AscEnum<StoreValueKind>>,
         flags: AscPtr<Array<AscPtr<AscString>>>,
     ) -> Result<(), Trap> {
+        self.run_ipfs_map(link_ptr, callback, user_data, flags)
+            .map(|_| ())
+    }
+
+    /// Like `ipfs.map`, but returns the number of malformed records that
+    /// were skipped because of the `skipErrors` flag.
+    /// function ipfs.mapWithSkipCount(link: String, callback: String, userData: Value, flags: String[]): i32
+    fn ipfs_map_with_skip_count(
+        &mut self,
+        link_ptr: AscPtr<AscString>,
+        callback: AscPtr<AscString>,
+        user_data: AscPtr<AscEnum<StoreValueKind>>,
+        flags: AscPtr<Array<AscPtr<AscString>>>,
+    ) -> Result<i32, Trap> {
+        let stats = self.run_ipfs_map(link_ptr, callback, user_data, flags)?;
+        Ok(i32::try_from(stats.skipped).unwrap_or(i32::max_value()))
+    }
+
+    /// Run `ipfs.map` for `ipfs_map` and `ipfs_map_with_skip_count`
+    fn run_ipfs_map(
+        &mut self,
+        link_ptr: AscPtr<AscString>,
+        callback: AscPtr<AscString>,
+        user_data: AscPtr<AscEnum<StoreValueKind>>,
+        flags: AscPtr<Array<AscPtr<AscString>>>,
+    ) -> Result<IpfsMapStats, Trap> {
         let link: String = self.asc_get(link_ptr);
         let callback: String = self.asc_get(callback);
         let user_data: store::Value = self.try_asc_get(user_data)?;
@@ -843,7 +901,7 @@ impl WasmInstanceContext {
         let _stopwatch_guard = defer::defer(|| defer_stopwatch.lock().unwrap().start());
 
         let start_time = Instant::now();
-        let output_states = HostExports::ipfs_map(
+        let stats = HostExports::ipfs_map(
             &self.ctx.host_exports.link_resolver.clone(),
             self,
             link.clone(),
@@ -857,22 +915,12 @@ impl WasmInstanceContext {
             "Successfully processed file with ipfs.map";
             "link" => &link,
             "callback" => &*callback,
-            "n_calls" => output_states.len(),
+            "n_calls" => stats.records,
+            "n_skipped" => stats.skipped,
             "time" => format!("{}ms", start_time.elapsed().as_millis())
         );
-        for output_state in output_states {
-            self.ctx
-                .state
-                .entity_cache
-                .extend(output_state.entity_cache)
-                .map_err(anyhow::Error::from)?;
-            self.ctx
-                .state
-                .created_data_sources
-                .extend(output_state.created_data_sources);
-        }
 
-        Ok(())
+        Ok(stats)
     }
 
     /// Expects a decimal string.
@@ -905,6 +953,112 @@ impl WasmInstanceContext {
         Ok(big_int_ptr)
     }
 
+    /// Log a failed JSON conversion and map it to the `true` that mappings
+    /// expect as the error of a `Result<T, boolean>`
+    fn json_conversion_failed(&self, json: &str, e: anyhow::Error) -> bool {
+        warn!(
+            &self.ctx.logger,
+            "Failed to convert JSON";
+            "json" => json,
+            "error" => format!("{:#}", e)
+        );
+        true
+    }
+
+    /// function json.try_toI64(json: String): Result<i64, boolean>
+    fn json_try_to_i64(
+        &mut self,
+        json_ptr: AscPtr<AscString>,
+    ) -> AscPtr<AscValueResult<i64, bool>> {
+        let json: String = self.asc_get(json_ptr);
+        let result = self
+            .ctx
+            .host_exports
+            .json_to_i64(json.clone())
+            .map_err(|e| self.json_conversion_failed(&json, e));
+        self.asc_new(&result)
+    }
+
+    /// function json.try_toU64(json: String): Result<u64, boolean>
+    fn json_try_to_u64(
+        &mut self,
+        json_ptr: AscPtr<AscString>,
+    ) -> AscPtr<AscValueResult<u64, bool>> {
+        let json: String = self.asc_get(json_ptr);
+        let result = self
+            .ctx
+            .host_exports
+            .json_to_u64(json.clone())
+            .map_err(|e| self.json_conversion_failed(&json, e));
+        self.asc_new(&result)
+    }
+
+    /// function json.try_toF64(json: String): Result<f64, boolean>
+    fn json_try_to_f64(
+        &mut self,
+        json_ptr: AscPtr<AscString>,
+    ) -> AscPtr<AscValueResult<f64, bool>> {
+        let json: String = self.asc_get(json_ptr);
+        let result = self
+            .ctx
+            .host_exports
+            .json_to_f64(json.clone())
+            .map_err(|e| self.json_conversion_failed(&json, e));
+        self.asc_new(&result)
+    }
+
+    /// function json.try_toBigInt(json: String): Result<BigInt, boolean>
+    fn json_try_to_big_int(
+        &mut self,
+        json_ptr: AscPtr<AscString>,
+    ) -> AscPtr<AscResult<AscBigInt, bool>> {
+        let json: String = self.asc_get(json_ptr);
+        let result = self
+            .ctx
+            .host_exports
+            .json_to_big_int(json.clone())
+            .map(|bytes| BigInt::from_signed_bytes_le(&bytes))
+            .map_err(|e| self.json_conversion_failed(&json, e));
+        self.asc_new(&result)
+    }
+
+    /// function json.get(value: JSONValue, path: String): JSONValue | null
+    fn json_get(
+        &mut self,
+        value_ptr: AscPtr<AscEnum<JsonValueKind>>,
+        path_ptr: AscPtr<AscString>,
+    ) -> Result<AscPtr<AscEnum<JsonValueKind>>, Trap> {
+        let value: serde_json::Value = self.try_asc_get(value_ptr)?;
+        let path: String = self.asc_get(path_ptr);
+        Ok(match host_exports::json_get(&value, &path)? {
+            Some(value) => self.asc_new(value),
+            None => AscPtr::null(),
+        })
+    }
+
+    /// function json.toBytes(value: JSONValue): Bytes
+    fn json_to_bytes(
+        &mut self,
+        value_ptr: AscPtr<AscEnum<JsonValueKind>>,
+    ) -> Result<AscPtr<Uint8Array>, Trap> {
+        let value: serde_json::Value = self.try_asc_get(value_ptr)?;
+        let bytes = serde_json::to_vec(&value).map_err(anyhow::Error::from)?;
+        Ok(self.asc_new(bytes.as_slice()))
+    }
+
+    /// function json.fromTypedMap(map: TypedMap<string, Value>): JSONValue
+    fn json_from_typed_map(
+        &mut self,
+        map_ptr: AscPtr<AscEntity>,
+    ) -> Result<AscPtr<AscEnum<JsonValueKind>>, Trap> {
+        let map: HashMap<String, store::Value> = self.try_asc_get(map_ptr)?;
+        let object: serde_json::Map<_, _> = map
+            .iter()
+            .map(|(key, value)| (key.clone(), host_exports::store_value_to_json(value)))
+            .collect();
+        Ok(self.asc_new(&serde_json::Value::Object(object)))
+    }
+
     /// function crypto.keccak256(input: Bytes): Bytes
     fn crypto_keccak_256(
         &mut self,
@@ -918,6 +1072,57 @@ impl WasmInstanceContext {
         Ok(hash_ptr)
     }
 
+    /// function crypto.sha256(input: Bytes): Bytes
+    fn crypto_sha256(&mut self, input_ptr: AscPtr<Uint8Array>) -> AscPtr<Uint8Array> {
+        let input: Vec<u8> = self.asc_get(input_ptr);
+        self.asc_new(host_exports::crypto_sha256(&input).as_slice())
+    }
+
+    /// function crypto.sha3(input: Bytes): Bytes
+    fn crypto_sha3(&mut self, input_ptr: AscPtr<Uint8Array>) -> AscPtr<Uint8Array> {
+        let input: Vec<u8> = self.asc_get(input_ptr);
+        self.asc_new(host_exports::crypto_sha3(&input).as_slice())
+    }
+
+    /// function crypto.secp256k1.recover(hash: Bytes, signature: Bytes): Address | null
+    fn crypto_secp256k1_recover(
+        &mut self,
+        hash_ptr: AscPtr<Uint8Array>,
+        signature_ptr: AscPtr<Uint8Array>,
+    ) -> Result<AscPtr<Uint8Array>, Trap> {
+        let hash: Vec<u8> = self.asc_get(hash_ptr);
+        let signature: Vec<u8> = self.asc_get(signature_ptr);
+        Ok(
+            match host_exports::crypto_secp256k1_recover(&hash, &signature)? {
+                Some(address) => self.asc_new(&address),
+                None => AscPtr::null(),
+            },
+        )
+    }
+
+    /// function ethereum.encode(token: ethereum.Value): Bytes
+    fn ethereum_encode(
+        &mut self,
+        token_ptr: AscPtr<AscEnum<EthereumValueKind>>,
+    ) -> AscPtr<Uint8Array> {
+        let data = host_exports::ethereum_encode(self.asc_get(token_ptr));
+        self.asc_new(data.as_slice())
+    }
+
+    /// function ethereum.decode(types: String, data: Bytes): ethereum.Value | null
+    fn ethereum_decode(
+        &mut self,
+        types_ptr: AscPtr<AscString>,
+        data_ptr: AscPtr<Uint8Array>,
+    ) -> Result<AscPtr<AscEnum<EthereumValueKind>>, Trap> {
+        let types: String = self.asc_get(types_ptr);
+        let data: Vec<u8> = self.asc_get(data_ptr);
+        Ok(match host_exports::ethereum_decode(&types, &data)? {
+            Some(token) => self.asc_new(&token),
+            None => AscPtr::null(),
+        })
+    }
+
     /// function bigInt.plus(x: BigInt, y: BigInt): BigInt
     fn big_int_plus(
         &mut self,
@@ -1027,6 +1232,38 @@ impl WasmInstanceContext {
         Ok(result_ptr)
     }
 
+    /// function typeConversion.bytesToBase64(bytes: Bytes): string
+    fn bytes_to_base64(&mut self, bytes_ptr: AscPtr<Uint8Array>) -> AscPtr<AscString> {
+        let bytes: Vec<u8> = self.asc_get(bytes_ptr);
+        self.asc_new(&host_exports::bytes_to_base64(&bytes))
+    }
+
+    /// function typeConversion.base64ToBytes(string: string): Bytes
+    fn base64_to_bytes(
+        &mut self,
+        string_ptr: AscPtr<AscString>,
+    ) -> Result<AscPtr<Uint8Array>, Trap> {
+        let string: String = self.asc_get(string_ptr);
+        let bytes = host_exports::base64_to_bytes(&string)?;
+        Ok(self.asc_new(bytes.as_slice()))
+    }
+
+    /// function typeConversion.bytesToBase32(bytes: Bytes): string
+    fn bytes_to_base32(&mut self, bytes_ptr: AscPtr<Uint8Array>) -> AscPtr<AscString> {
+        let bytes: Vec<u8> = self.asc_get(bytes_ptr);
+        self.asc_new(&host_exports::bytes_to_base32(&bytes))
+    }
+
+    /// function typeConversion.base32ToBytes(string: string): Bytes
+    fn base32_to_bytes(
+        &mut self,
+        string_ptr: AscPtr<AscString>,
+    ) -> Result<AscPtr<Uint8Array>, Trap> {
+        let string: String = self.asc_get(string_ptr);
+        let bytes = host_exports::base32_to_bytes(&string)?;
+        Ok(self.asc_new(bytes.as_slice()))
+    }
+
     /// function bigDecimal.toString(x: BigDecimal): string
     fn big_decimal_to_string(
         &mut self,
@@ -239,6 +239,69 @@ async fn json_conversions() {
     );
 }
 
+#[tokio::test]
+async fn json_try_conversions() {
+    let mut module = test_module(
+        "jsonTryConversions",
+        mock_data_source("wasm_test/string_to_number.wasm"),
+    );
+
+    // Read the fields of a `Result<T, boolean>` the way AssemblyScript lays them
+    // out: `value` and `error` are pointers to `Wrapped<T>`, which holds a value
+    // type such as `i64` inline.
+    fn read_result<T: AscType>(module: &WasmInstance, ptr: u32) -> (Option<T>, Option<bool>) {
+        let fields = module.get(ptr, 8);
+        let value_ptr = u32::from_le_bytes([fields[0], fields[1], fields[2], fields[3]]);
+        let error_ptr = u32::from_le_bytes([fields[4], fields[5], fields[6], fields[7]]);
+        let value = match value_ptr {
+            0 => None,
+            ptr => Some(T::from_asc_bytes(
+                &module.get(ptr, std::mem::size_of::<T>() as u32),
+            )),
+        };
+        let error = match error_ptr {
+            0 => None,
+            ptr => {
+                let inner = module.get(ptr, 4);
+                let inner = u32::from_le_bytes([inner[0], inner[1], inner[2], inner[3]]);
+                Some(bool::from_asc_bytes(&module.get(inner, 1)))
+            }
+        };
+        (value, error)
+    }
+
+    let number = -9223372036850770800i64;
+    let json_ptr = module.asc_new(&number.to_string());
+    let result = module.instance_ctx_mut().json_try_to_i64(json_ptr);
+    assert_eq!(
+        read_result::<i64>(&module, result.wasm_ptr()),
+        (Some(number), None)
+    );
+
+    let number = 9223372036850770800u64;
+    let json_ptr = module.asc_new(&number.to_string());
+    let result = module.instance_ctx_mut().json_try_to_u64(json_ptr);
+    assert_eq!(
+        read_result::<u64>(&module, result.wasm_ptr()),
+        (Some(number), None)
+    );
+
+    let number = -9223372036850770.92345034f64;
+    let json_ptr = module.asc_new(&number.to_string());
+    let result = module.instance_ctx_mut().json_try_to_f64(json_ptr);
+    assert_eq!(
+        read_result::<f64>(&module, result.wasm_ptr()),
+        (Some(number), None)
+    );
+
+    let json_ptr = module.asc_new("not a number");
+    let result = module.instance_ctx_mut().json_try_to_i64(json_ptr);
+    assert_eq!(
+        read_result::<i64>(&module, result.wasm_ptr()),
+        (None, Some(true))
+    );
+}
+
 #[tokio::test]
 async fn json_parsing() {
     let mut module = test_module(
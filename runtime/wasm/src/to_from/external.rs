@@ -5,13 +5,13 @@ use graph::components::ethereum::{
     EthereumBlockData, EthereumCallData, EthereumEventData, EthereumTransactionData,
 };
 use graph::data::store;
-use graph::prelude::anyhow::{ensure, Error};
+use graph::prelude::anyhow::{anyhow, ensure, Error};
 use graph::prelude::serde_json;
 use graph::prelude::web3::types as web3;
 use graph::prelude::{BigDecimal, BigInt};
 
 use crate::asc_abi::class::*;
-use crate::asc_abi::{AscHeap, AscPtr, AscType, FromAscObj, ToAscObj, TryFromAscObj};
+use crate::asc_abi::{AscHeap, AscPtr, AscType, AscValue, FromAscObj, ToAscObj, TryFromAscObj};
 
 use crate::UnresolvedContractCall;
 
@@ -300,6 +300,43 @@ impl ToAscObj<AscEnum<JsonValueKind>> for serde_json::Value {
     }
 }
 
+impl TryFromAscObj<AscEnum<JsonValueKind>> for serde_json::Value {
+    fn try_from_asc_obj<H: AscHeap>(
+        asc_enum: AscEnum<JsonValueKind>,
+        heap: &H,
+    ) -> Result<Self, Error> {
+        use serde_json::Value;
+
+        let payload = asc_enum.payload;
+        Ok(match asc_enum.kind {
+            JsonValueKind::Null => Value::Null,
+            JsonValueKind::Bool => Value::Bool(bool::from(payload)),
+            JsonValueKind::Number => {
+                let ptr: AscPtr<AscString> = AscPtr::from(payload);
+                let number: String = heap.asc_get(ptr);
+                Value::Number(
+                    number
+                        .parse()
+                        .map_err(|_| anyhow!("`{}` is not a JSON number", number))?,
+                )
+            }
+            JsonValueKind::String => {
+                let ptr: AscPtr<AscString> = AscPtr::from(payload);
+                Value::String(heap.asc_get(ptr))
+            }
+            JsonValueKind::Array => {
+                let ptr: AscEnumArray<JsonValueKind> = AscPtr::from(payload);
+                Value::Array(heap.try_asc_get(ptr)?)
+            }
+            JsonValueKind::Object => {
+                let ptr: AscPtr<AscJson> = AscPtr::from(payload);
+                let object: HashMap<String, Value> = heap.try_asc_get(ptr)?;
+                Value::Object(object.into_iter().collect())
+            }
+        })
+    }
+}
+
 impl ToAscObj<AscEthereumBlock> for EthereumBlockData {
     fn to_asc_obj<H: AscHeap>(&self, heap: &mut H) -> AscEthereumBlock {
         AscEthereumBlock {
@@ -449,12 +486,60 @@ impl ToAscObj<bool> for bool {
     }
 }
 
+impl ToAscObj<i64> for i64 {
+    fn to_asc_obj<H: AscHeap>(&self, _heap: &mut H) -> i64 {
+        *self
+    }
+}
+
+impl ToAscObj<u64> for u64 {
+    fn to_asc_obj<H: AscHeap>(&self, _heap: &mut H) -> u64 {
+        *self
+    }
+}
+
+impl ToAscObj<f64> for f64 {
+    fn to_asc_obj<H: AscHeap>(&self, _heap: &mut H) -> f64 {
+        *self
+    }
+}
+
 impl<T: AscType> ToAscObj<AscWrapped<T>> for AscWrapped<T> {
     fn to_asc_obj<H: AscHeap>(&self, _heap: &mut H) -> AscWrapped<T> {
         *self
     }
 }
 
+impl<V: AscValue> ToAscObj<AscWrappedValue<V>> for AscWrappedValue<V> {
+    fn to_asc_obj<H: AscHeap>(&self, _heap: &mut H) -> AscWrappedValue<V> {
+        *self
+    }
+}
+
+impl<V, E, EAsc> ToAscObj<AscValueResult<V, EAsc>> for Result<V, E>
+where
+    V: AscValue,
+    E: ToAscObj<EAsc>,
+    EAsc: AscType,
+{
+    fn to_asc_obj<H: AscHeap>(&self, heap: &mut H) -> AscValueResult<V, EAsc> {
+        match self {
+            Ok(value) => AscValueResult {
+                value: heap.asc_new(&AscWrappedValue { inner: *value }),
+                error: AscPtr::null(),
+            },
+            Err(e) => AscValueResult {
+                value: AscPtr::null(),
+                error: {
+                    let inner = heap.asc_new(e);
+                    let wrapped = AscWrapped { inner };
+                    heap.asc_new(&wrapped)
+                },
+            },
+        }
+    }
+}
+
 impl<V, E, VAsc, EAsc> ToAscObj<AscResult<VAsc, EAsc>> for Result<V, E>
 where
     V: ToAscObj<VAsc>,
@@ -1,15 +1,35 @@
 use graph::prelude::serde_json;
+use graph::url::form_urlencoded;
 use graphql_parser;
+use graphql_parser::query as q;
 use hyper::body::Bytes;
 
 use graph::components::server::query::GraphQLServerError;
+use graph::data::graphql::persisted::PersistedQueries;
 use graph::prelude::*;
 
+lazy_static! {
+    /// The largest number of queries a client can send in one batch
+    static ref MAX_BATCH_SIZE: usize = std::env::var("GRAPH_GRAPHQL_MAX_BATCH_SIZE")
+        .unwrap_or("10".into())
+        .parse::<usize>()
+        .expect("invalid GRAPH_GRAPHQL_MAX_BATCH_SIZE");
+}
+
+/// The persisted queries a request can refer to, and the deployment ID and
+/// subgraph name whose allow-lists apply to the request
+#[derive(Clone)]
+struct Persisted {
+    queries: Arc<PersistedQueries>,
+    targets: Vec<String>,
+}
+
 /// Future for a query parsed from an HTTP request.
 pub struct GraphQLRequest {
     body: Bytes,
     schema: Arc<ApiSchema>,
     network: Option<String>,
+    persisted: Option<Persisted>,
 }
 
 impl GraphQLRequest {
@@ -19,8 +39,54 @@ impl GraphQLRequest {
             body,
             schema,
             network,
+            persisted: None,
         }
     }
+
+    /// Allow the request to refer to `queries` by their hash, and subject
+    /// it to the allow-lists of `targets`
+    pub fn with_persisted_queries(
+        mut self,
+        queries: Arc<PersistedQueries>,
+        targets: Vec<String>,
+    ) -> Self {
+        self.persisted = Some(Persisted { queries, targets });
+        self
+    }
+
+    /// Creates a new GraphQLRequest future from the query string of a `GET`
+    /// request. The query is passed in the `query` parameter, and variables
+    /// can be passed as a JSON object in the `variables` parameter.
+    pub fn from_url_query(
+        url_query: &str,
+        schema: Arc<ApiSchema>,
+        network: Option<String>,
+    ) -> Result<Self, GraphQLServerError> {
+        let mut obj = serde_json::Map::new();
+        for (key, value) in form_urlencoded::parse(url_query.as_bytes()) {
+            match key.as_ref() {
+                "query" => {
+                    obj.insert(
+                        key.into_owned(),
+                        serde_json::Value::String(value.into_owned()),
+                    );
+                }
+                "variables" | "extensions" => {
+                    let json = serde_json::from_str(&value).map_err(|e| {
+                        GraphQLServerError::ClientError(format!(
+                            "The \"{}\" parameter is not valid JSON: {}",
+                            key, e
+                        ))
+                    })?;
+                    obj.insert(key.into_owned(), json);
+                }
+                _ => (),
+            }
+        }
+        let body = serde_json::to_vec(&serde_json::Value::Object(obj))
+            .expect("serializing a JSON object does not fail");
+        Ok(Self::new(Bytes::from(body), schema, network))
+    }
 }
 
 impl Future for GraphQLRequest {
@@ -28,55 +94,312 @@ impl Future for GraphQLRequest {
     type Error = GraphQLServerError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        // Fail if no schema is available
-        let schema = self.schema.clone();
+        // Parse request body as JSON
+        let json: serde_json::Value = serde_json::from_slice(&self.body)
+            .map_err(|e| GraphQLServerError::ClientError(format!("{}", e)))?;
+
+        parse_query(
+            &json,
+            self.schema.clone(),
+            self.network.clone(),
+            self.persisted.as_ref(),
+        )
+        .map(Async::Ready)
+    }
+}
+
+/// Future for a batch of queries parsed from an HTTP request whose body is
+/// a JSON array of query objects. Queries that can not be parsed as GraphQL
+/// do not fail the whole batch, but any other problem with the request
+/// does.
+pub struct GraphQLBatchRequest {
+    body: Bytes,
+    schema: Arc<ApiSchema>,
+    network: Option<String>,
+    persisted: Option<Persisted>,
+}
 
+impl GraphQLBatchRequest {
+    pub fn new(body: Bytes, schema: Arc<ApiSchema>, network: Option<String>) -> Self {
+        GraphQLBatchRequest {
+            body,
+            schema,
+            network,
+            persisted: None,
+        }
+    }
+
+    /// Allow the queries in the batch to refer to `queries` by their hash,
+    /// and subject them to the allow-lists of `targets`
+    pub fn with_persisted_queries(
+        mut self,
+        queries: Arc<PersistedQueries>,
+        targets: Vec<String>,
+    ) -> Self {
+        self.persisted = Some(Persisted { queries, targets });
+        self
+    }
+
+    /// Whether the request `body` is a batch of queries rather than a
+    /// single query
+    pub fn is_batch(body: &Bytes) -> bool {
+        body.iter()
+            .find(|b| !b.is_ascii_whitespace())
+            .map_or(false, |b| *b == b'[')
+    }
+}
+
+impl Future for GraphQLBatchRequest {
+    type Item = Vec<Result<Query, QueryError>>;
+    type Error = GraphQLServerError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         // Parse request body as JSON
         let json: serde_json::Value = serde_json::from_slice(&self.body)
             .map_err(|e| GraphQLServerError::ClientError(format!("{}", e)))?;
 
-        // Ensure the JSON data is an object
-        let obj = json.as_object().ok_or_else(|| {
-            GraphQLServerError::ClientError(String::from("Request data is not an object"))
+        // Ensure the JSON data is a non-empty array
+        let batch = json.as_array().ok_or_else(|| {
+            GraphQLServerError::ClientError(String::from("Request data is not an array"))
         })?;
+        if batch.is_empty() {
+            return Err(GraphQLServerError::ClientError(String::from(
+                "Request data is an empty batch",
+            )));
+        }
+        // Turn oversized batches away before we spend any time on parsing
+        // their queries
+        if batch.len() > *MAX_BATCH_SIZE {
+            return Err(GraphQLServerError::ClientError(format!(
+                "The batch contains {} queries, but at most {} are allowed",
+                batch.len(),
+                *MAX_BATCH_SIZE
+            )));
+        }
+
+        let mut queries = Vec::with_capacity(batch.len());
+        for json in batch {
+            match parse_query(
+                json,
+                self.schema.clone(),
+                self.network.clone(),
+                self.persisted.as_ref(),
+            ) {
+                Ok(query) => queries.push(Ok(query)),
+                Err(GraphQLServerError::QueryError(e)) => queries.push(Err(e)),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Async::Ready(queries))
+    }
+}
 
-        // Ensure the JSON data has a "query" field
-        let query_value = obj.get("query").ok_or_else(|| {
+/// The hash of the persisted query that a query object refers to in its
+/// `extensions` field, if any
+fn persisted_query_hash(
+    obj: &serde_json::Map<String, serde_json::Value>,
+) -> Result<Option<String>, GraphQLServerError> {
+    let persisted = match obj
+        .get("extensions")
+        .and_then(|extensions| extensions.get("persistedQuery"))
+    {
+        None | Some(serde_json::Value::Null) => return Ok(None),
+        Some(persisted) => persisted,
+    };
+    if persisted
+        .get("version")
+        .and_then(|version| version.as_u64())
+        != Some(1)
+    {
+        return Err(GraphQLServerError::ClientError(String::from(
+            "Unsupported persisted query version",
+        )));
+    }
+    persisted
+        .get("sha256Hash")
+        .and_then(|hash| hash.as_str())
+        .map(|hash| Some(hash.to_owned()))
+        .ok_or_else(|| {
+            GraphQLServerError::ClientError(String::from(
+                "The \"sha256Hash\" of the persisted query is not a string",
+            ))
+        })
+}
+
+/// Parse one query object of the form `{ "query": ..., "variables": ...,
+/// "extensions": ... }`
+fn parse_query(
+    json: &serde_json::Value,
+    schema: Arc<ApiSchema>,
+    network: Option<String>,
+    persisted: Option<&Persisted>,
+) -> Result<Query, GraphQLServerError> {
+    // Ensure the JSON data is an object
+    let obj = json.as_object().ok_or_else(|| {
+        GraphQLServerError::ClientError(String::from("Request data is not an object"))
+    })?;
+
+    // Ensure the "query" field is a string if it is present
+    let query_string = match obj.get("query") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(serde_json::Value::String(query)) => Some(query.clone()),
+        Some(_) => {
+            return Err(GraphQLServerError::ClientError(String::from(
+                "The \"query\" field is not a string",
+            )))
+        }
+    };
+
+    // Find the text of the query; with persisted queries, the request might
+    // only contain the query's hash
+    let query_string = match persisted {
+        Some(persisted) => {
+            let targets: Vec<_> = persisted.targets.iter().map(String::as_str).collect();
+            persisted
+                .queries
+                .resolve(&targets, query_string, persisted_query_hash(obj)?)?
+        }
+        None => query_string.ok_or_else(|| {
             GraphQLServerError::ClientError(String::from(
                 "The \"query\" field is missing in request data",
             ))
-        })?;
+        })?,
+    };
 
-        // Ensure the "query" field is a string
-        let query_string = query_value.as_str().ok_or_else(|| {
-            GraphQLServerError::ClientError(String::from("The \"query\" field is not a string"))
-        })?;
+    // Parse the "query" field of the JSON body
+    let document = graphql_parser::parse_query(&query_string).map_err(|e| {
+        GraphQLServerError::from(QueryError::ParseError(Arc::new(e.compat().into())))
+    })?;
 
-        // Parse the "query" field of the JSON body
-        let document = graphql_parser::parse_query(query_string).map_err(|e| {
-            GraphQLServerError::from(QueryError::ParseError(Arc::new(e.compat().into())))
-        })?;
+    // Parse the "variables" field of the JSON body, if present
+    let variables = match obj.get("variables") {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(variables @ serde_json::Value::Object(_)) => serde_json::from_value(variables.clone())
+            .map_err(|e| GraphQLServerError::ClientError(e.to_string()))
+            .map(Some),
+        _ => Err(GraphQLServerError::ClientError(
+            "Invalid query variables provided".to_string(),
+        )),
+    }?;
 
-        // Parse the "variables" field of the JSON body, if present
-        let variables = match obj.get("variables") {
-            None | Some(serde_json::Value::Null) => Ok(None),
-            Some(variables @ serde_json::Value::Object(_)) => {
-                serde_json::from_value(variables.clone())
-                    .map_err(|e| GraphQLServerError::ClientError(e.to_string()))
-                    .map(Some)
+    Ok(Query::new(schema, document, variables, network))
+}
+
+/// Split a live `query` into the query whose results are sent to the client
+/// and the subscriptions that say when to run it again. The query can be
+/// written either as a query or as a subscription; mutations are rejected.
+/// Since a subscription can only select one field, there is a subscription
+/// for each field the query selects at the top level
+pub(crate) fn live_query(query: Query) -> Result<(Query, Vec<Subscription>), GraphQLServerError> {
+    let Query {
+        schema,
+        mut document,
+        variables,
+        network,
+        ..
+    } = query;
+
+    for def in document.definitions.iter_mut() {
+        let query = match def {
+            q::Definition::Operation(q::OperationDefinition::Subscription(op)) => q::Query {
+                position: op.position,
+                name: op.name.take(),
+                variable_definitions: std::mem::take(&mut op.variable_definitions),
+                directives: std::mem::take(&mut op.directives),
+                selection_set: op.selection_set.clone(),
+            },
+            q::Definition::Operation(q::OperationDefinition::Mutation(_)) => {
+                return Err(GraphQLServerError::ClientError(String::from(
+                    "Mutations can not be run as live queries",
+                )))
             }
-            _ => Err(GraphQLServerError::ClientError(
-                "Invalid query variables provided".to_string(),
-            )),
-        }?;
+            q::Definition::Operation(q::OperationDefinition::Query(_))
+            | q::Definition::Operation(q::OperationDefinition::SelectionSet(_))
+            | q::Definition::Fragment(_) => continue,
+        };
+        *def = q::Definition::Operation(q::OperationDefinition::Query(query));
+    }
 
-        Ok(Async::Ready(Query::new(
-            schema,
-            document,
-            variables,
-            self.network.clone(),
-        )))
+    let mut triggers = Vec::new();
+    for (i, def) in document.definitions.iter().enumerate() {
+        let op = match def {
+            q::Definition::Operation(q::OperationDefinition::Query(op)) => op.clone(),
+            q::Definition::Operation(q::OperationDefinition::SelectionSet(set)) => q::Query {
+                position: set.span.0,
+                name: None,
+                variable_definitions: vec![],
+                directives: vec![],
+                selection_set: set.clone(),
+            },
+            _ => continue,
+        };
+        for item in &op.selection_set.items {
+            let mut trigger = document.clone();
+            trigger.definitions[i] =
+                q::Definition::Operation(q::OperationDefinition::Subscription(q::Subscription {
+                    position: op.position,
+                    name: op.name.clone(),
+                    variable_definitions: op.variable_definitions.clone(),
+                    directives: op.directives.clone(),
+                    selection_set: q::SelectionSet {
+                        span: op.selection_set.span,
+                        items: vec![item.clone()],
+                    },
+                }));
+            triggers.push(Subscription {
+                query: Query::new(schema.clone(), trigger, variables.clone(), network.clone()),
+                diff: false,
+                client: None,
+            });
+        }
     }
+
+    Ok((Query::new(schema, document, variables, network), triggers))
+}
+
+/// Whether every field that `query` selects at the top level is queried at
+/// a fixed block hash, i.e., with a `block` argument that sets `hash`. The
+/// result of such a query never changes for a given deployment. A block
+/// `number` does not pin the query, since the block with that number can
+/// still be reorged away when it is close to the chain head.
+pub(crate) fn pins_block_hash(query: &Query) -> bool {
+    fn value<'a>(query: &'a Query, value: &'a q::Value) -> Option<&'a q::Value> {
+        match value {
+            q::Value::Variable(name) => query.variables.as_ref().and_then(|vars| vars.get(name)),
+            q::Value::Null => None,
+            value => Some(value),
+        }
+    }
+
+    fn field_pins_block(query: &Query, field: &q::Field) -> bool {
+        field
+            .arguments
+            .iter()
+            .filter(|(name, _)| name == "block")
+            .filter_map(|(_, block)| match value(query, block) {
+                Some(q::Value::Object(block)) => Some(block),
+                _ => None,
+            })
+            .any(|block| block.get("hash").and_then(|v| value(query, v)).is_some())
+    }
+
+    let mut fields = 0;
+    for def in &query.document.definitions {
+        let selection_set = match def {
+            q::Definition::Operation(q::OperationDefinition::Query(op)) => &op.selection_set,
+            q::Definition::Operation(q::OperationDefinition::SelectionSet(set)) => set,
+            q::Definition::Operation(_) => return false,
+            q::Definition::Fragment(_) => continue,
+        };
+        for selection in &selection_set.items {
+            match selection {
+                q::Selection::Field(field) if field.name == "__typename" => (),
+                q::Selection::Field(field) if field_pins_block(query, field) => fields += 1,
+                _ => return false,
+            }
+        }
+    }
+    fields > 0
 }
 
 #[cfg(test)]
@@ -86,9 +409,12 @@ mod tests {
     use hyper;
     use std::collections::{BTreeMap, HashMap};
 
+    use graph::components::server::query::GraphQLServerError;
+    use graph::data::graphql::persisted::{query_hash, AllowList, AllowLists, PersistedQueries};
     use graph::prelude::*;
+    use graph_mock::MockMetricsRegistry;
 
-    use super::GraphQLRequest;
+    use super::{live_query, pins_block_hash, GraphQLBatchRequest, GraphQLRequest, MAX_BATCH_SIZE};
 
     const EXAMPLE_SCHEMA: &'static str = "type Query @entity { users: [User!] }";
 
@@ -237,4 +563,309 @@ mod tests {
         assert_eq!(query.document, expected_query);
         assert_eq!(query.variables, Some(expected_variables));
     }
+
+    #[test]
+    fn parses_url_queries() {
+        let schema =
+            Schema::parse(EXAMPLE_SCHEMA, SubgraphDeploymentId::new("test").unwrap()).unwrap();
+        let request = GraphQLRequest::from_url_query(
+            "query=%7B%20user%20%7B%20name%20%7D%20%7D&variables=%7B%22int%22%3A5%7D",
+            Arc::new(ApiSchema::from_api_schema(schema).unwrap()),
+            None,
+        )
+        .unwrap();
+        let query = request.wait().expect("Should accept valid queries");
+
+        let expected_query = graphql_parser::parse_query("{ user { name } }").unwrap();
+        let expected_variables = QueryVariables::new(HashMap::from_iter(
+            vec![(String::from("int"), q::Value::Int(q::Number::from(5)))].into_iter(),
+        ));
+        assert_eq!(query.document, expected_query);
+        assert_eq!(query.variables, Some(expected_variables));
+    }
+
+    #[test]
+    fn rejects_url_queries_with_invalid_variables() {
+        let schema =
+            Schema::parse(EXAMPLE_SCHEMA, SubgraphDeploymentId::new("test").unwrap()).unwrap();
+        let schema = Arc::new(ApiSchema::from_api_schema(schema).unwrap());
+        GraphQLRequest::from_url_query(
+            "query=%7B%20user%20%7D&variables=%7B",
+            schema.clone(),
+            None,
+        )
+        .err()
+        .expect("Should reject variables that are not JSON");
+        GraphQLRequest::from_url_query("variables=%7B%7D", schema, None)
+            .unwrap()
+            .wait()
+            .expect_err("Should reject requests without a query");
+    }
+
+    #[test]
+    fn parses_batches() {
+        let schema =
+            Schema::parse(EXAMPLE_SCHEMA, SubgraphDeploymentId::new("test").unwrap()).unwrap();
+        let body =
+            hyper::body::Bytes::from(" [{\"query\": \"{ user { name } }\"}, {\"query\": \"foo\"}]");
+        assert!(GraphQLBatchRequest::is_batch(&body));
+        let queries = GraphQLBatchRequest::new(
+            body,
+            Arc::new(ApiSchema::from_api_schema(schema).unwrap()),
+            None,
+        )
+        .wait()
+        .expect("Should accept batches");
+
+        assert_eq!(2, queries.len());
+        assert_eq!(
+            queries[0].as_ref().unwrap().document,
+            graphql_parser::parse_query("{ user { name } }").unwrap()
+        );
+        // A broken query only fails itself, not the whole batch
+        assert!(queries[1].is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_batches() {
+        let schema =
+            Schema::parse(EXAMPLE_SCHEMA, SubgraphDeploymentId::new("test").unwrap()).unwrap();
+        let schema = Arc::new(ApiSchema::from_api_schema(schema).unwrap());
+
+        assert!(!GraphQLBatchRequest::is_batch(&hyper::body::Bytes::from(
+            "{\"query\": \"{ user }\"}"
+        )));
+        for body in &["[]", "[{\"query\": \"{ user }\"}, 5]", "[{}]"] {
+            GraphQLBatchRequest::new(hyper::body::Bytes::from(*body), schema.clone(), None)
+                .wait()
+                .expect_err("Should reject malformed batches");
+        }
+
+        let too_large = format!(
+            "[{}]",
+            vec!["{\"query\": \"{ user }\"}"; *MAX_BATCH_SIZE + 1].join(",")
+        );
+        let err = GraphQLBatchRequest::new(hyper::body::Bytes::from(too_large), schema, None)
+            .wait()
+            .expect_err("Should reject batches that are too large");
+        assert!(err.to_string().contains("at most"));
+    }
+
+    #[test]
+    fn detects_pinned_queries() {
+        let schema =
+            Schema::parse(EXAMPLE_SCHEMA, SubgraphDeploymentId::new("test").unwrap()).unwrap();
+        let schema = Arc::new(ApiSchema::from_api_schema(schema).unwrap());
+        let pinned = |query: &str, variables: &str| {
+            let body = format!("{{\"query\": {:?}, \"variables\": {}}}", query, variables);
+            let query = GraphQLRequest::new(hyper::body::Bytes::from(body), schema.clone(), None)
+                .wait()
+                .unwrap();
+            pins_block_hash(&query)
+        };
+
+        assert!(pinned(
+            "{ users(block: { hash: \"0xab\" }) { id } }",
+            "null"
+        ));
+        assert!(pinned(
+            "{ a: users(block: { hash: \"0xab\" }) { id } __typename }",
+            "null"
+        ));
+        assert!(pinned(
+            "query($b: Block_height) { users(block: $b) { id } }",
+            "{\"b\": {\"hash\": \"0xab\"}}"
+        ));
+        assert!(pinned(
+            "query($h: Bytes) { users(block: { hash: $h }) { id } }",
+            "{\"h\": \"0xab\"}"
+        ));
+
+        assert!(!pinned("{ users { id } }", "null"));
+        // Blocks near the chain head can be reorged away
+        assert!(!pinned("{ users(block: { number: 5 }) { id } }", "null"));
+        assert!(!pinned(
+            "query($b: Block_height) { users(block: $b) { id } }",
+            "{\"b\": {\"number\": 5}}"
+        ));
+        assert!(!pinned(
+            "{ a: users(block: { hash: \"0xab\" }) { id } b: users { id } }",
+            "null"
+        ));
+        assert!(!pinned(
+            "query($h: Bytes) { users(block: { hash: $h }) { id } }",
+            "null"
+        ));
+        assert!(!pinned("{ __typename }", "null"));
+    }
+
+    #[test]
+    fn splits_live_queries() {
+        let schema =
+            Schema::parse(EXAMPLE_SCHEMA, SubgraphDeploymentId::new("test").unwrap()).unwrap();
+        let schema = Arc::new(ApiSchema::from_api_schema(schema).unwrap());
+        let live = |query: &str| {
+            let body = format!("{{\"query\": {:?}}}", query);
+            let query = GraphQLRequest::new(hyper::body::Bytes::from(body), schema.clone(), None)
+                .wait()
+                .unwrap();
+            live_query(query)
+        };
+        let is_query = |query: &Query| {
+            query.document.definitions.iter().all(|def| match def {
+                q::Definition::Operation(q::OperationDefinition::Query(_))
+                | q::Definition::Operation(q::OperationDefinition::SelectionSet(_))
+                | q::Definition::Fragment(_) => true,
+                _ => false,
+            })
+        };
+        // Each trigger is a subscription of one top-level field
+        let are_triggers = |triggers: &[Subscription]| {
+            triggers.iter().all(|trigger| {
+                trigger
+                    .query
+                    .document
+                    .definitions
+                    .iter()
+                    .all(|def| match def {
+                        q::Definition::Operation(q::OperationDefinition::Subscription(op)) => {
+                            op.selection_set.items.len() == 1
+                        }
+                        q::Definition::Fragment(_) => true,
+                        _ => false,
+                    })
+            })
+        };
+
+        let (query, triggers) = live("{ users { id } }").unwrap();
+        assert!(is_query(&query));
+        assert_eq!(triggers.len(), 1);
+        assert!(are_triggers(&triggers));
+
+        let (query, triggers) = live(
+            "query Users($n: Int) { all: users { ...U } some: users(first: $n) { ...U } } \
+             fragment U on User { id }",
+        )
+        .unwrap();
+        assert!(is_query(&query));
+        assert_eq!(triggers.len(), 2);
+        assert!(are_triggers(&triggers));
+
+        let (query, triggers) = live("subscription { users { id } }").unwrap();
+        assert!(is_query(&query));
+        assert_eq!(triggers.len(), 1);
+        assert!(are_triggers(&triggers));
+
+        match live("mutation { users { id } }") {
+            Err(GraphQLServerError::ClientError(_)) => (),
+            _ => panic!("expected mutations to be rejected"),
+        }
+    }
+
+    fn persisted_queries(allow_lists: AllowLists) -> Arc<PersistedQueries> {
+        let logger = Logger::root(slog::Discard, o!());
+        let registry = Arc::new(MockMetricsRegistry::new());
+        Arc::new(PersistedQueries::new(&logger, registry, allow_lists).unwrap())
+    }
+
+    fn persisted_request(
+        persisted: &Arc<PersistedQueries>,
+        body: serde_json::Value,
+    ) -> Result<Query, GraphQLServerError> {
+        let schema =
+            Schema::parse(EXAMPLE_SCHEMA, SubgraphDeploymentId::new("test").unwrap()).unwrap();
+        GraphQLRequest::new(
+            hyper::body::Bytes::from(body.to_string()),
+            Arc::new(ApiSchema::from_api_schema(schema).unwrap()),
+            None,
+        )
+        .with_persisted_queries(
+            persisted.clone(),
+            vec!["users".to_owned(), "test".to_owned()],
+        )
+        .wait()
+    }
+
+    #[test]
+    fn resolves_persisted_queries() {
+        let query = "{ users { name } }";
+        let hash = query_hash(query);
+        let persisted = persisted_queries(AllowLists::new());
+        let extensions = serde_json::json!({
+            "persistedQuery": { "version": 1, "sha256Hash": hash }
+        });
+
+        // The hash alone is not enough until the client has sent the query
+        let err = persisted_request(&persisted, serde_json::json!({ "extensions": extensions }))
+            .expect_err("Should not know the query yet");
+        assert!(matches!(
+            err,
+            GraphQLServerError::QueryError(QueryError::PersistedQueryNotFound)
+        ));
+
+        // Sending the query with a hash that does not match fails
+        persisted_request(
+            &persisted,
+            serde_json::json!({ "query": "{ users { id } }", "extensions": extensions }),
+        )
+        .expect_err("Should reject queries that do not match their hash");
+
+        persisted_request(
+            &persisted,
+            serde_json::json!({ "query": query, "extensions": extensions }),
+        )
+        .expect("Should register the query");
+        let parsed = persisted_request(&persisted, serde_json::json!({ "extensions": extensions }))
+            .expect("Should find the registered query");
+        assert_eq!(parsed.document, graphql_parser::parse_query(query).unwrap());
+    }
+
+    #[test]
+    fn rejects_queries_not_on_allow_list() {
+        let allowed = "{ users { name } }";
+        let mut allow_lists = AllowLists::new();
+        allow_lists.insert(
+            "users".to_owned(),
+            AllowList {
+                allow_list_only: true,
+                queries: vec![allowed.to_owned()],
+            },
+        );
+        let persisted = persisted_queries(allow_lists);
+
+        persisted_request(&persisted, serde_json::json!({ "query": allowed }))
+            .expect("Should accept allowed queries");
+        let parsed = persisted_request(
+            &persisted,
+            serde_json::json!({
+                "extensions": {
+                    "persistedQuery": { "version": 1, "sha256Hash": query_hash(allowed) }
+                }
+            }),
+        )
+        .expect("Should accept allowed queries by their hash");
+        assert_eq!(
+            parsed.document,
+            graphql_parser::parse_query(allowed).unwrap()
+        );
+
+        let err = persisted_request(
+            &persisted,
+            serde_json::json!({ "query": "{ users { id } }" }),
+        )
+        .expect_err("Should reject queries that are not allowed");
+        assert!(matches!(
+            err,
+            GraphQLServerError::QueryError(QueryError::QueryNotAllowed(hash))
+                if hash == query_hash("{ users { id } }")
+        ));
+
+        // Turning the allow-list-only mode off lets every query through
+        persisted.set_allow_list_only("users", false).unwrap();
+        persisted_request(
+            &persisted,
+            serde_json::json!({ "query": "{ users { id } }" }),
+        )
+        .expect("Should accept all queries");
+    }
 }
@@ -1,4 +1,4 @@
-use std::convert::TryFrom;
+use std::convert::{Infallible, TryFrom};
 use std::fmt;
 use std::ops::Deref;
 use std::pin::Pin;
@@ -7,13 +7,47 @@ use std::task::Poll;
 use std::time::Instant;
 
 use graph::components::server::query::GraphQLServerError;
+use graph::data::graphql::api_keys::{ApiClient, ApiKeys};
+use graph::data::graphql::persisted::PersistedQueries;
 use graph::data::subgraph::schema::{SubgraphEntity, SUBGRAPHS_ID};
 use graph::prelude::*;
+use graph::url::form_urlencoded;
 use http::header;
 use hyper::service::Service;
 use hyper::{Body, Method, Request, Response, StatusCode};
 
-use crate::request::GraphQLRequest;
+use crate::request::{live_query, pins_block_hash, GraphQLBatchRequest, GraphQLRequest};
+
+lazy_static! {
+    /// How long, in seconds, clients and proxies may cache the response to
+    /// a query that is pinned to a block hash
+    static ref PINNED_QUERY_MAX_AGE: u64 = std::env::var("GRAPH_GRAPHQL_PINNED_QUERY_MAX_AGE")
+        .unwrap_or("3600".into())
+        .parse::<u64>()
+        .expect("invalid GRAPH_GRAPHQL_PINNED_QUERY_MAX_AGE");
+}
+
+/// Format `result` as a server-sent event whose id is the `block` the
+/// subgraph was at when the result was sent
+fn server_sent_event(block: Option<BlockNumber>, result: &QueryResult) -> String {
+    let data = serde_json::to_string(result).expect("Failed to serialize GraphQL response to JSON");
+    match block {
+        Some(block) => format!("id: {}\ndata: {}\n\n", block, data),
+        None => format!("data: {}\n\n", data),
+    }
+}
+
+/// The `Cache-Control` header for a response. Only successful responses to
+/// queries of a deployment by its id that are pinned to a block hash can be
+/// cached. Everything else has to be revalidated since new blocks, reorgs,
+/// or deploying a new version under the same name change the result
+fn cache_control(cacheable: bool) -> String {
+    if cacheable {
+        format!("public, max-age={}", *PINNED_QUERY_MAX_AGE)
+    } else {
+        "no-cache".to_owned()
+    }
+}
 
 pub struct GraphQLServiceMetrics {
     query_execution_time: Box<HistogramVec>,
@@ -77,6 +111,8 @@ pub struct GraphQLService<Q, S> {
     metrics: Arc<GraphQLServiceMetrics>,
     graphql_runner: Arc<Q>,
     store: Arc<S>,
+    persisted_queries: Arc<PersistedQueries>,
+    api_keys: Arc<ApiKeys>,
     ws_port: u16,
     node_id: NodeId,
 }
@@ -88,6 +124,8 @@ impl<Q, S> Clone for GraphQLService<Q, S> {
             metrics: self.metrics.clone(),
             graphql_runner: self.graphql_runner.clone(),
             store: self.store.clone(),
+            persisted_queries: self.persisted_queries.clone(),
+            api_keys: self.api_keys.clone(),
             ws_port: self.ws_port,
             node_id: self.node_id.clone(),
         }
@@ -105,6 +143,8 @@ where
         metrics: Arc<GraphQLServiceMetrics>,
         graphql_runner: Arc<Q>,
         store: Arc<S>,
+        persisted_queries: Arc<PersistedQueries>,
+        api_keys: Arc<ApiKeys>,
         ws_port: u16,
         node_id: NodeId,
     ) -> Self {
@@ -113,6 +153,8 @@ where
             metrics,
             graphql_runner,
             store,
+            persisted_queries,
+            api_keys,
             ws_port,
             node_id,
         }
@@ -183,18 +225,19 @@ where
         self.serve_dynamic_file(self.graphiql_html())
     }
 
-    async fn handle_graphql_query_by_name(
-        self,
+    /// Look up the deployment that `subgraph_name` currently points to
+    async fn resolve_subgraph_name(
+        &self,
         subgraph_name: String,
-        request: Request<Body>,
-    ) -> GraphQLServiceResult {
+    ) -> Result<(SubgraphDeploymentId, SubgraphName), GraphQLServerError> {
         let subgraph_name = SubgraphName::new(subgraph_name.as_str()).map_err(|()| {
             GraphQLServerError::ClientError(format!("Invalid subgraph name {:?}", subgraph_name))
         })?;
 
         let store = self.store.cheap_clone();
+        let name = subgraph_name.clone();
         let subgraph_id =
-            tokio::task::spawn_blocking(move || store.resolve_subgraph_name_to_id(subgraph_name))
+            tokio::task::spawn_blocking(move || store.resolve_subgraph_name_to_id(name))
                 .await
                 .unwrap() // Propagate panics.
                 .map_err(|e| {
@@ -209,7 +252,16 @@ where
                     ))
                 })?;
 
-        self.handle_graphql_query(subgraph_id, request.into_body())
+        Ok((subgraph_id, subgraph_name))
+    }
+
+    async fn handle_graphql_query_by_name(
+        self,
+        subgraph_name: String,
+        request: Request<Body>,
+    ) -> GraphQLServiceResult {
+        let (subgraph_id, subgraph_name) = self.resolve_subgraph_name(subgraph_name).await?;
+        self.handle_graphql_query(subgraph_id, Some(subgraph_name), request)
             .await
     }
 
@@ -220,20 +272,16 @@ where
     ) -> GraphQLServiceResponse {
         match SubgraphDeploymentId::new(id) {
             Err(()) => self.handle_not_found(),
-            Ok(id) => self.handle_graphql_query(id, request.into_body()).boxed(),
+            Ok(id) => self.handle_graphql_query(id, None, request).boxed(),
         }
     }
 
-    async fn handle_graphql_query(
-        self,
-        id: SubgraphDeploymentId,
-        request_body: Body,
-    ) -> GraphQLServiceResult {
-        let service = self.clone();
-        let service_metrics = self.metrics.clone();
-        let sd_id = id.clone();
-
-        match self.store.is_deployed(&id) {
+    /// The API schema and network of the deployment `id`
+    fn deployment(
+        &self,
+        id: &SubgraphDeploymentId,
+    ) -> Result<(Arc<ApiSchema>, Option<String>), GraphQLServerError> {
+        match self.store.is_deployed(id) {
             Err(e) => {
                 return Err(GraphQLServerError::InternalError(e.to_string()));
             }
@@ -246,36 +294,285 @@ where
             Ok(true) => (),
         }
 
-        let schema = match self.store.api_schema(&id) {
+        let schema = match self.store.api_schema(id) {
             Ok(schema) => schema,
             Err(e) => {
                 return Err(GraphQLServerError::InternalError(e.to_string()));
             }
         };
 
-        let network = match self.store.network_name(&id) {
+        let network = match self.store.network_name(id) {
             Ok(network) => network,
             Err(e) => {
                 return Err(GraphQLServerError::InternalError(e.to_string()));
             }
         };
 
+        Ok((schema, network))
+    }
+
+    /// The allow-lists of both the deployment and the name it was
+    /// requested by apply to a request
+    fn allow_list_targets(id: &SubgraphDeploymentId, name: Option<SubgraphName>) -> Vec<String> {
+        name.map(|name| name.to_string())
+            .into_iter()
+            .chain(std::iter::once(id.to_string()))
+            .collect()
+    }
+
+    async fn handle_graphql_query(
+        self,
+        id: SubgraphDeploymentId,
+        name: Option<SubgraphName>,
+        request: Request<Body>,
+    ) -> GraphQLServiceResult {
+        let service_metrics = self.metrics.clone();
+        let sd_id = id.clone();
+
+        let client = self.api_keys.check(request.headers())?;
+        let (schema, network) = self.deployment(&id)?;
+        let persisted_queries = self.persisted_queries.clone();
+        let by_id = name.is_none();
+        let targets = Self::allow_list_targets(&id, name);
+
         let start = Instant::now();
-        let body = hyper::body::to_bytes(request_body)
-            .map_err(|_| GraphQLServerError::InternalError("Failed to read request body".into()))
+        let mut batch = false;
+        let response = if request.method() == Method::GET {
+            let url_query = request.uri().query().unwrap_or("");
+            let query = GraphQLRequest::from_url_query(url_query, schema, network)?
+                .with_persisted_queries(persisted_queries, targets)
+                .compat()
+                .await;
+            self.run_query(query, by_id).await?
+        } else {
+            let body = hyper::body::to_bytes(request.into_body())
+                .map_err(|_| {
+                    GraphQLServerError::InternalError("Failed to read request body".into())
+                })
+                .await?;
+            if GraphQLBatchRequest::is_batch(&body) {
+                let queries = GraphQLBatchRequest::new(body, schema, network)
+                    .with_persisted_queries(persisted_queries, targets)
+                    .compat()
+                    .await?;
+                // Each query in the batch counts as one request; the first
+                // one was already counted when the request came in
+                client.check_requests(queries.len() - 1)?;
+                batch = true;
+                self.run_batch(queries, by_id, &client).await?
+            } else {
+                let query = GraphQLRequest::new(body, schema, network)
+                    .with_persisted_queries(persisted_queries, targets)
+                    .compat()
+                    .await;
+                self.run_query(query, by_id).await?
+            }
+        };
+
+        let elapsed = start.elapsed();
+        // `run_batch` records the effort of each query in a batch
+        if !batch {
+            client.add_effort(elapsed);
+        }
+        service_metrics
+            .observe_query_execution_time(elapsed.as_secs_f64(), sd_id.deref().to_string());
+
+        Ok(response)
+    }
+
+    async fn handle_live_query_by_name(
+        self,
+        subgraph_name: String,
+        request: Request<Body>,
+    ) -> GraphQLServiceResult {
+        let (subgraph_id, subgraph_name) = self.resolve_subgraph_name(subgraph_name).await?;
+        self.handle_live_query(subgraph_id, Some(subgraph_name), request)
+            .await
+    }
+
+    fn handle_live_query_by_id(self, id: String, request: Request<Body>) -> GraphQLServiceResponse {
+        match SubgraphDeploymentId::new(id) {
+            Err(()) => self.handle_not_found(),
+            Ok(id) => self.handle_live_query(id, None, request).boxed(),
+        }
+    }
+
+    /// Serve the query in the URL as a stream of server-sent events. The
+    /// query is run once, and then again whenever the entities it touches
+    /// change. The id of each event is the block the subgraph was at before
+    /// the query was run; the result is never older than that block. A
+    /// result is only sent once per block, and a client that reconnects
+    /// with an id in the `Last-Event-ID` header does not get sent a result
+    /// for that block again
+    async fn handle_live_query(
+        self,
+        id: SubgraphDeploymentId,
+        name: Option<SubgraphName>,
+        request: Request<Body>,
+    ) -> GraphQLServiceResult {
+        let client = self.api_keys.check(request.headers())?;
+        let permit = client.subscribe()?;
+        let (schema, network) = self.deployment(&id)?;
+        let targets = Self::allow_list_targets(&id, name);
+
+        let last_block = match request.headers().get("Last-Event-ID") {
+            None => None,
+            Some(value) => Some(
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.trim().parse::<BlockNumber>().ok())
+                    .ok_or_else(|| {
+                        GraphQLServerError::ClientError(String::from(
+                            "The Last-Event-ID header is not a block number",
+                        ))
+                    })?,
+            ),
+        };
+
+        let url_query = request.uri().query().unwrap_or("");
+        let query = GraphQLRequest::from_url_query(url_query, schema, network)?
+            .with_persisted_queries(self.persisted_queries.clone(), targets)
+            .compat()
             .await?;
-        let query = GraphQLRequest::new(body, schema, network).compat().await;
+        let (query, subscriptions) = live_query(query)?;
+
+        // The results of the subscriptions only tell us when to run the
+        // query again
+        let mut triggers = Vec::new();
+        for mut subscription in subscriptions {
+            subscription.client = Some(client.clone());
+            match self
+                .graphql_runner
+                .clone()
+                .run_subscription(subscription)
+                .await
+            {
+                Ok(results) => triggers.push(results),
+                Err(SubscriptionError::GraphQLError(errors)) => {
+                    return Ok(QueryResult::from(errors).as_http_response())
+                }
+            }
+        }
 
-        let result = match query {
-            Ok(query) => service.graphql_runner.run_query(query).await,
-            Err(GraphQLServerError::QueryError(e)) => Arc::new(QueryResult::from(e)),
+        let store = self.store.clone();
+        let graphql_runner = self.graphql_runner.clone();
+        let mut last_block = last_block;
+        let events = futures03::stream::select_all(triggers)
+            .then(move |_| {
+                let store = store.clone();
+                let id = id.clone();
+                async move {
+                    tokio::task::spawn_blocking(move || store.block_ptr(id))
+                        .await
+                        .unwrap() // Propagate panics.
+                        .ok()
+                        .flatten()
+                        .map(|ptr| ptr.number as BlockNumber)
+                }
+            })
+            .filter_map(move |block| {
+                // Every subscription triggers a run when a block touches
+                // what it selects; one run per block is enough, so we skip
+                // triggers for a block we already ran the query for
+                let run = if block.is_some() && block == last_block {
+                    None
+                } else {
+                    last_block = block;
+                    Some(block)
+                };
+                futures03::future::ready(run)
+            })
+            .then(move |block| {
+                // The live query counts against the client's subscriptions
+                // for as long as the response is open
+                let _ = &permit;
+                let graphql_runner = graphql_runner.clone();
+                let query = query.clone();
+                let client = client.clone();
+                async move {
+                    let start = Instant::now();
+                    let result = graphql_runner.run_query(query).await;
+                    client.add_effort(start.elapsed());
+                    Ok::<_, Infallible>(server_sent_event(block, &result))
+                }
+            });
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Content-Type", "text/event-stream")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(Body::wrap_stream(events))
+            .unwrap())
+    }
+
+    /// Run one query and return its result, and whether the result may be
+    /// cached. Only queries of a deployment by its id can be cached, since
+    /// a subgraph name can point to a different deployment at any time
+    async fn run(&self, query: Result<Query, QueryError>, by_id: bool) -> (Arc<QueryResult>, bool) {
+        match query {
+            Ok(query) => {
+                let pinned = by_id && pins_block_hash(&query);
+                let result = self.graphql_runner.clone().run_query(query).await;
+                let cacheable = pinned && !result.has_errors();
+                (result, cacheable)
+            }
+            Err(e) => (Arc::new(QueryResult::from(e)), false),
+        }
+    }
+
+    async fn run_query(
+        &self,
+        query: Result<Query, GraphQLServerError>,
+        by_id: bool,
+    ) -> GraphQLServiceResult {
+        let query = match query {
+            Ok(query) => Ok(query),
+            Err(GraphQLServerError::QueryError(e)) => Err(e),
             Err(e) => return Err(e),
         };
+        let (result, cacheable) = self.run(query, by_id).await;
 
-        service_metrics
-            .observe_query_execution_time(start.elapsed().as_secs_f64(), sd_id.deref().to_string());
+        let mut response: Response<Body> = result.as_http_response();
+        response.headers_mut().insert(
+            header::CACHE_CONTROL,
+            header::HeaderValue::from_str(&cache_control(cacheable)).unwrap(),
+        );
+        Ok(response)
+    }
 
-        Ok(result.as_http_response())
+    /// Run a batch of queries concurrently. The response is a JSON array
+    /// with the result of each query in the order of the queries in the
+    /// request. The time each query takes counts towards the effort of
+    /// `client` separately
+    async fn run_batch(
+        &self,
+        queries: Vec<Result<Query, QueryError>>,
+        by_id: bool,
+        client: &ApiClient,
+    ) -> GraphQLServiceResult {
+        let outcomes = futures03::future::join_all(queries.into_iter().map(|query| async move {
+            let start = Instant::now();
+            let outcome = self.run(query, by_id).await;
+            client.add_effort(start.elapsed());
+            outcome
+        }))
+        .await;
+        let cacheable = outcomes.iter().all(|(_, cacheable)| *cacheable);
+        let results: Vec<_> = outcomes.iter().map(|(result, _)| result.as_ref()).collect();
+        let json =
+            serde_json::to_string(&results).expect("Failed to serialize GraphQL response to JSON");
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Headers", "Content-Type, User-Agent")
+            .header("Access-Control-Allow-Methods", "GET, OPTIONS, POST")
+            .header("Content-Type", "application/json")
+            .header(header::CACHE_CONTROL, cache_control(cacheable))
+            .body(Body::from(json))
+            .unwrap())
     }
 
     // Handles OPTIONS requests
@@ -331,7 +628,59 @@ where
             segments.collect::<Vec<_>>()
         };
 
+        // A `GET` request with a `query` parameter runs the query just like
+        // a `POST` request does
+        let is_query = method == Method::POST
+            || (method == Method::GET
+                && req.uri().query().map_or(false, |query| {
+                    form_urlencoded::parse(query.as_bytes()).any(|(key, _)| key == "query")
+                }));
+
         match (method, path_segments.as_slice()) {
+            (Method::GET, &["subgraphs", "live", "id", subgraph_id]) => {
+                self.handle_live_query_by_id(subgraph_id.to_owned(), req)
+            }
+            (Method::GET, &["subgraphs", "live", "name", subgraph_name]) => self
+                .handle_live_query_by_name(subgraph_name.to_owned(), req)
+                .boxed(),
+            (
+                Method::GET,
+                ["subgraphs", "live", "name", subgraph_name_part1, subgraph_name_part2],
+            ) => {
+                let subgraph_name = format!("{}/{}", subgraph_name_part1, subgraph_name_part2);
+                self.handle_live_query_by_name(subgraph_name, req).boxed()
+            }
+            (
+                Method::GET,
+                ["subgraphs", "live", "network", subgraph_name_part1, subgraph_name_part2],
+            ) => {
+                let subgraph_name =
+                    format!("network/{}/{}", subgraph_name_part1, subgraph_name_part2);
+                self.handle_live_query_by_name(subgraph_name, req).boxed()
+            }
+
+            (_, &["subgraphs", "id", subgraph_id]) if is_query => {
+                self.handle_graphql_query_by_id(subgraph_id.to_owned(), req)
+            }
+            (_, &["subgraphs", "name", subgraph_name]) if is_query => self
+                .handle_graphql_query_by_name(subgraph_name.to_owned(), req)
+                .boxed(),
+            (_, ["subgraphs", "name", subgraph_name_part1, subgraph_name_part2]) if is_query => {
+                let subgraph_name = format!("{}/{}", subgraph_name_part1, subgraph_name_part2);
+                self.handle_graphql_query_by_name(subgraph_name, req)
+                    .boxed()
+            }
+            (_, ["subgraphs", "network", subgraph_name_part1, subgraph_name_part2]) if is_query => {
+                let subgraph_name =
+                    format!("network/{}/{}", subgraph_name_part1, subgraph_name_part2);
+                self.handle_graphql_query_by_name(subgraph_name, req)
+                    .boxed()
+            }
+            // `/subgraphs` acts as an alias to `/subgraphs/id/SUBGRAPHS_ID`
+            (_, &["subgraphs"]) if is_query => {
+                self.handle_graphql_query_by_id(SUBGRAPHS_ID.to_string(), req)
+            }
+
             (Method::GET, [""]) => self.index().boxed(),
             (Method::GET, ["graphiql.css"]) => {
                 self.serve_file(include_str!("../assets/graphiql.css"))
@@ -355,33 +704,11 @@ where
                 self.handle_temp_redirect(dest).boxed()
             }
 
-            (Method::POST, &["subgraphs", "id", subgraph_id]) => {
-                self.handle_graphql_query_by_id(subgraph_id.to_owned(), req)
-            }
-            (Method::OPTIONS, ["subgraphs", "id", _]) => self.handle_graphql_options(req),
-            (Method::POST, &["subgraphs", "name", subgraph_name]) => self
-                .handle_graphql_query_by_name(subgraph_name.to_owned(), req)
-                .boxed(),
-            (Method::POST, ["subgraphs", "name", subgraph_name_part1, subgraph_name_part2]) => {
-                let subgraph_name = format!("{}/{}", subgraph_name_part1, subgraph_name_part2);
-                self.handle_graphql_query_by_name(subgraph_name, req)
-                    .boxed()
-            }
-            (Method::POST, ["subgraphs", "network", subgraph_name_part1, subgraph_name_part2]) => {
-                let subgraph_name =
-                    format!("network/{}/{}", subgraph_name_part1, subgraph_name_part2);
-                self.handle_graphql_query_by_name(subgraph_name, req)
-                    .boxed()
-            }
-
-            (Method::OPTIONS, ["subgraphs", "name", _])
+            (Method::OPTIONS, ["subgraphs", "id", _])
+            | (Method::OPTIONS, ["subgraphs", "name", _])
             | (Method::OPTIONS, ["subgraphs", "name", _, _])
             | (Method::OPTIONS, ["subgraphs", "network", _, _]) => self.handle_graphql_options(req),
 
-            // `/subgraphs` acts as an alias to `/subgraphs/id/SUBGRAPHS_ID`
-            (Method::POST, &["subgraphs"]) => {
-                self.handle_graphql_query_by_id(SUBGRAPHS_ID.to_string(), req)
-            }
             (Method::OPTIONS, ["subgraphs"]) => self.handle_graphql_options(req),
 
             _ => self.handle_not_found(),
@@ -417,6 +744,17 @@ where
                     .header("Content-Type", "text/plain")
                     .body(Body::from(err.to_string()))
                     .unwrap()),
+                Err(err @ GraphQLServerError::Unauthorized(_)) => Ok(Response::builder()
+                    .status(401)
+                    .header("Content-Type", "text/plain")
+                    .header(header::WWW_AUTHENTICATE, "Bearer")
+                    .body(Body::from(err.to_string()))
+                    .unwrap()),
+                Err(err @ GraphQLServerError::TooManyRequests(_)) => Ok(Response::builder()
+                    .status(429)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from(err.to_string()))
+                    .unwrap()),
                 Err(err @ GraphQLServerError::QueryError(_)) => {
                     error!(logger, "GraphQLService call failed: {}", err);
 
@@ -446,19 +784,27 @@ mod tests {
     use hyper::service::Service;
     use hyper::{Body, Method, Request};
     use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
+    use graph::data::graphql::api_keys::{ApiKeyConfig, ApiKeyConfigs, ApiKeys};
     use graph::data::graphql::effort::LoadManager;
+    use graph::data::graphql::persisted::{AllowLists, PersistedQueries};
     use graph::prelude::*;
     use graph_mock::{mock_store_with_users_subgraph, MockMetricsRegistry};
     use graphql_parser::query as q;
 
     use crate::test_utils;
 
+    use super::server_sent_event;
     use super::GraphQLService;
     use super::GraphQLServiceMetrics;
 
-    /// A simple stupid query runner for testing.
-    pub struct TestGraphQlRunner;
+    /// A simple stupid query runner for testing. It counts the queries it
+    /// runs.
+    #[derive(Default)]
+    pub struct TestGraphQlRunner {
+        queries: AtomicUsize,
+    }
 
     #[async_trait]
     impl GraphQlRunner for TestGraphQlRunner {
@@ -474,6 +820,7 @@ mod tests {
         }
 
         async fn run_query(self: Arc<Self>, _query: Query) -> Arc<QueryResult> {
+            self.queries.fetch_add(1, Ordering::SeqCst);
             Arc::new(QueryResult::new(Some(q::Value::Object(
                 BTreeMap::from_iter(
                     vec![(
@@ -489,7 +836,9 @@ mod tests {
             self: Arc<Self>,
             _subscription: Subscription,
         ) -> Result<SubscriptionResult, SubscriptionError> {
-            unreachable!();
+            // Every subscription produces one result and then ends
+            let result = Arc::new(QueryResult::new(None));
+            Ok(Box::new(futures03::stream::iter(vec![result])))
         }
 
         fn load_manager(&self) -> Arc<LoadManager> {
@@ -501,13 +850,29 @@ mod tests {
     fn posting_invalid_query_yields_error_response() {
         let logger = Logger::root(slog::Discard, o!());
         let metrics_registry = Arc::new(MockMetricsRegistry::new());
-        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry));
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry.clone()));
+        let persisted_queries = Arc::new(
+            PersistedQueries::new(&logger, metrics_registry.clone(), AllowLists::new()).unwrap(),
+        );
+        let api_keys = Arc::new(ApiKeys::new(
+            &logger,
+            metrics_registry,
+            ApiKeyConfigs::new(),
+        ));
         let (store, subgraph_id) = mock_store_with_users_subgraph();
-        let graphql_runner = Arc::new(TestGraphQlRunner);
+        let graphql_runner = Arc::new(TestGraphQlRunner::default());
 
         let node_id = NodeId::new("test").unwrap();
-        let mut service =
-            GraphQLService::new(logger, metrics, graphql_runner, store, 8001, node_id);
+        let mut service = GraphQLService::new(
+            logger,
+            metrics,
+            graphql_runner,
+            store,
+            persisted_queries,
+            api_keys,
+            8001,
+            node_id,
+        );
 
         let request = Request::builder()
             .method(Method::POST)
@@ -534,13 +899,29 @@ mod tests {
     async fn posting_valid_queries_yields_result_response() {
         let logger = Logger::root(slog::Discard, o!());
         let metrics_registry = Arc::new(MockMetricsRegistry::new());
-        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry));
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry.clone()));
+        let persisted_queries = Arc::new(
+            PersistedQueries::new(&logger, metrics_registry.clone(), AllowLists::new()).unwrap(),
+        );
+        let api_keys = Arc::new(ApiKeys::new(
+            &logger,
+            metrics_registry,
+            ApiKeyConfigs::new(),
+        ));
         let (store, subgraph_id) = mock_store_with_users_subgraph();
-        let graphql_runner = Arc::new(TestGraphQlRunner);
+        let graphql_runner = Arc::new(TestGraphQlRunner::default());
 
         let node_id = NodeId::new("test").unwrap();
-        let mut service =
-            GraphQLService::new(logger, metrics, graphql_runner, store, 8001, node_id);
+        let mut service = GraphQLService::new(
+            logger,
+            metrics,
+            graphql_runner,
+            store,
+            persisted_queries,
+            api_keys,
+            8001,
+            node_id,
+        );
 
         let request = Request::builder()
             .method(Method::POST)
@@ -566,4 +947,443 @@ mod tests {
             .expect("Query result field \"name\" is not a string");
         assert_eq!(name, "Jordi".to_string());
     }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn get_queries_yield_result_response() {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics_registry = Arc::new(MockMetricsRegistry::new());
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry.clone()));
+        let persisted_queries = Arc::new(
+            PersistedQueries::new(&logger, metrics_registry.clone(), AllowLists::new()).unwrap(),
+        );
+        let api_keys = Arc::new(ApiKeys::new(
+            &logger,
+            metrics_registry,
+            ApiKeyConfigs::new(),
+        ));
+        let (store, subgraph_id) = mock_store_with_users_subgraph();
+        let graphql_runner = Arc::new(TestGraphQlRunner::default());
+
+        let node_id = NodeId::new("test").unwrap();
+        let mut service = GraphQLService::new(
+            logger,
+            metrics,
+            graphql_runner,
+            store,
+            persisted_queries,
+            api_keys,
+            8001,
+            node_id,
+        );
+
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(format!(
+                "http://localhost:8000/subgraphs/id/{}?query=%7B%20name%20%7D",
+                subgraph_id
+            ))
+            .body(Body::empty())
+            .unwrap();
+
+        let response = tokio::spawn(service.call(request))
+            .await
+            .unwrap()
+            .expect("Should return a response");
+        // The query is not pinned to a block and can not be cached
+        assert_eq!(
+            response.headers().get(http::header::CACHE_CONTROL).unwrap(),
+            "no-cache"
+        );
+        let data = test_utils::assert_successful_response(response);
+        assert_eq!(
+            data.get("name").and_then(|name| name.as_str()),
+            Some("Jordi")
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn only_hash_pinned_queries_are_cacheable() {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics_registry = Arc::new(MockMetricsRegistry::new());
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry.clone()));
+        let persisted_queries = Arc::new(
+            PersistedQueries::new(&logger, metrics_registry.clone(), AllowLists::new()).unwrap(),
+        );
+        let api_keys = Arc::new(ApiKeys::new(
+            &logger,
+            metrics_registry,
+            ApiKeyConfigs::new(),
+        ));
+        let (store, subgraph_id) = mock_store_with_users_subgraph();
+        let graphql_runner = Arc::new(TestGraphQlRunner::default());
+
+        let node_id = NodeId::new("test").unwrap();
+        let service = GraphQLService::new(
+            logger,
+            metrics,
+            graphql_runner,
+            store,
+            persisted_queries,
+            api_keys,
+            8001,
+            node_id,
+        );
+
+        let cache_control = |query: &'static str| {
+            let mut service = service.clone();
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(format!(
+                    "http://localhost:8000/subgraphs/id/{}",
+                    subgraph_id
+                ))
+                .body(Body::from(query))
+                .unwrap();
+            async move {
+                let response = tokio::spawn(service.call(request))
+                    .await
+                    .unwrap()
+                    .expect("Should return a response");
+                response
+                    .headers()
+                    .get(http::header::CACHE_CONTROL)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_owned()
+            }
+        };
+
+        assert_eq!(
+            "public, max-age=3600",
+            cache_control("{\"query\": \"{ name(block: { hash: \\\"0xab\\\" }) }\"}").await
+        );
+        // The block with a given number can still be reorged away
+        assert_eq!(
+            "no-cache",
+            cache_control("{\"query\": \"{ name(block: { number: 1 }) }\"}").await
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn posting_batches_yields_array_response() {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics_registry = Arc::new(MockMetricsRegistry::new());
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry.clone()));
+        let persisted_queries = Arc::new(
+            PersistedQueries::new(&logger, metrics_registry.clone(), AllowLists::new()).unwrap(),
+        );
+        let api_keys = Arc::new(ApiKeys::new(
+            &logger,
+            metrics_registry,
+            ApiKeyConfigs::new(),
+        ));
+        let (store, subgraph_id) = mock_store_with_users_subgraph();
+        let graphql_runner = Arc::new(TestGraphQlRunner::default());
+
+        let node_id = NodeId::new("test").unwrap();
+        let mut service = GraphQLService::new(
+            logger,
+            metrics,
+            graphql_runner,
+            store,
+            persisted_queries,
+            api_keys,
+            8001,
+            node_id,
+        );
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!(
+                "http://localhost:8000/subgraphs/id/{}",
+                subgraph_id
+            ))
+            .body(Body::from(
+                "[{\"query\": \"{ name }\"}, {\"query\": \"{ name(block: { number: 1 }) }\"}]",
+            ))
+            .unwrap();
+
+        let response = tokio::spawn(service.call(request))
+            .await
+            .unwrap()
+            .expect("Should return a response");
+        assert_eq!(response.status(), StatusCode::OK);
+        // One of the queries is not pinned, and the batch can not be cached
+        assert_eq!(
+            response.headers().get(http::header::CACHE_CONTROL).unwrap(),
+            "no-cache"
+        );
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let results = json.as_array().expect("Batch response must be an array");
+        assert_eq!(2, results.len());
+        for result in results {
+            assert_eq!(result["data"]["name"], "Jordi");
+        }
+    }
+
+    #[test]
+    fn formats_server_sent_events() {
+        let result = QueryResult::new(Some(q::Value::Object(BTreeMap::from_iter(
+            vec![(
+                String::from("name"),
+                q::Value::String(String::from("Jordi")),
+            )]
+            .into_iter(),
+        ))));
+
+        assert_eq!(
+            server_sent_event(Some(7), &result),
+            "id: 7\ndata: {\"data\":{\"name\":\"Jordi\"}}\n\n"
+        );
+        assert_eq!(
+            server_sent_event(None, &result),
+            "data: {\"data\":{\"name\":\"Jordi\"}}\n\n"
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn live_query_rejects_invalid_last_event_id() {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics_registry = Arc::new(MockMetricsRegistry::new());
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry.clone()));
+        let persisted_queries = Arc::new(
+            PersistedQueries::new(&logger, metrics_registry.clone(), AllowLists::new()).unwrap(),
+        );
+        let api_keys = Arc::new(ApiKeys::new(
+            &logger,
+            metrics_registry,
+            ApiKeyConfigs::new(),
+        ));
+        let (store, subgraph_id) = mock_store_with_users_subgraph();
+        let graphql_runner = Arc::new(TestGraphQlRunner::default());
+
+        let node_id = NodeId::new("test").unwrap();
+        let mut service = GraphQLService::new(
+            logger,
+            metrics,
+            graphql_runner,
+            store,
+            persisted_queries,
+            api_keys,
+            8001,
+            node_id,
+        );
+
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(format!(
+                "http://localhost:8000/subgraphs/live/id/{}?query=%7B%20name%20%7D",
+                subgraph_id
+            ))
+            .header("Last-Event-ID", "latest")
+            .body(Body::from(""))
+            .unwrap();
+
+        let response = tokio::spawn(service.call(request))
+            .await
+            .unwrap()
+            .expect("Should return a response");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn live_query_streams_results() {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics_registry = Arc::new(MockMetricsRegistry::new());
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry.clone()));
+        let persisted_queries = Arc::new(
+            PersistedQueries::new(&logger, metrics_registry.clone(), AllowLists::new()).unwrap(),
+        );
+        let api_keys = Arc::new(ApiKeys::new(
+            &logger,
+            metrics_registry,
+            ApiKeyConfigs::new(),
+        ));
+        let (store, subgraph_id) = mock_store_with_users_subgraph();
+        let graphql_runner = Arc::new(TestGraphQlRunner::default());
+
+        let node_id = NodeId::new("test").unwrap();
+        let service = GraphQLService::new(
+            logger,
+            metrics,
+            graphql_runner.clone(),
+            store,
+            persisted_queries,
+            api_keys,
+            8001,
+            node_id,
+        );
+
+        let live = |last_event_id: Option<&str>| {
+            // Both top-level fields trigger a run of the whole query, and
+            // the mock store is at a new block every time it is asked
+            let mut request = Request::builder().method(Method::GET).uri(format!(
+                "http://localhost:8000/subgraphs/live/id/{}?query=%7B%20a%3A%20name%20b%3A%20name%20%7D",
+                subgraph_id
+            ));
+            if let Some(id) = last_event_id {
+                request = request.header("Last-Event-ID", id);
+            }
+            let mut service = service.clone();
+            let request = request.body(Body::from("")).unwrap();
+            async move {
+                let response = tokio::spawn(service.call(request))
+                    .await
+                    .unwrap()
+                    .expect("Should return a response");
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(
+                    response.headers().get("Content-Type").unwrap(),
+                    "text/event-stream"
+                );
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        let event = |block: BlockNumber| {
+            format!(
+                "id: {}\ndata: {{\"data\":{{\"name\":\"Jordi\"}}}}\n\n",
+                block
+            )
+        };
+        assert_eq!(live(None).await, format!("{}{}", event(1), event(2)));
+        // The store is at block 3 and then 4 now; the result for block 3
+        // was already sent, and the query is not even run for it
+        assert_eq!(live(Some("3")).await, event(4));
+        assert_eq!(3, graphql_runner.queries.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn enforces_api_keys() {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics_registry = Arc::new(MockMetricsRegistry::new());
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry.clone()));
+        let persisted_queries = Arc::new(
+            PersistedQueries::new(&logger, metrics_registry.clone(), AllowLists::new()).unwrap(),
+        );
+        let mut keys = ApiKeyConfigs::new();
+        keys.insert(
+            String::from("secret"),
+            ApiKeyConfig {
+                client: String::from("team-a"),
+                max_requests_per_second: Some(1.0),
+                max_effort: None,
+                max_subscriptions: None,
+            },
+        );
+        let api_keys = Arc::new(ApiKeys::with_required(
+            &logger,
+            metrics_registry,
+            keys,
+            true,
+        ));
+        let (store, subgraph_id) = mock_store_with_users_subgraph();
+        let graphql_runner = Arc::new(TestGraphQlRunner::default());
+
+        let node_id = NodeId::new("test").unwrap();
+        let mut service = GraphQLService::new(
+            logger,
+            metrics,
+            graphql_runner,
+            store,
+            persisted_queries,
+            api_keys,
+            8001,
+            node_id,
+        );
+
+        let mut status = |key: Option<&str>| {
+            let mut request = Request::builder().method(Method::POST).uri(format!(
+                "http://localhost:8000/subgraphs/id/{}",
+                subgraph_id
+            ));
+            if let Some(key) = key {
+                request = request.header("Authorization", format!("Bearer {}", key));
+            }
+            let request = request
+                .body(Body::from("{\"query\": \"{ name }\"}"))
+                .unwrap();
+            futures03::executor::block_on(service.call(request))
+                .expect("Should return a response")
+                .status()
+        };
+
+        assert_eq!(status(None), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("guess")), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("secret")), StatusCode::OK);
+        // The key only allows one request per second
+        assert_eq!(status(Some("secret")), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn charges_each_query_in_a_batch() {
+        let logger = Logger::root(slog::Discard, o!());
+        let metrics_registry = Arc::new(MockMetricsRegistry::new());
+        let metrics = Arc::new(GraphQLServiceMetrics::new(metrics_registry.clone()));
+        let persisted_queries = Arc::new(
+            PersistedQueries::new(&logger, metrics_registry.clone(), AllowLists::new()).unwrap(),
+        );
+        let mut keys = ApiKeyConfigs::new();
+        for key in &["team-a", "team-b"] {
+            keys.insert(
+                key.to_string(),
+                ApiKeyConfig {
+                    client: key.to_string(),
+                    max_requests_per_second: Some(2.0),
+                    max_effort: None,
+                    max_subscriptions: None,
+                },
+            );
+        }
+        let api_keys = Arc::new(ApiKeys::with_required(
+            &logger,
+            metrics_registry,
+            keys,
+            true,
+        ));
+        let (store, subgraph_id) = mock_store_with_users_subgraph();
+        let graphql_runner = Arc::new(TestGraphQlRunner::default());
+
+        let node_id = NodeId::new("test").unwrap();
+        let mut service = GraphQLService::new(
+            logger,
+            metrics,
+            graphql_runner,
+            store,
+            persisted_queries,
+            api_keys,
+            8001,
+            node_id,
+        );
+
+        let mut status = |key: &str, queries: usize| {
+            let body = if queries == 1 {
+                String::from("{\"query\": \"{ name }\"}")
+            } else {
+                format!("[{}]", vec!["{\"query\": \"{ name }\"}"; queries].join(","))
+            };
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(format!(
+                    "http://localhost:8000/subgraphs/id/{}",
+                    subgraph_id
+                ))
+                .header("Authorization", format!("Bearer {}", key))
+                .body(Body::from(body))
+                .unwrap();
+            futures03::executor::block_on(service.call(request))
+                .expect("Should return a response")
+                .status()
+        };
+
+        // A batch of two queries uses up both requests the key allows
+        assert_eq!(status("team-a", 2), StatusCode::OK);
+        assert_eq!(status("team-a", 1), StatusCode::TOO_MANY_REQUESTS);
+        // A batch that needs more requests than the key has left fails
+        assert_eq!(status("team-b", 3), StatusCode::TOO_MANY_REQUESTS);
+    }
 }
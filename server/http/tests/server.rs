@@ -4,7 +4,9 @@ use hyper::{Body, Client, Request};
 use std::collections::BTreeMap;
 use std::time::Duration;
 
+use graph::data::graphql::api_keys::{ApiKeyConfigs, ApiKeys};
 use graph::data::graphql::effort::LoadManager;
+use graph::data::graphql::persisted::{AllowLists, PersistedQueries};
 use graph::prelude::*;
 
 use graph_server_http::test_utils;
@@ -77,6 +79,16 @@ mod test {
     use super::*;
     use graph_mock::{mock_store_with_users_subgraph, MockMetricsRegistry};
 
+    fn persisted_queries(registry: Arc<MockMetricsRegistry>) -> Arc<PersistedQueries> {
+        let logger = Logger::root(slog::Discard, o!());
+        Arc::new(PersistedQueries::new(&logger, registry, AllowLists::new()).unwrap())
+    }
+
+    fn api_keys(registry: Arc<MockMetricsRegistry>) -> Arc<ApiKeys> {
+        let logger = Logger::root(slog::Discard, o!());
+        Arc::new(ApiKeys::new(&logger, registry, ApiKeyConfigs::new()))
+    }
+
     #[test]
     fn rejects_empty_json() {
         let mut runtime = tokio::runtime::Runtime::new().unwrap();
@@ -88,7 +100,7 @@ mod test {
                 let (store, id) = mock_store_with_users_subgraph();
                 let query_runner = Arc::new(TestGraphQlRunner);
                 let node_id = NodeId::new("test").unwrap();
-                let mut server = HyperGraphQLServer::new(&logger_factory, metrics_registry, query_runner, store, node_id);
+                let mut server = HyperGraphQLServer::new(&logger_factory, metrics_registry.clone(), query_runner, store, persisted_queries(metrics_registry.clone()), api_keys(metrics_registry), node_id);
                 let http_server = server
                     .serve(8001, 8002)
                     .expect("Failed to start GraphQL server");
@@ -132,9 +144,11 @@ mod test {
             let node_id = NodeId::new("test").unwrap();
             let mut server = HyperGraphQLServer::new(
                 &logger_factory,
-                metrics_registry,
+                metrics_registry.clone(),
                 query_runner,
                 store,
+                persisted_queries(metrics_registry.clone()),
+                api_keys(metrics_registry),
                 node_id,
             );
             let http_server = server
@@ -219,9 +233,11 @@ mod test {
             let node_id = NodeId::new("test").unwrap();
             let mut server = HyperGraphQLServer::new(
                 &logger_factory,
-                metrics_registry,
+                metrics_registry.clone(),
                 query_runner,
                 store,
+                persisted_queries(metrics_registry.clone()),
+                api_keys(metrics_registry),
                 node_id,
             );
             let http_server = server
@@ -271,9 +287,11 @@ mod test {
             let node_id = NodeId::new("test").unwrap();
             let mut server = HyperGraphQLServer::new(
                 &logger_factory,
-                metrics_registry,
+                metrics_registry.clone(),
                 query_runner,
                 store,
+                persisted_queries(metrics_registry.clone()),
+                api_keys(metrics_registry),
                 node_id,
             );
             let http_server = server
@@ -1,11 +1,12 @@
 use graphql_parser::{query as q, schema as s};
 use std::collections::HashMap;
 
+use graph::components::store::{CopyStatus, EntityTypeChanges, EntityTypeStats};
 use graph::data::graphql::{ObjectOrInterface, TryFromValue, ValueList, ValueMap};
 use graph::data::subgraph::schema::{SubgraphError, SubgraphHealth, SUBGRAPHS_ID};
 use graph::prelude::*;
 use graph_graphql::prelude::{object, ExecutionContext, IntoValue, Resolver};
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use web3::types::{Address, H256};
 
 static DEPLOYMENT_STATUS_FRAGMENT: &str = r#"
@@ -37,15 +38,62 @@ static DEPLOYMENT_STATUS_FRAGMENT: &str = r#"
             dataSources(first: 1) {
                 network
             }
+            allDataSources: dataSources(first: 1000000) @include(if: $dataSources) {
+                kind
+                name
+                network
+                source {
+                    address
+                    startBlock
+                }
+            }
+        }
+        dynamicDataSources(first: 1000000, orderBy: ethereumBlockNumber)
+            @include(if: $dynamicDataSources) {
+            kind
+            name
+            network
+            source {
+                address
+                startBlock
+            }
+            ethereumBlockHash
+            ethereumBlockNumber
         }
     }
   "#;
 
+/// The variables that `DEPLOYMENT_STATUS_FRAGMENT` needs. Loading all data
+/// sources of a deployment can be expensive, and we only do that if the
+/// query selects them from the statuses that `field` returns
+fn deployment_status_variables(field: &q::Field) -> Vec<(String, q::Value)> {
+    fn selects(selection_set: &q::SelectionSet, name: &str) -> bool {
+        selection_set.items.iter().any(|selection| match selection {
+            q::Selection::Field(field) => field.name == name,
+            q::Selection::InlineFragment(fragment) => selects(&fragment.selection_set, name),
+            // We do not have the fragment definitions here
+            q::Selection::FragmentSpread(_) => true,
+        })
+    }
+
+    vec![
+        (
+            "dataSources".to_owned(),
+            q::Value::Boolean(selects(&field.selection_set, "dataSources")),
+        ),
+        (
+            "dynamicDataSources".to_owned(),
+            q::Value::Boolean(selects(&field.selection_set, "dynamicDataSources")),
+        ),
+    ]
+}
+
 /// Resolver for the index node GraphQL API.
 pub struct IndexNodeResolver<R, S> {
     logger: Logger,
     graphql_runner: Arc<R>,
     store: Arc<S>,
+    host_metrics: SharedHostMetrics,
 }
 
 /// The ID of a subgraph deployment assignment.
@@ -121,6 +169,72 @@ impl From<ChainIndexingStatus> for q::Value {
     }
 }
 
+/// A data source of a subgraph, either from its manifest or created from a
+/// template while indexing.
+#[derive(Debug)]
+struct DataSourceInfo {
+    kind: String,
+    name: String,
+    network: Option<String>,
+    /// The address of the contract, hex-encoded; `None` if the data source
+    /// handles all contracts.
+    address: Option<String>,
+    start_block: Option<BigInt>,
+    /// The block in which a dynamic data source was created.
+    created_at_block: Option<EthereumBlock>,
+}
+
+impl TryFromValue for DataSourceInfo {
+    fn try_from_value(value: &q::Value) -> Result<Self, Error> {
+        let source = value.get_required::<q::Value>("source")?;
+        Ok(Self {
+            kind: value.get_required("kind")?,
+            name: value.get_required("name")?,
+            network: value.get_optional("network")?,
+            address: source.get_optional("address")?,
+            start_block: source.get_optional("startBlock")?,
+            created_at_block: IndexingStatusWithoutNode::block_from_value(value, "ethereumBlock")?,
+        })
+    }
+}
+
+impl From<DataSourceInfo> for q::Value {
+    fn from(data_source: DataSourceInfo) -> Self {
+        object! {
+            __typename: "DataSource",
+            kind: data_source.kind,
+            name: data_source.name,
+            network: data_source.network,
+            address: data_source.address,
+            startBlock: data_source.start_block.map(|block| format!("{}", block)),
+            createdAtBlock: data_source.created_at_block,
+        }
+    }
+}
+
+fn handler_stats_to_value(stats: HandlerStats) -> q::Value {
+    let average_time = stats.average_time().map_or(q::Value::Null, q::Value::Float);
+    let buckets: Vec<q::Value> = stats
+        .buckets
+        .into_iter()
+        .map(|(upper_bound, count)| {
+            object! {
+                __typename: "HandlerTimeBucket",
+                upperBound: q::Value::Float(upper_bound),
+                count: format!("{}", count),
+            }
+        })
+        .collect();
+    object! {
+        __typename: "HandlerStats",
+        handler: stats.handler,
+        count: format!("{}", stats.count),
+        totalTime: q::Value::Float(stats.total_time),
+        averageTime: average_time,
+        buckets: buckets,
+    }
+}
+
 /// The overall indexing status of a subgraph.
 #[derive(Debug)]
 struct IndexingStatusWithoutNode {
@@ -135,6 +249,12 @@ struct IndexingStatusWithoutNode {
 
     /// Indexing status on different chains involved in the subgraph's data sources.
     chains: Vec<ChainIndexingStatus>,
+
+    /// The data sources in the subgraph manifest.
+    data_sources: Vec<DataSourceInfo>,
+
+    /// The data sources created from templates, in the order they were created.
+    dynamic_data_sources: Vec<DataSourceInfo>,
 }
 
 #[derive(Debug)]
@@ -153,6 +273,16 @@ struct IndexingStatus {
 
     /// ID of the Graph Node that the subgraph is indexed by.
     node: String,
+
+    /// Progress of copying data from the graft base, if the subgraph is grafted.
+    copy_status: Option<CopyStatus>,
+
+    data_sources: Vec<DataSourceInfo>,
+    dynamic_data_sources: Vec<DataSourceInfo>,
+
+    /// How often each handler ran and how long that took, if this node
+    /// indexes the subgraph.
+    handlers: Option<Vec<HandlerStats>>,
 }
 
 impl IndexingStatusWithoutNode {
@@ -166,6 +296,10 @@ impl IndexingStatusWithoutNode {
             non_fatal_errors: self.non_fatal_errors,
             chains: self.chains,
             node,
+            copy_status: None,
+            data_sources: self.data_sources,
+            dynamic_data_sources: self.dynamic_data_sources,
+            handlers: None,
         }
     }
 
@@ -195,6 +329,7 @@ impl IndexingStatusWithoutNode {
 
 impl TryFromValue for IndexingStatusWithoutNode {
     fn try_from_value(value: &q::Value) -> Result<Self, Error> {
+        let manifest = value.get_required::<q::Value>("manifest")?;
         Ok(Self {
             subgraph: value.get_required("id")?,
             synced: value.get_required("synced")?,
@@ -202,8 +337,7 @@ impl TryFromValue for IndexingStatusWithoutNode {
             fatal_error: value.get_optional("fatalError")?,
             non_fatal_errors: value.get_required("nonFatalErrors")?,
             chains: vec![ChainIndexingStatus::Ethereum(EthereumIndexingStatus {
-                network: value
-                    .get_required::<q::Value>("manifest")?
+                network: manifest
                     .get_required::<q::Value>("dataSources")?
                     .get_values::<q::Value>()?[0]
                     .get_required("network")?,
@@ -211,6 +345,10 @@ impl TryFromValue for IndexingStatusWithoutNode {
                 earliest_block: Self::block_from_value(value, "earliestEthereumBlock")?,
                 latest_block: Self::block_from_value(value, "latestEthereumBlock")?,
             })],
+            data_sources: manifest.get_optional("allDataSources")?.unwrap_or_default(),
+            dynamic_data_sources: value
+                .get_optional("dynamicDataSources")?
+                .unwrap_or_default(),
         })
     }
 }
@@ -225,6 +363,10 @@ impl From<IndexingStatus> for q::Value {
             node,
             non_fatal_errors,
             synced,
+            copy_status,
+            data_sources,
+            dynamic_data_sources,
+            handlers,
         } = status;
 
         fn subgraph_error_to_value(subgraph_error: SubgraphError) -> q::Value {
@@ -254,6 +396,30 @@ impl From<IndexingStatus> for q::Value {
             .collect();
         let fatal_error_val = fatal_error.map_or(q::Value::Null, subgraph_error_to_value);
 
+        let copy_status_val = copy_status.map_or(q::Value::Null, |status| {
+            let tables: Vec<q::Value> = status
+                .tables
+                .into_iter()
+                .map(|table| {
+                    object! {
+                        __typename: "CopyTableStatus",
+                        entityType: table.entity_type,
+                        rowsCopied: format!("{}", table.rows_copied),
+                        nextVid: format!("{}", table.next_vid),
+                        targetVid: format!("{}", table.target_vid),
+                        finished: table.finished,
+                    }
+                })
+                .collect();
+            object! {
+                __typename: "CopyStatus",
+                source: status.src.to_string(),
+                targetBlock: EthereumBlock(status.target_block),
+                finished: status.finished,
+                tables: tables,
+            }
+        });
+
         object! {
             __typename: "SubgraphIndexingStatus",
             subgraph: subgraph,
@@ -263,10 +429,45 @@ impl From<IndexingStatus> for q::Value {
             nonFatalErrors: non_fatal_errors,
             chains: chains.into_iter().map(q::Value::from).collect::<Vec<_>>(),
             node: node,
+            copyStatus: copy_status_val,
+            dataSources: data_sources.into_iter().map(q::Value::from).collect::<Vec<_>>(),
+            dynamicDataSources: dynamic_data_sources
+                .into_iter()
+                .map(q::Value::from)
+                .collect::<Vec<_>>(),
+            handlers: handlers.map_or(q::Value::Null, |handlers| {
+                q::Value::List(handlers.into_iter().map(handler_stats_to_value).collect())
+            }),
         }
     }
 }
 
+fn entity_type_stats_to_value(stats: EntityTypeStats) -> q::Value {
+    let history_ratio = stats
+        .history_ratio()
+        .map_or(q::Value::Null, q::Value::Float);
+    object! {
+        __typename: "EntityTypeStats",
+        entityType: stats.entity_type,
+        entities: format!("{}", stats.entities),
+        versions: format!("{}", stats.versions),
+        historyRatio: history_ratio,
+        tableSize: format!("{}", stats.table_size),
+        indexSize: format!("{}", stats.index_size),
+    }
+}
+
+fn entity_type_changes_to_value(changes: EntityTypeChanges) -> q::Value {
+    let ids = |ids: Vec<String>| ids.into_iter().map(q::Value::String).collect::<Vec<_>>();
+    object! {
+        __typename: "EntityChanges",
+        entityType: changes.entity_type,
+        created: ids(changes.created),
+        updated: ids(changes.updated),
+        removed: ids(changes.removed),
+    }
+}
+
 struct IndexingStatuses(Vec<IndexingStatus>);
 
 impl From<q::Value> for IndexingStatuses {
@@ -297,6 +498,25 @@ impl From<q::Value> for IndexingStatuses {
     }
 }
 
+impl IndexingStatuses {
+    /// Add the handler statistics of the subgraphs that this node indexes
+    /// to their status
+    fn with_handler_stats(self, host_metrics: &SharedHostMetrics) -> Self {
+        IndexingStatuses(
+            self.0
+                .into_iter()
+                .map(|mut status| {
+                    status.handlers = SubgraphDeploymentId::new(status.subgraph.clone())
+                        .ok()
+                        .and_then(|id| host_metrics.get(&id))
+                        .map(|metrics| metrics.handler_stats());
+                    status
+                })
+                .collect(),
+        )
+    }
+}
+
 impl From<IndexingStatuses> for q::Value {
     fn from(statuses: IndexingStatuses) -> Self {
         q::Value::List(statuses.0.into_iter().map(q::Value::from).collect())
@@ -308,17 +528,52 @@ where
     R: GraphQlRunner,
     S: Store + SubgraphDeploymentStore,
 {
-    pub fn new(logger: &Logger, graphql_runner: Arc<R>, store: Arc<S>) -> Self {
+    pub fn new(
+        logger: &Logger,
+        graphql_runner: Arc<R>,
+        store: Arc<S>,
+        host_metrics: SharedHostMetrics,
+    ) -> Self {
         let logger = logger.new(o!("component" => "IndexNodeResolver"));
         Self {
             logger,
             graphql_runner,
             store,
+            host_metrics,
         }
     }
 
+    /// Add the progress of copying data from the graft base to each status
+    fn with_copy_status(
+        &self,
+        statuses: IndexingStatuses,
+    ) -> Result<IndexingStatuses, QueryExecutionError> {
+        let ids: Vec<_> = statuses
+            .0
+            .iter()
+            .filter_map(|status| SubgraphDeploymentId::new(status.subgraph.clone()).ok())
+            .collect();
+        let mut copy_statuses = self
+            .store
+            .copy_statuses(&ids)
+            .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+        Ok(IndexingStatuses(
+            statuses
+                .0
+                .into_iter()
+                .map(|mut status| {
+                    status.copy_status = SubgraphDeploymentId::new(status.subgraph.clone())
+                        .ok()
+                        .and_then(|id| copy_statuses.remove(&id));
+                    status
+                })
+                .collect(),
+        ))
+    }
+
     fn resolve_indexing_statuses(
         &self,
+        field: &q::Field,
         arguments: &HashMap<&q::Name, q::Value>,
     ) -> Result<q::Value, QueryExecutionError> {
         // Extract optional "subgraphs" argument
@@ -349,7 +604,9 @@ where
                 r#"
                 query deployments(
                   $whereDeployments: SubgraphDeployment_filter!,
-                  $whereAssignments: SubgraphDeploymentAssignment_filter!
+                  $whereAssignments: SubgraphDeploymentAssignment_filter!,
+                  $dataSources: Boolean!,
+                  $dynamicDataSources: Boolean!
                 ) {
                   subgraphDeployments: subgraphDeploymentDetails(where: $whereDeployments, first: 1000000) {
                     ...deploymentStatus
@@ -369,7 +626,8 @@ where
                     ("whereDeployments".into(), where_filter.clone()),
                     ("whereAssignments".into(), where_filter),
                 ]
-                .into_iter(),
+                .into_iter()
+                .chain(deployment_status_variables(field)),
             ))),
             None,
         );
@@ -399,11 +657,13 @@ where
             }
         };
 
-        Ok(IndexingStatuses::from(data).into())
+        let statuses = self.with_copy_status(IndexingStatuses::from(data))?;
+        Ok(statuses.with_handler_stats(&self.host_metrics).into())
     }
 
     fn resolve_indexing_statuses_for_subgraph_name(
         &self,
+        field: &q::Field,
         arguments: &HashMap<&q::Name, q::Value>,
     ) -> Result<q::Value, QueryExecutionError> {
         // Get the subgraph name from the arguments; we can safely use `expect` here
@@ -433,7 +693,11 @@ where
                 "{}{}",
                 DEPLOYMENT_STATUS_FRAGMENT,
                 r#"
-                query subgraphs($where: Subgraph_filter!) {
+                query subgraphs(
+                  $where: Subgraph_filter!,
+                  $dataSources: Boolean!,
+                  $dynamicDataSources: Boolean!
+                ) {
                   subgraphs(where: $where, first: 1000000) {
                     versions(orderBy: createdAt, orderDirection: asc, first: 1000000) {
                       deployment {
@@ -452,7 +716,9 @@ where
             // If the `subgraphs` argument was provided, build a suitable `where`
             // filter to match the IDs; otherwise leave the `where` filter empty
             Some(QueryVariables::new(HashMap::from_iter(
-                vec![("where".into(), where_filter)].into_iter(),
+                vec![("where".into(), where_filter)]
+                    .into_iter()
+                    .chain(deployment_status_variables(field)),
             ))),
             None,
         );
@@ -520,7 +786,8 @@ where
                     .expect("missing deployment assignments"),
         };
 
-        Ok(IndexingStatuses::from(transformed_data).into())
+        let statuses = self.with_copy_status(IndexingStatuses::from(transformed_data))?;
+        Ok(statuses.with_handler_stats(&self.host_metrics).into())
     }
 
     fn resolve_proof_of_indexing(
@@ -561,8 +828,86 @@ where
         Ok(poi)
     }
 
+    fn resolve_entity_stats(
+        &self,
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        let deployment_id = arguments
+            .get_required::<SubgraphDeploymentId>("subgraph")
+            .map_err(|e| {
+                QueryExecutionError::ValueParseError("subgraph".to_owned(), e.to_string())
+            })?;
+
+        let stats = self
+            .store
+            .estimated_entity_stats(&deployment_id)
+            .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+
+        Ok(q::Value::List(
+            stats.into_iter().map(entity_type_stats_to_value).collect(),
+        ))
+    }
+
+    fn resolve_entity_types(
+        &self,
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        let deployment_id = arguments
+            .get_required::<SubgraphDeploymentId>("subgraph")
+            .map_err(|e| {
+                QueryExecutionError::ValueParseError("subgraph".to_owned(), e.to_string())
+            })?;
+
+        let stats = self
+            .store
+            .estimated_entity_stats(&deployment_id)
+            .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+
+        Ok(q::Value::List(
+            stats
+                .into_iter()
+                .map(|stats| {
+                    object! {
+                        __typename: "EntityType",
+                        name: stats.entity_type,
+                        count: format!("{}", stats.entities),
+                    }
+                })
+                .collect(),
+        ))
+    }
+
+    fn resolve_entity_changes_in_block(
+        &self,
+        arguments: &HashMap<&q::Name, q::Value>,
+    ) -> Result<q::Value, QueryExecutionError> {
+        let deployment_id = arguments
+            .get_required::<SubgraphDeploymentId>("subgraph")
+            .map_err(|e| {
+                QueryExecutionError::ValueParseError("subgraph".to_owned(), e.to_string())
+            })?;
+        let block_number = arguments
+            .get_required::<u64>("blockNumber")
+            .map_err(|e| e.to_string())
+            .and_then(|number| BlockNumber::try_from(number).map_err(|e| e.to_string()))
+            .map_err(|e| QueryExecutionError::ValueParseError("blockNumber".to_owned(), e))?;
+
+        let changes = self
+            .store
+            .entity_changes_in_block(&deployment_id, block_number)
+            .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+
+        Ok(q::Value::List(
+            changes
+                .into_iter()
+                .map(entity_type_changes_to_value)
+                .collect(),
+        ))
+    }
+
     fn resolve_indexing_statuses_for_version(
         &self,
+        field: &q::Field,
         arguments: &HashMap<&q::Name, q::Value>,
 
         // If `true` return the current version, if `false` return the pending version.
@@ -588,7 +933,12 @@ where
                 "{}{}",
                 DEPLOYMENT_STATUS_FRAGMENT,
                 r#"
-                query subgraphs($where: Subgraph_filter!, $currentVersion: Boolean!) {
+                query subgraphs(
+                  $where: Subgraph_filter!,
+                  $currentVersion: Boolean!,
+                  $dataSources: Boolean!,
+                  $dynamicDataSources: Boolean!
+                ) {
                   subgraphs(where: $where, first: 1) {
                     currentVersion @include(if: $currentVersion) {
                         deployment {
@@ -616,7 +966,8 @@ where
                     ("where".into(), where_filter),
                     ("currentVersion".into(), q::Value::Boolean(current_version)),
                 ]
-                .into_iter(),
+                .into_iter()
+                .chain(deployment_status_variables(field)),
             ))),
             None,
         );
@@ -685,7 +1036,9 @@ where
                     .expect("missing deployment assignments"),
         );
 
-        Ok(IndexingStatuses::from(transformed_data)
+        let statuses = self.with_copy_status(IndexingStatuses::from(transformed_data))?;
+        Ok(statuses
+            .with_handler_stats(&self.host_metrics)
             .0
             .into_iter()
             .next()
@@ -704,6 +1057,7 @@ where
             logger: self.logger.clone(),
             graphql_runner: self.graphql_runner.clone(),
             store: self.store.clone(),
+            host_metrics: self.host_metrics.cheap_clone(),
         }
     }
 }
@@ -758,12 +1112,23 @@ where
         match (prefetched_objects, object_type.name(), field.name.as_str()) {
             // The top-level `indexingStatuses` field
             (None, "SubgraphIndexingStatus", "indexingStatuses") => {
-                self.resolve_indexing_statuses(arguments)
+                self.resolve_indexing_statuses(field, arguments)
             }
 
             // The top-level `indexingStatusesForSubgraphName` field
             (None, "SubgraphIndexingStatus", "indexingStatusesForSubgraphName") => {
-                self.resolve_indexing_statuses_for_subgraph_name(arguments)
+                self.resolve_indexing_statuses_for_subgraph_name(field, arguments)
+            }
+
+            // The top-level `entityStats` field
+            (None, "EntityTypeStats", "entityStats") => self.resolve_entity_stats(arguments),
+
+            // The top-level `entityTypes` field
+            (None, "EntityType", "entityTypes") => self.resolve_entity_types(arguments),
+
+            // The top-level `entityChangesInBlock` field
+            (None, "EntityChanges", "entityChangesInBlock") => {
+                self.resolve_entity_changes_in_block(arguments)
             }
 
             // Resolve fields of `Object` values (e.g. the `chains` field of `ChainIndexingStatus`)
@@ -782,12 +1147,12 @@ where
         match (prefetched_object, field.name.as_str()) {
             // The top-level `indexingStatusForCurrentVersion` field
             (None, "indexingStatusForCurrentVersion") => {
-                self.resolve_indexing_statuses_for_version(arguments, true)
+                self.resolve_indexing_statuses_for_version(field, arguments, true)
             }
 
             // The top-level `indexingStatusForPendingVersion` field
             (None, "indexingStatusForPendingVersion") => {
-                self.resolve_indexing_statuses_for_version(arguments, false)
+                self.resolve_indexing_statuses_for_version(field, arguments, false)
             }
 
             // Resolve fields of `Object` values (e.g. the `latestBlock` field of `EthereumBlock`)
@@ -795,3 +1160,141 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph_mock::MockMetricsRegistry;
+
+    #[test]
+    fn parses_data_sources() {
+        let value = object! {
+            kind: "ethereum/contract",
+            name: "Token",
+            network: "mainnet",
+            source: object! {
+                address: "0x22843e74c59580b3eaf6c233fa67d8b7c561a835",
+                startBlock: "100",
+            },
+        };
+        let data_source = DataSourceInfo::try_from_value(&value).unwrap();
+        assert_eq!("ethereum/contract", data_source.kind);
+        assert_eq!("Token", data_source.name);
+        assert_eq!(Some(String::from("mainnet")), data_source.network);
+        assert_eq!(
+            Some(String::from("0x22843e74c59580b3eaf6c233fa67d8b7c561a835")),
+            data_source.address
+        );
+        assert_eq!(Some(BigInt::from(100)), data_source.start_block);
+        assert!(data_source.created_at_block.is_none());
+
+        // Dynamic data sources also have the block they were created in
+        let hash = "0x8d8c2ea2a04e7ccb4ac5cf9dd1a1d3caac1e1dbb1e2b67ff6ed4ec0a8ffe5c22";
+        let value = object! {
+            kind: "ethereum/contract",
+            name: "Pair",
+            network: q::Value::Null,
+            source: object! {
+                address: q::Value::Null,
+                startBlock: q::Value::Null,
+            },
+            ethereumBlockHash: hash,
+            ethereumBlockNumber: "7",
+        };
+        let data_source = DataSourceInfo::try_from_value(&value).unwrap();
+        assert_eq!(None, data_source.network);
+        assert_eq!(None, data_source.address);
+        assert_eq!(None, data_source.start_block);
+        assert_eq!(
+            Some(EthereumBlockPointer {
+                hash: hash.trim_start_matches("0x").parse().unwrap(),
+                number: 7,
+            }),
+            data_source.created_at_block.map(|block| block.0)
+        );
+
+        let value = object! {
+            kind: "ethereum/contract",
+            name: "Token",
+        };
+        assert!(DataSourceInfo::try_from_value(&value).is_err());
+    }
+
+    #[test]
+    fn only_loads_selected_data_sources() {
+        let variables = |query: &str| {
+            let document = q::parse_query(query).unwrap();
+            let field = match &document.definitions[0] {
+                q::Definition::Operation(q::OperationDefinition::SelectionSet(set)) => {
+                    match &set.items[0] {
+                        q::Selection::Field(field) => field.clone(),
+                        _ => unreachable!(),
+                    }
+                }
+                _ => unreachable!(),
+            };
+            let variables: HashMap<_, _> =
+                deployment_status_variables(&field).into_iter().collect();
+            (
+                variables["dataSources"].clone(),
+                variables["dynamicDataSources"].clone(),
+            )
+        };
+        let (yes, no) = (q::Value::Boolean(true), q::Value::Boolean(false));
+
+        assert_eq!(
+            (no.clone(), no.clone()),
+            variables("{ indexingStatuses { subgraph synced } }")
+        );
+        assert_eq!(
+            (yes.clone(), no.clone()),
+            variables("{ indexingStatuses { subgraph dataSources { name } } }")
+        );
+        assert_eq!(
+            (no.clone(), yes.clone()),
+            variables(
+                "{ indexingStatuses { ... on SubgraphIndexingStatus { dynamicDataSources { name } } } }"
+            )
+        );
+        // We can not tell what fragment spreads select
+        assert_eq!(
+            (yes.clone(), yes.clone()),
+            variables("{ indexingStatuses { ...status } }")
+        );
+    }
+
+    #[test]
+    fn adds_handler_stats_of_indexed_subgraphs() {
+        let status = |subgraph: &str| IndexingStatus {
+            subgraph: subgraph.to_owned(),
+            synced: false,
+            health: SubgraphHealth::Healthy,
+            fatal_error: None,
+            non_fatal_errors: vec![],
+            chains: vec![],
+            node: String::from("default"),
+            copy_status: None,
+            data_sources: vec![],
+            dynamic_data_sources: vec![],
+            handlers: None,
+        };
+
+        let registry = Arc::new(MockMetricsRegistry::new());
+        let indexed = SubgraphDeploymentId::new("indexed").unwrap();
+        let stopwatch = StopwatchMetrics::new(
+            Logger::root(slog::Discard, o!()),
+            indexed.clone(),
+            registry.clone(),
+        );
+        let metrics = Arc::new(HostMetrics::new(registry, indexed.as_str(), stopwatch));
+        metrics.observe_handler_execution_time(0.25, "handleTransfer");
+        let host_metrics = SharedHostMetrics::default();
+        host_metrics.insert(indexed, metrics.clone());
+
+        let statuses = IndexingStatuses(vec![status("indexed"), status("elsewhere")])
+            .with_handler_stats(&host_metrics);
+        assert_eq!(Some(metrics.handler_stats()), statuses.0[0].handlers);
+        assert_eq!(1, statuses.0[0].handlers.as_ref().unwrap().len());
+        assert_eq!(None, statuses.0[1].handlers);
+    }
+}
@@ -25,6 +25,7 @@ pub struct IndexNodeServer<Q, S> {
     logger: Logger,
     graphql_runner: Arc<Q>,
     store: Arc<S>,
+    host_metrics: SharedHostMetrics,
     node_id: NodeId,
 }
 
@@ -34,6 +35,7 @@ impl<Q, S> IndexNodeServer<Q, S> {
         logger_factory: &LoggerFactory,
         graphql_runner: Arc<Q>,
         store: Arc<S>,
+        host_metrics: SharedHostMetrics,
         node_id: NodeId,
     ) -> Self {
         let logger = logger_factory.component_logger(
@@ -49,6 +51,7 @@ impl<Q, S> IndexNodeServer<Q, S> {
             logger,
             graphql_runner,
             store,
+            host_metrics,
             node_id,
         }
     }
@@ -79,12 +82,14 @@ where
         let logger_for_service = self.logger.clone();
         let graphql_runner = self.graphql_runner.clone();
         let store = self.store.clone();
+        let host_metrics = self.host_metrics.cheap_clone();
         let node_id = self.node_id.clone();
         let new_service = make_service_fn(move |_| {
             futures03::future::ok::<_, Error>(IndexNodeService::new(
                 logger_for_service.clone(),
                 graphql_runner.clone(),
                 store.clone(),
+                host_metrics.cheap_clone(),
                 node_id.clone(),
             ))
         });
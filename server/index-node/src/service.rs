@@ -21,6 +21,7 @@ pub struct IndexNodeService<Q, S> {
     logger: Logger,
     graphql_runner: Arc<Q>,
     store: Arc<S>,
+    host_metrics: SharedHostMetrics,
     node_id: NodeId,
 }
 
@@ -30,6 +31,7 @@ impl<Q, S> Clone for IndexNodeService<Q, S> {
             logger: self.logger.clone(),
             graphql_runner: self.graphql_runner.clone(),
             store: self.store.clone(),
+            host_metrics: self.host_metrics.cheap_clone(),
             node_id: self.node_id.clone(),
         }
     }
@@ -43,11 +45,18 @@ where
     S: SubgraphDeploymentStore + Store,
 {
     /// Creates a new GraphQL service.
-    pub fn new(logger: Logger, graphql_runner: Arc<Q>, store: Arc<S>, node_id: NodeId) -> Self {
+    pub fn new(
+        logger: Logger,
+        graphql_runner: Arc<Q>,
+        store: Arc<S>,
+        host_metrics: SharedHostMetrics,
+        node_id: NodeId,
+    ) -> Self {
         IndexNodeService {
             logger,
             graphql_runner,
             store,
+            host_metrics,
             node_id,
         }
     }
@@ -103,7 +112,12 @@ where
         let logger = self.logger.cheap_clone();
         let result = {
             let options = QueryExecutionOptions {
-                resolver: IndexNodeResolver::new(&logger, graphql_runner, store),
+                resolver: IndexNodeResolver::new(
+                    &logger,
+                    graphql_runner,
+                    store,
+                    self.host_metrics.cheap_clone(),
+                ),
                 deadline: None,
                 max_first: std::u32::MAX,
                 max_skip: std::u32::MAX,
@@ -222,6 +236,24 @@ where
                             .body(Body::from(format!("Invalid request: {}", err)))
                             .unwrap())
                     }
+                    Err(err @ GraphQLServerError::Unauthorized(_)) => {
+                        debug!(logger, "IndexNodeService call failed: {}", err);
+
+                        Ok(Response::builder()
+                            .status(401)
+                            .header("Content-Type", "text/plain")
+                            .body(Body::from(err.to_string()))
+                            .unwrap())
+                    }
+                    Err(err @ GraphQLServerError::TooManyRequests(_)) => {
+                        debug!(logger, "IndexNodeService call failed: {}", err);
+
+                        Ok(Response::builder()
+                            .status(429)
+                            .header("Content-Type", "text/plain")
+                            .body(Body::from(err.to_string()))
+                            .unwrap())
+                    }
                     Err(err @ GraphQLServerError::QueryError(_)) => {
                         error!(logger, "IndexNodeService call failed: {}", err);
 
@@ -0,0 +1,376 @@
+//! Authentication and authorization for the admin server.
+//!
+//! Principals authenticate in one of two ways:
+//!
+//! - with a static token, sent as `Authorization: Bearer <token>`
+//! - by signing each request with their secret. Signed requests carry the
+//!   name of the principal in `X-Graph-Principal`, the current Unix time in
+//!   seconds in `X-Graph-Timestamp`, a string that is unique for each
+//!   request in `X-Graph-Nonce`, and the hex-encoded HMAC-SHA256 of
+//!   `<timestamp>\n<nonce>\n<method>\n<params>` in `X-Graph-Signature`,
+//!   where `<params>` are the JSON-RPC params serialized without whitespace
+//!   and with object keys in sorted order. Signatures are only accepted for
+//!   a few minutes after the timestamp, and only once
+//!
+//! A principal may only call a method for a subgraph if one of its
+//! permissions lists the method and a prefix of the subgraph name.
+//! Requests for a deployment need that permission for every subgraph name
+//! that uses the deployment.
+use graph::prelude::{AdminPrincipal, AdminPrincipals};
+use hmac::{Hmac, Mac};
+use jsonrpc_http_server::hyper;
+use jsonrpc_http_server::jsonrpc_core::{Metadata, Value};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// How far the timestamp of a signed request may be from our clock, in seconds
+const MAX_CLOCK_SKEW: u64 = 300;
+
+const PRINCIPAL_HEADER: &str = "X-Graph-Principal";
+const TIMESTAMP_HEADER: &str = "X-Graph-Timestamp";
+const NONCE_HEADER: &str = "X-Graph-Nonce";
+const SIGNATURE_HEADER: &str = "X-Graph-Signature";
+
+/// The credentials that came with a request
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Credentials {
+    token: Option<String>,
+    principal: Option<String>,
+    timestamp: Option<String>,
+    nonce: Option<String>,
+    signature: Option<String>,
+}
+
+impl Metadata for Credentials {}
+
+impl Credentials {
+    pub fn from_request(request: &hyper::Request<hyper::Body>) -> Self {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim().to_owned())
+        };
+        let token = header("Authorization").and_then(|value| {
+            let mut parts = value.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                    Some(token.trim().to_owned())
+                }
+                _ => None,
+            }
+        });
+        Credentials {
+            token,
+            principal: header(PRINCIPAL_HEADER),
+            timestamp: header(TIMESTAMP_HEADER),
+            nonce: header(NONCE_HEADER),
+            signature: header(SIGNATURE_HEADER),
+        }
+    }
+}
+
+pub(crate) struct Auth {
+    principals: AdminPrincipals,
+    /// The timestamps of the signed requests we accepted recently, keyed by
+    /// the principal and nonce of the request, so that they can not be
+    /// replayed
+    seen: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl Auth {
+    pub fn new(principals: AdminPrincipals) -> Self {
+        Auth {
+            principals,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether we check who sends requests at all
+    pub fn is_enabled(&self) -> bool {
+        !self.principals.is_empty()
+    }
+
+    /// The name of the principal that sent a request for `method` with
+    /// `params`. `now` is the current Unix time in seconds. Returns `None`
+    /// if we do not check who sends requests
+    pub fn authenticate(
+        &self,
+        credentials: &Credentials,
+        method: &str,
+        params: &Value,
+        now: u64,
+    ) -> Result<Option<String>, String> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+
+        if let Some(name) = &credentials.principal {
+            let secret = self
+                .principals
+                .get(name)
+                .and_then(|principal| principal.secret.as_ref())
+                .ok_or_else(|| format!("unknown principal `{}`", name))?;
+            let timestamp = credentials
+                .timestamp
+                .as_ref()
+                .ok_or_else(|| format!("the {} header is missing", TIMESTAMP_HEADER))?;
+            let time: u64 = timestamp
+                .parse()
+                .map_err(|_| format!("invalid timestamp `{}`", timestamp))?;
+            if time.max(now) - time.min(now) > MAX_CLOCK_SKEW {
+                return Err(format!(
+                    "the timestamp `{}` is too far from the current time",
+                    timestamp
+                ));
+            }
+            let nonce = credentials
+                .nonce
+                .as_ref()
+                .filter(|nonce| !nonce.is_empty())
+                .ok_or_else(|| format!("the {} header is missing", NONCE_HEADER))?;
+            let signature = credentials
+                .signature
+                .as_ref()
+                .and_then(|signature| hex::decode(signature).ok())
+                .ok_or_else(|| format!("the {} header is missing or invalid", SIGNATURE_HEADER))?;
+            let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes())
+                .map_err(|_| String::from("invalid secret"))?;
+            mac.input(signed_message(timestamp, nonce, method, params).as_bytes());
+            if mac.verify(&signature).is_err() {
+                return Err(String::from("invalid signature"));
+            }
+
+            // We only need to remember a request for as long as its
+            // timestamp is accepted
+            let mut seen = self.seen.lock().unwrap();
+            seen.retain(|_, seen_at| *seen_at + MAX_CLOCK_SKEW >= now);
+            if seen.insert((name.clone(), nonce.clone()), time).is_some() {
+                return Err(format!("the nonce `{}` was already used", nonce));
+            }
+            return Ok(Some(name.clone()));
+        }
+
+        if let Some(token) = &credentials.token {
+            return self
+                .principals
+                .iter()
+                .find(|(_, principal)| match &principal.token {
+                    Some(expected) => constant_time_eq(token.as_bytes(), expected.as_bytes()),
+                    None => false,
+                })
+                .map(|(name, _)| Some(name.clone()))
+                .ok_or_else(|| String::from("invalid token"));
+        }
+
+        Err(String::from("the request does not come with credentials"))
+    }
+
+    /// Whether `principal` may call `method` for the subgraph name `target`
+    pub fn authorize(&self, principal: Option<&str>, method: &str, target: &str) -> bool {
+        match principal {
+            None => !self.is_enabled(),
+            Some(name) => self
+                .principals
+                .get(name)
+                .map(|principal| is_permitted(principal, method, target))
+                .unwrap_or(false),
+        }
+    }
+
+    /// Whether `principal` may call `method` for a deployment that the
+    /// subgraphs `names` use. A deployment that no subgraph uses can only
+    /// be managed by principals whose permissions are not restricted to
+    /// some subgraphs
+    pub fn authorize_deployment(
+        &self,
+        principal: Option<&str>,
+        method: &str,
+        names: &[String],
+    ) -> bool {
+        if names.is_empty() {
+            return self.authorize(principal, method, "");
+        }
+        names
+            .iter()
+            .all(|name| self.authorize(principal, method, name))
+    }
+}
+
+fn is_permitted(principal: &AdminPrincipal, method: &str, target: &str) -> bool {
+    principal.permissions.iter().any(|permission| {
+        permission.methods.iter().any(|m| m == "*" || m == method)
+            && (permission.prefixes.is_empty()
+                || permission
+                    .prefixes
+                    .iter()
+                    .any(|prefix| target.starts_with(prefix.as_str())))
+    })
+}
+
+/// The message that a signed request for `method` with `params` signs.
+/// `serde_json` keeps object keys sorted, which makes the serialization of
+/// `params` canonical
+fn signed_message(timestamp: &str, nonce: &str, method: &str, params: &Value) -> String {
+    format!("{}\n{}\n{}\n{}", timestamp, nonce, method, params)
+}
+
+/// Compare `a` and `b` in time that only depends on their length
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::prelude::{serde_json, AdminPermission};
+
+    fn auth() -> Auth {
+        let mut principals = AdminPrincipals::new();
+        principals.insert(
+            String::from("ci"),
+            AdminPrincipal {
+                token: Some(String::from("token")),
+                secret: Some(String::from("secret")),
+                permissions: vec![AdminPermission {
+                    methods: vec![String::from("subgraph_deploy")],
+                    prefixes: vec![String::from("team/")],
+                }],
+            },
+        );
+        Auth::new(principals)
+    }
+
+    fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+        mac.input(message);
+        mac.result().code().to_vec()
+    }
+
+    fn signed(timestamp: &str, nonce: &str, method: &str, params: &Value) -> Credentials {
+        let signature = hmac_sha256(
+            b"secret",
+            signed_message(timestamp, nonce, method, params).as_bytes(),
+        );
+        Credentials {
+            principal: Some(String::from("ci")),
+            timestamp: Some(timestamp.to_owned()),
+            nonce: Some(nonce.to_owned()),
+            signature: Some(hex::encode(signature)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        assert_eq!(
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?"))
+        );
+    }
+
+    #[test]
+    fn authenticates_tokens() {
+        let auth = auth();
+        let params = Value::Null;
+        let credentials = |token: &str| Credentials {
+            token: Some(token.to_owned()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Ok(Some(String::from("ci"))),
+            auth.authenticate(&credentials("token"), "subgraph_deploy", &params, 0)
+        );
+        assert!(auth
+            .authenticate(&credentials("other"), "subgraph_deploy", &params, 0)
+            .is_err());
+        assert!(auth
+            .authenticate(&Credentials::default(), "subgraph_deploy", &params, 0)
+            .is_err());
+        assert_eq!(
+            Ok(None),
+            Auth::new(AdminPrincipals::new()).authenticate(
+                &Credentials::default(),
+                "subgraph_deploy",
+                &params,
+                0
+            )
+        );
+    }
+
+    #[test]
+    fn authenticates_signatures() {
+        let auth = auth();
+        let params = serde_json::json!({ "name": "team/subgraph", "ipfs_hash": "Qm" });
+        let now = 1_600_000_000;
+
+        let credentials = signed("1600000000", "1", "subgraph_deploy", &params);
+        assert_eq!(
+            Ok(Some(String::from("ci"))),
+            auth.authenticate(&credentials, "subgraph_deploy", &params, now)
+        );
+        // Requests can not be replayed
+        assert!(auth
+            .authenticate(&credentials, "subgraph_deploy", &params, now)
+            .is_err());
+        let credentials = signed("1600000000", "2", "subgraph_deploy", &params);
+        // The signature covers the method and the params
+        assert!(auth
+            .authenticate(&credentials, "subgraph_remove", &params, now)
+            .is_err());
+        assert!(auth
+            .authenticate(&credentials, "subgraph_deploy", &Value::Null, now)
+            .is_err());
+        // Signatures expire
+        assert!(auth
+            .authenticate(&credentials, "subgraph_deploy", &params, now + 301)
+            .is_err());
+        // The nonce is required and covered by the signature
+        let mut credentials = signed("1600000000", "3", "subgraph_deploy", &params);
+        credentials.nonce = None;
+        assert!(auth
+            .authenticate(&credentials, "subgraph_deploy", &params, now)
+            .is_err());
+        credentials.nonce = Some(String::from("4"));
+        assert!(auth
+            .authenticate(&credentials, "subgraph_deploy", &params, now)
+            .is_err());
+        // Nonces are forgotten once their requests expire
+        let credentials = signed("1600000600", "1", "subgraph_deploy", &params);
+        assert_eq!(
+            Ok(Some(String::from("ci"))),
+            auth.authenticate(&credentials, "subgraph_deploy", &params, now + 600)
+        );
+        assert_eq!(1, auth.seen.lock().unwrap().len());
+    }
+
+    #[test]
+    fn checks_permissions() {
+        let auth = auth();
+
+        assert!(auth.authorize(Some("ci"), "subgraph_deploy", "team/subgraph"));
+        assert!(!auth.authorize(Some("ci"), "subgraph_deploy", "other/subgraph"));
+        assert!(!auth.authorize(Some("ci"), "subgraph_remove", "team/subgraph"));
+        assert!(!auth.authorize(None, "subgraph_deploy", "team/subgraph"));
+        assert!(Auth::new(AdminPrincipals::new()).authorize(None, "subgraph_remove", "any"));
+
+        // Deployments need permissions for all the subgraphs that use them
+        let names =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+        assert!(auth.authorize_deployment(Some("ci"), "subgraph_deploy", &names(&["team/a"])));
+        assert!(!auth.authorize_deployment(
+            Some("ci"),
+            "subgraph_deploy",
+            &names(&["team/a", "other/b"])
+        ));
+        assert!(!auth.authorize_deployment(Some("ci"), "subgraph_deploy", &[]));
+        assert!(Auth::new(AdminPrincipals::new()).authorize_deployment(
+            None,
+            "subgraph_deploy",
+            &[]
+        ));
+    }
+}
@@ -1,22 +1,34 @@
 extern crate graph;
+extern crate hex;
+extern crate hmac;
 extern crate jsonrpc_http_server;
 extern crate lazy_static;
 extern crate serde;
+extern crate sha2;
 
+mod auth;
+
+use graph::data::graphql::persisted::PersistedQueries;
 use graph::prelude::futures03::channel::{mpsc, oneshot};
 use graph::prelude::futures03::SinkExt;
 use graph::prelude::serde_json;
 use graph::prelude::{JsonRpcServer as JsonRpcServerTrait, *};
 use jsonrpc_http_server::{
-    jsonrpc_core::{self, Compatibility, IoHandler, Params, Value},
+    jsonrpc_core::{self, Compatibility, MetaIoHandler, Params, Value},
     RestApi, Server, ServerBuilder,
 };
 use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
 
 use std::collections::BTreeMap;
 use std::env;
+use std::fmt;
 use std::io;
 use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use self::auth::{Auth, Credentials};
 
 lazy_static! {
     static ref EXTERNAL_HTTP_BASE_URL: Option<String> = env::var_os("EXTERNAL_HTTP_BASE_URL")
@@ -29,6 +41,20 @@ const JSON_RPC_DEPLOY_ERROR: i64 = 0;
 const JSON_RPC_REMOVE_ERROR: i64 = 1;
 const JSON_RPC_CREATE_ERROR: i64 = 2;
 const JSON_RPC_REASSIGN_ERROR: i64 = 3;
+const JSON_RPC_HISTORY_BLOCKS_ERROR: i64 = 4;
+const JSON_RPC_CREATE_INDEX_ERROR: i64 = 5;
+const JSON_RPC_DROP_INDEX_ERROR: i64 = 6;
+const JSON_RPC_EVOLVE_SCHEMA_ERROR: i64 = 7;
+const JSON_RPC_MOVE_ERROR: i64 = 8;
+const JSON_RPC_PERSISTED_QUERY_ERROR: i64 = 9;
+const JSON_RPC_UNAUTHORIZED_ERROR: i64 = 10;
+const JSON_RPC_PAUSE_ERROR: i64 = 11;
+const JSON_RPC_RESUME_ERROR: i64 = 12;
+const JSON_RPC_REWIND_ERROR: i64 = 13;
+const JSON_RPC_REINDEX_ERROR: i64 = 14;
+const JSON_RPC_MOVE_ROLLBACK_ERROR: i64 = 15;
+const JSON_RPC_MOVE_FINALIZE_ERROR: i64 = 16;
+const JSON_RPC_IPFS_CACHE_ERROR: i64 = 17;
 
 #[derive(Debug, Deserialize)]
 struct SubgraphCreateParams {
@@ -53,8 +79,268 @@ struct SubgraphReassignParams {
     node_id: NodeId,
 }
 
+#[derive(Debug, Deserialize)]
+struct SubgraphPauseParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphResumeParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphRewindParams {
+    ipfs_hash: SubgraphDeploymentId,
+    /// The last block whose changes the deployment keeps
+    block: BlockNumber,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphReindexParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphSetHistoryBlocksParams {
+    ipfs_hash: SubgraphDeploymentId,
+    /// Keep all history if this is not set
+    history_blocks: Option<BlockNumber>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphCreateIndexParams {
+    ipfs_hash: SubgraphDeploymentId,
+    entity: String,
+    fields: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphDropIndexParams {
+    ipfs_hash: SubgraphDeploymentId,
+    index: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphEvolveSchemaParams {
+    ipfs_hash: SubgraphDeploymentId,
+    /// The IPFS hash of the new GraphQL schema
+    schema: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphMoveParams {
+    ipfs_hash: SubgraphDeploymentId,
+    /// The name of the shard to move the deployment to
+    shard: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphMoveRollbackParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphMoveFinalizeParams {
+    ipfs_hash: SubgraphDeploymentId,
+}
+
+/// Allow-lists of persisted queries belong to a 'target', which is either a
+/// subgraph name or a deployment ID
+#[derive(Debug, Deserialize)]
+struct SubgraphAllowQueryParams {
+    target: String,
+    /// The text of the query to allow
+    query: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphDisallowQueryParams {
+    target: String,
+    /// The SHA-256 hash of the query
+    hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphSetAllowListOnlyParams {
+    target: String,
+    enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphAllowListParams {
+    target: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IpfsSeedCacheParams {
+    /// A directory on the node with files named after their IPFS hash
+    dir: String,
+}
+
+/// What a request is about, which is what permissions are checked against
+enum Subject {
+    /// A subgraph name
+    Name(String),
+    /// A deployment, which is checked against the names of the subgraphs
+    /// that use it
+    Deployment(SubgraphDeploymentId),
+    /// The target of an allow-list, which is either a subgraph name or a
+    /// deployment ID
+    AllowList(String),
+    /// The node itself, which only principals whose permissions are not
+    /// restricted to some subgraphs may manage
+    Node,
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subject::Name(name) | Subject::AllowList(name) => write!(f, "{}", name),
+            Subject::Deployment(id) => write!(f, "{}", id),
+            Subject::Node => write!(f, "this node"),
+        }
+    }
+}
+
+trait Target {
+    fn target(&self) -> Subject;
+}
+
+impl Target for SubgraphCreateParams {
+    fn target(&self) -> Subject {
+        Subject::Name(self.name.to_string())
+    }
+}
+
+impl Target for SubgraphDeployParams {
+    fn target(&self) -> Subject {
+        Subject::Name(self.name.to_string())
+    }
+}
+
+impl Target for SubgraphRemoveParams {
+    fn target(&self) -> Subject {
+        Subject::Name(self.name.to_string())
+    }
+}
+
+impl Target for SubgraphReassignParams {
+    fn target(&self) -> Subject {
+        Subject::Deployment(self.ipfs_hash.clone())
+    }
+}
+
+impl Target for SubgraphPauseParams {
+    fn target(&self) -> Subject {
+        Subject::Deployment(self.ipfs_hash.clone())
+    }
+}
+
+impl Target for SubgraphResumeParams {
+    fn target(&self) -> Subject {
+        Subject::Deployment(self.ipfs_hash.clone())
+    }
+}
+
+impl Target for SubgraphRewindParams {
+    fn target(&self) -> Subject {
+        Subject::Deployment(self.ipfs_hash.clone())
+    }
+}
+
+impl Target for SubgraphReindexParams {
+    fn target(&self) -> Subject {
+        Subject::Deployment(self.ipfs_hash.clone())
+    }
+}
+
+impl Target for SubgraphSetHistoryBlocksParams {
+    fn target(&self) -> Subject {
+        Subject::Deployment(self.ipfs_hash.clone())
+    }
+}
+
+impl Target for SubgraphCreateIndexParams {
+    fn target(&self) -> Subject {
+        Subject::Deployment(self.ipfs_hash.clone())
+    }
+}
+
+impl Target for SubgraphDropIndexParams {
+    fn target(&self) -> Subject {
+        Subject::Deployment(self.ipfs_hash.clone())
+    }
+}
+
+impl Target for SubgraphEvolveSchemaParams {
+    fn target(&self) -> Subject {
+        Subject::Deployment(self.ipfs_hash.clone())
+    }
+}
+
+impl Target for SubgraphMoveParams {
+    fn target(&self) -> Subject {
+        Subject::Deployment(self.ipfs_hash.clone())
+    }
+}
+
+impl Target for SubgraphMoveRollbackParams {
+    fn target(&self) -> Subject {
+        Subject::Deployment(self.ipfs_hash.clone())
+    }
+}
+
+impl Target for SubgraphMoveFinalizeParams {
+    fn target(&self) -> Subject {
+        Subject::Deployment(self.ipfs_hash.clone())
+    }
+}
+
+impl Target for SubgraphAllowQueryParams {
+    fn target(&self) -> Subject {
+        Subject::AllowList(self.target.clone())
+    }
+}
+
+impl Target for SubgraphDisallowQueryParams {
+    fn target(&self) -> Subject {
+        Subject::AllowList(self.target.clone())
+    }
+}
+
+impl Target for SubgraphSetAllowListOnlyParams {
+    fn target(&self) -> Subject {
+        Subject::AllowList(self.target.clone())
+    }
+}
+
+impl Target for SubgraphAllowListParams {
+    fn target(&self) -> Subject {
+        Subject::AllowList(self.target.clone())
+    }
+}
+
+impl Target for IpfsSeedCacheParams {
+    fn target(&self) -> Subject {
+        Subject::Node
+    }
+}
+
+/// A request that we let through. It is recorded in the audit log once it
+/// has been handled
+struct AdminCall {
+    principal: Option<String>,
+    method: &'static str,
+    params: Value,
+}
+
 pub struct JsonRpcServer<R> {
     registrar: Arc<R>,
+    persisted_queries: Arc<PersistedQueries>,
+    ipfs_cache: Option<Arc<dyn IpfsCache>>,
+    auth: Auth,
+    audit_log: Arc<dyn AdminAuditLog>,
     http_port: u16,
     ws_port: u16,
     node_id: NodeId,
@@ -62,6 +348,123 @@ pub struct JsonRpcServer<R> {
 }
 
 impl<R: SubgraphRegistrar> JsonRpcServer<R> {
+    /// Check that the request for `method` comes from someone who may make
+    /// it, and parse its `params`. Requests that we refuse are recorded in
+    /// the audit log right away
+    async fn authorize<P>(
+        &self,
+        method: &'static str,
+        credentials: &Credentials,
+        params: Params,
+    ) -> Result<(AdminCall, P), jsonrpc_core::Error>
+    where
+        P: DeserializeOwned + Target,
+    {
+        let value = match &params {
+            Params::None => Value::Null,
+            Params::Array(values) => Value::Array(values.clone()),
+            Params::Map(map) => Value::Object(map.clone()),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+
+        let principal = self.auth.authenticate(credentials, method, &value, now);
+        let mut call = AdminCall {
+            principal: None,
+            method,
+            params: value,
+        };
+        match principal {
+            Ok(principal) => call.principal = principal,
+            Err(e) => return Err(self.refuse(call, e)),
+        }
+
+        let params: P = params.parse()?;
+        let subject = params.target();
+        let principal = call.principal.clone();
+        let principal = principal.as_deref();
+        let allowed = match &subject {
+            Subject::Name(name) => self.auth.authorize(principal, method, name),
+            Subject::Node => self.auth.authorize(principal, method, ""),
+            Subject::Deployment(id) => match self.registrar.subgraph_names(id).await {
+                Ok(names) => self.auth.authorize_deployment(principal, method, &names),
+                Err(e) => {
+                    let message = format!("failed to find the subgraphs using {}: {}", id, e);
+                    return Err(self.refuse(call, message));
+                }
+            },
+            // Allow-lists for a deployment are checked like requests for
+            // the deployment; anything else is a subgraph name
+            Subject::AllowList(target) => {
+                let names = match SubgraphDeploymentId::new(target.as_str()) {
+                    Ok(id) => match self.registrar.subgraph_names(&id).await {
+                        Ok(names) => names,
+                        Err(e) => {
+                            let message =
+                                format!("failed to find the subgraphs using {}: {}", id, e);
+                            return Err(self.refuse(call, message));
+                        }
+                    },
+                    Err(()) => vec![],
+                };
+                if names.is_empty() {
+                    self.auth.authorize(principal, method, target)
+                } else {
+                    self.auth.authorize_deployment(principal, method, &names)
+                }
+            }
+        };
+        if !allowed {
+            let message = format!("not allowed to call {} for {}", method, subject);
+            return Err(self.refuse(call, message));
+        }
+        Ok((call, params))
+    }
+
+    fn refuse(&self, call: AdminCall, message: String) -> jsonrpc_core::Error {
+        warn!(self.logger, "Refused admin request";
+            "method" => call.method,
+            "principal" => call.principal.as_deref().unwrap_or(""),
+            "reason" => &message);
+        self.record(call, Some(message.clone()));
+
+        jsonrpc_core::Error {
+            code: jsonrpc_core::ErrorCode::ServerError(JSON_RPC_UNAUTHORIZED_ERROR),
+            message,
+            data: None,
+        }
+    }
+
+    /// Record `call` and how it went in the audit log
+    fn audit(
+        &self,
+        call: AdminCall,
+        result: Result<Value, jsonrpc_core::Error>,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        let error = result.as_ref().err().map(|e| e.message.clone());
+        self.record(call, error);
+        result
+    }
+
+    fn record(&self, call: AdminCall, error: Option<String>) {
+        let method = call.method;
+        let entry = AdminAuditEntry {
+            principal: call.principal,
+            method: method.to_owned(),
+            params: call.params,
+            error,
+        };
+        // Not being able to write the audit log should not keep operators
+        // from managing their subgraphs
+        if let Err(e) = self.audit_log.record(entry) {
+            error!(self.logger, "Failed to write the admin audit log";
+                "method" => method,
+                "error" => e.to_string());
+        }
+    }
+
     /// Handler for the `subgraph_create` endpoint.
     async fn create_handler(
         &self,
@@ -151,6 +554,396 @@ impl<R: SubgraphRegistrar> JsonRpcServer<R> {
             )),
         }
     }
+
+    /// Handler for the `subgraph_pause` endpoint.
+    async fn pause_handler(
+        &self,
+        params: SubgraphPauseParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        info!(&self.logger, "Received subgraph_pause request"; "params" => format!("{:?}", params));
+
+        match self
+            .registrar
+            .pause_subgraph(params.ipfs_hash.clone())
+            .await
+        {
+            Ok(_) => Ok(Value::Null),
+            Err(e) => Err(json_rpc_error(
+                &self.logger,
+                "subgraph_pause",
+                e,
+                JSON_RPC_PAUSE_ERROR,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_resume` endpoint.
+    async fn resume_handler(
+        &self,
+        params: SubgraphResumeParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        info!(&self.logger, "Received subgraph_resume request"; "params" => format!("{:?}", params));
+
+        match self
+            .registrar
+            .resume_subgraph(params.ipfs_hash.clone())
+            .await
+        {
+            Ok(_) => Ok(Value::Null),
+            Err(e) => Err(json_rpc_error(
+                &self.logger,
+                "subgraph_resume",
+                e,
+                JSON_RPC_RESUME_ERROR,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_rewind` endpoint.
+    async fn rewind_handler(
+        &self,
+        params: SubgraphRewindParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        info!(&self.logger, "Received subgraph_rewind request"; "params" => format!("{:?}", params));
+
+        match self
+            .registrar
+            .rewind_subgraph(params.ipfs_hash.clone(), params.block)
+            .await
+        {
+            Ok(_) => Ok(Value::Null),
+            Err(e) => Err(json_rpc_error(
+                &self.logger,
+                "subgraph_rewind",
+                e,
+                JSON_RPC_REWIND_ERROR,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_reindex` endpoint.
+    async fn reindex_handler(
+        &self,
+        params: SubgraphReindexParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        info!(&self.logger, "Received subgraph_reindex request"; "params" => format!("{:?}", params));
+
+        match self
+            .registrar
+            .reindex_subgraph(params.ipfs_hash.clone())
+            .await
+        {
+            Ok(_) => Ok(Value::Null),
+            Err(e) => Err(json_rpc_error(
+                &self.logger,
+                "subgraph_reindex",
+                e,
+                JSON_RPC_REINDEX_ERROR,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_set_history_blocks` endpoint.
+    async fn set_history_blocks_handler(
+        &self,
+        params: SubgraphSetHistoryBlocksParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_set_history_blocks request"; "params" => format!("{:?}", params));
+
+        match self
+            .registrar
+            .set_history_blocks(params.ipfs_hash.clone(), params.history_blocks)
+            .await
+        {
+            Ok(_) => Ok(Value::Null),
+            Err(e) => Err(json_rpc_error(
+                &logger,
+                "subgraph_set_history_blocks",
+                e,
+                JSON_RPC_HISTORY_BLOCKS_ERROR,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_create_index` endpoint.
+    async fn create_index_handler(
+        &self,
+        params: SubgraphCreateIndexParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_create_index request"; "params" => format!("{:?}", params));
+
+        match self
+            .registrar
+            .create_index(
+                params.ipfs_hash.clone(),
+                params.entity.clone(),
+                params.fields.clone(),
+            )
+            .await
+        {
+            Ok(name) => Ok(serde_json::json!({ "index": name })),
+            Err(e) => Err(json_rpc_error(
+                &logger,
+                "subgraph_create_index",
+                e,
+                JSON_RPC_CREATE_INDEX_ERROR,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_drop_index` endpoint.
+    async fn drop_index_handler(
+        &self,
+        params: SubgraphDropIndexParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_drop_index request"; "params" => format!("{:?}", params));
+
+        match self
+            .registrar
+            .drop_index(params.ipfs_hash.clone(), params.index.clone())
+            .await
+        {
+            Ok(_) => Ok(Value::Null),
+            Err(e) => Err(json_rpc_error(
+                &logger,
+                "subgraph_drop_index",
+                e,
+                JSON_RPC_DROP_INDEX_ERROR,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_evolve_schema` endpoint.
+    async fn evolve_schema_handler(
+        &self,
+        params: SubgraphEvolveSchemaParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_evolve_schema request"; "params" => format!("{:?}", params));
+
+        match self
+            .registrar
+            .evolve_schema(params.ipfs_hash.clone(), params.schema.clone())
+            .await
+        {
+            Ok(_) => Ok(Value::Null),
+            Err(e) => Err(json_rpc_error(
+                &logger,
+                "subgraph_evolve_schema",
+                e,
+                JSON_RPC_EVOLVE_SCHEMA_ERROR,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_move` endpoint.
+    async fn move_handler(&self, params: SubgraphMoveParams) -> Result<Value, jsonrpc_core::Error> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_move request"; "params" => format!("{:?}", params));
+
+        match self
+            .registrar
+            .move_deployment(params.ipfs_hash.clone(), params.shard.clone())
+            .await
+        {
+            Ok(_) => Ok(Value::Null),
+            Err(e) => Err(json_rpc_error(
+                &logger,
+                "subgraph_move",
+                e,
+                JSON_RPC_MOVE_ERROR,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_move_rollback` endpoint.
+    async fn move_rollback_handler(
+        &self,
+        params: SubgraphMoveRollbackParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        info!(&self.logger, "Received subgraph_move_rollback request"; "params" => format!("{:?}", params));
+
+        match self.registrar.rollback_move(params.ipfs_hash.clone()).await {
+            Ok(_) => Ok(Value::Null),
+            Err(e) => Err(json_rpc_error(
+                &self.logger,
+                "subgraph_move_rollback",
+                e,
+                JSON_RPC_MOVE_ROLLBACK_ERROR,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_move_finalize` endpoint.
+    async fn move_finalize_handler(
+        &self,
+        params: SubgraphMoveFinalizeParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        info!(&self.logger, "Received subgraph_move_finalize request"; "params" => format!("{:?}", params));
+
+        match self.registrar.finalize_move(params.ipfs_hash.clone()).await {
+            Ok(_) => Ok(Value::Null),
+            Err(e) => Err(json_rpc_error(
+                &self.logger,
+                "subgraph_move_finalize",
+                e,
+                JSON_RPC_MOVE_FINALIZE_ERROR,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_allow_query` endpoint.
+    async fn allow_query_handler(
+        &self,
+        params: SubgraphAllowQueryParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_allow_query request"; "params" => format!("{:?}", params));
+
+        match check_target(&params.target).and_then(|()| {
+            self.persisted_queries
+                .allow(&params.target, params.query.clone())
+        }) {
+            Ok(hash) => Ok(serde_json::json!({ "hash": hash })),
+            Err(e) => Err(persisted_query_error(
+                &logger,
+                "subgraph_allow_query",
+                e,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_disallow_query` endpoint.
+    async fn disallow_query_handler(
+        &self,
+        params: SubgraphDisallowQueryParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_disallow_query request"; "params" => format!("{:?}", params));
+
+        match check_target(&params.target).and_then(|()| {
+            if self
+                .persisted_queries
+                .disallow(&params.target, &params.hash)?
+            {
+                Ok(())
+            } else {
+                Err(format_err!(
+                    "query is not on the allow-list: {}",
+                    params.hash
+                ))
+            }
+        }) {
+            Ok(()) => Ok(Value::Null),
+            Err(e) => Err(persisted_query_error(
+                &logger,
+                "subgraph_disallow_query",
+                e,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_set_allow_list_only` endpoint.
+    async fn set_allow_list_only_handler(
+        &self,
+        params: SubgraphSetAllowListOnlyParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_set_allow_list_only request"; "params" => format!("{:?}", params));
+
+        match check_target(&params.target).and_then(|()| {
+            self.persisted_queries
+                .set_allow_list_only(&params.target, params.enabled)
+        }) {
+            Ok(()) => Ok(Value::Null),
+            Err(e) => Err(persisted_query_error(
+                &logger,
+                "subgraph_set_allow_list_only",
+                e,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `subgraph_allow_list` endpoint.
+    async fn allow_list_handler(
+        &self,
+        params: SubgraphAllowListParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received subgraph_allow_list request"; "params" => format!("{:?}", params));
+
+        match check_target(&params.target) {
+            Ok(()) => {
+                let (allow_list_only, queries) = self.persisted_queries.allow_list(&params.target);
+                Ok(serde_json::json!({
+                    "allowListOnly": allow_list_only,
+                    "queries": queries,
+                }))
+            }
+            Err(e) => Err(persisted_query_error(
+                &logger,
+                "subgraph_allow_list",
+                e,
+                params,
+            )),
+        }
+    }
+
+    /// Handler for the `ipfs_seed_cache` endpoint.
+    async fn seed_cache_handler(
+        &self,
+        params: IpfsSeedCacheParams,
+    ) -> Result<Value, jsonrpc_core::Error> {
+        let logger = self.logger.clone();
+
+        info!(logger, "Received ipfs_seed_cache request"; "params" => format!("{:?}", params));
+
+        let result = match &self.ipfs_cache {
+            Some(cache) => cache.seed_from_dir(Path::new(&params.dir)),
+            None => Err(format_err!(
+                "this node does not cache IPFS files on disk; start it with `--ipfs-cache-dir`"
+            )),
+        };
+        match result {
+            Ok(count) => Ok(serde_json::json!({ "files": count })),
+            Err(e) => {
+                error!(logger, "ipfs_seed_cache failed";
+                    "error" => e.to_string(),
+                    "params" => format!("{:?}", params));
+                Err(jsonrpc_core::Error {
+                    code: jsonrpc_core::ErrorCode::ServerError(JSON_RPC_IPFS_CACHE_ERROR),
+                    message: e.to_string(),
+                    data: None,
+                })
+            }
+        }
+    }
 }
 
 impl<R> JsonRpcServerTrait<R> for JsonRpcServer<R>
@@ -164,6 +957,10 @@ where
         http_port: u16,
         ws_port: u16,
         registrar: Arc<R>,
+        persisted_queries: Arc<PersistedQueries>,
+        ipfs_cache: Option<Arc<dyn IpfsCache>>,
+        principals: AdminPrincipals,
+        audit_log: Arc<dyn AdminAuditLog>,
         node_id: NodeId,
         logger: Logger,
     ) -> Result<Self::Server, io::Error> {
@@ -174,12 +971,25 @@ where
             "Starting JSON-RPC admin server at: http://localhost:{}", port
         );
 
+        let auth = Auth::new(principals);
+        if !auth.is_enabled() {
+            warn!(
+                logger,
+                "The JSON-RPC admin server accepts requests from anyone; \
+                 set GRAPH_ADMIN_AUTH to require authentication"
+            );
+        }
+
         let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port);
 
-        let mut handler = IoHandler::with_compatibility(Compatibility::Both);
+        let mut handler = MetaIoHandler::with_compatibility(Compatibility::Both);
 
         let arc_self = Arc::new(JsonRpcServer {
             registrar,
+            persisted_queries,
+            ipfs_cache,
+            auth,
+            audit_log,
             http_port,
             ws_port,
             node_id,
@@ -217,66 +1027,424 @@ where
 
         let me = arc_self.clone();
         let sender = task_sender.clone();
-        handler.add_method("subgraph_create", move |params: Params| {
-            let me = me.clone();
-            Box::pin(tokio02_spawn(
-                sender.clone(),
-                async move {
-                    let params = params.parse()?;
-                    me.create_handler(params).await
-                }
-                .boxed(),
-            ))
-            .compat()
-        });
+        handler.add_method_with_meta(
+            "subgraph_create",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_create", &credentials, params)
+                            .await?;
+                        let result = me.create_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
 
         let me = arc_self.clone();
         let sender = task_sender.clone();
 
-        handler.add_method("subgraph_deploy", move |params: Params| {
-            let me = me.clone();
-            Box::pin(tokio02_spawn(
-                sender.clone(),
-                async move {
-                    let params = params.parse()?;
-                    me.deploy_handler(params).await
-                }
-                .boxed(),
-            ))
-            .compat()
-        });
+        handler.add_method_with_meta(
+            "subgraph_deploy",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_deploy", &credentials, params)
+                            .await?;
+                        let result = me.deploy_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
 
         let me = arc_self.clone();
         let sender = task_sender.clone();
-        handler.add_method("subgraph_remove", move |params: Params| {
-            let me = me.clone();
-            Box::pin(tokio02_spawn(
-                sender.clone(),
-                async move {
-                    let params = params.parse()?;
-                    me.remove_handler(params).await
-                }
-                .boxed(),
-            ))
-            .compat()
-        });
+        handler.add_method_with_meta(
+            "subgraph_remove",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_remove", &credentials, params)
+                            .await?;
+                        let result = me.remove_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
 
         let me = arc_self.clone();
         let sender = task_sender.clone();
-        handler.add_method("subgraph_reassign", move |params: Params| {
-            let me = me.clone();
-            Box::pin(tokio02_spawn(
-                sender.clone(),
-                async move {
-                    let params = params.parse()?;
-                    me.reassign_handler(params).await
-                }
-                .boxed(),
-            ))
-            .compat()
-        });
+        handler.add_method_with_meta(
+            "subgraph_reassign",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_reassign", &credentials, params)
+                            .await?;
+                        let result = me.reassign_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_pause",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) =
+                            me.authorize("subgraph_pause", &credentials, params).await?;
+                        let result = me.pause_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_resume",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_resume", &credentials, params)
+                            .await?;
+                        let result = me.resume_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_rewind",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_rewind", &credentials, params)
+                            .await?;
+                        let result = me.rewind_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_reindex",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_reindex", &credentials, params)
+                            .await?;
+                        let result = me.reindex_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_set_history_blocks",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_set_history_blocks", &credentials, params)
+                            .await?;
+                        let result = me.set_history_blocks_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_create_index",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_create_index", &credentials, params)
+                            .await?;
+                        let result = me.create_index_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_drop_index",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_drop_index", &credentials, params)
+                            .await?;
+                        let result = me.drop_index_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_evolve_schema",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_evolve_schema", &credentials, params)
+                            .await?;
+                        let result = me.evolve_schema_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_move",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) =
+                            me.authorize("subgraph_move", &credentials, params).await?;
+                        let result = me.move_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_move_rollback",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_move_rollback", &credentials, params)
+                            .await?;
+                        let result = me.move_rollback_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_move_finalize",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_move_finalize", &credentials, params)
+                            .await?;
+                        let result = me.move_finalize_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_allow_query",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_allow_query", &credentials, params)
+                            .await?;
+                        let result = me.allow_query_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_disallow_query",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_disallow_query", &credentials, params)
+                            .await?;
+                        let result = me.disallow_query_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
 
-        ServerBuilder::new(handler)
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_set_allow_list_only",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_set_allow_list_only", &credentials, params)
+                            .await?;
+                        let result = me.set_allow_list_only_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "subgraph_allow_list",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("subgraph_allow_list", &credentials, params)
+                            .await?;
+                        let result = me.allow_list_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        let me = arc_self.clone();
+        let sender = task_sender.clone();
+        handler.add_method_with_meta(
+            "ipfs_seed_cache",
+            move |params: Params, credentials: Credentials| {
+                let me = me.clone();
+                Box::pin(tokio02_spawn(
+                    sender.clone(),
+                    async move {
+                        let (call, params) = me
+                            .authorize("ipfs_seed_cache", &credentials, params)
+                            .await?;
+                        let result = me.seed_cache_handler(params).await;
+                        me.audit(call, result)
+                    }
+                    .boxed(),
+                ))
+                .compat()
+            },
+        );
+
+        ServerBuilder::with_meta_extractor(handler, Credentials::from_request)
             // Enable REST API:
             // POST /<method>/<param1>/<param2>
             .rest_api(RestApi::Secure)
@@ -308,6 +1476,35 @@ fn json_rpc_error(
     }
 }
 
+/// Check that `target` is a subgraph name or a deployment ID
+fn check_target(target: &str) -> Result<(), Error> {
+    if SubgraphDeploymentId::new(target).is_ok() || SubgraphName::new(target).is_ok() {
+        Ok(())
+    } else {
+        Err(format_err!(
+            "target is neither a subgraph name nor a deployment ID: {}",
+            target
+        ))
+    }
+}
+
+fn persisted_query_error(
+    logger: &Logger,
+    operation: &str,
+    e: Error,
+    params: impl std::fmt::Debug,
+) -> jsonrpc_core::Error {
+    error!(logger, "{} failed", operation;
+        "error" => format!("{:?}", e),
+        "params" => format!("{:?}", params));
+
+    jsonrpc_core::Error {
+        code: jsonrpc_core::ErrorCode::ServerError(JSON_RPC_PERSISTED_QUERY_ERROR),
+        message: e.to_string(),
+        data: None,
+    }
+}
+
 pub fn parse_response(response: Value) -> Result<(), jsonrpc_core::Error> {
     // serde deserialization of the `id` field to an `Id` struct is somehow
     // incompatible with the `arbitrary-precision` feature which we use, so we
@@ -2,16 +2,21 @@ use futures::future::IntoFuture;
 use futures::sync::mpsc;
 use futures03::stream::SplitStream;
 use graphql_parser::parse_query;
+use http::header::{HeaderMap, HeaderName, HeaderValue};
 use http::StatusCode;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::env;
 use std::str::FromStr;
+use std::time::Duration;
 use tokio::prelude::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
 use tokio_tungstenite::WebSocketStream;
 use uuid::Uuid;
 
+use graph::data::graphql::api_keys::{api_key, ApiClient, ApiKeys};
 use graph::prelude::*;
 
 lazy_static! {
@@ -21,6 +26,92 @@ lazy_static! {
             .map(|s| usize::from_str(&s).unwrap_or_else(|_| panic!(
                 "failed to parse env var GRAPH_GRAPHQL_MAX_OPERATIONS_PER_CONNECTION"
             )));
+
+    /// How often we send keep-alive messages to clients; `None` if we
+    /// should not send any
+    static ref KEEP_ALIVE_INTERVAL: Option<Duration> = {
+        let secs = env::var("GRAPH_GRAPHQL_WS_KEEP_ALIVE_INTERVAL")
+            .ok()
+            .map(|s| u64::from_str(&s).unwrap_or_else(|_| panic!(
+                "failed to parse env var GRAPH_GRAPHQL_WS_KEEP_ALIVE_INTERVAL"
+            )))
+            .unwrap_or(20);
+        if secs > 0 {
+            Some(Duration::from_secs(secs))
+        } else {
+            None
+        }
+    };
+
+    /// How long `graphql-transport-ws` clients have to send
+    /// `connection_init` after opening the connection
+    static ref CONNECTION_INIT_TIMEOUT: Duration = Duration::from_secs(
+        env::var("GRAPH_GRAPHQL_WS_CONNECTION_INIT_TIMEOUT")
+            .ok()
+            .map(|s| u64::from_str(&s).unwrap_or_else(|_| panic!(
+                "failed to parse env var GRAPH_GRAPHQL_WS_CONNECTION_INIT_TIMEOUT"
+            )))
+            .unwrap_or(3)
+    );
+}
+
+/// The GraphQL over WebSocket protocols we support. Clients choose one
+/// through the `Sec-WebSocket-Protocol` header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Protocol {
+    /// The protocol of the `subscriptions-transport-ws` library, which
+    /// calls itself `graphql-ws`
+    Legacy,
+    /// The `graphql-transport-ws` protocol of the `graphql-ws` library
+    TransportWs,
+}
+
+impl Protocol {
+    /// Pick the protocol for a connection from the protocols the client
+    /// offers. Clients that do not ask for a protocol we know get the
+    /// legacy protocol, since that is all we used to support
+    pub fn negotiate(requested: Option<&str>) -> Self {
+        let requested: Vec<_> = requested
+            .unwrap_or("")
+            .split(',')
+            .map(|protocol| protocol.trim())
+            .collect();
+        if requested.contains(&Protocol::TransportWs.name()) {
+            Protocol::TransportWs
+        } else {
+            Protocol::Legacy
+        }
+    }
+
+    /// The name of the protocol in the `Sec-WebSocket-Protocol` header
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Legacy => "graphql-ws",
+            Protocol::TransportWs => "graphql-transport-ws",
+        }
+    }
+
+    fn data(&self, id: String, payload: Arc<QueryResult>) -> OutgoingMessage {
+        match self {
+            Protocol::Legacy => OutgoingMessage::Data { id, payload },
+            Protocol::TransportWs => OutgoingMessage::Next { id, payload },
+        }
+    }
+
+    fn error(&self, id: String, message: String) -> OutgoingMessage {
+        let payload = match self {
+            Protocol::Legacy => serde_json::Value::String(message),
+            Protocol::TransportWs => serde_json::json!([{ "message": message }]),
+        };
+        OutgoingMessage::Error { id, payload }
+    }
+
+    fn keep_alive(&self) -> OutgoingMessage {
+        match self {
+            Protocol::Legacy => OutgoingMessage::KeepAlive,
+            Protocol::TransportWs => OutgoingMessage::Ping { payload: None },
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -29,9 +120,12 @@ struct StartPayload {
     query: String,
     variables: Option<serde_json::Value>,
     operation_name: Option<String>,
+    extensions: Option<serde_json::Value>,
 }
 
-/// GraphQL/WebSocket message received from a client.
+/// GraphQL/WebSocket message received from a client. `ConnectionTerminate`,
+/// `Start` and `Stop` only exist in the legacy protocol, `Subscribe`,
+/// `Complete`, `Ping` and `Pong` only in `graphql-transport-ws`
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum IncomingMessage {
@@ -39,6 +133,10 @@ enum IncomingMessage {
     ConnectionTerminate,
     Start { id: String, payload: StartPayload },
     Stop { id: String },
+    Subscribe { id: String, payload: StartPayload },
+    Complete { id: String },
+    Ping { payload: Option<serde_json::Value> },
+    Pong { payload: Option<serde_json::Value> },
 }
 
 impl IncomingMessage {
@@ -57,30 +155,34 @@ impl IncomingMessage {
 #[serde(tag = "type", rename_all = "snake_case")]
 enum OutgoingMessage {
     ConnectionAck,
+    ConnectionError {
+        payload: serde_json::Value,
+    },
+    #[serde(rename = "ka")]
+    KeepAlive,
     Error {
         id: String,
-        payload: String,
+        payload: serde_json::Value,
     },
     Data {
         id: String,
         payload: Arc<QueryResult>,
     },
+    Next {
+        id: String,
+        payload: Arc<QueryResult>,
+    },
     Complete {
         id: String,
     },
-}
-
-impl OutgoingMessage {
-    pub fn from_query_result(id: String, result: Arc<QueryResult>) -> Self {
-        OutgoingMessage::Data {
-            id: id,
-            payload: result,
-        }
-    }
-
-    pub fn from_error_string(id: String, s: String) -> Self {
-        OutgoingMessage::Error { id, payload: s }
-    }
+    Ping {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<serde_json::Value>,
+    },
+    Pong {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<serde_json::Value>,
+    },
 }
 
 impl From<OutgoingMessage> for WsMessage {
@@ -89,6 +191,23 @@ impl From<OutgoingMessage> for WsMessage {
     }
 }
 
+/// The API key in the payload of a `connection_init` message. Clients pass
+/// the key the same way as in the headers of an HTTP request, e.g., with
+/// `{ "Authorization": "Bearer <key>" }` or `{ "X-Api-Key": "<key>" }`
+fn api_key_from_payload(payload: &serde_json::Value) -> Option<String> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in payload.as_object().into_iter().flatten() {
+        let name = HeaderName::from_bytes(name.as_bytes()).ok();
+        let value = value
+            .as_str()
+            .and_then(|value| HeaderValue::from_str(value).ok());
+        if let (Some(name), Some(value)) = (name, value) {
+            headers.insert(name, value);
+        }
+    }
+    api_key(&headers).map(str::to_owned)
+}
+
 /// Helper function to send outgoing messages.
 fn send_message(
     sink: &mpsc::UnboundedSender<WsMessage>,
@@ -101,10 +220,25 @@ fn send_message(
 /// Helper function to send error messages.
 fn send_error_string(
     sink: &mpsc::UnboundedSender<WsMessage>,
+    protocol: Protocol,
     operation_id: String,
     error: String,
 ) -> Result<(), WsError> {
-    sink.unbounded_send(OutgoingMessage::from_error_string(operation_id, error).into())
+    send_message(sink, protocol.error(operation_id, error))
+}
+
+/// Helper function to close the connection with a `graphql-transport-ws`
+/// close code.
+fn send_close(
+    sink: &mpsc::UnboundedSender<WsMessage>,
+    code: u16,
+    reason: String,
+) -> Result<(), WsError> {
+    let frame = CloseFrame {
+        code: CloseCode::from(code),
+        reason: reason.into(),
+    };
+    sink.unbounded_send(WsMessage::Close(Some(frame)))
         .map_err(|_| WsError::Http(StatusCode::INTERNAL_SERVER_ERROR))
 }
 
@@ -113,13 +247,15 @@ fn send_error_string(
 struct Operations {
     operations: HashMap<String, CancelGuard>,
     msg_sink: mpsc::UnboundedSender<WsMessage>,
+    protocol: Protocol,
 }
 
 impl Operations {
-    fn new(msg_sink: mpsc::UnboundedSender<WsMessage>) -> Self {
+    fn new(msg_sink: mpsc::UnboundedSender<WsMessage>, protocol: Protocol) -> Self {
         Self {
             operations: HashMap::new(),
             msg_sink,
+            protocol,
         }
     }
 
@@ -148,11 +284,20 @@ impl Operations {
             }
             None => send_error_string(
                 &self.msg_sink,
+                self.protocol,
                 operation_id.clone(),
                 format!("Unknown operation ID: {}", operation_id),
             ),
         }
     }
+
+    /// Stop an operation that the client completed. Since the client
+    /// already knows that the operation is done, we do not tell it
+    fn complete(&mut self, operation_id: &str) {
+        if let Some(stopper) = self.operations.remove(operation_id) {
+            stopper.cancel();
+        }
+    }
 }
 
 impl Drop for Operations {
@@ -172,6 +317,11 @@ pub struct GraphQlConnection<Q, S> {
     graphql_runner: Arc<Q>,
     stream: WebSocketStream<S>,
     schema: Arc<ApiSchema>,
+    protocol: Protocol,
+    api_keys: Arc<ApiKeys>,
+    /// The client whose API key came with the request that opened the
+    /// connection (if any)
+    client: Option<Arc<ApiClient>>,
 }
 
 impl<Q, S> GraphQlConnection<Q, S>
@@ -185,16 +335,75 @@ where
         schema: Arc<ApiSchema>,
         stream: WebSocketStream<S>,
         graphql_runner: Arc<Q>,
+        protocol: Protocol,
+        api_keys: Arc<ApiKeys>,
+        client: Option<Arc<ApiClient>>,
     ) -> Self {
         GraphQlConnection {
             id: Uuid::new_v4().to_string(),
-            logger: logger.new(o!("component" => "GraphQlConnection")),
+            logger: logger.new(o!("component" => "GraphQlConnection",
+                                  "protocol" => protocol.name())),
             graphql_runner,
             stream,
             schema,
+            protocol,
+            api_keys,
+            client,
         }
     }
 
+    /// Parse the query and variables of a `start` or `subscribe` message.
+    /// Returns the error message for the client if they are invalid
+    fn subscription(
+        schema: &Arc<ApiSchema>,
+        payload: StartPayload,
+        client: Arc<ApiClient>,
+    ) -> Result<Subscription, String> {
+        // Parse the GraphQL query document
+        let query = parse_query(&payload.query)
+            .map_err(|e| format!("Invalid query: {}: {}", payload.query, e))?;
+
+        // Parse the query variables, if present
+        let variables = match payload.variables {
+            None | Some(serde_json::Value::Null) => None,
+            Some(variables @ serde_json::Value::Object(_)) => Some(
+                serde_json::from_value(variables)
+                    .map_err(|e| format!("Invalid variables provided: {}", e))?,
+            ),
+            _ => return Err("Invalid variables provided (must be an object)".to_owned()),
+        };
+
+        // Clients opt into receiving only changed entities with
+        // `"extensions": { "diff": true }`
+        let diff = payload
+            .extensions
+            .as_ref()
+            .and_then(|extensions| extensions.get("diff"))
+            .and_then(|diff| diff.as_bool())
+            .unwrap_or(false);
+
+        Ok(Subscription {
+            // Subscriptions currently do not benefit from the generational cache
+            // anyways, so don't bother passing a network.
+            query: Query::new(schema.clone(), query, variables, None),
+            diff,
+            client: Some(client),
+        })
+    }
+
+    /// Close the connection with a `graphql-transport-ws` close code and
+    /// ignore everything the client sends until it has closed its end, too
+    async fn close(
+        ws_stream: &mut SplitStream<WebSocketStream<S>>,
+        msg_sink: &mpsc::UnboundedSender<WsMessage>,
+        code: u16,
+        reason: String,
+    ) -> Result<(), WsError> {
+        send_close(msg_sink, code, reason)?;
+        while ws_stream.try_next().await?.is_some() {}
+        Ok(())
+    }
+
     async fn handle_incoming_messages(
         mut ws_stream: SplitStream<WebSocketStream<S>>,
         mut msg_sink: mpsc::UnboundedSender<WsMessage>,
@@ -202,30 +411,112 @@ where
         connection_id: String,
         schema: Arc<ApiSchema>,
         graphql_runner: Arc<Q>,
+        protocol: Protocol,
+        api_keys: Arc<ApiKeys>,
+        mut client: Option<Arc<ApiClient>>,
     ) -> Result<(), WsError> {
-        let mut operations = Operations::new(msg_sink.clone());
+        let mut operations = Operations::new(msg_sink.clone(), protocol);
+
+        // Whether a `graphql-transport-ws` client has sent `connection_init`;
+        // clients that do not do that in time get disconnected
+        let mut initialized = false;
+        let init_deadline = tokio::time::Instant::now() + *CONNECTION_INIT_TIMEOUT;
 
         // Process incoming messages as long as the WebSocket is open
-        while let Some(ws_msg) = ws_stream.try_next().await? {
+        loop {
             use self::IncomingMessage::*;
-            use self::OutgoingMessage::*;
+
+            let ws_msg = if protocol == Protocol::TransportWs && !initialized {
+                let next = tokio::time::timeout_at(init_deadline, ws_stream.try_next()).await;
+                match next {
+                    Ok(next) => next?,
+                    Err(_) => {
+                        return Self::close(
+                            &mut ws_stream,
+                            &msg_sink,
+                            4408,
+                            "Connection initialisation timeout".to_owned(),
+                        )
+                        .await;
+                    }
+                }
+            } else {
+                ws_stream.try_next().await?
+            };
+            let ws_msg = match ws_msg {
+                Some(ws_msg) => ws_msg,
+                None => break,
+            };
 
             debug!(logger, "Received message";
                    "connection" => &connection_id,
                    "msg" => format!("{}", ws_msg).as_str());
 
-            let msg = IncomingMessage::from_ws_message(ws_msg.clone())?;
+            let msg = match IncomingMessage::from_ws_message(ws_msg.clone()) {
+                Ok(msg) => msg,
+                Err(e) if protocol == Protocol::TransportWs => {
+                    return Self::close(&mut ws_stream, &msg_sink, 4400, e.to_string()).await;
+                }
+                Err(e) => return Err(e),
+            };
 
             debug!(logger, "GraphQL/WebSocket message";
                    "connection" => &connection_id,
                    "msg" => format!("{:?}", msg).as_str());
 
-            match msg {
-                // Always accept connection init requests
-                ConnectionInit { payload: _ } => send_message(&msg_sink, ConnectionAck),
+            match (protocol, msg) {
+                // Accept connection init requests in the legacy protocol
+                // unless the client's API key is not valid
+                (Protocol::Legacy, ConnectionInit { payload }) => {
+                    if client.is_none() {
+                        let key = payload.as_ref().and_then(api_key_from_payload);
+                        match api_keys.check_key(key.as_deref()) {
+                            Ok(checked) => client = Some(checked),
+                            Err(e) => {
+                                let payload = serde_json::json!({ "message": e.to_string() });
+                                send_message(
+                                    &msg_sink,
+                                    OutgoingMessage::ConnectionError { payload },
+                                )?;
+                                continue;
+                            }
+                        }
+                    }
+                    send_message(&msg_sink, OutgoingMessage::ConnectionAck)
+                }
+
+                // `graphql-transport-ws` clients must initialize exactly once
+                (Protocol::TransportWs, ConnectionInit { payload }) => {
+                    if initialized {
+                        return Self::close(
+                            &mut ws_stream,
+                            &msg_sink,
+                            4429,
+                            "Too many initialisation requests".to_owned(),
+                        )
+                        .await;
+                    }
+                    if client.is_none() {
+                        let key = payload.as_ref().and_then(api_key_from_payload);
+                        match api_keys.check_key(key.as_deref()) {
+                            Ok(checked) => client = Some(checked),
+                            Err(e) => {
+                                return Self::close(
+                                    &mut ws_stream,
+                                    &msg_sink,
+                                    4403,
+                                    format!("Forbidden: {}", e),
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                    initialized = true;
+                    send_message(&msg_sink, OutgoingMessage::ConnectionAck)
+                }
 
                 // When receiving a connection termination request
-                ConnectionTerminate => {
+                (Protocol::Legacy, ConnectionTerminate) => {
                     // Close the message sink
                     msg_sink.close().unwrap();
 
@@ -234,74 +525,96 @@ where
                 }
 
                 // When receiving a stop request
-                Stop { id } => operations.stop(id),
+                (Protocol::Legacy, Stop { id }) => operations.stop(id),
 
-                // When receiving a start request
-                Start { id, payload } => {
-                    // Respond with a GQL_ERROR if we already have an operation with this ID
-                    if operations.contains(&id) {
-                        return send_error_string(
-                            &msg_sink,
-                            id.clone(),
-                            format!("Operation with ID already started: {}", id),
-                        );
-                    }
+                // When the client is no longer interested in an operation
+                (Protocol::TransportWs, Complete { id }) => {
+                    operations.complete(&id);
+                    Ok(())
+                }
+
+                (Protocol::TransportWs, Ping { payload }) => {
+                    send_message(&msg_sink, OutgoingMessage::Pong { payload })
+                }
+                (Protocol::TransportWs, Pong { payload: _ }) => Ok(()),
+
+                (Protocol::TransportWs, Subscribe { .. }) if !initialized => {
+                    return Self::close(&mut ws_stream, &msg_sink, 4401, "Unauthorized".to_owned())
+                        .await;
+                }
 
+                (Protocol::TransportWs, Subscribe { id, .. }) if operations.contains(&id) => {
+                    return Self::close(
+                        &mut ws_stream,
+                        &msg_sink,
+                        4409,
+                        format!("Subscriber for {} already exists", id),
+                    )
+                    .await;
+                }
+
+                // Respond with a GQL_ERROR if we already have an operation with this ID
+                (Protocol::Legacy, Start { id, .. }) if operations.contains(&id) => {
+                    send_error_string(
+                        &msg_sink,
+                        protocol,
+                        id.clone(),
+                        format!("Operation with ID already started: {}", id),
+                    )
+                }
+
+                // When receiving a start or subscribe request
+                (Protocol::Legacy, Start { id, payload })
+                | (Protocol::TransportWs, Subscribe { id, payload }) => {
                     if let Some(max_ops) = *MAX_OPERATIONS_PER_CONNECTION {
                         if operations.operations.len() >= max_ops {
-                            return send_error_string(
+                            send_error_string(
                                 &msg_sink,
-                                id.clone(),
+                                protocol,
+                                id,
                                 format!(
                                     "Reached the limit of {} operations per connection",
                                     max_ops
                                 ),
-                            );
+                            )?;
+                            continue;
                         }
                     }
 
-                    // Parse the GraphQL query document; respond with a GQL_ERROR if
-                    // the query is invalid
-                    let query = match parse_query(&payload.query) {
-                        Ok(query) => query,
-                        Err(e) => {
-                            return send_error_string(
-                                &msg_sink,
-                                id.clone(),
-                                format!("Invalid query: {}: {}", payload.query, e),
-                            );
-                        }
+                    // Legacy clients do not have to send `connection_init`;
+                    // without it, they count as sending no API key
+                    let checked = match &client {
+                        Some(client) => client.clone(),
+                        None => match api_keys.check_key(None) {
+                            Ok(checked) => {
+                                client = Some(checked.clone());
+                                checked
+                            }
+                            Err(e) => {
+                                send_error_string(&msg_sink, protocol, id, e.to_string())?;
+                                continue;
+                            }
+                        },
                     };
 
-                    // Parse the query variables, if present
-                    let variables = match payload.variables {
-                        None | Some(serde_json::Value::Null) => None,
-                        Some(variables @ serde_json::Value::Object(_)) => {
-                            match serde_json::from_value(variables.clone()) {
-                                Ok(variables) => Some(variables),
-                                Err(e) => {
-                                    return send_error_string(
-                                        &msg_sink,
-                                        id.clone(),
-                                        format!("Invalid variables provided: {}", e),
-                                    );
-                                }
-                            }
-                        }
-                        _ => {
-                            return send_error_string(
-                                &msg_sink,
-                                id.clone(),
-                                format!("Invalid variables provided (must be an object)"),
-                            );
+                    // Each subscription counts against the limits of the
+                    // client's API key for as long as it runs
+                    let permit = match checked.subscribe() {
+                        Ok(permit) => permit,
+                        Err(e) => {
+                            send_error_string(&msg_sink, protocol, id, e.to_string())?;
+                            continue;
                         }
                     };
 
-                    // Construct a subscription
-                    let subscription = Subscription {
-                        // Subscriptions currently do not benefit from the generational cache
-                        // anyways, so don't bother passing a network.
-                        query: Query::new(schema.clone(), query, variables, None),
+                    // Respond with a GQL_ERROR if the query or the
+                    // variables are invalid
+                    let subscription = match Self::subscription(&schema, payload, checked) {
+                        Ok(subscription) => subscription,
+                        Err(e) => {
+                            send_error_string(&msg_sink, protocol, id, e)?;
+                            continue;
+                        }
                     };
 
                     debug!(logger, "Start operation";
@@ -335,10 +648,7 @@ where
                                         .any(|err| matches!(err, QueryExecutionError::TooExpensive))
                                     {
                                         let result = Arc::new(QueryResult::from(e));
-                                        let msg = OutgoingMessage::from_query_result(
-                                            err_id.clone(),
-                                            result,
-                                        );
+                                        let msg = protocol.data(err_id.clone(), result);
                                         error_sink.unbounded_send(msg.into()).unwrap();
                                     }
                                 }
@@ -348,7 +658,8 @@ where
                             // Send results back to the client as GQL_DATA
                             result_stream
                                 .map(move |result| {
-                                    OutgoingMessage::from_query_result(result_id.clone(), result)
+                                    let _ = &permit;
+                                    protocol.data(result_id.clone(), result)
                                 })
                                 .map(WsMessage::from)
                                 .map(Ok)
@@ -372,6 +683,20 @@ where
                     graph::spawn_allow_panic(run_subscription.compat());
                     Ok(())
                 }
+
+                // Messages that belong to the other protocol
+                (Protocol::Legacy, msg) => Err(WsError::Protocol(
+                    format!("Unexpected GraphQL over WebSocket message: {:?}", msg).into(),
+                )),
+                (Protocol::TransportWs, msg) => {
+                    return Self::close(
+                        &mut ws_stream,
+                        &msg_sink,
+                        4400,
+                        format!("Unexpected message: {:?}", msg),
+                    )
+                    .await;
+                }
             }?
         }
         Ok(())
@@ -404,10 +729,28 @@ where
             self.id.clone(),
             self.schema.clone(),
             self.graphql_runner.clone(),
+            self.protocol,
+            self.api_keys.clone(),
+            self.client.clone(),
         );
 
+        // Keep the connection alive by sending messages to the client
+        // periodically; proxies and load balancers close idle connections
+        let protocol = self.protocol;
+        let keep_alive: Box<dyn Stream<Item = WsMessage, Error = ()> + Send> =
+            match *KEEP_ALIVE_INTERVAL {
+                Some(interval) => Box::new(
+                    tokio::time::interval_at(tokio::time::Instant::now() + interval, interval)
+                        .map(move |_| Ok::<_, ()>(WsMessage::from(protocol.keep_alive())))
+                        .compat(),
+                ),
+                None => Box::new(stream::empty()),
+            };
+
         // Send outgoing messages asynchronously
-        let ws_writer = msg_stream.forward(ws_sink.compat().sink_map_err(|_| ()));
+        let ws_writer = msg_stream
+            .select(keep_alive)
+            .forward(ws_sink.compat().sink_map_err(|_| ()));
 
         // Silently swallow internal send results and errors. There is nothing
         // we can do about these errors ourselves. Clients will be disconnected
@@ -426,3 +769,213 @@ where
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::data::graphql::api_keys::ApiKeyConfigs;
+    use graph::data::graphql::effort::LoadManager;
+    use graph_mock::MockMetricsRegistry;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// A runner for connections that never get as far as running anything
+    struct NoRunner;
+
+    #[async_trait]
+    impl GraphQlRunner for NoRunner {
+        async fn run_query(self: Arc<Self>, _query: Query) -> Arc<QueryResult> {
+            unimplemented!()
+        }
+
+        async fn run_query_with_complexity(
+            self: Arc<Self>,
+            _query: Query,
+            _max_complexity: Option<u64>,
+            _max_depth: Option<u8>,
+            _max_first: Option<u32>,
+            _max_skip: Option<u32>,
+        ) -> Arc<QueryResult> {
+            unimplemented!()
+        }
+
+        async fn run_subscription(
+            self: Arc<Self>,
+            _subscription: Subscription,
+        ) -> Result<SubscriptionResult, SubscriptionError> {
+            unimplemented!()
+        }
+
+        fn load_manager(&self) -> Arc<LoadManager> {
+            unimplemented!()
+        }
+    }
+
+    /// Open a `graphql-transport-ws` connection and return the client's
+    /// end of it
+    async fn transport_ws_client() -> WebSocketStream<TcpStream> {
+        let logger = Logger::root(slog::Discard, o!());
+        let id = SubgraphDeploymentId::new("connection").unwrap();
+        let schema = Schema::parse("type Query { name: String }", id).unwrap();
+        let schema = Arc::new(ApiSchema::from_api_schema(schema).unwrap());
+        let api_keys = Arc::new(ApiKeys::with_required(
+            &logger,
+            Arc::new(MockMetricsRegistry::new()),
+            ApiKeyConfigs::new(),
+            false,
+        ));
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        graph::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            GraphQlConnection::new(
+                &logger,
+                schema,
+                stream,
+                Arc::new(NoRunner),
+                Protocol::TransportWs,
+                api_keys,
+                None,
+            )
+            .into_future()
+            .compat()
+            .await
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        tokio_tungstenite::client_async(format!("ws://{}/", addr), stream)
+            .await
+            .unwrap()
+            .0
+    }
+
+    /// The next message the server sends, or the code with which it
+    /// closes the connection
+    async fn receive(client: &mut WebSocketStream<TcpStream>) -> Result<String, u16> {
+        loop {
+            match client.next().await.unwrap().unwrap() {
+                WsMessage::Text(text) => return Ok(text),
+                WsMessage::Close(Some(frame)) => return Err(frame.code.into()),
+                _ => continue,
+            }
+        }
+    }
+
+    async fn send(client: &mut WebSocketStream<TcpStream>, msg: &str) {
+        client.send(WsMessage::text(msg)).await.unwrap();
+    }
+
+    const INIT: &str = r#"{"type": "connection_init"}"#;
+    const SUBSCRIBE: &str =
+        r#"{"type": "subscribe", "id": "1", "payload": {"query": "subscription { name }"}}"#;
+
+    #[tokio::test]
+    async fn transport_ws_acknowledges_init() {
+        let mut client = transport_ws_client().await;
+        send(&mut client, INIT).await;
+        assert_eq!(
+            Ok(r#"{"type":"connection_ack"}"#.to_owned()),
+            receive(&mut client).await
+        );
+        send(&mut client, r#"{"type": "ping"}"#).await;
+        assert_eq!(
+            Ok(r#"{"type":"pong"}"#.to_owned()),
+            receive(&mut client).await
+        );
+    }
+
+    #[tokio::test]
+    async fn transport_ws_rejects_duplicate_init() {
+        let mut client = transport_ws_client().await;
+        send(&mut client, INIT).await;
+        assert!(receive(&mut client).await.is_ok());
+        send(&mut client, INIT).await;
+        assert_eq!(Err(4429), receive(&mut client).await);
+    }
+
+    #[tokio::test]
+    async fn transport_ws_rejects_subscribe_before_ack() {
+        let mut client = transport_ws_client().await;
+        send(&mut client, SUBSCRIBE).await;
+        assert_eq!(Err(4401), receive(&mut client).await);
+    }
+
+    #[tokio::test]
+    async fn transport_ws_times_out_without_init() {
+        let mut client = transport_ws_client().await;
+        assert_eq!(Err(4408), receive(&mut client).await);
+    }
+
+    #[test]
+    fn negotiates_protocol() {
+        assert_eq!(Protocol::Legacy, Protocol::negotiate(None));
+        assert_eq!(Protocol::Legacy, Protocol::negotiate(Some("graphql-ws")));
+        assert_eq!(
+            Protocol::Legacy,
+            Protocol::negotiate(Some("something-else"))
+        );
+        assert_eq!(
+            Protocol::TransportWs,
+            Protocol::negotiate(Some("graphql-transport-ws"))
+        );
+        assert_eq!(
+            Protocol::TransportWs,
+            Protocol::negotiate(Some("graphql-ws, graphql-transport-ws"))
+        );
+    }
+
+    #[test]
+    fn serializes_messages_for_protocol() {
+        let json = |msg: OutgoingMessage| serde_json::to_value(&msg).unwrap();
+
+        assert_eq!(
+            serde_json::json!({ "type": "error", "id": "1", "payload": "boom" }),
+            json(Protocol::Legacy.error("1".to_owned(), "boom".to_owned()))
+        );
+        assert_eq!(
+            serde_json::json!({ "type": "error", "id": "1", "payload": [{ "message": "boom" }] }),
+            json(Protocol::TransportWs.error("1".to_owned(), "boom".to_owned()))
+        );
+        assert_eq!(
+            serde_json::json!({ "type": "ka" }),
+            json(Protocol::Legacy.keep_alive())
+        );
+        assert_eq!(
+            serde_json::json!({ "type": "ping" }),
+            json(Protocol::TransportWs.keep_alive())
+        );
+    }
+
+    #[test]
+    fn reads_api_key_from_connection_init() {
+        let key = |payload: serde_json::Value| api_key_from_payload(&payload);
+
+        assert_eq!(
+            Some("secret".to_owned()),
+            key(serde_json::json!({ "Authorization": "Bearer secret" }))
+        );
+        assert_eq!(
+            Some("secret".to_owned()),
+            key(serde_json::json!({ "x-api-key": "secret" }))
+        );
+        assert_eq!(None, key(serde_json::json!({ "Authorization": 42 })));
+        assert_eq!(None, key(serde_json::json!("secret")));
+    }
+
+    #[test]
+    fn parses_transport_ws_messages() {
+        let msg = WsMessage::text(
+            r#"{"type": "subscribe", "id": "1", "payload": {"query": "subscription { users { id } }"}}"#,
+        );
+        assert!(matches!(
+            IncomingMessage::from_ws_message(msg),
+            Ok(IncomingMessage::Subscribe { id, .. }) if id == "1"
+        ));
+        let msg = WsMessage::text(r#"{"type": "ping"}"#);
+        assert!(matches!(
+            IncomingMessage::from_ws_message(msg),
+            Ok(IncomingMessage::Ping { payload: None })
+        ));
+    }
+}
@@ -1,3 +1,5 @@
+use graph::components::server::query::GraphQLServerError;
+use graph::data::graphql::api_keys::{api_key, ApiKeys};
 use graph::data::subgraph::schema::SUBGRAPHS_ID;
 use graph::prelude::{SubscriptionServer as SubscriptionServerTrait, *};
 use http::{HeaderValue, Response, StatusCode};
@@ -7,13 +9,14 @@ use tokio::net::TcpListener;
 use tokio_tungstenite::accept_hdr_async;
 use tokio_tungstenite::tungstenite::handshake::server::Request;
 
-use crate::connection::GraphQlConnection;
+use crate::connection::{GraphQlConnection, Protocol};
 
 /// A GraphQL subscription server based on Hyper / Websockets.
 pub struct SubscriptionServer<Q, S> {
     logger: Logger,
     graphql_runner: Arc<Q>,
     store: Arc<S>,
+    api_keys: Arc<ApiKeys>,
 }
 
 impl<Q, S> SubscriptionServer<Q, S>
@@ -21,11 +24,17 @@ where
     Q: GraphQlRunner,
     S: SubgraphDeploymentStore + Store,
 {
-    pub fn new(logger: &Logger, graphql_runner: Arc<Q>, store: Arc<S>) -> Self {
+    pub fn new(
+        logger: &Logger,
+        graphql_runner: Arc<Q>,
+        store: Arc<S>,
+        api_keys: Arc<ApiKeys>,
+    ) -> Self {
         SubscriptionServer {
             logger: logger.new(o!("component" => "SubscriptionServer")),
             graphql_runner,
             store,
+            api_keys,
         }
     }
 
@@ -100,12 +109,37 @@ where
             let graphql_runner = self.graphql_runner.clone();
             let store = self.store.clone();
             let store2 = self.store.clone();
+            let api_keys = self.api_keys.clone();
+            let api_keys2 = self.api_keys.clone();
+
+            // The client whose API key the request came with (if any)
+            let client = Arc::new(Mutex::new(None));
+            let accept_client = client.clone();
 
             // Subgraph that the request is resolved to (if any)
             let subgraph_id = Arc::new(Mutex::new(None));
             let accept_subgraph_id = subgraph_id.clone();
 
+            // Protocol that the client and we agreed on
+            let protocol = Arc::new(Mutex::new(Protocol::Legacy));
+            let accept_protocol = protocol.clone();
+
             accept_hdr_async(stream, move |request: &Request, mut response: Response<()>| {
+                // Turn away clients with an invalid API key, or that have
+                // exceeded their limits. Browsers can not set headers for
+                // WebSockets; clients that send no key here can send it
+                // with `connection_init`
+                if let Some(key) = api_key(request.headers()) {
+                    let client = api_keys.check_key(Some(key)).map_err(|e| {
+                        let status = match e {
+                            GraphQLServerError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+                            _ => StatusCode::TOO_MANY_REQUESTS,
+                        };
+                        Response::builder().status(status).body(Some(e.to_string())).unwrap()
+                    })?;
+                    *accept_client.lock().unwrap() = Some(client);
+                }
+
                 // Try to obtain the subgraph ID or name from the URL path.
                 // Return a 404 if the URL path contains no name/ID segment.
                 let path = request.uri().path();
@@ -136,7 +170,15 @@ where
                 }
 
                 *accept_subgraph_id.lock().unwrap() = Some(subgraph_id);
-                response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static("graphql-ws"));
+
+                // Pick the protocol from the ones that the client offers
+                let requested = request
+                    .headers()
+                    .get("Sec-WebSocket-Protocol")
+                    .and_then(|protocols| protocols.to_str().ok());
+                let protocol = Protocol::negotiate(requested);
+                *accept_protocol.lock().unwrap() = protocol;
+                response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(protocol.name()));
                 Ok(response)
             })
             .then(move |result| async move {
@@ -144,6 +186,8 @@ where
                     Ok(ws_stream) => {
                         // Obtain the subgraph ID or name that we resolved the request to
                         let subgraph_id = subgraph_id.lock().unwrap().clone().unwrap();
+                        let protocol = *protocol.lock().unwrap();
+                        let client = client.lock().unwrap().take();
 
                         // Get the subgraph schema
                         let schema = match store2.api_schema(&subgraph_id) {
@@ -163,6 +207,9 @@ where
                             schema,
                             ws_stream,
                             graphql_runner.clone(),
+                            protocol,
+                            api_keys2.clone(),
+                            client,
                         );
 
                         graph::spawn_allow_panic(service.into_future().compat());
@@ -0,0 +1,10 @@
+-- The number of blocks of history to keep for a deployment; if it is null,
+-- history is never removed. These columns are not part of the GraphQL
+-- schema for subgraph metadata since they are only used by the store
+alter table subgraphs.subgraph_deployment
+  add column history_blocks int4;
+
+-- All entity versions that ended before this block have been removed, and
+-- queries for earlier blocks are rejected
+alter table subgraphs.subgraph_deployment
+  add column pruned_block int4 not null default 0;
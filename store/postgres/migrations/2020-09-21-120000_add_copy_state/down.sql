@@ -0,0 +1,2 @@
+drop table subgraphs.copy_table_state;
+drop table subgraphs.copy_state;
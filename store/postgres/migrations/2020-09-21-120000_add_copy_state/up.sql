@@ -0,0 +1,32 @@
+-- Progress of copying the data of a graft base into a new deployment.
+-- Data is copied in batches, each in its own transaction, so that copying
+-- can resume where it left off when it gets interrupted
+create table subgraphs.copy_state(
+  dst                 text primary key,
+  src                 text not null,
+  target_block_hash   bytea not null,
+  target_block_number int4 not null,
+  started_at          timestamptz not null default now(),
+  -- Set once all data has been copied and the deployment has been rewound
+  -- to the target block
+  finished_at         timestamptz
+);
+
+create table subgraphs.copy_table_state(
+  id                  serial primary key,
+  dst                 text not null
+                      references subgraphs.copy_state(dst) on delete cascade,
+  entity_type         text not null,
+  -- All rows in the source table with a vid less than next_vid have been
+  -- copied
+  next_vid            int8 not null,
+  -- The largest vid in the source table when copying started
+  target_vid          int8 not null,
+  batch_size          int8 not null,
+  rows_copied         int8 not null default 0,
+  -- Time spent copying this table so far
+  duration_ms         int8 not null default 0,
+  started_at          timestamptz not null default now(),
+  finished_at         timestamptz,
+  unique(dst, entity_type)
+);
@@ -0,0 +1,2 @@
+drop table public.deployment_moves;
+alter table public.deployment_schemas drop column shard;
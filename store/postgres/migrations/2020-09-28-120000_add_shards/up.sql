@@ -0,0 +1,21 @@
+-- The database (shard) in which the data of a deployment is stored. All
+-- metadata, including this table, is stored in the primary
+alter table public.deployment_schemas
+  add column shard text not null default 'primary';
+
+-- Deployments that are being moved to another shard. The data is copied
+-- into the schema `name` in `shard` while the deployment keeps indexing;
+-- when the copy has caught up, `deployment_schemas` is changed to point
+-- to it and the row here is removed
+create table public.deployment_moves(
+  subgraph            text primary key,
+  shard               text not null,
+  name                text not null,
+  -- The copy contains the data of the deployment as of this block
+  target_block_hash   bytea not null,
+  target_block_number int4 not null,
+  -- Set when the deployment was reverted past `target_block_number`
+  -- while it was being copied; the copy can not be used then
+  invalidated         bool not null default false,
+  started_at          timestamptz not null default now()
+);
@@ -0,0 +1 @@
+drop table subgraphs.admin_audit_log;
@@ -0,0 +1,15 @@
+-- Requests to the JSON-RPC admin server, including the ones that were
+-- refused. `principal` is null if the admin server does not check who
+-- sends requests, or could not tell who sent a refused request
+create table subgraphs.admin_audit_log(
+  id         serial primary key,
+  principal  text,
+  method     text not null,
+  params     jsonb not null,
+  -- Why the request failed or was refused; null if it succeeded
+  error      text,
+  created_at timestamptz not null default now()
+);
+
+create index admin_audit_log_principal
+  on subgraphs.admin_audit_log(principal, created_at);
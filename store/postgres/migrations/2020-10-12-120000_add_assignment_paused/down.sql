@@ -0,0 +1 @@
+alter table subgraphs.subgraph_deployment_assignment drop column paused;
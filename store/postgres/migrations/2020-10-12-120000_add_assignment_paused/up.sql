@@ -0,0 +1,4 @@
+-- Paused deployments stay assigned to their node, but the node does not
+-- index them
+alter table subgraphs.subgraph_deployment_assignment
+  add column paused boolean;
@@ -0,0 +1,10 @@
+-- Writes to a deployment whose data is not in the primary are committed
+-- with a two-phase commit: the transaction in the deployment's shard is
+-- prepared with the global id `gid`, and that id is recorded here in the
+-- same transaction that changes the deployment's metadata. A prepared
+-- transaction whose id is in this table must be committed, one whose id
+-- is not must be rolled back
+create table public.deployment_prepared_transactions(
+  gid        text primary key,
+  subgraph   text not null
+);
@@ -0,0 +1,11 @@
+-- The data a deployment had in its previous shard before it was moved.
+-- It is kept so that a move can be rolled back until it is dropped
+-- explicitly. The data is as of block `block_number`
+create table public.deployment_retired_sites(
+  subgraph            text primary key,
+  shard               text not null,
+  name                text not null,
+  block_hash          bytea not null,
+  block_number        int4 not null,
+  retired_at          timestamptz not null default now()
+);
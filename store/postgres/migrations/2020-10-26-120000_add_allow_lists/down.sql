@@ -0,0 +1,2 @@
+drop table subgraphs.allow_list_only;
+drop table subgraphs.allowed_queries;
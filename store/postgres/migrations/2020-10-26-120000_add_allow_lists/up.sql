@@ -0,0 +1,17 @@
+-- The queries that operators allowed for a subgraph name or deployment ID
+-- through the admin server. Query nodes reload all allow-lists when they
+-- get a notification on the `allow_lists` channel
+create table subgraphs.allowed_queries(
+  target     text not null,
+  -- The SHA-256 hash of the query in hex
+  hash       text not null,
+  query      text not null,
+  created_at timestamptz not null default now(),
+  primary key(target, hash)
+);
+
+-- Targets that reject all queries that are not on their allow-list
+create table subgraphs.allow_list_only(
+  target     text primary key,
+  enabled    boolean not null
+);
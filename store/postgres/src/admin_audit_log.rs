@@ -0,0 +1,45 @@
+//! The audit log of the JSON-RPC admin server, stored in
+//! `subgraphs.admin_audit_log`
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::{insert_into, ExpressionMethods, RunQueryDsl};
+
+use graph::prelude::{AdminAuditEntry, AdminAuditLog as AdminAuditLogTrait, Error};
+
+table! {
+    subgraphs.admin_audit_log(id) {
+        id -> Integer,
+        principal -> Nullable<Text>,
+        method -> Text,
+        params -> Jsonb,
+        error -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+pub struct AdminAuditLog {
+    pool: Pool<ConnectionManager<PgConnection>>,
+}
+
+impl AdminAuditLog {
+    pub fn new(pool: Pool<ConnectionManager<PgConnection>>) -> Self {
+        AdminAuditLog { pool }
+    }
+}
+
+impl AdminAuditLogTrait for AdminAuditLog {
+    fn record(&self, entry: AdminAuditEntry) -> Result<(), Error> {
+        use admin_audit_log as a;
+
+        let conn = self.pool.get()?;
+        insert_into(a::table)
+            .values((
+                a::principal.eq(entry.principal),
+                a::method.eq(entry.method),
+                a::params.eq(entry.params),
+                a::error.eq(entry.error),
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+//! Allow-lists for persisted queries, stored in `subgraphs.allowed_queries`
+//! and `subgraphs.allow_list_only`. Every change is announced on the
+//! `allow_lists` channel so that all query nodes reload the allow-lists
+use diesel::pg::upsert::excluded;
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::{delete, insert_into, Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+use std::sync::Mutex;
+
+use graph::data::graphql::persisted::{
+    AllowList, AllowListStore as AllowListStoreTrait, AllowLists,
+};
+use graph::prelude::{serde_json, Error, EventProducer, Logger, Stream};
+
+use crate::notification_listener::{JsonNotification, NotificationListener, SafeChannelName};
+
+const CHANNEL: &str = "allow_lists";
+
+table! {
+    subgraphs.allowed_queries(target, hash) {
+        target -> Text,
+        hash -> Text,
+        query -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    subgraphs.allow_list_only(target) {
+        target -> Text,
+        enabled -> Bool,
+    }
+}
+
+pub struct AllowListStore {
+    pool: Pool<ConnectionManager<PgConnection>>,
+    listener: Mutex<NotificationListener>,
+}
+
+impl AllowListStore {
+    pub fn new(
+        logger: &Logger,
+        pool: Pool<ConnectionManager<PgConnection>>,
+        postgres_url: String,
+    ) -> Self {
+        let listener = NotificationListener::new(
+            logger,
+            postgres_url,
+            SafeChannelName::i_promise_this_is_safe(CHANNEL),
+        );
+        AllowListStore {
+            pool,
+            listener: Mutex::new(listener),
+        }
+    }
+
+    /// Run `f` in a transaction that also tells all nodes that the
+    /// allow-lists changed
+    fn change<T>(&self, f: impl FnOnce(&PgConnection) -> Result<T, Error>) -> Result<T, Error> {
+        let conn = self.pool.get()?;
+        conn.transaction(|| {
+            let res = f(&conn)?;
+            JsonNotification::send(CHANNEL, &serde_json::json!({}), &conn)?;
+            Ok(res)
+        })
+    }
+}
+
+impl AllowListStoreTrait for AllowListStore {
+    fn allow_lists(&self) -> Result<AllowLists, Error> {
+        use allow_list_only as o;
+        use allowed_queries as q;
+
+        let conn = self.pool.get()?;
+        let mut allow_lists = AllowLists::new();
+        for (target, query) in q::table
+            .select((q::target, q::query))
+            .order_by((q::target, q::created_at))
+            .load::<(String, String)>(&conn)?
+        {
+            allow_lists
+                .entry(target)
+                .or_insert_with(AllowList::default)
+                .queries
+                .push(query);
+        }
+        for (target, enabled) in o::table
+            .select((o::target, o::enabled))
+            .load::<(String, bool)>(&conn)?
+        {
+            allow_lists
+                .entry(target)
+                .or_insert_with(AllowList::default)
+                .allow_list_only = enabled;
+        }
+        Ok(allow_lists)
+    }
+
+    fn allow(&self, target: &str, hash: &str, query: &str) -> Result<(), Error> {
+        use allowed_queries as q;
+
+        self.change(|conn| {
+            insert_into(q::table)
+                .values((q::target.eq(target), q::hash.eq(hash), q::query.eq(query)))
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    fn disallow(&self, target: &str, hash: &str) -> Result<bool, Error> {
+        use allowed_queries as q;
+
+        self.change(|conn| {
+            let count = delete(
+                q::table
+                    .filter(q::target.eq(target))
+                    .filter(q::hash.eq(hash)),
+            )
+            .execute(conn)?;
+            Ok(count > 0)
+        })
+    }
+
+    fn set_allow_list_only(&self, target: &str, enabled: bool) -> Result<(), Error> {
+        use allow_list_only as o;
+
+        self.change(|conn| {
+            insert_into(o::table)
+                .values((o::target.eq(target), o::enabled.eq(enabled)))
+                .on_conflict(o::target)
+                .do_update()
+                .set(o::enabled.eq(excluded(o::enabled)))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    fn changes(&self) -> Box<dyn Stream<Item = (), Error = ()> + Send> {
+        let mut listener = self.listener.lock().unwrap();
+        let changes = listener
+            .take_event_stream()
+            .expect("the changes to allow-lists can only be taken once");
+        listener.start();
+        Box::new(changes.map(|_| ()))
+    }
+}
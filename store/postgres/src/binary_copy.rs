@@ -0,0 +1,207 @@
+//! Encode rows in the binary format that `copy .. from stdin (format
+//! binary)` expects. The format is described in the Postgres documentation
+//! for `COPY`; arrays use the format of Postgres' `array_send`
+//!
+//! Diesel has no support for the `COPY` protocol, and data encoded here
+//! has to be sent with the `postgres` crate
+use std::convert::TryFrom;
+
+/// The signature every binary `COPY` stream starts with
+const SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+
+/// The Postgres types we can encode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CopyType {
+    Bool,
+    Bytea,
+    Int4,
+    Text,
+}
+
+impl CopyType {
+    /// The OID of the type, which arrays need to identify the type of
+    /// their elements
+    fn oid(self) -> u32 {
+        match self {
+            CopyType::Bool => 16,
+            CopyType::Bytea => 17,
+            CopyType::Int4 => 23,
+            CopyType::Text => 25,
+        }
+    }
+}
+
+/// A single non-null value
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Datum {
+    Bool(bool),
+    Bytea(Vec<u8>),
+    Int4(i32),
+    Text(String),
+}
+
+impl Datum {
+    fn copy_type(&self) -> CopyType {
+        match self {
+            Datum::Bool(_) => CopyType::Bool,
+            Datum::Bytea(_) => CopyType::Bytea,
+            Datum::Int4(_) => CopyType::Int4,
+            Datum::Text(_) => CopyType::Text,
+        }
+    }
+}
+
+/// One field of a row
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Field {
+    Null,
+    Scalar(Datum),
+    /// A one-dimensional array whose elements all have type `CopyType`;
+    /// `None` is an element that is `null`
+    Array(CopyType, Vec<Option<Datum>>),
+}
+
+/// Accumulates rows in the binary `COPY` format
+pub(crate) struct BinaryCopyWriter {
+    buf: Vec<u8>,
+}
+
+impl BinaryCopyWriter {
+    pub fn new() -> Self {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(SIGNATURE);
+        // Flags, and the length of the header extension
+        buf.extend_from_slice(&0i32.to_be_bytes());
+        buf.extend_from_slice(&0i32.to_be_bytes());
+        BinaryCopyWriter { buf }
+    }
+
+    pub fn write_row(&mut self, fields: &[Field]) {
+        let count = i16::try_from(fields.len()).expect("Postgres tables have at most 1600 columns");
+        self.buf.extend_from_slice(&count.to_be_bytes());
+        for field in fields {
+            match field {
+                Field::Null => self.buf.extend_from_slice(&(-1i32).to_be_bytes()),
+                Field::Scalar(datum) => {
+                    let mut data = Vec::new();
+                    encode(datum, &mut data);
+                    self.write_len_prefixed(&data);
+                }
+                Field::Array(elem_type, elems) => {
+                    let mut data = Vec::new();
+                    encode_array(*elem_type, elems, &mut data);
+                    self.write_len_prefixed(&data);
+                }
+            }
+        }
+    }
+
+    /// Add the trailer to the data and return it
+    pub fn finish(mut self) -> Vec<u8> {
+        self.buf.extend_from_slice(&(-1i16).to_be_bytes());
+        self.buf
+    }
+
+    fn write_len_prefixed(&mut self, data: &[u8]) {
+        write_len_prefixed(data, &mut self.buf);
+    }
+}
+
+fn write_len_prefixed(data: &[u8], buf: &mut Vec<u8>) {
+    let len = i32::try_from(data.len()).expect("Postgres values are smaller than 1GB");
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn encode(datum: &Datum, buf: &mut Vec<u8>) {
+    match datum {
+        Datum::Bool(b) => buf.push(*b as u8),
+        Datum::Bytea(bytes) => buf.extend_from_slice(bytes),
+        Datum::Int4(i) => buf.extend_from_slice(&i.to_be_bytes()),
+        Datum::Text(s) => buf.extend_from_slice(s.as_bytes()),
+    }
+}
+
+fn encode_array(elem_type: CopyType, elems: &[Option<Datum>], buf: &mut Vec<u8>) {
+    let has_nulls = elems.iter().any(|elem| elem.is_none());
+    // Postgres represents empty arrays as arrays without dimensions
+    let ndim: i32 = if elems.is_empty() { 0 } else { 1 };
+    buf.extend_from_slice(&ndim.to_be_bytes());
+    buf.extend_from_slice(&(has_nulls as i32).to_be_bytes());
+    buf.extend_from_slice(&elem_type.oid().to_be_bytes());
+    if ndim > 0 {
+        let len = i32::try_from(elems.len()).expect("Postgres arrays are smaller than 1GB");
+        buf.extend_from_slice(&len.to_be_bytes());
+        // The lower bound of the dimension
+        buf.extend_from_slice(&1i32.to_be_bytes());
+    }
+    for elem in elems {
+        match elem {
+            None => buf.extend_from_slice(&(-1i32).to_be_bytes()),
+            Some(datum) => {
+                debug_assert_eq!(elem_type, datum.copy_type());
+                let mut data = Vec::new();
+                encode(datum, &mut data);
+                write_len_prefixed(&data, buf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty() {
+        let data = BinaryCopyWriter::new().finish();
+        assert_eq!(
+            b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0\xff\xff".as_ref(),
+            data.as_slice()
+        );
+    }
+
+    #[test]
+    fn scalars() {
+        let mut writer = BinaryCopyWriter::new();
+        writer.write_row(&[
+            Field::Scalar(Datum::Int4(258)),
+            Field::Null,
+            Field::Scalar(Datum::Text("ab".to_owned())),
+            Field::Scalar(Datum::Bool(true)),
+        ]);
+        let data = writer.finish();
+        let row = &data[SIGNATURE.len() + 8..data.len() - 2];
+        assert_eq!(
+            b"\0\x04\
+              \0\0\0\x04\0\0\x01\x02\
+              \xff\xff\xff\xff\
+              \0\0\0\x02ab\
+              \0\0\0\x01\x01"
+                .as_ref(),
+            row
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        let mut writer = BinaryCopyWriter::new();
+        writer.write_row(&[
+            Field::Array(CopyType::Int4, vec![Some(Datum::Int4(7)), None]),
+            Field::Array(CopyType::Text, vec![]),
+        ]);
+        let data = writer.finish();
+        let row = &data[SIGNATURE.len() + 8..data.len() - 2];
+        assert_eq!(
+            b"\0\x02\
+              \0\0\0\x20\
+              \0\0\0\x01\0\0\0\x01\0\0\0\x17\0\0\0\x02\0\0\0\x01\
+              \0\0\0\x04\0\0\0\x07\
+              \xff\xff\xff\xff\
+              \0\0\0\x0c\
+              \0\0\0\0\0\0\0\0\0\0\0\x19"
+                .as_ref(),
+            row
+        );
+    }
+}
@@ -15,6 +15,11 @@ use crate::relational::Table;
 /// The name of the column in which we store the block range
 pub(crate) const BLOCK_RANGE_COLUMN: &str = "block_range";
 
+/// The name of the column in which we store the block at which an entity
+/// was created for immutable entity types. Since such entities can never
+/// be changed or deleted, they do not need a block range
+pub(crate) const BLOCK_COLUMN: &str = "block$";
+
 /// The SQL clause we use to check that an entity version is current;
 /// that version has an unbounded block range, but checking for
 /// `upper_inf(block_range)` is slow and can't use the exclusion
@@ -81,7 +86,8 @@ impl ToSql<Range<Integer>, Pg> for BlockRange {
 }
 
 /// Generate the clause that checks whether `block` is in the block range
-/// of an entity
+/// of an entity. For immutable entity types, that means checking that the
+/// entity was created at or before `block`
 #[derive(Constructor)]
 pub struct BlockRangeContainsClause<'a> {
     table: &'a Table,
@@ -93,6 +99,13 @@ impl<'a> QueryFragment<Pg> for BlockRangeContainsClause<'a> {
     fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
         out.unsafe_to_cache_prepared();
 
+        if self.table.immutable {
+            out.push_sql(self.table_prefix);
+            out.push_identifier(BLOCK_COLUMN)?;
+            out.push_sql(" <= ");
+            return out.push_bind_param::<Integer, _>(&self.block);
+        }
+
         out.push_sql(self.table_prefix);
         out.push_identifier(BLOCK_RANGE_COLUMN)?;
         out.push_sql(" @> ");
@@ -74,3 +74,48 @@ fn get_text_columns(
         });
     Ok(map)
 }
+
+/// Return `true` if the database schema `schema` contains an index
+/// called `name`
+pub fn index_exists(conn: &PgConnection, schema: &str, name: &str) -> Result<bool, StoreError> {
+    const QUERY: &str = "
+        select indexname
+          from pg_indexes
+         where schemaname = $1 and indexname = $2";
+
+    #[derive(Debug, QueryableByName)]
+    struct Index {
+        #[sql_type = "Text"]
+        #[allow(dead_code)]
+        pub indexname: String,
+    }
+
+    let indexes = diesel::sql_query(QUERY)
+        .bind::<Text, _>(schema)
+        .bind::<Text, _>(name)
+        .load::<Index>(conn)?;
+    Ok(!indexes.is_empty())
+}
+
+/// Return the definitions of the indexes in the database schema `schema`
+/// that were created by hand with `Layout::create_index`
+pub fn manual_indexes(conn: &PgConnection, schema: &str) -> Result<Vec<String>, StoreError> {
+    const QUERY: &str = "
+        select indexdef
+          from pg_indexes
+         where schemaname = $1 and indexname like 'manual\\_%'
+         order by indexname";
+
+    #[derive(Debug, QueryableByName)]
+    struct Index {
+        #[sql_type = "Text"]
+        pub indexdef: String,
+    }
+
+    Ok(diesel::sql_query(QUERY)
+        .bind::<Text, _>(schema)
+        .load::<Index>(conn)?
+        .into_iter()
+        .map(|index| index.indexdef)
+        .collect())
+}
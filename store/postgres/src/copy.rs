@@ -0,0 +1,516 @@
+//! Copy the data of a graft base into a new deployment, or the data of a
+//! deployment into another shard.
+//!
+//! Rows are copied in batches ordered by `vid`, and every batch is copied
+//! in its own transaction so that copying does not hold locks or keep a
+//! transaction open for a long time. Progress is recorded in
+//! `subgraphs.copy_state` and `subgraphs.copy_table_state`; when copying
+//! gets interrupted, it resumes where it left off the next time the
+//! deployment is started.
+use diesel::dsl::sql;
+use diesel::pg::PgConnection;
+use diesel::sql_types::{BigInt, Nullable, Timestamptz};
+use diesel::{
+    insert_into, update, Connection as _, ExpressionMethods, OptionalExtension, QueryDsl,
+    RunQueryDsl,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use graph::components::store::{CopyStatus, CopyTableStatus};
+use graph::data::subgraph::schema::DynamicEthereumContractDataSourceEntity;
+use graph::prelude::{
+    format_err, info, web3::types::H256, BlockNumber, EthereumBlockPointer, Logger, StoreError,
+    SubgraphDeploymentId,
+};
+
+use crate::relational::{Layout, Table};
+use crate::relational_queries as rq;
+
+table! {
+    subgraphs.copy_state(dst) {
+        dst -> Text,
+        src -> Text,
+        target_block_hash -> Binary,
+        target_block_number -> Integer,
+        finished_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    subgraphs.copy_table_state(id) {
+        id -> Integer,
+        dst -> Text,
+        entity_type -> Text,
+        next_vid -> BigInt,
+        target_vid -> BigInt,
+        batch_size -> BigInt,
+        rows_copied -> BigInt,
+        duration_ms -> BigInt,
+        finished_at -> Nullable<Timestamptz>,
+    }
+}
+
+/// The number of rows we copy in the first batch for each table
+const INITIAL_BATCH_SIZE: i64 = 10_000;
+
+/// We adjust the size of batches so that copying one batch takes about
+/// this long
+const TARGET_DURATION: Duration = Duration::from_secs(180);
+
+#[derive(QueryableByName)]
+struct VidRange {
+    #[sql_type = "BigInt"]
+    min_vid: i64,
+    #[sql_type = "BigInt"]
+    max_vid: i64,
+}
+
+/// Copy the data of `src` as of `target_block` into `dst`. Before each
+/// batch, we call `cancelled` and stop with an error if it returns `true`.
+/// Both `src` and `dst` must be in the shard behind `conn`, and `meta_conn`
+/// must be a connection to the primary shard. This must not be called
+/// inside a transaction
+pub(crate) fn copy_deployment(
+    logger: &Logger,
+    conn: &PgConnection,
+    meta_conn: &PgConnection,
+    src: &Layout,
+    dst: &Layout,
+    target_block: EthereumBlockPointer,
+    metadata: &Layout,
+    cancelled: impl Fn() -> Result<bool, StoreError>,
+) -> Result<(), StoreError> {
+    if copy_data(logger, conn, src, dst, &target_block, &cancelled)? {
+        return Ok(());
+    }
+
+    meta_conn.transaction(|| {
+        conn.transaction(|| -> Result<(), StoreError> {
+            use copy_state as cs;
+
+            // Another node might have finished copying while we were busy
+            let finished = cs::table
+                .select(cs::finished_at.is_not_null())
+                .filter(cs::dst.eq(dst.subgraph.as_str()))
+                .for_update()
+                .first::<bool>(conn)?;
+            if finished {
+                return Ok(());
+            }
+
+            // 2. Copy dynamic data sources and adjust their ID, unless an
+            // earlier attempt already did that
+            use crate::metadata::dynamic_ethereum_contract_data_source as decds;
+            let start = Instant::now();
+            let copied = decds::table
+                .filter(decds::deployment.eq(dst.subgraph.as_str()))
+                .count()
+                .get_result::<i64>(meta_conn)?;
+            if copied == 0 {
+                // Find existing dynamic data sources
+                let dds = decds::table
+                    .select(decds::id)
+                    .filter(decds::deployment.eq(src.subgraph.as_str()))
+                    .load::<String>(meta_conn)?;
+                // Create an equal number of brand new ids
+                let new_dds = (0..dds.len())
+                    .map(|_| DynamicEthereumContractDataSourceEntity::make_id())
+                    .collect::<Vec<_>>();
+                // Copy the data sources and all their subordinate entities,
+                // translating ids into new ids in the process and attaching
+                // them to `dst.subgraph`
+                rq::CopyDynamicDataSourceQuery::new(&dds, &new_dds, dst.subgraph.as_str())
+                    .execute(meta_conn)?;
+                info!(logger, "Copied {} dynamic data sources", dds.len();
+                      "time_ms" => start.elapsed().as_millis());
+            }
+
+            // 3. Rewind the subgraph. `revert_block` gets rid of everything
+            // including the block passed to it. We want to preserve
+            // `target_block` and therefore revert `target_block+1`. This also
+            // undoes any changes that were made to the source while we copied
+            let start = Instant::now();
+            let block_to_revert = (target_block.number + 1) as BlockNumber;
+            dst.revert_block(conn, block_to_revert)?;
+            metadata.revert_metadata(meta_conn, &dst.subgraph, block_to_revert)?;
+            info!(logger, "Rewound subgraph to block {}", target_block.number;
+                  "time_ms" => start.elapsed().as_millis());
+
+            update(cs::table.filter(cs::dst.eq(dst.subgraph.as_str())))
+                .set(cs::finished_at.eq(sql::<Nullable<Timestamptz>>("now()")))
+                .execute(conn)?;
+            Ok(())
+        })
+    })
+}
+
+/// Copy the data of a deployment that is being moved to another shard.
+/// The tables of `src` must be foreign tables for the tables of the
+/// deployment in its current shard, and `dst` must be the layout of the
+/// copy in the new shard behind `conn`. The copy contains the data as of
+/// `target_block`; changes after that are copied by `catch_up`. This must
+/// not be called inside a transaction
+pub(crate) fn copy_moving_deployment(
+    logger: &Logger,
+    conn: &PgConnection,
+    src: &Layout,
+    dst: &Layout,
+    target_block: EthereumBlockPointer,
+    cancelled: impl Fn() -> Result<bool, StoreError>,
+) -> Result<(), StoreError> {
+    if copy_data(logger, conn, src, dst, &target_block, &cancelled)? {
+        return Ok(());
+    }
+
+    conn.transaction(|| -> Result<(), StoreError> {
+        use copy_state as cs;
+
+        let finished = cs::table
+            .select(cs::finished_at.is_not_null())
+            .filter(cs::dst.eq(dst.subgraph.as_str()))
+            .for_update()
+            .first::<bool>(conn)?;
+        if finished {
+            return Ok(());
+        }
+        dst.revert_block(conn, (target_block.number + 1) as BlockNumber)?;
+        update(cs::table.filter(cs::dst.eq(dst.subgraph.as_str())))
+            .set(cs::finished_at.eq(sql::<Nullable<Timestamptz>>("now()")))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Apply all changes that were made to `src` after `target_block` to its
+/// copy `dst`, assuming that `dst` contains the data of `src` as of
+/// `target_block` and possibly some later changes. Nothing may write to
+/// `src` while this runs. Returns the number of entity versions that were
+/// copied
+pub(crate) fn catch_up(
+    conn: &PgConnection,
+    src: &Layout,
+    dst: &Layout,
+    target_block: BlockNumber,
+) -> Result<usize, StoreError> {
+    // Get rid of any changes after `target_block` so that we can simply
+    // copy all of them from `src`
+    dst.revert_block(conn, target_block + 1)?;
+
+    let mut count = 0;
+    for (dtable, stable) in common_tables(src, dst) {
+        if !dtable.immutable {
+            // Close the block ranges of versions that were replaced or
+            // deleted after `target_block`
+            let query = format!(
+                "update {dst} d
+                    set block_range = s.block_range
+                   from {src} s
+                  where s.id = d.id
+                    and lower(s.block_range) = lower(d.block_range)
+                    and upper_inf(d.block_range)
+                    and not upper_inf(s.block_range)",
+                dst = dtable.qualified_name,
+                src = stable.qualified_name
+            );
+            count += diesel::sql_query(query).execute(conn)?;
+        }
+        count +=
+            rq::CopyEntityDataQuery::created_after(dtable, stable, target_block)?.execute(conn)?;
+    }
+    Ok(count)
+}
+
+/// Remove all record of earlier copies into `dst` in the shard behind
+/// `conn`
+pub(crate) fn clear(conn: &PgConnection, dst: &SubgraphDeploymentId) -> Result<(), StoreError> {
+    use copy_state as cs;
+    use copy_table_state as cts;
+
+    diesel::delete(cts::table.filter(cts::dst.eq(dst.as_str()))).execute(conn)?;
+    diesel::delete(cs::table.filter(cs::dst.eq(dst.as_str()))).execute(conn)?;
+    Ok(())
+}
+
+/// The tables that we need to copy from `src` into `dst`, sorted by name
+fn common_tables<'a>(src: &'a Layout, dst: &'a Layout) -> Vec<(&'a Arc<Table>, &'a Table)> {
+    // We allow both not copying tables at all from the source, as well
+    // as adding new tables in `dst`; we only need to copy tables that
+    // exist in both
+    let mut tables = dst
+        .tables
+        .values()
+        .filter_map(|dtable| src.table(&dtable.name).map(|stable| (dtable, stable)))
+        .collect::<Vec<_>>();
+    tables.sort_by(|(a, _), (b, _)| a.name.as_str().cmp(b.name.as_str()));
+    tables
+}
+
+/// Copy the rows of all tables of `src` into `dst`. Returns `true` if an
+/// earlier call already finished copying
+fn copy_data(
+    logger: &Logger,
+    conn: &PgConnection,
+    src: &Layout,
+    dst: &Layout,
+    target_block: &EthereumBlockPointer,
+    cancelled: &impl Fn() -> Result<bool, StoreError>,
+) -> Result<bool, StoreError> {
+    // This can not be used to copy data to or from the metadata subgraph
+    assert!(!dst.subgraph.is_meta());
+    assert!(!src.subgraph.is_meta());
+
+    let tables = common_tables(src, dst);
+    if conn.transaction(|| init(conn, &dst.subgraph, &src.subgraph, target_block, &tables))? {
+        return Ok(true);
+    }
+
+    info!(
+        logger,
+        "Copying data from {} ({}) to {} ({})",
+        src.subgraph,
+        src.catalog.schema,
+        dst.subgraph,
+        dst.catalog.schema
+    );
+
+    // 1. Copy subgraph data
+    for (dtable, stable) in &tables {
+        copy_table(logger, conn, &dst.subgraph, dtable, stable, cancelled)?;
+    }
+    Ok(false)
+}
+
+/// Record that we are copying `src` into `dst` unless we have already
+/// started doing that earlier. Returns `true` if copying has already
+/// finished
+fn init(
+    conn: &PgConnection,
+    dst: &SubgraphDeploymentId,
+    src: &SubgraphDeploymentId,
+    target_block: &EthereumBlockPointer,
+    tables: &[(&Arc<Table>, &Table)],
+) -> Result<bool, StoreError> {
+    use copy_state as cs;
+    use copy_table_state as cts;
+
+    let finished = cs::table
+        .select(cs::finished_at.is_not_null())
+        .filter(cs::dst.eq(dst.as_str()))
+        .for_update()
+        .first::<bool>(conn)
+        .optional()?;
+    if let Some(finished) = finished {
+        return Ok(finished);
+    }
+
+    insert_into(cs::table)
+        .values((
+            cs::dst.eq(dst.as_str()),
+            cs::src.eq(src.as_str()),
+            cs::target_block_hash.eq(&target_block.hash.0[..]),
+            cs::target_block_number.eq(target_block.number as BlockNumber),
+        ))
+        .execute(conn)?;
+    for (dtable, stable) in tables {
+        let range = diesel::sql_query(format!(
+            "select coalesce(min(vid), 0) as min_vid, coalesce(max(vid), -1) as max_vid \
+               from {}",
+            stable.qualified_name
+        ))
+        .get_result::<VidRange>(conn)?;
+        insert_into(cts::table)
+            .values((
+                cts::dst.eq(dst.as_str()),
+                cts::entity_type.eq(dtable.object.as_str()),
+                cts::next_vid.eq(range.min_vid),
+                cts::target_vid.eq(range.max_vid),
+                cts::batch_size.eq(INITIAL_BATCH_SIZE),
+            ))
+            .execute(conn)?;
+    }
+    Ok(false)
+}
+
+/// Copy the rows of `src` into `dst` batch by batch until all rows up to
+/// the `target_vid` recorded for the table have been copied
+fn copy_table(
+    logger: &Logger,
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    dst: &Table,
+    src: &Table,
+    cancelled: &impl Fn() -> Result<bool, StoreError>,
+) -> Result<(), StoreError> {
+    use copy_table_state as cts;
+
+    let start = Instant::now();
+    let mut count = 0;
+    loop {
+        if cancelled()? {
+            return Err(format_err!(
+                "copying data into {} was cancelled; it will continue \
+                 when the deployment is started again",
+                subgraph
+            )
+            .into());
+        }
+
+        // Lock the progress row so that several nodes copying the same
+        // deployment never copy the same rows
+        let done = conn.transaction(|| -> Result<bool, StoreError> {
+            let state = || {
+                cts::table
+                    .filter(cts::dst.eq(subgraph.as_str()))
+                    .filter(cts::entity_type.eq(dst.object.as_str()))
+            };
+            let (next_vid, target_vid, batch_size, finished) = state()
+                .select((
+                    cts::next_vid,
+                    cts::target_vid,
+                    cts::batch_size,
+                    cts::finished_at.is_not_null(),
+                ))
+                .for_update()
+                .first::<(i64, i64, i64, bool)>(conn)?;
+            if finished {
+                return Ok(true);
+            }
+            if next_vid > target_vid {
+                update(state())
+                    .set(cts::finished_at.eq(sql::<Nullable<Timestamptz>>("now()")))
+                    .execute(conn)?;
+                return Ok(true);
+            }
+
+            let last_vid = (next_vid + batch_size - 1).min(target_vid);
+            let batch_start = Instant::now();
+            let rows = rq::CopyEntityDataQuery::new(dst, src, next_vid, last_vid)?.execute(conn)?;
+            let duration = batch_start.elapsed();
+            count += rows;
+
+            update(state())
+                .set((
+                    cts::next_vid.eq(last_vid + 1),
+                    cts::batch_size.eq(adjust_batch_size(batch_size, duration)),
+                    cts::rows_copied.eq(cts::rows_copied + rows as i64),
+                    cts::duration_ms.eq(cts::duration_ms + duration.as_millis() as i64),
+                ))
+                .execute(conn)?;
+            Ok(false)
+        })?;
+        if done {
+            break;
+        }
+    }
+    info!(logger, "Copied {} {} entities", count, src.object;
+          "time_ms" => start.elapsed().as_millis());
+    Ok(())
+}
+
+/// Pick the size of the next batch so that copying it takes about
+/// `TARGET_DURATION` if copying the last batch of `batch_size` rows took
+/// `duration`. The batch size grows by at most a factor of 2 at a time
+fn adjust_batch_size(batch_size: i64, duration: Duration) -> i64 {
+    let duration = duration.as_millis().max(1) as f64;
+    let target = TARGET_DURATION.as_millis() as f64;
+    let new_size = (batch_size as f64 * target / duration) as i64;
+    new_size.max(1).min(2 * batch_size)
+}
+
+/// Return `true` if `dst` is not being copied from a graft base or if
+/// copying has finished
+pub(crate) fn is_finished(
+    conn: &PgConnection,
+    dst: &SubgraphDeploymentId,
+) -> Result<bool, StoreError> {
+    use copy_state as cs;
+
+    Ok(cs::table
+        .select(cs::finished_at.is_not_null())
+        .filter(cs::dst.eq(dst.as_str()))
+        .first::<bool>(conn)
+        .optional()?
+        .unwrap_or(true))
+}
+
+/// The progress of copying data into each of `dsts`. Deployments that
+/// were never copied from a graft base are not in the result
+pub(crate) fn copy_statuses(
+    conn: &PgConnection,
+    dsts: &[&str],
+) -> Result<HashMap<SubgraphDeploymentId, CopyStatus>, StoreError> {
+    use copy_state as cs;
+    use copy_table_state as cts;
+
+    let mut tables: HashMap<String, Vec<CopyTableStatus>> = HashMap::new();
+    for (dst, entity_type, rows_copied, next_vid, target_vid, finished) in cts::table
+        .select((
+            cts::dst,
+            cts::entity_type,
+            cts::rows_copied,
+            cts::next_vid,
+            cts::target_vid,
+            cts::finished_at.is_not_null(),
+        ))
+        .filter(cts::dst.eq_any(dsts))
+        .order_by((cts::dst, cts::entity_type))
+        .load::<(String, String, i64, i64, i64, bool)>(conn)?
+    {
+        tables.entry(dst).or_default().push(CopyTableStatus {
+            entity_type,
+            rows_copied,
+            next_vid,
+            target_vid,
+            finished,
+        });
+    }
+
+    cs::table
+        .select((
+            cs::dst,
+            cs::src,
+            cs::target_block_hash,
+            cs::target_block_number,
+            cs::finished_at.is_not_null(),
+        ))
+        .filter(cs::dst.eq_any(dsts))
+        .load::<(String, String, Vec<u8>, i32, bool)>(conn)?
+        .into_iter()
+        .map(|(dst, src, hash, number, finished)| {
+            let id = |id: String| {
+                SubgraphDeploymentId::new(id.clone()).map_err(|_| {
+                    StoreError::Unknown(format_err!("invalid subgraph deployment id `{}`", id))
+                })
+            };
+            let status = CopyStatus {
+                src: id(src)?,
+                target_block: EthereumBlockPointer {
+                    hash: H256::from_slice(&hash),
+                    number: number as u64,
+                },
+                finished,
+                tables: tables.remove(&dst).unwrap_or_default(),
+            };
+            Ok((id(dst)?, status))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_size() {
+        // Batches that are much faster than the target only double in size
+        assert_eq!(20_000, adjust_batch_size(10_000, Duration::from_millis(0)));
+        assert_eq!(20_000, adjust_batch_size(10_000, Duration::from_secs(10)));
+        // Slow batches shrink in proportion to how slow they were
+        assert_eq!(5_000, adjust_batch_size(10_000, Duration::from_secs(360)));
+        assert_eq!(10_000, adjust_batch_size(10_000, TARGET_DURATION));
+        // We always copy at least one row
+        assert_eq!(1, adjust_batch_size(1, Duration::from_secs(100_000)));
+    }
+}
@@ -7,74 +7,40 @@
 //! manipulation of entities in the database should go through this module
 //! to make it easier to handle future schema changes
 
-// We use Diesel's dynamic table support for querying the entities and history
-// tables of a subgraph. Unfortunately, this support is not good enough for
-// modifying data, and we fall back to generating literal SQL queries for that.
-// For the `entities` table of the subgraph of subgraphs, we do map the table
-// statically and use it in some cases to bridge the gap between dynamic and
-// static table support, in particular in the update operation for entities.
-// Diesel deeply embeds the assumption that all schema is known at compile time;
-// for example, the column for a dynamic table can not implement
-// `diesel::query_source::Column` since that must carry the column name as a
-// constant. As a consequence, a lot of Diesel functionality is not available
-// for dynamic tables.
-
-use diesel::connection::SimpleConnection;
-use diesel::debug_query;
-use diesel::deserialize::QueryableByName;
-use diesel::dsl::any;
-use diesel::pg::{Pg, PgConnection};
+use diesel::connection::{SimpleConnection, TransactionManager};
+use diesel::pg::PgConnection;
 use diesel::r2d2::{ConnectionManager, PooledConnection};
-use diesel::sql_types::{Integer, Jsonb, Nullable, Text};
-use diesel::BoolExpressionMethods;
+use diesel::sql_types::{BigInt, Integer, Text};
 use diesel::Connection as _;
 use diesel::ExpressionMethods;
 use diesel::{OptionalExtension, QueryDsl, RunQueryDsl};
-use inflector::cases::snakecase::to_snake_case;
-use lazy_static::lazy_static;
 use maybe_owned::MaybeOwned;
-use std::collections::hash_map::DefaultHasher;
+use postgres::TlsMode;
 use std::collections::{BTreeMap, HashMap};
 use std::convert::TryInto;
-use std::hash::{Hash, Hasher};
 use std::ops::Deref as _;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use uuid::Uuid;
 
+use graph::components::store::{EntityTypeChanges, EntityTypeStats};
 use graph::data::schema::Schema as SubgraphSchema;
-use graph::data::subgraph::schema::{POI_OBJECT, POI_TABLE, SUBGRAPHS_ID};
+use graph::data::subgraph::schema::{
+    SubgraphDeploymentAssignmentEntity, TypedEntity, POI_OBJECT, POI_TABLE, SUBGRAPHS_ID,
+};
+use graph::prelude::web3::types::H256;
 use graph::prelude::{
-    debug, format_err, info, serde_json, warn, AttributeIndexDefinition, BlockNumber, Entity,
-    EntityChange, EntityChangeOperation, EntityCollection, EntityFilter, EntityKey,
-    EntityModification, EntityOrder, EntityRange, Error, EthereumBlockPointer, Logger,
-    QueryExecutionError, StoreError, StoreEvent, SubgraphDeploymentId, ValueType, BLOCK_NUMBER_MAX,
+    format_err, info, serde_json, BlockNumber, Entity, EntityCollection, EntityFilter, EntityKey,
+    EntityOrder, EntityRange, EthereumBlockPointer, Logger, QueryExecutionError, StoreError,
+    StoreEvent, SubgraphDeploymentId, BLOCK_NUMBER_MAX,
 };
 
 use crate::block_range::block_number;
+use crate::copy;
 use crate::history_event::HistoryEvent;
-use crate::jsonb_queries::FilterQuery;
 use crate::metadata;
 use crate::notification_listener::JsonNotification;
-use crate::relational::{Catalog, Layout};
-
-lazy_static! {
-    // We allow overriding the default storage scheme with the environment
-    // variable `GRAPH_STORAGE_SCHEME` in an overabundance of caution in case
-    // there is a problem with the default, relational storage. Eventually,
-    // this choice will go away.
-    static ref GRAPH_STORAGE_SCHEME: self::public::DeploymentSchemaVersion = {
-        use self::public::DeploymentSchemaVersion as v;
-
-        match std::env::var("GRAPH_STORAGE_SCHEME")
-                .unwrap_or_else(|_| "relational".to_owned())
-                .as_str() {
-            "relational" => v::Relational,
-            "json" => v::Split,
-            _ => panic!("Invalid value for GRAPH_STORAGE_SCHEME. It must be \
-                 either `relational` or `json`")
-        }
-    };
-}
+use crate::relational::{Catalog, Layout, SchemaEvolution};
+use crate::sharding::{self, PlacementRule};
 
 /// The size of string prefixes that we index. This is chosen so that we
 /// will index strings that people will do string comparisons like
@@ -84,30 +50,6 @@ lazy_static! {
 /// bigger than Postgres' limit on such strings which is about 2k
 pub const STRING_PREFIX_SIZE: usize = 256;
 
-/// The type of operation that led to a history entry. When we revert a block,
-/// we reverse the effects of that operation; e.g., an `Insert` entry in the
-/// history will cause us to delete the underlying entity
-enum OperationType {
-    Insert,
-    Update,
-    Delete,
-}
-
-/// Translate from the integer that is stored in `entity_history.op_id` to
-/// the symbolic `OperationType`
-impl Into<i32> for OperationType {
-    fn into(self) -> i32 {
-        match self {
-            OperationType::Insert => 0,
-            OperationType::Update => 1,
-            OperationType::Delete => 2,
-        }
-    }
-}
-
-/// Marker trait for tables that store entities
-pub(crate) trait EntitySource {}
-
 // Tables in the public schema that are shared across subgraphs. We put them
 // in this module to make sure that nobody else gets access to them. All
 // access to these tables must go through functions in this module.
@@ -131,10 +73,9 @@ mod public {
     /// `Split`. Subgraphs that use a relational schema for entities, and
     /// store their history in the same table are marked as 'Relational'
     ///
-    /// Migrating a subgraph amounts to changing the storage scheme for that
-    /// subgraph from one version to another. Whether a subgraph scheme needs
-    /// migrating is determined by `Table::needs_migrating`, the migration
-    /// machinery is kicked off with a call to `Connection::migrate`
+    /// The store only supports `Relational` storage; `Split` deployments
+    /// must be converted with the offline tool in `jsonb_migration` before
+    /// they can be used
     #[derive(DbEnum, Debug, Clone, Copy)]
     pub enum DeploymentSchemaVersion {
         Split,
@@ -145,19 +86,9 @@ mod public {
     /// enum. A subgraph that is ready to be used for indexing is in state
     /// `Ready`. The state `Init` is used to indicate that the subgraph has
     /// remaining initialization work to do, in particular, that it needs to
-    /// copy data if it is grafted onto another subgraph. The `Tables` state
-    /// is used during schema migrations as described below. Both of these
-    /// states move the subgraph to `Ready` upon successful completion of the
-    /// work associated with them.
-    ///
-    /// When a subgraph is migrated, the migration is broken into two steps:
-    /// in the first step, the schema changes that the migration needs are
-    /// put into place; in the second step data is moved from the old storage
-    /// scheme to the new one. These two steps happen in separate database
-    /// transactions, since the first step if fast but takes fairly strong
-    /// locks that can block other database work. The second step, moving data,
-    /// only requires relatively weak locks that do not block write activity
-    /// in other subgraphs.
+    /// copy data if it is grafted onto another subgraph; once that is done,
+    /// the subgraph moves to `Ready`. The `Tables` state was used by
+    /// in-place storage migrations, which are no longer supported
     #[derive(DbEnum, Debug, Clone)]
     pub enum DeploymentSchemaState {
         Ready,
@@ -172,130 +103,78 @@ mod public {
             name -> Text,
             /// The subgraph storage scheme used for this subgraph
             version -> crate::entities::public::DeploymentSchemaVersionMapping,
-            /// Whether this subgraph is in the process of being migrated to
-            /// a new storage scheme. This column functions as a lock (or
-            /// semaphore) and is used to limit the number of subgraphs that
-            /// are being migrated at any given time. The details of handling
-            /// this lock are in `Connection::should_migrate`
+            /// Unused; this was a lock for in-place storage migrations
             migrating -> Bool,
             /// See comment on DeploymentSchemaState
             state -> crate::entities::public::DeploymentSchemaStateMapping,
+            /// The shard in which the data of this subgraph is stored
+            shard -> Text,
         }
     }
-}
 
-pub(crate) type EntityTable = diesel_dynamic_schema::Table<String>;
+    // Deployments that are being copied to another shard. See
+    // `Store::start_deployment_move`
+    table! {
+        deployment_moves(subgraph) {
+            subgraph -> Text,
+            shard -> Text,
+            name -> Text,
+            target_block_hash -> Binary,
+            target_block_number -> Integer,
+            invalidated -> Bool,
+            started_at -> Timestamptz,
+        }
+    }
 
-pub(crate) type EntityColumn<ST> = diesel_dynamic_schema::Column<EntityTable, String, ST>;
+    // Prepared transactions in shards that must be committed. See
+    // `Connection::two_phase_transaction`
+    table! {
+        deployment_prepared_transactions(gid) {
+            gid -> Text,
+            subgraph -> Text,
+        }
+    }
 
-// This is a bit weak, as any DynamicTable<String> is now an EntitySource
-impl EntitySource for EntityTable {}
+    // The data deployments had in the shard they were moved away from. See
+    // `Store::finish_deployment_move`
+    table! {
+        deployment_retired_sites(subgraph) {
+            subgraph -> Text,
+            shard -> Text,
+            name -> Text,
+            block_hash -> Binary,
+            block_number -> Integer,
+            retired_at -> Timestamptz,
+        }
+    }
+}
 
 use public::deployment_schemas;
 
 /// Information about the database schema that stores the entities for a
-/// subgraph. The schemas are versioned by subgraph, which makes it possible
-/// to migrate subgraphs one at a time to newer storage schemes. Migrations
-/// are split into two stages to make sure that intrusive locks are
-/// only held a very short amount of time. The overall goal is to pause
-/// indexing (write activity) for a subgraph while we migrate, but keep it
-/// possible to query the subgraph, and not affect other subgraph's operation.
-///
-/// When writing a migration, the following guidelines should be followed:
-/// - each migration can only affect a single subgraph, and must not interfere
-///   with the working of any other subgraph
-/// - writing to the subgraph will be paused while the migration is running
-/// - each migration step is run in its own database transaction
+/// subgraph
 #[derive(Queryable, QueryableByName, Debug)]
 #[table_name = "deployment_schemas"]
-struct Schema {
+pub(crate) struct Schema {
     id: i32,
     subgraph: String,
-    name: String,
-    /// The version currently in use. While we are migrating, the version
-    /// will remain at the old version until the new version is ready to use.
-    /// Migrations should update this field as the very last operation they
-    /// perform.
+    /// The name of the database schema
+    pub name: String,
+    /// The storage scheme of the subgraph
     version: public::DeploymentSchemaVersion,
-    /// True if the subgraph is currently running a migration. The `migrating`
-    /// flags in the `deployment_schemas` table act as a semaphore that limits
-    /// the number of subgraphs that can undergo a migration at the same time.
     migrating: bool,
-    /// Track which parts of a migration have already been performed. The
-    /// `Ready` state means no work to get to the next version has been done
-    /// yet. A migration will first perform a transaction that purely does DDL;
-    /// since that generally requires fairly strong locks but is fast, that
-    /// is done in its own transaction. Once we have done the necessary DDL,
-    /// the state goes to `Tables`. The final state of the migration is
-    /// copying data, which can be very slow, but should not require intrusive
-    /// locks. When the data is in place, the migration updates `version` to
-    /// the new version we migrated to, and sets the state to `Ready`
+    /// See `DeploymentSchemaState`
     state: public::DeploymentSchemaState,
+    /// The shard in which the database schema `name` lives
+    shard: String,
 }
 
-/// Storage using JSONB for entities. All entities are stored in one table
-#[derive(Debug, Clone)]
-pub(crate) struct JsonStorage {
-    /// The name of the database schema
-    schema: String,
-    /// The subgraph id
-    subgraph: SubgraphDeploymentId,
-    table: EntityTable,
-    id: EntityColumn<diesel::sql_types::Text>,
-    entity: EntityColumn<diesel::sql_types::Text>,
-    data: EntityColumn<diesel::sql_types::Jsonb>,
-    event_source: EntityColumn<diesel::sql_types::Text>,
-    // The query to count all entities
-    count_query: String,
-}
-
-#[derive(Debug, Clone)]
-pub(crate) enum Storage {
-    Json(JsonStorage),
-    Relational(Layout),
-}
-
-impl Storage {
-    fn subgraph(&self) -> &SubgraphDeploymentId {
-        match self {
-            Storage::Json(json) => &json.subgraph,
-            Storage::Relational(layout) => &layout.subgraph,
-        }
-    }
-}
-
-/// Helper struct to support a custom query for entity history
-#[derive(Debug, Queryable)]
-struct RawHistory {
-    id: i32,
-    entity: String,
-    entity_id: String,
-    data: Option<serde_json::Value>,
-    // The operation that lead to this history record
-    // 0 = INSERT, 1 = UPDATE, 2 = DELETE
-    op: i16,
-}
-
-impl QueryableByName<Pg> for RawHistory {
-    // Extract one RawHistory entry from the database. The names of the columns
-    // must follow exactly the names used in the queries in revert_block
-    fn build<R: diesel::row::NamedRow<Pg>>(row: &R) -> diesel::deserialize::Result<Self> {
-        Ok(RawHistory {
-            id: row.get("id")?,
-            entity: row.get("entity")?,
-            entity_id: row.get("entity_id")?,
-            data: row.get::<Nullable<Jsonb>, _>("data_before")?,
-            op: row.get("op_id")?,
-        })
-    }
-}
-
-/// A cache for storage objects as constructing them takes a bit of
+/// A cache for the layouts of subgraphs as constructing them takes a bit of
 /// computation. The cache lives as an attribute on the Store, but is managed
 /// solely from this module
-pub(crate) type StorageCache = Mutex<HashMap<SubgraphDeploymentId, Arc<Storage>>>;
+pub(crate) type LayoutCache = Mutex<HashMap<SubgraphDeploymentId, Arc<Layout>>>;
 
-pub(crate) fn make_storage_cache() -> StorageCache {
+pub(crate) fn make_layout_cache() -> LayoutCache {
     Mutex::new(HashMap::new())
 }
 
@@ -305,39 +184,83 @@ pub(crate) fn make_storage_cache() -> StorageCache {
 /// generally result in a panic.
 ///
 /// Instances of this struct must not be cached across transactions as there
-/// is no mechanism in place to notify other index nodes that a subgraph has
-/// been migrated
-#[derive(Constructor)]
+/// is no mechanism in place to notify other index nodes that the layout of
+/// a subgraph has changed
 pub(crate) struct Connection<'a> {
+    /// The connection to the shard that stores the subgraph's entities
     conn: MaybeOwned<'a, PooledConnection<ConnectionManager<PgConnection>>>,
-    /// The storage of the subgraph we are dealing with; entities
+    /// A connection to the primary shard if the subgraph's entities are
+    /// stored in a different shard; subgraph metadata always lives in the
+    /// primary shard. When this is `None`, `conn` is used for metadata
+    meta_conn: Option<MaybeOwned<'a, PooledConnection<ConnectionManager<PgConnection>>>>,
+    /// The layout of the subgraph we are dealing with; entities
     /// go into this
-    storage: Arc<Storage>,
+    layout: Arc<Layout>,
     /// The layout of the subgraph of subgraphs where we keep subgraph
     /// metadata
-    metadata: Arc<Storage>,
+    metadata: Arc<Layout>,
+    /// The URL of the database that `conn` is connected to
+    postgres_url: String,
+    /// A connection for sending data with `COPY`, which Diesel can not do;
+    /// it is only opened when it is needed. See `insert_many`
+    copy_conn: Mutex<Option<postgres::Connection>>,
+}
+
+impl<'a> Connection<'a> {
+    pub(crate) fn new(
+        conn: MaybeOwned<'a, PooledConnection<ConnectionManager<PgConnection>>>,
+        meta_conn: Option<MaybeOwned<'a, PooledConnection<ConnectionManager<PgConnection>>>>,
+        layout: Arc<Layout>,
+        metadata: Arc<Layout>,
+        postgres_url: String,
+    ) -> Self {
+        Connection {
+            conn,
+            meta_conn,
+            layout,
+            metadata,
+            postgres_url,
+            copy_conn: Mutex::new(None),
+        }
+    }
 }
 
 impl Connection<'_> {
-    /// Return the storage for `key`, which must refer either to the subgraph
+    /// The connection to the primary shard, which holds all metadata
+    fn meta_conn(&self) -> &PgConnection {
+        match &self.meta_conn {
+            Some(meta_conn) => meta_conn,
+            None => &self.conn,
+        }
+    }
+
+    /// Return the connection for `key`; see `layout_for`
+    fn conn_for(&self, key: &EntityKey) -> &PgConnection {
+        if key.subgraph_id == *SUBGRAPHS_ID {
+            self.meta_conn()
+        } else {
+            &self.conn
+        }
+    }
+
+    /// Return the layout for `key`, which must refer either to the subgraph
     /// for this connection, or the metadata subgraph.
     ///
     /// # Panics
     ///
     /// If `key` does not reference the connection's subgraph or the metadata
     /// subgraph
-    fn storage_for(&self, key: &EntityKey) -> &Storage {
+    fn layout_for(&self, key: &EntityKey) -> &Layout {
         if key.subgraph_id == *SUBGRAPHS_ID {
             self.metadata.as_ref()
-        } else if &key.subgraph_id == self.storage.subgraph() {
-            self.storage.as_ref()
+        } else if key.subgraph_id == self.layout.subgraph {
+            self.layout.as_ref()
         } else {
             panic!(
                 "A connection can only be used with one subgraph and \
                  the metadata subgraph.\nThe connection for {} is also \
                  used with {}",
-                self.storage.subgraph(),
-                key.subgraph_id
+                self.layout.subgraph, key.subgraph_id
             );
         }
     }
@@ -347,68 +270,82 @@ impl Connection<'_> {
         use public::deployment_schemas as dsl;
         use public::DeploymentSchemaState as State;
 
+        let layout = &self.layout;
         let state = dsl::table
             .select(dsl::state)
-            .filter(dsl::subgraph.eq(self.storage.subgraph().as_str()))
-            .first::<State>(self.conn.as_ref())?;
+            .filter(dsl::subgraph.eq(layout.subgraph.as_str()))
+            .first::<State>(self.meta_conn())?;
 
         match state {
             State::Init => {
-                let graft = metadata::deployment_graft(&self.conn, &self.storage.subgraph())?;
-                match &*self.storage {
-                    Storage::Relational(layout) => {
-                        let start = Instant::now();
-                        if let Some((base, block)) = graft {
-                            let base = match Storage::new(&self.conn, &base)? {
-                                Storage::Relational(base) => base,
-                                Storage::Json(_) => unreachable!(
-                                    "A JSONB subgraph is never used as the base for a graft"
-                                ),
-                            };
-                            layout.copy_from(
-                                logger,
-                                &self.conn,
-                                &base,
-                                block,
-                                self.metadata_layout(),
-                            )?;
-                        }
-                        diesel::update(dsl::table)
-                            .set(dsl::state.eq(State::Ready))
-                            .filter(dsl::subgraph.eq(self.storage.subgraph().as_str()))
-                            .execute(self.conn.as_ref())?;
-                        info!(logger, "Subgraph successfully initialized";
-                              "time_ms" => start.elapsed().as_millis());
-                    }
-                    Storage::Json(_) => {
-                        if graft.is_some() {
-                            unreachable!("A JSONB subgraph is never grafted onto another subgraph");
-                        }
-                        diesel::update(dsl::table)
-                            .set(dsl::state.eq(State::Ready))
-                            .filter(dsl::subgraph.eq(self.storage.subgraph().as_str()))
-                            .execute(self.conn.as_ref())?;
-                    }
+                // The data from the graft base must have been copied
+                // with `copy_graft_base` before we get here
+                let graft = metadata::deployment_graft(self.meta_conn(), &layout.subgraph)?;
+                if graft.is_some() && !copy::is_finished(&self.conn, &layout.subgraph)? {
+                    return Err(format_err!(
+                        "subgraph {} can not be started before copying the \
+                         data of its graft base has finished",
+                        layout.subgraph
+                    )
+                    .into());
                 }
+                diesel::update(dsl::table)
+                    .set(dsl::state.eq(State::Ready))
+                    .filter(dsl::subgraph.eq(layout.subgraph.as_str()))
+                    .execute(self.meta_conn())?;
+                info!(logger, "Subgraph successfully initialized");
+            }
+            State::Tables => {
+                return Err(format_err!(
+                    "subgraph {} is in the middle of a storage migration, which \
+                     is no longer supported",
+                    layout.subgraph
+                )
+                .into())
             }
-            State::Tables => unimplemented!("continue the migration"),
             State::Ready => { // Nothing to do
             }
         }
+        layout.drop_staging_tables(&self.conn)?;
 
-        // Clear the `migrating` lock on the subgraph; this flag must be
-        // visible to other db users before the migration starts and is
-        // therefore set in its own txn before migration actually starts.
-        // If the migration does not finish, e.g., because the server is shut
-        // down, `migrating` remains set to `true` even though no work for
-        // the migration is being done.
-        Ok(
-            diesel::update(
-                dsl::table.filter(dsl::subgraph.eq(self.storage.subgraph().to_string())),
-            )
-            .set(dsl::migrating.eq(false))
-            .execute(self.conn.deref())
-            .map(|_| ())?,
+        Ok(())
+    }
+
+    /// If this subgraph is grafted onto another subgraph and has not been
+    /// initialized yet, copy the data of the graft base into it. Copying
+    /// uses many transactions, and this must therefore not be called
+    /// inside a transaction. Copying stops when the subgraph is unassigned
+    /// and continues where it left off when it is started again
+    pub(crate) fn copy_graft_base(&self, logger: &Logger) -> Result<(), StoreError> {
+        use public::deployment_schemas as dsl;
+        use public::DeploymentSchemaState as State;
+
+        let layout = &self.layout;
+        let state = dsl::table
+            .select(dsl::state)
+            .filter(dsl::subgraph.eq(layout.subgraph.as_str()))
+            .first::<State>(self.meta_conn())?;
+        if !matches!(state, State::Init) {
+            return Ok(());
+        }
+        let (base, block) = match metadata::deployment_graft(self.meta_conn(), &layout.subgraph)? {
+            Some(graft) => graft,
+            None => return Ok(()),
+        };
+        // Grafts are always placed in the shard of their base
+        let base = load_layout(self.meta_conn(), &self.conn, &base)?;
+
+        let assignment_type = SubgraphDeploymentAssignmentEntity::TYPENAME.to_owned();
+        let id = layout.subgraph.to_string();
+        copy::copy_deployment(
+            logger,
+            &self.conn,
+            self.meta_conn(),
+            &base,
+            layout,
+            block,
+            &self.metadata,
+            || Ok(self.find_metadata(&assignment_type, &id)?.is_none()),
         )
     }
 
@@ -418,10 +355,7 @@ impl Connection<'_> {
         id: &String,
         block: BlockNumber,
     ) -> Result<Option<Entity>, StoreError> {
-        match &*self.storage {
-            Storage::Json(json) => json.find(&self.conn, entity, id),
-            Storage::Relational(layout) => layout.find(&self.conn, entity, id, block),
-        }
+        self.layout.find(&self.conn, entity, id, block)
     }
 
     /// Returns a sequence of `(type, entity)`.
@@ -431,25 +365,7 @@ impl Connection<'_> {
         ids_for_type: BTreeMap<&str, Vec<&str>>,
         block: BlockNumber,
     ) -> Result<BTreeMap<String, Vec<Entity>>, StoreError> {
-        match &*self.storage {
-            Storage::Json(json) => {
-                // Reuse `find` since we don't care about the performance of this on json.
-                let mut entities: BTreeMap<String, Vec<Entity>> = BTreeMap::new();
-                for (entity_type, ids) in ids_for_type {
-                    for id in ids {
-                        if let Some(entity) = json.find(&self.conn, entity_type, id)? {
-                            entities
-                                .entry(entity_type.to_owned())
-                                .or_default()
-                                .push(entity)
-                        }
-                    }
-                }
-                Ok(entities)
-            }
-
-            Storage::Relational(layout) => layout.find_many(&self.conn, ids_for_type, block),
-        }
+        self.layout.find_many(&self.conn, ids_for_type, block)
     }
 
     pub(crate) fn query<T: crate::relational_queries::FromEntityData>(
@@ -461,29 +377,8 @@ impl Connection<'_> {
         range: EntityRange,
         block: BlockNumber,
     ) -> Result<Vec<T>, QueryExecutionError> {
-        match &*self.storage {
-            Storage::Json(json) => {
-                // JSON storage can only query at the latest block
-                if block != BLOCK_NUMBER_MAX {
-                    return Err(StoreError::QueryExecutionError(
-                        "This subgraph uses JSONB storage, which does not \
-                         support querying at a specific block height. Redeploy \
-                         a new version of this subgraph to enable this feature."
-                            .to_owned(),
-                    )
-                    .into());
-                }
-                let order = match order {
-                    EntityOrder::Ascending(attr, value_type) => Some((attr, value_type, "asc")),
-                    EntityOrder::Descending(attr, value_type) => Some((attr, value_type, "desc")),
-                    EntityOrder::Default | EntityOrder::Unordered => None,
-                };
-                json.query(&self.conn, collection, filter, order, range)
-            }
-            Storage::Relational(layout) => {
-                layout.query(logger, &self.conn, collection, filter, order, range, block)
-            }
-        }
+        self.layout
+            .query(logger, &self.conn, collection, filter, order, range, block)
     }
 
     pub(crate) fn conflicting_entity(
@@ -491,12 +386,17 @@ impl Connection<'_> {
         entity_id: &String,
         entities: Vec<&String>,
     ) -> Result<Option<String>, StoreError> {
-        match &*self.storage {
-            Storage::Json(json) => json.conflicting_entity(&self.conn, entity_id, entities),
-            Storage::Relational(layout) => {
-                layout.conflicting_entity(&self.conn, entity_id, entities)
-            }
-        }
+        self.layout
+            .conflicting_entity(&self.conn, entity_id, entities)
+    }
+
+    pub(crate) fn conflicting_entities(
+        &self,
+        entity_ids: &[&str],
+        entities: Vec<&String>,
+    ) -> Result<Vec<(String, String)>, StoreError> {
+        self.layout
+            .conflicting_entities(&self.conn, entity_ids, entities)
     }
 
     pub(crate) fn insert(
@@ -505,16 +405,11 @@ impl Connection<'_> {
         entity: Entity,
         history_event: Option<&HistoryEvent>,
     ) -> Result<(), StoreError> {
-        match self.storage_for(key) {
-            Storage::Json(json) => json
-                .insert(&self.conn, &key, entity, history_event)
-                .map(|_| ()),
-            Storage::Relational(layout) => match history_event {
-                Some(history_event) => {
-                    layout.insert(&self.conn, key, entity, block_number(&history_event))
-                }
-                None => layout.insert_unversioned(&self.conn, key, entity),
-            },
+        let conn = self.conn_for(key);
+        let layout = self.layout_for(key);
+        match history_event {
+            Some(history_event) => layout.insert(conn, key, entity, block_number(&history_event)),
+            None => layout.insert_unversioned(conn, key, entity),
         }
     }
 
@@ -527,18 +422,11 @@ impl Connection<'_> {
         entity: Entity,
         history_event: Option<&HistoryEvent>,
     ) -> Result<(), StoreError> {
-        match self.storage_for(key) {
-            Storage::Json(json) => json
-                .update(&self.conn, key, entity, history_event)
-                .map(|_| ()),
-            Storage::Relational(layout) => match history_event {
-                Some(history_event) => {
-                    layout.update(&self.conn, key, entity, block_number(&history_event))
-                }
-                None => layout
-                    .overwrite_unversioned(&self.conn, key, entity)
-                    .map(|_| ()),
-            },
+        let conn = self.conn_for(key);
+        let layout = self.layout_for(key);
+        match history_event {
+            Some(history_event) => layout.update(conn, key, entity, block_number(&history_event)),
+            None => layout.overwrite_unversioned(conn, key, entity).map(|_| ()),
         }
     }
 
@@ -549,8 +437,8 @@ impl Connection<'_> {
         key: &EntityKey,
         entity: &Entity,
     ) -> Result<usize, StoreError> {
-        self.metadata_layout()
-            .update_unversioned(&self.conn, key, entity)
+        self.metadata
+            .update_unversioned(self.meta_conn(), key, entity)
     }
 
     pub(crate) fn find_metadata(
@@ -558,8 +446,8 @@ impl Connection<'_> {
         entity: &String,
         id: &String,
     ) -> Result<Option<Entity>, StoreError> {
-        self.metadata_layout()
-            .find(&self.conn, entity, id, BLOCK_NUMBER_MAX)
+        self.metadata
+            .find(self.meta_conn(), entity, id, BLOCK_NUMBER_MAX)
     }
 
     pub(crate) fn delete(
@@ -567,23 +455,78 @@ impl Connection<'_> {
         key: &EntityKey,
         history_event: Option<&HistoryEvent>,
     ) -> Result<usize, StoreError> {
-        match self.storage_for(key) {
-            Storage::Json(json) => json.delete(&self.conn, key, history_event),
-            Storage::Relational(layout) => match history_event {
-                Some(history_event) => layout.delete(&self.conn, key, block_number(&history_event)),
-                None => layout.delete_unversioned(&self.conn, key),
-            },
+        let conn = self.conn_for(key);
+        let layout = self.layout_for(key);
+        match history_event {
+            Some(history_event) => layout.delete(conn, key, block_number(&history_event)),
+            None => layout.delete_unversioned(conn, key),
         }
     }
 
-    pub(crate) fn build_attribute_index(
+    /// Insert many entities of type `entity_type` of this subgraph at the
+    /// block of `history_event`; see `Layout::insert_many`
+    pub(crate) fn insert_many(
         &self,
-        index: &AttributeIndexDefinition,
-    ) -> Result<usize, StoreError> {
-        match &*self.storage {
-            Storage::Json(json) => json.build_attribute_index(&self.conn, index),
-            Storage::Relational(_) => Ok(1),
+        entity_type: &str,
+        entities: Vec<(&EntityKey, Entity)>,
+        history_event: &HistoryEvent,
+    ) -> Result<(), StoreError> {
+        self.with_copy_conn(|copy_conn| {
+            self.layout.insert_many(
+                &self.conn,
+                copy_conn,
+                entity_type,
+                entities,
+                block_number(history_event),
+            )
+        })
+    }
+
+    /// Overwrite many entities of type `entity_type` of this subgraph at
+    /// the block of `history_event`; see `Layout::update_many`
+    pub(crate) fn update_many(
+        &self,
+        entity_type: &str,
+        entities: Vec<(&EntityKey, Entity)>,
+        history_event: &HistoryEvent,
+    ) -> Result<(), StoreError> {
+        self.with_copy_conn(|copy_conn| {
+            self.layout.update_many(
+                &self.conn,
+                copy_conn,
+                entity_type,
+                entities,
+                block_number(history_event),
+            )
+        })
+    }
+
+    /// Run `f` with the connection for `COPY`, opening it if necessary
+    fn with_copy_conn<F, T>(&self, f: F) -> Result<T, StoreError>
+    where
+        F: FnOnce(&postgres::Connection) -> Result<T, StoreError>,
+    {
+        let mut copy_conn = self.copy_conn.lock().unwrap();
+        if copy_conn.is_none() {
+            let conn = postgres::Connection::connect(self.postgres_url.as_str(), TlsMode::None)
+                .map_err(|e| {
+                    StoreError::Unknown(format_err!("failed to connect to Postgres: {}", e))
+                })?;
+            *copy_conn = Some(conn);
         }
+        f(copy_conn.as_ref().unwrap())
+    }
+
+    /// Delete many entities of type `entity_type` of this subgraph at the
+    /// block of `history_event`; see `Layout::delete_many`
+    pub(crate) fn delete_many(
+        &self,
+        entity_type: &str,
+        ids: &[&str],
+        history_event: &HistoryEvent,
+    ) -> Result<usize, StoreError> {
+        self.layout
+            .delete_many(&self.conn, entity_type, ids, block_number(history_event))
     }
 
     pub(crate) fn revert_block(
@@ -598,227 +541,262 @@ impl Connection<'_> {
             .expect("block numbers fit into an i32");
 
         // Revert the block in the subgraph itself
-        let (event, count) = match &*self.storage {
-            Storage::Json(json) => json.revert_block(&self.conn, block_ptr.hash_hex())?,
-            Storage::Relational(layout) => layout.revert_block(&self.conn, block)?,
-        };
+        let (event, count) = self.layout.revert_block(&self.conn, block)?;
         // Revert the meta data changes that correspond to this subgraph.
         // Only certain meta data changes need to be reverted, most
         // importantly creation of dynamic data sources. We ensure in the
         // rest of the code that we only record history for those meta data
         // changes that might need to be reverted
         let meta_event =
-            self.metadata_layout()
-                .revert_metadata(&self.conn, &self.storage.subgraph(), block)?;
+            self.metadata
+                .revert_metadata(self.meta_conn(), &self.layout.subgraph, block)?;
+        self.invalidate_moves(block)?;
         Ok((event.extend(meta_event), count))
     }
 
+    /// Adjust the `entityCount` property of the `SubgraphDeployment` for
+    /// this subgraph by `count`. This needs to be performed after the
+    /// changes underlying `count` have been written to the store
     pub(crate) fn update_entity_count(&self, count: i32) -> Result<(), StoreError> {
         if count == 0 {
             return Ok(());
         }
 
-        self.storage.update_entity_count(&self.conn, count)
-    }
-
-    pub(crate) fn create_history_event(
-        &self,
-        block_ptr: EthereumBlockPointer,
-        mods: &Vec<EntityModification>,
-    ) -> Result<HistoryEvent, Error> {
-        let has_removes = mods.iter().any(|m| m.is_remove());
-        match &*self.storage {
-            Storage::Json(json) => {
-                HistoryEvent::allocate(&self.conn, json.subgraph.clone(), block_ptr, has_removes)
+        // The big complication in this query is how to determine what the
+        // new entityCount should be. We want to make sure that if the entityCount
+        // is NULL or the special value `-1`, it gets recomputed. Using `-1` here
+        // makes it possible to manually set the `entityCount` to that value
+        // to force a recount; setting it to `NULL` is not desirable since
+        // `entityCount` on the GraphQL level is not nullable, and so setting
+        // `entityCount` to `NULL` could cause errors at that layer; temporarily
+        // returning `-1` is more palatable. To be exact, recounts have to be
+        // done here, from the subgraph writer.
+        //
+        // The first argument of `coalesce` will be `NULL` if the entity count
+        // is `NULL` or `-1`, forcing `coalesce` to evaluate its second
+        // argument, the query to count entities. In all other cases,
+        // `coalesce` does not evaluate its second argument
+        let count_query = self.layout.count_query.as_str();
+        let subgraph = self.layout.subgraph.to_string();
+        let meta_conn = match &self.meta_conn {
+            None => {
+                let query = format!(
+                    "
+                    update subgraphs.subgraph_deployment
+                       set entity_count =
+                             coalesce((nullif(entity_count, -1)) + $1,
+                                      ({count_query}))
+                     where id = $2
+                    ",
+                    count_query = count_query
+                );
+                return Ok(diesel::sql_query(query)
+                    .bind::<Integer, _>(count)
+                    .bind::<Text, _>(subgraph)
+                    .execute(&*self.conn)
+                    .map(|_| ())?);
             }
-            Storage::Relational(layout) => {
-                // For relational storage, we do not need an entry in event_meta_data
-                Ok(HistoryEvent::create_without_event_metadata(
-                    layout.subgraph.clone(),
-                    block_ptr,
-                ))
+            Some(meta_conn) => meta_conn,
+        };
+
+        // The entities live in a different database than the metadata,
+        // and we can not count them as part of the update. Recounting is
+        // rare enough that it is fine to do it in a separate query
+        let updated = diesel::sql_query(
+            "update subgraphs.subgraph_deployment
+                set entity_count = entity_count + $1
+              where id = $2 and entity_count <> -1",
+        )
+        .bind::<Integer, _>(count)
+        .bind::<Text, _>(&subgraph)
+        .execute(&***meta_conn)?;
+        if updated == 0 {
+            #[derive(QueryableByName)]
+            struct Count {
+                #[sql_type = "BigInt"]
+                count: i64,
             }
+            let count = diesel::sql_query(format!(
+                "select coalesce(({}), 0)::int8 as count",
+                count_query
+            ))
+            .get_result::<Count>(&*self.conn)?
+            .count;
+            diesel::sql_query(
+                "update subgraphs.subgraph_deployment
+                    set entity_count = $1
+                  where id = $2",
+            )
+            .bind::<BigInt, _>(count)
+            .bind::<Text, _>(&subgraph)
+            .execute(&***meta_conn)?;
         }
+        Ok(())
     }
 
-    /// Check if the schema for `subgraph` needs to be migrated, and if so
-    /// if now (indicated by the block pointer) is the right time to do so.
-    /// We try to spread the actual database work associated with checking
-    /// if a subgraph should be migrated out as much as possible. This
-    /// function does not query the database, and the actual check for
-    /// migrating should only be done if this function returns `true`
-    pub(crate) fn should_migrate(
-        &self,
-        subgraph: &SubgraphDeploymentId,
-        block_ptr: &EthereumBlockPointer,
-    ) -> Result<bool, StoreError> {
-        // How often to check whether a subgraph needs to be migrated (in
-        // blocks) Doing it every 20 blocks translates to roughly once every
-        // 5 minutes
-        const MIGRATION_CHECK_FREQ: u64 = 20;
-
-        if self.storage.needs_migrating() {
-            // We determine whether it is time for us to check if we should
-            // migrate in a way that tries to splay the checks for different
-            // subgraphs, using the hash of the subgraph id as a somewhat
-            // arbitrary indicator. We really just want the checks to be
-            // distributed across all possible values mod MIGRATION_CHECK_FREQ
-            // so that we don't have a mad dash to migrate every
-            // MIGRATION_CHECK_FREQ blocks, which would happen if we checked
-            // for `block_ptr.number % MIGRATION_CHECK_FREQ == 0`
-            let mut hasher = DefaultHasher::new();
-            subgraph.hash(&mut hasher);
-            let hash = hasher.finish();
-            Ok(hash % MIGRATION_CHECK_FREQ == block_ptr.number % MIGRATION_CHECK_FREQ)
-        } else {
-            Ok(false)
+    pub(crate) fn create_history_event(&self, block_ptr: EthereumBlockPointer) -> HistoryEvent {
+        HistoryEvent::new(self.layout.subgraph.clone(), block_ptr)
+    }
+
+    pub(crate) fn send_store_event(&self, event: &StoreEvent) -> Result<(), StoreError> {
+        let v = serde_json::to_value(event)?;
+        JsonNotification::send("store_events", &v, self.meta_conn())
+    }
+
+    pub(crate) fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<diesel::result::Error>,
+    {
+        match &self.meta_conn {
+            Some(meta_conn) => self.two_phase_transaction(meta_conn, f),
+            None => self.conn.transaction(f),
         }
     }
 
-    /// Check if the database schema for `subgraph` needs to be migrated, and
-    /// if so, perform the migration. Return `true` if a migration was
-    /// performed, and `false` otherwise. A return value of `false` does not
-    /// indicate that no migration is necessary, just that we currently can
-    /// not perform it, for example, because too many other subgraphs are
-    /// migrating.
-    ///
-    /// Migrating requires performing multiple transactions, and the connection
-    /// in `self` must therefore not have a transaction open already.
-    pub(crate) fn migrate(
-        self,
-        logger: &Logger,
-        block_ptr: &EthereumBlockPointer,
-    ) -> Result<bool, Error> {
-        // How many simultaneous subgraph migrations we allow
-        const MIGRATION_LIMIT: i32 = 2;
-        let subgraph = self.storage.subgraph();
+    /// Run `f` in a transaction in the shard that stores the subgraph's
+    /// entities and in the primary, and commit both transactions or
+    /// neither. The transaction in the shard is prepared before the
+    /// changes to the metadata are committed, and committed after that;
+    /// if we crash in between, `finish_prepared_transactions` completes it
+    /// when the subgraph is started the next time. This requires that the
+    /// shard's database allows prepared transactions by setting
+    /// `max_prepared_transactions`
+    fn two_phase_transaction<T, E, F>(&self, meta_conn: &PgConnection, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<diesel::result::Error>,
+    {
+        use public::deployment_prepared_transactions as pt;
 
-        if !self.should_migrate(subgraph, block_ptr)? {
-            return Ok(false);
+        let conn: &PgConnection = &self.conn;
+        let tm = conn.transaction_manager();
+        // Inside of another transaction, we only need savepoints
+        if tm.get_transaction_depth() > 0 {
+            return meta_conn.transaction(|| conn.transaction(f));
         }
 
-        let do_migrate = self.conn.transaction(|| -> Result<bool, Error> {
-            let lock =
-                diesel::sql_query("lock table public.deployment_schemas in exclusive mode nowait")
-                    .execute(self.conn.deref());
-            if lock.is_err() {
-                return Ok(false);
+        let subgraph = self.layout.subgraph.as_str();
+        let gid = format!(
+            "{}{}",
+            prepared_transaction_prefix(&self.layout.subgraph),
+            Uuid::new_v4().to_simple()
+        );
+        let mut prepared = false;
+        tm.begin_transaction(conn)?;
+        let result = meta_conn.transaction(|| {
+            let value = f()?;
+            conn.batch_execute(&format!("prepare transaction '{}'", gid))?;
+            prepared = true;
+            diesel::insert_into(pt::table)
+                .values((pt::gid.eq(&gid), pt::subgraph.eq(subgraph)))
+                .execute(meta_conn)?;
+            Ok(value)
+        });
+        // After `prepare transaction`, the transaction is not associated
+        // with this connection anymore and this only resets the
+        // transaction depth that `tm` keeps track of
+        tm.rollback_transaction(conn)?;
+        if !prepared {
+            return result;
+        }
+
+        match result {
+            Ok(value) => {
+                conn.batch_execute(&format!("commit prepared '{}'", gid))?;
+                diesel::delete(pt::table.filter(pt::gid.eq(&gid))).execute(meta_conn)?;
+                Ok(value)
+            }
+            Err(e) => {
+                conn.batch_execute(&format!("rollback prepared '{}'", gid))?;
+                Err(e)
             }
+        }
+    }
 
-            let query = "
-                UPDATE public.deployment_schemas
-                   SET migrating = true
-                 WHERE subgraph=$1
-                   AND (SELECT count(*) FROM public.deployment_schemas WHERE migrating) < $2";
-            let query = diesel::sql_query(query)
-                .bind::<Text, _>(subgraph.to_string())
-                .bind::<Integer, _>(MIGRATION_LIMIT);
-            Ok(query.execute(self.conn.deref())? > 0)
-        })?;
-
-        if do_migrate {
-            use self::public::deployment_schemas as dsl;
-
-            let result = loop {
-                match self.migration_step(logger, subgraph) {
-                    Err(e) => {
-                        // An error in a migration should not lead to the
-                        // subgraph being marked as failed
-                        warn!(logger, "aborted migrating";
-                                        "subgraph" => subgraph.to_string(),
-                                        "error" => e.to_string(),
-                        );
-                        break Ok(false);
-                    }
-                    Ok(again) if !again => break Ok(true),
-                    Ok(_) => continue,
-                }
+    /// Complete the two-phase commits for this subgraph that were
+    /// interrupted by a crash: transactions whose metadata changes were
+    /// committed are committed, all others are rolled back. This must not
+    /// be called inside a transaction
+    pub(crate) fn finish_prepared_transactions(&self, logger: &Logger) -> Result<(), StoreError> {
+        use public::deployment_prepared_transactions as pt;
+
+        #[derive(QueryableByName)]
+        struct PreparedTransaction {
+            #[sql_type = "Text"]
+            gid: String,
+        }
+
+        let meta_conn = match &self.meta_conn {
+            Some(meta_conn) => meta_conn,
+            None => return Ok(()),
+        };
+        let subgraph = self.layout.subgraph.as_str();
+        let committed = pt::table
+            .select(pt::gid)
+            .filter(pt::subgraph.eq(subgraph))
+            .load::<String>(&***meta_conn)?;
+        let conn: &PgConnection = &self.conn;
+        let prepared = diesel::sql_query(
+            "select gid from pg_prepared_xacts \
+              where database = current_database() and gid like $1 || '%'",
+        )
+        .bind::<Text, _>(prepared_transaction_prefix(&self.layout.subgraph))
+        .load::<PreparedTransaction>(conn)?;
+        for PreparedTransaction { gid } in prepared {
+            let action = if committed.contains(&gid) {
+                "commit"
+            } else {
+                "rollback"
             };
-            // Relinquish the migrating lock, no matter what happened in
-            // the migration
-            diesel::update(dsl::table.filter(dsl::subgraph.eq(subgraph.to_string())))
-                .set(dsl::migrating.eq(false))
-                .execute(self.conn.deref())?;
-            result
-        } else {
-            Ok(false)
+            info!(logger, "Finishing interrupted write"; "action" => action, "gid" => &gid);
+            conn.batch_execute(&format!("{} prepared '{}'", action, gid))?;
         }
+        diesel::delete(pt::table.filter(pt::subgraph.eq(subgraph))).execute(&***meta_conn)?;
+        Ok(())
     }
 
-    /// Perform one migration step and return true if there are more steps
-    /// left to do. Each step of the migration is performed in  a separate
-    /// transaction so that any locks a step takes are freed up at the end
-    // We do not currently use this, but getting the framework right was
-    // painful enough that we should preserve the general setup of
-    // per-subgraph migrations
-    #[allow(unreachable_code, unused_variables)]
-    fn migration_step(
+    /// Pick the shard for the new deployment `subgraph` according to
+    /// `rules`. Grafts are always placed in the shard of their base so
+    /// that the data of the base can be copied within one database
+    pub(crate) fn place(
         &self,
-        logger: &Logger,
         subgraph: &SubgraphDeploymentId,
-    ) -> Result<bool, Error> {
-        unreachable!("The curent code base does not require any subgraph migrations");
-        self.conn.transaction(|| -> Result<bool, Error> {
-            let errmsg = format_err!(
-                "subgraph {} has no entry in deployment_schemas and can not be migrated",
-                subgraph.to_string()
-            );
-            let schema = find_schema(&self.conn, &subgraph)?.ok_or(errmsg)?;
-
-            debug!(
-                logger,
-                "start migrating";
-                "name" => &schema.name,
-                "subgraph" => subgraph.to_string(),
-                "state" => format!("{:?}", schema.state)
-            );
-            let start = Instant::now();
-            // Do the actual migration, and return an updated storage
-            // object, something like
-            //
-            // let storage = storage.migrate(&self.conn, logger, &schema)?;
-            // let needs_migrating = storage.needs_migrating();
-            // self.cache.borrow_mut().insert(subgraph.clone(), Arc::new(storage));
-            //
-            info!(
-                logger,
-                "finished migrating";
-                "name" => &schema.name,
-                "subgraph" => subgraph.to_string(),
-                "state" => format!("{:?}", schema.state),
-                "migration_time_ms" => start.elapsed().as_millis()
-            );
-            Ok(self.storage.needs_migrating())
-        })
-    }
-
-    pub(crate) fn send_store_event(&self, event: &StoreEvent) -> Result<(), StoreError> {
-        let v = serde_json::to_value(event)?;
-        JsonNotification::send("store_events", &v, &self.conn)
-    }
-
-    pub(crate) fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
-    where
-        F: FnOnce() -> Result<T, E>,
-        E: From<diesel::result::Error>,
-    {
-        self.conn.transaction(f)
+        rules: &[PlacementRule],
+    ) -> Result<String, StoreError> {
+        if let Some((base, _)) = metadata::deployment_graft(self.meta_conn(), subgraph)? {
+            return find_site(self.meta_conn(), &base)?
+                .map(|site| site.shard)
+                .ok_or_else(|| {
+                    StoreError::Unknown(format_err!("unknown graft base subgraph {}", base))
+                });
+        }
+        let names = metadata::subgraph_names(self.meta_conn(), subgraph)?;
+        Ok(sharding::place(rules, &names).to_owned())
     }
 
     /// Create the database schema for a new subgraph, including all tables etc.
+    /// The schema is created in `shard`; if that is not the primary, `conn`
+    /// must be a connection to that shard. Only the entry in
+    /// `deployment_schemas` is made through `self`
     ///
     /// It is an error if `deployment_schemas` already has an entry for this
     /// `subgraph_id`. Note that `self` must be a connection for the subgraph
     /// of subgraphs
-    pub(crate) fn create_schema(&self, schema: &SubgraphSchema) -> Result<(), StoreError> {
+    pub(crate) fn create_schema(
+        &self,
+        schema: &SubgraphSchema,
+        shard: &str,
+        conn: Option<&PgConnection>,
+    ) -> Result<(), StoreError> {
         use self::public::DeploymentSchemaState as s;
         use self::public::DeploymentSchemaVersion as v;
 
         assert_eq!(
-            &*SUBGRAPHS_ID,
-            self.storage.subgraph(),
+            &*SUBGRAPHS_ID, &self.layout.subgraph,
             "create_schema can only be called on a Connection for the metadata subgraph"
         );
+        let conn = conn.unwrap_or(self.meta_conn());
 
         // Check if there already is an entry for this subgraph. If so, do
         // nothing
@@ -834,8 +812,9 @@ impl Connection<'_> {
         let schemas: Vec<String> = diesel::insert_into(deployment_schemas::table)
             .values((
                 deployment_schemas::subgraph.eq(schema.id.to_string()),
-                deployment_schemas::version.eq(*GRAPH_STORAGE_SCHEME),
+                deployment_schemas::version.eq(v::Relational),
                 deployment_schemas::state.eq(s::Init),
+                deployment_schemas::shard.eq(shard),
             ))
             .returning(deployment_schemas::name)
             .get_results(self.conn.deref())?;
@@ -844,77 +823,180 @@ impl Connection<'_> {
             .ok_or_else(|| format_err!("failed to read schema name for {} back", &schema.id))?;
 
         let query = format!("create schema {}", schema_name);
-        self.conn.batch_execute(&*query)?;
-
-        match *GRAPH_STORAGE_SCHEME {
-            v::Relational => {
-                let layout =
-                    Layout::create_relational_schema(&self.conn, schema, schema_name.to_owned())?;
-                // See if we are grafting and check that the graft is permissible
-                if let Some((base, _)) = metadata::deployment_graft(&self.conn, &schema.id)? {
-                    match Storage::new(&self.conn, &base)? {
-                        Storage::Relational(base) => {
-                            let errors = layout.can_copy_from(&base);
-                            if !errors.is_empty() {
-                                return Err(StoreError::Unknown(format_err!(
-                                    "The subgraph `{}` cannot be used as the graft base \
-                                        for `{}` because the schemas are incompatible:\n    - {}",
-                                    &base.subgraph,
-                                    &layout.subgraph,
-                                    errors.join("\n    - ")
-                                )));
-                            }
-                        }
-                        Storage::Json(json) => {
-                            return Err(StoreError::Unknown(format_err!(
-                                "The subgraph `{}` cannot be used as the graft base \
-                                    for `{}` since it uses JSONB storage. Redeploy \
-                                    `{}` to fix this",
-                                &json.subgraph,
-                                &schema.id,
-                                &json.subgraph
-                            )));
-                        }
-                    }
-                }
-                Ok(())
-            }
-            v::Split => {
-                if metadata::deployment_graft(&self.conn, &schema.id)?.is_some() {
-                    return Err(StoreError::Unknown(format_err!(
-                        "JSONB storage does not support grafting onto another subgraph",
-                    )));
-                }
-                create_split_schema(&self.conn, &schema_name)
+        conn.batch_execute(&*query)?;
+
+        let layout = Layout::create_relational_schema(conn, schema, schema_name.to_owned())?;
+        // See if we are grafting and check that the graft is permissible
+        if let Some((base, _)) = metadata::deployment_graft(&self.conn, &schema.id)? {
+            let base = load_layout(&self.conn, conn, &base)?;
+            let errors = layout.can_copy_from(&base);
+            if !errors.is_empty() {
+                return Err(StoreError::Unknown(format_err!(
+                    "The subgraph `{}` cannot be used as the graft base \
+                     for `{}` because the schemas are incompatible:\n    - {}",
+                    &base.subgraph,
+                    &layout.subgraph,
+                    errors.join("\n    - ")
+                )));
             }
         }
+        Ok(())
+    }
+
+    /// Lock the deployment until the end of the current transaction; see
+    /// `metadata::lock_deployment`
+    pub(crate) fn lock_deployment(&self) -> Result<(), StoreError> {
+        metadata::lock_deployment(self.meta_conn(), &self.layout.subgraph)
+    }
+
+    /// Record that queries for blocks before `block` are no longer
+    /// possible
+    pub(crate) fn set_pruned_block(&self, block: BlockNumber) -> Result<(), StoreError> {
+        metadata::set_pruned_block(self.meta_conn(), &self.layout.subgraph, block)
+    }
+
+    /// Remove all entity versions that are not visible at `block` or any
+    /// later block, committing after each batch. Returns the number of
+    /// versions that were removed
+    pub(crate) fn prune(&self, block: BlockNumber) -> Result<usize, StoreError> {
+        self.layout.prune(&self.conn, block)
+    }
+
+    /// Create an index on `fields` of `entity_type` without blocking writes
+    /// to the underlying table; this must not be called inside a
+    /// transaction. Returns the name of the new index
+    pub(crate) fn create_index(
+        &self,
+        entity_type: &str,
+        fields: &[String],
+    ) -> Result<String, StoreError> {
+        self.layout.create_index(&self.conn, entity_type, fields)
     }
 
-    pub(crate) fn uses_relational_schema(&self) -> bool {
-        match &*self.storage {
-            Storage::Json(_) => false,
-            Storage::Relational(_) => true,
+    /// Drop the index `name` without blocking writes; this must not be
+    /// called inside a transaction
+    pub(crate) fn drop_index(&self, name: &str) -> Result<(), StoreError> {
+        self.layout.drop_index(&self.conn, name)
+    }
+
+    pub(crate) fn entity_stats(&self) -> Result<Vec<EntityTypeStats>, StoreError> {
+        self.layout.entity_stats(&self.conn)
+    }
+
+    pub(crate) fn estimated_entity_stats(&self) -> Result<Vec<EntityTypeStats>, StoreError> {
+        self.layout.estimated_entity_stats(&self.conn)
+    }
+
+    /// Find the entities that were changed in `block`. Pruning removes
+    /// the versions that ended at or before the pruned block, and with
+    /// them the updates and removals in those blocks; we refuse to report
+    /// changes for them rather than report incomplete ones
+    pub(crate) fn entity_changes_in_block(
+        &self,
+        block: BlockNumber,
+    ) -> Result<Vec<EntityTypeChanges>, StoreError> {
+        let pruned_block = self.pruned_block()?;
+        if pruned_block > 0 && block <= pruned_block {
+            return Err(format_err!(
+                "Can not list the entity changes of subgraph `{}` in block {} \
+                 as its history up to block {} has been removed",
+                self.layout.subgraph,
+                block,
+                pruned_block
+            )
+            .into());
         }
+        self.layout.entity_changes_in_block(&self.conn, block)
     }
 
-    fn metadata_layout(&self) -> &Layout {
-        match &*self.metadata {
-            Storage::Json(_) => unreachable!("JSONB storage of subgraph metadata is not supported"),
-            Storage::Relational(layout) => layout,
+    /// Check that the database schema of this subgraph can be changed in
+    /// place to match `schema`, and generate the DDL that does that
+    pub(crate) fn schema_evolution(
+        &self,
+        schema: &SubgraphSchema,
+    ) -> Result<SchemaEvolution, StoreError> {
+        let layout = &self.layout;
+        let catalog = Catalog::new(&self.conn, layout.catalog.schema.clone())?;
+        let new_layout = Layout::new(schema, catalog, self.supports_proof_of_indexing())?;
+        let errors = new_layout.can_evolve_from(layout);
+        if !errors.is_empty() {
+            return Err(StoreError::Unknown(format_err!(
+                "the schema of subgraph {} can not be changed in place: {}",
+                layout.subgraph,
+                errors.join(", ")
+            )));
         }
+        new_layout
+            .evolve_ddl(layout)
+            .map_err(|_| StoreError::Unknown(format_err!("failed to generate DDL for layout")))
     }
 
-    pub(crate) fn supports_proof_of_indexing(&self) -> bool {
-        match &*self.storage {
-            Storage::Json(_) => false,
-            Storage::Relational(layout) => layout.tables.contains_key(POI_OBJECT),
+    /// Add the new enum values from `evolution`. Postgres does not allow
+    /// that inside a transaction, and this must therefore be called before
+    /// `evolve_schema` and outside of any transaction
+    pub(crate) fn add_enum_values(&self, evolution: &SchemaEvolution) -> Result<(), StoreError> {
+        for stmt in &evolution.enum_values {
+            self.conn.batch_execute(stmt)?;
         }
+        Ok(())
+    }
+
+    /// Change the tables of this subgraph according to `evolution` and
+    /// make `schema` the subgraph's schema
+    pub(crate) fn evolve_schema(
+        &self,
+        evolution: &SchemaEvolution,
+        schema: &SubgraphSchema,
+    ) -> Result<(), StoreError> {
+        self.conn.batch_execute(&evolution.ddl)?;
+        metadata::set_subgraph_schema(
+            self.meta_conn(),
+            &self.layout.subgraph,
+            &schema.document.to_string(),
+        )
+    }
+
+    /// Undo all changes made to this subgraph after `block`, but keep the
+    /// changes made in `block` itself
+    pub(crate) fn rewind(&self, block: BlockNumber) -> Result<(StoreEvent, i32), StoreError> {
+        // `revert_block` gets rid of everything including the block
+        // passed to it
+        let (event, count) = self.layout.revert_block(&self.conn, block + 1)?;
+        let meta_event =
+            self.metadata
+                .revert_metadata(self.meta_conn(), &self.layout.subgraph, block + 1)?;
+        self.invalidate_moves(block + 1)?;
+        Ok((event.extend(meta_event), count))
+    }
+
+    /// Mark a copy of this subgraph into another shard as unusable if it
+    /// contains data from `block` or later, which was just reverted
+    fn invalidate_moves(&self, block: BlockNumber) -> Result<(), StoreError> {
+        use public::deployment_moves as dm;
+
+        diesel::update(
+            dm::table
+                .filter(dm::subgraph.eq(self.layout.subgraph.as_str()))
+                .filter(dm::target_block_number.ge(block)),
+        )
+        .set(dm::invalidated.eq(true))
+        .execute(self.meta_conn())?;
+        Ok(())
+    }
+
+    /// The block before which the history of this subgraph has been removed
+    pub(crate) fn pruned_block(&self) -> Result<BlockNumber, StoreError> {
+        metadata::pruned_block(self.meta_conn(), &self.layout.subgraph)
+    }
+
+    pub(crate) fn supports_proof_of_indexing(&self) -> bool {
+        self.layout.tables.contains_key(POI_OBJECT)
     }
 }
 
-// Find the database schema for `subgraph`. If no explicit schema exists,
-// return `None`.
-fn find_schema(
+/// Find the database schema for `subgraph`. If no explicit schema exists,
+/// return `None`.
+pub(crate) fn find_schema(
     conn: &diesel::pg::PgConnection,
     subgraph: &SubgraphDeploymentId,
 ) -> Result<Option<Schema>, StoreError> {
@@ -924,551 +1006,314 @@ fn find_schema(
         .optional()?)
 }
 
-fn supports_proof_of_indexing(
-    conn: &diesel::pg::PgConnection,
-    subgraph_id: &SubgraphDeploymentId,
-    schema: &str,
-) -> Result<bool, StoreError> {
-    if subgraph_id == &*SUBGRAPHS_ID {
-        return Ok(false);
-    }
-    #[derive(Debug, QueryableByName)]
-    struct Table {
-        #[sql_type = "Text"]
-        pub table_name: String,
+impl Schema {
+    /// Whether the subgraph still stores its entities as JSONB
+    pub(crate) fn is_jsonb(&self) -> bool {
+        matches!(self.version, public::DeploymentSchemaVersion::Split)
     }
-    let query =
-        "SELECT table_name FROM information_schema.tables WHERE table_schema=$1 AND table_name=$2";
-    let result: Vec<Table> = diesel::sql_query(query)
-        .bind::<Text, _>(schema)
-        .bind::<Text, _>(POI_TABLE)
-        .load(conn)?;
-    Ok(result.len() > 0)
 }
 
-fn entity_to_json(key: &EntityKey, entity: &Entity) -> Result<serde_json::Value, Error> {
-    serde_json::to_value(entity).map_err(|e| {
-        format_err!(
-            "Failed to convert entity ({}, {}, {}) to JSON: {}",
-            key.subgraph_id,
-            key.entity_type,
-            key.entity_id,
-            e
-        )
-    })
+/// The deployments that still store their entities as JSONB
+pub(crate) fn jsonb_deployments(conn: &PgConnection) -> Result<Vec<String>, StoreError> {
+    use public::deployment_schemas as ds;
+    use public::DeploymentSchemaVersion as V;
+
+    Ok(ds::table
+        .select(ds::subgraph)
+        .filter(ds::version.eq(V::Split))
+        .filter(ds::subgraph.ne(SUBGRAPHS_ID.as_str()))
+        .order_by(ds::subgraph)
+        .load::<String>(conn)?)
 }
 
-fn entity_from_json(json: serde_json::Value, entity: &str) -> Result<Entity, StoreError> {
-    let mut value = serde_json::from_value::<Entity>(json)?;
-    value.set("__typename", entity);
-    Ok(value)
+/// Make `subgraph` use the relational tables in the database schema
+/// `namespace` from now on
+pub(crate) fn set_relational(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    namespace: &str,
+) -> Result<(), StoreError> {
+    use public::deployment_schemas as ds;
+    use public::DeploymentSchemaState as S;
+    use public::DeploymentSchemaVersion as V;
+
+    diesel::update(ds::table.filter(ds::subgraph.eq(subgraph.as_str())))
+        .set((
+            ds::name.eq(namespace),
+            ds::version.eq(V::Relational),
+            ds::state.eq(S::Ready),
+        ))
+        .execute(conn)?;
+    Ok(())
 }
 
-impl JsonStorage {
-    fn find(
-        &self,
-        conn: &PgConnection,
-        entity: &str,
-        id: &str,
-    ) -> Result<Option<Entity>, StoreError> {
-        let entities = self.clone();
-        entities
-            .table
-            .filter(entities.entity.eq(entity).and(entities.id.eq(id)))
-            .select(entities.data)
-            .first::<serde_json::Value>(conn)
-            .optional()?
-            .map(|json| entity_from_json(json, entity))
-            .transpose()
-    }
-
-    /// order is a tuple (attribute, value_type, direction)
-    fn query<T: From<Entity>>(
-        &self,
-        conn: &PgConnection,
-        collection: EntityCollection,
-        filter: Option<EntityFilter>,
-        order: Option<(String, ValueType, &'static str)>,
-        range: EntityRange,
-    ) -> Result<Vec<T>, QueryExecutionError> {
-        let query = FilterQuery::new(&self.table, collection, filter, order, range)?;
-
-        let query_debug_info = debug_query(&query).to_string();
-
-        let values = query
-            .load::<(String, serde_json::Value, String)>(conn)
-            .map_err(|e| {
-                QueryExecutionError::ResolveEntitiesError(format!(
-                    "{}, query = {:?}",
-                    e, query_debug_info
-                ))
-            })?;
-        values
-            .into_iter()
-            .map(|(_, value, entity_type)| {
-                entity_from_json(value, &entity_type)
-                    .map(T::from)
-                    .map_err(QueryExecutionError::from)
-            })
-            .collect()
-    }
-
-    fn insert(
-        &self,
-        conn: &PgConnection,
-        key: &EntityKey,
-        entity: Entity,
-        history_event: Option<&HistoryEvent>,
-    ) -> Result<usize, StoreError> {
-        let data = entity_to_json(key, &entity)?;
-        let event_source = HistoryEvent::to_event_source_string(&history_event);
-
-        self.add_entity_history_record(conn, history_event, &key, OperationType::Insert)?;
-
-        Ok(diesel::sql_query(format!(
-            "insert into {}.entities(entity, id, data, event_source)
-                       values($1, $2, $3, $4)",
-            self.schema
-        ))
-        .bind::<Text, _>(&key.entity_type)
-        .bind::<Text, _>(&key.entity_id)
-        .bind::<Jsonb, _>(&data)
-        .bind::<Text, _>(&event_source)
-        .execute(conn)?)
-    }
+/// Where the entities of a deployment are stored
+#[derive(PartialEq)]
+pub(crate) struct Site {
+    /// The name of the database schema
+    pub namespace: String,
+    /// The shard that contains the database schema
+    pub shard: String,
+}
 
-    fn upsert(
-        &self,
-        conn: &PgConnection,
-        key: &EntityKey,
-        data: &serde_json::Value,
-        history_event: Option<&HistoryEvent>,
-    ) -> Result<usize, StoreError> {
-        let event_source = HistoryEvent::to_event_source_string(&history_event);
+/// Find the site for `subgraph`; `conn` must be a connection to the
+/// primary shard
+pub(crate) fn find_site(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+) -> Result<Option<Site>, StoreError> {
+    Ok(find_schema(conn, subgraph)?.map(|schema| Site {
+        namespace: schema.name,
+        shard: schema.shard,
+    }))
+}
 
-        let query = format!(
-            "insert into {}.entities(entity, id, data, event_source)
-                       values($1, $2, $3, $4)
-                     on conflict(entity, id)
-                       do update set data = $3, event_source = $4",
-            self.schema
-        );
-        let query = diesel::sql_query(query)
-            .bind::<Text, _>(&key.entity_type)
-            .bind::<Text, _>(&key.entity_id)
-            .bind::<Jsonb, _>(data)
-            .bind::<Text, _>(event_source);
-        Ok(query.execute(conn)?)
-    }
+/// Find the shard in which the copy state of each of `subgraphs` is kept.
+/// That is the shard a subgraph is being moved to if it is being moved,
+/// and the shard that holds its entities otherwise. Subgraphs we know
+/// nothing about are left out. `conn` must be a connection to the primary
+/// shard
+pub(crate) fn find_copy_shards(
+    conn: &PgConnection,
+    subgraphs: &[&str],
+) -> Result<HashMap<String, String>, StoreError> {
+    use public::deployment_moves as dm;
+    use public::deployment_schemas as ds;
+
+    let sites = ds::table
+        .select((ds::subgraph, ds::shard))
+        .filter(ds::subgraph.eq_any(subgraphs))
+        .load::<(String, String)>(conn)?;
+    let moves = dm::table
+        .select((dm::subgraph, dm::shard))
+        .filter(dm::subgraph.eq_any(subgraphs))
+        .load::<(String, String)>(conn)?;
+    // Moves come last so that they replace the entry from `sites`
+    Ok(sites.into_iter().chain(moves.into_iter()).collect())
+}
 
-    fn update(
-        &self,
-        conn: &PgConnection,
-        key: &EntityKey,
-        entity: Entity,
-        history_event: Option<&HistoryEvent>,
-    ) -> Result<usize, StoreError> {
-        let data = entity_to_json(key, &entity)?;
+/// The prefix of the global ids of the prepared transactions that
+/// `Connection::two_phase_transaction` uses for `subgraph`
+fn prepared_transaction_prefix(subgraph: &SubgraphDeploymentId) -> String {
+    format!("graph_{}_", subgraph)
+}
 
-        self.add_entity_history_record(conn, history_event, &key, OperationType::Update)?;
+/// Make the entities of `subgraph` use the database schema described by
+/// `site` from now on
+pub(crate) fn set_site(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    site: &Site,
+) -> Result<(), StoreError> {
+    use public::deployment_schemas as ds;
 
-        let event_source = HistoryEvent::to_event_source_string(&history_event);
+    diesel::update(ds::table.filter(ds::subgraph.eq(subgraph.as_str())))
+        .set((
+            ds::name.eq(site.namespace.as_str()),
+            ds::shard.eq(site.shard.as_str()),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
 
-        // We need to use a direct query since diesel::update does not like
-        // dynamic tables.
-        let query = format!(
-            "update {}.entities
-                       set data = $3, event_source = $4
-                       where entity = $1 and id = $2",
-            self.schema
-        );
-        let query = diesel::sql_query(query)
-            .bind::<Text, _>(&key.entity_type)
-            .bind::<Text, _>(&key.entity_id)
-            .bind::<Jsonb, _>(data)
-            .bind::<Text, _>(&event_source);
-        query.execute(conn).map_err(|e| {
-            format_err!(
-                "Failed to update entity ({}, {}, {}): {}",
-                key.subgraph_id,
-                key.entity_type,
-                key.entity_id,
-                e
-            )
-            .into()
-        })
+/// Pick the name for a new database schema for a deployment
+pub(crate) fn allocate_namespace(conn: &PgConnection) -> Result<String, StoreError> {
+    #[derive(QueryableByName)]
+    struct Namespace {
+        #[sql_type = "Text"]
+        name: String,
     }
+    Ok(
+        diesel::sql_query("select 'sgd'||nextval('deployment_schemas_id_seq') as name")
+            .get_result::<Namespace>(conn)?
+            .name,
+    )
+}
 
-    fn delete(
-        &self,
-        conn: &PgConnection,
-        key: &EntityKey,
-        history_event: Option<&HistoryEvent>,
-    ) -> Result<usize, StoreError> {
-        self.add_entity_history_record(conn, history_event, &key, OperationType::Delete)?;
+/// A deployment that is being moved to another shard
+pub(crate) struct Move {
+    /// The shard the deployment is moved to
+    pub shard: String,
+    /// The database schema in `shard` that receives the data
+    pub namespace: String,
+    /// The copy contains the data of the deployment as of this block
+    pub target_block: EthereumBlockPointer,
+    /// `true` if the deployment was reverted past `target_block` while
+    /// it was being copied
+    pub invalidated: bool,
+}
 
-        let query = format!(
-            "delete from {}.entities
-                      where entity = $1
-                        and id = $2",
-            self.schema
-        );
-        let query = diesel::sql_query(query)
-            .bind::<Text, _>(&key.entity_type)
-            .bind::<Text, _>(&key.entity_id);
-        Ok(query.execute(conn)?)
-    }
+/// The data that a subgraph had in the shard it was moved away from
+pub(crate) struct RetiredSite {
+    pub site: Site,
+    /// The data in `site` is as of this block
+    pub block: EthereumBlockPointer,
+}
 
-    fn conflicting_entity(
-        &self,
-        conn: &PgConnection,
-        entity_id: &String,
-        entities: Vec<&String>,
-    ) -> Result<Option<String>, StoreError> {
-        let ents = self.clone();
-        Ok(ents
-            .table
-            .select(ents.entity.clone())
-            .filter(ents.entity.eq(any(entities)))
-            .filter(ents.id.eq(entity_id))
-            .first(conn)
-            .optional()?)
-    }
+/// Find the data that `subgraph` left behind in its previous shard, and
+/// lock it for the rest of the current transaction
+pub(crate) fn find_retired_site(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+) -> Result<Option<RetiredSite>, StoreError> {
+    use public::deployment_retired_sites as rs;
+
+    Ok(rs::table
+        .select((rs::shard, rs::name, rs::block_hash, rs::block_number))
+        .filter(rs::subgraph.eq(subgraph.as_str()))
+        .for_update()
+        .first::<(String, String, Vec<u8>, i32)>(conn)
+        .optional()?
+        .map(|(shard, namespace, hash, number)| RetiredSite {
+            site: Site { namespace, shard },
+            block: EthereumBlockPointer {
+                hash: H256::from_slice(&hash),
+                number: number as u64,
+            },
+        }))
+}
 
-    /// This takes a history event, a reversion flag, an entity key to create
-    /// the history record for and an operation type (e.g. `OperationType::Insert`).
-    /// It then creates an entry in the subgraph's `entity_history` table.
-    ///
-    /// Special casing is applied for the `subgraphs.entities` table, as its
-    /// history records include the subgraph ID in the `subgraph` column.
-    fn add_entity_history_record(
-        &self,
-        conn: &PgConnection,
-        history_event: Option<&HistoryEvent>,
-        key: &EntityKey,
-        operation: OperationType,
-    ) -> Result<(), Error> {
-        // We only need to do work for the subgraph of subgraphs. All other
-        // entities tables have triggers that will populate a history record
-        // whenever we make a change to an entity
-        if !key.subgraph_id.is_meta() {
-            return Ok(());
-        }
-        let history_event = match history_event {
-            None => return Ok(()),
-            Some(event) => event,
-        };
-        let event_id = history_event
-            .id
-            .expect("we always allocate an event_id when we need to record metadata history");
-        let schema = self.schema.as_str();
-
-        diesel::sql_query(format!(
-            "insert into {}.entity_history(
-                   event_id,
-                   subgraph, entity, entity_id,
-                   data_before, op_id
-                 )
-                 select
-                   $1 as event_id,
-                   $2 as subgraph,
-                   $3 as entity,
-                   $4 as entity_id,
-                   (select data
-                      from {}.entities
-                     where entity = $3
-                       and id = $4) as data_before,
-                   $5 as op_id",
-            schema, schema,
+/// Remember `retired` as the data `subgraph` left behind in its previous
+/// shard, replacing what was recorded for it before
+pub(crate) fn retire_site(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    retired: &RetiredSite,
+) -> Result<(), StoreError> {
+    use public::deployment_retired_sites as rs;
+
+    delete_retired_site(conn, subgraph)?;
+    diesel::insert_into(rs::table)
+        .values((
+            rs::subgraph.eq(subgraph.as_str()),
+            rs::shard.eq(retired.site.shard.as_str()),
+            rs::name.eq(retired.site.namespace.as_str()),
+            rs::block_hash.eq(&retired.block.hash.0[..]),
+            rs::block_number.eq(retired.block.number as i32),
         ))
-        .bind::<Integer, _>(event_id)
-        .bind::<Text, _>(&*history_event.subgraph)
-        .bind::<Text, _>(&key.entity_type)
-        .bind::<Text, _>(&key.entity_id)
-        .bind::<Integer, i32>(operation.into())
         .execute(conn)?;
+    Ok(())
+}
 
-        Ok(())
-    }
-
-    /// Revert the block with the given `block_ptr` which must be the hash
-    /// of the block to revert. The returned `StoreEvent` reflects the changes
-    /// that were made during reversion
-    fn revert_block(
-        &self,
-        conn: &PgConnection,
-        block_ptr: String,
-    ) -> Result<(StoreEvent, i32), StoreError> {
-        // We can't use Diesel's JoinOnDsl here because DynamicTable
-        // does not implement AppearsInFromClause, so we have to run
-        // a raw SQL query
-        let query = format!(
-            "select h.id, h.entity, h.entity_id, h.data_before, h.op_id
-                       from {}.entity_history h, event_meta_data m
-                      where m.id = h.event_id
-                        and m.source = $1
-                      order by h.event_id desc",
-            self.schema
-        );
-
-        let query = diesel::sql_query(query).bind::<Text, _>(&block_ptr);
+pub(crate) fn delete_retired_site(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+) -> Result<(), StoreError> {
+    use public::deployment_retired_sites as rs;
 
-        // Collect entity history events for the subgraph for which we're
-        // reverting the block
-        let entries: Vec<RawHistory> = query.get_results(conn)?;
+    diesel::delete(rs::table.filter(rs::subgraph.eq(subgraph.as_str()))).execute(conn)?;
+    Ok(())
+}
 
-        // Apply revert operations
-        self.revert_entity_history_records(conn, entries)
-            .map(|(changes, count)| (StoreEvent::new(changes), count))
-    }
+/// Find the move of `subgraph` to another shard if there is one, and lock
+/// it for the rest of the current transaction
+pub(crate) fn find_move(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+) -> Result<Option<Move>, StoreError> {
+    use public::deployment_moves as dm;
+
+    Ok(dm::table
+        .select((
+            dm::shard,
+            dm::name,
+            dm::target_block_hash,
+            dm::target_block_number,
+            dm::invalidated,
+        ))
+        .filter(dm::subgraph.eq(subgraph.as_str()))
+        .for_update()
+        .first::<(String, String, Vec<u8>, i32, bool)>(conn)
+        .optional()?
+        .map(|(shard, namespace, hash, number, invalidated)| Move {
+            shard,
+            namespace,
+            target_block: EthereumBlockPointer {
+                hash: H256::from_slice(&hash),
+                number: number as u64,
+            },
+            invalidated,
+        }))
+}
 
-    fn build_attribute_index(
-        &self,
-        conn: &PgConnection,
-        index: &AttributeIndexDefinition,
-    ) -> Result<usize, StoreError> {
-        let (index_type, index_operator, jsonb_operator) = match index.field_value_type {
-            ValueType::Boolean
-            | ValueType::BigInt
-            | ValueType::Bytes
-            | ValueType::BigDecimal
-            | ValueType::Int
-            | ValueType::String => (String::from("btree"), String::from(""), "->>"),
-            ValueType::List => (String::from("gin"), String::from("jsonb_path_ops"), "->"),
-        };
-        // Cast between the type we store in JSONB for the field and the type
-        // as which comparisons should be made. For example, we store BigInt
-        // as a string in JSONB, but the comparison needs to be made as
-        // a number
-        let type_cast = match index.field_value_type {
-            ValueType::BigInt | ValueType::BigDecimal => "::numeric",
-            ValueType::Boolean => "::bool",
-            _ => "",
-        };
-        // It is not possible to use bind variables in this code,
-        // and we have to interpolate everything into the query directly.
-        // We also have to use conn.batch_execute to issue the `create index`
-        // commands; using `sql_query(..).execute(conn)` will make the database
-        // accept the commands, and log them as if they were successful, but
-        // without any effect on the schema
-        //
-        // Note that this code depends on `index.entity_number` and
-        // `index.attribute_number` to be stable, i.e., that we always get the
-        // same numbers for the same `(entity, attribute)` combination. If it is
-        // not stable, we will create duplicate indexes
-
-        // It is possible that the user's `entity_name` and
-        // `attribute_name` are so long that `name` becomes longer than
-        // 63 characters which is Postgres' length limit on identifiers.
-        // If we go over, Postgres will truncate the name to 63 characters;
-        // because of that we include the `entity_number` and
-        // `attribute_number` to ensure that a 63 character prefix
-        // of the name is guaranteed to be unique
-        let name = format!(
-            "attr_{}_{}_{}_{}",
-            index.entity_number,
-            index.attribute_number,
-            to_snake_case(&index.entity_name),
-            to_snake_case(&index.attribute_name)
-        );
-        let query = match index.field_value_type {
-            ValueType::String => format!(
-                "create index if not exists {name}
-                         on {subgraph}.entities
-                      using btree(left(data->'{attribute_name}'->>'data', {prefix_size}))
-                      where entity='{entity_name}'",
-                name = name,
-                subgraph = self.schema,
-                attribute_name = &index.attribute_name,
-                entity_name = &index.entity_name,
-                prefix_size = STRING_PREFIX_SIZE,
-            ),
-            _ => format!(
-                "create index if not exists {name}
-                         on {subgraph}.entities
-                      using {index_type} (
-                              ((data->'{attribute_name}'{jsonb_operator}'data'){type_cast})
-                              {index_operator}
-                            )
-                      where entity='{entity_name}'",
-                name = name,
-                subgraph = self.schema,
-                index_type = index_type,
-                attribute_name = &index.attribute_name,
-                jsonb_operator = jsonb_operator,
-                type_cast = type_cast,
-                index_operator = index_operator,
-                entity_name = &index.entity_name
-            ),
-        };
-        conn.batch_execute(&*query)?;
-        Ok(1)
-    }
+pub(crate) fn create_move(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    mv: &Move,
+) -> Result<(), StoreError> {
+    use public::deployment_moves as dm;
+
+    diesel::insert_into(dm::table)
+        .values((
+            dm::subgraph.eq(subgraph.as_str()),
+            dm::shard.eq(mv.shard.as_str()),
+            dm::name.eq(mv.namespace.as_str()),
+            dm::target_block_hash.eq(&mv.target_block.hash.0[..]),
+            dm::target_block_number.eq(mv.target_block.number as BlockNumber),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
 
-    fn revert_entity_history_records(
-        &self,
-        conn: &PgConnection,
-        records: Vec<RawHistory>,
-    ) -> Result<(Vec<EntityChange>, i32), StoreError> {
-        let subgraph_id = self.subgraph.clone();
-
-        let mut changes = vec![];
-        let mut count = 0;
-
-        for history in records.into_iter() {
-            // Perform the actual reversion
-            let key = EntityKey {
-                subgraph_id: self.subgraph.clone(),
-                entity_type: history.entity.clone(),
-                entity_id: history.entity_id.clone(),
-            };
-            match history.op {
-                0 => {
-                    // Reverse an insert
-                    self.delete(conn, &key, None)?;
-                    count -= 1;
-                }
-                1 | 2 => {
-                    // Reverse an update or delete
-                    if let Some(data) = history.data {
-                        self.upsert(conn, &key, &data, None)?;
-                    } else {
-                        return Err(StoreError::Unknown(format_err!(
-                            "History entry for update/delete has NULL data_before. id={}, op={}",
-                            history.id,
-                            history.op
-                        )));
-                    }
-                    if history.op == 2 {
-                        count += 1;
-                    }
-                }
-                _ => {
-                    return Err(StoreError::Unknown(format_err!(
-                        "bad operation {}",
-                        history.op
-                    )))
-                }
-            }
-            // Record the change that was just made
-            let change = EntityChange {
-                subgraph_id: subgraph_id.clone(),
-                entity_type: history.entity,
-                entity_id: history.entity_id,
-                operation: match history.op {
-                    0 => EntityChangeOperation::Removed,
-                    1 | 2 => EntityChangeOperation::Set,
-                    _ => {
-                        return Err(StoreError::Unknown(format_err!(
-                            "bad operation {}",
-                            history.op
-                        )))
-                    }
-                },
-            };
-            changes.push(change);
-        }
+pub(crate) fn delete_move(
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+) -> Result<(), StoreError> {
+    use public::deployment_moves as dm;
 
-        Ok((changes, count))
-    }
+    diesel::delete(dm::table.filter(dm::subgraph.eq(subgraph.as_str()))).execute(conn)?;
+    Ok(())
 }
 
-impl Storage {
-    /// Look up the schema for `subgraph` and return its entity storage.
-    /// Returns an error if `subgraph` does not have an entry in
-    /// `deployment_schemas`, which can only happen if `create_schema` was not
-    /// called for that `subgraph`
-    pub(crate) fn new(
-        conn: &PgConnection,
-        subgraph: &SubgraphDeploymentId,
-    ) -> Result<Self, StoreError> {
-        use public::DeploymentSchemaVersion as V;
-
-        let schema = find_schema(conn, subgraph)?
-            .ok_or_else(|| StoreError::Unknown(format_err!("unknown subgraph {}", subgraph)))?;
-        let storage = match schema.version {
-            V::Split => {
-                let table =
-                    diesel_dynamic_schema::schema(schema.name.clone()).table("entities".to_owned());
-                let id = table.column::<Text, _>("id".to_string());
-                let entity = table.column::<Text, _>("entity".to_string());
-                let data = table.column::<Jsonb, _>("data".to_string());
-                let event_source = table.column::<Text, _>("event_source".to_string());
-                let count_query = format!("select count(*) from \"{}\".entities", schema.name);
-
-                Storage::Json(JsonStorage {
-                    schema: schema.name,
-                    subgraph: subgraph.clone(),
-                    table,
-                    id,
-                    entity,
-                    data,
-                    event_source,
-                    count_query,
-                })
-            }
-            V::Relational => {
-                let subgraph_schema = metadata::subgraph_schema(conn, subgraph.to_owned())?;
-                let has_poi = supports_proof_of_indexing(conn, subgraph, &schema.name)?;
-                let catalog = Catalog::new(conn, schema.name)?;
-                let layout = Layout::new(&subgraph_schema, catalog, has_poi)?;
-                Storage::Relational(layout)
-            }
-        };
-        Ok(storage)
-    }
-
-    /// Return `true` if it is safe to cache this storage instance across
-    /// transactions
-    pub(crate) fn is_cacheable(&self) -> bool {
-        !self.needs_migrating()
+fn supports_proof_of_indexing(
+    conn: &diesel::pg::PgConnection,
+    subgraph_id: &SubgraphDeploymentId,
+    schema: &str,
+) -> Result<bool, StoreError> {
+    if subgraph_id == &*SUBGRAPHS_ID {
+        return Ok(false);
     }
-
-    /// Adjust the `entityCount` property of the `SubgraphDeployment` for
-    /// `subgraph` by `count`. This needs to be performed after the changes
-    /// underlying `count` have been written to the store.
-    pub(crate) fn update_entity_count(
-        &self,
-        conn: &PgConnection,
-        count: i32,
-    ) -> Result<(), StoreError> {
-        let count_query = match self {
-            Storage::Json(json) => json.count_query.as_str(),
-            Storage::Relational(layout) => layout.count_query.as_str(),
-        };
-        // The big complication in this query is how to determine what the
-        // new entityCount should be. We want to make sure that if the entityCount
-        // is NULL or the special value `-1`, it gets recomputed. Using `-1` here
-        // makes it possible to manually set the `entityCount` to that value
-        // to force a recount; setting it to `NULL` is not desirable since
-        // `entityCount` on the GraphQL level is not nullable, and so setting
-        // `entityCount` to `NULL` could cause errors at that layer; temporarily
-        // returning `-1` is more palatable. To be exact, recounts have to be
-        // done here, from the subgraph writer.
-        //
-        // The first argument of `coalesce` will be `NULL` if the entity count
-        // is `NULL` or `-1`, forcing `coalesce` to evaluate its second
-        // argument, the query to count entities. In all other cases,
-        // `coalesce` does not evaluate its second argument
-        let query = format!(
-            "
-            update subgraphs.subgraph_deployment
-               set entity_count =
-                     coalesce((nullif(entity_count, -1)) + $1,
-                              ({count_query}))
-             where id = $2
-            ",
-            count_query = count_query
-        );
-        Ok(diesel::sql_query(query)
-            .bind::<Integer, _>(count)
-            .bind::<Text, _>(self.subgraph().to_string())
-            .execute(conn)
-            .map(|_| ())?)
+    #[derive(Debug, QueryableByName)]
+    struct Table {
+        #[sql_type = "Text"]
+        pub table_name: String,
     }
+    let query =
+        "SELECT table_name FROM information_schema.tables WHERE table_schema=$1 AND table_name=$2";
+    let result: Vec<Table> = diesel::sql_query(query)
+        .bind::<Text, _>(schema)
+        .bind::<Text, _>(POI_TABLE)
+        .load(conn)?;
+    Ok(result.len() > 0)
+}
 
-    fn needs_migrating(&self) -> bool {
-        false
+/// Look up the schema for `subgraph` and return its layout. Returns an
+/// error if `subgraph` does not have an entry in `deployment_schemas`,
+/// which can only happen if `create_schema` was not called for that
+/// `subgraph`, or if the subgraph still uses JSONB storage. The
+/// `meta_conn` must be a connection to the primary shard, and `conn` one
+/// to the shard that holds the data of `subgraph`
+pub(crate) fn load_layout(
+    meta_conn: &PgConnection,
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+) -> Result<Layout, StoreError> {
+    use public::DeploymentSchemaVersion as V;
+
+    let schema = find_schema(meta_conn, subgraph)?
+        .ok_or_else(|| StoreError::Unknown(format_err!("unknown subgraph {}", subgraph)))?;
+    match schema.version {
+        V::Split => Err(StoreError::Unknown(format_err!(
+            "subgraph {} uses JSONB storage, which is no longer supported. \
+             Convert it with `graph-migrate-jsonb` or redeploy it",
+            subgraph
+        ))),
+        V::Relational => {
+            let subgraph_schema = metadata::subgraph_schema(meta_conn, subgraph.to_owned())?;
+            let has_poi = supports_proof_of_indexing(conn, subgraph, &schema.name)?;
+            let catalog = Catalog::new(conn, schema.name)?;
+            Layout::new(&subgraph_schema, catalog, has_poi)
+        }
     }
 }
 
@@ -1492,6 +1337,38 @@ pub fn delete_all_entities_for_test_use_only(
             .map_err(|_| StoreError::Unknown(format_err!("illegal subgraph {}", subgraph)))?;
         drop_schema(conn, &subgraph)?;
     }
+    // Delete the copies of deployments that are being moved or were moved
+    // away from a shard. In tests, all shards live in the same database
+    let names = public::deployment_moves::table
+        .select(public::deployment_moves::name)
+        .get_results::<String>(conn)?
+        .into_iter()
+        .chain(
+            public::deployment_retired_sites::table
+                .select(public::deployment_retired_sites::name)
+                .get_results::<String>(conn)?,
+        );
+    for name in names {
+        let query = format!(
+            "drop schema if exists {} cascade; drop schema if exists {} cascade",
+            name,
+            crate::sharding::foreign_schema_name(&name)
+        );
+        conn.batch_execute(&query)?;
+    }
+    #[derive(QueryableByName)]
+    struct Nsp {
+        #[sql_type = "Text"]
+        nspname: String,
+    }
+    for nsp in diesel::sql_query("select nspname from pg_namespace where nspname like 'fdw\\_sgd%'")
+        .get_results::<Nsp>(conn)?
+    {
+        conn.batch_execute(&format!("drop schema if exists {} cascade", nsp.nspname))?;
+    }
+    diesel::delete(public::deployment_moves::table).execute(conn)?;
+    diesel::delete(public::deployment_retired_sites::table).execute(conn)?;
+    diesel::delete(public::deployment_prepared_transactions::table).execute(conn)?;
     // Delete subgraphs entities
     // Generated by running 'layout -g delete subgraphs.graphql'
     let query = "
@@ -1513,78 +1390,7 @@ pub fn delete_all_entities_for_test_use_only(
         delete from subgraphs.ethereum_contract_event_handler;
     ";
     conn.batch_execute(query)?;
-    store.storage_cache.lock().unwrap().clear();
-    Ok(())
-}
-
-pub fn create_split_schema(conn: &PgConnection, schema_name: &str) -> Result<(), StoreError> {
-    // The order of columns in the primary key matters a lot, since
-    // we want the pk index to also support queries that do not have an id,
-    // just an entity (like counting the number of entities of a certain type)
-    let query = format!(
-        "create table {}.entities
-         (
-           entity       varchar not null,
-           id           varchar not null,
-           data         jsonb,
-           event_source varchar not null,
-
-           primary key(entity, id)
-         )",
-        schema_name
-    );
-    conn.batch_execute(&*query)?;
-
-    let query = format!(
-        "create trigger entity_change_insert_trigger
-           after insert on {schema}.entities
-           for each row
-             execute procedure subgraph_log_entity_event()",
-        schema = schema_name
-    );
-    conn.batch_execute(&*query)?;
-
-    let query = format!(
-        "create trigger entity_change_update_trigger
-           after update on {schema}.entities
-           for each row
-             when (old.data != new.data)
-             execute procedure subgraph_log_entity_event()",
-        schema = schema_name
-    );
-    conn.batch_execute(&*query)?;
-
-    let query = format!(
-        "create trigger entity_change_delete_trigger
-           after delete on {schema}.entities
-           for each row
-             execute procedure subgraph_log_entity_event()",
-        schema = schema_name
-    );
-    conn.batch_execute(&*query)?;
-
-    let query = format!(
-        "create table {}.entity_history
-         (
-           id           serial primary key,
-           event_id     integer,
-           entity       varchar not null,
-           entity_id    varchar not null,
-           data_before  jsonb,
-           reversion    bool not null default false,
-           op_id        int2 NOT NULL
-         )",
-        schema_name
-    );
-    conn.batch_execute(&*query)?;
-
-    let query = format!(
-        "create index entity_history_event_id_btree_idx
-           on {}.entity_history(event_id)",
-        schema_name
-    );
-    conn.batch_execute(&*query)?;
-
+    store.layout_cache.lock().unwrap().clear();
     Ok(())
 }
 
@@ -0,0 +1,344 @@
+//! Convert deployments that still store their entities as JSONB blobs
+//! (`Split` storage) to relational storage. The store itself can no longer
+//! read or write JSONB deployments; they have to be converted with the
+//! `graph-migrate-jsonb` tool while they are not assigned to any node.
+//!
+//! The conversion creates a new database schema with the relational layout
+//! for the deployment, copies all entities into it, and checks the copy by
+//! comparing the number of entities of each type and a random sample of
+//! entities before it switches the deployment over to the new schema and
+//! drops the old one.
+//!
+//! JSONB storage does not record the block at which an entity version was
+//! created. Converted entities are therefore valid from block 0 on, and the
+//! deployment is marked as pruned at its current block so that queries for
+//! earlier blocks and reverts past that block are refused rather than
+//! answered with made-up history.
+use diesel::connection::SimpleConnection;
+use diesel::pg::PgConnection;
+use diesel::sql_types::{BigInt, Integer, Jsonb, Text};
+use diesel::{Connection, RunQueryDsl};
+use std::collections::BTreeMap;
+
+use graph::data::subgraph::schema::{
+    SubgraphDeploymentAssignmentEntity, TypedEntity, SUBGRAPHS_ID,
+};
+use graph::prelude::{
+    format_err, info, serde_json, Entity, EntityKey, Error, Logger, StoreError,
+    SubgraphDeploymentId, Value, BLOCK_NUMBER_MAX,
+};
+
+use crate::entities as e;
+use crate::metadata;
+use crate::relational::{Catalog, Layout};
+
+/// The number of entities we copy in one transaction
+const BATCH_SIZE: i64 = 10_000;
+
+/// What happened when converting one deployment
+#[derive(Debug)]
+pub struct Report {
+    pub subgraph: SubgraphDeploymentId,
+    /// The database schema that held the JSONB entities
+    pub old_namespace: String,
+    /// The database schema that holds the relational entities
+    pub namespace: String,
+    /// The number of entities of each type
+    pub counts: BTreeMap<String, i64>,
+    /// The number of entities whose copies were compared with the original
+    pub sampled: usize,
+    /// `true` if the deployment was left unchanged because this was a dry
+    /// run
+    pub dry_run: bool,
+}
+
+#[derive(QueryableByName)]
+struct EntityCount {
+    #[sql_type = "Text"]
+    entity: String,
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+#[derive(QueryableByName)]
+struct JsonEntity {
+    #[sql_type = "Text"]
+    entity: String,
+    #[sql_type = "Text"]
+    id: String,
+    #[sql_type = "Jsonb"]
+    data: serde_json::Value,
+}
+
+impl JsonEntity {
+    fn into_entity(self) -> Result<(String, String, Entity), StoreError> {
+        let mut entity = serde_json::from_value::<Entity>(self.data)?;
+        entity.set("__typename", self.entity.as_str());
+        Ok((self.entity, self.id, entity))
+    }
+}
+
+/// Connect to the database at `postgres_url`, which must be the primary
+/// shard, since JSONB storage is only possible there
+pub fn connect(postgres_url: &str) -> Result<PgConnection, Error> {
+    PgConnection::establish(postgres_url)
+        .map_err(|e| format_err!("could not connect to the database: {}", e))
+}
+
+/// The deployments that still use JSONB storage
+pub fn jsonb_deployments(conn: &PgConnection) -> Result<Vec<SubgraphDeploymentId>, StoreError> {
+    e::jsonb_deployments(conn)?
+        .into_iter()
+        .map(|subgraph| {
+            SubgraphDeploymentId::new(subgraph.clone())
+                .map_err(|_| StoreError::Unknown(format_err!("illegal subgraph {}", subgraph)))
+        })
+        .collect()
+}
+
+/// Convert `subgraph` to relational storage and compare `samples` randomly
+/// chosen entities with their originals. With `dry_run`, the new schema is
+/// dropped again after checking it, and the deployment is left unchanged
+pub fn migrate(
+    logger: &Logger,
+    conn: &PgConnection,
+    subgraph: &SubgraphDeploymentId,
+    samples: usize,
+    dry_run: bool,
+) -> Result<Report, StoreError> {
+    let schema = e::find_schema(conn, subgraph)?
+        .ok_or_else(|| StoreError::Unknown(format_err!("unknown subgraph {}", subgraph)))?;
+    if !schema.is_jsonb() {
+        return Err(StoreError::Unknown(format_err!(
+            "subgraph {} already uses relational storage",
+            subgraph
+        )));
+    }
+    let old_namespace = schema.name;
+
+    let meta_layout = e::load_layout(conn, conn, &*SUBGRAPHS_ID)?;
+    let assignment = meta_layout.find(
+        conn,
+        SubgraphDeploymentAssignmentEntity::TYPENAME,
+        subgraph.as_str(),
+        BLOCK_NUMBER_MAX,
+    )?;
+    if assignment.is_some() {
+        return Err(StoreError::Unknown(format_err!(
+            "subgraph {} is assigned to a node; unassign it before converting it",
+            subgraph
+        )));
+    }
+
+    let counts = entity_counts(conn, &old_namespace)?;
+    let namespace = e::allocate_namespace(conn)?;
+    let layout = conn.transaction(|| -> Result<Layout, StoreError> {
+        conn.batch_execute(&format!("create schema {}", namespace))?;
+        // JSONB deployments never computed a proof of indexing, and
+        // starting in the middle of the chain would produce one that does
+        // not match what other indexers compute
+        let input_schema = metadata::subgraph_schema(conn, subgraph.clone())?;
+        let catalog = Catalog::new(conn, namespace.clone())?;
+        let layout = Layout::new(&input_schema, catalog, false)?;
+        let ddl = layout
+            .as_ddl()
+            .map_err(|_| StoreError::Unknown(format_err!("failed to generate DDL for layout")))?;
+        conn.batch_execute(&ddl)?;
+        Ok(layout)
+    })?;
+
+    info!(logger, "Converting JSONB deployment";
+          "subgraph" => subgraph.as_str(),
+          "from" => &old_namespace,
+          "to" => &namespace);
+    let result = copy_entities(logger, conn, &layout, &old_namespace, &counts)
+        .and_then(|_| verify(conn, &layout, &old_namespace, &counts, samples));
+    let sampled = match result {
+        Ok(sampled) => sampled,
+        Err(e) => {
+            conn.batch_execute(&format!("drop schema {} cascade", namespace))?;
+            return Err(e);
+        }
+    };
+
+    if dry_run {
+        conn.batch_execute(&format!("drop schema {} cascade", namespace))?;
+    } else {
+        conn.transaction(|| -> Result<(), StoreError> {
+            let block = metadata::latest_block_number(conn, subgraph)?.unwrap_or(0);
+            metadata::set_pruned_block(conn, subgraph, block)?;
+            e::set_relational(conn, subgraph, &namespace)?;
+            conn.batch_execute(&format!("drop schema {} cascade", old_namespace))?;
+            Ok(())
+        })?;
+    }
+
+    Ok(Report {
+        subgraph: subgraph.clone(),
+        old_namespace,
+        namespace,
+        counts,
+        sampled,
+        dry_run,
+    })
+}
+
+fn entity_counts(
+    conn: &PgConnection,
+    namespace: &str,
+) -> Result<BTreeMap<String, i64>, StoreError> {
+    let query = format!(
+        "select entity, count(*) as count from {}.entities group by entity",
+        namespace
+    );
+    Ok(diesel::sql_query(query)
+        .load::<EntityCount>(conn)?
+        .into_iter()
+        .map(|c| (c.entity, c.count))
+        .collect())
+}
+
+/// Copy all entities from the JSONB table in `old_namespace` into the
+/// tables of `layout`, one batch per transaction
+fn copy_entities(
+    logger: &Logger,
+    conn: &PgConnection,
+    layout: &Layout,
+    old_namespace: &str,
+    counts: &BTreeMap<String, i64>,
+) -> Result<(), StoreError> {
+    let unknown: Vec<_> = counts
+        .keys()
+        .filter(|entity| !layout.tables.contains_key(entity.as_str()))
+        .map(|entity| entity.as_str())
+        .collect();
+    if !unknown.is_empty() {
+        return Err(StoreError::Unknown(format_err!(
+            "subgraph {} has entities of types that are not in its schema: {}",
+            layout.subgraph,
+            unknown.join(", ")
+        )));
+    }
+
+    let query = format!(
+        "select entity, id, data from {}.entities
+          where entity = $1 and id > $2
+          order by id
+          limit $3",
+        old_namespace
+    );
+    for (entity_type, count) in counts {
+        let mut last = String::new();
+        loop {
+            let rows = diesel::sql_query(query.as_str())
+                .bind::<Text, _>(entity_type)
+                .bind::<Text, _>(&last)
+                .bind::<BigInt, _>(BATCH_SIZE)
+                .load::<JsonEntity>(conn)?;
+            let done = (rows.len() as i64) < BATCH_SIZE;
+            if let Some(row) = rows.last() {
+                last = row.id.clone();
+            }
+            conn.transaction(|| -> Result<(), StoreError> {
+                for row in rows {
+                    let (entity_type, entity_id, entity) = row.into_entity()?;
+                    let key = EntityKey {
+                        subgraph_id: layout.subgraph.clone(),
+                        entity_type,
+                        entity_id,
+                    };
+                    layout.insert(conn, &key, entity, 0)?;
+                }
+                Ok(())
+            })?;
+            if done {
+                break;
+            }
+        }
+        info!(logger, "Copied entities"; "entity" => entity_type, "count" => count);
+    }
+    Ok(())
+}
+
+/// Check that the tables of `layout` contain as many entities of each type
+/// as the JSONB table, and that `samples` randomly chosen entities were
+/// copied faithfully. Returns the number of entities that were compared
+fn verify(
+    conn: &PgConnection,
+    layout: &Layout,
+    old_namespace: &str,
+    counts: &BTreeMap<String, i64>,
+    samples: usize,
+) -> Result<usize, StoreError> {
+    #[derive(QueryableByName)]
+    struct Count {
+        #[sql_type = "BigInt"]
+        count: i64,
+    }
+
+    for (entity_type, count) in counts {
+        let table = layout.table_for_entity(entity_type)?;
+        let copied = diesel::sql_query(format!(
+            "select count(*) as count from {}",
+            table.qualified_name
+        ))
+        .get_result::<Count>(conn)?
+        .count;
+        if copied != *count {
+            return Err(StoreError::Unknown(format_err!(
+                "subgraph {} has {} entities of type {}, but {} were copied",
+                layout.subgraph,
+                count,
+                entity_type,
+                copied
+            )));
+        }
+    }
+
+    let sample = diesel::sql_query(format!(
+        "select entity, id, data from {}.entities order by random() limit $1",
+        old_namespace
+    ))
+    .bind::<Integer, _>(samples as i32)
+    .load::<JsonEntity>(conn)?;
+    let sampled = sample.len();
+    for row in sample {
+        let (entity_type, id, original) = row.into_entity()?;
+        let copy = layout
+            .find(conn, &entity_type, &id, BLOCK_NUMBER_MAX)?
+            .ok_or_else(|| {
+                StoreError::Unknown(format_err!(
+                    "entity {}[{}] of subgraph {} was not copied",
+                    entity_type,
+                    id,
+                    layout.subgraph
+                ))
+            })?;
+        let table = layout.table_for_entity(&entity_type)?;
+        for column in table.columns.iter().filter(|column| !column.is_fulltext()) {
+            let field = column.field.as_str();
+            if value(&original, field) != value(&copy, field) {
+                return Err(StoreError::Unknown(format_err!(
+                    "attribute {} of entity {}[{}] of subgraph {} differs after copying: \
+                     expected {:?} but got {:?}",
+                    field,
+                    entity_type,
+                    id,
+                    layout.subgraph,
+                    value(&original, field),
+                    value(&copy, field)
+                )));
+            }
+        }
+    }
+    Ok(sampled)
+}
+
+/// The value of `attr` in `entity`; relational storage does not store
+/// `null` values explicitly, and a missing attribute is the same as a
+/// `null` one
+fn value<'a>(entity: &'a Entity, attr: &str) -> Option<&'a Value> {
+    entity
+        .get(attr)
+        .filter(|value| !matches!(value, Value::Null))
+}
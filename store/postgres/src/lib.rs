@@ -17,28 +17,34 @@ extern crate inflector;
 extern crate lazy_static;
 extern crate lru_time_cache;
 extern crate postgres;
+extern crate regex;
 extern crate serde;
 extern crate uuid;
 
+mod admin_audit_log;
+mod allow_lists;
+mod binary_copy;
 mod block_range;
 mod catalog;
 mod chain_head_listener;
 pub mod connection_pool;
+mod copy;
 mod db_schema;
 mod entities;
-mod filter;
 mod functions;
 mod history_event;
 mod jsonb;
-mod jsonb_queries;
+pub mod jsonb_migration;
 mod metadata;
 mod notification_listener;
 pub mod query_store;
 pub mod relational;
 mod relational_queries;
+pub mod sharding;
 mod sql_value;
 pub mod store;
 mod store_events;
+mod write_queue;
 
 #[cfg(debug_assertions)]
 pub mod db_schema_for_tests {
@@ -53,6 +59,9 @@ pub mod layout_for_tests {
     pub use crate::relational::*;
 }
 
+pub use self::admin_audit_log::AdminAuditLog;
+pub use self::allow_lists::AllowListStore;
 pub use self::chain_head_listener::ChainHeadUpdateListener;
+pub use self::sharding::{PlacementRule, Shard, PRIMARY_SHARD};
 pub use self::store::{Store, StoreConfig};
 pub use self::store_events::SubscriptionManager;
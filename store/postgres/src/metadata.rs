@@ -4,8 +4,8 @@ use diesel::prelude::{ExpressionMethods, QueryDsl, RunQueryDsl};
 
 use graph::data::subgraph::schema::SubgraphManifestEntity;
 use graph::prelude::{
-    bigdecimal::ToPrimitive, format_err, web3::types::H256, BigDecimal, EthereumBlockPointer,
-    Schema, StoreError, SubgraphDeploymentId,
+    bigdecimal::ToPrimitive, format_err, web3::types::H256, BigDecimal, BlockNumber,
+    EthereumBlockPointer, Schema, StoreError, SubgraphDeploymentId,
 };
 
 // Diesel tables for some of the metadata
@@ -33,6 +33,9 @@ table! {
         graft_block_hash -> Nullable<Binary>,
         graft_block_number -> Nullable<Numeric>,
         block_range -> Range<Integer>,
+        // Not part of the GraphQL schema; only used for pruning history
+        history_blocks -> Nullable<Integer>,
+        pruned_block -> Integer,
     }
 }
 
@@ -81,6 +84,24 @@ table! {
     }
 }
 
+// Only the columns of `subgraph` and `subgraph_version` that we need
+table! {
+    subgraphs.subgraph (vid) {
+        vid -> BigInt,
+        id -> Text,
+        name -> Text,
+    }
+}
+
+table! {
+    subgraphs.subgraph_version (vid) {
+        vid -> BigInt,
+        id -> Text,
+        subgraph -> Text,
+        deployment -> Text,
+    }
+}
+
 /// Look up the graft point for the given subgraph in the database and
 /// return it
 pub fn deployment_graft(
@@ -138,6 +159,29 @@ pub fn subgraph_schema(
     res.map_err(|e| StoreError::Unknown(e))
 }
 
+/// Replace the GraphQL schema in the manifest of `id` with `schema`. This
+/// only changes the text of the schema; the caller is responsible for
+/// changing the deployment's tables to match
+pub fn set_subgraph_schema(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+    schema: &str,
+) -> Result<(), StoreError> {
+    use subgraph_manifest as sm;
+
+    let manifest_id = SubgraphManifestEntity::id(&id);
+    let count = diesel::update(sm::table.filter(sm::id.eq(manifest_id.as_str())))
+        .set(sm::schema.eq(schema))
+        .execute(conn)?;
+    if count == 0 {
+        return Err(StoreError::Unknown(format_err!(
+            "the manifest for subgraph deployment {} does not exist",
+            id
+        )));
+    }
+    Ok(())
+}
+
 pub fn subgraph_network(
     conn: &PgConnection,
     id: &SubgraphDeploymentId,
@@ -164,3 +208,173 @@ pub fn subgraph_network(
         .map(|x| x.flatten())
         .map_err(|e| e.into())
 }
+
+/// The settings for removing old entity versions of a deployment
+pub struct HistorySettings {
+    pub id: SubgraphDeploymentId,
+    /// How many blocks of history to keep
+    pub history_blocks: BlockNumber,
+    /// All entity versions that ended before this block have been removed
+    pub pruned_block: BlockNumber,
+    /// The block the deployment has processed last
+    pub latest_block: BlockNumber,
+}
+
+/// Keep only `history_blocks` blocks of history for `id`, or all history if
+/// `history_blocks` is `None`
+pub fn set_history_blocks(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+    history_blocks: Option<BlockNumber>,
+) -> Result<(), StoreError> {
+    use subgraph_deployment as sd;
+
+    let count = diesel::update(sd::table.filter(sd::id.eq(id.as_str())))
+        .set(sd::history_blocks.eq(history_blocks))
+        .execute(conn)?;
+    if count == 0 {
+        return Err(StoreError::Unknown(format_err!(
+            "subgraph deployment {} does not exist",
+            id
+        )));
+    }
+    Ok(())
+}
+
+/// The history settings of all deployments that limit their history and
+/// have processed at least one block
+pub fn history_settings(conn: &PgConnection) -> Result<Vec<HistorySettings>, StoreError> {
+    use subgraph_deployment as sd;
+
+    sd::table
+        .select((
+            sd::id,
+            sd::history_blocks,
+            sd::pruned_block,
+            sd::latest_ethereum_block_number,
+        ))
+        .filter(sd::history_blocks.is_not_null())
+        .filter(sd::latest_ethereum_block_number.is_not_null())
+        .load::<(String, Option<i32>, i32, Option<BigDecimal>)>(conn)?
+        .into_iter()
+        .map(|(id, history_blocks, pruned_block, latest_block)| {
+            let id = SubgraphDeploymentId::new(id.clone()).map_err(|_| {
+                StoreError::Unknown(format_err!("invalid subgraph deployment id `{}`", id))
+            })?;
+            Ok(HistorySettings {
+                id,
+                history_blocks: history_blocks
+                    .expect("we only load deployments with history_blocks"),
+                pruned_block,
+                latest_block: latest_block
+                    .and_then(|block| block.to_i32())
+                    .expect("block numbers fit into an i32"),
+            })
+        })
+        .collect()
+}
+
+/// The earliest block for which the history of `id` is complete
+pub fn pruned_block(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+) -> Result<BlockNumber, StoreError> {
+    use subgraph_deployment as sd;
+
+    if id.is_meta() {
+        return Ok(0);
+    }
+    Ok(sd::table
+        .select(sd::pruned_block)
+        .filter(sd::id.eq(id.as_str()))
+        .first::<i32>(conn)?)
+}
+
+/// Lock the row of deployment `id` until the end of the current
+/// transaction. Writing or reverting a block and rewinding the deployment
+/// all take this lock so that they can not interleave
+pub fn lock_deployment(conn: &PgConnection, id: &SubgraphDeploymentId) -> Result<(), StoreError> {
+    use subgraph_deployment as sd;
+
+    sd::table
+        .select(sd::vid)
+        .filter(sd::id.eq(id.as_str()))
+        .for_update()
+        .load::<i64>(conn)?;
+    Ok(())
+}
+
+pub fn set_pruned_block(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+    block: BlockNumber,
+) -> Result<(), StoreError> {
+    use subgraph_deployment as sd;
+
+    diesel::update(sd::table.filter(sd::id.eq(id.as_str())))
+        .set(sd::pruned_block.eq(block))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The number of the latest block that `id` has processed, or `None` if
+/// it has not processed any blocks yet
+pub fn latest_block_number(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+) -> Result<Option<BlockNumber>, StoreError> {
+    use subgraph_deployment as sd;
+
+    Ok(sd::table
+        .select(sd::latest_ethereum_block_number)
+        .filter(sd::id.eq(id.as_str()))
+        .first::<Option<BigDecimal>>(conn)?
+        .map(|block| block.to_i32().expect("block numbers fit into an i32")))
+}
+
+/// The latest block that `id` has processed, or `None` if it has not
+/// processed any blocks yet
+pub fn latest_block_ptr(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+) -> Result<Option<EthereumBlockPointer>, StoreError> {
+    use subgraph_deployment as sd;
+
+    match sd::table
+        .select((
+            sd::latest_ethereum_block_hash,
+            sd::latest_ethereum_block_number,
+        ))
+        .filter(sd::id.eq(id.as_str()))
+        .first::<(Option<Vec<u8>>, Option<BigDecimal>)>(conn)?
+    {
+        (None, None) => Ok(None),
+        (Some(hash), Some(block)) => {
+            let hash = H256::from_slice(hash.as_slice());
+            let block = block.to_u64().expect("block numbers fit into a u64");
+            Ok(Some(EthereumBlockPointer::from((hash, block))))
+        }
+        _ => unreachable!(
+            "the hash and number of the latest block are either both set or neither is set"
+        ),
+    }
+}
+
+/// The names of all subgraphs that have a version that uses deployment `id`
+pub fn subgraph_names(
+    conn: &PgConnection,
+    id: &SubgraphDeploymentId,
+) -> Result<Vec<String>, StoreError> {
+    use subgraph as s;
+    use subgraph_version as v;
+
+    let subgraphs = v::table
+        .select(v::subgraph)
+        .filter(v::deployment.eq(id.as_str()))
+        .load::<String>(conn)?;
+    Ok(s::table
+        .select(s::name)
+        .filter(s::id.eq_any(subgraphs))
+        .order_by(s::name)
+        .load::<String>(conn)?)
+}
@@ -7,15 +7,14 @@
 //! The pivotal struct in this module is the `Layout` which handles all the
 //! information about mapping a GraphQL schema to database tables
 use diesel::connection::SimpleConnection;
-use diesel::{
-    debug_query, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl,
-};
+use diesel::sql_types::{BigInt, Text};
+use diesel::{debug_query, Connection, OptionalExtension, PgConnection, QueryResult, RunQueryDsl};
 use graphql_parser::query as q;
 use graphql_parser::schema as s;
 use inflector::Inflector;
 use lazy_static::lazy_static;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
-use std::convert::{From, TryFrom, TryInto};
+use std::convert::{From, TryFrom};
 use std::env;
 use std::fmt::{self, Write};
 use std::str::FromStr;
@@ -23,27 +22,32 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use crate::relational_queries::{
-    self as rq, ClampRangeQuery, ConflictingEntityQuery, DeleteByPrefixQuery,
-    DeleteDynamicDataSourcesQuery, DeleteQuery, EntityData, FilterCollection, FilterQuery,
-    FindManyQuery, FindQuery, InsertQuery, RevertClampQuery, RevertRemoveQuery, UpdateQuery,
+    BlockChangeData, BlockChangesQuery, BulkClampRangeQuery, BulkInsertQuery, ClampRangeQuery,
+    ConflictingEntityQuery, DeleteByPrefixQuery, DeleteDynamicDataSourcesQuery, DeleteQuery,
+    EntityData, FilterCollection, FilterQuery, FindManyQuery, FindQuery, InsertQuery, PruneQuery,
+    RevertClampQuery, RevertRemoveQuery, TableStats, TableStatsEstimateQuery, TableStatsQuery,
+    UpdateQuery, STAGING_TABLE_PREFIX,
+};
+use graph::components::store::{EntityTypeChanges, EntityTypeStats};
+use graph::data::graphql::ext::{
+    DirectiveExt, DirectiveFinder, DocumentExt, ObjectTypeExt, ValueExt,
 };
-use graph::data::graphql::ext::{DocumentExt, ObjectTypeExt};
 use graph::data::schema::{FulltextConfig, FulltextDefinition, Schema, SCHEMA_TYPE_NAME};
 use graph::data::store::BYTES_SCALAR;
-use graph::data::subgraph::schema::{
-    DynamicEthereumContractDataSourceEntity, POI_OBJECT, POI_TABLE,
-};
+use graph::data::subgraph::schema::{POI_OBJECT, POI_TABLE};
 use graph::prelude::{
     format_err, info, BlockNumber, Entity, EntityChange, EntityChangeOperation, EntityCollection,
-    EntityFilter, EntityKey, EntityOrder, EntityRange, EthereumBlockPointer, Logger,
-    QueryExecutionError, StoreError, StoreEvent, SubgraphDeploymentId, Value, ValueType,
-    BLOCK_NUMBER_MAX,
+    EntityFilter, EntityKey, EntityOrder, EntityRange, Logger, QueryExecutionError, StoreError,
+    StoreEvent, SubgraphDeploymentId, Value, ValueType, BLOCK_NUMBER_MAX,
 };
 
-use crate::block_range::{BLOCK_RANGE_COLUMN, BLOCK_UNVERSIONED};
+use crate::block_range::{BLOCK_COLUMN, BLOCK_RANGE_COLUMN, BLOCK_UNVERSIONED};
 pub use crate::catalog::Catalog;
 use crate::entities::STRING_PREFIX_SIZE;
 
+/// The number of `vid`s whose versions `prune` checks in one transaction
+const PRUNE_BATCH_SIZE: i64 = 10_000;
+
 lazy_static! {
     /// Experimental: a list of fully qualified table names that contain
     /// entities that are like accounts in that they have a relatively small
@@ -296,10 +300,17 @@ impl Layout {
         let count_query = tables
             .iter()
             .map(|table| {
-                format!(
-                    "select count(*) from \"{}\".\"{}\" where upper_inf(block_range)",
-                    &catalog.schema, table.name
-                )
+                if table.immutable {
+                    format!(
+                        "select count(*) from \"{}\".\"{}\"",
+                        &catalog.schema, table.name
+                    )
+                } else {
+                    format!(
+                        "select count(*) from \"{}\".\"{}\" where upper_inf(block_range)",
+                        &catalog.schema, table.name
+                    )
+                }
             })
             .collect::<Vec<_>>()
             .join("\nunion all\n");
@@ -337,6 +348,7 @@ impl Layout {
                     column_type: ColumnType::Bytes,
                     fulltext_fields: None,
                     is_reference: false,
+                    use_index: true,
                 },
                 Column {
                     name: SqlName::from(PRIMARY_KEY_COLUMN),
@@ -347,6 +359,7 @@ impl Layout {
                     column_type: ColumnType::String,
                     fulltext_fields: None,
                     is_reference: false,
+                    use_index: true,
                 },
             ],
             /// The position of this table in all the tables for this layout; this
@@ -354,6 +367,8 @@ impl Layout {
             /// predictable
             position: position as u32,
             is_account_like: false,
+            immutable: false,
+            composite_indexes: vec![],
         }
     }
 
@@ -371,72 +386,6 @@ impl Layout {
         Ok(layout)
     }
 
-    pub fn copy_from(
-        &self,
-        logger: &Logger,
-        conn: &PgConnection,
-        base: &Layout,
-        block: EthereumBlockPointer,
-        metadata: &Layout,
-    ) -> Result<(), StoreError> {
-        // This can not be used to copy data to or from the metadata subgraph
-        assert!(!self.subgraph.is_meta());
-        assert!(!base.subgraph.is_meta());
-
-        info!(
-            logger,
-            "Initializing graft by copying data from {} to {}", base.subgraph, self.subgraph
-        );
-
-        // 1. Copy subgraph data
-        // We allow both not copying tables at all from the source, as well
-        // as adding new tables in `self`; we only need to check that tables
-        // that actually need to be copied from the source are compatible
-        // with the corresponding tables in `self`
-        for (dst, src) in self
-            .tables
-            .values()
-            .filter_map(|dst| base.table(&dst.name).map(|src| (dst, src)))
-        {
-            let start = Instant::now();
-            let count = rq::CopyEntityDataQuery::new(dst, src)?.execute(conn)?;
-            info!(logger, "Copied {} {} entities", count, src.object;
-                  "time_ms" => start.elapsed().as_millis());
-        }
-
-        // 2. Copy dynamic data sources and adjust their ID
-        use crate::metadata::dynamic_ethereum_contract_data_source as decds;
-        // Find existing dynamic data sources
-        let start = Instant::now();
-        let dds = decds::table
-            .select(decds::id)
-            .filter(decds::deployment.eq(base.subgraph.as_str()))
-            .load::<String>(conn)?;
-        // Create an equal number of brand new ids
-        let new_dds = (0..dds.len())
-            .map(|_| DynamicEthereumContractDataSourceEntity::make_id())
-            .collect::<Vec<_>>();
-        // Copy the data sources and all their subordinate entities, translating
-        // ids into new ids in the process and attaching them to `self.subgraph`
-        rq::CopyDynamicDataSourceQuery::new(&dds, &new_dds, self.subgraph.as_str())
-            .execute(conn)?;
-        info!(logger, "Copied {} dynamic data sources", dds.len();
-              "time_ms" => start.elapsed().as_millis());
-
-        // 3. Rewind the subgraph. `revert_block` gets rid of everything
-        // including the block passed to it. We want to preserve `block`
-        // and therefore revert `block+1`
-        let start = Instant::now();
-        let block_to_revert: BlockNumber = (block.number + 1)
-            .try_into()
-            .expect("block numbers fit into an i32");
-        self.revert_block(conn, block_to_revert)?;
-        metadata.revert_metadata(conn, &self.subgraph, block_to_revert)?;
-        info!(logger, "Rewound subgraph to block {}", block.number;
-              "time_ms" => start.elapsed().as_millis());
-        Ok(())
-    }
-
     /// Determine if it is possible to copy the data of `source` into `self`
     /// by checking that our schema is compatible with `source`.
     /// Returns a list of errors if copying is not possible. An empty
@@ -464,19 +413,7 @@ impl Layout {
 
         // Output enums first
         for (name, values) in &self.enums {
-            let mut sep = "";
-            let name = SqlName::from(name.as_str());
-            write!(
-                out,
-                "create type {}.{}\n    as enum (",
-                self.catalog.schema,
-                name.quoted()
-            )?;
-            for value in values.iter() {
-                write!(out, "{}'{}'", sep, value)?;
-                sep = ", "
-            }
-            writeln!(out, ");")?;
+            self.enum_ddl(&mut out, name, values)?;
         }
         // We sort tables here solely because the unit tests rely on
         // 'create table' statements appearing in a fixed order
@@ -490,6 +427,130 @@ impl Layout {
         Ok(out)
     }
 
+    /// Generate the `create type` statement for the enum `name`
+    fn enum_ddl(&self, out: &mut String, name: &str, values: &BTreeSet<String>) -> fmt::Result {
+        let mut sep = "";
+        let name = SqlName::from(name);
+        write!(
+            out,
+            "create type {}.{}\n    as enum (",
+            self.catalog.schema,
+            name.quoted()
+        )?;
+        for value in values.iter() {
+            write!(out, "{}'{}'", sep, value)?;
+            sep = ", "
+        }
+        writeln!(out, ");")
+    }
+
+    /// Determine if the database schema for `old` can be changed in place
+    /// so that it matches `self`. Only changes that leave existing data
+    /// untouched are possible: adding entity types, nullable attributes,
+    /// fulltext fields, indexes, enum types and enum values. Returns a
+    /// list of errors; an empty vector indicates that the change is
+    /// possible
+    pub fn can_evolve_from(&self, old: &Layout) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut tables = old.tables.values().collect::<Vec<_>>();
+        tables.sort_by_key(|table| table.position);
+        for otable in tables {
+            match self.table(&otable.name) {
+                Some(table) => errors.extend(table.can_evolve_from(otable)),
+                None => errors.push(format!("The entity type {} was removed", otable.object)),
+            }
+        }
+        for (name, ovalues) in &old.enums {
+            match self.enums.get(name) {
+                Some(values) if ovalues.is_subset(values) => (),
+                Some(_) => errors.push(format!("Values were removed from the enum type {}", name)),
+                None => errors.push(format!("The enum type {} was removed", name)),
+            }
+        }
+        errors
+    }
+
+    /// Generate the DDL that changes the database schema for `old` into
+    /// the one for `self`. The caller must have checked with
+    /// `can_evolve_from` that that is possible
+    ///
+    /// See the unit tests at the end of this file for the actual DDL that
+    /// gets generated
+    pub fn evolve_ddl(&self, old: &Layout) -> Result<SchemaEvolution, fmt::Error> {
+        let mut enum_values = Vec::new();
+        let mut ddl = String::new();
+
+        for (name, values) in &self.enums {
+            match old.enums.get(name) {
+                Some(ovalues) => {
+                    for value in values.difference(ovalues) {
+                        enum_values.push(format!(
+                            "alter type {}.{} add value if not exists '{}';",
+                            self.catalog.schema,
+                            SqlName::from(name.as_str()).quoted(),
+                            value
+                        ));
+                    }
+                }
+                None => self.enum_ddl(&mut ddl, name, values)?,
+            }
+        }
+        let mut tables = self.tables.values().collect::<Vec<_>>();
+        tables.sort_by_key(|table| table.position);
+        for table in tables {
+            match old.table(&table.name) {
+                Some(otable) => table.evolve_ddl(&mut ddl, self, otable)?,
+                None => table.as_ddl(&mut ddl, self)?,
+            }
+        }
+        Ok(SchemaEvolution { enum_values, ddl })
+    }
+
+    /// Generate the DDL that makes the tables of this layout, which are
+    /// stored in `remote_schema` in the database behind the foreign server
+    /// `server`, accessible as foreign tables in the schema of this layout.
+    /// Enum columns are declared as `text` since the enum types only exist
+    /// in the remote database
+    pub fn as_foreign_ddl(&self, server: &str, remote_schema: &str) -> Result<String, fmt::Error> {
+        let mut out = String::new();
+        let mut tables = self.tables.values().collect::<Vec<_>>();
+        tables.sort_by_key(|table| table.position);
+        for table in tables {
+            writeln!(
+                out,
+                "create foreign table {}.{} (",
+                self.catalog.schema,
+                table.name.quoted()
+            )?;
+            for column in &table.columns {
+                let sql_type = match &column.column_type {
+                    ColumnType::Enum(_) => "text",
+                    column_type => column_type.sql_type(),
+                };
+                write!(out, "    {} {}", column.name.quoted(), sql_type)?;
+                if column.is_list() {
+                    write!(out, "[]")?;
+                }
+                writeln!(out, ",")?;
+            }
+            if table.immutable {
+                writeln!(out, "    {} int8,\n    {} int4", VID_COLUMN, BLOCK_COLUMN)?;
+            } else {
+                writeln!(
+                    out,
+                    "    {} int8,\n    {} int4range",
+                    VID_COLUMN, BLOCK_RANGE_COLUMN
+                )?;
+            }
+            writeln!(
+                out,
+                ") server {} options (schema_name '{}', table_name '{}');",
+                server, remote_schema, table.name
+            )?;
+        }
+        Ok(out)
+    }
+
     /// Find the table with the provided `name`. The name must exactly match
     /// the name of an existing table. No conversions of the name are done
     pub fn table(&self, name: &SqlName) -> Option<&Table> {
@@ -559,6 +620,72 @@ impl Layout {
         Ok(())
     }
 
+    /// Insert `entities`, which must all be of type `entity_type`, with
+    /// as few statements as possible. The result is the same as calling
+    /// `insert` for each of them. The entities are sent to the database
+    /// with `COPY` over `copy_conn`, which must be a connection to the same
+    /// database as `conn`; see `BulkInsertQuery`
+    pub fn insert_many(
+        &self,
+        conn: &PgConnection,
+        copy_conn: &postgres::Connection,
+        entity_type: &str,
+        entities: Vec<(&EntityKey, Entity)>,
+        block: BlockNumber,
+    ) -> Result<(), StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        if entities.is_empty() {
+            return Ok(());
+        }
+        let query = BulkInsertQuery::new(&self.catalog.schema, table, entities, block)?;
+
+        let copy_err = |e: postgres::Error| {
+            StoreError::Unknown(format_err!(
+                "failed to copy entities of type {}: {}",
+                entity_type,
+                e
+            ))
+        };
+        copy_conn
+            .batch_execute(&query.create_staging_table())
+            .map_err(copy_err)?;
+        let data = query.copy_data()?;
+        copy_conn
+            .prepare(&query.copy_statement())
+            .and_then(|stmt| stmt.copy_in(&[], &mut data.as_slice()))
+            .map_err(copy_err)?;
+
+        query.execute(conn)?;
+        conn.batch_execute(&query.drop_staging_table())?;
+        Ok(())
+    }
+
+    /// Drop the staging tables that `insert_many` left behind when the
+    /// transaction it was part of failed. This must only be called when
+    /// nothing is writing to the subgraph
+    pub fn drop_staging_tables(&self, conn: &PgConnection) -> Result<(), StoreError> {
+        #[derive(QueryableByName)]
+        struct Staging {
+            #[sql_type = "Text"]
+            tablename: String,
+        }
+
+        let tables = diesel::sql_query(
+            "select tablename from pg_tables \
+              where schemaname = $1 and tablename like $2 || '%'",
+        )
+        .bind::<Text, _>(&self.catalog.schema)
+        .bind::<Text, _>(STAGING_TABLE_PREFIX)
+        .load::<Staging>(conn)?;
+        for table in tables {
+            conn.batch_execute(&format!(
+                "drop table \"{}\".\"{}\"",
+                self.catalog.schema, table.tablename
+            ))?;
+        }
+        Ok(())
+    }
+
     pub fn insert_unversioned(
         &self,
         conn: &PgConnection,
@@ -571,18 +698,97 @@ impl Layout {
         Ok(())
     }
 
+    /// Create a BTree index over the attributes `fields` of `entity_type`.
+    /// The index is built concurrently so that indexing can continue while
+    /// it is being built. Since that is not possible inside a transaction,
+    /// this must be called outside of one. Returns the name of the index
+    pub fn create_index(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        fields: &[String],
+    ) -> Result<String, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        if fields.is_empty() {
+            return Err(StoreError::QueryExecutionError(format!(
+                "an index on {} needs at least one attribute",
+                entity_type
+            )));
+        }
+        let columns = fields
+            .iter()
+            .map(|field| {
+                let column = table.column_for_field(field)?;
+                if column.is_list() || column.is_fulltext() {
+                    Err(StoreError::QueryExecutionError(format!(
+                        "the attribute {}.{} can not be part of an index",
+                        entity_type, field
+                    )))
+                } else {
+                    Ok(column.name.clone())
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let name = manual_index_name(&table.name, &columns);
+        let sql = format!(
+            "create index concurrently if not exists {name}\n    on {schema_name}.\"{table_name}\" using btree({index_expr})",
+            name = name,
+            schema_name = self.catalog.schema,
+            table_name = table.name,
+            index_expr = table.btree_exprs(&columns)?,
+        );
+        conn.batch_execute(&sql)?;
+        Ok(name)
+    }
+
+    /// Drop the index `name` from this layout's database schema without
+    /// blocking writes. This must be called outside of a transaction
+    pub fn drop_index(&self, conn: &PgConnection, name: &str) -> Result<(), StoreError> {
+        SqlName::check_valid_identifier(name, "index")?;
+        if !crate::catalog::index_exists(conn, &self.catalog.schema, name)? {
+            return Err(StoreError::QueryExecutionError(format!(
+                "there is no index {} for subgraph {}",
+                name, self.subgraph
+            )));
+        }
+        let sql = format!(
+            "drop index concurrently if exists {}.{}",
+            self.catalog.schema, name
+        );
+        conn.batch_execute(&sql)?;
+        Ok(())
+    }
+
     pub fn conflicting_entity(
         &self,
         conn: &PgConnection,
         entity_id: &String,
         entities: Vec<&String>,
     ) -> Result<Option<String>, StoreError> {
-        Ok(ConflictingEntityQuery::new(self, entities, entity_id)?
+        let ids = [entity_id.as_str()];
+        Ok(ConflictingEntityQuery::new(self, entities, &ids)?
             .load(conn)?
             .pop()
             .map(|data| data.entity))
     }
 
+    /// Like `conflicting_entity`, but check many ids at once. Returns the
+    /// entity type and id of each entity in `entities` whose id is one of
+    /// `entity_ids`
+    pub fn conflicting_entities(
+        &self,
+        conn: &PgConnection,
+        entity_ids: &[&str],
+        entities: Vec<&String>,
+    ) -> Result<Vec<(String, String)>, StoreError> {
+        Ok(ConflictingEntityQuery::new(self, entities, entity_ids)?
+            .load(conn)?
+            .into_iter()
+            .map(|data| (data.entity, data.id))
+            .collect())
+    }
+
     /// order is a tuple (attribute, value_type, direction)
     pub fn query<T: crate::relational_queries::FromEntityData>(
         &self,
@@ -655,12 +861,46 @@ impl Layout {
         block: BlockNumber,
     ) -> Result<(), StoreError> {
         let table = self.table_for_entity(&key.entity_type)?;
+        if table.immutable {
+            return Err(StoreError::QueryExecutionError(format!(
+                "can not update entity {}[{}] since entities of type {} are immutable",
+                key.entity_type, key.entity_id, key.entity_type
+            )));
+        }
         ClampRangeQuery::new(table, key, block).execute(conn)?;
         let query = InsertQuery::new(table, key, entity, block)?;
         query.execute(conn)?;
         Ok(())
     }
 
+    /// Update `entities`, which must all be of type `entity_type`, with as
+    /// few statements as possible. The result is the same as calling
+    /// `update` for each of them
+    pub fn update_many(
+        &self,
+        conn: &PgConnection,
+        copy_conn: &postgres::Connection,
+        entity_type: &str,
+        entities: Vec<(&EntityKey, Entity)>,
+        block: BlockNumber,
+    ) -> Result<(), StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        if let Some((key, _)) = entities.first() {
+            if table.immutable {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "can not update entity {}[{}] since entities of type {} are immutable",
+                    key.entity_type, key.entity_id, key.entity_type
+                )));
+            }
+        }
+        let ids: Vec<_> = entities
+            .iter()
+            .map(|(key, _)| key.entity_id.as_str())
+            .collect();
+        BulkClampRangeQuery::new(table, &ids, block).execute(conn)?;
+        self.insert_many(conn, copy_conn, entity_type, entities, block)
+    }
+
     pub fn update_unversioned(
         &self,
         conn: &PgConnection,
@@ -697,9 +937,37 @@ impl Layout {
         block: BlockNumber,
     ) -> Result<usize, StoreError> {
         let table = self.table_for_entity(&key.entity_type)?;
+        if table.immutable {
+            return Err(StoreError::QueryExecutionError(format!(
+                "can not remove entity {}[{}] since entities of type {} are immutable",
+                key.entity_type, key.entity_id, key.entity_type
+            )));
+        }
         Ok(ClampRangeQuery::new(table, key, block).execute(conn)?)
     }
 
+    /// Delete the entities of type `entity_type` with the given `ids`.
+    /// The result is the same as calling `delete` for each of them.
+    /// Returns the number of entities that were deleted
+    pub fn delete_many(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        ids: &[&str],
+        block: BlockNumber,
+    ) -> Result<usize, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        if let Some(id) = ids.first() {
+            if table.immutable {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "can not remove entity {}[{}] since entities of type {} are immutable",
+                    entity_type, id, entity_type
+                )));
+            }
+        }
+        Ok(BulkClampRangeQuery::new(table, ids, block).execute(conn)?)
+    }
+
     pub fn delete_unversioned(
         &self,
         conn: &PgConnection,
@@ -727,12 +995,17 @@ impl Layout {
                 .collect::<HashSet<_>>();
             // Make the versions current that existed at `block - 1` but that
             // are not current yet. Those are the ones that were updated or
-            // deleted at `block`
-            let unclamped = RevertClampQuery::new(table, block - 1)
-                .get_results(conn)?
-                .into_iter()
-                .map(|data| data.id)
-                .collect::<HashSet<_>>();
+            // deleted at `block`. Immutable entities are never updated or
+            // deleted, and there is nothing to unclamp for them
+            let unclamped = if table.immutable {
+                HashSet::new()
+            } else {
+                RevertClampQuery::new(table, block - 1)
+                    .get_results(conn)?
+                    .into_iter()
+                    .map(|data| data.id)
+                    .collect::<HashSet<_>>()
+            };
             // Adjust the entity count; we can tell which operation was
             // initially performed by
             //   id in (unset - unclamped)  => insert (we now deleted)
@@ -764,6 +1037,128 @@ impl Layout {
         Ok((StoreEvent::new(changes), count))
     }
 
+    /// Remove all entity versions that are not visible at `block` or any
+    /// later block. Afterwards, queries at blocks before `block` will
+    /// return incomplete results. Returns the number of versions that were
+    /// removed
+    ///
+    /// Versions are removed in batches of `PRUNE_BATCH_SIZE` `vid`s, each
+    /// in its own transaction, so that pruning a large table does not keep
+    /// a long-running transaction open. This must therefore not be called
+    /// inside a transaction
+    pub fn prune(&self, conn: &PgConnection, block: BlockNumber) -> Result<usize, StoreError> {
+        #[derive(QueryableByName)]
+        struct VidRange {
+            #[sql_type = "BigInt"]
+            min_vid: i64,
+            #[sql_type = "BigInt"]
+            max_vid: i64,
+        }
+
+        let mut count = 0;
+        // Immutable entities are visible at every block after they
+        // were created, and there is therefore never anything to prune
+        for table in self.tables.values().filter(|table| !table.immutable) {
+            let range = diesel::sql_query(format!(
+                "select coalesce(min(vid), 0) as min_vid, coalesce(max(vid), -1) as max_vid \
+                   from {}",
+                table.qualified_name
+            ))
+            .get_result::<VidRange>(conn)?;
+            let mut first_vid = range.min_vid;
+            while first_vid <= range.max_vid {
+                let last_vid = (first_vid + PRUNE_BATCH_SIZE - 1).min(range.max_vid);
+                count += conn.transaction(|| {
+                    PruneQuery::new(table, block, first_vid, last_vid).execute(conn)
+                })?;
+                first_vid = last_vid + 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Count the entities and entity versions of each type and determine
+    /// how much space their tables take up. The result is sorted by
+    /// entity type
+    pub fn entity_stats(&self, conn: &PgConnection) -> Result<Vec<EntityTypeStats>, StoreError> {
+        self.table_stats(|table| TableStatsQuery::new(table).get_result::<TableStats>(conn))
+    }
+
+    /// Like `entity_stats`, but use the statistics Postgres keeps about
+    /// each table to estimate the number of entities and entity versions
+    /// instead of counting them
+    pub fn estimated_entity_stats(
+        &self,
+        conn: &PgConnection,
+    ) -> Result<Vec<EntityTypeStats>, StoreError> {
+        let namespace = self.catalog.schema.as_str();
+        self.table_stats(|table| {
+            TableStatsEstimateQuery::new(namespace, table).get_result::<TableStats>(conn)
+        })
+    }
+
+    fn table_stats<F>(&self, query: F) -> Result<Vec<EntityTypeStats>, StoreError>
+    where
+        F: Fn(&Table) -> QueryResult<TableStats>,
+    {
+        let mut tables: Vec<_> = self.tables.values().collect();
+        tables.sort_by(|a, b| a.object.cmp(&b.object));
+        tables
+            .into_iter()
+            .map(|table| -> Result<EntityTypeStats, StoreError> {
+                let stats = query(table)?;
+                Ok(EntityTypeStats {
+                    entity_type: table.object.clone(),
+                    entities: stats.entities,
+                    versions: stats.versions,
+                    table_size: stats.table_size,
+                    index_size: stats.index_size,
+                })
+            })
+            .collect()
+    }
+
+    /// Find the entities that were created, updated or removed in `block`.
+    /// An entity was created in `block` if a version of it starts there but
+    /// none ends there, removed if a version ends there but none starts
+    /// there, and updated if both happen
+    pub fn entity_changes_in_block(
+        &self,
+        conn: &PgConnection,
+        block: BlockNumber,
+    ) -> Result<Vec<EntityTypeChanges>, StoreError> {
+        let mut tables: Vec<_> = self.tables.values().collect();
+        tables.sort_by(|a, b| a.object.cmp(&b.object));
+
+        let mut changes = Vec::new();
+        for table in tables {
+            let mut created = Vec::new();
+            let mut updated = Vec::new();
+            let mut removed = Vec::new();
+            for data in BlockChangesQuery::new(table, block).get_results::<BlockChangeData>(conn)? {
+                match (data.started, data.ended) {
+                    (true, false) => created.push(data.id),
+                    (true, true) => updated.push(data.id),
+                    (false, true) => removed.push(data.id),
+                    (false, false) => (),
+                }
+            }
+            if created.is_empty() && updated.is_empty() && removed.is_empty() {
+                continue;
+            }
+            created.sort();
+            updated.sort();
+            removed.sort();
+            changes.push(EntityTypeChanges {
+                entity_type: table.object.clone(),
+                created,
+                updated,
+                removed,
+            });
+        }
+        Ok(changes)
+    }
+
     /// Revert the metadata (dynamic data sources and related entities) for
     /// the given `subgraph`. This function can only be called on the `Layout`
     /// for the metadata subgraph.
@@ -837,6 +1232,17 @@ impl Layout {
     }
 }
 
+/// The DDL needed to change the database schema of a deployment in place,
+/// as generated by `Layout::evolve_ddl`
+#[derive(Debug)]
+pub struct SchemaEvolution {
+    /// Statements that add values to existing enum types. Postgres does
+    /// not allow running them inside a transaction block
+    pub enum_values: Vec<String>,
+    /// All other statements; they can run in one transaction
+    pub ddl: String,
+}
+
 /// A user-defined enum
 #[derive(Clone, Debug, PartialEq)]
 pub struct EnumType {
@@ -972,6 +1378,9 @@ pub struct Column {
     pub column_type: ColumnType,
     pub fulltext_fields: Option<HashSet<String>>,
     is_reference: bool,
+    /// Whether to create an index for this column; attributes marked with
+    /// `@noIndex` in the GraphQL schema do not get one
+    use_index: bool,
 }
 
 impl Column {
@@ -1000,6 +1409,7 @@ impl Column {
                 is_existing_text_column,
             )?
         };
+        let use_index = field.find_directive(String::from("noIndex")).is_none();
         Ok(Column {
             name: sql_name,
             field: field.name.clone(),
@@ -1007,6 +1417,7 @@ impl Column {
             field_type: field.field_type.clone(),
             fulltext_fields: None,
             is_reference,
+            use_index,
         })
     }
 
@@ -1021,6 +1432,7 @@ impl Column {
             column_type: ColumnType::TSVector(def.config.clone()),
             fulltext_fields: Some(def.included_fields.clone()),
             is_reference: false,
+            use_index: true,
         })
     }
 
@@ -1106,6 +1518,48 @@ impl Column {
         }
     }
 
+    /// Check that changing the column `old` into `self` does not require
+    /// changing the data stored in it
+    fn can_evolve_from(&self, old: &Self, object: &str) -> Option<String> {
+        if self.field_type != old.field_type {
+            return Some(format!(
+                "The attribute {}.{} has type {}, but its current type is {}",
+                object, self.field, self.field_type, old.field_type
+            ));
+        }
+        match (&self.column_type, &old.column_type) {
+            (ColumnType::TSVector(config), ColumnType::TSVector(old_config))
+                if config != old_config || self.fulltext_fields != old.fulltext_fields =>
+            {
+                Some(format!(
+                    "The definition of the fulltext field {}.{} has changed",
+                    object, self.field
+                ))
+            }
+            (ColumnType::Enum(enum_type), ColumnType::Enum(old_enum_type))
+                if enum_type.name != old_enum_type.name =>
+            {
+                Some(format!(
+                    "The attribute {}.{} changed from enum {} to enum {}",
+                    object, self.field, old_enum_type.name, enum_type.name
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// The expression to use when this column is part of a BTree index.
+    /// Attributes that are plain strings can be too large for Postgres'
+    /// limit on values that can go into a BTree. For those attributes, we
+    /// only index the first STRING_PREFIX_SIZE characters
+    fn btree_expr(&self) -> String {
+        if self.is_text() {
+            format!("left({}, {})", self.name.quoted(), STRING_PREFIX_SIZE)
+        } else {
+            self.name.quoted()
+        }
+    }
+
     /// Generate the DDL for one column, i.e. the part of a `create table`
     /// statement for this column.
     ///
@@ -1150,6 +1604,15 @@ pub struct Table {
     /// entities are updated frequently on average
     pub is_account_like: bool,
 
+    /// Entities of this type are never updated or removed once they have
+    /// been created (`@entity(immutable: true)`). Their table has a plain
+    /// `block$` column instead of a `block_range`
+    pub immutable: bool,
+
+    /// Additional indexes declared with `@index(fields: [..])` on the
+    /// GraphQL type. Each entry lists the columns of one index in order
+    pub composite_indexes: Vec<Vec<SqlName>>,
+
     /// The position of this table in all the tables for this layout; this
     /// is really only needed for the tests to make the names of indexes
     /// predictable
@@ -1177,11 +1640,44 @@ impl Table {
             .collect::<Result<Vec<Column>, StoreError>>()?;
         let is_account_like =
             ACCOUNT_TABLES.contains(&format!("{}.{}", catalog.schema, table_name));
+        let composite_indexes = defn
+            .directives
+            .iter()
+            .filter(|directive| directive.name == "index")
+            .map(|index| {
+                index
+                    .argument("fields")
+                    .and_then(|fields| fields.as_list())
+                    .ok_or_else(|| {
+                        StoreError::MalformedDirective(format!(
+                            "@index on {} needs a list of fields",
+                            defn.name
+                        ))
+                    })?
+                    .iter()
+                    .map(|field| {
+                        let field = field.as_string().ok_or_else(|| {
+                            StoreError::MalformedDirective(format!(
+                                "@index on {} must only list field names",
+                                defn.name
+                            ))
+                        })?;
+                        columns
+                            .iter()
+                            .find(|column| &column.field == field)
+                            .map(|column| column.name.clone())
+                            .ok_or_else(|| StoreError::UnknownField(field.to_owned()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
         let table = Table {
             object: defn.name.clone(),
             name: table_name.clone(),
             qualified_name: SqlName::qualified_name(&catalog.schema, &table_name),
             is_account_like,
+            immutable: defn.is_immutable(),
+            composite_indexes,
             columns,
             position,
         };
@@ -1210,6 +1706,15 @@ impl Table {
     }
 
     fn can_copy_from(&self, source: &Self) -> Vec<String> {
+        let immutable = if self.immutable != source.immutable {
+            Some(format!(
+                "The entity type {} is immutable in only one of the source \
+                 and the destination",
+                self.object
+            ))
+        } else {
+            None
+        };
         self.columns
             .iter()
             .filter_map(|dcol| match source.column(&dcol.name) {
@@ -1226,9 +1731,45 @@ impl Table {
                     }
                 }
             })
+            .chain(immutable)
             .collect()
     }
 
+    fn can_evolve_from(&self, old: &Self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.immutable != old.immutable {
+            errors.push(format!(
+                "The entity type {} can not change whether it is immutable",
+                self.object
+            ));
+        }
+        for ocol in &old.columns {
+            match self.columns.iter().find(|col| col.name == ocol.name) {
+                Some(col) => errors.extend(col.can_evolve_from(ocol, &self.object)),
+                None => errors.push(format!(
+                    "The attribute {}.{} was removed",
+                    self.object, ocol.field
+                )),
+            }
+        }
+        for col in self.new_columns(old) {
+            if !col.is_nullable() {
+                errors.push(format!(
+                    "The new attribute {}.{} must be nullable",
+                    self.object, col.field
+                ));
+            }
+        }
+        errors
+    }
+
+    /// The columns of `self` that do not exist in `old`
+    fn new_columns<'a>(&'a self, old: &'a Self) -> impl Iterator<Item = &'a Column> + 'a {
+        self.columns
+            .iter()
+            .filter(move |col| !old.columns.iter().any(|ocol| ocol.name == col.name))
+    }
+
     pub fn primary_key(&self) -> &Column {
         self.columns
             .iter()
@@ -1253,93 +1794,262 @@ impl Table {
             column.as_ddl(out)?;
             writeln!(out, ",")?;
         }
-        // Add block_range column and constraint
-        write!(
-            out,
-            "\n        {vid}                  bigserial primary key,\
-             \n        {block_range}          int4range not null,
-        exclude using gist   (id with =, {block_range} with &&)\n);\n",
-            vid = VID_COLUMN,
-            block_range = BLOCK_RANGE_COLUMN
-        )?;
+        if self.immutable {
+            // Immutable entities only ever have one version, and we only
+            // need to remember the block at which they were created
+            write!(
+                out,
+                "\n        {vid}                  bigserial primary key,\
+                 \n        {block}               int4 not null,\
+                 \n        unique(id)\n);\n",
+                vid = VID_COLUMN,
+                block = BLOCK_COLUMN
+            )?;
 
-        // Add a BRIN index on the block_range bounds to exploit the fact
-        // that block ranges closely correlate with where in a table an
-        // entity appears physically. This index is incredibly efficient for
-        // reverts where we look for very recent blocks, so that this index
-        // is highly selective. See https://github.com/graphprotocol/graph-node/issues/1415#issuecomment-630520713
-        // for details on one experiment.
-        //
-        // We do not index the `block_range` as a whole, but rather the lower
-        // and upper bound separately, since experimentation has shown that
-        // Postgres will not use the index on `block_range` for clauses like
-        // `block_range @> $block` but rather falls back to a full table scan.
-        //
-        // We also make sure that we do not put `NULL` in the index for
-        // the upper bound since nulls can not be compared to anything and
-        // will make the index less effective.
-        //
-        // To make the index usable, queries need to have clauses using
-        // `lower(block_range)` and `coalesce(..)` verbatim.
-        //
-        // We also index `vid` as that correlates with the order in which
-        // entities are stored.
-        write!(out,"create index brin_{table_name}\n    \
-                    on {schema_name}.{table_name}\n \
-                       using brin(lower(block_range), coalesce(upper(block_range), {block_max}), vid);\n",
-            table_name = self.name,
-            schema_name = layout.catalog.schema,
-            block_max = BLOCK_NUMBER_MAX)?;
+            // Reverts and queries by block only look at `block$`, which
+            // correlates with where the entity is physically stored in the
+            // same way as the block range for mutable entities does
+            write!(
+                out,
+                "create index brin_{table_name}\n    \
+                 on {schema_name}.{table_name}\n \
+                 using brin({block}, vid);\n",
+                table_name = self.name,
+                schema_name = layout.catalog.schema,
+                block = BLOCK_COLUMN
+            )?;
+        } else {
+            // Add block_range column and constraint
+            write!(
+                out,
+                "\n        {vid}                  bigserial primary key,\
+                 \n        {block_range}          int4range not null,
+        exclude using gist   (id with =, {block_range} with &&)\n);\n",
+                vid = VID_COLUMN,
+                block_range = BLOCK_RANGE_COLUMN
+            )?;
 
-        // Create indexes. Skip columns whose type is an array of enum,
-        // since there is no good way to index them with Postgres 9.6.
-        // Once we move to Postgres 11, we can enable that
-        // (tracked in graph-node issue #1330)
-        for (i, column) in self
-            .columns
-            .iter()
-            .filter(|col| !(col.is_list() && col.is_enum()))
-            .enumerate()
-        {
-            let (method, index_expr) = if column.is_reference() && !column.is_list() {
-                // For foreign keys, index the key together with the block range
-                // since we almost always also have a block_range clause in
-                // queries that look for specific foreign keys
-                let index_expr = format!("{}, {}", column.name.quoted(), BLOCK_RANGE_COLUMN);
-                ("gist", index_expr)
-            } else {
-                // Attributes that are plain strings are indexed with a BTree; but
-                // they can be too large for Postgres' limit on values that can go
-                // into a BTree. For those attributes, only index the first
-                // STRING_PREFIX_SIZE characters
-                let index_expr = if column.is_text() {
-                    format!("left({}, {})", column.name.quoted(), STRING_PREFIX_SIZE)
-                } else {
-                    column.name.quoted()
-                };
+            // Add a BRIN index on the block_range bounds to exploit the fact
+            // that block ranges closely correlate with where in a table an
+            // entity appears physically. This index is incredibly efficient for
+            // reverts where we look for very recent blocks, so that this index
+            // is highly selective. See https://github.com/graphprotocol/graph-node/issues/1415#issuecomment-630520713
+            // for details on one experiment.
+            //
+            // We do not index the `block_range` as a whole, but rather the lower
+            // and upper bound separately, since experimentation has shown that
+            // Postgres will not use the index on `block_range` for clauses like
+            // `block_range @> $block` but rather falls back to a full table scan.
+            //
+            // We also make sure that we do not put `NULL` in the index for
+            // the upper bound since nulls can not be compared to anything and
+            // will make the index less effective.
+            //
+            // To make the index usable, queries need to have clauses using
+            // `lower(block_range)` and `coalesce(..)` verbatim.
+            //
+            // We also index `vid` as that correlates with the order in which
+            // entities are stored.
+            write!(out,"create index brin_{table_name}\n    \
+                        on {schema_name}.{table_name}\n \
+                           using brin(lower(block_range), coalesce(upper(block_range), {block_max}), vid);\n",
+                table_name = self.name,
+                schema_name = layout.catalog.schema,
+                block_max = BLOCK_NUMBER_MAX)?;
+        }
 
-                let method = if column.is_list() || column.is_fulltext() {
-                    "gin"
-                } else {
-                    "btree"
-                };
+        for (i, column) in self.indexed_columns() {
+            self.attr_index_ddl(out, layout, i, column)?;
+        }
 
-                (method, index_expr)
-            };
+        for (i, columns) in self.composite_indexes.iter().enumerate() {
             write!(
                 out,
-                "create index attr_{table_index}_{column_index}_{table_name}_{column_name}\n    on {schema_name}.\"{table_name}\" using {method}({index_expr});\n",
+                "create index cidx_{table_index}_{index}_{table_name}\n    on {schema_name}.\"{table_name}\" using btree({index_expr});\n",
                 table_index = self.position,
                 table_name = self.name,
-                column_index = i,
-                column_name = column.name,
+                index = i,
                 schema_name = layout.catalog.schema,
-                method = method,
-                index_expr = index_expr,
+                index_expr = self.btree_exprs(columns).map_err(|_| fmt::Error)?,
             )?;
         }
         writeln!(out)
     }
+
+    /// Generate the DDL that adds what is new in `self` compared to `old`
+    /// to the existing table, i.e., `alter table` statements for new
+    /// columns and `create index` statements for their indexes and for
+    /// new composite indexes. New fulltext columns are filled from the
+    /// data that is already in the table
+    fn evolve_ddl(&self, out: &mut String, layout: &Layout, old: &Table) -> fmt::Result {
+        let new_columns = self.new_columns(old).collect::<Vec<_>>();
+        for column in &new_columns {
+            write!(
+                out,
+                "alter table {}.{}\n    add column {} {}",
+                layout.catalog.schema,
+                self.name.quoted(),
+                column.name.quoted(),
+                column.sql_type()
+            )?;
+            if column.is_list() {
+                write!(out, "[]")?;
+            }
+            writeln!(out, ";")?;
+
+            if let (ColumnType::TSVector(config), Some(fields)) =
+                (&column.column_type, &column.fulltext_fields)
+            {
+                // Mirror what inserting an entity does for fulltext fields
+                let mut fields = fields.iter().collect::<Vec<_>>();
+                fields.sort();
+                let exprs = fields
+                    .into_iter()
+                    .map(|field| {
+                        self.column_for_field(field).map(|col| {
+                            format!(
+                                "to_tsvector('{}'::regconfig, coalesce({}, ''))",
+                                config.language.as_str(),
+                                col.name.quoted()
+                            )
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| fmt::Error)?;
+                writeln!(
+                    out,
+                    "update {}.{}\n   set {} = {};",
+                    layout.catalog.schema,
+                    self.name.quoted(),
+                    column.name.quoted(),
+                    exprs.join(" || ")
+                )?;
+            }
+        }
+
+        for (i, column) in self
+            .indexed_columns()
+            .filter(|(_, col)| new_columns.iter().any(|ncol| ncol.name == col.name))
+        {
+            self.attr_index_ddl(out, layout, i, column)?;
+        }
+
+        // Use the same naming scheme as `Layout::create_index` since the
+        // positional names of indexes created with the table might already
+        // be taken by other indexes
+        for columns in self
+            .composite_indexes
+            .iter()
+            .filter(|columns| !old.composite_indexes.contains(columns))
+        {
+            write!(
+                out,
+                "create index if not exists {index_name}\n    on {schema_name}.\"{table_name}\" using btree({index_expr});\n",
+                index_name = manual_index_name(&self.name, columns),
+                table_name = self.name,
+                schema_name = layout.catalog.schema,
+                index_expr = self.btree_exprs(columns).map_err(|_| fmt::Error)?,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The columns that get an attribute index together with their
+    /// position, which is used to name the index. Skip columns whose type
+    /// is an array of enum, since there is no good way to index them with
+    /// Postgres 9.6. Once we move to Postgres 11, we can enable that
+    /// (tracked in graph-node issue #1330)
+    /// Columns marked with `@noIndex` are skipped, too, but we still
+    /// count them so that index names do not depend on them
+    fn indexed_columns(&self) -> impl Iterator<Item = (usize, &Column)> {
+        self.columns
+            .iter()
+            .filter(|col| !(col.is_list() && col.is_enum()))
+            .enumerate()
+            .filter(|(_, col)| col.use_index)
+    }
+
+    /// Generate the `create index` statement for the attribute index on
+    /// `column`, which is the `i`-th column in `indexed_columns`
+    fn attr_index_ddl(
+        &self,
+        out: &mut String,
+        layout: &Layout,
+        i: usize,
+        column: &Column,
+    ) -> fmt::Result {
+        let (method, index_expr) = if column.is_reference() && !column.is_list() {
+            // For foreign keys, index the key together with the block range
+            // since we almost always also have a block_range clause in
+            // queries that look for specific foreign keys
+            if self.immutable {
+                let index_expr = format!("{}, {}", column.name.quoted(), BLOCK_COLUMN);
+                ("btree", index_expr)
+            } else {
+                let index_expr = format!("{}, {}", column.name.quoted(), BLOCK_RANGE_COLUMN);
+                ("gist", index_expr)
+            }
+        } else {
+            let index_expr = column.btree_expr();
+
+            let method = if column.is_list() || column.is_fulltext() {
+                "gin"
+            } else {
+                "btree"
+            };
+
+            (method, index_expr)
+        };
+        write!(
+            out,
+            "create index attr_{table_index}_{column_index}_{table_name}_{column_name}\n    on {schema_name}.\"{table_name}\" using {method}({index_expr});\n",
+            table_index = self.position,
+            table_name = self.name,
+            column_index = i,
+            column_name = column.name,
+            schema_name = layout.catalog.schema,
+            method = method,
+            index_expr = index_expr,
+        )
+    }
+
+    /// The expression to use in a BTree index over `columns`
+    fn btree_exprs(&self, columns: &[SqlName]) -> Result<String, StoreError> {
+        let exprs = columns
+            .iter()
+            .map(|name| {
+                self.column(name)
+                    .map(|column| column.btree_expr())
+                    .ok_or_else(|| StoreError::UnknownField(name.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(exprs.join(", "))
+    }
+}
+
+/// The name for an index on `columns` of `table` that was created with
+/// `Layout::create_index`. Postgres limits identifiers to 63 bytes; for
+/// longer names we keep a prefix and make the name unique with a hash of
+/// the full name. The hash must not change between releases since we use
+/// the name to find indexes we created earlier
+fn manual_index_name(table: &SqlName, columns: &[SqlName]) -> String {
+    use fnv::FnvHasher;
+    use std::hash::Hasher;
+
+    const MAX_LEN: usize = 63;
+
+    let columns = columns.iter().map(|c| c.as_str()).collect::<Vec<_>>();
+    let name = format!("manual_{}_{}", table, columns.join("_"));
+    if name.len() <= MAX_LEN {
+        name
+    } else {
+        let mut hasher = FnvHasher::default();
+        hasher.write(name.as_bytes());
+        let hash = format!("{:016x}", hasher.finish());
+        let mut prefix = name;
+        prefix.truncate(MAX_LEN - hash.len() - 1);
+        format!("{}_{}", prefix, hash)
+    }
 }
 
 /// Return the enclosed named type for a field type, i.e., the type after
@@ -1425,6 +2135,34 @@ mod tests {
         let layout = test_layout(FORWARD_ENUM_GQL);
         let sql = layout.as_ddl().expect("Failed to generate DDL");
         assert_eq!(FORWARD_ENUM_SQL, sql);
+
+        let layout = test_layout(IMMUTABLE_GQL);
+        let sql = layout.as_ddl().expect("Failed to generate DDL");
+        assert_eq!(IMMUTABLE_DDL, sql);
+
+        let layout = test_layout(INDEX_GQL);
+        let sql = layout.as_ddl().expect("Failed to generate DDL");
+        assert_eq!(INDEX_DDL, sql);
+    }
+
+    #[test]
+    fn manual_index_names() {
+        let table = SqlName::from("swap");
+        let columns = vec![SqlName::from("pair"), SqlName::from("timestamp")];
+        assert_eq!(
+            "manual_swap_pair_timestamp",
+            manual_index_name(&table, &columns)
+        );
+
+        let columns = ('a'..='t')
+            .map(|c| SqlName::from(format!("column_{}", c)))
+            .collect::<Vec<_>>();
+        let name = manual_index_name(&table, &columns);
+        assert_eq!(
+            "manual_swap_column_a_column_b_column_c_column__b238b0439e4e38ff",
+            name
+        );
+        assert_ne!(name, manual_index_name(&table, &columns[1..]));
     }
 
     #[test]
@@ -1500,6 +2238,145 @@ mod tests {
             ],
             dest.can_copy_from(&source)
         );
+        // We can not change whether an entity type is immutable
+        let source = test_layout("type Scalar { id: ID }");
+        let dest = test_layout("type Scalar @entity(immutable: true) { id: ID }");
+        assert_eq!(
+            vec![
+                "The entity type Scalar is immutable in only one of the source \
+                 and the destination"
+            ],
+            dest.can_copy_from(&source)
+        );
+    }
+
+    #[test]
+    fn can_evolve_from() {
+        let old = test_layout(THING_GQL);
+        assert!(old.can_evolve_from(&old).is_empty());
+
+        let new = test_layout(
+            "type Thing @entity { id: ID!, bigThing: Thing!, size: Size } \
+             enum Color { yellow, red, BLUE, green } \
+             enum Size { small, medium, large } \
+             type Scalar { id: ID, bool: Boolean, int: Int, bigDecimal: BigDecimal, \
+                           string: String, bytes: Bytes, bigInt: BigInt, color: Color } \
+             type Other @entity { id: ID! }",
+        );
+        assert!(new.can_evolve_from(&old).is_empty());
+
+        let new = test_layout(
+            "type Thing @entity(immutable: true) { id: ID!, bigThing: Thing!, count: Int! } \
+             enum Color { yellow, red } \
+             type Scalar { id: ID, bool: Boolean, int: Int!, bigDecimal: BigDecimal, \
+                           string: [String], bytes: Bytes, bigInt: BigInt, color: Color }",
+        );
+        assert_eq!(
+            vec![
+                "The entity type Thing can not change whether it is immutable",
+                "The new attribute Thing.count must be nullable",
+                "The attribute Scalar.int has type Int!, but its current type is Int",
+                "The attribute Scalar.string has type [String], but its current type is String",
+                "Values were removed from the enum type Color",
+                "The enum type Size was removed",
+            ],
+            new.can_evolve_from(&old)
+        );
+
+        let new = test_layout("type Thing @entity { id: ID! }");
+        assert_eq!(
+            vec![
+                "The attribute Thing.bigThing was removed",
+                "The entity type Scalar was removed",
+                "The enum type Color was removed",
+                "The enum type Size was removed",
+            ],
+            new.can_evolve_from(&old)
+        );
+    }
+
+    #[test]
+    fn evolve_ddl() {
+        let old = test_layout(
+            "type Thing @entity { id: ID!, name: String! } \
+             enum Color { red }",
+        );
+        let new = test_layout(
+            "type Thing @entity @index(fields: [\"name\", \"color\"]) { \
+                 id: ID!, name: String!, color: Color, tags: [String!] } \
+             enum Color { red, blue } \
+             enum Size { small } \
+             type Other @entity { id: ID! }",
+        );
+        assert!(new.can_evolve_from(&old).is_empty());
+        let evolution = new.evolve_ddl(&old).expect("Failed to generate DDL");
+        assert_eq!(
+            vec!["alter type rel.\"color\" add value if not exists 'blue';"],
+            evolution.enum_values
+        );
+        assert_eq!(EVOLVE_DDL, evolution.ddl);
+
+        // New fulltext fields get filled from existing data
+        let old = test_layout("type Animal @entity { id: ID!, name: String!, species: String! }");
+        let new = test_layout(&format!(
+            "{} type Animal @entity {{ id: ID!, name: String!, species: String! }}",
+            "type _Schema_ @fulltext(name: \"search\", language: en, algorithm: rank, \
+               include: [{ entity: \"Animal\", fields: [{ name: \"species\" }, { name: \"name\" }] }])"
+        ));
+        assert!(new.can_evolve_from(&old).is_empty());
+        let evolution = new.evolve_ddl(&old).expect("Failed to generate DDL");
+        assert!(evolution.enum_values.is_empty());
+        assert_eq!(
+            "alter table rel.\"animal\"
+    add column \"search\" tsvector;
+update rel.\"animal\"
+   set \"search\" = to_tsvector('english'::regconfig, coalesce(\"name\", '')) || \
+             to_tsvector('english'::regconfig, coalesce(\"species\", ''));
+create index attr_0_3_animal_search
+    on rel.\"animal\" using gin(\"search\");
+",
+            evolution.ddl
+        );
+
+        // Changing the fulltext definition is not possible
+        let newer = test_layout(&format!(
+            "{} type Animal @entity {{ id: ID!, name: String!, species: String! }}",
+            "type _Schema_ @fulltext(name: \"search\", language: en, algorithm: rank, \
+               include: [{ entity: \"Animal\", fields: [{ name: \"name\" }] }])"
+        ));
+        assert_eq!(
+            vec!["The definition of the fulltext field Animal.search has changed"],
+            newer.can_evolve_from(&new)
+        );
+    }
+
+    #[test]
+    fn foreign_ddl() {
+        let layout = test_layout(
+            "type Thing @entity { id: ID!, color: Color, tags: [String!] } \
+             type Event @entity(immutable: true) { id: ID!, thing: Thing! } \
+             enum Color { red, blue }",
+        );
+        let ddl = layout
+            .as_foreign_ddl("shard_other", "sgd7")
+            .expect("Failed to generate foreign DDL");
+        assert_eq!(
+            "create foreign table rel.\"thing\" (
+    \"id\" text,
+    \"color\" text,
+    \"tags\" text[],
+    vid int8,
+    block_range int4range
+) server shard_other options (schema_name 'sgd7', table_name 'thing');
+create foreign table rel.\"event\" (
+    \"id\" text,
+    \"thing\" text,
+    vid int8,
+    block$ int4
+) server shard_other options (schema_name 'sgd7', table_name 'event');
+",
+            ddl
+        );
     }
 
     const THING_GQL: &str = "
@@ -1873,5 +2750,111 @@ create index attr_0_0_thing_id
 create index attr_0_1_thing_orientation
     on rel.\"thing\" using btree(\"orientation\");
 
+";
+
+    const IMMUTABLE_GQL: &str = "
+type Transfer @entity(immutable: true) {
+    id: ID!,
+    token: Token!
+}
+
+type Token @entity {
+    id: ID!
+}
+";
+
+    const IMMUTABLE_DDL: &str = "create table rel.\"transfer\" (
+        \"id\"                 text not null,
+        \"token\"              text not null,
+
+        vid                  bigserial primary key,
+        block$               int4 not null,
+        unique(id)
+);
+create index brin_transfer
+    on rel.transfer
+ using brin(block$, vid);
+create index attr_0_0_transfer_id
+    on rel.\"transfer\" using btree(\"id\");
+create index attr_0_1_transfer_token
+    on rel.\"transfer\" using btree(\"token\", block$);
+
+create table rel.\"token\" (
+        \"id\"                 text not null,
+
+        vid                  bigserial primary key,
+        block_range          int4range not null,
+        exclude using gist   (id with =, block_range with &&)
+);
+create index brin_token
+    on rel.token
+ using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
+create index attr_1_0_token_id
+    on rel.\"token\" using btree(\"id\");
+
+";
+
+    const EVOLVE_DDL: &str = "create type rel.\"size\"
+    as enum ('small');
+alter table rel.\"thing\"
+    add column \"color\" \"rel\".\"color\";
+alter table rel.\"thing\"
+    add column \"tags\" text[];
+create index attr_0_2_thing_color
+    on rel.\"thing\" using btree(\"color\");
+create index attr_0_3_thing_tags
+    on rel.\"thing\" using gin(\"tags\");
+create index if not exists manual_thing_name_color
+    on rel.\"thing\" using btree(left(\"name\", 256), \"color\");
+create table rel.\"other\" (
+        \"id\"                 text not null,
+
+        vid                  bigserial primary key,
+        block_range          int4range not null,
+        exclude using gist   (id with =, block_range with &&)
+);
+create index brin_other
+    on rel.other
+ using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
+create index attr_1_0_other_id
+    on rel.\"other\" using btree(\"id\");
+
+";
+
+    const INDEX_GQL: &str = "
+type Swap @entity @index(fields: [\"pair\", \"timestamp\"]) {
+    id: ID!,
+    pair: String!,
+    timestamp: Int!,
+    memo: String @noIndex,
+    amount: BigInt!
+}
+";
+
+    const INDEX_DDL: &str = "create table rel.\"swap\" (
+        \"id\"                 text not null,
+        \"pair\"               text not null,
+        \"timestamp\"          integer not null,
+        \"memo\"               text,
+        \"amount\"             numeric not null,
+
+        vid                  bigserial primary key,
+        block_range          int4range not null,
+        exclude using gist   (id with =, block_range with &&)
+);
+create index brin_swap
+    on rel.swap
+ using brin(lower(block_range), coalesce(upper(block_range), 2147483647), vid);
+create index attr_0_0_swap_id
+    on rel.\"swap\" using btree(\"id\");
+create index attr_0_1_swap_pair
+    on rel.\"swap\" using btree(left(\"pair\", 256));
+create index attr_0_2_swap_timestamp
+    on rel.\"swap\" using btree(\"timestamp\");
+create index attr_0_4_swap_amount
+    on rel.\"swap\" using btree(\"amount\");
+create index cidx_0_0_swap
+    on rel.\"swap\" using btree(left(\"pair\", 256), \"timestamp\");
+
 ";
 }
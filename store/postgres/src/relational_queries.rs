@@ -9,14 +9,17 @@ use diesel::pg::{Pg, PgConnection};
 use diesel::query_builder::{AstPass, QueryFragment, QueryId};
 use diesel::query_dsl::{LoadQuery, RunQueryDsl};
 use diesel::result::{Error as DieselError, QueryResult};
-use diesel::sql_types::{Array, Binary, Bool, Integer, Jsonb, Range, Text};
+use diesel::sql_types::{Array, BigInt, Binary, Bool, Integer, Jsonb, Range, Text};
 use diesel::Connection;
 use lazy_static::lazy_static;
 use std::collections::{BTreeMap, HashSet};
 use std::convert::TryFrom;
 use std::env;
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
 use std::iter::FromIterator;
 use std::str::FromStr;
+use uuid::Uuid;
 
 use graph::data::{schema::FulltextAlgorithm, store::scalar};
 use graph::prelude::{
@@ -25,11 +28,11 @@ use graph::prelude::{
     QueryExecutionError, StoreError, Value,
 };
 
+use crate::binary_copy::{BinaryCopyWriter, CopyType, Datum, Field};
 use crate::block_range::{
-    BlockRange, BlockRangeContainsClause, BLOCK_RANGE_COLUMN, BLOCK_RANGE_CURRENT,
+    BlockRange, BlockRangeContainsClause, BLOCK_COLUMN, BLOCK_RANGE_COLUMN, BLOCK_RANGE_CURRENT,
 };
 use crate::entities::STRING_PREFIX_SIZE;
-use crate::filter::UnsupportedFilter;
 use crate::relational::{Column, ColumnType, IdType, Layout, SqlName, Table, PRIMARY_KEY_COLUMN};
 use crate::sql_value::SqlValue;
 
@@ -49,6 +52,30 @@ lazy_static! {
     };
 }
 
+#[derive(Debug)]
+pub(crate) struct UnsupportedFilter {
+    pub filter: String,
+    pub value: Value,
+}
+
+impl Display for UnsupportedFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "unsupported filter `{}` for value `{}`",
+            self.filter, self.value
+        )
+    }
+}
+
+impl StdError for UnsupportedFilter {}
+
+impl From<UnsupportedFilter> for diesel::result::Error {
+    fn from(error: UnsupportedFilter) -> Self {
+        diesel::result::Error::QueryBuilderError(Box::new(error))
+    }
+}
+
 fn str_as_bytes(id: &str) -> QueryResult<scalar::Bytes> {
     scalar::Bytes::from_str(&id).map_err(|e| DieselError::SerializationError(Box::new(e)))
 }
@@ -1172,30 +1199,7 @@ impl<'a> InsertQuery<'a> {
         entity: Entity,
         block: BlockNumber,
     ) -> Result<InsertQuery<'a>, StoreError> {
-        let mut entity = entity;
-        for column in table.columns.iter() {
-            match column.fulltext_fields.as_ref() {
-                Some(fields) => {
-                    let fulltext_field_values = fields
-                        .iter()
-                        .filter_map(|field| entity.get(field))
-                        .cloned()
-                        .collect::<Vec<Value>>();
-                    if !fulltext_field_values.is_empty() {
-                        entity.insert(column.field.to_string(), Value::List(fulltext_field_values));
-                    }
-                }
-                None => (),
-            }
-            if !column.is_nullable() && !entity.contains_key(&column.field) {
-                return Err(StoreError::QueryExecutionError(format!(
-                    "can not insert entity {}[{}] since value for non-nullable attribute {} is missing. \
-                     To fix this, mark the attribute as nullable in the GraphQL schema or change the \
-                     mapping code to always set this attribute.",
-                    key.entity_type, key.entity_id, column.field
-                )));
-            }
-        }
+        let entity = prepare_for_insert(table, key, entity)?;
 
         Ok(InsertQuery {
             table,
@@ -1206,6 +1210,39 @@ impl<'a> InsertQuery<'a> {
     }
 }
 
+/// Add the values for fulltext columns to `entity` and check that it has
+/// values for all non-nullable columns of `table`
+fn prepare_for_insert(
+    table: &Table,
+    key: &EntityKey,
+    mut entity: Entity,
+) -> Result<Entity, StoreError> {
+    for column in table.columns.iter() {
+        match column.fulltext_fields.as_ref() {
+            Some(fields) => {
+                let fulltext_field_values = fields
+                    .iter()
+                    .filter_map(|field| entity.get(field))
+                    .cloned()
+                    .collect::<Vec<Value>>();
+                if !fulltext_field_values.is_empty() {
+                    entity.insert(column.field.to_string(), Value::List(fulltext_field_values));
+                }
+            }
+            None => (),
+        }
+        if !column.is_nullable() && !entity.contains_key(&column.field) {
+            return Err(StoreError::QueryExecutionError(format!(
+                "can not insert entity {}[{}] since value for non-nullable attribute {} is missing. \
+                 To fix this, mark the attribute as nullable in the GraphQL schema or change the \
+                 mapping code to always set this attribute.",
+                key.entity_type, key.entity_id, column.field
+            )));
+        }
+    }
+    Ok(entity)
+}
+
 impl<'a> QueryFragment<Pg> for InsertQuery<'a> {
     fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
         out.unsafe_to_cache_prepared();
@@ -1224,7 +1261,11 @@ impl<'a> QueryFragment<Pg> for InsertQuery<'a> {
                 out.push_sql(", ");
             }
         }
-        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        if self.table.immutable {
+            out.push_identifier(BLOCK_COLUMN)?;
+        } else {
+            out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        }
 
         out.push_sql(")\nvalues(");
         for column in self.table.columns.iter() {
@@ -1233,8 +1274,12 @@ impl<'a> QueryFragment<Pg> for InsertQuery<'a> {
                 out.push_sql(", ");
             }
         }
-        let block_range: BlockRange = (self.block..).into();
-        out.push_bind_param::<Range<Integer>, _>(&block_range)?;
+        if self.table.immutable {
+            out.push_bind_param::<Integer, _>(&self.block)?;
+        } else {
+            let block_range: BlockRange = (self.block..).into();
+            out.push_bind_param::<Range<Integer>, _>(&block_range)?;
+        }
         out.push_sql(")");
         Ok(())
     }
@@ -1248,6 +1293,292 @@ impl<'a> QueryId for InsertQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for InsertQuery<'a> {}
 
+/// Insert many entities into the same table. Postgres limits how many
+/// values a statement can have, and it is much faster to send data with
+/// `COPY` in binary format than as a large `insert` statement. Since Diesel
+/// does not support `COPY`, the entities are copied into a staging table
+/// through a separate connection, and this query then moves them from there
+/// into the entity table. That keeps the insert in the same transaction as
+/// the rest of the block's changes. The entities get the same `block_range`
+/// and `vid` treatment as with `InsertQuery`, so that they can be reverted
+/// and pruned like any other entity version
+///
+/// The staging table is `unlogged` and lives next to the entity table. It
+/// gets a new name every time, since the transaction that writes the block
+/// drops it and keeps a lock on it until it commits. If that transaction
+/// fails, the table is left behind until the deployment is started again;
+/// see `Layout::drop_staging_tables`
+#[derive(Debug, Clone)]
+pub struct BulkInsertQuery<'a> {
+    table: &'a Table,
+    entities: Vec<Entity>,
+    /// The columns for which at least one of the entities has a value
+    columns: Vec<&'a Column>,
+    block: BlockNumber,
+    /// The qualified name of the staging table
+    staging: String,
+}
+
+impl<'a> BulkInsertQuery<'a> {
+    pub fn new(
+        namespace: &str,
+        table: &'a Table,
+        entities: Vec<(&EntityKey, Entity)>,
+        block: BlockNumber,
+    ) -> Result<BulkInsertQuery<'a>, StoreError> {
+        let entities = entities
+            .into_iter()
+            .map(|(key, entity)| prepare_for_insert(table, key, entity))
+            .collect::<Result<Vec<_>, _>>()?;
+        let columns = table
+            .columns
+            .iter()
+            .filter(|column| {
+                entities
+                    .iter()
+                    .any(|entity| entity.contains_key(&column.field))
+            })
+            .collect();
+        let staging = format!(
+            "\"{}\".\"{}{}\"",
+            namespace,
+            STAGING_TABLE_PREFIX,
+            Uuid::new_v4().to_simple()
+        );
+
+        Ok(BulkInsertQuery {
+            table,
+            entities,
+            columns,
+            block,
+            staging,
+        })
+    }
+
+    /// The statement that creates an empty staging table. Values that
+    /// `COPY` can not send directly are staged as text and converted
+    /// when they are moved into the entity table
+    pub fn create_staging_table(&self) -> String {
+        let columns: Vec<_> = self
+            .columns
+            .iter()
+            .map(|column| format!("\"{}\" {}", column.name.as_str(), staging_type(column)))
+            .collect();
+        format!(
+            "create unlogged table {}({})",
+            self.staging,
+            columns.join(", ")
+        )
+    }
+
+    /// The `COPY` statement that loads `copy_data` into the staging table
+    pub fn copy_statement(&self) -> String {
+        let columns: Vec<_> = self
+            .columns
+            .iter()
+            .map(|column| format!("\"{}\"", column.name.as_str()))
+            .collect();
+        format!(
+            "copy {}({}) from stdin (format binary)",
+            self.staging,
+            columns.join(", ")
+        )
+    }
+
+    /// The entities in the binary `COPY` format, one row per entity
+    pub fn copy_data(&self) -> Result<Vec<u8>, StoreError> {
+        let mut writer = BinaryCopyWriter::new();
+        for entity in &self.entities {
+            let fields = self
+                .columns
+                .iter()
+                .map(|column| match entity.get(&column.field) {
+                    Some(value) => copy_field(value, column),
+                    None => Ok(Field::Null),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            writer.write_row(&fields);
+        }
+        Ok(writer.finish())
+    }
+
+    pub fn drop_staging_table(&self) -> String {
+        format!("drop table {}", self.staging)
+    }
+}
+
+/// The names of staging tables for `BulkInsertQuery` start with this
+pub(crate) const STAGING_TABLE_PREFIX: &str = "bulk$";
+
+/// The type of the column in the staging table for `column`
+fn staging_type(column: &Column) -> String {
+    let sql_type = match &column.column_type {
+        ColumnType::Boolean => "boolean",
+        ColumnType::Bytes | ColumnType::BytesId => "bytea",
+        ColumnType::Int => "integer",
+        ColumnType::BigDecimal | ColumnType::BigInt | ColumnType::String | ColumnType::Enum(_) => {
+            "text"
+        }
+        // The values of the fields that make up the fulltext column
+        ColumnType::TSVector(_) => return "text[]".to_owned(),
+    };
+    if column.is_list() {
+        format!("{}[]", sql_type)
+    } else {
+        sql_type.to_owned()
+    }
+}
+
+/// Convert `value` to what we need to send to the staging table for
+/// `column`; see `staging_type`
+fn copy_field(value: &Value, column: &Column) -> Result<Field, StoreError> {
+    fn datum(value: &Value, column: &Column) -> Result<Datum, StoreError> {
+        let datum = match (value, &column.column_type) {
+            (Value::Bool(b), ColumnType::Boolean) => Datum::Bool(*b),
+            (Value::Bytes(b), ColumnType::Bytes) | (Value::Bytes(b), ColumnType::BytesId) => {
+                Datum::Bytea(b.as_slice().to_vec())
+            }
+            (Value::String(s), ColumnType::Bytes) | (Value::String(s), ColumnType::BytesId) => {
+                let bytes = scalar::Bytes::from_str(s)
+                    .map_err(|e| StoreError::Unknown(format_err!("{}", e)))?;
+                Datum::Bytea(bytes.as_slice().to_vec())
+            }
+            (Value::Int(i), ColumnType::Int) => Datum::Int4(*i),
+            (Value::Int(i), ColumnType::BigInt) | (Value::Int(i), ColumnType::BigDecimal) => {
+                Datum::Text(i.to_string())
+            }
+            (Value::BigInt(i), ColumnType::BigInt) | (Value::BigInt(i), ColumnType::BigDecimal) => {
+                Datum::Text(i.to_string())
+            }
+            (Value::BigDecimal(d), ColumnType::BigDecimal)
+            | (Value::BigDecimal(d), ColumnType::BigInt) => Datum::Text(d.to_string()),
+            (Value::String(s), ColumnType::String)
+            | (Value::String(s), ColumnType::Enum(_))
+            | (Value::String(s), ColumnType::TSVector(_)) => Datum::Text(s.clone()),
+            (value, column_type) => {
+                return Err(StoreError::Unknown(format_err!(
+                    "can not store value {} in attribute {} of type {}",
+                    value,
+                    column.field,
+                    column_type.sql_type()
+                )))
+            }
+        };
+        Ok(datum)
+    }
+
+    let copy_type = match &column.column_type {
+        ColumnType::Boolean => CopyType::Bool,
+        ColumnType::Bytes | ColumnType::BytesId => CopyType::Bytea,
+        ColumnType::Int => CopyType::Int4,
+        ColumnType::BigDecimal
+        | ColumnType::BigInt
+        | ColumnType::String
+        | ColumnType::Enum(_)
+        | ColumnType::TSVector(_) => CopyType::Text,
+    };
+    match value {
+        Value::Null => Ok(Field::Null),
+        Value::List(values) => {
+            let elems = values
+                .iter()
+                .map(|value| match value {
+                    Value::Null => Ok(None),
+                    value => datum(value, column).map(Some),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Field::Array(copy_type, elems))
+        }
+        value => Ok(Field::Scalar(datum(value, column)?)),
+    }
+}
+
+impl<'a> QueryFragment<Pg> for BulkInsertQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Construct a query
+        //   insert into schema.table(column, ..., block_range)
+        //   select column, ..., $block_range
+        //     from schema."table$bulk"
+        // converting columns that were staged as text to their actual
+        // type, and computing fulltext columns from their fields
+        out.push_sql("insert into ");
+        out.push_sql(self.table.qualified_name.as_str());
+
+        out.push_sql("(");
+        for column in &self.columns {
+            out.push_identifier(column.name.as_str())?;
+            out.push_sql(", ");
+        }
+        if self.table.immutable {
+            out.push_identifier(BLOCK_COLUMN)?;
+        } else {
+            out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        }
+        out.push_sql(")\nselect ");
+
+        for column in &self.columns {
+            let array = if column.is_list() { "[]" } else { "" };
+            match &column.column_type {
+                ColumnType::BigDecimal | ColumnType::BigInt => {
+                    out.push_identifier(column.name.as_str())?;
+                    out.push_sql("::numeric");
+                    out.push_sql(array);
+                }
+                ColumnType::Enum(enum_type) => {
+                    out.push_identifier(column.name.as_str())?;
+                    out.push_sql("::");
+                    out.push_sql(enum_type.name.as_str());
+                    out.push_sql(array);
+                }
+                ColumnType::TSVector(config) => {
+                    // Same as what `QueryValue` does for a single entity:
+                    // concatenate the vectors for each field
+                    let fields = column.fulltext_fields.as_ref().map_or(0, |f| f.len());
+                    out.push_sql("case when ");
+                    out.push_identifier(column.name.as_str())?;
+                    out.push_sql(" is null then null else ");
+                    for i in 1..=fields {
+                        if i > 1 {
+                            out.push_sql(" || ");
+                        }
+                        out.push_sql("coalesce(to_tsvector(");
+                        out.push_bind_param::<Text, _>(&config.language.as_str())?;
+                        out.push_sql("::regconfig, ");
+                        out.push_identifier(column.name.as_str())?;
+                        out.push_sql(&format!("[{}]), ''::tsvector)", i));
+                    }
+                    out.push_sql(" end");
+                }
+                ColumnType::Boolean
+                | ColumnType::Bytes
+                | ColumnType::BytesId
+                | ColumnType::Int
+                | ColumnType::String => out.push_identifier(column.name.as_str())?,
+            }
+            out.push_sql(", ");
+        }
+        if self.table.immutable {
+            out.push_bind_param::<Integer, _>(&self.block)?;
+        } else {
+            let block_range: BlockRange = (self.block..).into();
+            out.push_bind_param::<Range<Integer>, _>(&block_range)?;
+        }
+        out.push_sql("\n  from ");
+        out.push_sql(&self.staging);
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for BulkInsertQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for BulkInsertQuery<'a> {}
+
 /// Update an existing entity in place. The `entity` only needs to contain
 /// the attributes that should be changed, and not the entire entity. In
 /// particular, it might not have an `id` attribute. If the entity has
@@ -1363,17 +1694,18 @@ impl<'a> QueryId for DeleteQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for DeleteQuery<'a> {}
 
+/// Find which of the tables for `entities` contain any of `entity_ids`
 #[derive(Debug, Clone)]
 pub struct ConflictingEntityQuery<'a> {
     layout: &'a Layout,
     tables: Vec<&'a Table>,
-    entity_id: &'a String,
+    entity_ids: &'a [&'a str],
 }
 impl<'a> ConflictingEntityQuery<'a> {
     pub fn new(
         layout: &'a Layout,
         entities: Vec<&'a String>,
-        entity_id: &'a String,
+        entity_ids: &'a [&'a str],
     ) -> Result<Self, StoreError> {
         let tables = entities
             .iter()
@@ -1382,7 +1714,7 @@ impl<'a> ConflictingEntityQuery<'a> {
         Ok(ConflictingEntityQuery {
             layout,
             tables,
-            entity_id,
+            entity_ids,
         })
     }
 }
@@ -1392,9 +1724,9 @@ impl<'a> QueryFragment<Pg> for ConflictingEntityQuery<'a> {
         out.unsafe_to_cache_prepared();
 
         // Construct a query
-        //   select 'Type1' as entity from schema.table1 where id = $1
+        //   select 'Type1' as entity, id from schema.table1 where id = any($1)
         //   union all
-        //   select 'Type2' as entity from schema.table2 where id = $1
+        //   select 'Type2' as entity, id from schema.table2 where id = any($1)
         //   union all
         //   ...
         for (i, table) in self.tables.iter().enumerate() {
@@ -1403,10 +1735,11 @@ impl<'a> QueryFragment<Pg> for ConflictingEntityQuery<'a> {
             }
             out.push_sql("select ");
             out.push_bind_param::<Text, _>(&table.object)?;
-            out.push_sql(" as entity from ");
+            out.push_sql(" as entity, id from ");
             out.push_sql(table.qualified_name.as_str());
-            out.push_sql(" where id = ");
-            out.push_bind_param::<Text, _>(self.entity_id)?;
+            out.push_sql(" where id = any(");
+            out.push_bind_param::<Array<Text>, _>(&self.entity_ids)?;
+            out.push_sql(")");
         }
         Ok(())
     }
@@ -1422,6 +1755,8 @@ impl<'a> QueryId for ConflictingEntityQuery<'a> {
 pub struct ConflictingEntityData {
     #[sql_type = "Text"]
     pub entity: String,
+    #[sql_type = "Text"]
+    pub id: String,
 }
 
 impl<'a> LoadQuery<PgConnection, ConflictingEntityData> for ConflictingEntityQuery<'a> {
@@ -2488,6 +2823,50 @@ impl<'a> QueryId for ClampRangeQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for ClampRangeQuery<'a> {}
 
+/// Reduce the upper bound of the block range of the current versions of
+/// all entities in `ids` to `block`; this is the same as running
+/// `ClampRangeQuery` for each of them
+#[derive(Debug, Clone, Constructor)]
+pub struct BulkClampRangeQuery<'a> {
+    table: &'a Table,
+    ids: &'a [&'a str],
+    block: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for BulkClampRangeQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        // update table
+        //    set block_range = int4range(lower(block_range), $block)
+        //  where id = any($ids)
+        //    and block_range @> INTMAX
+        out.unsafe_to_cache_prepared();
+        out.push_sql("update ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql("\n   set ");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(" = int4range(lower(");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql("), ");
+        out.push_bind_param::<Integer, _>(&self.block)?;
+        out.push_sql(")\n where ");
+        out.push_sql(PRIMARY_KEY_COLUMN);
+        out.push_sql(" = any(");
+        self.table.primary_key().bind_ids(self.ids, &mut out)?;
+        out.push_sql(") and (");
+        out.push_sql(BLOCK_RANGE_CURRENT);
+        out.push_sql(")");
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for BulkClampRangeQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for BulkClampRangeQuery<'a> {}
+
 /// Helper struct for returning the id's touched by the RevertRemove and
 /// RevertExtend queries
 #[derive(QueryableByName, PartialEq, Eq, Hash)]
@@ -2528,11 +2907,21 @@ impl<'a> QueryFragment<Pg> for RevertRemoveQuery<'a> {
         //   delete from table
         //    where lower(block_range) >= $block
         //   returning id
+        // or, for immutable entities,
+        //   delete from table
+        //    where block$ >= $block
+        //   returning id
         out.push_sql("delete from ");
         out.push_sql(self.table.qualified_name.as_str());
-        out.push_sql("\n where lower(");
-        out.push_identifier(BLOCK_RANGE_COLUMN)?;
-        out.push_sql(") >= ");
+        if self.table.immutable {
+            out.push_sql("\n where ");
+            out.push_identifier(BLOCK_COLUMN)?;
+        } else {
+            out.push_sql("\n where lower(");
+            out.push_identifier(BLOCK_RANGE_COLUMN)?;
+            out.push_sql(")");
+        }
+        out.push_sql(" >= ");
         out.push_bind_param::<Integer, _>(&self.block)?;
         out.push_sql("\nreturning ");
         out.push_sql(PRIMARY_KEY_COLUMN);
@@ -2556,6 +2945,298 @@ impl<'a> LoadQuery<PgConnection, RevertEntityData> for RevertRemoveQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for RevertRemoveQuery<'a> {}
 
+/// A query that removes all versions whose block range ended at or before
+/// `block`, i.e., versions that are not visible at `block` or any later
+/// block, and whose `vid` lies between `first_vid` and `last_vid`
+/// (inclusive).
+#[derive(Debug, Clone, Constructor)]
+pub struct PruneQuery<'a> {
+    table: &'a Table,
+    block: BlockNumber,
+    first_vid: i64,
+    last_vid: i64,
+}
+
+impl<'a> QueryFragment<Pg> for PruneQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Construct a query
+        //   delete from table
+        //    where coalesce(upper(block_range), INTMAX) <= $block
+        //      and vid >= $first_vid and vid <= $last_vid
+        out.push_sql("delete from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql("\n where coalesce(upper(");
+        out.push_sql(BLOCK_RANGE_COLUMN);
+        out.push_sql("), 2147483647) <= ");
+        out.push_bind_param::<Integer, _>(&self.block)?;
+        out.push_sql("\n   and vid >= ");
+        out.push_bind_param::<BigInt, _>(&self.first_vid)?;
+        out.push_sql(" and vid <= ");
+        out.push_bind_param::<BigInt, _>(&self.last_vid)?;
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for PruneQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for PruneQuery<'a> {}
+
+/// The number of entities and entity versions in a table, and the disk
+/// space it takes up; see `TableStatsQuery`
+#[derive(QueryableByName)]
+pub struct TableStats {
+    #[sql_type = "BigInt"]
+    pub entities: i64,
+    #[sql_type = "BigInt"]
+    pub versions: i64,
+    #[sql_type = "BigInt"]
+    pub table_size: i64,
+    #[sql_type = "BigInt"]
+    pub index_size: i64,
+}
+
+/// A query that counts the current entities and all entity versions in
+/// `table` and determines the size of the table and its indexes. Counting
+/// requires a full scan of the table.
+#[derive(Debug, Clone, Constructor)]
+pub struct TableStatsQuery<'a> {
+    table: &'a Table,
+}
+
+impl<'a> QueryFragment<Pg> for TableStatsQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Construct a query
+        //   select count(*) filter (where upper_inf(block_range)) as entities,
+        //          count(*) as versions,
+        //          pg_table_size($table::regclass) as table_size,
+        //          pg_indexes_size($table::regclass) as index_size
+        //     from table
+        // For immutable entities, every version is current, and the
+        // number of entities is simply count(*)
+        if self.table.immutable {
+            out.push_sql("select count(*) as entities,\n");
+        } else {
+            out.push_sql("select count(*) filter (where upper_inf(");
+            out.push_identifier(BLOCK_RANGE_COLUMN)?;
+            out.push_sql(")) as entities,\n");
+        }
+        out.push_sql("       count(*) as versions,\n");
+        out.push_sql("       pg_table_size(");
+        out.push_bind_param::<Text, _>(&self.table.qualified_name.as_str())?;
+        out.push_sql("::regclass) as table_size,\n");
+        out.push_sql("       pg_indexes_size(");
+        out.push_bind_param::<Text, _>(&self.table.qualified_name.as_str())?;
+        out.push_sql("::regclass) as index_size\n");
+        out.push_sql("  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for TableStatsQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, TableStats> for TableStatsQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<TableStats>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for TableStatsQuery<'a> {}
+
+/// Like `TableStatsQuery`, but estimates the number of entities and entity
+/// versions from the statistics Postgres keeps about `table` rather than
+/// counting them. The number of entities is the estimated number of
+/// distinct ids, and therefore includes entities that have been removed.
+/// Tables that have never been analyzed report 0 entities and versions
+#[derive(Debug, Clone, Constructor)]
+pub struct TableStatsEstimateQuery<'a> {
+    namespace: &'a str,
+    table: &'a Table,
+}
+
+impl<'a> QueryFragment<Pg> for TableStatsEstimateQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Construct a query
+        //   select case when s.n_distinct is null then c.reltuples
+        //               when s.n_distinct >= 0 then s.n_distinct
+        //               else -s.n_distinct * c.reltuples end as entities,
+        //          c.reltuples as versions,
+        //          pg_table_size(c.oid) as table_size,
+        //          pg_indexes_size(c.oid) as index_size
+        //     from pg_class c
+        //          left join pg_stats s
+        //            on s.schemaname = $namespace and s.tablename = $name
+        //           and s.attname = 'id'
+        //    where c.oid = $table::regclass
+        // For immutable entities, every version is current, and the
+        // number of entities is the same as the number of versions.
+        // Postgres uses a negative `reltuples` for tables it has never
+        // looked at, and a negative `n_distinct` for a fraction of the
+        // number of rows
+        out.push_sql("select ");
+        if self.table.immutable {
+            out.push_sql("greatest(c.reltuples, 0)::int8 as entities,\n");
+        } else {
+            out.push_sql("(case when s.n_distinct is null then greatest(c.reltuples, 0)\n");
+            out.push_sql("             when s.n_distinct >= 0 then s.n_distinct\n");
+            out.push_sql("             else -s.n_distinct * greatest(c.reltuples, 0) end)");
+            out.push_sql("::int8 as entities,\n");
+        }
+        out.push_sql("       greatest(c.reltuples, 0)::int8 as versions,\n");
+        out.push_sql("       pg_table_size(c.oid) as table_size,\n");
+        out.push_sql("       pg_indexes_size(c.oid) as index_size\n");
+        out.push_sql("  from pg_class c\n");
+        out.push_sql("       left join pg_stats s\n");
+        out.push_sql("         on s.schemaname = ");
+        out.push_bind_param::<Text, _>(&self.namespace)?;
+        out.push_sql(" and s.tablename = ");
+        out.push_bind_param::<Text, _>(&self.table.name.as_str())?;
+        out.push_sql("\n        and s.attname = ");
+        out.push_bind_param::<Text, _>(&PRIMARY_KEY_COLUMN)?;
+        out.push_sql("\n where c.oid = ");
+        out.push_bind_param::<Text, _>(&self.table.qualified_name.as_str())?;
+        out.push_sql("::regclass");
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for TableStatsEstimateQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, TableStats> for TableStatsEstimateQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<TableStats>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for TableStatsEstimateQuery<'a> {}
+
+/// The id of an entity that changed in a block, and whether a version of
+/// it started or ended in that block
+#[derive(QueryableByName)]
+pub struct BlockChangeData {
+    #[sql_type = "Text"]
+    pub id: String,
+    #[sql_type = "Bool"]
+    pub started: bool,
+    #[sql_type = "Bool"]
+    pub ended: bool,
+}
+
+impl BlockChangeData {
+    /// Convert primary key ids from Postgres' internal form to the format we
+    /// use by stripping `\\x` off the front of bytes strings
+    fn bytes_as_str(table: &Table, mut data: Vec<BlockChangeData>) -> Vec<BlockChangeData> {
+        match table.primary_key().column_type.id_type() {
+            IdType::String => data,
+            IdType::Bytes => {
+                for entry in data.iter_mut() {
+                    entry.id = bytes_as_str(&entry.id);
+                }
+                data
+            }
+        }
+    }
+}
+
+/// A query that finds the entities in `table` that were created, updated
+/// or removed in `block`. The conditions on the bounds of the block range
+/// have the same form as the BRIN index on `lower(block_range)`,
+/// `coalesce(upper(block_range), 2147483647)` and `vid`, so that Postgres
+/// can use that index to skip most of the table.
+#[derive(Debug, Clone, Constructor)]
+pub struct BlockChangesQuery<'a> {
+    table: &'a Table,
+    block: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for BlockChangesQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Construct a query
+        //   select id::text as id,
+        //          bool_or(lower(block_range) = $block) as started,
+        //          bool_or(coalesce(upper(block_range), 2147483647) = $block) as ended
+        //     from table
+        //    where lower(block_range) = $block
+        //       or coalesce(upper(block_range), 2147483647) = $block
+        //    group by id
+        // or, for immutable entities, which are never updated or removed,
+        //   select id::text as id, true as started, false as ended
+        //     from table
+        //    where block$ = $block
+        out.push_sql("select ");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql("::text as id,\n");
+        if self.table.immutable {
+            out.push_sql("       true as started, false as ended\n");
+            out.push_sql("  from ");
+            out.push_sql(self.table.qualified_name.as_str());
+            out.push_sql("\n where ");
+            out.push_identifier(BLOCK_COLUMN)?;
+            out.push_sql(" = ");
+            out.push_bind_param::<Integer, _>(&self.block)?;
+            return Ok(());
+        }
+        out.push_sql("       bool_or(lower(");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(") = ");
+        out.push_bind_param::<Integer, _>(&self.block)?;
+        out.push_sql(") as started,\n");
+        out.push_sql("       bool_or(coalesce(upper(");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql("), 2147483647) = ");
+        out.push_bind_param::<Integer, _>(&self.block)?;
+        out.push_sql(") as ended\n");
+        out.push_sql("  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql("\n where lower(");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(") = ");
+        out.push_bind_param::<Integer, _>(&self.block)?;
+        out.push_sql("\n    or coalesce(upper(");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql("), 2147483647) = ");
+        out.push_bind_param::<Integer, _>(&self.block)?;
+        out.push_sql("\n group by ");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for BlockChangesQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, BlockChangeData> for BlockChangesQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<BlockChangeData>> {
+        conn.query_by_name(&self)
+            .map(|data| BlockChangeData::bytes_as_str(&self.table, data))
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for BlockChangesQuery<'a> {}
+
 /// A query that unclamps the block range of all versions that contain
 /// `block` by setting the upper bound of the block range to infinity.
 #[derive(Debug, Clone, Constructor)]
@@ -2629,7 +3310,8 @@ impl<'a, Conn> RunQueryDsl<Conn> for RevertClampQuery<'a> {}
 
 #[test]
 fn block_number_max_is_i32_max() {
-    // The code in RevertClampQuery::walk_ast embeds i32::MAX
+    // The code in RevertClampQuery::walk_ast, PruneQuery::walk_ast and
+    // BlockChangesQuery::walk_ast embeds i32::MAX
     // aka BLOCK_NUMBER_MAX in strings for efficiency. This assertion
     // makes sure that BLOCK_NUMBER_MAX still is what we think it is
     assert_eq!(2147483647, graph::prelude::BLOCK_NUMBER_MAX);
@@ -2727,7 +3409,8 @@ impl<'a> LoadQuery<PgConnection, RevertEntityData> for DeleteByPrefixQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for DeleteByPrefixQuery<'a> {}
 
-/// Copy the data of one table to another table
+/// Copy the rows of one table whose `vid` lies between `first_vid` and
+/// `last_vid` (inclusive) to another table
 #[derive(Debug, Clone)]
 pub struct CopyEntityDataQuery<'a> {
     src: &'a Table,
@@ -2735,10 +3418,27 @@ pub struct CopyEntityDataQuery<'a> {
     // A list of columns common between src and dst that
     // need to be copied
     columns: Vec<&'a Column>,
+    first_vid: i64,
+    last_vid: i64,
+    // Only copy rows that were created after this block
+    after_block: Option<BlockNumber>,
 }
 
 impl<'a> CopyEntityDataQuery<'a> {
-    pub fn new(dst: &'a Table, src: &'a Table) -> Result<Self, StoreError> {
+    pub fn new(
+        dst: &'a Table,
+        src: &'a Table,
+        first_vid: i64,
+        last_vid: i64,
+    ) -> Result<Self, StoreError> {
+        if dst.immutable != src.immutable {
+            return Err(format_err!(
+                "The entity type {} is immutable in only one of the source \
+                 and the destination",
+                dst.object
+            )
+            .into());
+        }
         let mut columns = Vec::new();
         for dcol in &dst.columns {
             if let Some(scol) = src.column(&dcol.name) {
@@ -2760,7 +3460,25 @@ impl<'a> CopyEntityDataQuery<'a> {
             }
         }
 
-        Ok(Self { src, dst, columns })
+        Ok(Self {
+            src,
+            dst,
+            columns,
+            first_vid,
+            last_vid,
+            after_block: None,
+        })
+    }
+
+    /// Copy all rows of `src` that were created after `block` into `dst`
+    pub fn created_after(
+        dst: &'a Table,
+        src: &'a Table,
+        block: BlockNumber,
+    ) -> Result<Self, StoreError> {
+        let mut query = Self::new(dst, src, 0, std::i64::MAX)?;
+        query.after_block = Some(block);
+        Ok(query)
     }
 }
 
@@ -2771,6 +3489,7 @@ impl<'a> QueryFragment<Pg> for CopyEntityDataQuery<'a> {
         // Construct a query
         //   insert into {dst}({columns})
         //   select {columns} from {src}
+        //    where vid >= {first_vid} and vid <= {last_vid}
         out.push_sql("insert into ");
         out.push_sql(self.dst.qualified_name.as_str());
         out.push_sql("(");
@@ -2778,7 +3497,13 @@ impl<'a> QueryFragment<Pg> for CopyEntityDataQuery<'a> {
             out.push_identifier(column.name.as_str())?;
             out.push_sql(", ");
         }
-        out.push_sql("block_range)");
+        let block_column = if self.dst.immutable {
+            BLOCK_COLUMN
+        } else {
+            BLOCK_RANGE_COLUMN
+        };
+        out.push_identifier(block_column)?;
+        out.push_sql(")");
         out.push_sql("\nselect ");
         for column in &self.columns {
             out.push_identifier(column.name.as_str())?;
@@ -2789,8 +3514,25 @@ impl<'a> QueryFragment<Pg> for CopyEntityDataQuery<'a> {
             }
             out.push_sql(", ");
         }
-        out.push_sql("block_range from ");
+        out.push_identifier(block_column)?;
+        out.push_sql(" from ");
         out.push_sql(self.src.qualified_name.as_str());
+        out.push_sql("\n where vid >= ");
+        out.push_bind_param::<BigInt, _>(&self.first_vid)?;
+        out.push_sql(" and vid <= ");
+        out.push_bind_param::<BigInt, _>(&self.last_vid)?;
+        if let Some(block) = &self.after_block {
+            if self.src.immutable {
+                out.push_sql(" and ");
+                out.push_identifier(BLOCK_COLUMN)?;
+            } else {
+                out.push_sql(" and lower(");
+                out.push_identifier(BLOCK_RANGE_COLUMN)?;
+                out.push_sql(")");
+            }
+            out.push_sql(" > ");
+            out.push_bind_param::<Integer, _>(block)?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,186 @@
+//! Support for storing the data of deployments in several databases
+//! (shards). All metadata, including the table `deployment_schemas` that
+//! records which shard holds the data of a deployment, is kept in the
+//! `primary` shard, which is the database given with `--postgres-url`.
+//!
+//! Data is copied between shards with `postgres_fdw`: the shard that
+//! receives a deployment makes the tables of the deployment in its
+//! current shard available as foreign tables in a schema `fdw_<name>`
+//!
+//! Changes to a deployment that is not stored in the primary are committed
+//! with a two-phase commit, and all shards other than the primary must
+//! therefore set `max_prepared_transactions` to at least the size of their
+//! connection pool
+use diesel::connection::SimpleConnection;
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use regex::Regex;
+use std::str::FromStr;
+
+use graph::prelude::{format_err, Error, StoreError};
+use graph::url::Url;
+
+use crate::relational::{Layout, SqlName};
+
+/// The name of the shard that holds all metadata
+pub const PRIMARY_SHARD: &str = "primary";
+
+/// A database in which we can store the data of deployments
+#[derive(Clone)]
+pub struct Shard {
+    pub name: String,
+    /// The URL that other shards use to connect to this shard
+    pub postgres_url: String,
+    pub pool: Pool<ConnectionManager<PgConnection>>,
+}
+
+impl Shard {
+    pub fn new(
+        name: String,
+        postgres_url: String,
+        pool: Pool<ConnectionManager<PgConnection>>,
+    ) -> Result<Self, Error> {
+        SqlName::check_valid_identifier(&name, "shard")?;
+        Ok(Shard {
+            name,
+            postgres_url,
+            pool,
+        })
+    }
+}
+
+/// A rule that places new deployments whose subgraph name matches `name`
+/// into `shard`. Rules are written as `SHARD=REGEX`
+#[derive(Clone, Debug)]
+pub struct PlacementRule {
+    pub shard: String,
+    pub name: Regex,
+}
+
+impl FromStr for PlacementRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut parts = s.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(shard), Some(name)) if !shard.is_empty() => {
+                let name = Regex::new(name).map_err(|e| {
+                    format_err!("invalid subgraph name pattern in rule `{}`: {}", s, e)
+                })?;
+                Ok(PlacementRule {
+                    shard: shard.to_owned(),
+                    name,
+                })
+            }
+            _ => Err(format_err!(
+                "invalid placement rule `{}`, rules must have the form SHARD=REGEX",
+                s
+            )),
+        }
+    }
+}
+
+/// Find the shard for a new deployment that is deployed under the
+/// subgraph names `names`. The first rule that matches any of the names
+/// wins; if no rule matches, the deployment goes into the primary
+pub fn place<'a>(rules: &'a [PlacementRule], names: &[String]) -> &'a str {
+    rules
+        .iter()
+        .find(|rule| names.iter().any(|name| rule.name.is_match(name)))
+        .map(|rule| rule.shard.as_str())
+        .unwrap_or(PRIMARY_SHARD)
+}
+
+/// The name of the foreign server through which other shards access `shard`
+pub(crate) fn server_name(shard: &str) -> String {
+    format!("shard_{}", shard)
+}
+
+/// The name of the schema that holds the foreign tables for the
+/// deployment schema `namespace` in another shard
+pub(crate) fn foreign_schema_name(namespace: &str) -> String {
+    format!("fdw_{}", namespace)
+}
+
+fn quote(s: &str) -> String {
+    s.replace("'", "''")
+}
+
+/// Make `src` accessible from the database behind `conn` as the foreign
+/// server `shard_<name>`, unless that server already exists
+///
+/// If the URL of `src` contains a password, the user mapping for the
+/// foreign server stores it in plain text in the `pg_user_mapping` catalog
+/// of the database behind `conn`, where superusers of that database can
+/// read it. To avoid that, leave the password out of the URL; the
+/// database server behind `conn` then needs to be able to connect to `src`
+/// without one, for example, with a `.pgpass` file in the home directory
+/// of the operating system user that runs it. Postgres only allows that
+/// when the user that `conn` connects as is a superuser
+pub(crate) fn create_foreign_server(conn: &PgConnection, src: &Shard) -> Result<(), StoreError> {
+    let url = Url::parse(&src.postgres_url)
+        .map_err(|e| format_err!("invalid URL for shard {}: {}", src.name, e))?;
+    let dbname = url.path().trim_start_matches('/');
+    let password = url
+        .password()
+        .map(|password| format!(", password '{}'", quote(password)))
+        .unwrap_or_default();
+    let query = format!(
+        "create extension if not exists postgres_fdw;
+         create server if not exists {server}
+                foreign data wrapper postgres_fdw
+                options (host '{host}', port '{port}', dbname '{dbname}');
+         create user mapping if not exists for current_user
+                server {server}
+                options (user '{user}'{password});",
+        server = server_name(&src.name),
+        host = quote(url.host_str().unwrap_or("localhost")),
+        port = url.port().unwrap_or(5432),
+        dbname = quote(dbname),
+        user = quote(url.username()),
+        password = password
+    );
+    Ok(conn.batch_execute(&query)?)
+}
+
+/// Create the foreign tables for `layout`, whose database schema must be
+/// a schema created with `foreign_schema_name` for the deployment schema
+/// `remote` in `src`
+pub(crate) fn create_foreign_tables(
+    conn: &PgConnection,
+    src: &Shard,
+    layout: &Layout,
+    remote: &str,
+) -> Result<(), StoreError> {
+    let ddl = layout
+        .as_foreign_ddl(&server_name(&src.name), remote)
+        .map_err(|_| StoreError::Unknown(format_err!("failed to generate foreign DDL")))?;
+    let query = format!(
+        "drop schema if exists {schema} cascade;
+         create schema {schema};
+         {ddl}",
+        schema = layout.catalog.schema,
+        ddl = ddl
+    );
+    Ok(conn.batch_execute(&query)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placement() {
+        let rules = vec!["big=^uniswap/".parse::<PlacementRule>().unwrap()];
+        let names = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!("big", place(&rules, &names(&["uniswap/v2"])));
+        assert_eq!("big", place(&rules, &names(&["other", "uniswap/v1"])));
+        assert_eq!(PRIMARY_SHARD, place(&rules, &names(&["other/uniswap"])));
+        assert_eq!(PRIMARY_SHARD, place(&rules, &names(&[])));
+
+        assert!("big".parse::<PlacementRule>().is_err());
+        assert!("=.*".parse::<PlacementRule>().is_err());
+        assert!("big=(".parse::<PlacementRule>().is_err());
+    }
+}
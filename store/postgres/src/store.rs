@@ -7,42 +7,50 @@ use futures03::FutureExt as _;
 use graph::prelude::{CancelGuard, CancelHandle, CancelToken, CancelableError};
 use lazy_static::lazy_static;
 use lru_time_cache::LruCache;
+use maybe_owned::MaybeOwned;
 use rand::{seq::SliceRandom, thread_rng};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
 use std::iter::FromIterator;
 use std::ops::Deref;
 use std::sync::{atomic::AtomicUsize, Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::sync::Semaphore;
 
-use graph::components::store::{EntityCollection, QueryStore, Store as StoreTrait};
+use graph::components::store::{
+    CopyStatus, EntityCollection, EntityTypeChanges, EntityTypeStats, QueryStore,
+    Store as StoreTrait,
+};
 use graph::components::subgraph::ProofOfIndexingFinisher;
 use graph::data::subgraph::schema::{
-    SubgraphDeploymentEntity, TypedEntity as _, POI_OBJECT, SUBGRAPHS_ID,
+    SubgraphDeploymentEntity, SubgraphManifestEntity, TypedEntity as _, POI_OBJECT, SUBGRAPHS_ID,
 };
 use graph::prelude::{
-    debug, ethabi, format_err, futures03, info, o, serde_json, tiny_keccak, tokio, trace, warn,
-    web3, ApiSchema, AttributeIndexDefinition, BigInt, BlockNumber, ChainHeadUpdateListener as _,
-    ChainHeadUpdateStream, ChainStore, CheapClone, DynTryFuture, Entity, EntityKey,
+    debug, ethabi, format_err, futures03, info, o, serde_json, tiny_keccak, tokio, trace, web3,
+    ApiSchema, BigInt, BlockNumber, ChainHeadUpdateListener as _, ChainHeadUpdateStream,
+    ChainStore, CheapClone, DynTryFuture, Entity, EntityChange, EntityChangeOperation, EntityKey,
     EntityModification, EntityOrder, EntityQuery, EntityRange, Error, EthereumBlock,
-    EthereumBlockPointer, EthereumCallCache, EthereumNetworkIdentifier, Future, LightEthereumBlock,
-    Logger, MetadataOperation, MetricsRegistry, QueryExecutionError, Schema, StopwatchMetrics,
-    StoreError, StoreEvent, StoreEventStreamBox, Stream, SubgraphAssignmentProviderError,
-    SubgraphDeploymentId, SubgraphDeploymentStore, SubgraphEntityPair, TransactionAbortError,
-    Value, BLOCK_NUMBER_MAX,
+    EthereumBlockPointer, EthereumCallCache, EthereumNetworkIdentifier, Future, Future01CompatExt,
+    LightEthereumBlock, Logger, MetadataOperation, MetricsRegistry, QueryExecutionError, Schema,
+    StopwatchMetrics, StoreError, StoreEvent, StoreEventStreamBox, Stream, SubgraphDeploymentId,
+    SubgraphDeploymentStore, SubgraphEntityPair, TransactionAbortError, Value, BLOCK_NUMBER_MAX,
 };
 
 use graph_graphql::prelude::api_schema;
 use web3::types::{Address, H256};
 
+use crate::catalog::{self, Catalog};
 use crate::chain_head_listener::ChainHeadUpdateListener;
+use crate::copy;
 use crate::entities as e;
 use crate::functions::{attempt_chain_head_update, lookup_ancestor_block};
 use crate::history_event::HistoryEvent;
 use crate::metadata;
+use crate::relational::Layout;
 use crate::relational_queries::FromEntityData;
+use crate::sharding::{self, PlacementRule, Shard, PRIMARY_SHARD};
 use crate::store_events::SubscriptionManager;
+use crate::write_queue::WriteQueue;
 
 lazy_static! {
     static ref CONNECTION_LIMITER: Semaphore = {
@@ -53,6 +61,58 @@ lazy_static! {
 
         Semaphore::new(db_conn_pool_size)
     };
+
+    /// Blocks with at least this many entity modifications are written
+    /// with one statement per entity type and kind of modification rather
+    /// than one statement per modification. Setting this to 0 turns bulk
+    /// writes off
+    static ref BULK_WRITE_THRESHOLD: Option<usize> = {
+        let threshold = std::env::var("GRAPH_STORE_BULK_WRITE_THRESHOLD")
+            .unwrap_or("10000".into())
+            .parse::<usize>()
+            .expect("invalid GRAPH_STORE_BULK_WRITE_THRESHOLD");
+        if threshold == 0 {
+            None
+        } else {
+            Some(threshold)
+        }
+    };
+
+    /// The number of processed blocks per deployment that may wait to be
+    /// written while the next block is processed. Setting this to 0 turns
+    /// writing in the background off
+    static ref WRITE_QUEUE_DEPTH: Option<usize> = {
+        let depth = std::env::var("GRAPH_STORE_WRITE_QUEUE")
+            .unwrap_or("0".into())
+            .parse::<usize>()
+            .expect("invalid GRAPH_STORE_WRITE_QUEUE");
+        if depth == 0 {
+            None
+        } else {
+            Some(depth)
+        }
+    };
+
+    /// How long, in seconds, we use the earliest block of a deployment
+    /// that we cached before reading it from the database again. Pruning
+    /// that other nodes do only becomes visible to queries on this node
+    /// after that time
+    static ref EARLIEST_BLOCK_TTL: Duration = {
+        let secs = std::env::var("GRAPH_STORE_EARLIEST_BLOCK_TTL")
+            .unwrap_or("10".into())
+            .parse::<u64>()
+            .expect("invalid GRAPH_STORE_EARLIEST_BLOCK_TTL");
+        Duration::from_secs(secs)
+    };
+}
+
+/// The modifications of one entity type in a block, grouped for bulk
+/// writing
+#[derive(Default)]
+struct BulkWrites {
+    inserts: Vec<(EntityKey, Entity)>,
+    overwrites: Vec<(EntityKey, Entity)>,
+    removes: Vec<String>,
 }
 
 embed_migrations!("./migrations");
@@ -128,6 +188,8 @@ fn initiate_schema(logger: &Logger, conn: &PgConnection, blocking_conn: &PgConne
 pub struct StoreConfig {
     pub postgres_url: String,
     pub network_name: String,
+    /// Rules that determine the shard for new deployments
+    pub placement: Vec<PlacementRule>,
 }
 
 /// When connected to read replicas, this allows choosing which DB server to use for an operation.
@@ -142,7 +204,9 @@ pub enum ReplicaId {
 
 /// Commonly needed information about a subgraph that we cache in
 /// `Store.subgraph_cache`. Only immutable subgraph data can be cached this
-/// way as the cache lives for the lifetime of the `Store` object
+/// way as the cache lives for the lifetime of the `Store` object, with the
+/// exception of `earliest_block` which we refresh periodically, and the
+/// schemas, which `handle_schema_changes` evicts when they are changed
 #[derive(Clone)]
 struct SubgraphInfo {
     /// The schema as supplied by the user
@@ -154,6 +218,9 @@ struct SubgraphInfo {
     /// The block number at which this subgraph was grafted onto
     /// another one. We do not allow reverting past this block
     graft_block: Option<BlockNumber>,
+    /// The earliest block for which the subgraph still has history, and
+    /// when we read it from the database
+    earliest_block: Arc<Mutex<(BlockNumber, Instant)>>,
 }
 
 pub struct StoreInner {
@@ -168,13 +235,27 @@ pub struct StoreInner {
     replica_order: Vec<ReplicaId>,
     conn_round_robin_counter: AtomicUsize,
 
+    /// All shards, including the primary, by name
+    shards: HashMap<String, Shard>,
+    placement: Vec<PlacementRule>,
+
     /// A cache of commonly needed data about a subgraph.
     subgraph_cache: Mutex<LruCache<SubgraphDeploymentId, SubgraphInfo>>,
 
-    /// A cache for the storage metadata for subgraphs. The Store just
+    /// A cache for the layout metadata for subgraphs. The Store just
     /// hosts this because it lives long enough, but it is managed from
     /// the entities module
-    pub(crate) storage_cache: e::StorageCache,
+    pub(crate) layout_cache: e::LayoutCache,
+
+    /// The blocks waiting to be written for each deployment when blocks
+    /// are written in the background
+    write_queues: Mutex<HashMap<SubgraphDeploymentId, Arc<WriteQueue>>>,
+
+    /// The block pointer each deployment that is indexing on this node had
+    /// after we last wrote or reverted a block for it. If the block pointer
+    /// in the database differs from it when we write the next block,
+    /// somebody else changed it, and the block must not be written
+    block_ptrs: Mutex<HashMap<SubgraphDeploymentId, EthereumBlockPointer>>,
 
     registry: Arc<dyn MetricsRegistry>,
 }
@@ -202,6 +283,7 @@ impl Store {
         pool: Pool<ConnectionManager<PgConnection>>,
         read_only_pools: Vec<Pool<ConnectionManager<PgConnection>>>,
         mut pool_weights: Vec<usize>,
+        shards: Vec<Shard>,
         registry: Arc<dyn MetricsRegistry>,
     ) -> Self {
         // Create a store-specific logger
@@ -210,6 +292,37 @@ impl Store {
         // Create the entities table (if necessary)
         initiate_schema(&logger, &pool.get().unwrap(), &pool.get().unwrap());
 
+        // All shards have the same database layout, even though only the
+        // primary uses the tables for metadata
+        for shard in &shards {
+            let logger = logger.new(o!("shard" => shard.name.clone()));
+            initiate_schema(
+                &logger,
+                &shard.pool.get().unwrap(),
+                &shard.pool.get().unwrap(),
+            );
+        }
+        let primary = Shard::new(
+            PRIMARY_SHARD.to_owned(),
+            config.postgres_url.clone(),
+            pool.clone(),
+        )
+        .expect("the name of the primary shard is valid");
+        let shards: HashMap<_, _> = shards
+            .into_iter()
+            .chain(std::iter::once(primary))
+            .map(|shard| (shard.name.clone(), shard))
+            .collect();
+        for rule in &config.placement {
+            if !shards.contains_key(&rule.shard) {
+                panic!(
+                    "The placement rule for shard `{}` refers to a shard that \
+                     is not configured",
+                    rule.shard
+                );
+            }
+        }
+
         // Create a list of replicas with repetitions according to the weights
         // and shuffle the resulting list. Any missing weights in the list
         // default to 1
@@ -242,8 +355,12 @@ impl Store {
             read_only_pools,
             replica_order,
             conn_round_robin_counter: AtomicUsize::new(0),
+            shards,
+            placement: config.placement,
             subgraph_cache: Mutex::new(LruCache::with_capacity(100)),
-            storage_cache: e::make_storage_cache(),
+            layout_cache: e::make_layout_cache(),
+            write_queues: Mutex::new(HashMap::new()),
+            block_ptrs: Mutex::new(HashMap::new()),
             registry,
         };
         let store = Store(Arc::new(store));
@@ -251,10 +368,36 @@ impl Store {
         // Add network to store and check network identifiers
         store.add_network_if_missing(net_identifiers).unwrap();
 
+        store.handle_schema_changes();
+
         // Return the store
         store
     }
 
+    /// Forget what we cached about deployments whose schema changed, which
+    /// might have happened on another node, so that we load their layout
+    /// and schemas from the database again the next time we need them
+    fn handle_schema_changes(&self) {
+        let store = Arc::downgrade(&self.0);
+        graph::spawn(
+            self.subscriptions
+                .subscribe(vec![SubgraphManifestEntity::subgraph_entity_pair()])
+                .for_each(move |event| -> Result<(), ()> {
+                    // Stop listening once the store is gone
+                    let store = store.upgrade().ok_or(())?;
+                    for change in &event.changes {
+                        let id = change.entity_id.trim_end_matches("-manifest");
+                        if let Ok(id) = SubgraphDeploymentId::new(id) {
+                            store.layout_cache.lock().unwrap().remove(&id);
+                            store.subgraph_cache.lock().unwrap().remove(&id);
+                        }
+                    }
+                    Ok(())
+                })
+                .compat(),
+        );
+    }
+
     fn add_network_if_missing(
         &self,
         new_net_identifiers: EthereumNetworkIdentifier,
@@ -378,22 +521,12 @@ impl Store {
         // if that's Fred the Dog, Fred the Cat or both.
         //
         // This assumes that there are no concurrent writes to a subgraph.
-        let schema = self.api_schema(&key.subgraph_id)?;
-        let types_for_interface = schema.types_for_interface();
-        let types_with_shared_interface = Vec::from_iter(
-            schema
-                .interfaces_for_type(&key.entity_type)
-                .into_iter()
-                .flatten()
-                .map(|interface| &types_for_interface[&interface.name])
-                .flatten()
-                .map(|object_type| &object_type.name)
-                .filter(|type_name| **type_name != key.entity_type),
-        );
+        let types_with_shared_interface =
+            self.types_with_shared_interface(&key.subgraph_id, &key.entity_type)?;
 
         if !types_with_shared_interface.is_empty() {
-            if let Some(conflicting_entity) =
-                conn.conflicting_entity(&key.entity_id, types_with_shared_interface)?
+            if let Some(conflicting_entity) = conn
+                .conflicting_entity(&key.entity_id, types_with_shared_interface.iter().collect())?
             {
                 return Err(StoreError::ConflictingId(
                     key.entity_type.clone(),
@@ -537,6 +670,27 @@ impl Store {
         }
     }
 
+    /// The entity types other than `entity_type` that implement one of the
+    /// interfaces that `entity_type` implements
+    fn types_with_shared_interface(
+        &self,
+        subgraph: &SubgraphDeploymentId,
+        entity_type: &str,
+    ) -> Result<Vec<String>, StoreError> {
+        let schema = self.api_schema(subgraph)?;
+        let types_for_interface = schema.types_for_interface();
+        Ok(schema
+            .interfaces_for_type(&entity_type.to_owned())
+            .into_iter()
+            .flatten()
+            .map(|interface| &types_for_interface[&interface.name])
+            .flatten()
+            .map(|object_type| &object_type.name)
+            .filter(|type_name| type_name.as_str() != entity_type)
+            .cloned()
+            .collect())
+    }
+
     fn apply_entity_modifications(
         &self,
         conn: &e::Connection,
@@ -544,6 +698,143 @@ impl Store {
         history_event: Option<&HistoryEvent>,
         stopwatch: StopwatchMetrics,
     ) -> Result<(), StoreError> {
+        let count = match (history_event, *BULK_WRITE_THRESHOLD) {
+            (Some(history_event), Some(threshold)) if mods.len() >= threshold => {
+                self.apply_entity_modifications_in_bulk(conn, mods, history_event, &stopwatch)?
+            }
+            _ => {
+                self.apply_entity_modifications_one_by_one(conn, mods, history_event, &stopwatch)?
+            }
+        };
+        conn.update_entity_count(count)
+    }
+
+    /// Apply `mods` with one statement per entity type and kind of
+    /// modification. This leaves the store in the same state as
+    /// `apply_entity_modifications_one_by_one`, but avoids a round trip
+    /// to the database for each modification for blocks that change lots
+    /// of entities. Returns by how much the number of entities changed
+    fn apply_entity_modifications_in_bulk(
+        &self,
+        conn: &e::Connection,
+        mods: Vec<EntityModification>,
+        history_event: &HistoryEvent,
+        stopwatch: &StopwatchMetrics,
+    ) -> Result<i32, StoreError> {
+        // There is never much metadata in a block, and it lives in a
+        // different layout from the subgraph's entities
+        let (meta_mods, mods): (Vec<_>, Vec<_>) = mods
+            .into_iter()
+            .partition(|modification| modification.entity_key().subgraph_id.is_meta());
+        self.apply_entity_modifications_one_by_one(
+            conn,
+            meta_mods,
+            Some(history_event),
+            stopwatch,
+        )?;
+
+        let subgraph = history_event.subgraph.clone();
+        let mut writes: BTreeMap<String, BulkWrites> = BTreeMap::new();
+        for modification in mods {
+            use EntityModification::*;
+
+            let writes = writes
+                .entry(modification.entity_key().entity_type.clone())
+                .or_default();
+            match modification {
+                Insert { key, data } => writes.inserts.push((key, data)),
+                Overwrite { key, data } => writes.overwrites.push((key, data)),
+                Remove { key } => writes.removes.push(key.entity_id),
+            }
+        }
+
+        let _section = stopwatch.start_section("apply_entity_modifications_bulk");
+        let mut count: i32 = 0;
+
+        // Removing entities first makes their ids available for entities
+        // of other types that share an interface with them
+        for (entity_type, writes) in &writes {
+            if !writes.removes.is_empty() {
+                let ids: Vec<_> = writes.removes.iter().map(|id| id.as_str()).collect();
+                count -= conn.delete_many(entity_type, &ids, history_event)? as i32;
+            }
+        }
+
+        // Check that entities that share an interface have different ids,
+        // both among the entities in this block, and between the block
+        // and what is already in the store
+        let written: HashMap<&str, HashSet<&str>> = writes
+            .iter()
+            .map(|(entity_type, writes)| {
+                let ids = writes
+                    .inserts
+                    .iter()
+                    .chain(writes.overwrites.iter())
+                    .map(|(key, _)| key.entity_id.as_str())
+                    .collect();
+                (entity_type.as_str(), ids)
+            })
+            .collect();
+        for (entity_type, type_writes) in &writes {
+            let shared = self.types_with_shared_interface(&subgraph, entity_type)?;
+            if shared.is_empty() {
+                continue;
+            }
+            let ids: Vec<_> = type_writes
+                .inserts
+                .iter()
+                .chain(type_writes.overwrites.iter())
+                .map(|(key, _)| key.entity_id.as_str())
+                .collect();
+            for id in &ids {
+                let in_block = shared.iter().find(|other| {
+                    written
+                        .get(other.as_str())
+                        .map_or(false, |ids| ids.contains(id))
+                });
+                if let Some(other) = in_block {
+                    return Err(StoreError::ConflictingId(
+                        entity_type.clone(),
+                        id.to_string(),
+                        other.clone(),
+                    ));
+                }
+            }
+            // Check all ids against what is already in the store with one
+            // query per entity type
+            let conflicts = conn.conflicting_entities(&ids, shared.iter().collect())?;
+            if let Some((other, id)) = conflicts.into_iter().next() {
+                return Err(StoreError::ConflictingId(entity_type.clone(), id, other));
+            }
+        }
+
+        drop(written);
+
+        for (entity_type, writes) in writes {
+            if !writes.overwrites.is_empty() {
+                let (keys, data): (Vec<_>, Vec<_>) = writes.overwrites.into_iter().unzip();
+                let overwrites = keys.iter().zip(data).collect();
+                conn.update_many(&entity_type, overwrites, history_event)?;
+            }
+            if !writes.inserts.is_empty() {
+                count += writes.inserts.len() as i32;
+                let (keys, data): (Vec<_>, Vec<_>) = writes.inserts.into_iter().unzip();
+                let inserts = keys.iter().zip(data).collect();
+                conn.insert_many(&entity_type, inserts, history_event)?;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Apply `mods` one statement at a time. Returns by how much the number
+    /// of entities changed
+    fn apply_entity_modifications_one_by_one(
+        &self,
+        conn: &e::Connection,
+        mods: Vec<EntityModification>,
+        history_event: Option<&HistoryEvent>,
+        stopwatch: &StopwatchMetrics,
+    ) -> Result<i32, StoreError> {
         let mut count = 0;
 
         for modification in mods {
@@ -586,8 +877,7 @@ impl Store {
                 count += n;
             }
         }
-        conn.update_entity_count(count)?;
-        Ok(())
+        Ok(count)
     }
 
     fn apply_metadata_operations_with_conn(
@@ -605,36 +895,6 @@ impl Store {
         Ok(event)
     }
 
-    /// Build a partial Postgres index on a Subgraph-Entity-Attribute
-    fn build_entity_attribute_index_with_conn(
-        &self,
-        conn: &e::Connection,
-        index: AttributeIndexDefinition,
-    ) -> Result<(), SubgraphAssignmentProviderError> {
-        conn.build_attribute_index(&index)
-            .map_err(|e| SubgraphAssignmentProviderError::Unknown(e.into()))
-            .and_then(move |row_count| match row_count {
-                1 => Ok(()),
-                _ => Err(SubgraphAssignmentProviderError::BuildIndexesError(
-                    index.subgraph_id.to_string(),
-                    index.entity_name.clone(),
-                    index.attribute_name.clone(),
-                )),
-            })
-    }
-
-    /// Build a set of indexes on the entities table
-    fn build_entity_attribute_indexes_with_conn(
-        &self,
-        conn: &e::Connection,
-        indexes: Vec<AttributeIndexDefinition>,
-    ) -> Result<(), SubgraphAssignmentProviderError> {
-        for index in indexes.into_iter() {
-            self.build_entity_attribute_index_with_conn(conn, index)?;
-        }
-        Ok(())
-    }
-
     /// Execute a closure with a connection to the database.
     ///
     /// # API
@@ -753,15 +1013,8 @@ impl Store {
                 .inc_by(start.elapsed().as_secs_f64());
 
             cancel_handle.check_cancel()?;
-            let storage = store
-                .storage(&conn, &subgraph)
-                .map_err(Into::<Error>::into)?;
-            cancel_handle.check_cancel()?;
-            let metadata = store
-                .storage(&conn, &*SUBGRAPHS_ID)
-                .map_err(Into::<Error>::into)?;
+            let conn = store.entity_conn(conn.into(), &subgraph)?;
             cancel_handle.check_cancel()?;
-            let conn = e::Connection::new(conn.into(), storage, metadata);
 
             f(&conn, cancel_handle)
         })
@@ -801,35 +1054,100 @@ impl Store {
                 subgraph.as_str(),
             )?
             .inc_by(start.elapsed().as_secs_f64());
-        let storage = self.storage(&conn, subgraph)?;
-        let metadata = self.storage(&conn, &*SUBGRAPHS_ID)?;
-        Ok(e::Connection::new(conn.into(), storage, metadata))
-    }
-
-    /// Return the storage for the subgraph. Since constructing a `Storage`
-    /// object takes a bit of computation, we cache storage objects that do
-    /// not have a pending migration in the Store, i.e., for the lifetime of
-    /// the Store. Storage objects with a pending migration can not be
-    /// cached for longer than a transaction since they might change
-    /// without us knowing
-    fn storage(
+        self.entity_conn(conn.into(), subgraph)
+    }
+
+    /// Turn `conn`, a connection to the primary shard or one of its read
+    /// replicas, into an entity connection for `subgraph`. If the entities
+    /// of `subgraph` are stored in another shard, they are accessed
+    /// through a new connection to that shard, and `conn` is only used
+    /// for metadata. Shards other than the primary do not have read
+    /// replicas
+    fn entity_conn<'a>(
         &self,
-        conn: &PgConnection,
+        conn: MaybeOwned<'a, PooledConnection<ConnectionManager<PgConnection>>>,
         subgraph: &SubgraphDeploymentId,
-    ) -> Result<Arc<e::Storage>, StoreError> {
-        if let Some(storage) = self.storage_cache.lock().unwrap().get(subgraph) {
-            return Ok(storage.clone());
+    ) -> Result<e::Connection<'a>, Error> {
+        let metadata = self.layout(&conn, &conn, &*SUBGRAPHS_ID, None)?;
+        let primary_url = self.shard(PRIMARY_SHARD)?.postgres_url.clone();
+        if self.shards.len() == 1 {
+            // Everything is stored in the primary
+            let layout = self.layout(&conn, &conn, subgraph, None)?;
+            return Ok(e::Connection::new(
+                conn,
+                None,
+                layout,
+                metadata,
+                primary_url,
+            ));
         }
 
-        let storage = Arc::new(e::Storage::new(conn, subgraph)?);
-        if storage.is_cacheable() {
-            &self
-                .storage_cache
-                .lock()
-                .unwrap()
-                .insert(subgraph.clone(), storage.clone());
+        // The deployment might have been moved to another shard by a
+        // different graph-node since we cached its layout, and we need
+        // to check where it lives every time
+        let site = e::find_site(&conn, subgraph)?
+            .ok_or_else(|| format_err!("unknown subgraph {}", subgraph))?;
+        if site.shard == PRIMARY_SHARD {
+            let layout = self.layout(&conn, &conn, subgraph, Some(&site.namespace))?;
+            Ok(e::Connection::new(
+                conn,
+                None,
+                layout,
+                metadata,
+                primary_url,
+            ))
+        } else {
+            let data_conn = self.shard_conn(&site.shard)?;
+            let layout = self.layout(&conn, &data_conn, subgraph, Some(&site.namespace))?;
+            Ok(e::Connection::new(
+                data_conn.into(),
+                Some(conn),
+                layout,
+                metadata,
+                self.shard(&site.shard)?.postgres_url.clone(),
+            ))
+        }
+    }
+
+    fn shard(&self, name: &str) -> Result<&Shard, StoreError> {
+        self.shards.get(name).ok_or_else(|| {
+            StoreError::Unknown(format_err!(
+                "shard `{}` is not configured for this graph-node",
+                name
+            ))
+        })
+    }
+
+    fn shard_conn(
+        &self,
+        name: &str,
+    ) -> Result<PooledConnection<ConnectionManager<PgConnection>>, Error> {
+        self.shard(name)?.pool.get().map_err(Error::from)
+    }
+
+    /// Return the layout for the subgraph. Since constructing a `Layout`
+    /// object takes a bit of computation, we cache layouts in the Store,
+    /// i.e., for the lifetime of the Store. If `namespace` is given, a
+    /// cached layout for a different database schema is considered outdated
+    fn layout(
+        &self,
+        meta_conn: &PgConnection,
+        conn: &PgConnection,
+        subgraph: &SubgraphDeploymentId,
+        namespace: Option<&str>,
+    ) -> Result<Arc<Layout>, StoreError> {
+        if let Some(layout) = self.layout_cache.lock().unwrap().get(subgraph) {
+            if namespace.map_or(true, |namespace| layout.catalog.schema == namespace) {
+                return Ok(layout.clone());
+            }
         }
-        Ok(storage.clone())
+
+        let layout = Arc::new(e::load_layout(meta_conn, conn, subgraph)?);
+        self.layout_cache
+            .lock()
+            .unwrap()
+            .insert(subgraph.clone(), layout.clone());
+        Ok(layout)
     }
 
     fn subgraph_info(&self, subgraph_id: &SubgraphDeploymentId) -> Result<SubgraphInfo, Error> {
@@ -849,6 +1167,7 @@ impl Store {
 
         let graft_block =
             metadata::deployment_graft(&conn, &subgraph_id)?.map(|(_, ptr)| ptr.number as i32);
+        let earliest_block = metadata::pruned_block(&conn, &subgraph_id)?;
 
         // Generate an API schema for the subgraph and make sure all types in the
         // API schema have a @subgraphId directive as well
@@ -868,6 +1187,7 @@ impl Store {
             ),
             network,
             graft_block,
+            earliest_block: Arc::new(Mutex::new((earliest_block, Instant::now()))),
         };
 
         // Insert the schema into the cache.
@@ -877,6 +1197,113 @@ impl Store {
         Ok(cache.get(&subgraph_id).unwrap().clone())
     }
 
+    /// Remove old entity versions from all deployments that limit how many
+    /// blocks of history they keep. We never remove versions that are
+    /// needed to revert the last `reorg_threshold` blocks, regardless of
+    /// the setting of a deployment.
+    pub fn prune_deployments(
+        &self,
+        logger: &Logger,
+        reorg_threshold: BlockNumber,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        let settings = metadata::history_settings(&conn)?;
+        for settings in settings {
+            // Copying a deployment to another shard relies on the history
+            // of the deployment staying intact
+            if e::find_move(&conn, &settings.id)?.is_some() {
+                continue;
+            }
+            let history_blocks = settings.history_blocks.max(reorg_threshold);
+            let block = settings.latest_block - history_blocks;
+            if block <= settings.pruned_block {
+                continue;
+            }
+
+            let start = Instant::now();
+            let econn = self.get_entity_conn(&settings.id, ReplicaId::Main)?;
+            // Refuse queries for the blocks we are about to remove before
+            // we remove anything, since versions are removed in batches
+            // that are committed one by one. If pruning gets interrupted,
+            // the next round removes what was left over
+            econn.set_pruned_block(block)?;
+            if let Some(info) = self.subgraph_cache.lock().unwrap().get(&settings.id) {
+                *info.earliest_block.lock().unwrap() = (block, Instant::now());
+            }
+            let count = econn.prune(block)?;
+            debug!(logger, "Pruned entity history";
+                           "subgraph" => settings.id.as_str(),
+                           "block" => block,
+                           "versions" => count,
+                           "time_ms" => start.elapsed().as_millis());
+        }
+        Ok(())
+    }
+
+    /// Drop the database schema of `site` and the foreign tables through
+    /// which other shards accessed it. Other graph-nodes might still be
+    /// running queries against it; dropping the schema waits for those
+    /// to finish
+    fn drop_site(&self, site: &e::Site) -> Result<(), StoreError> {
+        let query = format!("drop schema if exists {} cascade", site.namespace);
+        self.shard_conn(&site.shard)?.batch_execute(&query)?;
+        let query = format!(
+            "drop schema if exists {} cascade",
+            sharding::foreign_schema_name(&site.namespace)
+        );
+        for shard in self.shards.keys() {
+            self.shard_conn(shard)?.batch_execute(&query)?;
+        }
+        Ok(())
+    }
+
+    /// The relational layout of `subgraph` in the shard in which it is
+    /// currently stored, a connection to that shard, and its site
+    fn relational_site(
+        &self,
+        meta_conn: &PgConnection,
+        subgraph: &SubgraphDeploymentId,
+    ) -> Result<
+        (
+            e::Site,
+            PooledConnection<ConnectionManager<PgConnection>>,
+            Layout,
+        ),
+        StoreError,
+    > {
+        let site = e::find_site(meta_conn, subgraph)?
+            .ok_or_else(|| format_err!("unknown subgraph {}", subgraph))?;
+        let conn = self.shard_conn(&site.shard)?;
+        let layout = self.layout(meta_conn, &conn, subgraph, Some(&site.namespace))?;
+        Ok((site, conn, layout.as_ref().clone()))
+    }
+
+    /// The layouts of the source and the destination for moving `subgraph`
+    /// according to `mv`. The tables of the source are foreign tables in
+    /// the destination shard, and `dst_conn` must be a connection to that
+    /// shard
+    fn move_layouts(
+        &self,
+        meta_conn: &PgConnection,
+        dst_conn: &PgConnection,
+        subgraph: &SubgraphDeploymentId,
+        mv: &e::Move,
+    ) -> Result<(e::Site, Layout, Layout), StoreError> {
+        let (site, _, src) = self.relational_site(meta_conn, subgraph)?;
+        let schema = metadata::subgraph_schema(meta_conn, subgraph.clone())?;
+        let has_poi = src.tables.contains_key(POI_OBJECT);
+
+        let mut catalog = src.catalog.clone();
+        catalog.schema = sharding::foreign_schema_name(&site.namespace);
+        let foreign = Layout::new(&schema, catalog, has_poi)?;
+        let dst = Layout::new(
+            &schema,
+            Catalog::new(dst_conn, mv.namespace.clone())?,
+            has_poi,
+        )?;
+        Ok((site, foreign, dst))
+    }
+
     fn block_ptr_with_conn(
         subgraph_id: &SubgraphDeploymentId,
         conn: &e::Connection,
@@ -914,6 +1341,176 @@ impl Store {
             )),
         }
     }
+
+    /// Check that `subgraph_id` was not grafted after `block_ptr`; data
+    /// from before the graft point can not be reverted
+    fn check_graft_block(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block_ptr: &EthereumBlockPointer,
+    ) -> Result<(), StoreError> {
+        let info = self.subgraph_info(subgraph_id)?;
+        if let Some(graft_block) = info.graft_block {
+            if graft_block as u64 > block_ptr.number {
+                return Err(format_err!(
+                    "Can not rewind subgraph `{}` to block {} as it was \
+                     grafted at block {}",
+                    subgraph_id,
+                    block_ptr.number,
+                    graft_block
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo all changes `subgraph_id` made after `block_ptr` and set its
+    /// block pointer to `block_ptr`. This must run in a transaction on
+    /// `econn`; the returned events for the entity and the metadata changes
+    /// must be sent once that transaction has been committed
+    fn rewind_with_conn(
+        &self,
+        econn: &e::Connection,
+        subgraph_id: &SubgraphDeploymentId,
+        block_ptr: EthereumBlockPointer,
+    ) -> Result<(StoreEvent, StoreEvent), StoreError> {
+        // Wait for a block that is being written to be committed. Blocks
+        // the deployment was still processing when we rewind it will be
+        // refused by `check_block_ptr`
+        econn.lock_deployment()?;
+        let pruned_block = econn.pruned_block()?;
+        if (block_ptr.number as i64) < pruned_block as i64 {
+            return Err(format_err!(
+                "Can not rewind subgraph `{}` to block {} as its history \
+                 before block {} has been removed",
+                subgraph_id,
+                block_ptr.number,
+                pruned_block
+            )
+            .into());
+        }
+        match Self::block_ptr_with_conn(subgraph_id, econn)? {
+            Some(current) if current.number >= block_ptr.number => (),
+            _ => {
+                return Err(format_err!(
+                    "Can not rewind subgraph `{}` to block {} as it has not \
+                     processed that block yet",
+                    subgraph_id,
+                    block_ptr.number
+                )
+                .into())
+            }
+        }
+
+        let ops = SubgraphDeploymentEntity::update_ethereum_block_pointer_operations(
+            subgraph_id,
+            block_ptr,
+        );
+        let metadata_event = self.apply_metadata_operations_with_conn(econn, ops)?;
+        let block = block_ptr
+            .number
+            .try_into()
+            .expect("block numbers fit into an i32");
+        let (event, count) = econn.rewind(block)?;
+        econn.update_entity_count(count)?;
+        Ok((event.at_block(block), metadata_event))
+    }
+
+    /// Check that the block pointer of `subgraph_id` is still the one we
+    /// left it at when we last wrote or reverted a block. If it is not, the
+    /// deployment was changed behind our back, most likely by rewinding it
+    /// while it was still indexing, and the block that we were about to
+    /// write or revert was processed on top of a state that no longer
+    /// exists
+    fn check_block_ptr(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        current: Option<EthereumBlockPointer>,
+    ) -> Result<(), StoreError> {
+        match self.block_ptrs.lock().unwrap().get(subgraph_id) {
+            Some(expected) if current.as_ref() != Some(expected) => Err(format_err!(
+                "the block pointer of subgraph `{}` was changed from {} to {} while \
+                 it was indexing; the subgraph needs to be restarted",
+                subgraph_id,
+                expected,
+                current.map_or_else(|| "none".to_owned(), |ptr| ptr.to_string())
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_block_ptr(&self, subgraph_id: &SubgraphDeploymentId, block_ptr: EthereumBlockPointer) {
+        self.block_ptrs
+            .lock()
+            .unwrap()
+            .insert(subgraph_id.clone(), block_ptr);
+    }
+
+    /// The blocks of `subgraph_id` that are waiting to be written, if there
+    /// have ever been any
+    fn write_queue(&self, subgraph_id: &SubgraphDeploymentId) -> Option<Arc<WriteQueue>> {
+        self.write_queues.lock().unwrap().get(subgraph_id).cloned()
+    }
+
+    /// Write the changes for `block_ptr_to` in the current thread, and
+    /// only return once they have been committed
+    fn write_block_operations(
+        &self,
+        subgraph_id: SubgraphDeploymentId,
+        block_ptr_to: EthereumBlockPointer,
+        mods: Vec<EntityModification>,
+        stopwatch: StopwatchMetrics,
+    ) -> Result<(), StoreError> {
+        let econn = self.get_entity_conn(&subgraph_id, ReplicaId::Main)?;
+
+        let (event, metadata_event) = econn.transaction(|| -> Result<_, StoreError> {
+            econn.lock_deployment()?;
+            let block_ptr_from = Self::block_ptr_with_conn(&subgraph_id, &econn)?;
+            self.check_block_ptr(&subgraph_id, block_ptr_from)?;
+            if let Some(ref block_ptr_from) = block_ptr_from {
+                if block_ptr_from.number >= block_ptr_to.number {
+                    return Err(StoreError::DuplicateBlockProcessing(
+                        subgraph_id,
+                        block_ptr_to.number,
+                    ));
+                }
+            }
+
+            let history_event = econn.create_history_event(block_ptr_to);
+
+            // Emit a store event for the changes we are about to make. We
+            // wait with sending it until we have done all our other work
+            // so that we do not hold a lock on the notification queue
+            // for longer than we have to
+            let event = mods
+                .iter()
+                .collect::<StoreEvent>()
+                .at_block(block_ptr_to.number as BlockNumber);
+
+            // Make the changes
+            let section = stopwatch.start_section("apply_entity_modifications");
+            self.apply_entity_modifications(&econn, mods, Some(&history_event), stopwatch)?;
+            section.end();
+
+            // Update the subgraph block pointer, without an event source; this way
+            // no entity history is recorded for the block pointer update itself
+            let block_ptr_ops = SubgraphDeploymentEntity::update_ethereum_block_pointer_operations(
+                &subgraph_id,
+                block_ptr_to,
+            );
+            let metadata_event = self.apply_metadata_operations_with_conn(&econn, block_ptr_ops)?;
+            Ok((event, metadata_event))
+        })?;
+        self.set_block_ptr(&subgraph_id, block_ptr_to);
+
+        // Send the events separately, because NOTIFY uses a global DB lock.
+        econn.transaction(|| {
+            econn.send_store_event(&metadata_event)?;
+            econn.send_store_event(&event)
+        })
+    }
 }
 
 impl StoreTrait for Store {
@@ -921,6 +1518,12 @@ impl StoreTrait for Store {
         &self,
         subgraph_id: SubgraphDeploymentId,
     ) -> Result<Option<EthereumBlockPointer>, Error> {
+        if let Some(block_ptr) = self
+            .write_queue(&subgraph_id)
+            .and_then(|queue| queue.block_ptr())
+        {
+            return Ok(Some(block_ptr));
+        }
         Self::block_ptr_with_conn(
             &subgraph_id,
             &self
@@ -1054,6 +1657,12 @@ impl StoreTrait for Store {
     }
 
     fn get(&self, key: EntityKey) -> Result<Option<Entity>, QueryExecutionError> {
+        if let Some(entity) = self
+            .write_queue(&key.subgraph_id)
+            .and_then(|queue| queue.get(&key))
+        {
+            return Ok(entity);
+        }
         let conn = self
             .get_entity_conn(&key.subgraph_id, ReplicaId::Main)
             .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
@@ -1068,13 +1677,54 @@ impl StoreTrait for Store {
         if ids_for_type.is_empty() {
             return Ok(BTreeMap::new());
         }
-        let conn = self
-            .get_entity_conn(subgraph_id, ReplicaId::Main)
-            .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
-        conn.find_many(ids_for_type, BLOCK_NUMBER_MAX)
+        let queue = match self.write_queue(subgraph_id) {
+            Some(queue) => queue,
+            None => {
+                let conn = self
+                    .get_entity_conn(subgraph_id, ReplicaId::Main)
+                    .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+                return conn.find_many(ids_for_type, BLOCK_NUMBER_MAX);
+            }
+        };
+
+        // Entities that are changed by blocks that have not been written
+        // yet are taken from the write queue, everything else from the
+        // database
+        let mut queued: BTreeMap<String, Vec<Entity>> = BTreeMap::new();
+        let mut missing: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (entity_type, ids) in ids_for_type {
+            for id in ids {
+                let key = EntityKey {
+                    subgraph_id: subgraph_id.clone(),
+                    entity_type: entity_type.to_owned(),
+                    entity_id: id.to_owned(),
+                };
+                match queue.get(&key) {
+                    Some(Some(entity)) => queued
+                        .entry(entity_type.to_owned())
+                        .or_default()
+                        .push(entity),
+                    Some(None) => { /* removed in a queued block */ }
+                    None => missing.entry(entity_type).or_default().push(id),
+                }
+            }
+        }
+        let mut entities = if missing.is_empty() {
+            BTreeMap::new()
+        } else {
+            let conn = self
+                .get_entity_conn(subgraph_id, ReplicaId::Main)
+                .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+            conn.find_many(missing, BLOCK_NUMBER_MAX)?
+        };
+        for (entity_type, mut found) in queued {
+            entities.entry(entity_type).or_default().append(&mut found);
+        }
+        Ok(entities)
     }
 
     fn find(&self, query: EntityQuery) -> Result<Vec<Entity>, QueryExecutionError> {
+        self.flush_writes(&query.subgraph_id)?;
         let conn = self
             .get_entity_conn(&query.subgraph_id, ReplicaId::Main)
             .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
@@ -1083,6 +1733,7 @@ impl StoreTrait for Store {
 
     fn find_one(&self, mut query: EntityQuery) -> Result<Option<Entity>, QueryExecutionError> {
         query.range = EntityRange::first(1);
+        self.flush_writes(&query.subgraph_id)?;
 
         let conn = self
             .get_entity_conn(&query.subgraph_id, ReplicaId::Main)
@@ -1120,7 +1771,7 @@ impl StoreTrait for Store {
         block_ptr_to: EthereumBlockPointer,
         mods: Vec<EntityModification>,
         stopwatch: StopwatchMetrics,
-    ) -> Result<bool, StoreError> {
+    ) -> Result<(), StoreError> {
         // All operations should apply only to entities in this subgraph or
         // the subgraph of subgraphs
         if mods
@@ -1134,59 +1785,59 @@ impl StoreTrait for Store {
             );
         }
 
-        let econn = self.get_entity_conn(&subgraph_id, ReplicaId::Main)?;
-
-        let (event, metadata_event, should_migrate) =
-            econn.transaction(|| -> Result<_, StoreError> {
-                let block_ptr_from = Self::block_ptr_with_conn(&subgraph_id, &econn)?;
-                if let Some(ref block_ptr_from) = block_ptr_from {
-                    if block_ptr_from.number >= block_ptr_to.number {
-                        return Err(StoreError::DuplicateBlockProcessing(
-                            subgraph_id,
-                            block_ptr_to.number,
-                        ));
+        match *WRITE_QUEUE_DEPTH {
+            None => self.write_block_operations(subgraph_id, block_ptr_to, mods, stopwatch),
+            Some(depth) => {
+                let queue = {
+                    let mut queues = self.write_queues.lock().unwrap();
+                    match queues.get(&subgraph_id) {
+                        Some(queue) => queue.clone(),
+                        None => {
+                            let store = self.clone();
+                            let id = subgraph_id.clone();
+                            let queue = WriteQueue::start(
+                                subgraph_id.clone(),
+                                depth,
+                                Box::new(move |block_ptr, mods, stopwatch| {
+                                    store.write_block_operations(
+                                        id.clone(),
+                                        block_ptr,
+                                        mods,
+                                        stopwatch,
+                                    )
+                                }),
+                            )?;
+                            queues.insert(subgraph_id.clone(), queue.clone());
+                            queue
+                        }
                     }
-                }
-
-                // Ensure the history event exists in the database
-                let history_event = econn.create_history_event(block_ptr_to, &mods)?;
-
-                let should_migrate = econn.should_migrate(&subgraph_id, &block_ptr_to)?;
-
-                // Emit a store event for the changes we are about to make. We
-                // wait with sending it until we have done all our other work
-                // so that we do not hold a lock on the notification queue
-                // for longer than we have to
-                let event: StoreEvent = mods.iter().collect();
-
-                // Make the changes
-                let section = stopwatch.start_section("apply_entity_modifications");
-                self.apply_entity_modifications(&econn, mods, Some(&history_event), stopwatch)?;
-                section.end();
-
-                // Update the subgraph block pointer, without an event source; this way
-                // no entity history is recorded for the block pointer update itself
-                let block_ptr_ops =
-                    SubgraphDeploymentEntity::update_ethereum_block_pointer_operations(
-                        &subgraph_id,
-                        block_ptr_to,
-                    );
-                let metadata_event =
-                    self.apply_metadata_operations_with_conn(&econn, block_ptr_ops)?;
-                Ok((event, metadata_event, should_migrate))
-            })?;
+                };
+                queue.push(block_ptr_to, mods, stopwatch)
+            }
+        }
+    }
 
-        // Send the events separately, because NOTIFY uses a global DB lock.
-        econn.transaction(|| {
-            econn.send_store_event(&metadata_event)?;
-            econn.send_store_event(&event)
-        })?;
+    fn flush_writes(&self, subgraph_id: &SubgraphDeploymentId) -> Result<(), StoreError> {
+        // Queued blocks can also change metadata, like dynamic data
+        // sources, but they are always in the queue of their deployment.
+        // Reading that metadata therefore needs to flush the deployment
+        // itself; there is never a queue for the subgraph of subgraphs
+        match self.write_queue(subgraph_id) {
+            Some(queue) => queue.flush(),
+            None => Ok(()),
+        }
+    }
 
-        Ok(should_migrate)
+    fn stop_writes(&self, subgraph_id: &SubgraphDeploymentId) -> Result<(), StoreError> {
+        // Take the queue out of the map first so that we do not hold the
+        // lock while we wait for its blocks to be written
+        let queue = self.write_queues.lock().unwrap().remove(subgraph_id);
+        match queue {
+            Some(queue) => queue.stop(),
+            None => Ok(()),
+        }
     }
 
-    /// Apply a series of entity operations. Return `true` if the subgraph
-    /// mentioned in `history_event` should have its schema migrated
     fn apply_metadata_operations(
         &self,
         operations: Vec<MetadataOperation>,
@@ -1199,15 +1850,6 @@ impl StoreTrait for Store {
         econn.transaction(|| econn.send_store_event(&event))
     }
 
-    fn build_entity_attribute_indexes(
-        &self,
-        subgraph: &SubgraphDeploymentId,
-        indexes: Vec<AttributeIndexDefinition>,
-    ) -> Result<(), SubgraphAssignmentProviderError> {
-        let econn = self.get_entity_conn(subgraph, ReplicaId::Main)?;
-        econn.transaction(|| self.build_entity_attribute_indexes_with_conn(&econn, indexes))
-    }
-
     fn revert_block_operations(
         &self,
         subgraph_id: SubgraphDeploymentId,
@@ -1218,6 +1860,8 @@ impl StoreTrait for Store {
         if block_ptr_from.number != block_ptr_to.number + 1 {
             panic!("revert_block_operations must revert a single block only");
         }
+        // The block we revert might still be waiting to be written
+        self.flush_writes(&subgraph_id)?;
         // Don't revert past a graft point
         let info = self.subgraph_info(&subgraph_id)?;
         if let Some(graft_block) = info.graft_block {
@@ -1236,10 +1880,24 @@ impl StoreTrait for Store {
 
         let econn = self.get_entity_conn(&subgraph_id, ReplicaId::Main)?;
         let (event, metadata_event) = econn.transaction(|| -> Result<_, StoreError> {
-            assert_eq!(
-                Some(block_ptr_from),
-                Self::block_ptr_with_conn(&subgraph_id, &econn)?
-            );
+            econn.lock_deployment()?;
+            let current = Self::block_ptr_with_conn(&subgraph_id, &econn)?;
+            self.check_block_ptr(&subgraph_id, current)?;
+
+            // Don't revert past the point up to which history was removed
+            let pruned_block = econn.pruned_block()?;
+            if (block_ptr_to.number as i64) < pruned_block as i64 {
+                return Err(format_err!(
+                    "Can not revert subgraph `{}` to block {} as its history \
+                     before block {} has been removed",
+                    subgraph_id,
+                    block_ptr_to.number,
+                    pruned_block
+                )
+                .into());
+            }
+
+            assert_eq!(Some(block_ptr_from), current);
             let ops = SubgraphDeploymentEntity::update_ethereum_block_pointer_operations(
                 &subgraph_id,
                 block_ptr_to,
@@ -1248,8 +1906,150 @@ impl StoreTrait for Store {
 
             let (event, count) = econn.revert_block(&block_ptr_from)?;
             econn.update_entity_count(count)?;
+            let event = event.at_block(block_ptr_to.number as BlockNumber);
             Ok((event, metadata_event))
         })?;
+        self.set_block_ptr(&subgraph_id, block_ptr_to);
+
+        // Send the events separately, because NOTIFY uses a global DB lock.
+        econn.transaction(|| {
+            econn.send_store_event(&metadata_event)?;
+            econn.send_store_event(&event)
+        })
+    }
+
+    fn subgraph_names(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<String>, StoreError> {
+        metadata::subgraph_names(&*self.get_conn()?, subgraph_id)
+    }
+
+    fn set_history_blocks(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        history_blocks: Option<BlockNumber>,
+    ) -> Result<(), StoreError> {
+        if let Some(history_blocks) = history_blocks {
+            if history_blocks < 0 {
+                return Err(format_err!(
+                    "the number of blocks of history to keep must not be negative but is {}",
+                    history_blocks
+                )
+                .into());
+            }
+        }
+        metadata::set_history_blocks(&*self.get_conn()?, subgraph_id, history_blocks)
+    }
+
+    fn create_index(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        entity_type: &str,
+        fields: Vec<String>,
+    ) -> Result<String, StoreError> {
+        let econn = self.get_entity_conn(subgraph_id, ReplicaId::Main)?;
+        econn.create_index(entity_type, &fields)
+    }
+
+    fn drop_index(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        index_name: &str,
+    ) -> Result<(), StoreError> {
+        let econn = self.get_entity_conn(subgraph_id, ReplicaId::Main)?;
+        econn.drop_index(index_name)
+    }
+
+    fn copy_statuses(
+        &self,
+        subgraph_ids: &[SubgraphDeploymentId],
+    ) -> Result<HashMap<SubgraphDeploymentId, CopyStatus>, StoreError> {
+        let ids: Vec<_> = subgraph_ids.iter().map(|id| id.as_str()).collect();
+        // A move has its own copy progress in the shard it moves to
+        let mut by_shard: HashMap<String, Vec<String>> = HashMap::new();
+        for (id, shard) in e::find_copy_shards(&*self.get_conn()?, &ids)? {
+            by_shard.entry(shard).or_default().push(id);
+        }
+
+        let mut statuses = HashMap::new();
+        for (shard, ids) in by_shard {
+            let ids: Vec<_> = ids.iter().map(String::as_str).collect();
+            statuses.extend(copy::copy_statuses(&*self.shard_conn(&shard)?, &ids)?);
+        }
+        Ok(statuses)
+    }
+
+    fn entity_stats(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<EntityTypeStats>, StoreError> {
+        let econn = self.get_entity_conn(subgraph_id, ReplicaId::Main)?;
+        econn.entity_stats()
+    }
+
+    fn estimated_entity_stats(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<Vec<EntityTypeStats>, StoreError> {
+        let econn = self.get_entity_conn(subgraph_id, ReplicaId::Main)?;
+        econn.estimated_entity_stats()
+    }
+
+    fn entity_changes_in_block(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block: BlockNumber,
+    ) -> Result<Vec<EntityTypeChanges>, StoreError> {
+        let econn = self.get_entity_conn(subgraph_id, ReplicaId::Main)?;
+        econn.entity_changes_in_block(block)
+    }
+
+    fn evolve_deployment_schema(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        schema: &Schema,
+    ) -> Result<(), StoreError> {
+        self.flush_writes(subgraph_id)?;
+
+        if e::find_move(&*self.get_conn()?, subgraph_id)?.is_some() {
+            return Err(format_err!(
+                "the schema of subgraph {} can not be changed while it is moved to another shard",
+                subgraph_id
+            )
+            .into());
+        }
+
+        let econn = self.get_entity_conn(subgraph_id, ReplicaId::Main)?;
+        let evolution = econn.schema_evolution(schema)?;
+        econn.add_enum_values(&evolution)?;
+        econn.transaction(|| econn.evolve_schema(&evolution, schema))?;
+
+        // The layout and schemas we cached for the deployment are outdated
+        self.layout_cache.lock().unwrap().remove(subgraph_id);
+        self.subgraph_cache.lock().unwrap().remove(subgraph_id);
+
+        // Other nodes clear their caches when they see this event in
+        // `handle_schema_changes`
+        let change = EntityChange::from_key(
+            SubgraphManifestEntity::key(SubgraphManifestEntity::id(subgraph_id)),
+            EntityChangeOperation::Set,
+        );
+        let event = StoreEvent::new(vec![change]);
+        econn.transaction(|| econn.send_store_event(&event))
+    }
+
+    fn rewind(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        block_ptr: EthereumBlockPointer,
+    ) -> Result<(), StoreError> {
+        self.flush_writes(subgraph_id)?;
+        self.check_graft_block(subgraph_id, &block_ptr)?;
+
+        let econn = self.get_entity_conn(subgraph_id, ReplicaId::Main)?;
+        let (event, metadata_event) =
+            econn.transaction(|| self.rewind_with_conn(&econn, subgraph_id, block_ptr))?;
 
         // Send the events separately, because NOTIFY uses a global DB lock.
         econn.transaction(|| {
@@ -1258,6 +2058,250 @@ impl StoreTrait for Store {
         })
     }
 
+    fn start_deployment_move(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+        shard: &str,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        self.shard(shard)?;
+
+        if let Some(mv) = e::find_move(&conn, subgraph_id)? {
+            if mv.shard == shard && !mv.invalidated {
+                return Ok(());
+            }
+            if !mv.invalidated {
+                return Err(format_err!(
+                    "subgraph {} is already being moved to shard `{}`",
+                    subgraph_id,
+                    mv.shard
+                )
+                .into());
+            }
+            // Throw the unusable copy away and start over
+            let query = format!("drop schema if exists {} cascade", mv.namespace);
+            self.shard_conn(&mv.shard)?.batch_execute(&query)?;
+            e::delete_move(&conn, subgraph_id)?;
+        }
+
+        let (site, src_conn, src) = self.relational_site(&conn, subgraph_id)?;
+        if site.shard == shard {
+            return Err(format_err!(
+                "subgraph {} is already stored in shard `{}`",
+                subgraph_id,
+                shard
+            )
+            .into());
+        }
+        if !copy::is_finished(&src_conn, subgraph_id)? {
+            return Err(format_err!(
+                "subgraph {} can not be moved before copying the data of its \
+                 graft base has finished",
+                subgraph_id
+            )
+            .into());
+        }
+        let econn = self.get_entity_conn(&*SUBGRAPHS_ID, ReplicaId::Main)?;
+        let target_block = Self::block_ptr_with_conn(subgraph_id, &econn)?.ok_or_else(|| {
+            format_err!(
+                "subgraph {} can not be moved since it has not processed any blocks yet",
+                subgraph_id
+            )
+        })?;
+
+        let mv = e::Move {
+            shard: shard.to_owned(),
+            namespace: e::allocate_namespace(&conn)?,
+            target_block,
+            invalidated: false,
+        };
+        let schema = metadata::subgraph_schema(&conn, subgraph_id.clone())?;
+        let dst_conn = self.shard_conn(shard)?;
+        conn.transaction(|| {
+            dst_conn.transaction(|| -> Result<(), StoreError> {
+                copy::clear(&dst_conn, subgraph_id)?;
+                dst_conn.batch_execute(&format!("create schema {}", mv.namespace))?;
+                let dst = Layout::new(
+                    &schema,
+                    Catalog::new(&dst_conn, mv.namespace.clone())?,
+                    src.tables.contains_key(POI_OBJECT),
+                )?;
+                let ddl = dst.as_ddl().map_err(|_| {
+                    StoreError::Unknown(format_err!("failed to generate DDL for layout"))
+                })?;
+                dst_conn.batch_execute(&ddl)?;
+                // Indexes that were added by hand are not part of the layout
+                for index in catalog::manual_indexes(&src_conn, &site.namespace)? {
+                    let index = index.replace(
+                        &format!(" ON {}.", site.namespace),
+                        &format!(" ON {}.", mv.namespace),
+                    );
+                    dst_conn.batch_execute(&index)?;
+                }
+                e::create_move(&conn, subgraph_id, &mv)
+            })
+        })
+    }
+
+    fn copy_moving_deployment(
+        &self,
+        logger: &Logger,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        let mv = e::find_move(&conn, subgraph_id)?
+            .ok_or_else(|| format_err!("subgraph {} is not being moved", subgraph_id))?;
+        let dst_conn = self.shard_conn(&mv.shard)?;
+        let (site, src, dst) = self.move_layouts(&conn, &dst_conn, subgraph_id, &mv)?;
+
+        let src_shard = self.shard(&site.shard)?;
+        sharding::create_foreign_server(&dst_conn, src_shard)?;
+        sharding::create_foreign_tables(&dst_conn, src_shard, &src, &site.namespace)?;
+
+        copy::copy_moving_deployment(logger, &dst_conn, &src, &dst, mv.target_block, || {
+            // Stop copying when the move was cancelled or became pointless
+            Ok(e::find_move(&*self.get_conn()?, subgraph_id)?.map_or(true, |mv| mv.invalidated))
+        })
+    }
+
+    fn finish_deployment_move(
+        &self,
+        logger: &Logger,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError> {
+        self.flush_writes(subgraph_id)?;
+
+        let conn = self.get_conn()?;
+        let (previous, block, dst) = conn.transaction(|| -> Result<_, StoreError> {
+            let mv = e::find_move(&conn, subgraph_id)?
+                .ok_or_else(|| format_err!("subgraph {} is not being moved", subgraph_id))?;
+            if mv.invalidated {
+                return Err(format_err!(
+                    "the copy of subgraph {} in shard `{}` can not be used because the \
+                     subgraph was reverted past block {}; the move needs to be started again",
+                    subgraph_id,
+                    mv.shard,
+                    mv.target_block.number
+                )
+                .into());
+            }
+            let dst_conn = self.shard_conn(&mv.shard)?;
+            if !copy::is_finished(&dst_conn, subgraph_id)? {
+                return Err(format_err!(
+                    "copying the data of subgraph {} to shard `{}` has not finished yet",
+                    subgraph_id,
+                    mv.shard
+                )
+                .into());
+            }
+            let (old, src, dst) = self.move_layouts(&conn, &dst_conn, subgraph_id, &mv)?;
+
+            let start = Instant::now();
+            let count = dst_conn.transaction(|| {
+                copy::catch_up(&dst_conn, &src, &dst, mv.target_block.number as BlockNumber)
+            })?;
+            info!(logger, "Copied changes made while moving the subgraph";
+                          "subgraph" => subgraph_id.as_str(),
+                          "versions" => count,
+                          "time_ms" => start.elapsed().as_millis());
+
+            let site = e::Site {
+                namespace: mv.namespace,
+                shard: mv.shard,
+            };
+            e::set_site(&conn, subgraph_id, &site)?;
+            e::delete_move(&conn, subgraph_id)?;
+
+            // Keep the old data so that the move can be rolled back
+            let block = metadata::latest_block_ptr(&conn, subgraph_id)?.ok_or_else(|| {
+                format_err!("subgraph {} has not processed any blocks", subgraph_id)
+            })?;
+            let previous = e::find_retired_site(&conn, subgraph_id)?;
+            e::retire_site(&conn, subgraph_id, &e::RetiredSite { site: old, block })?;
+            Ok((previous, block, site))
+        })?;
+
+        // The layout we cached for the deployment is outdated
+        self.layout_cache.lock().unwrap().remove(subgraph_id);
+
+        // The data from an earlier move can not be used anymore
+        if let Some(previous) = previous {
+            self.drop_site(&previous.site)?;
+        }
+        info!(logger, "Moved subgraph to shard `{}`", dst.shard;
+                      "subgraph" => subgraph_id.as_str(),
+                      "namespace" => &dst.namespace,
+                      "block" => block.number);
+        Ok(())
+    }
+
+    fn rollback_deployment_move(
+        &self,
+        logger: &Logger,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        if e::find_move(&conn, subgraph_id)?.is_some() {
+            return Err(format_err!(
+                "subgraph {} is being moved and an earlier move can not be rolled back",
+                subgraph_id
+            )
+            .into());
+        }
+        let retired = e::find_retired_site(&conn, subgraph_id)?.ok_or_else(|| {
+            format_err!("subgraph {} has no data from before a move", subgraph_id)
+        })?;
+
+        // The old data is as of the block at which the deployment was
+        // moved. Rewinding the deployment to that block makes its current
+        // data the same as the old data so that we can switch back to it.
+        // Everything the deployment indexed since it was moved is lost
+        self.rewind(subgraph_id, retired.block)?;
+
+        let current = conn.transaction(|| -> Result<_, StoreError> {
+            let current = e::find_site(&conn, subgraph_id)?
+                .ok_or_else(|| format_err!("unknown subgraph {}", subgraph_id))?;
+            match e::find_retired_site(&conn, subgraph_id)? {
+                Some(other) if other.site == retired.site => (),
+                _ => {
+                    return Err(format_err!(
+                        "the data of subgraph {} from before its move was changed \
+                         while rolling the move back",
+                        subgraph_id
+                    )
+                    .into())
+                }
+            }
+            e::set_site(&conn, subgraph_id, &retired.site)?;
+            e::delete_retired_site(&conn, subgraph_id)?;
+            Ok(current)
+        })?;
+        self.layout_cache.lock().unwrap().remove(subgraph_id);
+
+        self.drop_site(&current)?;
+        info!(logger, "Moved subgraph back to shard `{}`", retired.site.shard;
+                      "subgraph" => subgraph_id.as_str(),
+                      "namespace" => &retired.site.namespace,
+                      "block" => retired.block.number);
+        Ok(())
+    }
+
+    fn drop_retired_deployment_data(
+        &self,
+        subgraph_id: &SubgraphDeploymentId,
+    ) -> Result<(), StoreError> {
+        let conn = self.get_conn()?;
+        let retired = conn.transaction(|| -> Result<_, StoreError> {
+            let retired = e::find_retired_site(&conn, subgraph_id)?;
+            e::delete_retired_site(&conn, subgraph_id)?;
+            Ok(retired)
+        })?;
+        match retired {
+            Some(retired) => self.drop_site(&retired.site),
+            None => Ok(()),
+        }
+    }
+
     fn subscribe(&self, entities: Vec<SubgraphEntityPair>) -> StoreEventStreamBox {
         self.subscriptions.subscribe(entities)
     }
@@ -1270,9 +2314,19 @@ impl StoreTrait for Store {
         let econn = self.get_entity_conn(&*SUBGRAPHS_ID, ReplicaId::Main)?;
         econn.transaction(|| -> Result<(), StoreError> {
             let event = self.apply_metadata_operations_with_conn(&econn, ops.clone())?;
-            econn.create_schema(schema)?;
+            // The names of the subgraph are only known once the versioning
+            // operations have been applied
+            let shard = econn.place(&schema.id, &self.placement)?;
+            if shard == PRIMARY_SHARD {
+                econn.create_schema(schema, &shard, None)?;
+            } else {
+                let conn = self.shard_conn(&shard)?;
+                conn.transaction(|| econn.create_schema(schema, &shard, Some(&conn)))?;
+            }
             econn.send_store_event(&event)
-        })
+        })?;
+        self.block_ptrs.lock().unwrap().remove(&schema.id);
+        Ok(())
     }
 
     fn start_subgraph_deployment(
@@ -1281,16 +2335,21 @@ impl StoreTrait for Store {
         subgraph_id: &SubgraphDeploymentId,
         ops: Vec<MetadataOperation>,
     ) -> Result<(), StoreError> {
+        // Wait for blocks from an earlier run to be written, and start with
+        // an empty write queue; blocks that could not be written because
+        // of an error will be processed again
+        self.stop_writes(subgraph_id).ok();
+        // Whatever happened to the deployment while it was not running,
+        // like rewinding it, is now its starting point
+        self.block_ptrs.lock().unwrap().remove(subgraph_id);
+
         let econn = self.get_entity_conn(subgraph_id, ReplicaId::Main)?;
 
-        if !econn.uses_relational_schema() {
-            warn!(
-                logger,
-                "This subgraph uses JSONB storage, which is \
-              deprecated and support for it will be removed in a future release. \
-              Please redeploy the subgraph to address this warning"
-            );
-        }
+        // Finishing interrupted writes and copying from a graft base use
+        // their own transactions and therefore need to happen before we
+        // start the transaction below
+        econn.finish_prepared_transactions(logger)?;
+        econn.copy_graft_base(logger)?;
 
         econn.transaction(|| {
             let event = self.apply_metadata_operations_with_conn(&econn, ops)?;
@@ -1299,33 +2358,6 @@ impl StoreTrait for Store {
         })
     }
 
-    fn migrate_subgraph_deployment(
-        &self,
-        logger: &Logger,
-        subgraph_id: &SubgraphDeploymentId,
-        block_ptr: &EthereumBlockPointer,
-    ) {
-        let econn = match self.get_entity_conn(subgraph_id, ReplicaId::Main) {
-            Ok(econn) => econn,
-            Err(e) => {
-                warn!(logger, "failed to get connection to start migrating";
-                                "subgraph" => subgraph_id.to_string(),
-                                "error" => e.to_string(),
-                );
-                return;
-            }
-        };
-
-        if let Err(e) = econn.migrate(logger, block_ptr) {
-            // An error in a migration should not lead to the
-            // subgraph being marked as failed
-            warn!(logger, "aborted migrating";
-                            "subgraph" => subgraph_id.to_string(),
-                            "error" => e.to_string(),
-            );
-        }
-    }
-
     fn block_number(
         &self,
         subgraph_id: &SubgraphDeploymentId,
@@ -1399,14 +2431,21 @@ impl SubgraphDeploymentStore for Store {
         Ok(self.subgraph_info(subgraph_id)?.api)
     }
 
-    fn uses_relational_schema(&self, subgraph: &SubgraphDeploymentId) -> Result<bool, Error> {
-        self.get_entity_conn(subgraph, ReplicaId::Main)
-            .map(|econn| econn.uses_relational_schema())
-    }
-
     fn network_name(&self, subgraph_id: &SubgraphDeploymentId) -> Result<Option<String>, Error> {
         Ok(self.subgraph_info(subgraph_id)?.network)
     }
+
+    fn earliest_block(&self, subgraph_id: &SubgraphDeploymentId) -> Result<BlockNumber, Error> {
+        let info = self.subgraph_info(subgraph_id)?;
+        let mut earliest = info.earliest_block.lock().unwrap();
+        if earliest.1.elapsed() > *EARLIEST_BLOCK_TTL {
+            *earliest = (
+                metadata::pruned_block(&*self.get_conn()?, subgraph_id)?,
+                Instant::now(),
+            );
+        }
+        Ok(earliest.0)
+    }
 }
 
 impl ChainStore for Store {
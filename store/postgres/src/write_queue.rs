@@ -0,0 +1,393 @@
+//! A queue of blocks whose changes have been processed but not yet written
+//! to the database. Each deployment has one background thread that writes
+//! its blocks so that the next block can be processed while the previous
+//! one is written.
+//!
+//! Each block is still written in its own transaction which also moves the
+//! deployment's block pointer; if the process dies with blocks in the
+//! queue, the database is consistent as of the last written block, and the
+//! queued blocks are simply processed again after a restart.
+//!
+//! While blocks are queued, lookups of individual entities consult the
+//! queue before they go to the database, and the block pointer of the
+//! deployment is that of the last queued block. Everything that can not
+//! take the queue into account, like reverting a block or running a query,
+//! has to wait for the queue to be drained with `flush`.
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use graph::prelude::{
+    format_err, Entity, EntityKey, EntityModification, EthereumBlockPointer, StopwatchMetrics,
+    StoreError, SubgraphDeploymentId,
+};
+
+/// A block that is waiting to be written
+struct PendingBlock {
+    block_ptr: EthereumBlockPointer,
+    mods: Vec<EntityModification>,
+    /// The index into `mods` of the last modification of each entity
+    latest: HashMap<EntityKey, usize>,
+    stopwatch: StopwatchMetrics,
+}
+
+impl PendingBlock {
+    fn new(
+        block_ptr: EthereumBlockPointer,
+        mods: Vec<EntityModification>,
+        stopwatch: StopwatchMetrics,
+    ) -> Self {
+        let latest = mods
+            .iter()
+            .enumerate()
+            .map(|(i, modification)| (modification.entity_key().clone(), i))
+            .collect();
+        PendingBlock {
+            block_ptr,
+            mods,
+            latest,
+            stopwatch,
+        }
+    }
+
+    /// The state of the entity after this block, or `None` if the block
+    /// does not change the entity
+    fn get(&self, key: &EntityKey) -> Option<Option<Entity>> {
+        self.latest.get(key).map(|i| match &self.mods[*i] {
+            EntityModification::Insert { data, .. }
+            | EntityModification::Overwrite { data, .. } => Some(data.clone()),
+            EntityModification::Remove { .. } => None,
+        })
+    }
+}
+
+/// Writes the changes for one block and only returns once they have been
+/// committed
+pub(crate) type WriteFn = Box<
+    dyn Fn(
+            EthereumBlockPointer,
+            Vec<EntityModification>,
+            StopwatchMetrics,
+        ) -> Result<(), StoreError>
+        + Send,
+>;
+
+#[derive(Default)]
+struct State {
+    /// The blocks that still need to be written, oldest first. The block
+    /// at the front stays in the queue while it is being written
+    pending: VecDeque<Arc<PendingBlock>>,
+    /// The block whose write failed, and the error. Once a write has
+    /// failed, nothing else gets written
+    error: Option<(EthereumBlockPointer, String)>,
+    /// Whether the writer should exit once the queue is empty
+    stopped: bool,
+}
+
+/// The blocks waiting to be written for one deployment
+pub(crate) struct WriteQueue {
+    subgraph: SubgraphDeploymentId,
+    /// The maximum number of blocks in the queue; adding a block to a full
+    /// queue blocks until the oldest block has been written
+    depth: usize,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl WriteQueue {
+    /// Create a queue and start the thread that writes its blocks with
+    /// `write`. The thread runs until the queue is stopped or a write
+    /// fails
+    pub fn start(
+        subgraph: SubgraphDeploymentId,
+        depth: usize,
+        write: WriteFn,
+    ) -> Result<Arc<Self>, StoreError> {
+        let queue = Arc::new(WriteQueue {
+            subgraph,
+            depth,
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        });
+        let writer = queue.clone();
+        std::thread::Builder::new()
+            .name(format!("write-queue-{}", queue.subgraph))
+            .spawn(move || writer.run(write))
+            .map_err(|e| StoreError::Unknown(format_err!("failed to start block writer: {}", e)))?;
+        Ok(queue)
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap()
+    }
+
+    fn check(&self, state: &State) -> Result<(), StoreError> {
+        match &state.error {
+            Some((block_ptr, error)) => Err(StoreError::WriteFailed(
+                self.subgraph.clone(),
+                *block_ptr,
+                error.clone(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Add the changes for `block_ptr` to the queue. Waits while the queue
+    /// is full
+    pub fn push(
+        &self,
+        block_ptr: EthereumBlockPointer,
+        mods: Vec<EntityModification>,
+        stopwatch: StopwatchMetrics,
+    ) -> Result<(), StoreError> {
+        let mut state = self.lock();
+        while state.error.is_none() && state.pending.len() >= self.depth {
+            state = self.changed.wait(state).unwrap();
+        }
+        self.check(&state)?;
+        if state.stopped {
+            return Err(StoreError::Unknown(format_err!(
+                "the write queue for subgraph `{}` has been stopped",
+                self.subgraph
+            )));
+        }
+        if let Some(last) = state.pending.back() {
+            if last.block_ptr.number >= block_ptr.number {
+                return Err(StoreError::DuplicateBlockProcessing(
+                    self.subgraph.clone(),
+                    block_ptr.number,
+                ));
+            }
+        }
+        state
+            .pending
+            .push_back(Arc::new(PendingBlock::new(block_ptr, mods, stopwatch)));
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Write queued blocks as they arrive until the queue is stopped or a
+    /// write fails
+    fn run(&self, write: WriteFn) {
+        loop {
+            let block = {
+                let mut state = self.lock();
+                loop {
+                    if state.error.is_some() {
+                        return;
+                    }
+                    match state.pending.front() {
+                        Some(block) => break block.clone(),
+                        None if state.stopped => return,
+                        None => state = self.changed.wait(state).unwrap(),
+                    }
+                }
+            };
+            // A panic while writing must not leave `flush` waiting forever
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                write(block.block_ptr, block.mods.clone(), block.stopwatch.clone())
+            }))
+            .unwrap_or_else(|_| {
+                Err(StoreError::Unknown(format_err!(
+                    "writing block {} panicked",
+                    block.block_ptr
+                )))
+            });
+
+            let mut state = self.lock();
+            match result {
+                Ok(()) => {
+                    state.pending.pop_front();
+                }
+                Err(e) => {
+                    // The blocks after the failed one can not be written
+                    // either; they will be processed again once the
+                    // subgraph is restarted
+                    state.error = Some((block.block_ptr, e.to_string()));
+                    state.pending.clear();
+                }
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    /// Wait until all queued blocks have been written
+    pub fn flush(&self) -> Result<(), StoreError> {
+        let mut state = self.lock();
+        while state.error.is_none() && !state.pending.is_empty() {
+            state = self.changed.wait(state).unwrap();
+        }
+        self.check(&state)
+    }
+
+    /// Write all queued blocks and then stop the writer. Nothing can be
+    /// added to the queue afterwards
+    pub fn stop(&self) -> Result<(), StoreError> {
+        self.lock().stopped = true;
+        self.changed.notify_all();
+        self.flush()
+    }
+
+    /// The pointer of the last block in the queue, or `None` if the queue
+    /// is empty
+    pub fn block_ptr(&self) -> Option<EthereumBlockPointer> {
+        self.lock().pending.back().map(|block| block.block_ptr)
+    }
+
+    /// The state of the entity with `key` after all queued blocks, or
+    /// `None` if none of them changes the entity
+    pub fn get(&self, key: &EntityKey) -> Option<Option<Entity>> {
+        self.lock()
+            .pending
+            .iter()
+            .rev()
+            .find_map(|block| block.get(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::prelude::{o, slog, web3::types::H256, Logger, Value};
+    use graph_mock::MockMetricsRegistry;
+    use std::collections::HashSet;
+
+    fn subgraph() -> SubgraphDeploymentId {
+        SubgraphDeploymentId::new("writeQueue").unwrap()
+    }
+
+    fn block(number: u64) -> EthereumBlockPointer {
+        EthereumBlockPointer {
+            hash: H256::from_low_u64_be(number),
+            number,
+        }
+    }
+
+    fn key(id: &str) -> EntityKey {
+        EntityKey {
+            subgraph_id: subgraph(),
+            entity_type: "Thing".to_owned(),
+            entity_id: id.to_owned(),
+        }
+    }
+
+    fn insert(id: &str, value: i32) -> EntityModification {
+        let mut data = Entity::new();
+        data.set("id", id);
+        data.set("value", Value::Int(value));
+        EntityModification::Insert { key: key(id), data }
+    }
+
+    fn stopwatch() -> StopwatchMetrics {
+        StopwatchMetrics::new(
+            Logger::root(slog::Discard, o!()),
+            subgraph(),
+            Arc::new(MockMetricsRegistry::new()),
+        )
+    }
+
+    #[test]
+    fn writes_blocks_in_order() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        // Nothing gets written while the test holds the gate
+        let gate = Arc::new(Mutex::new(()));
+        let closed = gate.lock().unwrap();
+        let queue = {
+            let written = written.clone();
+            let gate = gate.clone();
+            WriteQueue::start(
+                subgraph(),
+                10,
+                Box::new(move |block_ptr, _, _| {
+                    let _open = gate.lock().unwrap();
+                    written.lock().unwrap().push(block_ptr.number);
+                    Ok(())
+                }),
+            )
+            .unwrap()
+        };
+
+        for number in 1..=5 {
+            queue
+                .push(
+                    block(number),
+                    vec![insert("one", number as i32)],
+                    stopwatch(),
+                )
+                .unwrap();
+            // Queued changes are visible before they are written
+            assert_eq!(Some(block(number)), queue.block_ptr());
+            let entity = queue.get(&key("one")).unwrap().unwrap();
+            assert_eq!(Some(&Value::Int(number as i32)), entity.get("value"));
+            assert_eq!(None, queue.get(&key("two")));
+        }
+        assert!(matches!(
+            queue.push(block(5), vec![], stopwatch()),
+            Err(StoreError::DuplicateBlockProcessing(_, 5))
+        ));
+
+        drop(closed);
+        queue.flush().unwrap();
+        assert_eq!(vec![1, 2, 3, 4, 5], *written.lock().unwrap());
+        assert_eq!(None, queue.block_ptr());
+        assert_eq!(None, queue.get(&key("one")));
+    }
+
+    #[test]
+    fn uses_one_writer() {
+        let writers = Arc::new(Mutex::new(HashSet::new()));
+        let queue = {
+            let writers = writers.clone();
+            WriteQueue::start(
+                subgraph(),
+                1,
+                Box::new(move |_, _, _| {
+                    writers.lock().unwrap().insert(std::thread::current().id());
+                    Ok(())
+                }),
+            )
+            .unwrap()
+        };
+
+        // The queue runs empty after every block
+        for number in 1..=5 {
+            queue.push(block(number), vec![], stopwatch()).unwrap();
+            queue.flush().unwrap();
+        }
+        assert_eq!(1, writers.lock().unwrap().len());
+
+        queue.stop().unwrap();
+        assert!(queue.push(block(6), vec![], stopwatch()).is_err());
+    }
+
+    #[test]
+    fn stops_after_failed_write() {
+        let queue = WriteQueue::start(
+            subgraph(),
+            10,
+            Box::new(|block_ptr, _, _| {
+                if block_ptr.number == 2 {
+                    Err(StoreError::Unknown(format_err!("write failed")))
+                } else {
+                    Ok(())
+                }
+            }),
+        )
+        .unwrap();
+
+        // Pushing a block fails once the write of block 2 has failed
+        for number in 1..=3 {
+            queue.push(block(number), vec![], stopwatch()).ok();
+        }
+        // The error names the block that could not be written
+        assert!(matches!(
+            queue.flush(),
+            Err(StoreError::WriteFailed(_, block_ptr, _)) if block_ptr == block(2)
+        ));
+        assert_eq!(None, queue.block_ptr());
+        assert!(matches!(
+            queue.push(block(4), vec![], stopwatch()),
+            Err(StoreError::WriteFailed(_, block_ptr, _)) if block_ptr == block(2)
+        ));
+    }
+}
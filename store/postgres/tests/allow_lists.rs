@@ -0,0 +1,124 @@
+//! Test that query nodes share the allow-lists for persisted queries
+//! through the database
+use diesel::connection::SimpleConnection as _;
+use diesel::pg::PgConnection;
+use diesel::Connection as _;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use graph::data::graphql::persisted::{query_hash, AllowList, AllowLists, PersistedQueries};
+use graph::prelude::*;
+use graph_mock::MockMetricsRegistry;
+use graph_store_postgres::connection_pool::create_connection_pool;
+use graph_store_postgres::AllowListStore;
+use test_store::*;
+
+const QUERY: &str = "{ users { id } }";
+
+fn remove_test_data() {
+    let url = postgres_test_url();
+    let conn = PgConnection::establish(url.as_str()).expect("Failed to connect to Postgres");
+    conn.batch_execute(
+        "delete from subgraphs.allowed_queries;
+         delete from subgraphs.allow_list_only;",
+    )
+    .expect("Failed to remove allow-lists");
+}
+
+/// Test harness for running database integration tests.
+fn run_test<F, R>(test: F)
+where
+    F: FnOnce() -> R,
+    R: Future<Output = ()>,
+{
+    // Lock regardless of poisoning. This also forces sequential test execution.
+    let mut runtime = match STORE_RUNTIME.lock() {
+        Ok(guard) => guard,
+        Err(err) => err.into_inner(),
+    };
+
+    remove_test_data();
+    runtime.block_on(test());
+}
+
+/// A query node that keeps its allow-lists in the database and starts
+/// with `allow_lists`
+fn query_node(allow_lists: AllowLists) -> Arc<PersistedQueries> {
+    let url = postgres_test_url();
+    let registry = Arc::new(MockMetricsRegistry::new());
+    let pool = create_connection_pool(
+        "allow_lists",
+        url.clone(),
+        2,
+        &*LOGGER,
+        registry.clone(),
+        POOL_WAIT_STATS.clone(),
+    );
+    let store = Arc::new(AllowListStore::new(&*LOGGER, pool, url));
+    let persisted = PersistedQueries::new(&*LOGGER, registry, allow_lists)
+        .and_then(|persisted| persisted.with_store(store))
+        .expect("Failed to load allow-lists");
+    let persisted = Arc::new(persisted);
+    persisted.listen();
+    persisted
+}
+
+/// Wait until the allow-list of `target` on `node` is `expected`
+async fn wait_for(
+    node: &PersistedQueries,
+    target: &str,
+    expected: (bool, BTreeMap<String, String>),
+) {
+    let start = Instant::now();
+    while node.allow_list(target) != expected {
+        if start.elapsed() > Duration::from_secs(10) {
+            assert_eq!(expected, node.allow_list(target));
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+}
+
+#[test]
+fn changes_reach_other_nodes() {
+    run_test(|| async {
+        let node1 = query_node(AllowLists::new());
+        let node2 = query_node(AllowLists::new());
+        let hash = query_hash(QUERY);
+        let allowed: BTreeMap<_, _> = vec![(hash.clone(), QUERY.to_owned())].into_iter().collect();
+
+        assert_eq!(hash, node1.allow("users", QUERY.to_owned()).unwrap());
+        node1.set_allow_list_only("users", true).unwrap();
+        wait_for(&node2, "users", (true, allowed.clone())).await;
+
+        assert!(node2.disallow("users", &hash).unwrap());
+        assert!(!node2.disallow("users", &hash).unwrap());
+        wait_for(&node1, "users", (true, BTreeMap::new())).await;
+
+        // Nodes that start later see the stored allow-lists
+        node1.allow("users", QUERY.to_owned()).unwrap();
+        let node3 = query_node(AllowLists::new());
+        assert_eq!((true, allowed), node3.allow_list("users"));
+    })
+}
+
+#[test]
+fn configured_allow_lists_are_stored() {
+    run_test(|| async {
+        let mut allow_lists = AllowLists::new();
+        allow_lists.insert(
+            "users".to_owned(),
+            AllowList {
+                allow_list_only: true,
+                queries: vec![QUERY.to_owned()],
+            },
+        );
+        let _configured = query_node(allow_lists);
+
+        let node = query_node(AllowLists::new());
+        let allowed: BTreeMap<_, _> = vec![(query_hash(QUERY), QUERY.to_owned())]
+            .into_iter()
+            .collect();
+        assert_eq!((true, allowed), node.allow_list("users"));
+    })
+}
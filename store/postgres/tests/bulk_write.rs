@@ -0,0 +1,303 @@
+//! Test writing blocks with one statement per entity type. Every block in
+//! these tests is written in bulk, since `run_test` sets the threshold for
+//! bulk writes to a single modification
+use diesel::pg::PgConnection;
+use diesel::Connection;
+use hex_literal::hex;
+use lazy_static::lazy_static;
+use std::str::FromStr;
+use test_store::*;
+
+use graph::components::store::{EntityFilter, EntityKey, EntityOrder, EntityQuery};
+use graph::data::store::scalar;
+use graph::prelude::*;
+use graph_store_postgres::Store as DieselStore;
+use web3::types::H256;
+
+const USER_GQL: &str = r#"
+    type _Schema_ @fulltext(
+        name: "userSearch"
+        language: en
+        algorithm: rank
+        include: [
+            {
+                entity: "User",
+                fields: [
+                    { name: "name"},
+                    { name: "email"},
+                ]
+            }
+        ]
+    )
+
+    enum Color { yellow, red, blue, green }
+
+    interface Named {
+        id: ID!,
+        name: String
+    }
+
+    type User implements Named @entity {
+        id: ID!,
+        name: String,
+        bin_name: Bytes,
+        email: String,
+        age: Int,
+        seconds_age: BigInt,
+        weight: BigDecimal,
+        coffee: Boolean,
+        favorite_color: Color,
+        nicknames: [String!]
+    }
+
+    type Person implements Named @entity {
+        id: ID!,
+        name: String
+    }
+"#;
+
+const USER: &str = "User";
+const PERSON: &str = "Person";
+
+lazy_static! {
+    static ref TEST_SUBGRAPH_ID: SubgraphDeploymentId =
+        SubgraphDeploymentId::new("bulkwritesubgraph").unwrap();
+    static ref BLOCKS: Vec<EthereumBlockPointer> = vec![
+        (
+            H256::from(hex!(
+                "bd34884280958002c51d3f7b5f853e6febeba33de0f40d15b0363006533c924f"
+            )),
+            0u64
+        )
+            .into(),
+        (
+            H256::from(hex!(
+                "8511fa04b64657581e3f00e14543c1d522d5d7e771b54aa3060b662ade47da13"
+            )),
+            1u64
+        )
+            .into(),
+        (
+            H256::from(hex!(
+                "b98fb783b49de5652097a989414c767824dff7e7fd765a63b493772511db81c1"
+            )),
+            2u64
+        )
+            .into(),
+    ];
+}
+
+fn make_user(id: &str, name: &str, age: i32, color: Option<&str>, nicknames: &[&str]) -> Entity {
+    let mut user = Entity::new();
+    user.set("id", id);
+    user.set("name", name);
+    user.set(
+        "bin_name",
+        scalar::Bytes::from_str(&hex::encode(name)).unwrap(),
+    );
+    user.set("email", format!("{}@email.com", name.to_lowercase()));
+    user.set("age", age);
+    user.set("seconds_age", BigInt::from(age) * 31557600.into());
+    user.set("weight", BigDecimal::from(184.4));
+    user.set("coffee", age % 2 == 0);
+    user.set(
+        "favorite_color",
+        color.map(Value::from).unwrap_or(Value::Null),
+    );
+    user.set(
+        "nicknames",
+        Value::List(nicknames.iter().map(|nick| Value::from(*nick)).collect()),
+    );
+    user
+}
+
+fn key(entity_type: &str, id: &str) -> EntityKey {
+    EntityKey {
+        subgraph_id: TEST_SUBGRAPH_ID.clone(),
+        entity_type: entity_type.to_owned(),
+        entity_id: id.to_owned(),
+    }
+}
+
+fn set(entity_type: &str, data: Entity) -> EntityOperation {
+    EntityOperation::Set {
+        key: key(entity_type, &data.id().unwrap()),
+        data,
+    }
+}
+
+fn remove(entity_type: &str, id: &str) -> EntityOperation {
+    EntityOperation::Remove {
+        key: key(entity_type, id),
+    }
+}
+
+/// Check that the user `expected` is stored exactly like that
+fn check_user(store: &DieselStore, expected: &Entity) {
+    let id = expected.id().unwrap();
+    let actual = store
+        .get(key(USER, &id))
+        .unwrap()
+        .unwrap_or_else(|| panic!("user {} exists", id));
+    for (attr, value) in expected.iter() {
+        if value == &Value::Null {
+            assert_eq!(None, actual.get(attr), "{}[{}]", attr, id);
+        } else {
+            assert_eq!(Some(value), actual.get(attr), "{}[{}]", attr, id);
+        }
+    }
+}
+
+fn search(store: &DieselStore, text: &str) -> Vec<String> {
+    let query = EntityQuery::new(
+        TEST_SUBGRAPH_ID.clone(),
+        BLOCK_NUMBER_MAX,
+        EntityCollection::All(vec![USER.to_owned()]),
+    )
+    .filter(EntityFilter::Equal("userSearch".to_owned(), text.into()))
+    .order(EntityOrder::Ascending("id".to_owned(), ValueType::String));
+    store
+        .find(query)
+        .unwrap()
+        .into_iter()
+        .map(|entity| entity.id().unwrap())
+        .collect()
+}
+
+fn remove_test_data() {
+    let url = postgres_test_url();
+    let conn = PgConnection::establish(url.as_str()).expect("Failed to connect to Postgres");
+    graph_store_postgres::store::delete_all_entities_for_test_use_only(&STORE, &conn)
+        .expect("Failed to remove entity test data");
+}
+
+/// Test harness for running database integration tests.
+fn run_test<F>(test: F)
+where
+    F: FnOnce(Arc<DieselStore>),
+{
+    // Every block, however small, should be written in bulk. This has to
+    // happen before the store first looks at the variable
+    std::env::set_var("GRAPH_STORE_BULK_WRITE_THRESHOLD", "1");
+
+    // Lock regardless of poisoning. This also forces sequential test execution.
+    let _runtime = match STORE_RUNTIME.lock() {
+        Ok(guard) => guard,
+        Err(err) => err.into_inner(),
+    };
+
+    remove_test_data();
+    create_test_subgraph(TEST_SUBGRAPH_ID.as_str(), USER_GQL);
+    test(STORE.clone());
+}
+
+#[test]
+fn bulk_write_and_revert() {
+    run_test(|store| {
+        let johnton = make_user("1", "Johnton", 67, Some("yellow"), &["john"]);
+        let cindini = make_user("2", "Cindini", 43, None, &[]);
+        let shaqueeena = make_user("3", "Shaqueeena", 28, Some("red"), &["shaq", "queen"]);
+        transact_entity_operations(
+            &store,
+            TEST_SUBGRAPH_ID.clone(),
+            BLOCKS[1],
+            vec![
+                set(USER, johnton.clone()),
+                set(USER, cindini.clone()),
+                set(USER, shaqueeena.clone()),
+                set(PERSON, {
+                    let mut person = Entity::new();
+                    person.set("id", "4");
+                    person.set("name", "Ada");
+                    person
+                }),
+            ],
+        )
+        .unwrap();
+
+        for user in &[&johnton, &cindini, &shaqueeena] {
+            check_user(&store, user);
+        }
+        assert_eq!(vec!["3".to_owned()], search(&store, "Shaqueeena"));
+        assert_eq!(vec!["2".to_owned()], search(&store, "Cindini"));
+
+        // Update one user, remove another, and add a new one
+        let johnton2 = make_user("1", "Johnton", 68, Some("blue"), &[]);
+        let tonya = make_user("5", "Tonya", 19, Some("green"), &["tee"]);
+        transact_entity_operations(
+            &store,
+            TEST_SUBGRAPH_ID.clone(),
+            BLOCKS[2],
+            vec![
+                set(USER, johnton2.clone()),
+                remove(USER, "2"),
+                set(USER, tonya.clone()),
+            ],
+        )
+        .unwrap();
+
+        check_user(&store, &johnton2);
+        check_user(&store, &tonya);
+        assert!(store.get(key(USER, "2")).unwrap().is_none());
+        assert_eq!(vec!["5".to_owned()], search(&store, "Tonya"));
+
+        // Reverting the second block restores the state after the first
+        store
+            .revert_block_operations(TEST_SUBGRAPH_ID.clone(), BLOCKS[2], BLOCKS[1])
+            .unwrap();
+        for user in &[&johnton, &cindini, &shaqueeena] {
+            check_user(&store, user);
+        }
+        assert!(store.get(key(USER, "5")).unwrap().is_none());
+        assert!(search(&store, "Tonya").is_empty());
+        assert_eq!(
+            Some(BLOCKS[1]),
+            store.block_ptr(TEST_SUBGRAPH_ID.clone()).unwrap()
+        );
+    })
+}
+
+#[test]
+fn bulk_write_rejects_conflicting_ids() {
+    run_test(|store| {
+        let johnton = make_user("1", "Johnton", 67, None, &[]);
+        let mut ada = Entity::new();
+        ada.set("id", "1");
+        ada.set("name", "Ada");
+
+        // Conflicts within the block
+        let res = transact_entity_operations(
+            &store,
+            TEST_SUBGRAPH_ID.clone(),
+            BLOCKS[1],
+            vec![set(USER, johnton.clone()), set(PERSON, ada.clone())],
+        );
+        match res {
+            Err(StoreError::ConflictingId(_, id, _)) => assert_eq!("1", id),
+            _ => panic!("expected a conflicting id error but got {:?}", res),
+        }
+
+        // Conflicts with entities that are already in the store
+        transact_entity_operations(
+            &store,
+            TEST_SUBGRAPH_ID.clone(),
+            BLOCKS[1],
+            vec![set(USER, johnton)],
+        )
+        .unwrap();
+        let res = transact_entity_operations(
+            &store,
+            TEST_SUBGRAPH_ID.clone(),
+            BLOCKS[2],
+            vec![set(PERSON, ada)],
+        );
+        match res {
+            Err(StoreError::ConflictingId(entity_type, id, other)) => {
+                assert_eq!(PERSON, entity_type);
+                assert_eq!("1", id);
+                assert_eq!(USER, other);
+            }
+            _ => panic!("expected a conflicting id error but got {:?}", res),
+        }
+    })
+}
@@ -0,0 +1,231 @@
+//! Test converting deployments that use JSONB storage to relational storage
+use diesel::connection::SimpleConnection as _;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Jsonb, Text};
+use lazy_static::lazy_static;
+
+use graph::prelude::{serde_json, Entity, Schema, SubgraphDeploymentId, Value, BLOCK_NUMBER_MAX};
+use graph_store_postgres::jsonb_migration;
+use graph_store_postgres::layout_for_tests::{Catalog, Layout};
+
+use test_store::*;
+
+const USER_GQL: &str = "
+    type User @entity {
+        id: ID!,
+        name: String,
+        age: Int,
+        weight: BigDecimal,
+        coffee: Boolean
+    }
+";
+
+lazy_static! {
+    static ref TEST_SUBGRAPH_ID: SubgraphDeploymentId =
+        SubgraphDeploymentId::new("jsonbsubgraph").unwrap();
+}
+
+fn user(id: &str, name: &str, age: Option<i32>, weight: f64, coffee: bool) -> Entity {
+    let mut entity = Entity::new();
+    entity.set("id", id);
+    entity.set("name", name);
+    entity.set("age", age.map(Value::Int).unwrap_or(Value::Null));
+    entity.set("weight", Value::BigDecimal(weight.into()));
+    entity.set("coffee", coffee);
+    entity
+}
+
+fn users() -> Vec<Entity> {
+    vec![
+        user("1", "Johnton", Some(67), 184.4, false),
+        user("2", "Cindini", None, 111.7, true),
+        user("3", "Shaqueeena", Some(28), 94.4, false),
+    ]
+}
+
+/// The value of `attr` in `entity`, treating `null` the same as a missing
+/// attribute
+fn value<'a>(entity: &'a Entity, attr: &str) -> Option<&'a Value> {
+    entity.get(attr).filter(|value| *value != &Value::Null)
+}
+
+/// The name of the database schema that holds the data of `subgraph`
+fn namespace(conn: &PgConnection, subgraph: &SubgraphDeploymentId) -> String {
+    #[derive(QueryableByName)]
+    struct Namespace {
+        #[sql_type = "Text"]
+        name: String,
+    }
+
+    diesel::sql_query("select name from deployment_schemas where subgraph = $1")
+        .bind::<Text, _>(subgraph.as_str())
+        .get_result::<Namespace>(conn)
+        .expect("Failed to look up deployment schema")
+        .name
+}
+
+fn schema_exists(conn: &PgConnection, namespace: &str) -> bool {
+    #[derive(QueryableByName)]
+    struct Count {
+        #[sql_type = "BigInt"]
+        count: i64,
+    }
+
+    diesel::sql_query("select count(*) as count from pg_namespace where nspname = $1")
+        .bind::<Text, _>(namespace)
+        .get_result::<Count>(conn)
+        .unwrap()
+        .count
+        > 0
+}
+
+fn insert_jsonb_entity(conn: &PgConnection, namespace: &str, entity_type: &str, entity: &Entity) {
+    let query = format!(
+        "insert into {}.entities(entity, id, data, event_source)
+         values($1, $2, $3, 'none')",
+        namespace
+    );
+    diesel::sql_query(query)
+        .bind::<Text, _>(entity_type)
+        .bind::<Text, _>(entity.id().unwrap())
+        .bind::<Jsonb, _>(serde_json::to_value(entity).unwrap())
+        .execute(conn)
+        .expect("Failed to insert JSONB entity");
+}
+
+/// Create a deployment the way the store used to before it stored
+/// entities in relational tables
+fn insert_test_data(conn: &PgConnection) {
+    create_test_subgraph(TEST_SUBGRAPH_ID.as_str(), USER_GQL);
+
+    let namespace = namespace(conn, &TEST_SUBGRAPH_ID);
+    let query = format!(
+        "drop schema {nsp} cascade;
+         create schema {nsp};
+         create table {nsp}.entities(
+           entity       varchar not null,
+           id           varchar not null,
+           data         jsonb,
+           event_source varchar not null,
+           primary key(entity, id));
+         update deployment_schemas
+            set version = 'split'
+          where subgraph = '{subgraph}';",
+        nsp = namespace,
+        subgraph = TEST_SUBGRAPH_ID.as_str()
+    );
+    conn.batch_execute(&query)
+        .expect("Failed to create JSONB deployment");
+    for user in users() {
+        insert_jsonb_entity(conn, &namespace, "User", &user);
+    }
+}
+
+fn remove_test_data(conn: &PgConnection) {
+    graph_store_postgres::store::delete_all_entities_for_test_use_only(&STORE, conn)
+        .expect("Failed to remove entity test data");
+}
+
+/// Test harness for running database integration tests.
+fn run_test<F>(test: F)
+where
+    F: FnOnce(&PgConnection),
+{
+    let url = postgres_test_url();
+    let conn = PgConnection::establish(url.as_str()).expect("Failed to connect to Postgres");
+
+    // Lock regardless of poisoning. This also forces sequential test execution.
+    let _runtime = match STORE_RUNTIME.lock() {
+        Ok(guard) => guard,
+        Err(err) => err.into_inner(),
+    };
+
+    remove_test_data(&conn);
+    insert_test_data(&conn);
+    test(&conn);
+}
+
+#[test]
+fn migrate_jsonb_deployment() {
+    run_test(|conn| {
+        assert_eq!(
+            vec![TEST_SUBGRAPH_ID.clone()],
+            jsonb_migration::jsonb_deployments(conn).unwrap()
+        );
+        let old_namespace = namespace(conn, &TEST_SUBGRAPH_ID);
+
+        let report =
+            jsonb_migration::migrate(&*LOGGER, conn, &TEST_SUBGRAPH_ID, 10, false).unwrap();
+        assert_eq!(Some(&3), report.counts.get("User"));
+        assert_eq!(1, report.counts.len());
+        assert_eq!(3, report.sampled);
+        assert!(!report.dry_run);
+        assert_eq!(old_namespace, report.old_namespace);
+
+        // The deployment now uses the new schema, and the old one is gone
+        assert!(jsonb_migration::jsonb_deployments(conn).unwrap().is_empty());
+        assert_eq!(report.namespace, namespace(conn, &TEST_SUBGRAPH_ID));
+        assert!(!schema_exists(conn, &old_namespace));
+
+        let schema = Schema::parse(USER_GQL, TEST_SUBGRAPH_ID.clone()).unwrap();
+        let catalog = Catalog::new(conn, report.namespace.clone()).unwrap();
+        let layout = Layout::new(&schema, catalog, false).unwrap();
+        for expected in users() {
+            let id = expected.id().unwrap();
+            let actual = layout
+                .find(conn, "User", &id, BLOCK_NUMBER_MAX)
+                .unwrap()
+                .expect("entity was copied");
+            for attr in &["id", "name", "age", "weight", "coffee"] {
+                assert_eq!(
+                    value(&expected, attr),
+                    value(&actual, attr),
+                    "{}[{}]",
+                    attr,
+                    id
+                );
+            }
+        }
+    })
+}
+
+#[test]
+fn migrate_jsonb_deployment_dry_run() {
+    run_test(|conn| {
+        let old_namespace = namespace(conn, &TEST_SUBGRAPH_ID);
+
+        let report = jsonb_migration::migrate(&*LOGGER, conn, &TEST_SUBGRAPH_ID, 10, true).unwrap();
+        assert_eq!(Some(&3), report.counts.get("User"));
+        assert_eq!(3, report.sampled);
+        assert!(report.dry_run);
+
+        // The deployment is unchanged
+        assert_eq!(
+            vec![TEST_SUBGRAPH_ID.clone()],
+            jsonb_migration::jsonb_deployments(conn).unwrap()
+        );
+        assert_eq!(old_namespace, namespace(conn, &TEST_SUBGRAPH_ID));
+        assert!(!schema_exists(conn, &report.namespace));
+    })
+}
+
+#[test]
+fn migrate_jsonb_deployment_with_unknown_entity_type() {
+    run_test(|conn| {
+        let old_namespace = namespace(conn, &TEST_SUBGRAPH_ID);
+        let mut ghost = Entity::new();
+        ghost.set("id", "1");
+        insert_jsonb_entity(conn, &old_namespace, "Ghost", &ghost);
+
+        assert!(jsonb_migration::migrate(&*LOGGER, conn, &TEST_SUBGRAPH_ID, 10, false).is_err());
+
+        // The deployment is unchanged
+        assert_eq!(
+            vec![TEST_SUBGRAPH_ID.clone()],
+            jsonb_migration::jsonb_deployments(conn).unwrap()
+        );
+        assert_eq!(old_namespace, namespace(conn, &TEST_SUBGRAPH_ID));
+        assert!(schema_exists(conn, &old_namespace));
+    })
+}
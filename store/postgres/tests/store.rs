@@ -9,13 +9,17 @@ use std::str::FromStr;
 use std::time::Duration;
 use test_store::*;
 
-use graph::components::store::{EntityFilter, EntityKey, EntityOrder, EntityQuery};
+use graph::components::store::{
+    EntityFilter, EntityKey, EntityOrder, EntityQuery, EntityTypeChanges,
+};
 use graph::data::store::scalar;
 use graph::data::subgraph::schema::*;
 use graph::data::subgraph::*;
 use graph::prelude::*;
+use graph_graphql::prelude::{BlockConstraint, StoreResolver};
 use graph_store_postgres::layout_for_tests::STRING_PREFIX_SIZE;
 use graph_store_postgres::Store as DieselStore;
+use graph_store_postgres::PRIMARY_SHARD;
 use web3::types::{Address, H256};
 
 const USER_GQL: &str = "
@@ -334,6 +338,179 @@ fn delete_entity() {
     })
 }
 
+#[test]
+fn entity_stats() {
+    run_test(|store| -> Result<(), ()> {
+        let stats = store.entity_stats(&TEST_SUBGRAPH_ID).unwrap();
+        let users = stats
+            .iter()
+            .find(|stats| stats.entity_type == USER)
+            .expect("there are statistics for users");
+
+        // User 3 was updated once, and all other users were only inserted
+        assert_eq!(3, users.entities);
+        assert_eq!(4, users.versions);
+        assert_eq!(Some(1.0 / 3.0), users.history_ratio());
+        assert!(users.table_size > 0);
+        assert!(users.index_size > 0);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn estimated_entity_stats() {
+    run_test(|store| -> Result<(), ()> {
+        use diesel::connection::SimpleConnection;
+
+        // Make sure Postgres has statistics for the deployment's tables
+        let conn = PgConnection::establish(postgres_test_url().as_str())
+            .expect("Failed to connect to Postgres");
+        conn.batch_execute("analyze").unwrap();
+
+        let stats = store.estimated_entity_stats(&TEST_SUBGRAPH_ID).unwrap();
+        let users = stats
+            .iter()
+            .find(|stats| stats.entity_type == USER)
+            .expect("there are statistics for users");
+
+        assert_eq!(3, users.entities);
+        assert_eq!(4, users.versions);
+        assert!(users.table_size > 0);
+        assert!(users.index_size > 0);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn prune_history() {
+    run_test(|store| {
+        async move {
+            // Delete entity with id=2; its only version now ends at block 3
+            let key = |id: &str| EntityKey {
+                subgraph_id: TEST_SUBGRAPH_ID.clone(),
+                entity_type: USER.to_owned(),
+                entity_id: id.to_owned(),
+            };
+            transact_entity_operations(
+                &store,
+                TEST_SUBGRAPH_ID.clone(),
+                *TEST_BLOCK_3_PTR,
+                vec![EntityOperation::Remove { key: key("2") }],
+            )
+            .unwrap();
+
+            let versions = |store: &DieselStore| {
+                store
+                    .entity_stats(&TEST_SUBGRAPH_ID)
+                    .unwrap()
+                    .into_iter()
+                    .find(|stats| stats.entity_type == USER)
+                    .expect("there are statistics for users")
+                    .versions
+            };
+            assert_eq!(4, versions(&store));
+
+            store
+                .set_history_blocks(&TEST_SUBGRAPH_ID, Some(1))
+                .unwrap();
+
+            // History within the reorg threshold is always kept
+            store.prune_deployments(&*LOGGER, 3).unwrap();
+            assert_eq!(0, store.earliest_block(&TEST_SUBGRAPH_ID).unwrap());
+            assert_eq!(4, versions(&store));
+
+            // Only the version of user 3 that ended at block 2 is not
+            // visible at block 2 or later
+            store.prune_deployments(&*LOGGER, 0).unwrap();
+            assert_eq!(2, store.earliest_block(&TEST_SUBGRAPH_ID).unwrap());
+            assert_eq!(3, versions(&store));
+
+            // Queries for blocks before the pruned block are refused
+            let at = |number| {
+                StoreResolver::at_block(
+                    &*LOGGER,
+                    store.clone(),
+                    BlockConstraint::Number(number),
+                    TEST_SUBGRAPH_ID.clone(),
+                )
+            };
+            assert!(at(1).await.is_err());
+            assert!(at(2).await.is_ok());
+            assert!(at(3).await.is_ok());
+
+            // The changes in blocks up to the pruned block are incomplete
+            let changes = |number| store.entity_changes_in_block(&TEST_SUBGRAPH_ID, number);
+            assert!(changes(1).is_err());
+            assert!(changes(2).is_err());
+            assert!(changes(3).is_ok());
+
+            // Blocks after the pruned block can still be reverted
+            store
+                .revert_block_operations(
+                    TEST_SUBGRAPH_ID.clone(),
+                    *TEST_BLOCK_3_PTR,
+                    *TEST_BLOCK_2_PTR,
+                )
+                .unwrap();
+            assert!(store.get(key("2")).unwrap().is_some());
+            assert_eq!(
+                Some(&Value::from("teeko@email.com")),
+                store.get(key("3")).unwrap().unwrap().get("email")
+            );
+
+            Ok::<(), ()>(())
+        }
+        .boxed()
+        .compat()
+    })
+}
+
+#[test]
+fn entity_changes_in_block() {
+    run_test(|store| -> Result<(), ()> {
+        // Delete entity with id=2
+        let del_key = EntityKey {
+            subgraph_id: TEST_SUBGRAPH_ID.clone(),
+            entity_type: USER.to_owned(),
+            entity_id: "2".to_owned(),
+        };
+        transact_entity_operations(
+            &store,
+            TEST_SUBGRAPH_ID.clone(),
+            *TEST_BLOCK_3_PTR,
+            vec![EntityOperation::Remove { key: del_key }],
+        )
+        .unwrap();
+
+        let changes = |ptr: &EthereumBlockPointer| {
+            store
+                .entity_changes_in_block(&TEST_SUBGRAPH_ID, ptr.number as BlockNumber)
+                .unwrap()
+        };
+        let users = |created: Vec<&str>, updated: Vec<&str>, removed: Vec<&str>| {
+            let ids = |ids: Vec<&str>| ids.into_iter().map(String::from).collect();
+            vec![EntityTypeChanges {
+                entity_type: USER.to_owned(),
+                created: ids(created),
+                updated: ids(updated),
+                removed: ids(removed),
+            }]
+        };
+
+        assert_eq!(
+            users(vec!["2", "3"], vec![], vec![]),
+            changes(&TEST_BLOCK_1_PTR)
+        );
+        assert_eq!(users(vec![], vec!["3"], vec![]), changes(&TEST_BLOCK_2_PTR));
+        assert_eq!(users(vec![], vec![], vec!["2"]), changes(&TEST_BLOCK_3_PTR));
+        assert!(changes(&TEST_BLOCK_4_PTR).is_empty());
+
+        Ok(())
+    })
+}
+
 /// Check that user 1 was inserted correctly
 #[test]
 fn get_entity_1() {
@@ -1190,6 +1367,7 @@ fn subscribe_and_consume(
                 Some(Arc::new(StoreEvent {
                     tag: event.tag,
                     changes,
+                    block: event.block,
                 }))
             }
         });
@@ -1334,6 +1512,167 @@ fn revert_block_with_delete() {
     })
 }
 
+#[test]
+fn rewind_several_blocks() {
+    run_test(|store| -> Result<(), ()> {
+        // Delete entity with id=2
+        let del_key = EntityKey {
+            subgraph_id: TEST_SUBGRAPH_ID.clone(),
+            entity_type: USER.to_owned(),
+            entity_id: "2".to_owned(),
+        };
+        transact_entity_operations(
+            &store,
+            TEST_SUBGRAPH_ID.clone(),
+            *TEST_BLOCK_3_PTR,
+            vec![EntityOperation::Remove { key: del_key }],
+        )
+        .unwrap();
+
+        // Undo the changes from blocks 2 and 3
+        let count = get_entity_count(store.clone(), &TEST_SUBGRAPH_ID);
+        store.rewind(&TEST_SUBGRAPH_ID, *TEST_BLOCK_1_PTR).unwrap();
+        assert_eq!(
+            count + 1,
+            get_entity_count(store.clone(), &TEST_SUBGRAPH_ID)
+        );
+        assert_eq!(
+            Some(*TEST_BLOCK_1_PTR),
+            store.block_ptr(TEST_SUBGRAPH_ID.clone()).unwrap()
+        );
+
+        let key = |id: &str| EntityKey {
+            subgraph_id: TEST_SUBGRAPH_ID.clone(),
+            entity_type: USER.to_owned(),
+            entity_id: id.to_owned(),
+        };
+        assert!(store.get(key("2")).unwrap().is_some());
+        assert_eq!(
+            Some(&Value::from("queensha@email.com")),
+            store.get(key("3")).unwrap().unwrap().get("email")
+        );
+
+        // We can not rewind to a block the deployment has not processed
+        assert!(store.rewind(&TEST_SUBGRAPH_ID, *TEST_BLOCK_2_PTR).is_err());
+
+        // A block that was processed on top of block 3 before the rewind
+        // is refused until the deployment is started again
+        assert!(transact_entity_operations(
+            &store,
+            TEST_SUBGRAPH_ID.clone(),
+            *TEST_BLOCK_4_PTR,
+            vec![]
+        )
+        .is_err());
+        assert_eq!(
+            Some(*TEST_BLOCK_1_PTR),
+            store.block_ptr(TEST_SUBGRAPH_ID.clone()).unwrap()
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn move_deployment_to_another_shard() {
+    fn shard_of(id: &SubgraphDeploymentId) -> String {
+        #[derive(QueryableByName)]
+        struct Shard {
+            #[sql_type = "diesel::sql_types::Text"]
+            shard: String,
+        }
+
+        let conn = PgConnection::establish(postgres_test_url().as_str())
+            .expect("Failed to connect to Postgres");
+        diesel::sql_query("select shard from deployment_schemas where subgraph = $1")
+            .bind::<diesel::sql_types::Text, _>(id.as_str())
+            .get_result::<Shard>(&conn)
+            .unwrap()
+            .shard
+    }
+
+    run_test(|store| -> Result<(), ()> {
+        let key = |id: &str| EntityKey {
+            subgraph_id: TEST_SUBGRAPH_ID.clone(),
+            entity_type: USER.to_owned(),
+            entity_id: id.to_owned(),
+        };
+
+        store
+            .start_deployment_move(&TEST_SUBGRAPH_ID, TEST_SHARD)
+            .unwrap();
+        store
+            .copy_moving_deployment(&*LOGGER, &TEST_SUBGRAPH_ID)
+            .unwrap();
+
+        // The deployment keeps indexing in the old shard while it is copied
+        let user4 = create_test_entity(
+            "4",
+            USER,
+            "Steve",
+            "nieve@email.com",
+            72,
+            120.7,
+            false,
+            None,
+        );
+        transact_entity_operations(
+            &store,
+            TEST_SUBGRAPH_ID.clone(),
+            *TEST_BLOCK_3_PTR,
+            vec![user4],
+        )
+        .unwrap();
+        assert_eq!(PRIMARY_SHARD, shard_of(&TEST_SUBGRAPH_ID));
+
+        // Finishing the move catches up on block 3 and switches shards
+        let count = get_entity_count(store.clone(), &TEST_SUBGRAPH_ID);
+        store
+            .finish_deployment_move(&*LOGGER, &TEST_SUBGRAPH_ID)
+            .unwrap();
+        assert_eq!(TEST_SHARD, shard_of(&TEST_SUBGRAPH_ID));
+        assert_eq!(count, get_entity_count(store.clone(), &TEST_SUBGRAPH_ID));
+        assert_eq!(
+            Some(*TEST_BLOCK_3_PTR),
+            store.block_ptr(TEST_SUBGRAPH_ID.clone()).unwrap()
+        );
+        assert!(store.get(key("4")).unwrap().is_some());
+
+        // Writes now go to the new shard
+        let user5 = create_test_entity("5", USER, "Ada", "ada@email.com", 36, 54.1, true, None);
+        transact_entity_operations(
+            &store,
+            TEST_SUBGRAPH_ID.clone(),
+            *TEST_BLOCK_4_PTR,
+            vec![user5],
+        )
+        .unwrap();
+        assert!(store.get(key("5")).unwrap().is_some());
+
+        // Rolling back returns to the old data as of the end of the move
+        store
+            .rollback_deployment_move(&*LOGGER, &TEST_SUBGRAPH_ID)
+            .unwrap();
+        assert_eq!(PRIMARY_SHARD, shard_of(&TEST_SUBGRAPH_ID));
+        assert_eq!(
+            Some(*TEST_BLOCK_3_PTR),
+            store.block_ptr(TEST_SUBGRAPH_ID.clone()).unwrap()
+        );
+        assert!(store.get(key("4")).unwrap().is_some());
+        assert!(store.get(key("5")).unwrap().is_none());
+
+        // There is nothing left to roll back to
+        assert!(store
+            .rollback_deployment_move(&*LOGGER, &TEST_SUBGRAPH_ID)
+            .is_err());
+        store
+            .drop_retired_deployment_data(&TEST_SUBGRAPH_ID)
+            .unwrap();
+
+        Ok(())
+    })
+}
+
 #[test]
 fn revert_block_with_partial_update() {
     run_test(|store| {
@@ -1581,6 +1920,7 @@ fn revert_block_with_dynamic_data_source_operations() {
                 ]
                 .into_iter(),
             ),
+            block: None,
         }];
         check_events(subscription, expected_events)
     })
@@ -1905,18 +2245,6 @@ fn handle_large_string_with_index() {
     };
 
     run_test(|store| -> Result<(), ()> {
-        let index = AttributeIndexDefinition {
-            subgraph_id: TEST_SUBGRAPH_ID.clone(),
-            entity_number: 0,
-            attribute_number: 0,
-            field_value_type: ValueType::String,
-            attribute_name: NAME.to_owned(),
-            entity_name: USER.to_owned(),
-        };
-        store
-            .build_entity_attribute_indexes(&*TEST_SUBGRAPH_ID, vec![index])
-            .unwrap();
-
         // We have to produce a massive string (1_000_000 chars) because
         // the repeated text compresses so well. This leads to an error
         // 'index row requires 11488 bytes, maximum size is 8191' if
@@ -2217,12 +2545,9 @@ fn find_at_block() {
         })
     }
 
-    // These tests only make sense with relational storage
-    if *USING_RELATIONAL_STORAGE {
-        shaqueeena_at_block(1, "queensha@email.com");
-        shaqueeena_at_block(2, "teeko@email.com");
-        shaqueeena_at_block(7000, "teeko@email.com");
-    }
+    shaqueeena_at_block(1, "queensha@email.com");
+    shaqueeena_at_block(2, "teeko@email.com");
+    shaqueeena_at_block(7000, "teeko@email.com");
 }
 
 #[test]
@@ -10,7 +10,9 @@ use graph_graphql::prelude::{
 };
 use graph_mock::MockMetricsRegistry;
 use graph_store_postgres::connection_pool::create_connection_pool;
-use graph_store_postgres::{ChainHeadUpdateListener, Store, StoreConfig, SubscriptionManager};
+use graph_store_postgres::{
+    ChainHeadUpdateListener, Shard, Store, StoreConfig, SubscriptionManager,
+};
 use hex_literal::hex;
 use lazy_static::lazy_static;
 use std::env;
@@ -27,6 +29,9 @@ pub fn postgres_test_url() -> String {
 
 pub const NETWORK_NAME: &str = "fake_network";
 pub const NETWORK_VERSION: &str = "graph test suite";
+/// A second shard that lives in the same database as the primary so that
+/// tests can move deployments between shards
+pub const TEST_SHARD: &str = "test";
 
 const CONN_POOL_SIZE: usize = 20;
 
@@ -77,10 +82,16 @@ lazy_static! {
                     logger.clone(),
                     postgres_url.clone(),
                 ));
+                let shard = Shard::new(
+                    TEST_SHARD.to_owned(),
+                    postgres_url.clone(),
+                    postgres_conn_pool.clone(),
+                ).unwrap();
                 Arc::new(Store::new(
                     StoreConfig {
                         postgres_url,
                         network_name: NETWORK_NAME.to_owned(),
+                        placement: vec![],
                     },
                     &logger,
                     net_identifiers,
@@ -89,6 +100,7 @@ lazy_static! {
                     postgres_conn_pool,
                     Vec::new(),
                     Vec::new(),
+                    vec![shard],
                     registry.clone(),
                 ))
             })
@@ -106,14 +118,6 @@ lazy_static! {
         )),
         1u64
     ).into();
-
-    /// True if we are using relational storage in our tests
-    pub static ref USING_RELATIONAL_STORAGE: bool = {
-        std::env::var("GRAPH_STORAGE_SCHEME")
-            .unwrap_or_else(|_| "relational".to_owned())
-            .as_str()
-        == "relational"
-    };
 }
 
 #[cfg(debug_assertions)]
@@ -169,7 +173,7 @@ pub fn transact_entity_operations(
     subgraph_id: SubgraphDeploymentId,
     block_ptr_to: EthereumBlockPointer,
     ops: Vec<EntityOperation>,
-) -> Result<bool, StoreError> {
+) -> Result<(), StoreError> {
     let mut entity_cache = EntityCache::new(store.clone());
     entity_cache.append(ops)?;
     let mods = entity_cache